@@ -1,4 +1,7 @@
+import cbor2
 import datetime
+import gzip
+import io
 import random
 import time
 import pendulum
@@ -9,9 +12,49 @@ import math
 
 client = datastore.Client()
 
+# Devices ignore commands that were issued longer ago than this
+COMMAND_VALIDITY_SECONDS = 5 * 60
+COMMANDS = ['reboot', 'identify', 'clear_nvs_section', 'resend_stats', 'stay_awake',
+            'allow_sleep', 'resend_trace', 'set_name']
+
+# Short keys of uploads in CBOR, see `CBOR_KEYS` in the serializer of the gateway
+CBOR_KEYS = {
+    'lat': 'latitude', 'lon': 'longitude', 'hd': 'hdop', 'ts': 'timestamp', 'rx': 'received',
+    'utc': 'utc', 'fq': 'fix_quality', 'sat': 'satellites', 'id': 'uid', 'ch': 'charging',
+    'bv': 'battery_voltage', 'tx': 'tx_power', 'bi': 'boot_id', 'sq': 'seq',
+    'gf': 'gps_fault_suspected', 'pr': 'profile', 'cv': 'config_version', 'bf': 'backfill',
+    'gh': 'geohash', 't': 'test', 'st': 'stale', 'pv': 'privacy', 'pd': 'path_delay_ms',
+    'uo': 'update_of', 'cs': 'clock_source', 'co': 'clock_offset_ms', 'gs': 'gps_state',
+    'ik': 'idempotency_key', 'gw': 'gateway_hint', 'nm': 'name', 'fe': 'fix_epoch',
+    'se': 'sent_epoch', 're': 'relay_epoch', 'ge': 'gateway_epoch',
+}
+
 app = Flask(__name__)
 
 
+class GunzipRequests:
+    """Decompress request bodies sent with Content-Encoding: gzip, see `gzip` in the gateway."""
+
+    def __init__(self, wsgi_app):
+        self.wsgi_app = wsgi_app
+
+    def __call__(self, environ, start_response):
+        if environ.get('HTTP_CONTENT_ENCODING') == 'gzip':
+            length = int(environ.get('CONTENT_LENGTH') or 0)
+            try:
+                body = gzip.decompress(environ['wsgi.input'].read(length))
+            except (OSError, EOFError):
+                start_response('400 Bad Request', [('Content-Type', 'text/plain')])
+                return [b'Invalid gzip body']
+            environ['wsgi.input'] = io.BytesIO(body)
+            environ['CONTENT_LENGTH'] = str(len(body))
+            del environ['HTTP_CONTENT_ENCODING']
+        return self.wsgi_app(environ, start_response)
+
+
+app.wsgi_app = GunzipRequests(app.wsgi_app)
+
+
 def store_location(source, location):
     """Store a location in Datastore."""
 
@@ -24,9 +67,21 @@ def store_location(source, location):
     parent_entity.update({
         'id': source,
     })
+    # Trackers only send the version of their configuration every now and then, 0 in between
+    if location.get('config_version'):
+        parent_entity['config_version'] = int(location['config_version'])
+    # The name the tracker gave itself, also only in some reports
+    if location.get('name'):
+        parent_entity['name'] = location['name']
     client.put(parent_entity)
 
-    key = client.key('location', parent=parent_key)
+    # A retry of an upload we already stored has the same idempotency key, so it overwrites the
+    # location instead of adding another one. The key is in the body or the Idempotency-Key header.
+    idempotency_key = location.get('idempotency_key') or request.headers.get('Idempotency-Key')
+    if idempotency_key:
+        key = client.key('location', idempotency_key, parent=parent_key)
+    else:
+        key = client.key('location', parent=parent_key)
     entity = datastore.Entity(key=key, exclude_from_indexes=['expiry_timestamp'])
     lat = location['latitude']
     lon = location['longitude']
@@ -50,6 +105,28 @@ def store_location(source, location):
         'uid': location['uid'],
         'charging': charging,
         'battery_voltage': float(battery_voltage),
+        # Injected by a gateway to test the pipeline
+        'test': bool(location.get('test', False)),
+        # Too old to show on a live map by the time the gateway uploaded it
+        'stale': bool(location.get('stale', False)),
+        # Reporting profile of the tracker, missing for trackers without profiles
+        'profile': location.get('profile'),
+        'config_version': location.get('config_version') or None,
+        # Where the clock of the tracker got its time and how far off it was, see `timesync`
+        'clock_source': location.get('clock_source'),
+        'clock_offset_ms': location.get('clock_offset_ms'),
+        # Whether the GPS module sent nothing, sentences without a fix, or a 2D or 3D fix
+        'gps_state': location.get('gps_state'),
+        # The gateway the beacon that wrote the fix to the UART is attached to, as the beacon
+        # learned it. Missing for fixes from older beacons and gateways in combo mode.
+        'gateway_hint': location.get('gateway_hint'),
+        # When the fix was measured, sent by the tracker, relayed by a beacon and received by the
+        # gateway, see `timestamps` in morty-rs. Missing or None when unknown, `timestamp`,
+        # `received` and `utc` are the older names of the last three.
+        'fix_epoch': location.get('fix_epoch'),
+        'sent_epoch': location.get('sent_epoch'),
+        'relay_epoch': location.get('relay_epoch'),
+        'gateway_epoch': location.get('gateway_epoch'),
     })
     client.put(entity)
 
@@ -71,9 +148,20 @@ def sources():
     sources = [s for s in fetch_sources()]
     return sources
 
+# The configuration versions the devices should run with, set with `desired_config_version` on
+# the source. Gateways compare these with the versions the devices report.
+@app.route('/api/v1/sources/config_versions')
+def config_versions():
+    return {s['id']: int(s['desired_config_version'])
+            for s in fetch_sources() if s.get('desired_config_version')}
+
 @app.route('/api/v1/source/<source>/location', methods=['POST'])
 def post_locastion(source):
-    location = request.get_json()
+    if request.mimetype == 'application/cbor':
+        location = cbor2.loads(request.get_data())
+        location = {CBOR_KEYS.get(k, k): v for k, v in location.items()}
+    else:
+        location = request.get_json()
     store_location(source, location)
     return {'status': 'ok'}
 
@@ -126,6 +214,129 @@ def update_source(source):
     client.put(entity)
     return entity
 
+@app.route('/api/v1/source/<source>/command', methods=['POST'])
+def post_command(source):
+    body = request.get_json()
+    if body.get('command') not in COMMANDS:
+        return {'status': 'error', 'error': 'unknown command'}, 400
+
+    entity = datastore.Entity(key=client.key('command'))
+    entity.update({
+        'target': source,
+        'command': body['command'],
+        'section': body.get('section', ''),
+        'name': body.get('name', ''),
+        'nonce': random.getrandbits(32),
+        'timestamp': int(time.time()),
+        'acked': False,
+        'ok': None,
+    })
+    client.put(entity)
+    return entity
+
+@app.route('/api/v1/commands/pending', methods=['GET'])
+def pending_commands():
+    query = client.query(kind='command')
+    query.add_filter('acked', '=', False)
+    since = int(time.time()) - COMMAND_VALIDITY_SECONDS
+    return [c for c in query.fetch() if c['timestamp'] >= since]
+
+@app.route('/api/v1/source/<source>/command/<int:nonce>/ack', methods=['POST'])
+def ack_command(source, nonce):
+    body = request.get_json()
+    query = client.query(kind='command')
+    query.add_filter('target', '=', source)
+    query.add_filter('nonce', '=', nonce)
+    for entity in query.fetch():
+        entity.update({'acked': True, 'ok': bool(body.get('ok'))})
+        client.put(entity)
+    return {'status': 'ok'}
+
+@app.route('/api/v1/source/<source>/event', methods=['POST'])
+def post_event(source):
+    body = request.get_json()
+    entity = datastore.Entity(key=client.key('event', parent=client.key('source', source)))
+    entity.update({
+        'event': body['event'],
+        'timestamp': int(body['timestamp']),
+    })
+    client.put(entity)
+    return {'status': 'ok'}
+
+@app.route('/api/v1/source/<source>/trace', methods=['POST'])
+def post_trace(source):
+    """The last wakes of a tracker, sent on a resend_trace command, see `trace` in morty-rs."""
+    body = request.get_json()
+    entity = datastore.Entity(key=client.key('trace', parent=client.key('source', source)),
+                              exclude_from_indexes=['records'])
+    entity.update({
+        'records': body['records'],
+        'received': int(time.time()),
+    })
+    client.put(entity)
+    return {'status': 'ok'}
+
+@app.route('/api/v1/source/<source>/hello', methods=['POST'])
+def post_hello(source):
+    """A device booted, see `hello` in morty-rs."""
+    body = request.get_json()
+    parent_key = client.key('source', source)
+    parent_entity = client.get(parent_key)
+    if parent_entity is None:
+        parent_entity = datastore.Entity(key=parent_key)
+    parent_entity.update({
+        'id': source,
+        'role': body['role'],
+        'firmware_version': body['firmware_version'],
+        'capabilities': body['capabilities'],
+        'last_hello': int(body['timestamp']),
+    })
+    # The gateway has no configuration version
+    if body.get('config_version'):
+        parent_entity['config_version'] = int(body['config_version'])
+    # Every hello has the name, empty when the device has none. Older gateways leave it out.
+    if 'name' in body:
+        parent_entity['name'] = body['name'] or None
+    client.put(parent_entity)
+
+    entity = datastore.Entity(key=client.key('hello', parent=parent_key))
+    entity.update({
+        'timestamp': int(body['timestamp']),
+        'device_id': body['device_id'],
+        'firmware_version': body['firmware_version'],
+        'reset_reason': body['reset_reason'],
+    })
+    client.put(entity)
+    return {'status': 'ok'}
+
+@app.route('/api/v1/ota/manifest', methods=['PUT'])
+def put_ota_manifest():
+    # Signed with sign_manifest.py of the gateway, the gateways check the signature
+    entity = datastore.Entity(key=client.key('ota', 'manifest'), exclude_from_indexes=['text'])
+    entity.update({'text': request.get_data(as_text=True)})
+    client.put(entity)
+    return {'status': 'ok'}
+
+@app.route('/api/v1/gateway/<gateway>', methods=['PUT'])
+def update_gateway(gateway):
+    # e.g. {"pinned_version": "0.2.0"} to keep a gateway on a version, null to let it follow the
+    # manifest
+    properties = request.get_json()
+    entity = client.get(client.key('gateway', gateway)) or datastore.Entity(
+        key=client.key('gateway', gateway))
+    entity.update(properties)
+    client.put(entity)
+    return entity
+
+@app.route('/api/v1/gateway/<gateway>/ota', methods=['GET'])
+def gateway_ota(gateway):
+    manifest = client.get(client.key('ota', 'manifest'))
+    entity = client.get(client.key('gateway', gateway))
+    return {
+        'manifest': manifest['text'] if manifest else None,
+        'pinned_version': entity.get('pinned_version') if entity else None,
+    }
+
 @app.route('/')
 def root():
     return send_from_directory('static', "index.html")
@@ -0,0 +1,279 @@
+//! Settings of the beacon that can differ between installations, see `morty_rs::config`. They
+//! are loaded from NVS once at boot, anything that isn't stored there gets the default below.
+//! Settings we couldn't use are logged with the stats.
+
+use morty_rs::config::check_schema;
+use morty_rs::config::load_nvs;
+use morty_rs::config::Kind;
+use morty_rs::config::Setting;
+use morty_rs::config::Settings;
+use morty_rs::config::Value;
+use morty_rs::duty::DutyPolicy;
+use morty_rs::naming::valid_name;
+use morty_rs::naming::MAX_NAME_LEN;
+use morty_rs::naming::NAME_KEY;
+use std::borrow::Cow;
+use std::sync::Mutex;
+use std::time::Duration;
+
+const SSID: &str = "ssid";
+const PASS: &str = "pass";
+const TX_POWER_DBM: &str = "tx_power_dbm";
+const STATS_LOG_INTERVAL: &str = "stats_interval";
+const UART_FRAMING_ERROR_THRESHOLD: &str = "framing_errors";
+const RADIO_SILENCE_TIMEOUT: &str = "silence_timeout";
+const RADIO_MAX_REINITS: &str = "max_reinits";
+const RELAY_DELAY_BUDGET: &str = "relay_budget";
+const HEAP_CHECK_INTERVAL: &str = "heap_interval";
+const POWER_CHECK_INTERVAL: &str = "power_interval";
+const BATTERY_BEACON_PRESENT_INTERVAL: &str = "batt_present";
+const DUTY_CYCLE: &str = "duty_cycle";
+const DUTY_PERIOD: &str = "duty_period";
+const DUTY_LISTEN: &str = "duty_listen";
+const DUTY_LISTEN_LOW_BATTERY: &str = "duty_listen_low";
+const DUTY_LOW_BATTERY_VOLTS: &str = "duty_low_batt";
+
+const fn secs(secs: u64) -> Duration {
+    Duration::from_secs(secs)
+}
+
+pub static SCHEMA: &[Setting] = &[
+    // The wifi is only used to set the clock at boot, see `main`
+    Setting::new(
+        SSID,
+        Kind::Str { max_len: 32 },
+        Value::Str(Cow::Borrowed("SandyWalty")),
+    ),
+    Setting::new(
+        PASS,
+        Kind::Str { max_len: 64 },
+        Value::Str(Cow::Borrowed("EddieVedder7")),
+    )
+    .secret(),
+    Setting::new(
+        TX_POWER_DBM,
+        Kind::F32 {
+            min: 2.0,
+            max: 20.0,
+        },
+        Value::F32(20.0),
+    ),
+    Setting::new(
+        STATS_LOG_INTERVAL,
+        Kind::Duration {
+            min: secs(10),
+            max: secs(24 * 60 * 60),
+        },
+        Value::Duration(secs(60)),
+    ),
+    // Warn about the wiring when there are more framing errors than this in a minute
+    Setting::new(
+        UART_FRAMING_ERROR_THRESHOLD,
+        Kind::U32 {
+            min: 1,
+            max: 10_000,
+        },
+        Value::U32(10),
+    ),
+    // Reinitialize the radio when we haven't received anything for this long, reboot when that
+    // didn't help after a couple of tries.
+    Setting::new(
+        RADIO_SILENCE_TIMEOUT,
+        Kind::Duration {
+            min: secs(60),
+            max: secs(24 * 60 * 60),
+        },
+        Value::Duration(secs(30 * 60)),
+    ),
+    Setting::new(
+        RADIO_MAX_REINITS,
+        Kind::U32 { min: 0, max: 10 },
+        Value::U32(2),
+    ),
+    // Relays that spent longer than this in the beacons before they got to us are dropped when
+    // we've seen a newer fix of their source already, see `morty_rs::relay`
+    Setting::new(
+        RELAY_DELAY_BUDGET,
+        Kind::Duration {
+            min: secs(1),
+            max: secs(60),
+        },
+        Value::Duration(secs(5)),
+    ),
+    Setting::new(
+        HEAP_CHECK_INTERVAL,
+        Kind::Duration {
+            min: secs(1),
+            max: secs(60),
+        },
+        Value::Duration(secs(5)),
+    ),
+    // How often we check whether we run on the backup battery, see `power`
+    Setting::new(
+        POWER_CHECK_INTERVAL,
+        Kind::Duration {
+            min: secs(1),
+            max: secs(60),
+        },
+        Value::Duration(secs(5)),
+    ),
+    // How often we send beacon present messages while on battery
+    Setting::new(
+        BATTERY_BEACON_PRESENT_INTERVAL,
+        Kind::Duration {
+            min: secs(10),
+            max: secs(10 * 60),
+        },
+        Value::Duration(secs(60)),
+    ),
+    // Turn the radio off outside a listening window, see `morty_rs::duty`. This is for repeaters
+    // on a battery, a beacon with a gateway on its UART must listen all the time.
+    Setting::new(DUTY_CYCLE, Kind::Bool, Value::Bool(false)),
+    Setting::new(
+        DUTY_PERIOD,
+        Kind::Duration {
+            min: secs(10),
+            max: secs(60 * 60),
+        },
+        Value::Duration(secs(60)),
+    ),
+    Setting::new(
+        DUTY_LISTEN,
+        Kind::Duration {
+            min: secs(1),
+            max: secs(60 * 60),
+        },
+        Value::Duration(secs(10)),
+    ),
+    // How long we listen when the battery is below `DUTY_LOW_BATTERY_VOLTS`
+    Setting::new(
+        DUTY_LISTEN_LOW_BATTERY,
+        Kind::Duration {
+            min: secs(1),
+            max: secs(60 * 60),
+        },
+        Value::Duration(secs(5)),
+    ),
+    Setting::new(
+        DUTY_LOW_BATTERY_VOLTS,
+        Kind::F32 { min: 3.0, max: 4.2 },
+        Value::F32(3.5),
+    ),
+    // What the beacon calls itself, see `morty_rs::naming`, empty for no name
+    Setting::new(
+        NAME_KEY,
+        Kind::Str {
+            max_len: MAX_NAME_LEN,
+        },
+        Value::Str(Cow::Borrowed("")),
+    ),
+];
+
+/// What the beacon runs with.
+pub struct Config {
+    pub ssid: String,
+    pub pass: String,
+    pub tx_power_dbm: f32,
+    pub stats_log_interval: Duration,
+    pub uart_framing_error_threshold: usize,
+    pub radio_silence_timeout: Duration,
+    pub radio_max_reinits: u32,
+    pub relay_delay_budget: Duration,
+    pub heap_check_interval: Duration,
+    pub power_check_interval: Duration,
+    pub battery_beacon_present_interval: Duration,
+    /// When to listen, None to listen all the time
+    pub duty_cycle: Option<DutyPolicy>,
+    /// Empty when the beacon has no name
+    pub name: String,
+    /// Every setting with where it came from, and the stored values we couldn't use
+    pub settings: Settings,
+}
+
+impl Config {
+    fn new(settings: Settings) -> Self {
+        Self {
+            ssid: settings.str(SSID).to_string(),
+            pass: settings.str(PASS).to_string(),
+            tx_power_dbm: settings.f32(TX_POWER_DBM),
+            stats_log_interval: settings.duration(STATS_LOG_INTERVAL),
+            uart_framing_error_threshold: settings.u32(UART_FRAMING_ERROR_THRESHOLD) as usize,
+            radio_silence_timeout: settings.duration(RADIO_SILENCE_TIMEOUT),
+            radio_max_reinits: settings.u32(RADIO_MAX_REINITS),
+            relay_delay_budget: settings.duration(RELAY_DELAY_BUDGET),
+            heap_check_interval: settings.duration(HEAP_CHECK_INTERVAL),
+            power_check_interval: settings.duration(POWER_CHECK_INTERVAL),
+            battery_beacon_present_interval: settings.duration(BATTERY_BEACON_PRESENT_INTERVAL),
+            duty_cycle: settings.bool(DUTY_CYCLE).then(|| DutyPolicy {
+                period: settings.duration(DUTY_PERIOD),
+                window: settings.duration(DUTY_LISTEN),
+                low_battery_window: settings.duration(DUTY_LISTEN_LOW_BATTERY),
+                low_battery_volts: settings.f32(DUTY_LOW_BATTERY_VOLTS),
+            }),
+            name: valid_name(settings.str(NAME_KEY)),
+            settings,
+        }
+    }
+}
+
+// The configuration, once it's loaded
+static CONFIG: Mutex<Option<&'static Config>> = Mutex::new(None);
+
+/// Load the configuration from NVS, which has to be initialized. Invalid values are logged and
+/// replaced by their default.
+pub fn load() -> Result<&'static Config, anyhow::Error> {
+    check_schema(SCHEMA)?;
+    let settings = load_nvs(SCHEMA);
+    settings.log_invalid();
+    let config: &'static Config = Box::leak(Box::new(Config::new(settings)));
+    *CONFIG.lock().unwrap() = Some(config);
+    Ok(config)
+}
+
+/// The configuration. Panics when it wasn't loaded yet.
+pub fn config() -> &'static Config {
+    CONFIG.lock().unwrap().expect("Configuration not loaded")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_schema_checks_out() {
+        check_schema(SCHEMA).unwrap();
+    }
+
+    #[test]
+    fn the_defaults_make_a_config() {
+        let config = Config::new(Settings::load(SCHEMA, |_| Ok(None)));
+        assert!(config.settings.invalid().is_empty());
+        assert_eq!(config.name, "");
+    }
+
+    fn config_with_name(name: &str) -> Config {
+        Config::new(Settings::load(SCHEMA, |key| match key {
+            NAME_KEY => Ok(Some(name.to_string())),
+            _ => Ok(None),
+        }))
+    }
+
+    #[test]
+    fn a_stored_name_is_used() {
+        let config = config_with_name("trailer-1");
+        assert!(config.settings.invalid().is_empty());
+        assert_eq!(config.name, "trailer-1");
+    }
+
+    #[test]
+    fn invalid_names_are_ignored() {
+        // Too long for the setting
+        let config = config_with_name("0123456789abcdefg");
+        assert_eq!(config.settings.invalid()[0].key, NAME_KEY);
+        assert_eq!(config.name, "");
+        // Short enough, but not a name
+        let config = config_with_name("trailer 1");
+        assert!(config.settings.invalid().is_empty());
+        assert_eq!(config.name, "");
+    }
+}
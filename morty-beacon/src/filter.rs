@@ -0,0 +1,79 @@
+use anyhow::anyhow;
+use morty_rs::comm::parse_mac;
+
+/// Whether the list of sources names the ones to relay or the ones to ignore.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FilterMode {
+    Allow,
+    Deny,
+}
+
+/// A source MAC, or all sources with the same OUI (the first three bytes), written as
+/// `aa:bb:cc:*`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum MacPattern {
+    Exact([u8; 6]),
+    Oui([u8; 3]),
+}
+
+impl MacPattern {
+    fn parse(s: &str) -> Option<Self> {
+        match s.strip_suffix(":*") {
+            Some(oui) => {
+                let mut bytes = [0u8; 3];
+                let mut parts = oui.split(':');
+                for b in bytes.iter_mut() {
+                    *b = u8::from_str_radix(parts.next()?, 16).ok()?;
+                }
+                parts.next().is_none().then_some(MacPattern::Oui(bytes))
+            }
+            None => parse_mac(s).map(MacPattern::Exact),
+        }
+    }
+
+    fn matches(&self, mac: &[u8]) -> bool {
+        match self {
+            MacPattern::Exact(m) => mac == m,
+            MacPattern::Oui(oui) => mac.len() == 6 && mac[..3] == oui[..],
+        }
+    }
+}
+
+/// Decides which sources the beacon relays. An empty list relays everything, whatever the mode.
+pub struct SourceFilter {
+    mode: FilterMode,
+    patterns: Vec<MacPattern>,
+}
+
+impl SourceFilter {
+    pub fn new(mode: FilterMode, list: &[&str]) -> Result<Self, anyhow::Error> {
+        let mut filter = Self {
+            mode,
+            patterns: Vec::new(),
+        };
+        for s in list {
+            filter.add(s)?;
+        }
+        Ok(filter)
+    }
+
+    /// Add a MAC or OUI pattern to the list.
+    pub fn add(&mut self, s: &str) -> Result<(), anyhow::Error> {
+        let pattern = MacPattern::parse(s).ok_or_else(|| anyhow!("Invalid MAC pattern: {s}"))?;
+        self.patterns.push(pattern);
+        Ok(())
+    }
+
+    /// Whether frames from `mac` should be relayed.
+    pub fn allows(&self, mac: &[u8]) -> bool {
+        if self.patterns.is_empty() {
+            return true;
+        }
+        let listed = self.patterns.iter().any(|p| p.matches(mac));
+        match self.mode {
+            FilterMode::Allow => listed,
+            FilterMode::Deny => !listed,
+        }
+    }
+}
@@ -1,5 +1,16 @@
+mod config;
+mod filter;
+mod power;
+mod presence;
+mod silence;
+mod stats;
+mod transmit;
+mod uart_writer;
+
+use anyhow::bail;
 use base64::engine::general_purpose;
 use base64::Engine;
+use config::config;
 use embedded_svc::wifi::ClientConfiguration;
 use embedded_svc::wifi::Configuration;
 use esp_idf_hal::cpu::Core;
@@ -10,126 +21,468 @@ use esp_idf_hal::uart;
 use esp_idf_hal::uart::Uart;
 use esp_idf_hal::uart::UartDriver;
 use esp_idf_svc::eventloop::EspSystemEventLoop;
-use esp_idf_svc::sntp::SyncStatus;
 use esp_idf_svc::systime::EspSystemTime;
+use esp_idf_svc::wifi::EspWifi;
 use esp_idf_sys as _;
 use esp_idf_sys::esp;
+use filter::FilterMode;
+use filter::SourceFilter;
 use log::*;
+use morty_rs::baud::BaudChange;
+use morty_rs::baud::BaudLink;
+use morty_rs::baud::DEFAULT_BAUD;
+use morty_rs::budget::check_frame_budget;
+use morty_rs::clock::clock_status;
+use morty_rs::clock::correct_system_time;
+use morty_rs::clock::set_clock_status;
+use morty_rs::clock::Clock;
 use morty_rs::comm::broadcast_data;
+use morty_rs::comm::broadcast_encoded;
 use morty_rs::comm::broadcast_msg;
 use morty_rs::comm::decode_msg;
 use morty_rs::comm::encode_msg;
-use morty_rs::comm::esp_now_init;
+use morty_rs::comm::encode_relay;
+use morty_rs::comm::esp_now_init_with_encryption;
+use morty_rs::comm::esp_now_reinit;
 use morty_rs::comm::mac_to_string;
+use morty_rs::comm::own_mac;
+use morty_rs::comm::parse_mac;
+use morty_rs::comm::reframe_relay;
+use morty_rs::comm::set_frame_format;
+use morty_rs::comm::set_tx_power_dbm;
 use morty_rs::comm::start_wifi;
+use morty_rs::comm::tx_power_dbm;
+use morty_rs::comm::CommError;
+use morty_rs::comm::EncryptedPeer;
+use morty_rs::comm::Encryption;
+use morty_rs::comm::FrameFormat;
+use morty_rs::comm::Priority;
+use morty_rs::comm::RecvDispatcher;
+use morty_rs::comm::RecvFrame;
+use morty_rs::comm::SeqDedup;
+use morty_rs::comm::ESP_NOW_CHANNEL;
+use morty_rs::comm::UART_FRAME_DELIMITER;
+use morty_rs::comm::UART_HEADER;
+use morty_rs::command;
+use morty_rs::command::CommandHandler;
+use morty_rs::config::ConfigVersion;
+use morty_rs::config::CONFIG_NAMESPACE;
+use morty_rs::duty::ListenSchedule;
+use morty_rs::flashlog;
+use morty_rs::flashlog::EventKind;
+use morty_rs::heap::start_heap_guard;
+use morty_rs::heap::HeapActions;
+use morty_rs::heap::HeapThresholds;
+use morty_rs::hello;
+use morty_rs::hello::HelloLimiter;
+use morty_rs::hello::HELLO_INTERVAL;
 use morty_rs::led::colors;
 use morty_rs::led::Led;
+use morty_rs::ledlog;
+use morty_rs::ledlog::LEDLOG_NAMESPACE;
 use morty_rs::messages::*;
-use morty_rs::utils::set_thread_spawn_configuration;
+use morty_rs::nvs_recovery;
+use morty_rs::nvs_recovery::Criticality;
+use morty_rs::nvs_recovery::Namespace;
+use morty_rs::phase;
+use morty_rs::relay::add_delay;
+use morty_rs::relay::HopBudget;
+use morty_rs::relay::Verdict;
+use morty_rs::status::OrFatal;
+use morty_rs::status::Status;
+use morty_rs::timesync;
+use morty_rs::timesync::Correction;
+use morty_rs::timesync::Discipline;
+use morty_rs::timesync::DEFAULT_POLICY;
+use morty_rs::uart_errors::monitor_uart_errors;
+use morty_rs::utils::boot_complete;
+use morty_rs::utils::spawn_thread;
+use morty_rs::utils::sync_sntp;
+use morty_rs::utils::Backoff;
+use morty_rs::utils::PeriodicSet;
+use morty_rs::utils::ThreadConfig;
 use morty_rs::BEACON_PRESENT_INTERVAL_SECONDS;
-use std::sync::mpsc::sync_channel;
+use power::PowerSense;
+use presence::PresenceResponder;
+use silence::SilenceAction;
+use silence::SilenceWatchdog;
+use stats::BeaconStats;
+use stats::STATS;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
 use std::sync::mpsc::Receiver;
+use std::sync::mpsc::RecvTimeoutError;
 use std::sync::Arc;
-use std::time::Duration; // If using the `binstart` feature of `esp-idf-sys`, always keep this module imported
+use std::sync::Mutex;
+use std::time::Duration;
+use transmit::TransmitAction;
+use transmit::TransmitWatchdog;
+use uart_writer::UartWriter; // If using the `binstart` feature of `esp-idf-sys`, always keep this module imported
 
-const SSID: &str = "SandyWalty";
-const PASS: &str = "EddieVedder7";
+// The settings that differ between installations, e.g. the wifi and the radio timeouts, are in
+// `config`
 
 const LED_BRIGHTNESS: u8 = 10;
+const EVENT_LOG_LEVEL: LevelFilter = LevelFilter::Info;
+// Layout of the frames we send. Switch to `FrameFormat::Header` once the whole fleet
+// understands it.
+const FRAME_FORMAT: FrameFormat = FrameFormat::Legacy;
+const SNTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Number of received frames that can wait for the recv thread. Anything beyond that is dropped.
+const RECV_QUEUE_SIZE: usize = 8;
+
+// Frames going to the gateway are packed into lines of at most this many bytes, and wait at most
+// this long for other frames to join them.
+const UART_MAX_LINE_LEN: usize = 512;
+const UART_MAX_DELAY: Duration = Duration::from_millis(100);
+// Frames held while the gateway is in maintenance, see `UartWriter`. Older ones are dropped.
+const UART_HOLD_FRAMES: usize = 64;
+// How often we check for commands from the gateway
+const UART_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// ESP-NOW encryption. Use `Encryption::Transitional` while rolling out keys over the fleet, so
+// nodes that don't have them yet can still be heard, and `Encryption::Required` after that.
+const ENCRYPTION: Encryption = Encryption::Off;
+#[allow(dead_code)]
+const ESP_NOW_PMK: [u8; 16] = *b"morty-pmk-000000";
+#[allow(dead_code)]
+const ENCRYPTED_PEERS: &[EncryptedPeer] = &[];
+
+// Only relay the sources in the list (Allow) or everything but the sources in the list (Deny).
+// Entries are MACs or OUIs like "aa:bb:cc:*". An empty list relays everything.
+const SOURCE_FILTER_MODE: FilterMode = FilterMode::Allow;
+const SOURCE_FILTER_LIST: &[&str] = &[];
+
+// Trackers we haven't heard for this long get an extra beacon present message, at most once every
+// PRESENCE_MIN_INTERVAL. It goes out after a random delay, so it doesn't collide with the relay.
+const PRESENCE_FORGET_AFTER: Duration = Duration::from_secs(5 * 60);
+const PRESENCE_MIN_INTERVAL: Duration = Duration::from_secs(10);
+const PRESENCE_MIN_DELAY_MS: u32 = 20;
+const PRESENCE_MAX_DELAY_MS: u32 = 100;
+
+// Sources we haven't heard from for this long are forgotten by the latency budget of relays, see
+// `morty_rs::relay`
+const RELAY_FORGET_AFTER: Duration = Duration::from_secs(5 * 60);
+// Fixes we remember to drop copies of, see `SeqDedup`
+const SEQ_DEDUP_CAPACITY: usize = 64;
+
+// A beacon present message that fails to go out is retried after TRANSMIT_RETRY_DELAY, doubling
+// up to the interval of the messages. When they keep failing for TRANSMIT_FAILURE_TIMEOUT, the
+// radio is reinitialized.
+const TRANSMIT_RETRY_DELAY: Duration = Duration::from_millis(100);
+const TRANSMIT_FAILURE_TIMEOUT: Duration = Duration::from_secs(3 * 60);
+
+// The clocks of other beacons in their beacon present messages are passed on to the gateway, at
+// most once per beacon per CLOCK_REPORT_INTERVAL
+const CLOCK_REPORT_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+// Shed load when the heap runs low, reboot when it stays critically low
+const HEAP_THRESHOLDS: HeapThresholds = HeapThresholds {
+    warning: 16 * 1024,
+    critical: 8 * 1024,
+    hysteresis: 2 * 1024,
+    critical_timeout: Duration::from_secs(30),
+};
+
+// Beacons with a backup battery sense external power, see `vbus_sense_pin` in main. While on
+// battery we keep relaying, but send beacon present messages less often, stop logging stats and
+// dim the LED.
+// Number of readings in a row needed to believe the power source changed
+const POWER_CHANGE_READINGS: u32 = 3;
+const BATTERY_LED_BRIGHTNESS: u8 = 1;
+// What we keep in NVS. Wifi doesn't use NVS on the beacon, but the settings can hold its password.
+const NVS_NAMESPACES: &[Namespace] = &[
+    Namespace {
+        name: LEDLOG_NAMESPACE,
+        criticality: Criticality::Stats,
+    },
+    Namespace {
+        name: CONFIG_NAMESPACE,
+        criticality: Criticality::Credentials,
+    },
+];
+
+// Every thread we spawn, by priority. The recv thread has the radio and the UART to the gateway
+// to itself on core 1, the beacon present messages come right after. The LED only shows what the
+// others do, so it gets whatever time is left.
+const RECV_THREAD: ThreadConfig = ThreadConfig::new("recv-thread\0", 8196, 15, Some(Core::Core1));
+const BEACON_THREAD: ThreadConfig = ThreadConfig::new("beacon-thread\0", 4196, 12, None);
+const UART_ERRORS_THREAD: ThreadConfig = ThreadConfig::new("uart-errors\0", 4096, 6, None);
+const HEAP_GUARD_THREAD: ThreadConfig = ThreadConfig::new("heap-guard\0", 4096, 6, None);
+const LED_THREAD: ThreadConfig = ThreadConfig::new("led-thread\0", 4196, 2, None);
+
+// Timers
+const STATS_LOG: &str = "stats_log";
+const POWER_CHECK: &str = "power_check";
+
+// States recorded in the event log
+const STATE_RADIO_REINIT: u32 = 1;
+const STATE_ON_BATTERY: u32 = 2;
+const STATE_EXTERNAL_POWER: u32 = 3;
 
-// Struct that is used to pass data from the recv callback to the thread that handles the data
-struct RecvData {
-    src: Vec<u8>,
-    data: Vec<u8>,
+// Time (in seconds since boot) of the last received ESP-NOW frame, 0 if none was received.
+static LAST_RECV_SECS: AtomicU32 = AtomicU32::new(0);
+// Set while the heap is low. Lines to the gateway are written right away instead of batched.
+static SHED_LOAD: AtomicBool = AtomicBool::new(false);
+// Set while we run on the backup battery
+static ON_BATTERY: AtomicBool = AtomicBool::new(false);
+// Voltage of the backup battery in mV, 0 when we don't know. Only read when we duty-cycle the
+// radio, see `listen_schedule`.
+static BATTERY_MV: AtomicU32 = AtomicU32::new(0);
+// The gateway we're attached to, from its pings. Stamped on what we write to the UART, so the
+// backend can tell which gateway a fix went through. Beacons without a gateway leave it empty.
+static GATEWAY_ID: Mutex<String> = Mutex::new(String::new());
+
+// What the beacon does when the heap runs low
+struct BeaconHeapActions;
+
+impl HeapActions for BeaconHeapActions {
+    fn shed_load(&mut self, shed: bool) {
+        SHED_LOAD.store(shed, Ordering::Relaxed);
+    }
+
+    fn flush(&mut self) {
+        STATS.log();
+    }
 }
 
 fn main() -> anyhow::Result<()> {
     esp_idf_svc::log::EspLogger::initialize_default();
+    set_frame_format(FRAME_FORMAT);
+    if let Err(e) = check_frame_budget() {
+        error!("{e}");
+    }
 
-    let sysloop = EspSystemEventLoop::take()?;
     let peripherals = Peripherals::take().unwrap();
     let pins = peripherals.pins;
 
-    // Configure the LED
+    // Configure the LED before anything else, so it can show what goes wrong after this
     let mut led = Led::new();
-    led.start(pins.gpio18.into(), pins.gpio17.into())?;
+    led.start(pins.gpio18.into(), pins.gpio17.into(), LED_THREAD)
+        .or_fatal(Status::Startup, &led);
     led.set_color(colors::DARK_ORANGE, LED_BRIGHTNESS)?;
 
+    // The event log is nice to have, so we carry on without it
+    phase!("flashlog", {
+        if let Err(e) = flashlog::start(EVENT_LOG_LEVEL) {
+            error!("Unable to start event log: {e}");
+        }
+    });
+
+    let sysloop = EspSystemEventLoop::take().or_fatal(Status::Startup, &led);
+    // Initializes NVS, where the settings are kept. When it's broken beyond repair, we run with
+    // the default settings.
+    let _nvs = nvs_recovery::take_partition(NVS_NAMESPACES).or_fatal(Status::Startup, &led);
+    ledlog::restore();
+    ledlog::install_panic_hook();
+    let config = config::load().or_fatal(Status::Startup, &led);
+
     // For the beacon, we start in client mode and connect to the wifi network. This is so we can
     // update the system time via SNTP. Once we have the time, we disconnect from the wifi network
     // and switch to ESP-NOW mode, since regular wifi and ESP-NOW cannot be used at the same time.
-    let mut wifi = start_wifi(peripherals.modem, sysloop, SSID, PASS)?;
+    // Without SNTP, the time beacons of the gateway set the time, see `morty_rs::timesync`.
+    let mut wifi = phase!("wifi_connect", {
+        start_wifi(peripherals.modem, sysloop, &config.ssid, &config.pass)
+            .or_fatal(Status::Wifi, &led)
+    });
 
     led.set_color(colors::ORANGE, LED_BRIGHTNESS)?;
-    update_sntp()?;
+    phase!("sntp", {
+        if let Err(e) = sync_sntp(SNTP_TIMEOUT) {
+            warn!("{e}, waiting for a time beacon");
+        }
+    });
 
-    // Disconnect from wifi and setup for ESP-NOW
-    wifi.disconnect()?;
-    wifi.stop()?;
-    wifi.set_configuration(&Configuration::Client(ClientConfiguration {
-        ..Default::default()
-    }))?;
+    phase!("esp_now_switch", {
+        switch_to_esp_now(&mut wifi).or_fatal(Status::Radio, &led)
+    });
+    let tx_power = set_tx_power_dbm(config.tx_power_dbm).or_fatal(Status::Radio, &led);
 
-    esp!(unsafe {
-        esp_idf_sys::esp_wifi_set_protocol(
-            esp_idf_sys::wifi_interface_t_WIFI_IF_STA,
-            esp_idf_sys::WIFI_PROTOCOL_LR.try_into().unwrap(),
+    // Set these to the pins that sense external power and the backup battery, if the board has
+    // them, e.g. `Some(pins.gpio33.into())` and `Some(pins.gpio10)`
+    let vbus_sense_pin: Option<gpio::AnyInputPin> = None;
+    let vbat_sense_pin: Option<gpio::Gpio10> = None;
+    let power = vbus_sense_pin.map(|pin| {
+        PowerSense::new(
+            pin,
+            vbat_sense_pin.map(|vbat| (vbat, peripherals.adc1)),
+            POWER_CHANGE_READINGS,
         )
-    })?;
-
-    wifi.start()?;
+        .or_fatal(Status::Startup, &led)
+    });
 
     led.set_color(colors::GREEN, LED_BRIGHTNESS)?;
 
-    // Channel for sending data to the recv thread
-    let (recv_data_sender, recv_data_receiver) = sync_channel::<RecvData>(2);
-
-    // Callback function for receiving data. This is executed on core0 (because wifi is started here),
-    // so we keep this as short as possible. We send the data to the recv thread via a channel.
-    let esp_now_recv_cb = move |src: &[u8], data: &[u8]| {
-        info!("Data recv from {}, len {}", mac_to_string(src), data.len());
-        let recv_data = RecvData {
-            src: src.to_vec(),
-            data: data.to_vec(),
-        };
-        recv_data_sender.send(recv_data).unwrap();
-    };
+    // The receive callback is executed on core0 (because wifi is started here), so the filter is
+    // kept as short as possible. It doesn't log, that goes over the UART and would hold up the wifi
+    // stack when it's busiest, the stats log has the counts. Everything that passes goes to the
+    // recv thread.
+    let mut dispatcher = RecvDispatcher::new().with_filter(|src: &[u8], _data: &[u8]| {
+        LAST_RECV_SECS.store(now_monotonic().as_secs() as u32, Ordering::Relaxed);
+        BeaconStats::inc(&STATS.frames_received);
+        if !ENCRYPTION.accepts(src) {
+            BeaconStats::inc(&STATS.frames_rejected);
+            return false;
+        }
+        if !ENCRYPTION.is_encrypted_peer(src) {
+            BeaconStats::inc(&STATS.frames_plaintext);
+        }
+        true
+    });
+    let recv_data_receiver =
+        dispatcher.register("recv", None, RECV_QUEUE_SIZE, &STATS.frames_dropped);
+    let dispatcher = dispatcher.freeze();
 
     // Initialize ESP-NOW and register the callback
-    let esp_now = Arc::new(esp_now_init());
-    esp_now.register_recv_cb(esp_now_recv_cb).unwrap();
+    let esp_now = Arc::new(esp_now_init_with_encryption(&ENCRYPTION).or_fatal(Status::Radio, &led));
+    dispatcher.attach(&esp_now).or_fatal(Status::Radio, &led);
+
+    let led_handle = led.handle();
+    let beacon_led = led.handle();
+    start_heap_guard(
+        HEAP_THRESHOLDS,
+        config.heap_check_interval,
+        led.handle(),
+        Box::new(BeaconHeapActions),
+        HEAP_GUARD_THREAD,
+    )
+    .or_fatal(Status::Thread, &led_handle);
 
     let beacon_espnow = esp_now.clone();
-    // Spawn the beacon present thread
-    set_thread_spawn_configuration("beacon-thread\0", 4196, 15, None)?;
-    let beacon_thread = std::thread::Builder::new()
-        .stack_size(4196)
-        .spawn(move || loop {
-            let msg = morty_message::Msg::BeaconPresent(BeaconPresentMsg {
-                timestamp: EspSystemTime.now().as_secs() as i64,
+    // Spawn the beacon present thread. This thread also keeps an eye on the radio and tries to
+    // recover it when it stops receiving.
+    let beacon_thread = spawn_thread(BEACON_THREAD, move || {
+        let clock = Clock::new().or_fatal(Status::Startup, &beacon_led);
+        let mut watchdog =
+            SilenceWatchdog::new(config.radio_silence_timeout, config.radio_max_reinits);
+        let mut transmit = TransmitWatchdog::new(
+            TRANSMIT_FAILURE_TIMEOUT,
+            Backoff::new(
+                TRANSMIT_RETRY_DELAY,
+                2,
+                Duration::from_secs(BEACON_PRESENT_INTERVAL_SECONDS),
+            )
+            .with_jitter(),
+        );
+        let mut schedule = PeriodicSet::new();
+        schedule.add(STATS_LOG, config.stats_log_interval);
+        let reinit_radio = || {
+            BeaconStats::inc(&STATS.radio_reinits);
+            STATS.log();
+            match esp_now_reinit(&beacon_espnow, &ENCRYPTION) {
+                Ok(()) => {
+                    dispatcher
+                        .attach(&beacon_espnow)
+                        .or_fatal(Status::Radio, &beacon_led);
+                    set_tx_power_dbm(config.tx_power_dbm).or_fatal(Status::Radio, &beacon_led);
+                }
+                Err(e) => error!("Unable to reinitialize ESP-NOW: {e}"),
+            }
+        };
+        loop {
+            let on_battery = ON_BATTERY.load(Ordering::Relaxed);
+            if schedule.due(STATS_LOG, now_monotonic()) && !on_battery {
+                STATS.log();
+                for name in dispatcher.gone() {
+                    warn!("Recv handler {name} is gone");
+                }
+            }
+
+            // A send that fails, e.g. when ESP-NOW is out of buffers for a moment, is retried
+            let mut error = None;
+            let action = transmit.send(now_monotonic(), || {
+                broadcast_msg(&beacon_present(tx_power), Priority::Routine, &beacon_espnow)
+                    .map_err(|e| error = Some(e))
             });
-            broadcast_msg(&msg, &beacon_espnow).unwrap();
-            std::thread::sleep(Duration::from_secs(BEACON_PRESENT_INTERVAL_SECONDS));
-        })?;
+            STATS
+                .present_failing
+                .store(transmit.consecutive_failures(), Ordering::Relaxed);
+            if let Some(e) = error {
+                BeaconStats::inc(&STATS.present_send_failures);
+                warn!(
+                    "Unable to send beacon present message, {} failures in a row: {e}",
+                    transmit.consecutive_failures()
+                );
+            }
+            if action == TransmitAction::Reinit {
+                error!("Beacon present messages keep failing, reinitializing the radio");
+                flashlog::log_event(EventKind::State {
+                    state: STATE_RADIO_REINIT,
+                });
+                reinit_radio();
+            }
+
+            let last_recv = LAST_RECV_SECS.load(Ordering::Relaxed);
+            if last_recv != 0 {
+                watchdog.received(Duration::from_secs(last_recv as u64));
+            }
+            match watchdog.check(now_monotonic()) {
+                SilenceAction::None => {}
+                SilenceAction::Reinit => {
+                    flashlog::log_event(EventKind::State {
+                        state: STATE_RADIO_REINIT,
+                    });
+                    reinit_radio();
+                }
+                SilenceAction::Reboot => {
+                    BeaconStats::inc(&STATS.radio_reboots);
+                    STATS.log();
+                    error!("Radio still silent after reinitializing, rebooting");
+                    ledlog::save();
+                    unsafe { esp_idf_sys::esp_restart() };
+                }
+            }
+
+            if let TransmitAction::Retry(delay) = action {
+                std::thread::sleep(delay);
+                continue;
+            }
+
+            let interval = if on_battery {
+                config.battery_beacon_present_interval
+            } else {
+                Duration::from_secs(BEACON_PRESENT_INTERVAL_SECONDS)
+            };
+            // Without a wall clock we don't know when our window is, so we keep listening
+            let schedule = match clock.wall() {
+                Some(_) => listen_schedule(),
+                None => ListenSchedule::ALWAYS,
+            };
+            let window_left = schedule.window_left(wall_ms());
+            if window_left >= interval {
+                std::thread::sleep(interval);
+                continue;
+            }
+            // Listen for the rest of the window and turn the radio off until the next one. We
+            // send a beacon present message when we're back, so trackers know we listen.
+            std::thread::sleep(window_left);
+            let off = schedule.until_window(wall_ms());
+            radio_sleep(off, config.tx_power_dbm).or_fatal(Status::Radio, &beacon_led);
+            watchdog.radio_off(off);
+        }
+    })
+    .or_fatal(Status::Thread, &led_handle);
 
     // Spawn the recv thread on core 1
-    set_thread_spawn_configuration("recv-thread\0", 8196, 15, Some(Core::Core1))?;
-    let recv_thread = std::thread::Builder::new()
-        .stack_size(8196)
-        .spawn(move || {
-            recv_data_task(
-                peripherals.uart1,
-                pins.gpio1.into(),
-                pins.gpio0.into(),
-                &esp_now,
-                recv_data_receiver,
-                &mut led,
-            )
-            .unwrap();
-        })?;
+    let recv_thread = spawn_thread(RECV_THREAD, move || {
+        let uart_led = led.handle();
+        recv_data_task(
+            peripherals.uart1,
+            pins.gpio1.into(),
+            pins.gpio0.into(),
+            &esp_now,
+            recv_data_receiver,
+            power,
+            &mut led,
+        )
+        .or_fatal(Status::Uart, &uart_led);
+    })
+    .or_fatal(Status::Thread, &led_handle);
 
-    beacon_thread.join().unwrap();
-    recv_thread.join().unwrap();
+    beacon_thread.join().or_fatal(Status::Thread, &led_handle);
+    recv_thread.join().or_fatal(Status::Thread, &led_handle);
     Ok(())
 }
 
@@ -139,41 +492,146 @@ fn recv_data_task(
     tx: gpio::AnyOutputPin,
     rx: gpio::AnyInputPin,
     esp_now: &esp_idf_svc::espnow::EspNow,
-    recv_data_receiver: Receiver<RecvData>,
+    recv_data_receiver: Receiver<RecvFrame>,
+    mut power: Option<PowerSense>,
     led: &mut Led,
 ) -> Result<(), anyhow::Error> {
-    let uart = uart_init(uart, tx, rx)?;
+    let uart_driver = uart_init(uart, tx, rx)?;
+    monitor_uart_errors(
+        uart_driver.port() as i32,
+        &STATS.uart_errors,
+        config().uart_framing_error_threshold,
+        led.handle(),
+        UART_ERRORS_THREAD,
+    )?;
+    let mut uart = UartWriter::new(
+        uart_driver,
+        UART_MAX_LINE_LEN,
+        UART_MAX_DELAY,
+        UART_HOLD_FRAMES,
+    );
+    let mut commands = CommandHandler::new(own_mac()?, led.handle());
+    let clock = Clock::new()?;
+    let filter = SourceFilter::new(SOURCE_FILTER_MODE, SOURCE_FILTER_LIST)?;
+    let mut presence = PresenceResponder::new(PRESENCE_FORGET_AFTER, PRESENCE_MIN_INTERVAL);
+    let mut hop_budget = HopBudget::new(config().relay_delay_budget, RELAY_FORGET_AFTER);
+    let mut seq_dedup = SeqDedup::new(SEQ_DEDUP_CAPACITY);
+    let mut schedule = PeriodicSet::new();
+    schedule.add(POWER_CHECK, config().power_check_interval);
+    // The gateway decides on the baud rate, we go along, see `morty_rs::baud`
+    let mut baud = BaudLink::new(now_monotonic());
+    // Hellos we pass on, at most one per source per minute
+    let mut hellos = HelloLimiter::new(HELLO_INTERVAL);
+    let mut clock_reports = HelloLimiter::new(CLOCK_REPORT_INTERVAL);
+    let mut discipline = Discipline::new(DEFAULT_POLICY, clock_status().source());
+    send_hello(esp_now, &mut uart, power.is_some())?;
+    boot_complete();
 
     loop {
-        // Wait for data
-        let recv_data = recv_data_receiver.recv().unwrap();
+        if SHED_LOAD.load(Ordering::Relaxed) {
+            uart.flush()?;
+        }
+        for line in uart.read_lines()? {
+            handle_gateway_line(
+                &line,
+                &mut commands,
+                &clock,
+                &mut uart,
+                &mut baud,
+                &mut discipline,
+                esp_now,
+            )?;
+        }
+        match baud.poll(now_monotonic()) {
+            Some(BaudChange::Switched(rate)) => {
+                info!("Switching the UART to {rate} baud");
+                uart.set_baudrate(rate)?;
+            }
+            Some(BaudChange::FellBack { from }) => {
+                warn!("Gateway went quiet at {from} baud, going back to {DEFAULT_BAUD}");
+                uart.set_baudrate(DEFAULT_BAUD)?;
+            }
+            None => {}
+        }
+        if let Some(power) = power.as_mut() {
+            if schedule.due(POWER_CHECK, now_monotonic()) {
+                if let Some(event) = power.check()? {
+                    power_changed(event, esp_now, &mut uart, led)?;
+                }
+                if config().duty_cycle.is_some() {
+                    let volts = power.battery_voltage()?.unwrap_or(0.0);
+                    BATTERY_MV.store((volts * 1000.0) as u32, Ordering::Relaxed);
+                }
+            }
+        }
+        if presence.take_due(now_monotonic()) {
+            info!("Answering a new tracker with a beacon present message");
+            let present = beacon_present(tx_power_dbm()?);
+            if let Err(e) = broadcast_msg(&present, Priority::Routine, esp_now) {
+                BeaconStats::inc(&STATS.present_send_failures);
+                warn!("Unable to answer a new tracker: {e}");
+            }
+        }
+
+        // Wait for data, but make sure pending frames and presence responses go out in time and
+        // we keep an ear on the gateway.
+        let timeout = [
+            uart.time_to_flush(),
+            presence.time_to_respond(now_monotonic()),
+        ]
+        .into_iter()
+        .flatten()
+        .fold(UART_POLL_INTERVAL, Duration::min);
+        let recv_data = match recv_data_receiver.recv_timeout(timeout) {
+            Ok(recv_data) => recv_data,
+            Err(RecvTimeoutError::Timeout) => {
+                uart.flush_if_due()?;
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                uart.flush()?;
+                bail!("Recv channel disconnected");
+            }
+        };
+
+        // Our part of the path delay of what we relay
+        let delay = now_monotonic().saturating_sub(recv_data.received_at);
+        STATS.callback_to_processed.record(delay);
 
         // Decode the mac address and message
         let src = mac_to_string(recv_data.src.as_slice());
+        info!("Data recv from {src}, len {}", recv_data.data.len());
         match decode_msg(&recv_data.data) {
             // If we receive a beacon present message, we forward it to other beacons
             // by wrapping it in a RelayMsg and sending it over ESP-NOW as well as
-            // writing it to UART for the gateway.
+            // writing it to UART for the gateway. The fix goes into the RelayMsg as we received
+            // it, it isn't encoded again.
             Ok(Some(morty_message::Msg::Gps(gps))) => {
                 info!("GPS from {src}: {:?}", gps);
+                if !filter.allows(&recv_data.src) {
+                    BeaconStats::inc(&STATS.frames_filtered);
+                    info!("Not relaying {src}");
+                    continue;
+                }
                 let now = EspSystemTime.now().as_secs() as i64;
+                presence.heard(&recv_data.src, now_monotonic(), presence_delay());
+                hop_budget.heard(&src, gps.boot_id, gps.seq, now_monotonic());
+                if seq_dedup.seen(&src, (gps.boot_id, gps.seq)) {
+                    BeaconStats::inc(&STATS.duplicates_dropped);
+                    info!("Not relaying another copy of {} from {src}", gps.seq);
+                    continue;
+                }
 
-                let relay_msg = RelayMsg {
-                    timestamp: now,
-                    src,
-                    msg: Some(morty_rs::messages::relay_msg::Msg::Gps(gps)),
-                };
-
-                let data = encode_msg(&morty_message::Msg::Relay(relay_msg));
+                let path_delay_ms = add_delay(0, delay);
 
                 // Broadcast over ESP-NOW
-                broadcast_data(&data, esp_now)?;
+                broadcast_relay(&src, now, path_delay_ms, &recv_data.data, esp_now);
 
-                // Send over UART
-                uart_write(&uart, &data)?;
+                // Send over UART, with the gateway we're attached to
+                push_relay(&mut uart, &src, now, path_delay_ms, &recv_data.data)?;
                 led.blink_color(
                     colors::PURPLE,
-                    LED_BRIGHTNESS,
+                    led_brightness(),
                     Duration::from_millis(300),
                     2,
                 )?;
@@ -183,22 +641,137 @@ fn recv_data_task(
             // write it to UART for the gateway.
             Ok(Some(morty_message::Msg::Relay(relay))) => {
                 info!("Relay from {src}: {:?}", relay);
-                let data = encode_msg(&morty_message::Msg::Relay(relay));
-                uart_write(&uart, &data)?;
+                if !parse_mac(&relay.src).map_or(true, |mac| filter.allows(&mac)) {
+                    BeaconStats::inc(&STATS.frames_filtered);
+                    info!("Not relaying {}", relay.src);
+                    continue;
+                }
+                if matches!(relay.msg, Some(relay_msg::Msg::Hello(_)))
+                    && !hellos.allow(&relay.src, now_monotonic())
+                {
+                    info!("Not relaying another hello of {}", relay.src);
+                    continue;
+                }
+                if let Some(relay_msg::Msg::Gps(gps)) = &relay.msg {
+                    if seq_dedup.seen(&relay.src, (gps.boot_id, gps.seq)) {
+                        BeaconStats::inc(&STATS.duplicates_dropped);
+                        info!(
+                            "Not relaying another copy of {} from {}",
+                            gps.seq, relay.src
+                        );
+                        continue;
+                    }
+                }
+                let path_delay_ms = match &relay.msg {
+                    Some(relay_msg::Msg::Gps(gps)) => match hop_budget.check(
+                        &relay.src,
+                        gps.boot_id,
+                        gps.seq,
+                        relay.path_delay_ms,
+                        delay,
+                        now_monotonic(),
+                    ) {
+                        Verdict::Forward(path_delay_ms) => path_delay_ms,
+                        Verdict::Drop => {
+                            BeaconStats::inc(&STATS.relays_over_budget);
+                            info!(
+                                "Not relaying {} after {} ms, there is a newer fix",
+                                relay.src, relay.path_delay_ms
+                            );
+                            continue;
+                        }
+                    },
+                    _ => add_delay(relay.path_delay_ms, delay),
+                };
+                match reframe_relay(&recv_data.data, path_delay_ms, &gateway_hint()) {
+                    Ok(line) => uart.push(&line)?,
+                    Err(e) => {
+                        relay_failed(&relay.src, &e);
+                        continue;
+                    }
+                }
                 led.blink_color(
                     colors::YELLOW,
-                    LED_BRIGHTNESS,
+                    led_brightness(),
                     Duration::from_millis(300),
                     2,
                 )?;
             }
 
-            // Beacon present messages are received but ignored. Maybe they have a use in the
-            // future.
+            // Commands are broadcast by the beacon that is connected to the gateway. We only act
+            // on the ones for us and don't forward them.
+            Ok(Some(morty_message::Msg::Command(cmd))) => {
+                if commands.is_for_us(&cmd) {
+                    execute_command(&cmd, &mut commands, &clock, &mut uart)?;
+                }
+            }
+
+            // Acknowledgements from trackers go to the gateway, like fixes
+            Ok(Some(morty_message::Msg::CommandAck(ack))) => {
+                info!("Command ack from {src}: {:?}", ack);
+                let now = EspSystemTime.now().as_secs() as i64;
+                push_relay(&mut uart, &src, now, 0, &recv_data.data)?;
+            }
+            Ok(Some(morty_message::Msg::TransferAck(ack))) => {
+                info!("Transfer ack from {src}: {:?}", ack);
+                let now = EspSystemTime.now().as_secs() as i64;
+                push_relay(&mut uart, &src, now, 0, &recv_data.data)?;
+            }
+            Ok(Some(morty_message::Msg::Trace(trace))) => {
+                info!("Trace {}/{} from {src}", trace.index + 1, trace.count);
+                let now = EspSystemTime.now().as_secs() as i64;
+                push_relay(&mut uart, &src, now, 0, &recv_data.data)?;
+            }
+            // Hellos are passed on like fixes, but only one per source per minute. A site that
+            // comes back from a power cut would flood the gateway otherwise.
+            Ok(Some(morty_message::Msg::Hello(hello))) => {
+                info!("Hello from {src}: {:?}", hello);
+                if !hellos.allow(&src, now_monotonic()) {
+                    info!("Not relaying another hello of {src}");
+                    continue;
+                }
+                let now = EspSystemTime.now().as_secs() as i64;
+                let path_delay_ms = add_delay(0, delay);
+                broadcast_relay(&src, now, path_delay_ms, &recv_data.data, esp_now);
+                push_relay(&mut uart, &src, now, path_delay_ms, &recv_data.data)?;
+            }
+            // So are power events from other beacons
+            Ok(Some(morty_message::Msg::PowerEvent(event))) => {
+                info!("Power event from {src}: {:?}", event);
+                let now = EspSystemTime.now().as_secs() as i64;
+                push_relay(&mut uart, &src, now, 0, &recv_data.data)?;
+            }
+
+            // Chunks are only for trackers
+            Ok(Some(morty_message::Msg::Chunk(_))) => {}
+            // Pings and pongs only go over the UART
+            Ok(Some(morty_message::Msg::Ping(_) | morty_message::Msg::Pong(_))) => {}
+            Ok(Some(morty_message::Msg::TimeBeacon(beacon))) => {
+                take_time(
+                    &beacon,
+                    recv_data.received_at,
+                    &clock,
+                    &mut discipline,
+                    esp_now,
+                )?;
+            }
+
+            // The gateway only hears the clocks of beacons that aren't attached to it through
+            // their beacon present messages, so those are passed on every now and then
             Ok(Some(morty_message::Msg::BeaconPresent(beacon))) => {
                 info!("Beacon from {src}: {:?}", beacon);
+                if beacon.clock.is_some() && clock_reports.allow(&src, now_monotonic()) {
+                    let now = EspSystemTime.now().as_secs() as i64;
+                    push_relay(&mut uart, &src, now, 0, &recv_data.data)?;
+                }
+            }
+            // Frames garbled on the air happen now and then, the sender repeats what matters
+            Err(e @ CommError::CrcMismatch { .. }) => {
+                STATS.decode_failures.record(&e);
+                warn!("Dropping garbled frame from {src}: {e}");
             }
             Err(e) => {
+                STATS.decode_failures.record(&e);
                 error!("Error decoding message: {e}");
             }
             Ok(None) => {
@@ -208,15 +781,395 @@ fn recv_data_task(
     }
 }
 
-/// Because we need to add timestamps to relay messages we have to wait for SNTP to sync.
-fn update_sntp() -> Result<(), anyhow::Error> {
-    let sntp = esp_idf_svc::sntp::EspSntp::new_default()?;
-    while sntp.get_sync_status() != SyncStatus::Completed {
-        info!("Waiting for SNTP to sync");
-        std::thread::sleep(Duration::from_secs(1));
+/// Handle a line from the gateway. These contain commands, that we either execute or broadcast to
+/// the device they are for, pings that we answer and time beacons that we take and broadcast.
+/// Pings can carry the negotiation of the baud rate as well.
+fn handle_gateway_line(
+    line: &str,
+    commands: &mut CommandHandler,
+    clock: &Clock,
+    uart: &mut UartWriter,
+    baud: &mut BaudLink,
+    discipline: &mut Discipline,
+    esp_now: &esp_idf_svc::espnow::EspNow,
+) -> Result<(), anyhow::Error> {
+    let Some(frames) = line.strip_prefix(UART_HEADER) else {
+        warn!("Received invalid line from gateway: {line}");
+        return Ok(());
+    };
+
+    for frame in frames.split(UART_FRAME_DELIMITER) {
+        let bytes = match general_purpose::STANDARD.decode(frame) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                BeaconStats::inc(&STATS.gateway_frames_failed);
+                error!("Unable to decode {frame}: {e}");
+                continue;
+            }
+        };
+        let msg = decode_msg(&bytes);
+        if msg.is_ok() {
+            BeaconStats::inc(&STATS.gateway_frames);
+            baud.heard(now_monotonic());
+        } else {
+            BeaconStats::inc(&STATS.gateway_frames_failed);
+        }
+        match msg {
+            Ok(Some(morty_message::Msg::Command(cmd))) => {
+                if commands.is_for_us(&cmd) {
+                    execute_command(&cmd, commands, clock, uart)?;
+                } else {
+                    info!("Broadcasting command for {}", cmd.target);
+                    broadcast_encoded(&bytes, Priority::High, esp_now)?;
+                }
+            }
+            // Chunks of transfers for the trackers, the gateway paces them
+            Ok(Some(morty_message::Msg::Chunk(_))) => {
+                broadcast_encoded(&bytes, Priority::Routine, esp_now)?;
+            }
+            // The gateway checks that we're attached, the answer goes out right away, also while
+            // it's paused for maintenance
+            Ok(Some(morty_message::Msg::Ping(ping))) => {
+                if !ping.gateway_id.is_empty() {
+                    let mut gateway_id = GATEWAY_ID.lock().unwrap();
+                    if *gateway_id != ping.gateway_id {
+                        info!("Attached to gateway {}", ping.gateway_id);
+                        *gateway_id = ping.gateway_id.clone();
+                    }
+                }
+                let accept_baud = if BaudLink::supports(ping.propose_baud) {
+                    ping.propose_baud
+                } else {
+                    0
+                };
+                let switch_in = Duration::from_millis(ping.switch_in_ms as u64);
+                let switch_ack_baud = if ping.switch_baud != 0
+                    && baud.count_down(ping.switch_baud, switch_in, now_monotonic())
+                {
+                    ping.switch_baud
+                } else {
+                    0
+                };
+                // Before the answer, so the gateway knows nothing comes after it
+                uart.set_paused(ping.paused)?;
+                let pong = PongMsg {
+                    nonce: ping.nonce,
+                    device_id: own_mac()?,
+                    firmware_version: env!("CARGO_PKG_VERSION").to_string(),
+                    uptime_ms: now_monotonic().as_millis() as u64,
+                    config_version: config_version(),
+                    accept_baud,
+                    switch_ack_baud,
+                    uart_frames: STATS.gateway_frames.load(Ordering::Relaxed),
+                    uart_frames_failed: STATS.gateway_frames_failed.load(Ordering::Relaxed),
+                    uart_framing_errors: STATS.uart_errors.framing.load(Ordering::Relaxed),
+                    clock: Some(clock_status()),
+                    paused: ping.paused,
+                };
+                uart.send_now(&encode_msg(&morty_message::Msg::Pong(pong))?)?;
+            }
+            Ok(Some(morty_message::Msg::TimeBeacon(beacon))) => {
+                take_time(&beacon, now_monotonic(), clock, discipline, esp_now)?;
+            }
+            Ok(msg) => warn!("Unexpected message from gateway: {:?}", msg),
+            Err(e) => {
+                STATS.decode_failures.record(&e);
+                error!("Error decoding message from gateway: {e}");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Discipline the system time with a time beacon we received at monotonic `received_at`, and
+/// broadcast it again for the beacons and trackers further away.
+fn take_time(
+    beacon: &TimeBeaconMsg,
+    received_at: Duration,
+    clock: &Clock,
+    discipline: &mut Discipline,
+    esp_now: &esp_idf_svc::espnow::EspNow,
+) -> Result<(), anyhow::Error> {
+    let held = now_monotonic().saturating_sub(received_at);
+    let local_ms = EspSystemTime.now().as_millis() as i64;
+    let offset_ms = timesync::offset_ms(beacon, held, local_ms);
+    let correction = discipline.sample(offset_ms, beacon.hops, clock.is_valid(), now_monotonic());
+    if correction != Correction::Ignore {
+        info!("Time beacon through {} hops: {:?}", beacon.hops, correction);
+        correct_system_time(correction)?;
+        set_clock_status(discipline.status());
+    }
+    let held = now_monotonic().saturating_sub(received_at);
+    if let Some(relayed) = discipline.relay(beacon, held) {
+        let relayed = morty_message::Msg::TimeBeacon(relayed);
+        if let Err(e) = broadcast_msg(&relayed, Priority::Routine, esp_now) {
+            BeaconStats::inc(&STATS.relays_failed);
+            warn!("Unable to pass on a time beacon: {e}");
+        }
     }
-    let now = EspSystemTime.now();
-    info!("Current time: {:?}", now);
+    Ok(())
+}
+
+/// Execute a command for this beacon and send the acknowledgement to the gateway.
+fn execute_command(
+    cmd: &CommandMsg,
+    commands: &mut CommandHandler,
+    clock: &Clock,
+    uart: &mut UartWriter,
+) -> Result<(), anyhow::Error> {
+    let Some(handled) = commands.handle(cmd, clock.wall(), Some(&|| STATS.log()), None, None)
+    else {
+        return Ok(());
+    };
+
+    let relay_msg = RelayMsg {
+        timestamp: EspSystemTime.now().as_secs() as i64,
+        src: own_mac()?,
+        gateway_hint: gateway_hint(),
+        msg: Some(morty_rs::messages::relay_msg::Msg::CommandAck(handled.ack)),
+        ..Default::default()
+    };
+    uart.push(&encode_msg(&morty_message::Msg::Relay(relay_msg))?)?;
+    uart.flush()?;
+
+    if handled.reboot {
+        command::reboot();
+    }
+    Ok(())
+}
+
+/// Tell the gateway that we booted, directly and through the other beacons, see
+/// `morty_rs::hello`.
+fn send_hello(
+    esp_now: &esp_idf_svc::espnow::EspNow,
+    uart: &mut UartWriter,
+    backup_battery: bool,
+) -> Result<(), anyhow::Error> {
+    let mut capabilities = hello::CAP_RELAY | hello::CAP_COMMANDS;
+    if FRAME_FORMAT == FrameFormat::Header {
+        capabilities |= hello::CAP_FRAME_HEADER;
+    }
+    if backup_battery {
+        capabilities |= hello::CAP_BACKUP_BATTERY;
+    }
+    let hello = hello::hello(
+        Role::Beacon,
+        env!("CARGO_PKG_VERSION"),
+        capabilities,
+        config_version(),
+        &config().name,
+    )?;
+    broadcast_msg(
+        &morty_message::Msg::Hello(hello.clone()),
+        Priority::High,
+        esp_now,
+    )?;
+    let relay_msg = RelayMsg {
+        timestamp: EspSystemTime.now().as_secs() as i64,
+        src: own_mac()?,
+        gateway_hint: gateway_hint(),
+        msg: Some(morty_rs::messages::relay_msg::Msg::Hello(hello)),
+        ..Default::default()
+    };
+    uart.push(&encode_msg(&morty_message::Msg::Relay(relay_msg))?)?;
+    uart.flush()?;
+    Ok(())
+}
+
+/// We lost or regained external power. Let the gateway know, directly and through the other
+/// beacons, and switch what we do to match.
+fn power_changed(
+    event: PowerEventMsg,
+    esp_now: &esp_idf_svc::espnow::EspNow,
+    uart: &mut UartWriter,
+    led: &mut Led,
+) -> Result<(), anyhow::Error> {
+    ON_BATTERY.store(event.on_battery, Ordering::Relaxed);
+    flashlog::log_event(EventKind::State {
+        state: if event.on_battery {
+            STATE_ON_BATTERY
+        } else {
+            STATE_EXTERNAL_POWER
+        },
+    });
+    led.set_color(colors::GREEN, led_brightness())?;
+
+    broadcast_msg(
+        &morty_message::Msg::PowerEvent(event.clone()),
+        Priority::High,
+        esp_now,
+    )?;
+    let relay_msg = RelayMsg {
+        timestamp: EspSystemTime.now().as_secs() as i64,
+        src: own_mac()?,
+        gateway_hint: gateway_hint(),
+        msg: Some(morty_rs::messages::relay_msg::Msg::PowerEvent(event)),
+        ..Default::default()
+    };
+    uart.push(&encode_msg(&morty_message::Msg::Relay(relay_msg))?)?;
+    uart.flush()?;
+    Ok(())
+}
+
+/// LED brightness for what we show, dimmed while we run on battery.
+fn led_brightness() -> u8 {
+    if ON_BATTERY.load(Ordering::Relaxed) {
+        BATTERY_LED_BRIGHTNESS
+    } else {
+        LED_BRIGHTNESS
+    }
+}
+
+fn beacon_present(tx_power: f32) -> morty_message::Msg {
+    let mut msg = BeaconPresentMsg {
+        timestamp: EspSystemTime.now().as_secs() as i64,
+        tx_power,
+        channel: ESP_NOW_CHANNEL as u32,
+        clock: Some(clock_status()),
+        ..Default::default()
+    };
+    listen_schedule().advertise(&mut msg);
+    morty_message::Msg::BeaconPresent(msg)
+}
+
+/// When we listen, see `morty_rs::duty`. We listen all the time unless the duty cycle is
+/// configured, and listen less when the battery is low.
+fn listen_schedule() -> ListenSchedule {
+    let Some(policy) = config().duty_cycle else {
+        return ListenSchedule::ALWAYS;
+    };
+    let Some(mac) = own_mac().ok().and_then(|mac| parse_mac(&mac)) else {
+        return ListenSchedule::ALWAYS;
+    };
+    let battery_mv = BATTERY_MV.load(Ordering::Relaxed);
+    let battery_volts = (battery_mv != 0).then(|| battery_mv as f32 / 1000.0);
+    ListenSchedule::for_device(&mac, policy.cycle(battery_volts))
+}
+
+/// Turn the radio off and light sleep for `off`. ESP-NOW and the protocol survive stopping the
+/// wifi, the TX power doesn't.
+fn radio_sleep(off: Duration, tx_power_dbm: f32) -> Result<(), anyhow::Error> {
+    info!("Turning the radio off for {} ms", off.as_millis());
+    esp!(unsafe { esp_idf_sys::esp_wifi_stop() })?;
+    esp!(unsafe { esp_idf_sys::esp_sleep_enable_timer_wakeup(off.as_micros() as u64) })?;
+    esp!(unsafe { esp_idf_sys::esp_light_sleep_start() })?;
+    esp!(unsafe { esp_idf_sys::esp_wifi_start() })?;
+    set_tx_power_dbm(tx_power_dbm)?;
+    Ok(())
+}
+
+/// Count and log a frame of `src` we couldn't pass on. That only costs the frame: anyone in range
+/// can send us one that doesn't relay, and the ESP-NOW queue can be full for a moment, so neither
+/// may end the receive thread.
+fn relay_failed(src: &str, e: &CommError) {
+    BeaconStats::inc(&STATS.relays_failed);
+    warn!("Unable to relay a frame of {src}: {e}");
+}
+
+/// Broadcast the `frame` of `src` to the other beacons, wrapped in a relay.
+fn broadcast_relay(
+    src: &str,
+    timestamp: i64,
+    path_delay_ms: u32,
+    frame: &[u8],
+    esp_now: &esp_idf_svc::espnow::EspNow,
+) {
+    let sent = encode_relay(src, timestamp, path_delay_ms, "", frame)
+        .and_then(|data| broadcast_data(&data, Priority::Routine, esp_now));
+    if let Err(e) = sent {
+        relay_failed(src, &e);
+    }
+}
+
+/// Write the `frame` of `src` to the UART, wrapped in a relay. Only a failing UART is an error.
+fn push_relay(
+    uart: &mut UartWriter,
+    src: &str,
+    timestamp: i64,
+    path_delay_ms: u32,
+    frame: &[u8],
+) -> Result<(), anyhow::Error> {
+    match uart_relay(src, timestamp, path_delay_ms, frame) {
+        Ok(line) => uart.push(&line),
+        Err(e) => {
+            relay_failed(src, &e);
+            Ok(())
+        }
+    }
+}
+
+/// A frame from `src` wrapped in a RelayMsg for the gateway, see `encode_relay`, stamped with the
+/// gateway we're attached to.
+fn uart_relay(
+    src: &str,
+    timestamp: i64,
+    path_delay_ms: u32,
+    frame: &[u8],
+) -> Result<Vec<u8>, CommError> {
+    encode_relay(src, timestamp, path_delay_ms, &gateway_hint(), frame)
+}
+
+/// The gateway we're attached to, empty until it pinged us.
+fn gateway_hint() -> String {
+    GATEWAY_ID.lock().unwrap().clone()
+}
+
+/// Wall clock time in ms since the epoch.
+fn wall_ms() -> i64 {
+    EspSystemTime.now().as_millis() as i64
+}
+
+/// Random delay for a presence response.
+fn presence_delay() -> Duration {
+    let range = PRESENCE_MAX_DELAY_MS - PRESENCE_MIN_DELAY_MS;
+    let jitter = unsafe { esp_idf_sys::esp_random() } % (range + 1);
+    Duration::from_millis((PRESENCE_MIN_DELAY_MS + jitter) as u64)
+}
+
+/// Time since boot, from the same clock the ESP-NOW callback uses to stamp received frames.
+fn now_monotonic() -> Duration {
+    Duration::from_micros(unsafe { esp_idf_sys::esp_timer_get_time() } as u64)
+}
+
+/// Version of the configuration we run with, so the gateway can tell when it's not the one it
+/// should be. Keys stay out of it.
+fn config_version() -> u32 {
+    let (encryption, peers) = match ENCRYPTION {
+        Encryption::Off => ("off", 0),
+        Encryption::Transitional { peers, .. } => ("transitional", peers.len()),
+        Encryption::Required { peers, .. } => ("required", peers.len()),
+    };
+    let mut version = ConfigVersion::new();
+    version
+        .f32("tx_power_dbm", config().tx_power_dbm)
+        .bool("frame_header", FRAME_FORMAT == FrameFormat::Header)
+        .u32("channel", ESP_NOW_CHANNEL as u32)
+        .str("encryption", encryption)
+        .u32("encrypted_peers", peers as u32)
+        .bool(
+            "source_filter_allow",
+            SOURCE_FILTER_MODE == FilterMode::Allow,
+        )
+        .str("source_filter", &SOURCE_FILTER_LIST.join(","));
+    version.finish()
+}
+
+/// Disconnect from wifi and set up for ESP-NOW.
+fn switch_to_esp_now(wifi: &mut EspWifi) -> Result<(), anyhow::Error> {
+    wifi.disconnect()?;
+    wifi.stop()?;
+    wifi.set_configuration(&Configuration::Client(ClientConfiguration {
+        ..Default::default()
+    }))?;
+
+    esp!(unsafe {
+        esp_idf_sys::esp_wifi_set_protocol(
+            esp_idf_sys::wifi_interface_t_WIFI_IF_STA,
+            esp_idf_sys::WIFI_PROTOCOL_LR.try_into().unwrap(),
+        )
+    })?;
+
+    wifi.start()?;
     Ok(())
 }
 
@@ -237,15 +1190,3 @@ fn uart_init(
 
     Ok(uart_driver)
 }
-
-/// Write data to UART. The data is base64 encoded and prefixed with a header.
-fn uart_write(uart: &UartDriver, data: &[u8]) -> Result<(), anyhow::Error> {
-    const UART_HEADER: &str = "MORTYGPS";
-    let b64_encoded = general_purpose::STANDARD.encode(data);
-    let bytes = b64_encoded.as_bytes();
-    uart.write(UART_HEADER.as_bytes())?;
-    uart.write(bytes)?;
-    uart.write(b"\n")?;
-    info!("Wrote {} bytes over UART", bytes.len());
-    Ok(())
-}
@@ -0,0 +1,70 @@
+use esp_idf_hal::adc;
+use esp_idf_hal::gpio;
+use log::*;
+use morty_rs::messages::PowerEventMsg;
+use morty_rs::power::read_battery_voltage;
+use morty_rs::power::read_level_debounced;
+use morty_rs::power::PowerMonitor;
+use morty_rs::power::PowerSource;
+
+/// The power pins of a beacon with a backup battery: vbus sense for external power and,
+/// optionally, battery sense on GPIO10, like on the trackers.
+pub struct PowerSense {
+    vbus_sense: gpio::PinDriver<'static, gpio::AnyInputPin, gpio::Input>,
+    vbat: Option<(
+        adc::AdcChannelDriver<'static, gpio::Gpio10, adc::Atten11dB<adc::ADC1>>,
+        adc::AdcDriver<'static, adc::ADC1>,
+    )>,
+    monitor: PowerMonitor,
+}
+
+impl PowerSense {
+    /// `readings` is the number of readings in a row needed to believe the power source changed.
+    pub fn new(
+        vbus_sense_pin: gpio::AnyInputPin,
+        vbat: Option<(gpio::Gpio10, adc::ADC1)>,
+        readings: u32,
+    ) -> Result<Self, anyhow::Error> {
+        let vbat = match vbat {
+            Some((pin, adc1)) => Some((
+                adc::AdcChannelDriver::new(pin)?,
+                adc::AdcDriver::new(adc1, &adc::config::Config::new().calibration(true))?,
+            )),
+            None => None,
+        };
+        Ok(Self {
+            vbus_sense: gpio::PinDriver::input(vbus_sense_pin)?,
+            vbat,
+            monitor: PowerMonitor::new(readings),
+        })
+    }
+
+    /// The voltage of the backup battery, None when we can't read it.
+    pub fn battery_voltage(&mut self) -> Result<Option<f32>, anyhow::Error> {
+        match &mut self.vbat {
+            Some((vbat_driver, adc)) => Ok(Some(read_battery_voltage(vbat_driver, adc)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Read the power pins. Returns the event to send when we lost or regained external power.
+    pub fn check(&mut self) -> Result<Option<PowerEventMsg>, anyhow::Error> {
+        let Some(source) = self.monitor.update(read_level_debounced(&self.vbus_sense)) else {
+            return Ok(None);
+        };
+        let battery_voltage = match &mut self.vbat {
+            Some((vbat_driver, adc)) => read_battery_voltage(vbat_driver, adc)?,
+            None => 0.0,
+        };
+        let on_battery = source == PowerSource::Battery;
+        if on_battery {
+            warn!("Lost external power, battery at {battery_voltage:.2}V");
+        } else {
+            info!("External power is back, battery at {battery_voltage:.2}V");
+        }
+        Ok(Some(PowerEventMsg {
+            on_battery,
+            battery_voltage,
+        }))
+    }
+}
@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Answers trackers we haven't heard in a while with an extra BeaconPresentMsg. A tracker that
+/// scans for a beacon only listens briefly on every channel and easily misses the regular ones.
+///
+/// Responses are rate limited to one per `min_interval` for all trackers together, and go out
+/// after a small delay, so they don't collide with the relay of the fix that triggered them.
+///
+/// All times are monotonic and passed in by the caller.
+pub struct PresenceResponder {
+    forget_after: Duration,
+    min_interval: Duration,
+    // When we last heard every source
+    heard: HashMap<Vec<u8>, Duration>,
+    last_response: Option<Duration>,
+    due: Option<Duration>,
+}
+
+impl PresenceResponder {
+    pub fn new(forget_after: Duration, min_interval: Duration) -> Self {
+        Self {
+            forget_after,
+            min_interval,
+            heard: HashMap::new(),
+            last_response: None,
+            due: None,
+        }
+    }
+
+    /// Record that we heard a fix from `src` at `now`. If it's new to us, a response is scheduled
+    /// `delay` from now, unless that's too soon after the last one. Returns whether a response was
+    /// scheduled.
+    pub fn heard(&mut self, src: &[u8], now: Duration, delay: Duration) -> bool {
+        let forget_after = self.forget_after;
+        self.heard
+            .retain(|_, at| now.saturating_sub(*at) < forget_after);
+        let new = self.heard.insert(src.to_vec(), now).is_none();
+
+        let allowed = self
+            .last_response
+            .map_or(true, |last| now.saturating_sub(last) >= self.min_interval);
+        if !new || !allowed || self.due.is_some() {
+            return false;
+        }
+        self.due = Some(now + delay);
+        self.last_response = Some(now);
+        true
+    }
+
+    /// Time until the scheduled response is due, None if there is none.
+    pub fn time_to_respond(&self, now: Duration) -> Option<Duration> {
+        self.due.map(|due| due.saturating_sub(now))
+    }
+
+    /// Whether the scheduled response is due. Once this returns true, the response is considered
+    /// sent.
+    pub fn take_due(&mut self, now: Duration) -> bool {
+        match self.due {
+            Some(due) if due <= now => {
+                self.due = None;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORGET_AFTER: Duration = Duration::from_secs(300);
+    const MIN_INTERVAL: Duration = Duration::from_secs(10);
+    const DELAY: Duration = Duration::from_millis(50);
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn a_new_tracker_gets_a_response_after_the_delay() {
+        let mut responder = PresenceResponder::new(FORGET_AFTER, MIN_INTERVAL);
+        assert_eq!(responder.time_to_respond(secs(0)), None);
+        assert!(responder.heard(b"tracker", secs(10), DELAY));
+        assert_eq!(responder.time_to_respond(secs(10)), Some(DELAY));
+        assert!(!responder.take_due(secs(10)));
+        assert!(responder.take_due(secs(10) + DELAY));
+        // It's sent once
+        assert!(!responder.take_due(secs(11)));
+        assert_eq!(responder.time_to_respond(secs(11)), None);
+    }
+
+    #[test]
+    fn a_tracker_we_heard_recently_gets_none() {
+        let mut responder = PresenceResponder::new(FORGET_AFTER, MIN_INTERVAL);
+        assert!(responder.heard(b"tracker", secs(0), DELAY));
+        assert!(responder.take_due(secs(1)));
+        assert!(!responder.heard(b"tracker", secs(60), DELAY));
+        // Every fix counts as hearing it, so it's forgotten 5 minutes after the last one
+        assert!(!responder.heard(b"tracker", secs(359), DELAY));
+        assert!(responder.heard(b"tracker", secs(659), DELAY));
+    }
+
+    #[test]
+    fn responses_are_rate_limited_for_all_trackers_together() {
+        let mut responder = PresenceResponder::new(FORGET_AFTER, MIN_INTERVAL);
+        assert!(responder.heard(b"one", secs(0), DELAY));
+        assert!(responder.take_due(secs(1)));
+        assert!(!responder.heard(b"two", secs(9), DELAY));
+        assert!(responder.heard(b"three", secs(10), DELAY));
+        // A tracker heard during the rate limit is known now, and doesn't get one later
+        assert!(responder.take_due(secs(11)));
+        assert!(!responder.heard(b"two", secs(30), DELAY));
+    }
+
+    #[test]
+    fn one_response_is_scheduled_at_a_time() {
+        let mut responder = PresenceResponder::new(FORGET_AFTER, MIN_INTERVAL);
+        assert!(responder.heard(b"one", secs(0), secs(20)));
+        assert!(!responder.heard(b"two", secs(15), DELAY));
+        assert_eq!(responder.time_to_respond(secs(15)), Some(secs(5)));
+        assert!(responder.take_due(secs(20)));
+    }
+}
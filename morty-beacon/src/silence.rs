@@ -0,0 +1,136 @@
+use std::time::Duration;
+
+/// What to do about a radio that has gone quiet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SilenceAction {
+    None,
+    Reinit,
+    Reboot,
+}
+
+/// Keeps track of when we last received an ESP-NOW frame. If nothing was heard for `timeout`
+/// while we did hear others before, the radio is probably wedged. We first try to reinitialize
+/// it `max_reinits` times and if that doesn't help, we reboot.
+///
+/// All times are monotonic and passed in by the caller.
+pub struct SilenceWatchdog {
+    timeout: Duration,
+    max_reinits: u32,
+    last_recv: Option<Duration>,
+    silent_since: Duration,
+    reinits: u32,
+}
+
+impl SilenceWatchdog {
+    pub fn new(timeout: Duration, max_reinits: u32) -> Self {
+        Self {
+            timeout,
+            max_reinits,
+            last_recv: None,
+            silent_since: Duration::from_secs(0),
+            reinits: 0,
+        }
+    }
+
+    /// Record that a frame was received at `at`.
+    pub fn received(&mut self, at: Duration) {
+        if self.last_recv.map_or(true, |last| at > last) {
+            self.last_recv = Some(at);
+            self.silent_since = at;
+            self.reinits = 0;
+        }
+    }
+
+    /// Record that we turned the radio off for `off`, which doesn't count as silence.
+    pub fn radio_off(&mut self, off: Duration) {
+        self.silent_since += off;
+    }
+
+    /// Check whether the radio has been silent for too long and what to do about it.
+    pub fn check(&mut self, now: Duration) -> SilenceAction {
+        // We never heard anybody, so there's nothing to miss
+        if self.last_recv.is_none() {
+            return SilenceAction::None;
+        }
+
+        if now.saturating_sub(self.silent_since) < self.timeout {
+            return SilenceAction::None;
+        }
+
+        // Give every recovery attempt a full timeout to prove itself
+        self.silent_since = now;
+        if self.reinits < self.max_reinits {
+            self.reinits += 1;
+            SilenceAction::Reinit
+        } else {
+            SilenceAction::Reboot
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(60);
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn nothing_to_miss_before_the_first_frame() {
+        let mut watchdog = SilenceWatchdog::new(TIMEOUT, 2);
+        assert_eq!(watchdog.check(secs(3600)), SilenceAction::None);
+    }
+
+    #[test]
+    fn silence_reinits_and_then_reboots() {
+        let mut watchdog = SilenceWatchdog::new(TIMEOUT, 2);
+        watchdog.received(secs(10));
+        assert_eq!(watchdog.check(secs(69)), SilenceAction::None);
+        assert_eq!(watchdog.check(secs(70)), SilenceAction::Reinit);
+        // Every attempt gets a full timeout
+        assert_eq!(watchdog.check(secs(129)), SilenceAction::None);
+        assert_eq!(watchdog.check(secs(130)), SilenceAction::Reinit);
+        assert_eq!(watchdog.check(secs(190)), SilenceAction::Reboot);
+        assert_eq!(watchdog.check(secs(250)), SilenceAction::Reboot);
+    }
+
+    #[test]
+    fn a_frame_resets_the_attempts() {
+        let mut watchdog = SilenceWatchdog::new(TIMEOUT, 1);
+        watchdog.received(secs(10));
+        assert_eq!(watchdog.check(secs(70)), SilenceAction::Reinit);
+        watchdog.received(secs(80));
+        assert_eq!(watchdog.check(secs(139)), SilenceAction::None);
+        assert_eq!(watchdog.check(secs(140)), SilenceAction::Reinit);
+        assert_eq!(watchdog.check(secs(200)), SilenceAction::Reboot);
+    }
+
+    #[test]
+    fn frames_out_of_order_dont_count() {
+        let mut watchdog = SilenceWatchdog::new(TIMEOUT, 1);
+        watchdog.received(secs(50));
+        assert_eq!(watchdog.check(secs(110)), SilenceAction::Reinit);
+        // Stamped before the last one, so it doesn't prove the reinit worked
+        watchdog.received(secs(40));
+        assert_eq!(watchdog.check(secs(170)), SilenceAction::Reboot);
+    }
+
+    #[test]
+    fn the_radio_being_off_isnt_silence() {
+        let mut watchdog = SilenceWatchdog::new(TIMEOUT, 1);
+        watchdog.received(secs(10));
+        watchdog.radio_off(secs(30));
+        assert_eq!(watchdog.check(secs(99)), SilenceAction::None);
+        assert_eq!(watchdog.check(secs(100)), SilenceAction::Reinit);
+    }
+
+    #[test]
+    fn without_reinits_silence_reboots() {
+        let mut watchdog = SilenceWatchdog::new(TIMEOUT, 0);
+        watchdog.received(secs(0));
+        assert_eq!(watchdog.check(secs(60)), SilenceAction::Reboot);
+    }
+}
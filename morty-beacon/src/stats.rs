@@ -0,0 +1,129 @@
+use crate::config::config;
+use log::*;
+use morty_rs::comm::DecodeFailures;
+use morty_rs::metrics::Histogram;
+use morty_rs::uart_errors::UartErrors;
+use morty_rs::utils::boot_phases;
+use morty_rs::utils::dump_threads;
+use morty_rs::utils::format_duration;
+use morty_rs::utils::uptime;
+use morty_rs::wifi;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+// Upper bounds of the latency buckets
+const LATENCY_BUCKETS: [Duration; 6] = [
+    Duration::from_millis(1),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(500),
+];
+
+/// Counters for things that happen on the beacon. These are updated from the ESP-NOW callback as
+/// well as from the threads, so they are all atomics.
+pub struct BeaconStats {
+    pub frames_received: AtomicU32,
+    pub frames_dropped: AtomicU32,
+    // Frames from nodes that aren't encrypted peers, accepted or rejected depending on the
+    // encryption mode
+    pub frames_plaintext: AtomicU32,
+    pub frames_rejected: AtomicU32,
+    // Frames from sources we don't relay
+    pub frames_filtered: AtomicU32,
+    // Relays that took too long while there was a newer fix of their source, see `relay`
+    pub relays_over_budget: AtomicU32,
+    // Copies of fixes we already passed on, see `SeqDedup`
+    pub duplicates_dropped: AtomicU32,
+    // Frames we couldn't pass on, because they don't relay or ESP-NOW didn't take them
+    pub relays_failed: AtomicU32,
+    pub radio_reinits: AtomicU32,
+    pub radio_reboots: AtomicU32,
+    // Beacon present messages that failed to go out, and how many of the last ones failed in a
+    // row. A beacon that relays but fails to send these can't be found by trackers.
+    pub present_send_failures: AtomicU32,
+    pub present_failing: AtomicU32,
+    // Hardware errors on the UART to the gateway
+    pub uart_errors: UartErrors,
+    // Frames from the gateway that decoded and that didn't, for the baud rate negotiation
+    pub gateway_frames: AtomicU32,
+    pub gateway_frames_failed: AtomicU32,
+    // Frames from trackers, beacons and the gateway that didn't decode
+    pub decode_failures: DecodeFailures,
+    // Time from the ESP-NOW callback until the recv thread picks up the frame. The time from the
+    // radio to the callback can't be measured and is close to zero.
+    pub callback_to_processed: Histogram<{ LATENCY_BUCKETS.len() }>,
+    // Set once the boot phases went out with the stats
+    boot_phases_logged: AtomicBool,
+}
+
+pub static STATS: BeaconStats = BeaconStats::new();
+
+impl BeaconStats {
+    const fn new() -> Self {
+        Self {
+            frames_received: AtomicU32::new(0),
+            frames_dropped: AtomicU32::new(0),
+            frames_plaintext: AtomicU32::new(0),
+            frames_rejected: AtomicU32::new(0),
+            frames_filtered: AtomicU32::new(0),
+            relays_over_budget: AtomicU32::new(0),
+            duplicates_dropped: AtomicU32::new(0),
+            relays_failed: AtomicU32::new(0),
+            radio_reinits: AtomicU32::new(0),
+            radio_reboots: AtomicU32::new(0),
+            present_send_failures: AtomicU32::new(0),
+            present_failing: AtomicU32::new(0),
+            uart_errors: UartErrors::new(),
+            gateway_frames: AtomicU32::new(0),
+            gateway_frames_failed: AtomicU32::new(0),
+            decode_failures: DecodeFailures::new(),
+            callback_to_processed: Histogram::new(LATENCY_BUCKETS),
+            boot_phases_logged: AtomicBool::new(false),
+        }
+    }
+
+    pub fn inc(counter: &AtomicU32) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn log(&self) {
+        info!(
+            "Stats: frames_received={} frames_dropped={} frames_plaintext={} frames_rejected={} frames_filtered={} relays_over_budget={} duplicates_dropped={} relays_failed={} radio_reinits={} radio_reboots={} present_send_failures={} present_failing={} gateway_frames={} gateway_frames_failed={} {} {}",
+            self.frames_received.load(Ordering::Relaxed),
+            self.frames_dropped.load(Ordering::Relaxed),
+            self.frames_plaintext.load(Ordering::Relaxed),
+            self.frames_rejected.load(Ordering::Relaxed),
+            self.frames_filtered.load(Ordering::Relaxed),
+            self.relays_over_budget.load(Ordering::Relaxed),
+            self.duplicates_dropped.load(Ordering::Relaxed),
+            self.relays_failed.load(Ordering::Relaxed),
+            self.radio_reinits.load(Ordering::Relaxed),
+            self.radio_reboots.load(Ordering::Relaxed),
+            self.present_send_failures.load(Ordering::Relaxed),
+            self.present_failing.load(Ordering::Relaxed),
+            self.gateway_frames.load(Ordering::Relaxed),
+            self.gateway_frames_failed.load(Ordering::Relaxed),
+            self.uart_errors,
+            self.decode_failures,
+        );
+        info!(
+            "Stats: callback_to_processed {}",
+            self.callback_to_processed
+        );
+        info!("Stats: uptime {}", format_duration(uptime()));
+        info!("Stats: wifi {}", wifi::link());
+        for invalid in config().settings.invalid() {
+            info!("Stats: invalid setting {invalid}, using the default");
+        }
+        info!("Stats: threads\n{}", dump_threads());
+        if !self.boot_phases_logged.swap(true, Ordering::Relaxed) {
+            if let Some(phases) = boot_phases() {
+                info!("Stats: boot phases {phases}");
+            }
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use morty_rs::utils::Backoff;
+use std::time::Duration;
+
+/// What to do after trying to send a beacon present message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransmitAction {
+    /// It went out, carry on as usual
+    Sent,
+    /// It failed, try again after this long
+    Retry(Duration),
+    /// It kept failing for too long, the radio is probably wedged
+    Reinit,
+}
+
+/// Keeps track of the beacon present messages that fail to go out, e.g. because ESP-NOW ran out
+/// of buffers for a moment. Failures are retried after a delay from `backoff`. When sends keep
+/// failing for `timeout`, we reinitialize the radio, the way `SilenceWatchdog` does when we stop
+/// receiving. A beacon that can receive but not send still relays, but trackers don't know it's
+/// there.
+///
+/// The send is passed in, so this doesn't need a radio. All times are monotonic and passed in by
+/// the caller.
+pub struct TransmitWatchdog {
+    timeout: Duration,
+    backoff: Backoff,
+    failing_since: Option<Duration>,
+    consecutive_failures: u32,
+}
+
+impl TransmitWatchdog {
+    pub fn new(timeout: Duration, backoff: Backoff) -> Self {
+        Self {
+            timeout,
+            backoff,
+            failing_since: None,
+            consecutive_failures: 0,
+        }
+    }
+
+    /// Try `send` at `now` and decide what to do next.
+    pub fn send<E>(
+        &mut self,
+        now: Duration,
+        send: impl FnOnce() -> Result<(), E>,
+    ) -> TransmitAction {
+        if send().is_ok() {
+            self.failing_since = None;
+            self.consecutive_failures = 0;
+            self.backoff.reset();
+            return TransmitAction::Sent;
+        }
+
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        let since = *self.failing_since.get_or_insert(now);
+        if now.saturating_sub(since) >= self.timeout {
+            // Give the reinitialized radio a full timeout to prove itself
+            self.failing_since = Some(now);
+            self.backoff.reset();
+            return TransmitAction::Reinit;
+        }
+        // The backoff has no limit on its attempts, so there's always a delay
+        TransmitAction::Retry(self.backoff.next_delay().unwrap_or_default())
+    }
+
+    /// Sends that failed in a row, 0 when the last one went out.
+    pub fn consecutive_failures(&self) -> u32 {
+        self.consecutive_failures
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(3 * 60);
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    fn millis(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    fn watchdog() -> TransmitWatchdog {
+        TransmitWatchdog::new(TIMEOUT, Backoff::new(millis(100), 2, secs(1)))
+    }
+
+    // A send at `now` that fails, like ESP-NOW does when it's out of buffers
+    fn fail(watchdog: &mut TransmitWatchdog, now: u64) -> TransmitAction {
+        watchdog.send(secs(now), || Err("ESP_ERR_ESPNOW_NO_MEM"))
+    }
+
+    fn succeed(watchdog: &mut TransmitWatchdog, now: u64) -> TransmitAction {
+        watchdog.send(secs(now), || Ok::<(), &str>(()))
+    }
+
+    #[test]
+    fn sends_that_work_carry_on() {
+        let mut watchdog = watchdog();
+        for now in [0, 10, 20] {
+            assert_eq!(succeed(&mut watchdog, now), TransmitAction::Sent);
+        }
+        assert_eq!(watchdog.consecutive_failures(), 0);
+    }
+
+    #[test]
+    fn failures_are_retried_later_and_later() {
+        let mut watchdog = watchdog();
+        let actions: Vec<_> = (0..6).map(|now| fail(&mut watchdog, now)).collect();
+        assert_eq!(
+            actions,
+            [100, 200, 400, 800, 1000, 1000].map(|ms| TransmitAction::Retry(millis(ms)))
+        );
+        assert_eq!(watchdog.consecutive_failures(), 6);
+    }
+
+    #[test]
+    fn a_send_that_works_starts_over() {
+        let mut watchdog = watchdog();
+        fail(&mut watchdog, 0);
+        fail(&mut watchdog, 1);
+        assert_eq!(succeed(&mut watchdog, 2), TransmitAction::Sent);
+        assert_eq!(watchdog.consecutive_failures(), 0);
+        assert_eq!(fail(&mut watchdog, 3), TransmitAction::Retry(millis(100)));
+        // The timeout starts over as well
+        assert_eq!(fail(&mut watchdog, 182), TransmitAction::Retry(millis(200)));
+        assert_eq!(fail(&mut watchdog, 183), TransmitAction::Reinit);
+    }
+
+    #[test]
+    fn failing_for_the_timeout_reinits_the_radio() {
+        let mut watchdog = watchdog();
+        assert_eq!(fail(&mut watchdog, 10), TransmitAction::Retry(millis(100)));
+        assert_eq!(fail(&mut watchdog, 100), TransmitAction::Retry(millis(200)));
+        assert_eq!(fail(&mut watchdog, 189), TransmitAction::Retry(millis(400)));
+        assert_eq!(fail(&mut watchdog, 190), TransmitAction::Reinit);
+        assert_eq!(watchdog.consecutive_failures(), 4);
+        // The reinitialized radio gets a full timeout, and the backoff starts over
+        assert_eq!(fail(&mut watchdog, 191), TransmitAction::Retry(millis(100)));
+        assert_eq!(fail(&mut watchdog, 369), TransmitAction::Retry(millis(200)));
+        assert_eq!(fail(&mut watchdog, 370), TransmitAction::Reinit);
+        assert_eq!(watchdog.consecutive_failures(), 7);
+    }
+
+    #[test]
+    fn the_send_is_tried_once() {
+        let mut watchdog = watchdog();
+        let mut tries = 0;
+        watchdog.send(secs(0), || {
+            tries += 1;
+            Err(())
+        });
+        watchdog.send(secs(1), || {
+            tries += 1;
+            Ok::<(), ()>(())
+        });
+        assert_eq!(tries, 2);
+    }
+}
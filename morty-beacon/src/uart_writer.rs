@@ -0,0 +1,505 @@
+use base64::engine::general_purpose;
+use base64::Engine;
+use esp_idf_hal::delay::NON_BLOCK;
+use esp_idf_hal::uart::UartDriver;
+use log::*;
+use morty_rs::baud::set_uart_baud;
+use morty_rs::comm::UART_FRAME_DELIMITER;
+use morty_rs::comm::UART_HEADER;
+use std::collections::VecDeque;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Writes frames to the gateway over UART. Frames are base64 encoded and a single line, prefixed
+/// with a header, can hold several frames. During bursts this saves us the header and some
+/// writes for every frame. A line is written when it's full, or when the oldest frame in it has
+/// waited for `max_delay`.
+///
+/// The gateway sends commands back over the same UART, so this also collects the lines it sends.
+///
+/// While the gateway is in maintenance, it's paused and frames are held, at most `max_held` of
+/// them, until it resumes. A full hold drops its oldest frame.
+pub struct UartWriter<'a> {
+    uart: UartDriver<'a>,
+    lines: LinePacker,
+    received: Vec<u8>,
+    max_line_len: usize,
+    hold: FrameHold,
+}
+
+impl<'a> UartWriter<'a> {
+    pub fn new(
+        uart: UartDriver<'a>,
+        max_line_len: usize,
+        max_delay: Duration,
+        max_held: usize,
+    ) -> Self {
+        Self {
+            uart,
+            lines: LinePacker::new(max_line_len, max_delay),
+            received: Vec::new(),
+            max_line_len,
+            hold: FrameHold::new(max_held),
+        }
+    }
+
+    /// Add a frame to the current line, writing the line first if the frame doesn't fit. While
+    /// the gateway is paused, the frame is held instead.
+    pub fn push(&mut self, data: &[u8]) -> Result<(), anyhow::Error> {
+        if self.hold.hold(data) {
+            return Ok(());
+        }
+        self.push_line(data)
+    }
+
+    /// Write a frame right away, also while the gateway is paused, e.g. a pong.
+    pub fn send_now(&mut self, data: &[u8]) -> Result<(), anyhow::Error> {
+        self.push_line(data)?;
+        self.flush()
+    }
+
+    /// Pause or resume writing frames to the gateway. Pausing writes the current line first, so
+    /// nothing is left half sent. Resuming writes the frames that were held.
+    pub fn set_paused(&mut self, paused: bool) -> Result<(), anyhow::Error> {
+        if !self.hold.set_paused(paused) {
+            return Ok(());
+        }
+        if paused {
+            info!("Gateway paused, holding frames");
+            return self.flush();
+        }
+        let (held, dropped) = self.hold.release();
+        info!(
+            "Gateway resumed, writing {} held frames, dropped {dropped}",
+            held.len()
+        );
+        for data in held {
+            self.push_line(&data)?;
+        }
+        self.flush()
+    }
+
+    fn push_line(&mut self, data: &[u8]) -> Result<(), anyhow::Error> {
+        let uart = &mut self.uart;
+        self.lines.push(data, Instant::now(), |line, frames| {
+            write_line(uart, line, frames)
+        })
+    }
+
+    /// How long until the current line has to be written, None if there is nothing to write.
+    pub fn time_to_flush(&self) -> Option<Duration> {
+        self.lines.time_to_flush(Instant::now())
+    }
+
+    /// Write the current line if it's due.
+    pub fn flush_if_due(&mut self) -> Result<(), anyhow::Error> {
+        match self.time_to_flush() {
+            Some(t) if t.is_zero() => self.flush(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Read whatever the gateway sent us, without blocking, and return the lines that are
+    /// complete.
+    pub fn read_lines(&mut self) -> Result<Vec<String>, anyhow::Error> {
+        let mut buf = [0u8; 128];
+        loop {
+            let read = self.uart.read(&mut buf, NON_BLOCK)?;
+            if read == 0 {
+                break;
+            }
+            self.received.extend_from_slice(&buf[..read]);
+        }
+
+        let mut lines = Vec::new();
+        while let Some(end) = self.received.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.received.drain(..=end).collect();
+            lines.push(String::from_utf8_lossy(&line).trim().to_string());
+        }
+
+        // Don't let garbage without newlines pile up
+        if self.received.len() > self.max_line_len {
+            warn!("Discarding {} bytes without a newline", self.received.len());
+            self.received.clear();
+        }
+        Ok(lines)
+    }
+
+    /// Switch the UART to `baud`, after writing the current line. What we received so far at the
+    /// old rate is thrown away.
+    pub fn set_baudrate(&mut self, baud: u32) -> Result<(), anyhow::Error> {
+        self.flush()?;
+        set_uart_baud(self.uart.port() as i32, baud)?;
+        self.received.clear();
+        Ok(())
+    }
+
+    /// Write the current line.
+    pub fn flush(&mut self) -> Result<(), anyhow::Error> {
+        let uart = &mut self.uart;
+        self.lines
+            .flush(|line, frames| write_line(uart, line, frames))
+    }
+}
+
+fn write_line(uart: &mut UartDriver, line: &str, frames: usize) -> Result<(), anyhow::Error> {
+    uart.write(line.as_bytes())?;
+    info!("Wrote {frames} frames in {} bytes over UART", line.len());
+    Ok(())
+}
+
+/// Packs frames into lines for `UartWriter`: base64 encoded, after the UART header and separated
+/// by `UART_FRAME_DELIMITER`. A line is done when the next frame doesn't fit in `max_line_len`,
+/// when it's at least that long, or when its oldest frame has waited for `max_delay`. A frame
+/// that's longer than a line on its own gets a line of its own.
+pub struct LinePacker {
+    line: String,
+    frames: usize,
+    max_line_len: usize,
+    max_delay: Duration,
+    oldest: Option<Instant>,
+}
+
+impl LinePacker {
+    pub fn new(max_line_len: usize, max_delay: Duration) -> Self {
+        Self {
+            line: String::new(),
+            frames: 0,
+            max_line_len,
+            max_delay,
+            oldest: None,
+        }
+    }
+
+    /// Add a frame that came in at `now`. Lines that are done are passed to `write`, with a
+    /// newline and their number of frames.
+    pub fn push<E>(
+        &mut self,
+        data: &[u8],
+        now: Instant,
+        mut write: impl FnMut(&str, usize) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let encoded = general_purpose::STANDARD.encode(data);
+        if self.frames > 0 && self.line.len() + 1 + encoded.len() > self.max_line_len {
+            self.flush(&mut write)?;
+        }
+
+        if self.frames == 0 {
+            self.line.push_str(UART_HEADER);
+            self.oldest = Some(now);
+        } else {
+            self.line.push(UART_FRAME_DELIMITER);
+        }
+        self.line.push_str(&encoded);
+        self.frames += 1;
+
+        if self.line.len() >= self.max_line_len {
+            self.flush(write)?;
+        }
+        Ok(())
+    }
+
+    /// How long after `now` the current line has to be written, None if there is nothing to
+    /// write.
+    pub fn time_to_flush(&self, now: Instant) -> Option<Duration> {
+        self.oldest.map(|oldest| {
+            self.max_delay
+                .saturating_sub(now.saturating_duration_since(oldest))
+        })
+    }
+
+    /// Pass the current line to `write`, if there is one. It's kept when `write` fails.
+    pub fn flush<E>(
+        &mut self,
+        mut write: impl FnMut(&str, usize) -> Result<(), E>,
+    ) -> Result<(), E> {
+        if self.frames == 0 {
+            return Ok(());
+        }
+        self.line.push('\n');
+        let written = write(&self.line, self.frames);
+        self.line.pop();
+        written?;
+        self.line.clear();
+        self.frames = 0;
+        self.oldest = None;
+        Ok(())
+    }
+}
+
+/// Frames for a paused gateway, see `UartWriter`: at most `max_held` of them, a full hold drops
+/// its oldest frame.
+pub struct FrameHold {
+    paused: bool,
+    held: VecDeque<Vec<u8>>,
+    max_held: usize,
+    dropped: u32,
+}
+
+impl FrameHold {
+    pub fn new(max_held: usize) -> Self {
+        Self {
+            paused: false,
+            held: VecDeque::new(),
+            max_held,
+            dropped: 0,
+        }
+    }
+
+    /// Hold on to `data` when the gateway is paused. Returns whether it was held.
+    pub fn hold(&mut self, data: &[u8]) -> bool {
+        if !self.paused {
+            return false;
+        }
+        if self.held.len() >= self.max_held {
+            self.held.pop_front();
+            self.dropped = self.dropped.saturating_add(1);
+        }
+        self.held.push_back(data.to_vec());
+        true
+    }
+
+    /// Pause or resume. Returns whether that's a change.
+    pub fn set_paused(&mut self, paused: bool) -> bool {
+        let changed = paused != self.paused;
+        self.paused = paused;
+        changed
+    }
+
+    /// Take the frames that were held, oldest first, and how many were dropped to make room.
+    pub fn release(&mut self) -> (Vec<Vec<u8>>, u32) {
+        let dropped = std::mem::take(&mut self.dropped);
+        (self.held.drain(..).collect(), dropped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Base64 of FRAME is 4 bytes, so a line of one frame is 12 bytes and every next frame adds 5
+    const FRAME: [u8; 3] = [1, 2, 3];
+    const DELAY: Duration = Duration::from_secs(5);
+
+    fn into(lines: &mut Vec<(String, usize)>) -> impl FnMut(&str, usize) -> Result<(), ()> + '_ {
+        |line, frames| {
+            lines.push((line.to_string(), frames));
+            Ok(())
+        }
+    }
+
+    fn line(frames: usize) -> (String, usize) {
+        let line = format!("MORTYGPS{}\n", vec!["AQID"; frames].join(","));
+        (line, frames)
+    }
+
+    #[test]
+    fn frames_share_a_line_until_the_next_doesnt_fit() {
+        let mut packer = LinePacker::new(20, DELAY);
+        let mut lines = Vec::new();
+        let now = Instant::now();
+        for _ in 0..3 {
+            packer.push(&FRAME, now, into(&mut lines)).unwrap();
+        }
+        assert_eq!(lines, [line(2)]);
+        packer.flush(into(&mut lines)).unwrap();
+        assert_eq!(lines, [line(2), line(1)]);
+        // Nothing left
+        packer.flush(into(&mut lines)).unwrap();
+        assert_eq!(lines.len(), 2);
+    }
+
+    #[test]
+    fn full_lines_are_written_right_away() {
+        let mut packer = LinePacker::new(17, DELAY);
+        let mut lines = Vec::new();
+        packer
+            .push(&FRAME, Instant::now(), into(&mut lines))
+            .unwrap();
+        assert!(lines.is_empty());
+        packer
+            .push(&FRAME, Instant::now(), into(&mut lines))
+            .unwrap();
+        assert_eq!(lines, [line(2)]);
+        assert_eq!(packer.time_to_flush(Instant::now()), None);
+    }
+
+    #[test]
+    fn frames_longer_than_a_line_get_one_of_their_own() {
+        let mut packer = LinePacker::new(10, DELAY);
+        let mut lines = Vec::new();
+        packer
+            .push(&FRAME, Instant::now(), into(&mut lines))
+            .unwrap();
+        packer
+            .push(&FRAME, Instant::now(), into(&mut lines))
+            .unwrap();
+        assert_eq!(lines, [line(1), line(1)]);
+    }
+
+    #[test]
+    fn lines_are_due_when_their_oldest_frame_waited_long_enough() {
+        let mut packer = LinePacker::new(512, DELAY);
+        let mut lines = Vec::new();
+        let start = Instant::now();
+        assert_eq!(packer.time_to_flush(start), None);
+        packer.push(&FRAME, start, into(&mut lines)).unwrap();
+        let later = start + Duration::from_secs(3);
+        packer.push(&FRAME, later, into(&mut lines)).unwrap();
+        assert_eq!(packer.time_to_flush(later), Some(Duration::from_secs(2)));
+        assert_eq!(packer.time_to_flush(start + DELAY), Some(Duration::ZERO));
+        assert_eq!(
+            packer.time_to_flush(start + DELAY * 2),
+            Some(Duration::ZERO)
+        );
+        packer.flush(into(&mut lines)).unwrap();
+        assert_eq!(lines, [line(2)]);
+        assert_eq!(packer.time_to_flush(later), None);
+    }
+
+    #[test]
+    fn lines_that_fail_to_write_are_kept() {
+        let mut packer = LinePacker::new(512, DELAY);
+        let mut lines = Vec::new();
+        packer
+            .push(&FRAME, Instant::now(), into(&mut lines))
+            .unwrap();
+        assert_eq!(packer.flush(|_, _| Err("busy")), Err("busy"));
+        assert!(packer.time_to_flush(Instant::now()).is_some());
+        packer
+            .push(&FRAME, Instant::now(), into(&mut lines))
+            .unwrap();
+        packer.flush(into(&mut lines)).unwrap();
+        assert_eq!(lines, [line(2)]);
+    }
+
+    fn frame(n: u8) -> Vec<u8> {
+        vec![n; 3]
+    }
+
+    #[test]
+    fn frames_go_out_while_the_gateway_isnt_paused() {
+        let mut hold = FrameHold::new(4);
+        assert!(!hold.hold(&frame(1)));
+        assert!(!hold.set_paused(false));
+        assert_eq!(hold.release(), (vec![], 0));
+    }
+
+    #[test]
+    fn frames_are_held_while_the_gateway_is_paused() {
+        let mut hold = FrameHold::new(4);
+        assert!(hold.set_paused(true));
+        assert!(!hold.set_paused(true));
+        for n in 1..=3 {
+            assert!(hold.hold(&frame(n)));
+        }
+        assert!(hold.set_paused(false));
+        assert_eq!(hold.release(), (vec![frame(1), frame(2), frame(3)], 0));
+        assert_eq!(hold.release(), (vec![], 0));
+        assert!(!hold.hold(&frame(4)));
+    }
+
+    #[test]
+    fn a_full_hold_drops_its_oldest_frames() {
+        let mut hold = FrameHold::new(2);
+        hold.set_paused(true);
+        for n in 1..=5 {
+            assert!(hold.hold(&frame(n)));
+        }
+        hold.set_paused(false);
+        assert_eq!(hold.release(), (vec![frame(4), frame(5)], 3));
+        // The count starts over
+        hold.set_paused(true);
+        hold.hold(&frame(6));
+        hold.set_paused(false);
+        assert_eq!(hold.release(), (vec![frame(6)], 0));
+    }
+
+    // The beacon's end of the UART as `UartWriter` drives it, writing lines to `lines`
+    struct Beacon {
+        hold: FrameHold,
+        packer: LinePacker,
+        lines: Vec<(String, usize)>,
+    }
+
+    impl Beacon {
+        fn new(max_held: usize) -> Self {
+            Self {
+                hold: FrameHold::new(max_held),
+                packer: LinePacker::new(512, DELAY),
+                lines: Vec::new(),
+            }
+        }
+
+        fn push(&mut self, data: &[u8]) {
+            if !self.hold.hold(data) {
+                let lines = &mut self.lines;
+                self.packer.push(data, Instant::now(), into(lines)).unwrap();
+            }
+        }
+
+        // A ping that says whether the gateway is paused, answered with a pong of `pong`
+        fn ping(&mut self, paused: bool, pong: &[u8]) {
+            if self.hold.set_paused(paused) {
+                if !paused {
+                    for data in self.hold.release().0 {
+                        let lines = &mut self.lines;
+                        self.packer
+                            .push(&data, Instant::now(), into(lines))
+                            .unwrap();
+                    }
+                }
+                self.packer.flush(into(&mut self.lines)).unwrap();
+            }
+            let lines = &mut self.lines;
+            self.packer.push(pong, Instant::now(), into(lines)).unwrap();
+            self.packer.flush(into(&mut self.lines)).unwrap();
+        }
+
+        fn flush(&mut self) {
+            self.packer.flush(into(&mut self.lines)).unwrap();
+        }
+    }
+
+    fn encoded_line(frames: &[Vec<u8>]) -> (String, usize) {
+        let encoded: Vec<_> = frames
+            .iter()
+            .map(|f| general_purpose::STANDARD.encode(f))
+            .collect();
+        (format!("MORTYGPS{}\n", encoded.join(",")), frames.len())
+    }
+
+    #[test]
+    fn a_paused_gateway_gets_the_held_frames_when_it_resumes() {
+        let pong = frame(0xff);
+        let mut beacon = Beacon::new(8);
+        beacon.push(&frame(1));
+        // The line in progress goes out before the pong that confirms the pause
+        beacon.ping(true, &pong);
+        for n in 2..=4 {
+            beacon.push(&frame(n));
+        }
+        beacon.flush();
+        // Pongs still go out, nothing else does
+        beacon.ping(true, &pong);
+        assert_eq!(
+            beacon.lines,
+            [
+                encoded_line(&[frame(1)]),
+                encoded_line(&[pong.clone()]),
+                encoded_line(&[pong.clone()]),
+            ]
+        );
+        beacon.ping(false, &pong);
+        beacon.push(&frame(5));
+        beacon.flush();
+        assert_eq!(
+            beacon.lines[3..],
+            [
+                encoded_line(&[frame(2), frame(3), frame(4)]),
+                encoded_line(&[pong.clone()]),
+                encoded_line(&[frame(5)]),
+            ]
+        );
+    }
+}
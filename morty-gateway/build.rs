@@ -2,5 +2,34 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     embuild::build::CfgArgs::output_propagated("ESP_IDF")?;
     embuild::build::LinkArgs::output_propagated("ESP_IDF")?;
+    ota_public_key()?;
+    Ok(())
+}
+
+// The Ed25519 public key OTA manifests have to be signed with, as 64 hex digits in
+// MORTY_OTA_PUBLIC_KEY, see sign_manifest.py. Without it the gateway doesn't do OTA updates.
+fn ota_public_key() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-env-changed=MORTY_OTA_PUBLIC_KEY");
+    let key = match std::env::var("MORTY_OTA_PUBLIC_KEY") {
+        Ok(hex) => {
+            let hex = hex.trim();
+            if hex.len() != 64 || !hex.is_ascii() {
+                return Err(
+                    format!("MORTY_OTA_PUBLIC_KEY should be 64 hex digits, not {hex}").into(),
+                );
+            }
+            let bytes = (0..32)
+                .map(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| format!("MORTY_OTA_PUBLIC_KEY isn't hex: {hex}"))?;
+            format!("Some({bytes:?})")
+        }
+        Err(_) => "None".to_string(),
+    };
+    let out = std::path::Path::new(&std::env::var("OUT_DIR")?).join("ota_public_key.rs");
+    std::fs::write(
+        out,
+        format!("pub const OTA_PUBLIC_KEY: Option<[u8; 32]> = {key};\n"),
+    )?;
     Ok(())
 }
@@ -0,0 +1,57 @@
+#!/usr/bin/env python3
+"""Keys and manifests for over the air updates of the gateway, see `ota` in morty-rs.
+
+    sign_manifest.py keygen <private key file>
+        Make a key pair. Prints the public key, build the gateway with it in MORTY_OTA_PUBLIC_KEY.
+
+    sign_manifest.py sign <private key file> <image> <url> <version> <min compatible version> \
+            [--allow-downgrade]
+        Print the signed manifest of <image>, which is downloaded from <url>. Upload it with
+        `curl -X PUT --data-binary @manifest https://<backend>/api/v1/ota/manifest`.
+"""
+
+import hashlib
+import sys
+
+from cryptography.hazmat.primitives import serialization
+from cryptography.hazmat.primitives.asymmetric.ed25519 import Ed25519PrivateKey
+
+
+def keygen(path):
+    key = Ed25519PrivateKey.generate()
+    with open(path, 'wb') as f:
+        f.write(key.private_bytes(serialization.Encoding.PEM, serialization.PrivateFormat.PKCS8,
+                                  serialization.NoEncryption()))
+    public = key.public_key().public_bytes(serialization.Encoding.Raw,
+                                           serialization.PublicFormat.Raw)
+    print(public.hex())
+
+
+def sign(path, image, url, version, min_compatible_version, allow_downgrade):
+    with open(path, 'rb') as f:
+        key = serialization.load_pem_private_key(f.read(), password=None)
+    with open(image, 'rb') as f:
+        sha256 = hashlib.sha256(f.read()).hexdigest()
+    # The signature is over everything before its own line
+    payload = (f'version={version}\n'
+               f'url={url}\n'
+               f'sha256={sha256}\n'
+               f'min_compatible_version={min_compatible_version}\n'
+               f'allow_downgrade={"true" if allow_downgrade else "false"}\n')
+    signature = key.sign(payload.encode())
+    sys.stdout.write(f'{payload}signature={signature.hex()}\n')
+
+
+def main(args):
+    allow_downgrade = '--allow-downgrade' in args
+    args = [a for a in args if a != '--allow-downgrade']
+    if len(args) == 2 and args[0] == 'keygen':
+        keygen(args[1])
+    elif len(args) == 6 and args[0] == 'sign':
+        sign(*args[1:], allow_downgrade)
+    else:
+        sys.exit(__doc__)
+
+
+if __name__ == '__main__':
+    main(sys.argv[1:])
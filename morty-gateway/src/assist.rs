@@ -0,0 +1,101 @@
+//! GPS assistance data for the trackers. Once a day we download an EPO file, which lets the GPS
+//! module get a fix a lot quicker after it lost power, and send the part that is currently valid
+//! to the trackers in chunks, through the beacon. Trackers only listen while they're awake, so we
+//! repeat the transfer every now and then.
+
+use crate::downlink::http_get;
+use crate::downlink::send_frame;
+use log::*;
+use morty_rs::budget::MAX_CHUNK_DATA_LEN;
+use morty_rs::clock::Clock;
+use morty_rs::epo;
+use morty_rs::messages::morty_message;
+use morty_rs::messages::TransferAckMsg;
+use morty_rs::transfer;
+use morty_rs::utils::Backoff;
+use morty_rs::utils::PeriodicSet;
+use std::time::Duration;
+
+// Number of 6 hour segments we send, the trackers get new ones long before these run out
+const SEGMENTS: usize = 4;
+// Time between chunks, so the beacon and the radio can keep up
+const CHUNK_INTERVAL: Duration = Duration::from_millis(50);
+// How often we look at the timers
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// Timers for downloading the EPO file and sending it
+const DOWNLOAD: &str = "download";
+const SEND: &str = "send";
+// Delays before downloading again after a failed download
+const RETRY_DELAY: Duration = Duration::from_secs(60);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(60 * 60);
+
+/// Download the EPO file at `url` every `refresh` and send it to the trackers every `resend`.
+pub fn assist_task(uart_port: i32, url: &str, refresh: Duration, resend: Duration) -> ! {
+    let clock = Clock::new().unwrap();
+    let mut schedule = PeriodicSet::new();
+    schedule.add(DOWNLOAD, refresh);
+    schedule.add(SEND, resend);
+    let mut epo_file: Option<Vec<u8>> = None;
+    let mut backoff = Backoff::new(RETRY_DELAY, 2, RETRY_MAX_DELAY.min(refresh)).with_jitter();
+
+    loop {
+        if schedule.due(DOWNLOAD, clock.monotonic()) {
+            match http_get(url) {
+                Ok(data) if epo::valid_until(&data).is_some() => {
+                    info!("Downloaded {} bytes of GPS assistance data", data.len());
+                    epo_file = Some(data);
+                    backoff.reset();
+                }
+                Ok(_) => error!("{url} is not an EPO file"),
+                Err(e) => {
+                    error!("Unable to download GPS assistance data: {:?}", e);
+                    if let Some(delay) = backoff.next_delay() {
+                        schedule.restore(DOWNLOAD, clock.monotonic() + delay);
+                    }
+                }
+            }
+        }
+
+        if let (Some(data), Some(now)) = (&epo_file, clock.wall()) {
+            if schedule.due(SEND, clock.monotonic()) {
+                match epo::current(data, now.as_secs() as i64, SEGMENTS) {
+                    Some(current) => send(uart_port, current),
+                    None => warn!("GPS assistance data is stale, not sending it"),
+                }
+            }
+        }
+
+        std::thread::sleep(CHECK_INTERVAL);
+    }
+}
+
+/// Log the progress of a transfer, as reported by a tracker.
+pub fn log_ack(src: &str, ack: &TransferAckMsg) {
+    if ack.done {
+        info!(
+            "GPS assistance transfer {} to {src} done, ok: {}",
+            ack.transfer_id, ack.ok
+        );
+    } else {
+        info!(
+            "GPS assistance transfer {} to {src}: {}/{} chunks",
+            ack.transfer_id, ack.received, ack.count
+        );
+    }
+}
+
+fn send(uart_port: i32, data: &[u8]) {
+    // The same data gets the same transfer id, so trackers can pick up chunks they missed the
+    // previous time around
+    let valid_until = epo::valid_until(data).unwrap_or(0);
+    let transfer_id = valid_until as u32;
+    let chunks = transfer::split(data, transfer_id, "", valid_until, *MAX_CHUNK_DATA_LEN);
+    info!(
+        "Sending GPS assistance transfer {transfer_id} in {} chunks",
+        chunks.len()
+    );
+    for chunk in chunks {
+        send_frame(uart_port, &morty_message::Msg::Chunk(chunk));
+        std::thread::sleep(CHUNK_INTERVAL);
+    }
+}
@@ -0,0 +1,155 @@
+use log::*;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::fmt;
+use std::hash::Hasher;
+
+/// A single attempt to upload a fix.
+#[derive(Clone, Debug)]
+pub struct AuditEntry {
+    pub uid: String,
+    pub src: String,
+    // Starts at 1 and goes up with every retry of the same upload
+    pub attempt: u32,
+    // The Idempotency-Key we sent, the same for every attempt
+    pub idempotency_key: String,
+    // HTTP status and a hash of the response body, None when we didn't get a response
+    pub status: Option<u16>,
+    pub body_hash: Option<u64>,
+}
+
+impl fmt::Display for AuditEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} from {} attempt {} key {}: ",
+            self.uid, self.src, self.attempt, self.idempotency_key
+        )?;
+        match (self.status, self.body_hash) {
+            (Some(status), Some(hash)) => write!(f, "HTTP {status}, body {hash:016x}"),
+            _ => write!(f, "no response"),
+        }
+    }
+}
+
+/// The last couple of upload attempts. When the backend shows a fix twice, this tells us whether
+/// we uploaded it twice.
+pub struct AuditLog {
+    entries: VecDeque<AuditEntry>,
+    size: usize,
+}
+
+impl AuditLog {
+    pub fn new(size: usize) -> Self {
+        Self {
+            entries: VecDeque::new(),
+            size,
+        }
+    }
+
+    /// Record an attempt, dropping the oldest one when the log is full.
+    pub fn record(&mut self, entry: AuditEntry) {
+        self.entries.push_back(entry);
+        if self.entries.len() > self.size {
+            self.entries.pop_front();
+        }
+    }
+
+    /// Forget all attempts.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// All attempts for `uid` that are still in the log, oldest first.
+    pub fn query<'a>(&'a self, uid: &'a str) -> impl Iterator<Item = &'a AuditEntry> {
+        self.entries.iter().filter(move |e| e.uid == uid)
+    }
+
+    /// Write all attempts for `uid` to the console.
+    pub fn log(&self, uid: &str) {
+        let mut found = false;
+        for entry in self.query(uid) {
+            info!("Audit: {entry}");
+            found = true;
+        }
+        if !found {
+            info!("Audit: no upload attempts for {uid}");
+        }
+    }
+}
+
+/// Hash of a response body, so responses can be told apart without keeping them around.
+pub fn body_hash(body: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(body);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attempt(uid: &str, attempt: u32, status: Option<u16>) -> AuditEntry {
+        AuditEntry {
+            uid: uid.to_string(),
+            src: "beacon".to_string(),
+            attempt,
+            idempotency_key: format!("{uid}-key"),
+            status,
+            body_hash: status.map(|_| body_hash(b"ok")),
+        }
+    }
+
+    #[test]
+    fn the_oldest_attempts_fall_out() {
+        let mut log = AuditLog::new(3);
+        for i in 1..=5 {
+            log.record(attempt("a", i, None));
+        }
+        let attempts: Vec<u32> = log.query("a").map(|e| e.attempt).collect();
+        assert_eq!(attempts, [3, 4, 5]);
+    }
+
+    #[test]
+    fn query_only_returns_the_uid_oldest_first() {
+        let mut log = AuditLog::new(10);
+        log.record(attempt("a", 1, None));
+        log.record(attempt("b", 1, Some(200)));
+        log.record(attempt("a", 2, Some(500)));
+        let attempts: Vec<u32> = log.query("a").map(|e| e.attempt).collect();
+        assert_eq!(attempts, [1, 2]);
+        assert_eq!(log.query("b").count(), 1);
+        assert_eq!(log.query("c").count(), 0);
+    }
+
+    #[test]
+    fn clear_forgets_everything() {
+        let mut log = AuditLog::new(10);
+        log.record(attempt("a", 1, None));
+        log.clear();
+        assert_eq!(log.query("a").count(), 0);
+    }
+
+    #[test]
+    fn entries_show_the_response_when_there_was_one() {
+        let entry = attempt("a", 2, None);
+        assert_eq!(
+            entry.to_string(),
+            "a from beacon attempt 2 key a-key: no response"
+        );
+        let entry = attempt("a", 2, Some(200));
+        assert_eq!(
+            entry.to_string(),
+            format!(
+                "a from beacon attempt 2 key a-key: HTTP 200, body {:016x}",
+                body_hash(b"ok")
+            )
+        );
+    }
+
+    #[test]
+    fn different_bodies_hash_differently() {
+        assert_eq!(body_hash(b"ok"), body_hash(b"ok"));
+        assert_ne!(body_hash(b"ok"), body_hash(b"not ok"));
+    }
+}
@@ -0,0 +1,389 @@
+//! Beacons bridged over TCP, for beacons too far from the gateway for a cable but with a network
+//! nearby. A serial to ethernet or wifi converter on the UART of the beacon connects to
+//! `bridge_port` and passes the UART through as it is, so the lines are the same as on our own
+//! UART. They are read with the same `frames::read_line_bounded` and go into the same pipeline,
+//! tagged with the address of the connection, see `frames::Via`.
+//!
+//! Like the beacon on the UART, a bridged beacon is pinged, when it connects and every
+//! `ping_interval` after that, and gets our time beacons, see `link`. The pong goes back to the
+//! connection it came from, so `status` on the console shows which beacon is on the other end.
+//! Commands for the devices only go out over the UART.
+//!
+//! Every connection is read by its own thread. A connection that sends nothing for `bridge_idle`
+//! is closed, the converter is expected to connect again. One that connects again from the same
+//! address replaces its old connection, which is mostly gone without us noticing. We take at most
+//! MAX_BRIDGES at the same time.
+
+use crate::config::config;
+use crate::downlink::frame_line;
+use crate::frames::read_line_bounded;
+use crate::frames::Via;
+use crate::frames::MAX_LINE_LEN;
+use crate::handoff::LineSender;
+use crate::link::BeaconIdentity;
+use crate::link::LinkMonitor;
+use crate::maintenance;
+use crate::BRIDGE_READ_THREAD;
+use crate::BRIDGE_THREAD;
+use log::*;
+use morty_rs::clock::Clock;
+use morty_rs::comm::own_mac;
+use morty_rs::messages::morty_message;
+use morty_rs::messages::PongMsg;
+use morty_rs::timesync::time_beacon;
+use morty_rs::utils::format_duration;
+use morty_rs::utils::spawn_thread;
+use std::io::BufReader;
+use std::io::ErrorKind;
+use std::io::Write;
+use std::net::Shutdown;
+use std::net::SocketAddr;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Connections we take at the same time, more are refused
+const MAX_BRIDGES: usize = 4;
+// How often we look for new connections, pings to send and pongs that didn't come
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A beacon connected over TCP, as `status` shows it.
+#[derive(Clone, Debug)]
+pub struct Bridge {
+    pub peer: SocketAddr,
+    /// Monotonic time it connected
+    pub connected_at: Duration,
+    pub lines: u32,
+    /// Lines longer than MAX_LINE_LEN, which were dropped
+    pub dropped: u32,
+    /// Monotonic time of the last line, None before the first one
+    pub last_line: Option<Duration>,
+    /// Pings that weren't answered in time
+    pub missed_pongs: u32,
+    /// Who the beacon said it is in its last pong
+    pub beacon: Option<BeaconIdentity>,
+}
+
+impl Bridge {
+    pub fn status_line(&self, now: Duration) -> String {
+        let beacon = self
+            .beacon
+            .as_ref()
+            .map_or("unknown beacon".to_string(), |b| {
+                format!("beacon {} firmware {}", b.device_id, b.firmware_version)
+            });
+        let last_line = self.last_line.map_or("never".to_string(), |t| {
+            format!("{} ago", format_duration(now.saturating_sub(t)))
+        });
+        format!(
+            "{} {beacon}, connected for {}, lines={} dropped={} missed_pongs={}, \
+             last line {last_line}",
+            self.peer,
+            format_duration(now.saturating_sub(self.connected_at)),
+            self.lines,
+            self.dropped,
+            self.missed_pongs
+        )
+    }
+}
+
+struct Connection {
+    // Tells a connection apart from a later one from the same address
+    id: u32,
+    bridge: Bridge,
+    // To write pings and time beacons to, and to close it
+    stream: TcpStream,
+    monitor: LinkMonitor,
+    next_ping: Duration,
+}
+
+static CONNECTIONS: Mutex<Vec<Connection>> = Mutex::new(Vec::new());
+
+/// The bridges that are connected.
+pub fn bridges() -> Vec<Bridge> {
+    CONNECTIONS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|c| c.bridge.clone())
+        .collect()
+}
+
+/// Hand a pong that came in over the bridge at `peer` to its connection.
+pub fn pong(peer: SocketAddr, pong: &PongMsg) {
+    let mut connections = CONNECTIONS.lock().unwrap();
+    let Some(connection) = connections.iter_mut().find(|c| c.bridge.peer == peer) else {
+        return;
+    };
+    if connection.monitor.pong(pong).is_some() {
+        info!(
+            "Bridge {peer} is beacon {}, firmware {}",
+            pong.device_id, pong.firmware_version
+        );
+    }
+    connection.bridge.beacon = connection.monitor.beacon().cloned();
+}
+
+/// Accept bridges on `port` and hand their lines to `lines`. Connections that send nothing for
+/// `idle` are closed.
+pub fn start(port: u16, idle: Duration, lines: LineSender) -> Result<(), anyhow::Error> {
+    let listener = TcpListener::bind(("0.0.0.0", port))?;
+    // Accepting is polled, so the same thread can ping the bridges
+    listener.set_nonblocking(true)?;
+    info!("Accepting bridges on port {port}");
+    spawn_thread(BRIDGE_THREAD, move || bridge_task(listener, idle, lines))?;
+    Ok(())
+}
+
+/// Accept connections and ping the bridges.
+fn bridge_task(listener: TcpListener, idle: Duration, lines: LineSender) -> ! {
+    let clock = Clock::new().unwrap();
+    let mut next_id = 0;
+    let mut next_time = Duration::ZERO;
+    loop {
+        loop {
+            match listener.accept() {
+                Ok((stream, peer)) => {
+                    next_id += 1;
+                    if let Err(e) = accept(stream, peer, next_id, idle, &lines, &clock) {
+                        error!("Unable to take bridge {peer}: {e}");
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    error!("Unable to accept a bridge: {e}");
+                    break;
+                }
+            }
+        }
+
+        let now = clock.monotonic();
+        let time = if now >= next_time {
+            next_time = now + config().time_beacon_interval;
+            clock.wall().map(time_beacon)
+        } else {
+            None
+        };
+        for connection in CONNECTIONS.lock().unwrap().iter_mut() {
+            if connection.monitor.check(now).is_some() {
+                connection.bridge.missed_pongs += 1;
+                warn!("Beacon on bridge {} doesn't answer", connection.bridge.peer);
+            }
+            let mut messages = Vec::new();
+            if now >= connection.next_ping {
+                let ping = connection.monitor.ping(now, maintenance::paused());
+                messages.push(morty_message::Msg::Ping(ping));
+                connection.next_ping = now + config().beacon_ping_interval;
+            }
+            if let Some(time) = &time {
+                messages.push(morty_message::Msg::TimeBeacon(time.clone()));
+            }
+            for msg in messages {
+                let line = match frame_line(&msg) {
+                    Ok(line) => line,
+                    Err(e) => {
+                        error!("Unable to encode {msg:?}: {e}");
+                        continue;
+                    }
+                };
+                // The reading thread notices a broken connection and cleans up
+                if let Err(e) = connection.stream.write_all(line.as_bytes()) {
+                    warn!("Unable to write to bridge {}: {e}", connection.bridge.peer);
+                    break;
+                }
+            }
+        }
+        std::thread::sleep(CHECK_INTERVAL);
+    }
+}
+
+/// Add the connection from `peer` and start reading it.
+fn accept(
+    stream: TcpStream,
+    peer: SocketAddr,
+    id: u32,
+    idle: Duration,
+    lines: &LineSender,
+    clock: &Clock,
+) -> Result<(), anyhow::Error> {
+    // The listener doesn't block, but the connection should
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(idle))?;
+    // Pings are written with the connections locked, a bridge that doesn't read can't hold that up
+    stream.set_write_timeout(Some(CHECK_INTERVAL))?;
+    let mut connections = CONNECTIONS.lock().unwrap();
+    // A bridge that connects again, probably after losing its network, replaces its old
+    // connection
+    if let Some(i) = connections
+        .iter()
+        .position(|c| c.bridge.peer.ip() == peer.ip())
+    {
+        let old = connections.remove(i);
+        info!("Bridge {peer} replaces {}", old.bridge.peer);
+        let _ = old.stream.shutdown(Shutdown::Both);
+    }
+    if connections.len() >= MAX_BRIDGES {
+        warn!("Refusing bridge {peer}, there are {MAX_BRIDGES} already");
+        let _ = stream.shutdown(Shutdown::Both);
+        return Ok(());
+    }
+
+    let reader = stream.try_clone()?;
+    let now = clock.monotonic();
+    connections.push(Connection {
+        id,
+        bridge: Bridge {
+            peer,
+            connected_at: now,
+            lines: 0,
+            dropped: 0,
+            last_line: None,
+            missed_pongs: 0,
+            beacon: None,
+        },
+        stream,
+        monitor: LinkMonitor::new(config().beacon_pong_timeout, &own_mac().unwrap_or_default()),
+        // Ping right away, to learn who's on the other end
+        next_ping: now,
+    });
+    drop(connections);
+    info!("Bridge {peer} connected");
+
+    let lines = lines.clone();
+    if let Err(e) = spawn_thread(BRIDGE_READ_THREAD, move || {
+        read_bridge(reader, peer, id, lines)
+    }) {
+        remove(id);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Read lines from the bridge at `peer` until it's closed or idle, then forget it.
+fn read_bridge(stream: TcpStream, peer: SocketAddr, id: u32, lines: LineSender) {
+    let clock = Clock::new().unwrap();
+    let mut reader = BufReader::new(stream);
+    loop {
+        let mut buffer = String::with_capacity(MAX_LINE_LEN);
+        let complete = match read_line_bounded(&mut reader, &mut buffer) {
+            Ok(_) if buffer.is_empty() => {
+                info!("Bridge {peer} disconnected");
+                break;
+            }
+            Ok(complete) => complete,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                info!("Closing bridge {peer}, nothing was received for a while");
+                break;
+            }
+            Err(e) => {
+                warn!("Bridge {peer} dropped: {e}");
+                break;
+            }
+        };
+        let now = clock.monotonic();
+        if !update(id, |bridge| {
+            bridge.last_line = Some(now);
+            if complete {
+                bridge.lines += 1;
+            } else {
+                bridge.dropped += 1;
+            }
+        }) {
+            // Replaced by a new connection from the same address
+            return;
+        }
+        if !complete {
+            warn!("Dropping line longer than {MAX_LINE_LEN} bytes from bridge {peer}");
+            continue;
+        }
+        if lines.send((now, Via::Bridge(peer), buffer)).is_err() {
+            break;
+        }
+    }
+    remove(id);
+}
+
+/// Update the stats of connection `id`. Returns false when it's gone.
+fn update(id: u32, f: impl FnOnce(&mut Bridge)) -> bool {
+    let mut connections = CONNECTIONS.lock().unwrap();
+    match connections.iter_mut().find(|c| c.id == id) {
+        Some(connection) => {
+            f(&mut connection.bridge);
+            true
+        }
+        None => false,
+    }
+}
+
+fn remove(id: u32) {
+    let mut connections = CONNECTIONS.lock().unwrap();
+    if let Some(i) = connections.iter().position(|c| c.id == id) {
+        let connection = connections.remove(i);
+        let _ = connection.stream.shutdown(Shutdown::Both);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    fn bridge() -> Bridge {
+        Bridge {
+            peer: "192.168.1.20:40123".parse().unwrap(),
+            connected_at: secs(10),
+            lines: 0,
+            dropped: 0,
+            last_line: None,
+            missed_pongs: 0,
+            beacon: None,
+        }
+    }
+
+    #[test]
+    fn a_new_bridge_shows_what_we_dont_know_yet() {
+        assert_eq!(
+            bridge().status_line(secs(15)),
+            "192.168.1.20:40123 unknown beacon, connected for 5s, lines=0 dropped=0 \
+             missed_pongs=0, last line never"
+        );
+    }
+
+    #[test]
+    fn a_bridge_shows_its_beacon_and_stats() {
+        let bridge = Bridge {
+            lines: 12,
+            dropped: 1,
+            last_line: Some(secs(40)),
+            missed_pongs: 2,
+            beacon: Some(BeaconIdentity {
+                device_id: "beacon-1".to_string(),
+                firmware_version: "1.2.3".to_string(),
+                config_version: 4,
+            }),
+            ..bridge()
+        };
+        assert_eq!(
+            bridge.status_line(secs(50)),
+            "192.168.1.20:40123 beacon beacon-1 firmware 1.2.3, connected for 40s, lines=12 \
+             dropped=1 missed_pongs=2, last line 10s ago"
+        );
+    }
+
+    #[test]
+    fn a_clock_behind_the_bridge_doesnt_underflow() {
+        let bridge = Bridge {
+            last_line: Some(secs(20)),
+            ..bridge()
+        };
+        assert_eq!(
+            bridge.status_line(secs(5)),
+            "192.168.1.20:40123 unknown beacon, connected for 0s, lines=0 dropped=0 \
+             missed_pongs=0, last line 0s ago"
+        );
+    }
+}
@@ -0,0 +1,298 @@
+//! The last frames from the UART that didn't decode, so we can look at the actual bytes when the
+//! decode failures go up. Capturing is off until it's started from the console and stops by
+//! itself after a while, so it can't be forgotten. Memory is bounded by the number of frames and
+//! the length we keep of every frame.
+
+use morty_rs::comm::DecodeFailure;
+use std::collections::VecDeque;
+use std::io::Write;
+use std::time::Duration;
+
+/// Longest part of a frame we keep, ESP-NOW frames are at most 250 bytes.
+pub const MAX_FRAME_LEN: usize = 300;
+// Start of a binary dump, with the version of the format
+const BINARY_MAGIC: &[u8; 4] = b"MFF\x01";
+// Cause bytes of frames that weren't valid base64, or failed for a reason we don't sort
+const BASE64_CAUSE: u8 = 0xff;
+const UNKNOWN_CAUSE: u8 = 0xfe;
+const HEXDUMP_WIDTH: usize = 16;
+
+/// Why a frame didn't decode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cause {
+    /// The frame wasn't valid base64, the frame has the base64 text instead of the bytes
+    Base64,
+    Decode(DecodeFailure),
+    /// The decoder failed in a way `classify_decode_error` doesn't know
+    Unknown,
+}
+
+impl Cause {
+    fn name(&self) -> &'static str {
+        match self {
+            Cause::Base64 => "base64",
+            Cause::Decode(cause) => cause.name(),
+            Cause::Unknown => "unknown",
+        }
+    }
+
+    fn byte(&self) -> u8 {
+        match self {
+            Cause::Base64 => BASE64_CAUSE,
+            Cause::Decode(cause) => *cause as u8,
+            Cause::Unknown => UNKNOWN_CAUSE,
+        }
+    }
+}
+
+/// A frame that didn't decode.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FailedFrame {
+    // Monotonic time at which the line with the frame came in
+    pub received_at: Duration,
+    pub cause: Cause,
+    // Length of the whole frame, `data` is cut off at MAX_FRAME_LEN
+    pub len: usize,
+    pub data: Vec<u8>,
+}
+
+impl FailedFrame {
+    pub fn new(received_at: Duration, cause: Cause, data: &[u8]) -> Self {
+        Self {
+            received_at,
+            cause,
+            len: data.len(),
+            data: data[..data.len().min(MAX_FRAME_LEN)].to_vec(),
+        }
+    }
+}
+
+/// Ring of the last failed frames, while capturing.
+pub struct FailedFrames {
+    frames: VecDeque<FailedFrame>,
+    size: usize,
+    max_time: Duration,
+    // Time at which capturing stops, None when it's off
+    until: Option<Duration>,
+}
+
+impl FailedFrames {
+    /// Keep the last `size` frames, capturing for at most `max_time` after it's started.
+    pub fn new(size: usize, max_time: Duration) -> Self {
+        Self {
+            frames: VecDeque::with_capacity(size),
+            size,
+            max_time,
+            until: None,
+        }
+    }
+
+    /// Start capturing at `now`, or capture for longer when we already are.
+    pub fn start(&mut self, now: Duration) {
+        self.until = Some(now + self.max_time);
+    }
+
+    pub fn stop(&mut self) {
+        self.until = None;
+    }
+
+    /// Whether we're capturing at `now`. Returns false from the time capturing stops by itself.
+    pub fn capturing(&mut self, now: Duration) -> bool {
+        match self.until {
+            Some(until) if now < until => true,
+            Some(_) => {
+                self.until = None;
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Keep `frame` when we're capturing at `now`, dropping the oldest frame when the ring is
+    /// full.
+    pub fn record(&mut self, frame: FailedFrame, now: Duration) {
+        if self.size == 0 || !self.capturing(now) {
+            return;
+        }
+        if self.frames.len() == self.size {
+            self.frames.pop_front();
+        }
+        self.frames.push_back(frame);
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    /// The frames, oldest first.
+    pub fn frames(&self) -> Vec<FailedFrame> {
+        self.frames.iter().cloned().collect()
+    }
+}
+
+/// Write `frames` as a hexdump, every frame with a line saying when it came in and why it failed.
+pub fn write_hexdump(out: &mut impl Write, frames: &[FailedFrame]) -> std::io::Result<()> {
+    for frame in frames {
+        writeln!(
+            out,
+            "{:.3}s {} {} bytes{}",
+            frame.received_at.as_secs_f64(),
+            frame.cause.name(),
+            frame.len,
+            if frame.len > frame.data.len() {
+                format!(", first {}", frame.data.len())
+            } else {
+                String::new()
+            }
+        )?;
+        for (i, row) in frame.data.chunks(HEXDUMP_WIDTH).enumerate() {
+            let hex: Vec<String> = row.iter().map(|b| format!("{b:02x}")).collect();
+            let ascii: String = row
+                .iter()
+                .map(|&b| {
+                    if b.is_ascii_graphic() || b == b' ' {
+                        b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            writeln!(
+                out,
+                "  {:04x}  {:width$}  {ascii}",
+                i * HEXDUMP_WIDTH,
+                hex.join(" "),
+                width = HEXDUMP_WIDTH * 3 - 1
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Write `frames` as a binary blob: the magic and for every frame the time it came in in
+/// microseconds (u64), the cause (u8, `DecodeFailure` order, 0xfe for unknown and 0xff for
+/// base64), the length of the frame (u16), the length that follows (u16) and the data. Numbers
+/// are little endian.
+pub fn write_binary(out: &mut impl Write, frames: &[FailedFrame]) -> std::io::Result<()> {
+    out.write_all(BINARY_MAGIC)?;
+    for frame in frames {
+        out.write_all(&(frame.received_at.as_micros() as u64).to_le_bytes())?;
+        out.write_all(&[frame.cause.byte()])?;
+        out.write_all(&(frame.len.min(u16::MAX as usize) as u16).to_le_bytes())?;
+        out.write_all(&(frame.data.len() as u16).to_le_bytes())?;
+        out.write_all(&frame.data)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_TIME: Duration = Duration::from_secs(600);
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    fn frame(byte: u8) -> FailedFrame {
+        FailedFrame::new(secs(byte as u64), Cause::Unknown, &[byte])
+    }
+
+    fn captured(frames: &FailedFrames) -> Vec<u8> {
+        frames.frames().iter().map(|f| f.data[0]).collect()
+    }
+
+    #[test]
+    fn nothing_is_kept_until_capturing_starts() {
+        let mut frames = FailedFrames::new(4, MAX_TIME);
+        frames.record(frame(1), secs(0));
+        frames.start(secs(0));
+        frames.record(frame(2), secs(1));
+        frames.stop();
+        frames.record(frame(3), secs(2));
+        assert_eq!(captured(&frames), [2]);
+    }
+
+    #[test]
+    fn capturing_stops_by_itself() {
+        let mut frames = FailedFrames::new(4, MAX_TIME);
+        frames.start(secs(100));
+        assert!(frames.capturing(secs(699)));
+        frames.record(frame(1), secs(699));
+        assert!(!frames.capturing(secs(700)));
+        frames.record(frame(2), secs(700));
+        // Also when the clock would say otherwise
+        assert!(!frames.capturing(secs(0)));
+        assert_eq!(captured(&frames), [1]);
+    }
+
+    #[test]
+    fn starting_again_captures_for_longer() {
+        let mut frames = FailedFrames::new(4, MAX_TIME);
+        frames.start(secs(0));
+        frames.start(secs(500));
+        assert!(frames.capturing(secs(1000)));
+    }
+
+    #[test]
+    fn the_oldest_frames_make_room() {
+        let mut frames = FailedFrames::new(2, MAX_TIME);
+        frames.start(secs(0));
+        for byte in 1..=3 {
+            frames.record(frame(byte), secs(1));
+        }
+        assert_eq!(captured(&frames), [2, 3]);
+        frames.clear();
+        assert_eq!(captured(&frames), []);
+    }
+
+    #[test]
+    fn long_frames_are_cut_off() {
+        let frame = FailedFrame::new(secs(0), Cause::Base64, &[0; MAX_FRAME_LEN + 10]);
+        assert_eq!(frame.len, MAX_FRAME_LEN + 10);
+        assert_eq!(frame.data.len(), MAX_FRAME_LEN);
+    }
+
+    #[test]
+    fn hexdumps_show_bytes_and_text() {
+        let mut data = b"MORTY frame\x00\x01\xff".to_vec();
+        data.extend_from_slice(b"ok!");
+        let frames = [FailedFrame {
+            received_at: Duration::from_millis(1500),
+            cause: Cause::Decode(DecodeFailure::CrcMismatch),
+            len: 40,
+            data,
+        }];
+        let mut out = Vec::new();
+        write_hexdump(&mut out, &frames).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "1.500s crc_mismatch 40 bytes, first 17\n\
+             \x20 0000  4d 4f 52 54 59 20 66 72 61 6d 65 00 01 ff 6f 6b  MORTY frame...ok\n\
+             \x20 0010  21                                               !\n"
+        );
+    }
+
+    #[test]
+    fn binary_dumps_have_a_header_per_frame() {
+        let frames = [
+            FailedFrame::new(
+                Duration::from_micros(0x0102),
+                Cause::Decode(DecodeFailure::TypeMismatch),
+                &[0xaa, 0xbb],
+            ),
+            FailedFrame::new(secs(0), Cause::Base64, b"!"),
+        ];
+        let mut out = Vec::new();
+        write_binary(&mut out, &frames).unwrap();
+        assert_eq!(
+            out,
+            [
+                b'M', b'F', b'F', 1, // magic
+                0x02, 0x01, 0, 0, 0, 0, 0, 0, 2, 2, 0, 2, 0, 0xaa, 0xbb, // first frame
+                0, 0, 0, 0, 0, 0, 0, 0, 0xff, 1, 0, 1, 0, b'!', // second frame
+            ]
+        );
+    }
+}
@@ -0,0 +1,162 @@
+//! Just enough of a CBOR (RFC 8949) encoder for our uploads: maps with text keys and simple
+//! values. Everything is written in its shortest form, definite lengths only.
+
+// Major types
+const UNSIGNED: u8 = 0;
+const NEGATIVE: u8 = 1;
+const TEXT: u8 = 3;
+const MAP: u8 = 5;
+const SIMPLE: u8 = 7;
+
+// Simple values and floats, in major type 7
+const FALSE: u8 = 20;
+const TRUE: u8 = 21;
+const NULL: u8 = 22;
+const FLOAT32: u8 = 26;
+const FLOAT64: u8 = 27;
+
+#[derive(Default)]
+pub struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start a map of `len` pairs. Every pair is written as a key followed by a value.
+    pub fn map(&mut self, len: usize) -> &mut Self {
+        self.head(MAP, len as u64)
+    }
+
+    pub fn text(&mut self, s: &str) -> &mut Self {
+        self.head(TEXT, s.len() as u64);
+        self.buf.extend_from_slice(s.as_bytes());
+        self
+    }
+
+    pub fn int(&mut self, n: i64) -> &mut Self {
+        if n < 0 {
+            // -1 - n can't overflow for negative n
+            self.head(NEGATIVE, (-1 - n) as u64)
+        } else {
+            self.head(UNSIGNED, n as u64)
+        }
+    }
+
+    pub fn f32(&mut self, f: f32) -> &mut Self {
+        self.buf.push(SIMPLE << 5 | FLOAT32);
+        self.buf.extend_from_slice(&f.to_be_bytes());
+        self
+    }
+
+    pub fn f64(&mut self, f: f64) -> &mut Self {
+        self.buf.push(SIMPLE << 5 | FLOAT64);
+        self.buf.extend_from_slice(&f.to_be_bytes());
+        self
+    }
+
+    pub fn bool(&mut self, b: bool) -> &mut Self {
+        self.buf.push(SIMPLE << 5 | if b { TRUE } else { FALSE });
+        self
+    }
+
+    pub fn null(&mut self) -> &mut Self {
+        self.buf.push(SIMPLE << 5 | NULL);
+        self
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+
+    /// The initial byte of an item of `major` type with argument `n`, and the bytes of `n` that
+    /// don't fit in it.
+    fn head(&mut self, major: u8, n: u64) -> &mut Self {
+        let major = major << 5;
+        if n < 24 {
+            self.buf.push(major | n as u8);
+        } else if n <= u8::MAX as u64 {
+            self.buf.extend_from_slice(&[major | 24, n as u8]);
+        } else if n <= u16::MAX as u64 {
+            self.buf.push(major | 25);
+            self.buf.extend_from_slice(&(n as u16).to_be_bytes());
+        } else if n <= u32::MAX as u64 {
+            self.buf.push(major | 26);
+            self.buf.extend_from_slice(&(n as u32).to_be_bytes());
+        } else {
+            self.buf.push(major | 27);
+            self.buf.extend_from_slice(&n.to_be_bytes());
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(write: impl FnOnce(&mut Encoder) -> &mut Encoder) -> Vec<u8> {
+        let mut encoder = Encoder::new();
+        write(&mut encoder);
+        encoder.finish()
+    }
+
+    // The examples of RFC 8949, appendix A
+    #[test]
+    fn ints_are_written_in_their_shortest_form() {
+        let cases: &[(i64, &[u8])] = &[
+            (0, &[0x00]),
+            (23, &[0x17]),
+            (24, &[0x18, 0x18]),
+            (100, &[0x18, 0x64]),
+            (1000, &[0x19, 0x03, 0xe8]),
+            (1_000_000, &[0x1a, 0x00, 0x0f, 0x42, 0x40]),
+            (
+                1_000_000_000_000,
+                &[0x1b, 0x00, 0x00, 0x00, 0xe8, 0xd4, 0xa5, 0x10, 0x00],
+            ),
+            (-1, &[0x20]),
+            (-10, &[0x29]),
+            (-100, &[0x38, 0x63]),
+            (-1000, &[0x39, 0x03, 0xe7]),
+            (
+                i64::MIN,
+                &[0x3b, 0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+            ),
+        ];
+        for (n, expected) in cases {
+            assert_eq!(encode(|e| e.int(*n)), *expected, "{n}");
+        }
+    }
+
+    #[test]
+    fn texts_are_prefixed_with_their_length() {
+        assert_eq!(encode(|e| e.text("")), [0x60]);
+        assert_eq!(encode(|e| e.text("a")), [0x61, 0x61]);
+        assert_eq!(encode(|e| e.text("IETF")), [0x64, 0x49, 0x45, 0x54, 0x46]);
+        let long = "x".repeat(24);
+        assert_eq!(encode(|e| e.text(&long))[..2], [0x78, 24]);
+    }
+
+    #[test]
+    fn simple_values_and_floats() {
+        assert_eq!(encode(|e| e.bool(false)), [0xf4]);
+        assert_eq!(encode(|e| e.bool(true)), [0xf5]);
+        assert_eq!(encode(|e| e.null()), [0xf6]);
+        assert_eq!(encode(|e| e.f32(100000.0)), [0xfa, 0x47, 0xc3, 0x50, 0x00]);
+        assert_eq!(
+            encode(|e| e.f64(1.1)),
+            [0xfb, 0x3f, 0xf1, 0x99, 0x99, 0x99, 0x99, 0x99, 0x9a]
+        );
+    }
+
+    #[test]
+    fn maps_are_followed_by_their_pairs() {
+        assert_eq!(encode(|e| e.map(0)), [0xa0]);
+        // {"a": 1, "b": true}
+        let map = encode(|e| e.map(2).text("a").int(1).text("b").bool(true));
+        assert_eq!(map, [0xa2, 0x61, 0x61, 0x01, 0x61, 0x62, 0xf5]);
+    }
+}
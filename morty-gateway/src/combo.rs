@@ -0,0 +1,223 @@
+//! Combo mode, for small installations without a beacon: the gateway hears the trackers over
+//! ESP-NOW itself. ESP-NOW has to share the radio with the wifi connection, so it runs on the
+//! channel of the access point and without long range mode. Trackers have to be set up for that
+//! channel and without long range mode as well. Frames are wrapped in a RelayMsg like a beacon
+//! would and handed to the same pipeline as the lines from the UART. Like the beacon on the UART,
+//! we broadcast time beacons for the clocks of the trackers, see `morty_rs::timesync`.
+
+use crate::config::config;
+use crate::frames::Via;
+use crate::handoff::LineSender;
+use crate::COMBO_THREAD;
+use crate::FRAME_FORMAT;
+use crate::UART_FRAMES;
+use base64::engine::general_purpose;
+use base64::Engine;
+use esp_idf_svc::espnow::EspNow;
+use esp_idf_svc::systime::EspSystemTime;
+use log::*;
+use morty_rs::clock::clock_status;
+use morty_rs::clock::Clock;
+use morty_rs::comm::broadcast_msg;
+use morty_rs::comm::decode_msg;
+use morty_rs::comm::encode_msg;
+use morty_rs::comm::esp_now_init_on_sta_channel;
+use morty_rs::comm::mac_to_string;
+use morty_rs::comm::tx_power_dbm;
+use morty_rs::comm::CommError;
+use morty_rs::comm::FrameFormat;
+use morty_rs::comm::Priority;
+use morty_rs::comm::RecvDispatcher;
+use morty_rs::comm::RecvFrame;
+use morty_rs::comm::BEACON_PRESENT_TYPE;
+use morty_rs::comm::COMMAND_ACK_TYPE;
+use morty_rs::comm::GPS_TYPE;
+use morty_rs::comm::HELLO_TYPE;
+use morty_rs::comm::POWER_EVENT_TYPE;
+use morty_rs::comm::RELAY_TYPE;
+use morty_rs::comm::TRACE_TYPE;
+use morty_rs::comm::TRANSFER_ACK_TYPE;
+use morty_rs::comm::UART_HEADER;
+use morty_rs::hello;
+use morty_rs::messages::morty_message;
+use morty_rs::messages::relay_msg;
+use morty_rs::messages::BeaconPresentMsg;
+use morty_rs::messages::RelayMsg;
+use morty_rs::messages::Role;
+use morty_rs::timesync::time_beacon;
+use morty_rs::utils::spawn_thread;
+use morty_rs::utils::PeriodicSet;
+use morty_rs::BEACON_PRESENT_INTERVAL_SECONDS;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::Duration;
+
+// Timers
+const BEACON_PRESENT: &str = "beacon_present";
+const TIME_BEACON: &str = "time_beacon";
+
+/// Frames received over ESP-NOW, next to the lines from the UART.
+pub static ESP_NOW_FRAMES: AtomicU32 = AtomicU32::new(0);
+
+/// Frames dropped because the combo thread didn't keep up.
+pub static ESP_NOW_DROPPED: AtomicU32 = AtomicU32::new(0);
+
+// Frames that wait for the combo thread, more are dropped
+const RECV_QUEUE_SIZE: usize = 32;
+
+// The message types a beacon passes on, see `relay_line`
+const RELAYED_TYPES: &[u8] = &[
+    GPS_TYPE,
+    RELAY_TYPE,
+    COMMAND_ACK_TYPE,
+    TRANSFER_ACK_TYPE,
+    POWER_EVENT_TYPE,
+    HELLO_TYPE,
+    BEACON_PRESENT_TYPE,
+    TRACE_TYPE,
+];
+
+/// Start receiving over ESP-NOW and hand what we hear to `lines`. Wifi has to be connected. When
+/// this fails, the gateway carries on with just the UART.
+pub fn start(lines: LineSender) -> Result<(), anyhow::Error> {
+    let (esp_now, channel) = esp_now_init_on_sta_channel()?;
+    let mut dispatcher = RecvDispatcher::new();
+    let received = dispatcher.register(
+        "combo",
+        Some(RELAYED_TYPES),
+        RECV_QUEUE_SIZE,
+        &ESP_NOW_DROPPED,
+    );
+    dispatcher.freeze().attach(&esp_now)?;
+    info!("Receiving ESP-NOW on channel {channel}");
+    if let Err(e) = send_hello(&esp_now) {
+        error!("Unable to send hello: {e}");
+    }
+
+    spawn_thread(COMBO_THREAD, move || {
+        combo_task(esp_now, channel, received, lines)
+    })?;
+    Ok(())
+}
+
+/// Turn the frames into lines and tell the trackers we're here, like a beacon does.
+fn combo_task(esp_now: EspNow, channel: u8, received: Receiver<RecvFrame>, lines: LineSender) {
+    let clock = Clock::new().unwrap();
+    let mut schedule = PeriodicSet::new();
+    schedule.add(
+        BEACON_PRESENT,
+        Duration::from_secs(BEACON_PRESENT_INTERVAL_SECONDS),
+    );
+    schedule.add(TIME_BEACON, config().time_beacon_interval);
+    loop {
+        if schedule.due(BEACON_PRESENT, clock.monotonic()) {
+            if let Err(e) = beacon_present(&esp_now, channel) {
+                error!("Unable to send beacon present message: {e}");
+            }
+        }
+        if schedule.due(TIME_BEACON, clock.monotonic()) {
+            if let Some(wall) = clock.wall() {
+                let msg = morty_message::Msg::TimeBeacon(time_beacon(wall));
+                if let Err(e) = broadcast_msg(&msg, Priority::Routine, &esp_now) {
+                    error!("Unable to send time beacon: {e}");
+                }
+            }
+        }
+        let frame = match received.recv_timeout(Duration::from_secs(1)) {
+            Ok(frame) => frame,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        ESP_NOW_FRAMES.fetch_add(1, Ordering::Relaxed);
+        if let Some(line) = relay_line(&frame.src, &frame.data) {
+            if lines.send((frame.received_at, Via::EspNow, line)).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Tell the trackers and beacons on our channel that we booted, see `morty_rs::hello`.
+fn send_hello(esp_now: &EspNow) -> Result<(), anyhow::Error> {
+    let mut capabilities = hello::CAP_RELAY;
+    if FRAME_FORMAT == FrameFormat::Header {
+        capabilities |= hello::CAP_FRAME_HEADER;
+    }
+    if crate::ota::OTA_PUBLIC_KEY.is_some() {
+        capabilities |= hello::CAP_OTA;
+    }
+    // The gateway has no configuration version and no name
+    let hello = hello::hello(
+        Role::GatewayCombo,
+        env!("CARGO_PKG_VERSION"),
+        capabilities,
+        0,
+        "",
+    )?;
+    Ok(broadcast_msg(
+        &morty_message::Msg::Hello(hello),
+        Priority::High,
+        esp_now,
+    )?)
+}
+
+fn beacon_present(esp_now: &EspNow, channel: u8) -> Result<(), anyhow::Error> {
+    let msg = morty_message::Msg::BeaconPresent(BeaconPresentMsg {
+        timestamp: EspSystemTime.now().as_secs() as i64,
+        tx_power: tx_power_dbm()?,
+        channel: channel as u32,
+        clock: Some(clock_status()),
+        ..Default::default()
+    });
+    Ok(broadcast_msg(&msg, Priority::Routine, esp_now)?)
+}
+
+/// The line a beacon would write to the UART for a frame from `src`, None for frames that a
+/// beacon doesn't pass on.
+fn relay_line(src: &[u8], data: &[u8]) -> Option<String> {
+    let msg = match decode_msg(data) {
+        Ok(Some(morty_message::Msg::Gps(gps))) => relay_msg::Msg::Gps(gps),
+        Ok(Some(morty_message::Msg::CommandAck(ack))) => relay_msg::Msg::CommandAck(ack),
+        Ok(Some(morty_message::Msg::TransferAck(ack))) => relay_msg::Msg::TransferAck(ack),
+        Ok(Some(morty_message::Msg::PowerEvent(event))) => relay_msg::Msg::PowerEvent(event),
+        Ok(Some(morty_message::Msg::Hello(hello))) => relay_msg::Msg::Hello(hello),
+        Ok(Some(morty_message::Msg::Trace(trace))) => relay_msg::Msg::Trace(trace),
+        // Only for the clocks of the beacons in range
+        Ok(Some(morty_message::Msg::BeaconPresent(present))) if present.clock.is_some() => {
+            relay_msg::Msg::BeaconPresent(present)
+        }
+        // Beacons in range relay to each other, those frames can go as they are
+        Ok(Some(morty_message::Msg::Relay(_))) => return Some(line(data)),
+        Ok(_) => return None,
+        // Frames garbled on the air happen now and then, the sender repeats what matters
+        Err(e @ CommError::CrcMismatch { .. }) => {
+            UART_FRAMES.decode_failures.record(&e);
+            warn!("Dropping garbled ESP-NOW frame: {e}");
+            return None;
+        }
+        Err(e) => {
+            UART_FRAMES.decode_failures.record(&e);
+            error!("Error decoding ESP-NOW frame: {e}");
+            return None;
+        }
+    };
+    let relay = RelayMsg {
+        src: mac_to_string(src),
+        timestamp: EspSystemTime.now().as_secs() as i64,
+        msg: Some(msg),
+        ..Default::default()
+    };
+    match encode_msg(&morty_message::Msg::Relay(relay)) {
+        Ok(data) => Some(line(&data)),
+        Err(e) => {
+            error!("Unable to encode a relay of {}: {e}", mac_to_string(src));
+            None
+        }
+    }
+}
+
+fn line(data: &[u8]) -> String {
+    format!("{UART_HEADER}{}\n", general_purpose::STANDARD.encode(data))
+}
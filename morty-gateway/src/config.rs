@@ -0,0 +1,427 @@
+//! Settings of the gateway that can differ between installations, see `morty_rs::config`. They
+//! are loaded from NVS once at boot, anything that isn't stored there gets the default below.
+//! `config list` on the console shows what we run with.
+
+use crate::queue::DrainPolicy;
+use crate::queue::DRAIN_POLICY_NAMES;
+use log::*;
+use morty_rs::config::check_schema;
+use morty_rs::config::load_nvs;
+use morty_rs::config::Kind;
+use morty_rs::config::Setting;
+use morty_rs::config::Settings;
+use morty_rs::config::Value;
+use morty_rs::wifi::parse_networks;
+use morty_rs::wifi::Network;
+use std::borrow::Cow;
+use std::sync::Mutex;
+use std::time::Duration;
+
+const SSID: &str = "ssid";
+const PASS: &str = "pass";
+const NETWORKS: &str = "networks";
+const API_HOST: &str = "api_host";
+pub const API_CA: &str = "api_ca";
+const TX_POWER_DBM: &str = "tx_power_dbm";
+const COMMAND_POLL_INTERVAL: &str = "cmd_interval";
+const BEACON_PING_INTERVAL: &str = "ping_interval";
+const BEACON_PONG_TIMEOUT: &str = "pong_timeout";
+const TIME_BEACON_INTERVAL: &str = "time_interval";
+const OTA_CHECK_INTERVAL: &str = "ota_interval";
+const HEAP_CHECK_INTERVAL: &str = "heap_interval";
+const STATS_LOG_INTERVAL: &str = "stats_interval";
+const UART_FRAMING_ERROR_THRESHOLD: &str = "framing_errors";
+const UPLOAD_RETRY_DELAY: &str = "retry_delay";
+const UPLOAD_RETRY_MAX_DELAY: &str = "retry_max_delay";
+const GZIP_THRESHOLD: &str = "gzip_threshold";
+const COMBO_MODE: &str = "combo_mode";
+const WEBHOOK_URL: &str = "webhook_url";
+const WEBHOOK_AUTH: &str = "webhook_auth";
+const NOTIFY_RULES: &str = "notify_rules";
+const NOTIFY_GRACE: &str = "notify_grace";
+const LOW_BATTERY_VOLTS: &str = "low_battery_v";
+const BRIDGE_PORT: &str = "bridge_port";
+const BRIDGE_IDLE: &str = "bridge_idle";
+const LINE_BUDGET_MS: &str = "line_budget_ms";
+const DRAIN_POLICY: &str = "drain_policy";
+
+pub const DEFAULT_NOTIFY_RULES: &str =
+    "low_battery=21600,gps_fault=3600,power_lost=3600,beacon_lost=3600,uplink_restored=3600";
+
+const fn secs(secs: u64) -> Duration {
+    Duration::from_secs(secs)
+}
+
+pub static SCHEMA: &[Setting] = &[
+    Setting::new(
+        SSID,
+        Kind::Str { max_len: 32 },
+        Value::Str(Cow::Borrowed("IoT")),
+    ),
+    Setting::new(
+        PASS,
+        Kind::Str { max_len: 64 },
+        Value::Str(Cow::Borrowed("EddieVedder7")),
+    )
+    .secret(),
+    // Networks in order of preference, e.g. "main=secret;backup=secret2", instead of the ones
+    // above, see `morty_rs::wifi::parse_networks`. We move on to the next one when the one we use
+    // fails, and start with the one that last worked.
+    Setting::new(
+        NETWORKS,
+        Kind::Str { max_len: 256 },
+        Value::Str(Cow::Borrowed("")),
+    )
+    .secret(),
+    Setting::new(
+        API_HOST,
+        Kind::Str { max_len: 64 },
+        Value::Str(Cow::Borrowed("wouterdebie-personal.ue.r.appspot.com")),
+    ),
+    // Certificate we trust for the API host instead of the certificate bundle, as PEM, see `pin`.
+    // Empty to trust the bundle.
+    Setting::new(
+        API_CA,
+        Kind::Str { max_len: 3000 },
+        Value::Str(Cow::Borrowed("")),
+    ),
+    Setting::new(
+        TX_POWER_DBM,
+        Kind::F32 {
+            min: 2.0,
+            max: 20.0,
+        },
+        Value::F32(20.0),
+    ),
+    // How often we ask the backend for commands for the devices
+    Setting::new(
+        COMMAND_POLL_INTERVAL,
+        Kind::Duration {
+            min: secs(5),
+            max: secs(60 * 60),
+        },
+        Value::Duration(secs(30)),
+    ),
+    // How often we check that the beacon is attached, and how long it has to answer
+    Setting::new(
+        BEACON_PING_INTERVAL,
+        Kind::Duration {
+            min: secs(30),
+            max: secs(24 * 60 * 60),
+        },
+        Value::Duration(secs(5 * 60)),
+    ),
+    Setting::new(
+        BEACON_PONG_TIMEOUT,
+        Kind::Duration {
+            min: secs(1),
+            max: secs(60),
+        },
+        Value::Duration(secs(5)),
+    ),
+    // How often we send the beacons our time, for the clocks of the devices without SNTP
+    Setting::new(
+        TIME_BEACON_INTERVAL,
+        Kind::Duration {
+            min: secs(30),
+            max: secs(60 * 60),
+        },
+        Value::Duration(secs(5 * 60)),
+    ),
+    // How often we ask the backend for the image we should run. Gateways built without
+    // MORTY_OTA_PUBLIC_KEY don't ask.
+    Setting::new(
+        OTA_CHECK_INTERVAL,
+        Kind::Duration {
+            min: secs(5 * 60),
+            max: secs(7 * 24 * 60 * 60),
+        },
+        Value::Duration(secs(60 * 60)),
+    ),
+    Setting::new(
+        HEAP_CHECK_INTERVAL,
+        Kind::Duration {
+            min: secs(1),
+            max: secs(60),
+        },
+        Value::Duration(secs(5)),
+    ),
+    Setting::new(
+        STATS_LOG_INTERVAL,
+        Kind::Duration {
+            min: secs(10),
+            max: secs(24 * 60 * 60),
+        },
+        Value::Duration(secs(5 * 60)),
+    ),
+    // Warn about the wiring when there are more framing errors than this in a minute
+    Setting::new(
+        UART_FRAMING_ERROR_THRESHOLD,
+        Kind::U32 {
+            min: 1,
+            max: 10_000,
+        },
+        Value::U32(10),
+    ),
+    // After a failed upload, we wait up to this long before uploading again, up to twice as long
+    // every time it fails again
+    Setting::new(
+        UPLOAD_RETRY_DELAY,
+        Kind::Duration {
+            min: secs(1),
+            max: secs(5 * 60),
+        },
+        Value::Duration(secs(5)),
+    ),
+    Setting::new(
+        UPLOAD_RETRY_MAX_DELAY,
+        Kind::Duration {
+            min: secs(5),
+            max: secs(60 * 60),
+        },
+        Value::Duration(secs(5 * 60)),
+    ),
+    // Uploads to the backend of at least this many bytes are sent compressed, see `gzip`. 0 sends
+    // everything uncompressed, for backends that don't take `Content-Encoding: gzip`.
+    Setting::new(
+        GZIP_THRESHOLD,
+        Kind::U32 {
+            min: 0,
+            max: 64 * 1024,
+        },
+        Value::U32(0),
+    ),
+    // Hear the trackers over ESP-NOW as well, for installations without a beacon. This runs on
+    // the channel of the access point and can't use long range mode, see `combo`. When ESP-NOW
+    // doesn't start, we carry on with just the UART.
+    Setting::new(COMBO_MODE, Kind::Bool, Value::Bool(false)),
+    // Where notifications of critical events are posted, see `notify`. Without a URL nothing is
+    // sent. The auth header goes out as the Authorization header, e.g. "Bearer <token>".
+    Setting::new(
+        WEBHOOK_URL,
+        Kind::Str { max_len: 128 },
+        Value::Str(Cow::Borrowed("")),
+    ),
+    Setting::new(
+        WEBHOOK_AUTH,
+        Kind::Str { max_len: 128 },
+        Value::Str(Cow::Borrowed("")),
+    )
+    .secret(),
+    // Which events are critical, with the least number of seconds between two notifications of
+    // the same event for the same source, see `notify::Rules`
+    Setting::new(
+        NOTIFY_RULES,
+        Kind::Str { max_len: 128 },
+        Value::Str(Cow::Borrowed(DEFAULT_NOTIFY_RULES)),
+    ),
+    // No notifications for this long after a boot or a jump of the wall clock, when everything
+    // comes back from a power outage at once, see `notify`. 0 notifies right away.
+    Setting::new(
+        NOTIFY_GRACE,
+        Kind::Duration {
+            min: secs(0),
+            max: secs(2 * 60 * 60),
+        },
+        Value::Duration(secs(10 * 60)),
+    ),
+    // Trackers that report less than this while not charging have a low battery
+    Setting::new(
+        LOW_BATTERY_VOLTS,
+        Kind::F32 { min: 2.5, max: 4.5 },
+        Value::F32(3.4),
+    ),
+    // Port we accept beacons bridged over TCP on, see `bridge`. 0 doesn't accept any.
+    Setting::new(
+        BRIDGE_PORT,
+        Kind::U32 {
+            min: 0,
+            max: u16::MAX as u32,
+        },
+        Value::U32(0),
+    ),
+    // A bridge that sends nothing for this long is disconnected. Its beacon answers pings, so
+    // this should be longer than the ping interval.
+    Setting::new(
+        BRIDGE_IDLE,
+        Kind::Duration {
+            min: secs(60),
+            max: secs(24 * 60 * 60),
+        },
+        Value::Duration(secs(15 * 60)),
+    ),
+    // Handling a line that takes longer than this many milliseconds is logged, with the types of
+    // its frames. Uploads aren't counted.
+    Setting::new(
+        LINE_BUDGET_MS,
+        Kind::U32 {
+            min: 1,
+            max: 10_000,
+        },
+        Value::U32(50),
+    ),
+    // The order in which the retry queue uploads what piled up while the internet connection was
+    // down, see `queue::DrainPolicy`
+    Setting::new(
+        DRAIN_POLICY,
+        Kind::Choice {
+            choices: &DRAIN_POLICY_NAMES,
+        },
+        Value::Str(Cow::Borrowed(DrainPolicy::OldestFirst.name())),
+    ),
+];
+
+/// What the gateway runs with.
+pub struct Config {
+    pub ssid: String,
+    pub pass: String,
+    pub networks: String,
+    pub api_host: String,
+    pub api_ca: String,
+    pub tx_power_dbm: f32,
+    pub command_poll_interval: Duration,
+    pub beacon_ping_interval: Duration,
+    pub beacon_pong_timeout: Duration,
+    pub time_beacon_interval: Duration,
+    pub ota_check_interval: Duration,
+    pub heap_check_interval: Duration,
+    pub stats_log_interval: Duration,
+    pub uart_framing_error_threshold: usize,
+    pub upload_retry_delay: Duration,
+    pub upload_retry_max_delay: Duration,
+    pub gzip_threshold: usize,
+    pub combo_mode: bool,
+    pub webhook_url: String,
+    pub webhook_auth: String,
+    pub notify_rules: String,
+    pub notify_grace: Duration,
+    pub low_battery_volts: f32,
+    pub bridge_port: u16,
+    pub bridge_idle: Duration,
+    pub line_budget: Duration,
+    pub drain_policy: DrainPolicy,
+    /// Every setting with where it came from, and the stored values we couldn't use
+    pub settings: Settings,
+}
+
+impl Config {
+    fn new(settings: Settings) -> Self {
+        Self {
+            ssid: settings.str(SSID).to_string(),
+            pass: settings.str(PASS).to_string(),
+            networks: settings.str(NETWORKS).to_string(),
+            api_host: settings.str(API_HOST).to_string(),
+            api_ca: settings.str(API_CA).to_string(),
+            tx_power_dbm: settings.f32(TX_POWER_DBM),
+            command_poll_interval: settings.duration(COMMAND_POLL_INTERVAL),
+            beacon_ping_interval: settings.duration(BEACON_PING_INTERVAL),
+            beacon_pong_timeout: settings.duration(BEACON_PONG_TIMEOUT),
+            time_beacon_interval: settings.duration(TIME_BEACON_INTERVAL),
+            ota_check_interval: settings.duration(OTA_CHECK_INTERVAL),
+            heap_check_interval: settings.duration(HEAP_CHECK_INTERVAL),
+            stats_log_interval: settings.duration(STATS_LOG_INTERVAL),
+            uart_framing_error_threshold: settings.u32(UART_FRAMING_ERROR_THRESHOLD) as usize,
+            upload_retry_delay: settings.duration(UPLOAD_RETRY_DELAY),
+            upload_retry_max_delay: settings.duration(UPLOAD_RETRY_MAX_DELAY),
+            gzip_threshold: settings.u32(GZIP_THRESHOLD) as usize,
+            combo_mode: settings.bool(COMBO_MODE),
+            webhook_url: settings.str(WEBHOOK_URL).to_string(),
+            webhook_auth: settings.str(WEBHOOK_AUTH).to_string(),
+            notify_rules: settings.str(NOTIFY_RULES).to_string(),
+            notify_grace: settings.duration(NOTIFY_GRACE),
+            low_battery_volts: settings.f32(LOW_BATTERY_VOLTS),
+            bridge_port: settings.u32(BRIDGE_PORT) as u16,
+            bridge_idle: settings.duration(BRIDGE_IDLE),
+            line_budget: Duration::from_millis(settings.u32(LINE_BUDGET_MS) as u64),
+            // The schema only lets the names of the policies through
+            drain_policy: settings
+                .str(DRAIN_POLICY)
+                .parse()
+                .unwrap_or(DrainPolicy::OldestFirst),
+            settings,
+        }
+    }
+
+    /// The networks to connect to, in order of preference. When the list of networks doesn't
+    /// parse, it's just the one of `ssid` and `pass`.
+    pub fn networks(&self) -> Vec<Network> {
+        match parse_networks(&self.networks) {
+            Ok(networks) if !networks.is_empty() => networks,
+            Ok(_) => vec![Network::new(&self.ssid, &self.pass)],
+            Err(e) => {
+                error!("Invalid wifi networks, using {}: {e}", self.ssid);
+                vec![Network::new(&self.ssid, &self.pass)]
+            }
+        }
+    }
+}
+
+// The configuration, once it's loaded
+static CONFIG: Mutex<Option<&'static Config>> = Mutex::new(None);
+
+/// Load the configuration from NVS, which has to be initialized. Invalid values are logged and
+/// replaced by their default.
+pub fn load() -> Result<&'static Config, anyhow::Error> {
+    check_schema(SCHEMA)?;
+    let settings = load_nvs(SCHEMA);
+    settings.log_invalid();
+    let config: &'static Config = Box::leak(Box::new(Config::new(settings)));
+    *CONFIG.lock().unwrap() = Some(config);
+    Ok(config)
+}
+
+/// The configuration. Panics when it wasn't loaded yet.
+pub fn config() -> &'static Config {
+    CONFIG.lock().unwrap().expect("Configuration not loaded")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_schema_checks_out() {
+        check_schema(SCHEMA).unwrap();
+    }
+
+    #[test]
+    fn the_defaults_make_a_config() {
+        let config = Config::new(Settings::load(SCHEMA, |_| Ok(None)));
+        assert!(config.settings.invalid().is_empty());
+        assert_eq!(config.drain_policy, DrainPolicy::OldestFirst);
+    }
+
+    #[test]
+    fn every_drain_policy_in_the_schema_parses() {
+        for name in DRAIN_POLICY_NAMES {
+            assert!(name.parse::<DrainPolicy>().is_ok(), "{name}");
+        }
+    }
+
+    // A config with `networks`, and the default ssid and pass
+    fn config_with_networks(networks: &str) -> Config {
+        Config::new(Settings::load(SCHEMA, |key| match key {
+            NETWORKS => Ok(Some(networks.to_string())),
+            _ => Ok(None),
+        }))
+    }
+
+    #[test]
+    fn the_network_list_is_used_when_it_parses() {
+        let config = config_with_networks("main=secret;backup=secret2");
+        assert_eq!(
+            config.networks(),
+            [
+                Network::new("main", "secret"),
+                Network::new("backup", "secret2"),
+            ]
+        );
+    }
+
+    #[test]
+    fn ssid_and_pass_are_used_without_a_valid_network_list() {
+        let fallback = [Network::new("IoT", "EddieVedder7")];
+        assert_eq!(config_with_networks("").networks(), fallback);
+        assert_eq!(config_with_networks("main=a;main=b").networks(), fallback);
+    }
+}
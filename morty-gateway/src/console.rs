@@ -0,0 +1,479 @@
+//! Commands typed on the serial console of the gateway. `inject test-fix [lat lon]` makes up a
+//! fix from a reserved source and feeds it to the pipeline as if the beacon sent it, so we can
+//! check the connection to the API server and the payload without a tracker. The fix takes the
+//! same path as real frames and the command waits for the outcome of its first upload.
+//! `export csv [src]` writes the last fix of every source, or just `src`, as CSV.
+//! `capture failed on|off` starts or stops capturing the frames that don't decode, `dump failed
+//! [hex|base64]` writes them as a hexdump or as a base64 encoded binary blob (see
+//! `capture::write_binary`) and `clear failed` forgets them. `usage [days]` writes the fixes per
+//! source and per day of the last 7 days, or `days`, as JSON, see `usage`. `status` writes the
+//! uptime, the state of the wifi connection and why it last dropped, the drain policy of the retry
+//! queue, the settings we couldn't use, the last hello of every device, see `devices`, the beacons
+//! bridged over TCP, see `bridge`, and the gateway hints of other gateways we got messages with,
+//! see `link::check_hint`.
+//! `wifi list` writes the networks we know with the one we use, `wifi use <n>` moves to network
+//! `n` of that list right away, to try the failover, see `morty_rs::wifi`.
+//! `threads` writes the threads with their priority, core and the least stack they had left.
+//! `config list` writes every setting with its value and where that came from. `config set <key>
+//! <value>` stores a setting for the next boot and `config unset <key>` goes back to its default.
+//! The pinned certificate of the API host, `api_ca`, takes effect right away, so it can be rotated
+//! without a reboot, see `pin`. A PEM pasted on one line is fine. `led log` writes
+//! what the LED showed before the last reboot, see `morty_rs::ledlog`. `webhook test` posts a
+//! test notification to the webhook right away and writes how that went, see `notify`.
+//! `replay <file> [payloads|failed]` feeds a capture of the UART on the captures partition through
+//! the pipeline without uploading anything and writes what it made of it, with the payloads in
+//! base64 or a hexdump of the frames that didn't decode, see `replay`.
+//! `sources [json]` writes every source we ever heard from, see `sources`. `sources prune <days>`
+//! writes the ones we didn't hear from in `days` days, `sources prune <days> confirm` forgets them.
+
+use crate::api;
+use crate::bridge;
+use crate::capture::write_binary;
+use crate::capture::write_hexdump;
+use crate::config::config;
+use crate::config::API_CA;
+use crate::config::SCHEMA;
+use crate::events::CaptureRequest;
+use crate::events::Events;
+use crate::events::GatewayEvent;
+use crate::export::write_csv;
+use crate::frames::Via;
+use crate::handoff::LineSender;
+use crate::link;
+use crate::maintenance;
+use crate::maintenance::Phase;
+use crate::notify;
+use crate::notify::test_notification;
+use crate::pin::parse_pem;
+use crate::replay;
+use crate::replay::Replay;
+use crate::sources::Source;
+use crate::PAYLOAD_FORMAT;
+use anyhow::anyhow;
+use anyhow::bail;
+use base64::engine::general_purpose;
+use base64::Engine;
+use json::JsonValue;
+use log::*;
+use morty_rs::clock::Clock;
+use morty_rs::comm::encode_msg;
+use morty_rs::comm::UART_HEADER;
+use morty_rs::config::erase_nvs;
+use morty_rs::config::store_nvs;
+use morty_rs::ledlog;
+use morty_rs::messages::morty_message;
+use morty_rs::messages::relay_msg;
+use morty_rs::messages::GpsMsg;
+use morty_rs::messages::RelayMsg;
+use morty_rs::naming::Resolved;
+use morty_rs::utils::dump_threads;
+use morty_rs::utils::format_duration;
+use morty_rs::utils::uptime;
+use morty_rs::wifi;
+use std::io::BufRead;
+use std::io::Write;
+use std::time::Duration;
+
+/// Source of injected fixes. It's a locally administered MAC, so no device has it.
+pub const TEST_SOURCE: &str = "02:00:00:00:00:00";
+
+// The console doesn't block when nothing was typed, so we poll it
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+// How long we wait for the upload of an injected fix
+const UPLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+// How long we wait for the last fixes to export
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(5);
+// Days `usage` shows when not told otherwise
+const USAGE_DAYS: u32 = 7;
+// Position of an injected fix when none is given
+const DEFAULT_POSITION: (f64, f64) = (0.0, 0.0);
+
+/// Read commands from the console. Injected frames are written to `lines`, like the lines read
+/// from the UART.
+pub fn console_task(lines: LineSender, events: Events) -> ! {
+    let stdin = std::io::stdin();
+    let mut input = stdin.lock();
+    let mut line = String::new();
+    loop {
+        match input.read_line(&mut line) {
+            Ok(_) if line.ends_with('\n') => {
+                if let Err(e) = handle_command(line.trim(), &lines, &events) {
+                    error!("{e}");
+                }
+                line.clear();
+            }
+            _ => std::thread::sleep(POLL_INTERVAL),
+        }
+    }
+}
+
+fn handle_command(command: &str, lines: &LineSender, events: &Events) -> Result<(), anyhow::Error> {
+    let args: Vec<&str> = command.split_whitespace().collect();
+    match args.as_slice() {
+        [] => Ok(()),
+        ["inject", "test-fix"] => inject_test_fix(DEFAULT_POSITION, lines, events),
+        ["inject", "test-fix", lat, lon] => {
+            inject_test_fix((lat.parse()?, lon.parse()?), lines, events)
+        }
+        ["export", "csv"] => export_csv(None, events),
+        ["export", "csv", src] => export_csv(Some(src), events),
+        ["capture", "failed", "on"] => capture(CaptureRequest::Start, events),
+        ["capture", "failed", "off"] => capture(CaptureRequest::Stop, events),
+        ["clear", "failed"] => capture(CaptureRequest::Clear, events),
+        ["dump", "failed"] | ["dump", "failed", "hex"] => dump_failed(false, events),
+        ["dump", "failed", "base64"] => dump_failed(true, events),
+        ["usage"] => usage(USAGE_DAYS, events),
+        ["usage", days] => usage(days.parse()?, events),
+        ["status"] => {
+            println!("Uptime: {}", format_duration(uptime()));
+            println!(
+                "Wifi: {} ssid={}",
+                wifi::link(),
+                wifi::active_ssid().unwrap_or_default()
+            );
+            println!("Retry queue: drain policy {}", config().drain_policy);
+            for invalid in config().settings.invalid() {
+                println!("Invalid setting {invalid}, using the default");
+            }
+            let (reply, devices) = std::sync::mpsc::channel();
+            events.emit(GatewayEvent::DevicesRequested { reply });
+            let now = Clock::new()?.wall().map(|t| t.as_secs() as i64);
+            for device in devices.recv_timeout(EXPORT_TIMEOUT)? {
+                match now {
+                    Some(now) => println!("{}", device.status_line(now)),
+                    None => println!("{device}"),
+                }
+            }
+            let (reply, clocks) = std::sync::mpsc::channel();
+            events.emit(GatewayEvent::ClocksRequested { reply });
+            for clock in clocks.recv_timeout(EXPORT_TIMEOUT)? {
+                println!("{clock}");
+            }
+            let now = Clock::new()?.monotonic();
+            for bridge in bridge::bridges() {
+                println!("Bridge {}", bridge.status_line(now));
+            }
+            for mismatch in link::hint_mismatches() {
+                println!(
+                    "Gateway hint {} isn't us: {} messages, last from {}",
+                    mismatch.hint, mismatch.count, mismatch.src
+                );
+            }
+            for source in sources(events)? {
+                if let Resolved {
+                    name: Some(name),
+                    conflict: Some(local),
+                } = source.resolved_name()
+                {
+                    println!(
+                        "Name conflict for {}: calls itself {name}, SOURCE_NAMES has {local}",
+                        source.src
+                    );
+                }
+            }
+            let phase = maintenance::phase();
+            if phase != Phase::Running {
+                println!("Maintenance: {phase}");
+            }
+            Ok(())
+        }
+        ["maintenance"] => {
+            let now = Clock::new()?.monotonic();
+            if !maintenance::with(|m| m.enter(now)) {
+                println!("Already in maintenance: {}", maintenance::phase());
+                return Ok(());
+            }
+            events.emit(GatewayEvent::Maintenance {
+                phase: Phase::Pausing,
+            });
+            println!("Pausing, unplug when the LED is steady white, `maintenance off` to resume");
+            Ok(())
+        }
+        ["maintenance", "off"] => {
+            let now = Clock::new()?.monotonic();
+            if !maintenance::with(|m| m.resume(now)) {
+                println!("Not in maintenance");
+                return Ok(());
+            }
+            events.emit(GatewayEvent::Maintenance {
+                phase: Phase::Running,
+            });
+            println!("Resumed");
+            Ok(())
+        }
+        ["config", "list"] => {
+            for (setting, value, source) in config().settings.iter() {
+                if setting.secret {
+                    println!("{} = <hidden> ({source})", setting.key);
+                } else {
+                    println!("{} = {value} ({source})", setting.key);
+                }
+            }
+            Ok(())
+        }
+        ["config", "set", key, value @ ..] if !value.is_empty() => {
+            config_set(key, &value.join(" "))
+        }
+        ["config", "unset", key] => config_unset(key),
+        ["wifi", "list"] => {
+            let (networks, active) = wifi::networks();
+            for (i, network) in networks.iter().enumerate() {
+                let marker = if i == active { " (active)" } else { "" };
+                println!("{i}: {}{marker}", network.ssid);
+            }
+            Ok(())
+        }
+        ["wifi", "use", index] => wifi::switch_to(index.parse()?),
+        ["threads"] => {
+            println!("{}", dump_threads());
+            Ok(())
+        }
+        ["led", "log"] => {
+            for record in ledlog::restored() {
+                println!("{record}");
+            }
+            println!("Records dropped since boot: {}", ledlog::dropped());
+            Ok(())
+        }
+        ["webhook", "test"] => webhook_test(),
+        ["replay", file] => replay(file, None),
+        ["replay", file, output @ ("payloads" | "failed")] => replay(file, Some(output)),
+        ["sources"] => {
+            let now = Clock::new()?.wall().map(|t| t.as_secs() as i64);
+            for source in sources(events)? {
+                println!("{}", source.status_line(now));
+            }
+            Ok(())
+        }
+        ["sources", "json"] => {
+            let sources: Vec<JsonValue> = sources(events)?.iter().map(Source::to_json).collect();
+            println!("{}", JsonValue::Array(sources));
+            Ok(())
+        }
+        ["sources", "prune", days] => {
+            let before = prune_before(days.parse()?)?;
+            let stale: Vec<Source> = sources(events)?
+                .into_iter()
+                .filter(|s| s.last_seen < before)
+                .collect();
+            if stale.is_empty() {
+                println!("No sources to prune");
+                return Ok(());
+            }
+            for source in &stale {
+                println!("Would forget {}", source.src);
+            }
+            println!("Type `sources prune {days} confirm` to forget them");
+            Ok(())
+        }
+        ["sources", "prune", days, "confirm"] => {
+            let before = prune_before(days.parse()?)?;
+            let (reply, pruned) = std::sync::mpsc::channel();
+            events.emit(GatewayEvent::SourcesPruned { before, reply });
+            let pruned = pruned.recv_timeout(EXPORT_TIMEOUT)?;
+            for src in &pruned {
+                println!("Forgot {src}");
+            }
+            println!("Pruned {} sources", pruned.len());
+            Ok(())
+        }
+        _ => bail!(
+            "Unknown command: {command}, try `inject test-fix [lat lon]`, `export csv [src]`, \
+             `capture failed on|off`, `dump failed [hex|base64]`, `clear failed`, \
+             `usage [days]`, `status`, `wifi list`, `wifi use <n>`, `threads`, `config list`, \
+             `config set <key> <value>`, `config unset <key>`, `led log`, `webhook test`, \
+             `replay <file> [payloads|failed]`, `sources [json]`, \
+             `sources prune <days> [confirm]` or `maintenance [off]`"
+        ),
+    }
+}
+
+/// Check `text` as the value of setting `key` and store it for the next boot. A pinned
+/// certificate is used right away.
+fn config_set(key: &str, text: &str) -> Result<(), anyhow::Error> {
+    let Some(setting) = SCHEMA.iter().find(|setting| setting.key == key) else {
+        bail!("Unknown setting {key}");
+    };
+    if let Err(e) = setting.parse(text) {
+        bail!("Invalid value for {key}: {e}");
+    }
+    if key == API_CA {
+        let der = parse_pem(text).map_err(|e| anyhow!("Invalid certificate: {e}"))?;
+        store_nvs(key, text.trim())?;
+        api::pin_certificate(&config().api_host, der);
+        println!("Pinned the certificate for {}", config().api_host);
+        return Ok(());
+    }
+    store_nvs(key, text.trim())?;
+    println!("Stored {key}, it takes effect after a reboot");
+    Ok(())
+}
+
+/// Forget the stored value of setting `key`, it gets its default at the next boot. Unpinning the
+/// certificate is right away.
+fn config_unset(key: &str) -> Result<(), anyhow::Error> {
+    if !SCHEMA.iter().any(|setting| setting.key == key) {
+        bail!("Unknown setting {key}");
+    }
+    erase_nvs(key)?;
+    if key == API_CA {
+        api::unpin_certificate();
+        println!("Trusting the certificate bundle for {}", config().api_host);
+        return Ok(());
+    }
+    println!("Unset {key}, it gets its default after a reboot");
+    Ok(())
+}
+
+/// Post a test notification to the webhook, outside the retry queue.
+fn webhook_test() -> Result<(), anyhow::Error> {
+    let wall = Clock::new()?.wall().map(|t| t.as_secs() as i64);
+    let status = notify::post(&test_notification(wall).payload().dump())?;
+    println!("Webhook answered HTTP {status}");
+    Ok(())
+}
+
+/// Replay a capture from the captures partition and write the summary, and the payloads or the
+/// frames that didn't decode when asked.
+fn replay(file: &str, output: Option<&str>) -> Result<(), anyhow::Error> {
+    replay::mount()?;
+    let path = format!("{}/{file}", replay::MOUNT_POINT);
+    let input = std::io::BufReader::new(std::fs::File::open(&path)?);
+    let mut replay = Replay::new(PAYLOAD_FORMAT.serializer()?);
+    replay.run(input)?;
+    let mut out = std::io::stdout().lock();
+    match output {
+        Some("payloads") => {
+            for payload in &replay.payloads {
+                writeln!(
+                    out,
+                    "{} {} {}",
+                    payload.src,
+                    payload.uid,
+                    general_purpose::STANDARD.encode(&payload.data)
+                )?;
+            }
+        }
+        Some(_) => write_hexdump(&mut out, &replay.failed.frames())?,
+        None => {}
+    }
+    writeln!(out, "Replay of {path}:\n{}", replay.summary)?;
+    Ok(())
+}
+
+/// Write the last fixes to the console as CSV.
+fn export_csv(src: Option<&str>, events: &Events) -> Result<(), anyhow::Error> {
+    let (reply, fixes) = std::sync::mpsc::channel();
+    events.emit(GatewayEvent::ExportRequested { reply });
+    let fixes = fixes.recv_timeout(EXPORT_TIMEOUT)?;
+    write_csv(&mut std::io::stdout().lock(), &fixes, src)?;
+    Ok(())
+}
+
+fn capture(request: CaptureRequest, events: &Events) -> Result<(), anyhow::Error> {
+    info!("Capture of frames that don't decode: {request:?}");
+    events.emit(GatewayEvent::CaptureRequested { request });
+    Ok(())
+}
+
+/// Write the captured frames that didn't decode to the console, as a hexdump or as a binary blob
+/// in base64.
+fn dump_failed(binary: bool, events: &Events) -> Result<(), anyhow::Error> {
+    let (reply, frames) = std::sync::mpsc::channel();
+    events.emit(GatewayEvent::CaptureRequested {
+        request: CaptureRequest::Dump(reply),
+    });
+    let frames = frames.recv_timeout(EXPORT_TIMEOUT)?;
+    let mut out = std::io::stdout().lock();
+    if binary {
+        let mut blob = Vec::new();
+        write_binary(&mut blob, &frames)?;
+        writeln!(out, "{}", general_purpose::STANDARD.encode(blob))?;
+    } else {
+        write_hexdump(&mut out, &frames)?;
+    }
+    info!("Dumped {} frames that didn't decode", frames.len());
+    Ok(())
+}
+
+/// Write the fixes per source and per day to the console, as JSON.
+fn usage(days: u32, events: &Events) -> Result<(), anyhow::Error> {
+    let (reply, json) = std::sync::mpsc::channel();
+    events.emit(GatewayEvent::UsageRequested { days, reply });
+    println!("{}", json.recv_timeout(EXPORT_TIMEOUT)?);
+    Ok(())
+}
+
+/// Every source the gateway heard from.
+fn sources(events: &Events) -> Result<Vec<Source>, anyhow::Error> {
+    let (reply, sources) = std::sync::mpsc::channel();
+    events.emit(GatewayEvent::SourcesRequested { reply });
+    Ok(sources.recv_timeout(EXPORT_TIMEOUT)?)
+}
+
+/// Seconds since the epoch `days` days ago. Pruning needs the time, or every source would look
+/// stale.
+fn prune_before(days: u32) -> Result<i64, anyhow::Error> {
+    let Some(now) = Clock::new()?.wall() else {
+        bail!("The time isn't known yet, try again later");
+    };
+    Ok(now.as_secs() as i64 - days as i64 * 24 * 60 * 60)
+}
+
+/// Feed a made up fix to the pipeline and wait for its upload.
+fn inject_test_fix(
+    (latitude, longitude): (f64, f64),
+    lines: &LineSender,
+    events: &Events,
+) -> Result<(), anyhow::Error> {
+    let clock = Clock::new()?;
+    let timestamp = clock.wall().map_or(0, |t| t.as_secs() as i64);
+    let gps = GpsMsg {
+        latitude,
+        longitude,
+        fix_quality: 1,
+        hdop: 1.0,
+        utc: (timestamp % (24 * 60 * 60)) as i32,
+        fix_epoch: timestamp,
+        sent_epoch: timestamp,
+        // A new uid every time, so we don't get dropped as a duplicate
+        uid: format!("{:06x}", unsafe { esp_idf_sys::esp_random() } & 0xffffff),
+        ..Default::default()
+    };
+    let relay = RelayMsg {
+        src: TEST_SOURCE.to_string(),
+        timestamp,
+        msg: Some(relay_msg::Msg::Gps(gps.clone())),
+        backfill: false,
+        path_delay_ms: 0,
+        gateway_hint: String::new(),
+    };
+
+    // Ask for the outcome before the fix goes in, so we can't miss it
+    let (reply, outcome) = std::sync::mpsc::channel();
+    events.emit(GatewayEvent::TestFixInjected {
+        uid: gps.uid.clone(),
+        reply,
+    });
+    let data = encode_msg(&morty_message::Msg::Relay(relay))?;
+    lines.send((
+        clock.monotonic(),
+        Via::Console,
+        format!("{UART_HEADER}{}\n", general_purpose::STANDARD.encode(data)),
+    ))?;
+    info!("Injected test fix {} at {latitude}, {longitude}", gps.uid);
+
+    match outcome.recv_timeout(UPLOAD_TIMEOUT) {
+        Ok(Ok(status)) => info!("Test fix {} uploaded, status {status}", gps.uid),
+        Ok(Err(reason)) => error!(
+            "Test fix {} failed to upload, it stays queued: {reason}",
+            gps.uid
+        ),
+        Err(_) => error!(
+            "Test fix {} wasn't uploaded within {}",
+            gps.uid,
+            format_duration(UPLOAD_TIMEOUT)
+        ),
+    }
+    Ok(())
+}
@@ -0,0 +1,217 @@
+//! What we know about the devices from their hellos, see `morty_rs::hello`: the role, firmware
+//! version and capabilities of every device that booted since we did. Hellos are uploaded to the
+//! backend as they come in, and the console's `status` shows the table. The clocks the devices
+//! report are kept next to it, see `morty_rs::timesync`.
+
+use crate::api::ApiClient;
+use crate::config::config;
+use crate::serializer::idempotency_key;
+use crate::sources::resolve_name;
+use crate::PROXY;
+use json::JsonValue;
+use morty_rs::hello::capability_names;
+use morty_rs::hello::reset_reason_name;
+use morty_rs::hello::role_name;
+use morty_rs::messages::ClockStatusMsg;
+use morty_rs::messages::HelloMsg;
+use morty_rs::naming::with_name;
+use morty_rs::timesync::source_name;
+use morty_rs::utils::format_age;
+use morty_rs::utils::format_duration;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+
+/// The last hello of a device.
+#[derive(Clone, Debug)]
+pub struct Device {
+    pub src: String,
+    // Time of the hello, as stamped by the beacon that relayed it
+    pub timestamp: i64,
+    pub hello: HelloMsg,
+}
+
+impl Device {
+    /// The device for the console's `status`, with how long before `now` it booted, in seconds
+    /// since the epoch.
+    pub fn status_line(&self, now: i64) -> String {
+        self.line(&format!("booted {}", format_age(self.timestamp, now)))
+    }
+
+    fn line(&self, booted: &str) -> String {
+        format!(
+            "{}: {} firmware {}, config {:08x}, {booted} ({}), capabilities [{}]",
+            with_name(&self.src, resolve_name(&self.src, &self.hello.name).name),
+            role_name(self.hello.role),
+            self.hello.firmware_version,
+            self.hello.config_version,
+            reset_reason_name(self.hello.reset_reason),
+            capability_names(self.hello.capabilities).join(", ")
+        )
+    }
+}
+
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.line(&format!("booted at {}", self.timestamp)))
+    }
+}
+
+/// The last clock status a device reported.
+#[derive(Clone, Debug)]
+pub struct DeviceClock {
+    pub src: String,
+    pub clock: ClockStatusMsg,
+}
+
+impl fmt::Display for DeviceClock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: time from {}, {} {} through {} hops, stepped {} times",
+            self.src,
+            source_name(self.clock.source),
+            format_duration(Duration::from_millis(
+                self.clock.offset_ms.unsigned_abs().into()
+            )),
+            if self.clock.offset_ms < 0 {
+                "ahead"
+            } else {
+                "behind"
+            },
+            self.clock.hops,
+            self.clock.steps
+        )
+    }
+}
+
+/// The last hello and clock status of every device, by source.
+#[derive(Default)]
+pub struct Devices {
+    devices: BTreeMap<String, Device>,
+    clocks: BTreeMap<String, ClockStatusMsg>,
+}
+
+impl Devices {
+    pub fn record(&mut self, device: Device) {
+        self.devices.insert(device.src.clone(), device);
+    }
+
+    pub fn record_clock(&mut self, src: &str, clock: &ClockStatusMsg) {
+        self.clocks.insert(src.to_string(), clock.clone());
+    }
+
+    /// The devices, ordered by source.
+    pub fn list(&self) -> Vec<Device> {
+        self.devices.values().cloned().collect()
+    }
+
+    /// The clocks of the devices, ordered by source.
+    pub fn clocks(&self) -> Vec<DeviceClock> {
+        self.clocks
+            .iter()
+            .map(|(src, clock)| DeviceClock {
+                src: src.clone(),
+                clock: clock.clone(),
+            })
+            .collect()
+    }
+}
+
+/// The body of the upload of a hello, with its `idempotency_key`.
+pub fn payload(timestamp: i64, hello: &HelloMsg, idempotency_key: &str) -> JsonValue {
+    json::object! {
+        "timestamp": timestamp,
+        "device_id": hello.device_id.as_str(),
+        "role": role_name(hello.role),
+        "firmware_version": hello.firmware_version.as_str(),
+        "reset_reason": reset_reason_name(hello.reset_reason),
+        "capabilities": capability_names(hello.capabilities),
+        "capability_bits": hello.capabilities,
+        "config_version": hello.config_version,
+        "name": hello.name.as_str(),
+        "idempotency_key": idempotency_key,
+    }
+}
+
+/// Let the backend know that `src` booted.
+pub fn upload(src: &str, timestamp: i64, hello: &HelloMsg) -> Result<(), anyhow::Error> {
+    let uri = format!("https://{}/api/v1/source/{src}/hello", config().api_host);
+    let key = idempotency_key(&[src.as_bytes(), &timestamp.to_le_bytes()]);
+    let data = payload(timestamp, hello, &key).dump();
+    ApiClient::new(PROXY).with_idempotency_key(&key).post(
+        &uri,
+        "application/json",
+        data.as_bytes(),
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use morty_rs::hello::CAP_GPS;
+    use morty_rs::hello::CAP_OTA;
+    use morty_rs::messages::Role;
+
+    // esp_reset_reason_t of a power on
+    const POWER_ON: u32 = 1;
+
+    fn hello(firmware_version: &str) -> HelloMsg {
+        HelloMsg {
+            device_id: "24:0a:c4:00:00:01".to_string(),
+            role: Role::Tracker as i32,
+            firmware_version: firmware_version.to_string(),
+            reset_reason: POWER_ON,
+            capabilities: CAP_GPS | CAP_OTA,
+            config_version: 0xdeadbeef,
+            name: "bike".to_string(),
+        }
+    }
+
+    fn device(src: &str, firmware_version: &str) -> Device {
+        Device {
+            src: src.to_string(),
+            timestamp: 1_700_000_000,
+            hello: hello(firmware_version),
+        }
+    }
+
+    #[test]
+    fn payloads_name_what_they_can() {
+        let payload = payload(1_700_000_000, &hello("1.2.3"), "key");
+        assert_eq!(payload["timestamp"], 1_700_000_000);
+        assert_eq!(payload["device_id"], "24:0a:c4:00:00:01");
+        assert_eq!(payload["role"], "tracker");
+        assert_eq!(payload["firmware_version"], "1.2.3");
+        assert_eq!(payload["reset_reason"], "power_on");
+        assert_eq!(payload["capabilities"], json::array!["gps", "ota"]);
+        assert_eq!(payload["capability_bits"], CAP_GPS | CAP_OTA);
+        assert_eq!(payload["config_version"], 0xdeadbeef_u32);
+        assert_eq!(payload["name"], "bike");
+        assert_eq!(payload["idempotency_key"], "key");
+        assert_eq!(payload.len(), 10);
+    }
+
+    #[test]
+    fn devices_keep_their_last_hello() {
+        let mut devices = Devices::default();
+        devices.record(device("b", "1.0.0"));
+        devices.record(device("a", "1.0.0"));
+        devices.record(device("b", "1.1.0"));
+        let list = devices.list();
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[0].src, "a");
+        assert_eq!(list[1].src, "b");
+        assert_eq!(list[1].hello.firmware_version, "1.1.0");
+    }
+
+    #[test]
+    fn devices_show_what_they_are() {
+        assert_eq!(
+            device("a", "1.2.3").to_string(),
+            "a (bike): tracker firmware 1.2.3, config deadbeef, booted at 1700000000 (power_on), \
+             capabilities [gps, ota]"
+        );
+    }
+}
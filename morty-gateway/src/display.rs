@@ -0,0 +1,236 @@
+//! Status display for gateways with an SSD1306 on I2C. It listens to the gateway events like any
+//! other subscriber and rotates through the pages from `pages` on its own thread. Without a
+//! display on the bus, the gateway carries on without one.
+
+use crate::events::GatewayEvent;
+use crate::events::Subscriber;
+use crate::link::BeaconIdentity;
+use crate::link::LinkState;
+use crate::pages;
+use crate::pages::Snapshot;
+use crate::pages::LINES;
+use crate::DISPLAY_THREAD;
+use crate::UART_ERRORS;
+use crate::UART_FRAMES;
+use anyhow::anyhow;
+use embedded_graphics::mono_font::ascii::FONT_6X10;
+use embedded_graphics::mono_font::MonoTextStyle;
+use embedded_graphics::pixelcolor::BinaryColor;
+use embedded_graphics::prelude::*;
+use embedded_graphics::text::Baseline;
+use embedded_graphics::text::Text;
+use esp_idf_hal::gpio::AnyIOPin;
+use esp_idf_hal::i2c;
+use esp_idf_hal::peripheral::Peripheral;
+use esp_idf_hal::prelude::*;
+use esp_idf_sys::esp;
+use log::*;
+use morty_rs::utils::spawn_thread;
+use morty_rs::wifi;
+use ssd1306::mode::BufferedGraphicsMode;
+use ssd1306::prelude::*;
+use ssd1306::I2CDisplayInterface;
+use ssd1306::Ssd1306;
+use std::collections::VecDeque;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+// Time every page is shown
+const PAGE_INTERVAL: Duration = Duration::from_secs(4);
+// Height of a line of text, in pixels
+const LINE_HEIGHT: i32 = 64 / LINES as i32;
+const HOUR: Duration = Duration::from_secs(60 * 60);
+
+type Display = Ssd1306<
+    I2CInterface<i2c::I2cDriver<'static>>,
+    DisplaySize128x64,
+    BufferedGraphicsMode<DisplaySize128x64>,
+>;
+
+/// What the display shows, kept up to date from the gateway events.
+#[derive(Default)]
+struct DisplayState {
+    received: VecDeque<Instant>,
+    uploaded: VecDeque<Instant>,
+    queue_depth: usize,
+    last_fixes: Vec<(String, Instant)>,
+    upload_failures: u32,
+    beacon: Option<BeaconIdentity>,
+    beacon_up: Option<bool>,
+}
+
+impl DisplayState {
+    fn handle(&mut self, event: &GatewayEvent, now: Instant) {
+        match event {
+            GatewayEvent::FixValidated { src, .. } => {
+                self.received.push_back(now);
+                match self.last_fixes.iter_mut().find(|(s, _)| s == src) {
+                    Some((_, at)) => *at = now,
+                    None => self.last_fixes.push((src.clone(), now)),
+                }
+            }
+            GatewayEvent::UploadSucceeded { .. } => self.uploaded.push_back(now),
+            GatewayEvent::UploadFailed { .. } => self.upload_failures += 1,
+            GatewayEvent::QueueDepthChanged { depth } => self.queue_depth = *depth,
+            GatewayEvent::BeaconLink { state } => match state {
+                LinkState::Up(beacon)
+                | LinkState::Changed {
+                    current: beacon, ..
+                }
+                | LinkState::Rebooted(beacon) => {
+                    self.beacon = Some(beacon.clone());
+                    self.beacon_up = Some(true);
+                }
+                LinkState::Lost => self.beacon_up = Some(false),
+            },
+            _ => {}
+        }
+        forget_before(&mut self.received, now);
+        forget_before(&mut self.uploaded, now);
+    }
+
+    fn snapshot(&mut self, ssid: &str, ip: &str, now: Instant) -> Snapshot {
+        forget_before(&mut self.received, now);
+        forget_before(&mut self.uploaded, now);
+        Snapshot {
+            ssid: ssid.to_string(),
+            ip: ip.to_string(),
+            rssi: rssi(),
+            received_last_hour: self.received.len(),
+            uploaded_last_hour: self.uploaded.len(),
+            queue_depth: self.queue_depth,
+            sources: self
+                .last_fixes
+                .iter()
+                .map(|(src, at)| (src.clone(), now.duration_since(*at)))
+                .collect(),
+            upload_failures: self.upload_failures,
+            uart_framing_errors: UART_ERRORS.framing.load(Ordering::Relaxed),
+            uart_parity_errors: UART_ERRORS.parity.load(Ordering::Relaxed),
+            uart_overruns: UART_ERRORS.overrun.load(Ordering::Relaxed),
+            decode_failures: UART_FRAMES.decode_failures.total(),
+            beacon: self
+                .beacon
+                .as_ref()
+                .map(|b| (b.device_id.clone(), b.firmware_version.clone())),
+            beacon_up: self.beacon_up,
+        }
+    }
+}
+
+/// Feeds the gateway events to the display.
+pub struct DisplaySubscriber {
+    state: Arc<Mutex<DisplayState>>,
+}
+
+impl Subscriber for DisplaySubscriber {
+    fn handle(&mut self, event: &GatewayEvent) {
+        self.state.lock().unwrap().handle(event, Instant::now());
+    }
+}
+
+/// Start the display on the I2C bus on `sda` and `scl`. Returns None when there is no display,
+/// or it doesn't respond.
+pub fn start(
+    i2c: impl Peripheral<P = impl i2c::I2c> + 'static,
+    sda: AnyIOPin,
+    scl: AnyIOPin,
+    ip: String,
+) -> Option<DisplaySubscriber> {
+    let display = match init(i2c, sda, scl) {
+        Ok(display) => display,
+        Err(e) => {
+            info!("No status display: {e}");
+            return None;
+        }
+    };
+    info!("Status display found");
+
+    let state = Arc::new(Mutex::new(DisplayState::default()));
+    if let Err(e) = spawn(display, state.clone(), ip) {
+        error!("Unable to start the status display: {e}");
+        return None;
+    }
+    Some(DisplaySubscriber { state })
+}
+
+fn spawn(
+    display: Display,
+    state: Arc<Mutex<DisplayState>>,
+    ip: String,
+) -> Result<(), anyhow::Error> {
+    spawn_thread(DISPLAY_THREAD, move || show_pages(display, state, ip))?;
+    Ok(())
+}
+
+fn init(
+    i2c: impl Peripheral<P = impl i2c::I2c> + 'static,
+    sda: AnyIOPin,
+    scl: AnyIOPin,
+) -> Result<Display, anyhow::Error> {
+    let config = i2c::I2cConfig::new().baudrate(400.kHz().into());
+    let driver = i2c::I2cDriver::new(i2c, sda, scl, &config)?;
+    let mut display = Ssd1306::new(
+        I2CDisplayInterface::new(driver),
+        DisplaySize128x64,
+        DisplayRotation::Rotate0,
+    )
+    .into_buffered_graphics_mode();
+    // Nothing acknowledges the first command when there is no display
+    display.init().map_err(|e| anyhow!("{e:?}"))?;
+    Ok(display)
+}
+
+/// Show the pages one after the other, forever, with the network we use now. When the display
+/// stops responding, we give up on it.
+fn show_pages(mut display: Display, state: Arc<Mutex<DisplayState>>, ip: String) {
+    let mut page = 0;
+    loop {
+        let ssid = wifi::active_ssid().unwrap_or_default();
+        let snapshot = state.lock().unwrap().snapshot(&ssid, &ip, Instant::now());
+        let pages = pages::pages(&snapshot);
+        page %= pages.len();
+        if let Err(e) = draw(&mut display, &pages[page]) {
+            error!("Status display stopped responding: {e}");
+            return;
+        }
+        page += 1;
+        std::thread::sleep(PAGE_INTERVAL);
+    }
+}
+
+fn draw(display: &mut Display, lines: &[String]) -> Result<(), anyhow::Error> {
+    let style = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
+    display.clear();
+    for (i, line) in lines.iter().enumerate() {
+        Text::with_baseline(
+            line,
+            Point::new(0, i as i32 * LINE_HEIGHT),
+            style,
+            Baseline::Top,
+        )
+        .draw(display)
+        .map_err(|e| anyhow!("{e:?}"))?;
+    }
+    display.flush().map_err(|e| anyhow!("{e:?}"))
+}
+
+/// Signal strength of the access point we're connected to.
+fn rssi() -> Option<i8> {
+    let mut info = esp_idf_sys::wifi_ap_record_t::default();
+    esp!(unsafe { esp_idf_sys::esp_wifi_sta_get_ap_info(&mut info) }).ok()?;
+    Some(info.rssi)
+}
+
+/// Drop the times that are more than an hour before `now`.
+fn forget_before(times: &mut VecDeque<Instant>, now: Instant) {
+    while times
+        .front()
+        .map_or(false, |&t| now.duration_since(t) > HOUR)
+    {
+        times.pop_front();
+    }
+}
@@ -0,0 +1,162 @@
+//! Commands from the backend for devices in the field. We poll the backend for them and write them
+//! to the beacon over UART, which executes them or broadcasts them to the device they are for.
+//! Acknowledgements come back the same way as fixes and are posted to the backend.
+
+use crate::api::ApiClient;
+use crate::config::config;
+use crate::events::Events;
+use crate::events::GatewayEvent;
+use crate::serializer::idempotency_key;
+use crate::PROXY;
+use base64::engine::general_purpose;
+use base64::Engine;
+use log::*;
+use morty_rs::comm::encode_msg;
+use morty_rs::comm::CommError;
+use morty_rs::comm::UART_HEADER;
+use morty_rs::messages::morty_message;
+use morty_rs::messages::Command;
+use morty_rs::messages::CommandAckMsg;
+use morty_rs::messages::CommandMsg;
+use morty_rs::utils::Backoff;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+// Longest time between polls while the backend is unreachable
+const MAX_POLL_INTERVAL: Duration = Duration::from_secs(10 * 60);
+// Number of nonces we remember, so we don't send the same command twice
+const SENT_NONCES: usize = 32;
+
+/// Poll the backend for commands every `interval` and write the new ones to the UART on
+/// `uart_port`. The UART driver is owned by the thread reading from it, but writing to it from
+/// here is safe. The configuration versions the devices should have are fetched along with the
+/// commands and emitted as an event.
+pub fn command_task(uart_port: i32, interval: Duration, events: Events) -> ! {
+    let mut sent = VecDeque::new();
+    let mut backoff = Backoff::new(interval, 2, MAX_POLL_INTERVAL.max(interval)).with_jitter();
+    loop {
+        let mut delay = interval;
+        match fetch_commands() {
+            Ok(commands) => {
+                backoff.reset();
+                for cmd in commands {
+                    if sent.contains(&cmd.nonce) {
+                        continue;
+                    }
+                    info!("Sending command {:?} to {}", cmd.command, cmd.target);
+                    send_frame(uart_port, &morty_message::Msg::Command(cmd.clone()));
+
+                    sent.push_back(cmd.nonce);
+                    if sent.len() > SENT_NONCES {
+                        sent.pop_front();
+                    }
+                }
+            }
+            Err(e) => {
+                error!("Unable to fetch commands: {:?}", e);
+                // Poll less often while the backend is unreachable, but not less than usual
+                delay = backoff.next_delay().unwrap_or(interval).max(interval);
+            }
+        }
+        match fetch_desired_configs() {
+            Ok(versions) => events.emit(GatewayEvent::DesiredConfigs { versions }),
+            Err(e) => error!("Unable to fetch configuration versions: {:?}", e),
+        }
+        std::thread::sleep(delay);
+    }
+}
+
+/// Let the backend know that `src` received a command.
+pub fn upload_ack(src: &str, ack: &CommandAckMsg) -> Result<(), anyhow::Error> {
+    let uri = format!(
+        "https://{}/api/v1/source/{src}/command/{}/ack",
+        config().api_host,
+        ack.nonce
+    );
+    let key = idempotency_key(&[src.as_bytes(), &ack.nonce.to_le_bytes()]);
+    let data = json::object! { "ok": ack.ok, "idempotency_key": key.as_str() }.dump();
+    ApiClient::new(PROXY).with_idempotency_key(&key).post(
+        &uri,
+        "application/json",
+        data.as_bytes(),
+    )?;
+    Ok(())
+}
+
+/// Write a message for the beacon to the UART on `uart_port`.
+pub fn send_frame(uart_port: i32, msg: &morty_message::Msg) {
+    match frame_line(msg) {
+        Ok(line) => uart_write(uart_port, line.as_bytes()),
+        Err(e) => error!("Unable to encode {msg:?}: {e}"),
+    }
+}
+
+/// `msg` as a line for a beacon, on the UART or over a bridge.
+pub fn frame_line(msg: &morty_message::Msg) -> Result<String, CommError> {
+    let data = encode_msg(msg)?;
+    Ok(format!(
+        "{UART_HEADER}{}\n",
+        general_purpose::STANDARD.encode(data)
+    ))
+}
+
+/// Fetch `uri` and return the body.
+pub fn http_get(uri: &str) -> Result<Vec<u8>, anyhow::Error> {
+    Ok(ApiClient::new(PROXY).get(uri)?.body)
+}
+
+fn fetch_commands() -> Result<Vec<CommandMsg>, anyhow::Error> {
+    let body = http_get(&format!(
+        "https://{}/api/v1/commands/pending",
+        config().api_host
+    ))?;
+    let json = json::parse(&String::from_utf8_lossy(&body))?;
+    Ok(json.members().filter_map(parse_command).collect())
+}
+
+/// The configuration versions the devices should have, by MAC address.
+fn fetch_desired_configs() -> Result<HashMap<String, u32>, anyhow::Error> {
+    let body = http_get(&format!(
+        "https://{}/api/v1/sources/config_versions",
+        config().api_host
+    ))?;
+    let json = json::parse(&String::from_utf8_lossy(&body))?;
+    Ok(json
+        .entries()
+        .filter_map(|(src, version)| Some((src.to_string(), version.as_u32()?)))
+        .collect())
+}
+
+fn parse_command(json: &json::JsonValue) -> Option<CommandMsg> {
+    let command = match json["command"].as_str()? {
+        "reboot" => Command::Reboot,
+        "identify" => Command::Identify,
+        "clear_nvs_section" => Command::ClearNvsSection,
+        "resend_stats" => Command::ResendStats,
+        "stay_awake" => Command::StayAwake,
+        "allow_sleep" => Command::AllowSleep,
+        "resend_trace" => Command::ResendTrace,
+        "set_name" => Command::SetName,
+        other => {
+            warn!("Unknown command {other}");
+            return None;
+        }
+    };
+    Some(CommandMsg {
+        target: json["target"].as_str()?.to_string(),
+        command: command as i32,
+        nonce: json["nonce"].as_u32()?,
+        timestamp: json["timestamp"].as_i64()?,
+        section: json["section"].as_str().unwrap_or_default().to_string(),
+        name: json["name"].as_str().unwrap_or_default().to_string(),
+    })
+}
+
+fn uart_write(uart_port: i32, data: &[u8]) {
+    let written =
+        unsafe { esp_idf_sys::uart_write_bytes(uart_port, data.as_ptr() as *const _, data.len()) };
+    if written < 0 {
+        error!("Unable to write to UART {uart_port}");
+    }
+}
@@ -0,0 +1,951 @@
+//! Things that happen while the gateway processes frames. The pipeline emits them and doesn't
+//! care who listens. Subscribers turn them into LED patterns, audit entries and log lines on
+//! their own thread, so new observers don't have to touch the pipeline.
+
+use crate::audit::AuditEntry;
+use crate::audit::AuditLog;
+use crate::capture::FailedFrame;
+use crate::capture::FailedFrames;
+use crate::console::TEST_SOURCE;
+use crate::devices::Device;
+use crate::devices::DeviceClock;
+use crate::devices::Devices;
+use crate::frames::Via;
+use crate::last_fix::LastFix;
+use crate::last_fix::LastFixes;
+use crate::link::LinkState;
+use crate::maintenance::Phase;
+use crate::notify::Notifier;
+use crate::pin::CertFailure;
+use crate::queue::PendingEvent;
+use crate::queue::UploadClass;
+use crate::sources::Registry;
+use crate::sources::Source;
+use crate::usage::date;
+use crate::usage::day_json;
+use crate::usage::day_of;
+use crate::usage::DailyUsage;
+use crate::EVENT_THREAD;
+use crate::LED_BRIGHTNESS;
+use log::*;
+use morty_rs::clock::monotonic_to_wall;
+use morty_rs::clock::Clock;
+use morty_rs::config::check_drift;
+use morty_rs::config::Drift;
+use morty_rs::heap::HeapActions;
+use morty_rs::led::colors;
+use morty_rs::led::LedHandle;
+use morty_rs::messages::ClockStatusMsg;
+use morty_rs::messages::GpsMsg;
+use morty_rs::utils::format_duration;
+use morty_rs::utils::spawn_thread;
+use morty_rs::utils::uptime;
+use std::collections::HashMap;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+use std::time::Instant;
+
+// Period of the white pulse of the LED while maintenance mode pauses and drains
+const MAINTENANCE_PULSE: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Debug)]
+pub enum GatewayEvent {
+    /// A frame from the beacon decoded fine.
+    FrameDecoded {
+        src: String,
+    },
+    /// A frame from the beacon didn't decode.
+    FrameFailed {
+        frame: FailedFrame,
+    },
+    /// A fix we have seen before, e.g. because multiple beacons relayed it.
+    DuplicateDropped {
+        uid: String,
+    },
+    /// A fix was too old to upload by the time we got to it.
+    StaleDropped {
+        uid: String,
+        src: String,
+        age: Duration,
+    },
+    /// A fix or event was queued for longer than its class is worth uploading. `id` is the UID
+    /// of a fix or the name of an event.
+    UploadExpired {
+        class: UploadClass,
+        id: String,
+        src: String,
+        age: Duration,
+    },
+    /// An event of a source was queued for upload, e.g. a beacon that lost power.
+    EventQueued {
+        src: String,
+        name: &'static str,
+        timestamp: i64,
+    },
+    /// A new fix was queued for upload.
+    FixValidated {
+        uid: String,
+        src: String,
+        timestamp: i64,
+        gps: GpsMsg,
+        // A retransmission of a fix we had seen, with news, see `morty_rs::dedup`
+        update: bool,
+    },
+    UploadSucceeded {
+        uid: String,
+        src: String,
+        attempt: u32,
+        idempotency_key: String,
+        status: u16,
+        body_hash: u64,
+    },
+    UploadFailed {
+        uid: String,
+        src: String,
+        attempt: u32,
+        idempotency_key: String,
+        // The proxy failed, rather than the API server
+        proxy: bool,
+        // Why we refused the certificate of the API server, if we did
+        certificate: Option<CertFailure>,
+        reason: String,
+    },
+    QueueDepthChanged {
+        depth: usize,
+    },
+    /// The heap runs low, stop (or resume) what we can do without.
+    LoadShedding {
+        active: bool,
+    },
+    /// We're about to reboot, or to be unplugged, write out what should survive it.
+    ShuttingDown,
+    /// Maintenance mode moved on to `phase`, see `maintenance`.
+    Maintenance {
+        phase: Phase,
+    },
+    /// A test fix was injected from the console, which wants to know how its upload went.
+    TestFixInjected {
+        uid: String,
+        reply: Sender<Result<u16, String>>,
+    },
+    /// The beacon on the UART answered a ping for the first time, stopped answering or was
+    /// replaced.
+    BeaconLink {
+        state: LinkState,
+    },
+    /// A message came in with the gateway hint of another gateway, for the first time since boot.
+    /// The beacon that stamped it is attached to that gateway, or thinks it is.
+    GatewayHintMismatch {
+        hint: String,
+        src: String,
+        via: Via,
+    },
+    /// The configuration versions the devices should have, by MAC address, as fetched from the
+    /// backend.
+    DesiredConfigs {
+        versions: HashMap<String, u32>,
+    },
+    /// A device booted, see `morty_rs::hello`.
+    HelloReceived {
+        device: Device,
+    },
+    /// The console wants the last hello of every device.
+    DevicesRequested {
+        reply: Sender<Vec<Device>>,
+    },
+    /// A device reported its clock, see `morty_rs::timesync`.
+    ClockReported {
+        src: String,
+        clock: ClockStatusMsg,
+    },
+    /// The console wants the last clock status of every device.
+    ClocksRequested {
+        reply: Sender<Vec<DeviceClock>>,
+    },
+    /// The console wants a copy of the last fixes.
+    ExportRequested {
+        reply: Sender<Vec<LastFix>>,
+    },
+    /// The console wants something done with the captured frames that didn't decode.
+    CaptureRequested {
+        request: CaptureRequest,
+    },
+    /// The console wants every source we ever heard from.
+    SourcesRequested {
+        reply: Sender<Vec<Source>>,
+    },
+    /// The console confirmed forgetting the sources we last heard from before `before`, in
+    /// seconds since the epoch. The reply has the ones that were forgotten.
+    SourcesPruned {
+        before: i64,
+        reply: Sender<Vec<String>>,
+    },
+    /// The console wants the fixes per source of the last `days` days, as JSON.
+    UsageRequested {
+        days: u32,
+        reply: Sender<String>,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub enum CaptureRequest {
+    /// Capture frames that don't decode, for as long as captures last.
+    Start,
+    Stop,
+    Clear,
+    /// A copy of the captured frames, oldest first.
+    Dump(Sender<Vec<FailedFrame>>),
+}
+
+/// Something that wants to know about gateway events. Subscribers run on the event thread, one
+/// event at a time.
+pub trait Subscriber: Send {
+    fn handle(&mut self, event: &GatewayEvent);
+}
+
+/// Cheap handle for emitting events, that can be cloned and handed to other threads.
+#[derive(Clone)]
+pub struct Events {
+    tx: Sender<GatewayEvent>,
+}
+
+impl Events {
+    pub fn emit(&self, event: GatewayEvent) {
+        // Nobody listening isn't a reason to stop processing frames
+        if self.tx.send(event).is_err() {
+            warn!("Event thread is gone, dropping event");
+        }
+    }
+}
+
+/// Turns what the heap guard wants into events.
+pub struct HeapEvents {
+    events: Events,
+}
+
+impl HeapEvents {
+    pub fn new(events: Events) -> Self {
+        Self { events }
+    }
+}
+
+impl HeapActions for HeapEvents {
+    fn shed_load(&mut self, shed: bool) {
+        self.events
+            .emit(GatewayEvent::LoadShedding { active: shed });
+    }
+
+    fn flush(&mut self) {
+        self.events.emit(GatewayEvent::ShuttingDown);
+    }
+}
+
+/// Start the event thread with the given subscribers.
+pub fn start(subscribers: Vec<Box<dyn Subscriber>>) -> Result<Events, anyhow::Error> {
+    let (tx, rx) = std::sync::mpsc::channel();
+    spawn_thread(EVENT_THREAD, move || dispatch(rx, subscribers))?;
+    Ok(Events { tx })
+}
+
+fn dispatch(rx: Receiver<GatewayEvent>, mut subscribers: Vec<Box<dyn Subscriber>>) {
+    for event in rx {
+        for subscriber in subscribers.iter_mut() {
+            subscriber.handle(&event);
+        }
+    }
+}
+
+/// Keeps the last hello and clock status of every device, for the console.
+#[derive(Default)]
+pub struct DeviceSubscriber {
+    devices: Devices,
+}
+
+impl Subscriber for DeviceSubscriber {
+    fn handle(&mut self, event: &GatewayEvent) {
+        match event {
+            GatewayEvent::HelloReceived { device } => self.devices.record(device.clone()),
+            GatewayEvent::DevicesRequested { reply } => {
+                // The console might have given up waiting
+                let _ = reply.send(self.devices.list());
+            }
+            GatewayEvent::ClockReported { src, clock } => self.devices.record_clock(src, clock),
+            GatewayEvent::FixValidated {
+                src,
+                gps: GpsMsg {
+                    clock: Some(clock), ..
+                },
+                ..
+            } => self.devices.record_clock(src, clock),
+            GatewayEvent::ClocksRequested { reply } => {
+                let _ = reply.send(self.devices.clocks());
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Hands the notifications of critical events to the pipeline, which queues them for the webhook.
+/// The `site_recovered` one goes out with the first event after the grace period.
+pub struct NotifySubscriber {
+    notifier: Notifier,
+    clock: Clock,
+    notifications: Sender<PendingEvent>,
+}
+
+impl NotifySubscriber {
+    pub fn new(notifier: Notifier, clock: Clock, notifications: Sender<PendingEvent>) -> Self {
+        Self {
+            notifier,
+            clock,
+            notifications,
+        }
+    }
+}
+
+impl Subscriber for NotifySubscriber {
+    fn handle(&mut self, event: &GatewayEvent) {
+        let now = self.clock.monotonic();
+        let wall = self.clock.wall().map(|t| t.as_secs() as i64);
+        let recovered = self.notifier.check_grace(now, wall);
+        let notification = self.notifier.handle(event, now, wall);
+        for notification in recovered.into_iter().chain(notification) {
+            info!("Notifying {notification}");
+            let pending = PendingEvent::notification(
+                notification.src.clone(),
+                notification.critical.name(),
+                notification.timestamp,
+                now,
+                notification.payload().dump(),
+            );
+            // The pipeline might be gone, then there's nobody to send it anyway
+            let _ = self.notifications.send(pending);
+        }
+    }
+}
+
+/// Shows what happens on the LED.
+pub struct LedSubscriber {
+    led: LedHandle,
+    // Safe to unplug, the LED stays steady white until maintenance is over
+    safe: bool,
+}
+
+impl LedSubscriber {
+    pub fn new(led: LedHandle) -> Self {
+        Self { led, safe: false }
+    }
+
+    fn maintenance(&mut self, phase: Phase) -> anyhow::Result<()> {
+        self.safe = phase == Phase::Safe;
+        match phase {
+            Phase::Running => self.led.set_color(colors::GREEN, LED_BRIGHTNESS),
+            Phase::Pausing | Phase::Draining => {
+                self.led
+                    .pulse_color(colors::WHITE, LED_BRIGHTNESS, MAINTENANCE_PULSE)
+            }
+            Phase::Safe => self.led.set_color(colors::WHITE, LED_BRIGHTNESS),
+        }
+    }
+}
+
+impl Subscriber for LedSubscriber {
+    fn handle(&mut self, event: &GatewayEvent) {
+        if let GatewayEvent::Maintenance { phase } = event {
+            if let Err(e) = self.maintenance(*phase) {
+                error!("Unable to show maintenance on the LED: {e}");
+            }
+            return;
+        }
+        if self.safe {
+            return;
+        }
+        let color = match event {
+            GatewayEvent::DuplicateDropped { .. } => colors::ORANGE,
+            GatewayEvent::UploadSucceeded { .. } => colors::PURPLE,
+            GatewayEvent::UploadFailed { .. } => colors::RED,
+            GatewayEvent::BeaconLink {
+                state: LinkState::Lost | LinkState::Changed { .. },
+            }
+            | GatewayEvent::GatewayHintMismatch { .. } => colors::YELLOW,
+            _ => return,
+        };
+        if let Err(e) = self
+            .led
+            .blink_color(color, LED_BRIGHTNESS, Duration::from_millis(300), 2)
+        {
+            error!("Unable to blink LED: {e}");
+        }
+    }
+}
+
+/// Records upload attempts in the audit log, and shows the earlier attempts when a duplicate
+/// comes in. While shedding load, the log is emptied and nothing is recorded.
+pub struct AuditSubscriber {
+    audit: AuditLog,
+    paused: bool,
+}
+
+impl AuditSubscriber {
+    pub fn new(audit: AuditLog) -> Self {
+        Self {
+            audit,
+            paused: false,
+        }
+    }
+}
+
+impl Subscriber for AuditSubscriber {
+    fn handle(&mut self, event: &GatewayEvent) {
+        if let GatewayEvent::LoadShedding { active } = event {
+            self.paused = *active;
+            if self.paused {
+                self.audit.clear();
+            }
+        }
+        if self.paused {
+            return;
+        }
+
+        match event {
+            GatewayEvent::UploadSucceeded {
+                uid,
+                src,
+                attempt,
+                idempotency_key,
+                status,
+                body_hash,
+            } => self.audit.record(AuditEntry {
+                uid: uid.clone(),
+                src: src.clone(),
+                attempt: *attempt,
+                idempotency_key: idempotency_key.clone(),
+                status: Some(*status),
+                body_hash: Some(*body_hash),
+            }),
+            GatewayEvent::UploadFailed {
+                uid,
+                src,
+                attempt,
+                idempotency_key,
+                ..
+            } => self.audit.record(AuditEntry {
+                uid: uid.clone(),
+                src: src.clone(),
+                attempt: *attempt,
+                idempotency_key: idempotency_key.clone(),
+                status: None,
+                body_hash: None,
+            }),
+            GatewayEvent::DuplicateDropped { uid } => self.audit.log(uid),
+            _ => {}
+        }
+    }
+}
+
+/// Keeps track of the last fix of every source.
+pub struct LastFixSubscriber {
+    fixes: LastFixes,
+}
+
+impl LastFixSubscriber {
+    pub fn new(fixes: LastFixes) -> Self {
+        Self { fixes }
+    }
+}
+
+impl Subscriber for LastFixSubscriber {
+    fn handle(&mut self, event: &GatewayEvent) {
+        match event {
+            // Test fixes don't say anything about where a tracker is
+            GatewayEvent::FixValidated { src, .. } if src == TEST_SOURCE => {}
+            GatewayEvent::FixValidated {
+                src,
+                timestamp,
+                gps,
+                ..
+            } => self.fixes.update(src, *timestamp, gps),
+            GatewayEvent::ShuttingDown => self.fixes.save(),
+            GatewayEvent::ExportRequested { reply } => {
+                // The console might have given up waiting
+                let _ = reply.send(self.fixes.fixes().to_vec());
+            }
+            // Changes that were held back are written once they're due
+            _ => self.fixes.save_if_due(),
+        }
+    }
+}
+
+/// Keeps the registry of every source we heard from, and logs a summary of it every day.
+pub struct SourceSubscriber {
+    registry: Registry,
+    clock: Clock,
+    // The day we last logged the summary
+    day: Option<u32>,
+}
+
+impl SourceSubscriber {
+    pub fn new(registry: Registry, clock: Clock) -> Self {
+        Self {
+            registry,
+            clock,
+            day: None,
+        }
+    }
+}
+
+impl Subscriber for SourceSubscriber {
+    fn handle(&mut self, event: &GatewayEvent) {
+        let wall = self.clock.wall();
+        let now = wall.map(|t| t.as_secs() as i64);
+        if let Some(wall) = wall {
+            let day = day_of(wall);
+            if self.day != Some(day) {
+                self.day = Some(day);
+                info!(
+                    "Source registry: {}",
+                    self.registry.summary(wall.as_secs() as i64)
+                );
+            }
+        }
+
+        match event {
+            GatewayEvent::FrameDecoded { src } if src != TEST_SOURCE => {
+                self.registry.seen(src, now)
+            }
+            GatewayEvent::HelloReceived { device } => self.registry.hello(
+                &device.src,
+                &device.hello.firmware_version,
+                &device.hello.name,
+            ),
+            GatewayEvent::FixValidated { src, gps, .. } => self.registry.name(src, &gps.name),
+            GatewayEvent::SourcesRequested { reply } => {
+                // The console might have given up waiting
+                let _ = reply.send(self.registry.sources().to_vec());
+            }
+            GatewayEvent::SourcesPruned { before, reply } => {
+                let _ = reply.send(self.registry.prune(*before));
+            }
+            GatewayEvent::ShuttingDown => self.registry.save(),
+            _ => {}
+        }
+        self.registry.save_if_due();
+    }
+}
+
+/// Tells the console how the first upload of an injected test fix went.
+#[derive(Default)]
+pub struct TestFixSubscriber {
+    pending: HashMap<String, Sender<Result<u16, String>>>,
+}
+
+impl Subscriber for TestFixSubscriber {
+    fn handle(&mut self, event: &GatewayEvent) {
+        let (uid, outcome) = match event {
+            GatewayEvent::TestFixInjected { uid, reply } => {
+                self.pending.insert(uid.clone(), reply.clone());
+                return;
+            }
+            GatewayEvent::UploadSucceeded { uid, status, .. } => (uid, Ok(*status)),
+            GatewayEvent::UploadFailed { uid, reason, .. } => (uid, Err(reason.clone())),
+            GatewayEvent::StaleDropped { uid, age, .. } => (
+                uid,
+                Err(format!("dropped as stale, {} old", format_duration(*age))),
+            ),
+            GatewayEvent::UploadExpired { id, age, .. } => (
+                id,
+                Err(format!(
+                    "expired after {} in the queue",
+                    format_duration(*age)
+                )),
+            ),
+            _ => return,
+        };
+        if let Some(reply) = self.pending.remove(uid) {
+            // The console might have given up waiting
+            let _ = reply.send(outcome);
+        }
+    }
+}
+
+/// Compares the configuration versions that the trackers and the beacon report with the ones they
+/// should have, and warns about the devices that drifted. Only changes are logged.
+#[derive(Default)]
+pub struct DriftSubscriber {
+    desired: HashMap<String, u32>,
+    reported: HashMap<String, u32>,
+    drift: HashMap<String, Drift>,
+}
+
+impl DriftSubscriber {
+    fn check(&mut self, src: &str) {
+        let Some(&reported) = self.reported.get(src) else {
+            return;
+        };
+        let drift = check_drift(reported, self.desired.get(src).copied());
+        if self.drift.insert(src.to_string(), drift) == Some(drift) {
+            return;
+        }
+        match drift {
+            Drift::Drifted { reported, desired } => warn!(
+                "Configuration of {src} drifted: it runs {reported:08x}, it should run {desired:08x}"
+            ),
+            Drift::InSync => info!("Configuration of {src} is up to date ({reported:08x})"),
+            Drift::Unknown => {}
+        }
+    }
+}
+
+impl Subscriber for DriftSubscriber {
+    fn handle(&mut self, event: &GatewayEvent) {
+        let (src, version) = match event {
+            // Trackers only send their version every now and then
+            GatewayEvent::FixValidated { src, gps, .. } if gps.config_version != 0 => {
+                (src, gps.config_version)
+            }
+            GatewayEvent::BeaconLink {
+                state:
+                    LinkState::Up(beacon)
+                    | LinkState::Changed {
+                        current: beacon, ..
+                    }
+                    | LinkState::Rebooted(beacon),
+            } if beacon.config_version != 0 => (&beacon.device_id, beacon.config_version),
+            GatewayEvent::DesiredConfigs { versions } => {
+                self.desired = versions.clone();
+                let sources: Vec<String> = self.reported.keys().cloned().collect();
+                for src in sources {
+                    self.check(&src);
+                }
+                return;
+            }
+            _ => return,
+        };
+        self.reported.insert(src.clone(), version);
+        self.check(src);
+    }
+}
+
+/// Keeps the last frames that didn't decode, while capturing. While shedding load, the frames
+/// are dropped and capturing stops.
+pub struct CaptureSubscriber {
+    frames: FailedFrames,
+}
+
+impl CaptureSubscriber {
+    pub fn new(frames: FailedFrames) -> Self {
+        Self { frames }
+    }
+}
+
+impl Subscriber for CaptureSubscriber {
+    fn handle(&mut self, event: &GatewayEvent) {
+        match event {
+            GatewayEvent::FrameFailed { frame } => self.frames.record(frame.clone(), uptime()),
+            GatewayEvent::CaptureRequested { request } => match request {
+                CaptureRequest::Start => self.frames.start(uptime()),
+                CaptureRequest::Stop => self.frames.stop(),
+                CaptureRequest::Clear => self.frames.clear(),
+                CaptureRequest::Dump(reply) => {
+                    // The console might have given up waiting
+                    let _ = reply.send(self.frames.frames());
+                }
+            },
+            GatewayEvent::LoadShedding { active: true } => {
+                self.frames.stop();
+                self.frames.clear();
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Counts the fixes accepted for upload per source and per day, see `usage`. When a day ends, its
+/// counts are logged. Changes are written to NVS at most once every `save_interval`.
+pub struct UsageSubscriber {
+    usage: DailyUsage,
+    clock: Clock,
+    save_interval: Duration,
+    last_save: Option<Instant>,
+    dirty: bool,
+}
+
+impl UsageSubscriber {
+    pub fn new(usage: DailyUsage, clock: Clock, save_interval: Duration) -> Self {
+        Self {
+            usage,
+            clock,
+            save_interval,
+            last_save: None,
+            dirty: false,
+        }
+    }
+
+    fn save(&mut self) {
+        if let Err(e) = self.usage.save() {
+            error!("Unable to store usage: {e}");
+        }
+        // Also after a failure, so we don't hammer NVS
+        self.last_save = Some(Instant::now());
+        self.dirty = false;
+    }
+}
+
+impl Subscriber for UsageSubscriber {
+    fn handle(&mut self, event: &GatewayEvent) {
+        // Days only start once the wall clock is valid
+        if let Some(wall) = self.clock.wall() {
+            let now = self.clock.monotonic();
+            self.usage
+                .date(|monotonic| monotonic_to_wall(monotonic, now, wall));
+            if let Some(day) = self.usage.advance(wall) {
+                info!(
+                    "Usage of {}: {}",
+                    date(day),
+                    day_json(day, &self.usage.day(day)).dump()
+                );
+                self.dirty = true;
+            }
+        }
+
+        match event {
+            // Test fixes aren't billed, and neither are updates of fixes that were billed already
+            GatewayEvent::FixValidated { src, .. } if src == TEST_SOURCE => {}
+            GatewayEvent::FixValidated { update: true, .. } => {}
+            GatewayEvent::FixValidated { src, .. } => {
+                self.usage
+                    .record(src, self.clock.wall(), self.clock.monotonic());
+                self.dirty = true;
+            }
+            GatewayEvent::UsageRequested { days, reply } => {
+                // The console might have given up waiting
+                let _ = reply.send(self.usage.to_json(*days));
+            }
+            GatewayEvent::ShuttingDown => self.save(),
+            _ => {}
+        }
+
+        if self.dirty
+            && self
+                .last_save
+                .map_or(true, |t| t.elapsed() >= self.save_interval)
+        {
+            self.save();
+        }
+    }
+}
+
+/// Writes events to the console. While shedding load, only errors are written.
+#[derive(Default)]
+pub struct TraceSubscriber {
+    quiet: bool,
+}
+
+impl Subscriber for TraceSubscriber {
+    fn handle(&mut self, event: &GatewayEvent) {
+        match event {
+            GatewayEvent::UploadFailed {
+                uid,
+                proxy: true,
+                reason,
+                ..
+            } => error!("Error uploading {uid} through the proxy: {reason}"),
+            GatewayEvent::UploadFailed {
+                uid,
+                certificate: Some(failure),
+                ..
+            } => error!("Error uploading {uid}, refused the API server: {failure}"),
+            GatewayEvent::UploadFailed { uid, reason, .. } => {
+                error!("Error uploading {uid}: {reason}")
+            }
+            GatewayEvent::LoadShedding { active } => self.quiet = *active,
+            _ if self.quiet => {}
+            GatewayEvent::QueueDepthChanged { depth } => info!("{depth} uploads pending"),
+            _ => debug!("{:?}", event),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+
+    // Writes down the duplicates it sees, behind its own name
+    struct Recorder {
+        name: &'static str,
+        seen: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Subscriber for Recorder {
+        fn handle(&mut self, event: &GatewayEvent) {
+            if let GatewayEvent::DuplicateDropped { uid } = event {
+                self.seen
+                    .lock()
+                    .unwrap()
+                    .push(format!("{} {uid}", self.name));
+            }
+        }
+    }
+
+    fn succeeded(uid: &str, attempt: u32) -> GatewayEvent {
+        GatewayEvent::UploadSucceeded {
+            uid: uid.to_string(),
+            src: "beacon".to_string(),
+            attempt,
+            idempotency_key: format!("{uid}-key"),
+            status: 200,
+            body_hash: 0,
+        }
+    }
+
+    fn failed(uid: &str, attempt: u32) -> GatewayEvent {
+        GatewayEvent::UploadFailed {
+            uid: uid.to_string(),
+            src: "beacon".to_string(),
+            attempt,
+            idempotency_key: format!("{uid}-key"),
+            proxy: false,
+            certificate: None,
+            reason: "timed out".to_string(),
+        }
+    }
+
+    fn fix(src: &str, config_version: u32) -> GatewayEvent {
+        GatewayEvent::FixValidated {
+            uid: "uid".to_string(),
+            src: src.to_string(),
+            timestamp: 0,
+            gps: GpsMsg {
+                config_version,
+                ..Default::default()
+            },
+            update: false,
+        }
+    }
+
+    #[test]
+    fn every_subscriber_gets_every_event_in_order() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let subscribers: Vec<Box<dyn Subscriber>> = vec![
+            Box::new(Recorder {
+                name: "a",
+                seen: seen.clone(),
+            }),
+            Box::new(Recorder {
+                name: "b",
+                seen: seen.clone(),
+            }),
+        ];
+        let (tx, rx) = channel();
+        let events = Events { tx };
+        events.emit(GatewayEvent::DuplicateDropped {
+            uid: "1".to_string(),
+        });
+        events.emit(GatewayEvent::QueueDepthChanged { depth: 1 });
+        events.emit(GatewayEvent::DuplicateDropped {
+            uid: "2".to_string(),
+        });
+        drop(events);
+        // Returns once the last handle is gone
+        dispatch(rx, subscribers);
+        assert_eq!(*seen.lock().unwrap(), ["a 1", "b 1", "a 2", "b 2"]);
+    }
+
+    #[test]
+    fn emitting_without_an_event_thread_is_fine() {
+        let (tx, rx) = channel();
+        drop(rx);
+        Events { tx }.emit(GatewayEvent::ShuttingDown);
+    }
+
+    #[test]
+    fn upload_attempts_go_to_the_audit_log() {
+        let mut subscriber = AuditSubscriber::new(AuditLog::new(10));
+        subscriber.handle(&failed("a", 1));
+        subscriber.handle(&succeeded("a", 2));
+        subscriber.handle(&succeeded("b", 1));
+        let statuses: Vec<Option<u16>> = subscriber.audit.query("a").map(|e| e.status).collect();
+        assert_eq!(statuses, [None, Some(200)]);
+    }
+
+    #[test]
+    fn shedding_load_empties_and_pauses_the_audit_log() {
+        let mut subscriber = AuditSubscriber::new(AuditLog::new(10));
+        subscriber.handle(&succeeded("a", 1));
+        subscriber.handle(&GatewayEvent::LoadShedding { active: true });
+        subscriber.handle(&succeeded("a", 2));
+        assert_eq!(subscriber.audit.query("a").count(), 0);
+        subscriber.handle(&GatewayEvent::LoadShedding { active: false });
+        subscriber.handle(&succeeded("a", 3));
+        assert_eq!(subscriber.audit.query("a").count(), 1);
+    }
+
+    #[test]
+    fn test_fixes_hear_about_their_first_upload_only() {
+        let mut subscriber = TestFixSubscriber::default();
+        let (reply, outcome) = channel();
+        subscriber.handle(&GatewayEvent::TestFixInjected {
+            uid: "test".to_string(),
+            reply,
+        });
+        subscriber.handle(&succeeded("other", 1));
+        subscriber.handle(&failed("test", 1));
+        subscriber.handle(&succeeded("test", 2));
+        assert_eq!(outcome.try_recv(), Ok(Err("timed out".to_string())));
+        // The sender was dropped after the first outcome
+        assert!(outcome.try_recv().is_err());
+        assert!(subscriber.pending.is_empty());
+    }
+
+    #[test]
+    fn expired_test_fixes_say_how_long_they_waited() {
+        let mut subscriber = TestFixSubscriber::default();
+        let (reply, outcome) = channel();
+        subscriber.handle(&GatewayEvent::TestFixInjected {
+            uid: "test".to_string(),
+            reply,
+        });
+        subscriber.handle(&GatewayEvent::StaleDropped {
+            uid: "test".to_string(),
+            src: TEST_SOURCE.to_string(),
+            age: Duration::from_secs(90),
+        });
+        let outcome = outcome.try_recv().unwrap().unwrap_err();
+        assert!(outcome.starts_with("dropped as stale"), "{outcome}");
+    }
+
+    #[test]
+    fn drift_is_checked_against_the_desired_versions() {
+        let mut subscriber = DriftSubscriber::default();
+        subscriber.handle(&fix("tracker", 7));
+        assert_eq!(subscriber.drift["tracker"], Drift::Unknown);
+
+        let versions = HashMap::from([("tracker".to_string(), 8)]);
+        subscriber.handle(&GatewayEvent::DesiredConfigs { versions });
+        assert_eq!(
+            subscriber.drift["tracker"],
+            Drift::Drifted {
+                reported: 7,
+                desired: 8
+            }
+        );
+
+        subscriber.handle(&fix("tracker", 8));
+        assert_eq!(subscriber.drift["tracker"], Drift::InSync);
+    }
+
+    #[test]
+    fn fixes_without_a_version_dont_change_the_drift() {
+        let mut subscriber = DriftSubscriber::default();
+        subscriber.handle(&GatewayEvent::DesiredConfigs {
+            versions: HashMap::from([("tracker".to_string(), 8)]),
+        });
+        subscriber.handle(&fix("tracker", 8));
+        subscriber.handle(&fix("tracker", 0));
+        assert_eq!(subscriber.drift["tracker"], Drift::InSync);
+    }
+}
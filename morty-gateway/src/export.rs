@@ -0,0 +1,137 @@
+//! The last fixes as CSV, for a quick look in a spreadsheet. Rows are written one at a time, so
+//! exporting doesn't need a buffer for the whole file.
+
+use crate::last_fix::LastFix;
+use crate::mapping::battery_percent;
+use crate::mapping::iso8601;
+use std::io::Write;
+
+/// The columns, in the order they're written. Add new ones at the end, so existing sheets keep
+/// working.
+pub const COLUMNS: &[&str] = &[
+    "src",
+    "iso_time",
+    "lat",
+    "lon",
+    "hdop",
+    "battery_voltage",
+    "battery_percent",
+    "charging",
+    "from_persistence",
+];
+
+/// Write `fixes` to `out` as CSV with a header row. With `src`, only the fixes of that source are
+/// written.
+pub fn write_csv<'a>(
+    out: &mut impl Write,
+    fixes: impl IntoIterator<Item = &'a LastFix>,
+    src: Option<&str>,
+) -> std::io::Result<()> {
+    write_row(out, COLUMNS.iter().map(|c| c.to_string()))?;
+    for fix in fixes {
+        if src.map_or(false, |src| !fix.src.eq_ignore_ascii_case(src)) {
+            continue;
+        }
+        let gps = &fix.gps;
+        write_row(
+            out,
+            [
+                fix.src.clone(),
+                iso8601(fix.timestamp),
+                format!("{:.6}", gps.latitude),
+                format!("{:.6}", gps.longitude),
+                format!("{:.1}", gps.hdop),
+                format!("{:.2}", gps.battery_voltage),
+                battery_percent(gps).to_string(),
+                gps.charging.to_string(),
+                fix.from_persistence.to_string(),
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+fn write_row(
+    out: &mut impl Write,
+    fields: impl IntoIterator<Item = String>,
+) -> std::io::Result<()> {
+    let row: Vec<String> = fields.into_iter().map(|f| escape(&f)).collect();
+    writeln!(out, "{}", row.join(","))
+}
+
+/// Quote a field when it contains a separator, quote or line break, doubling the quotes in it.
+fn escape(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use morty_rs::messages::GpsMsg;
+
+    fn fix(src: &str) -> LastFix {
+        LastFix {
+            src: src.to_string(),
+            // 2023-04-01T12:00:00Z
+            timestamp: 1_680_350_400,
+            gps: GpsMsg {
+                latitude: 52.370216,
+                longitude: 4.895168,
+                hdop: 0.84,
+                battery_voltage: 3.912,
+                battery_percent: 71,
+                charging: true,
+                ..Default::default()
+            },
+            from_persistence: false,
+        }
+    }
+
+    fn csv(fixes: &[LastFix], src: Option<&str>) -> String {
+        let mut out = Vec::new();
+        write_csv(&mut out, fixes, src).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn rows_have_the_columns_in_order() {
+        let mut restored = fix("11:22:33:44:55:66");
+        restored.from_persistence = true;
+        assert_eq!(
+            csv(&[fix("aa:bb:cc:dd:ee:ff"), restored], None),
+            "src,iso_time,lat,lon,hdop,battery_voltage,battery_percent,charging,from_persistence\n\
+             aa:bb:cc:dd:ee:ff,2023-04-01T12:00:00Z,52.370216,4.895168,0.8,3.91,71,true,false\n\
+             11:22:33:44:55:66,2023-04-01T12:00:00Z,52.370216,4.895168,0.8,3.91,71,true,true\n"
+        );
+    }
+
+    #[test]
+    fn without_fixes_there_is_only_the_header() {
+        assert_eq!(csv(&[], None), format!("{}\n", COLUMNS.join(",")));
+    }
+
+    #[test]
+    fn fixes_can_be_filtered_by_source() {
+        let fixes = [fix("aa:bb:cc:dd:ee:ff"), fix("11:22:33:44:55:66")];
+        let csv = csv(&fixes, Some("AA:BB:CC:DD:EE:FF"));
+        assert_eq!(csv.lines().count(), 2);
+        assert!(csv
+            .lines()
+            .nth(1)
+            .unwrap()
+            .starts_with("aa:bb:cc:dd:ee:ff,"));
+    }
+
+    #[test]
+    fn fields_are_quoted_when_needed() {
+        assert_eq!(escape("plain"), "plain");
+        assert_eq!(escape("a,b"), "\"a,b\"");
+        assert_eq!(escape("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(escape("two\nlines"), "\"two\nlines\"");
+        assert_eq!(escape("cr\r"), "\"cr\r\"");
+    }
+}
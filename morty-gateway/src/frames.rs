@@ -0,0 +1,360 @@
+//! Lines from the beacon hold frames of base64 encoded protobuf after the UART header, separated
+//! by `UART_FRAME_DELIMITER`. The pipeline and `replay` both decode them here, each counting
+//! them in their own `FrameCounts`, so a replay doesn't show up in the live stats. Lines are read
+//! with `read_line_bounded`, from the UART as well as from the beacons bridged over TCP, see
+//! `bridge`.
+
+use crate::capture::Cause;
+use crate::capture::FailedFrame;
+use crate::FRAME_BUFFER_LEN;
+use anyhow::anyhow;
+use base64::engine::general_purpose;
+use base64::Engine;
+use log::*;
+use morty_rs::comm::decode_msg;
+use morty_rs::comm::DecodeFailures;
+use morty_rs::comm::UART_FRAME_DELIMITER;
+use morty_rs::comm::UART_HEADER;
+use morty_rs::messages::morty_message::Msg;
+use std::fmt;
+use std::io::BufRead;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+/// Longest line we take from a beacon, which writes lines of about 512 bytes. Longer lines are
+/// dropped.
+pub const MAX_LINE_LEN: usize = 1024;
+
+/// Where a line came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Via {
+    /// The beacon on the UART
+    Uart,
+    /// Heard over ESP-NOW in combo mode
+    EspNow,
+    /// Injected on the console
+    Console,
+    /// A beacon bridged over TCP, by the address of its connection
+    Bridge(SocketAddr),
+}
+
+impl fmt::Display for Via {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Via::Uart => write!(f, "uart"),
+            Via::EspNow => write!(f, "esp-now"),
+            Via::Console => write!(f, "console"),
+            Via::Bridge(peer) => write!(f, "bridge {peer}"),
+        }
+    }
+}
+
+/// Frames that decoded and that didn't.
+pub struct FrameCounts {
+    pub decoded: AtomicU32,
+    /// Frames that weren't valid base64 or protobuf
+    pub failed: AtomicU32,
+    /// Frames too long for the frame buffer, these are dropped without decoding
+    pub oversized: AtomicU32,
+    /// Longest frame we decoded, to see how close we get to the size of the buffer
+    pub peak_len: AtomicUsize,
+    /// The protobuf failures, by cause
+    pub decode_failures: DecodeFailures,
+}
+
+impl FrameCounts {
+    pub const fn new() -> Self {
+        Self {
+            decoded: AtomicU32::new(0),
+            failed: AtomicU32::new(0),
+            oversized: AtomicU32::new(0),
+            peak_len: AtomicUsize::new(0),
+            decode_failures: DecodeFailures::new(),
+        }
+    }
+}
+
+impl Default for FrameCounts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Decode the frames of `line` and hand them to `on_frame` in order, with the frames that didn't
+/// decode as a FailedFrame and why. Every frame is handled on its own, so a broken frame doesn't
+/// take the rest of the line with it. Returns false when the line doesn't start with the UART
+/// header or has nothing after it, e.g. a blank line or a write cut short by a reset.
+pub fn decode_line(
+    line: &str,
+    received_at: Duration,
+    buffer: &mut [u8; FRAME_BUFFER_LEN],
+    counts: &FrameCounts,
+    mut on_frame: impl FnMut(Result<Option<Msg>, (FailedFrame, anyhow::Error)>),
+) -> bool {
+    let Some(frames) = line.strip_prefix(UART_HEADER).map(str::trim) else {
+        return false;
+    };
+    if frames.is_empty() {
+        return false;
+    }
+    for frame in frames.split(UART_FRAME_DELIMITER) {
+        // The base64 decoder wants room for the padding as well
+        if (frame.len() + 3) / 4 * 3 > FRAME_BUFFER_LEN {
+            counts.oversized.fetch_add(1, Ordering::Relaxed);
+            error!(
+                "Dropping frame of {} bytes, more than fits in {FRAME_BUFFER_LEN} bytes",
+                frame.len()
+            );
+            continue;
+        }
+        let len = match general_purpose::STANDARD.decode_slice(frame, buffer) {
+            Ok(len) => len,
+            Err(e) => {
+                counts.failed.fetch_add(1, Ordering::Relaxed);
+                on_frame(Err((
+                    FailedFrame::new(received_at, Cause::Base64, frame.as_bytes()),
+                    anyhow!("Unable to decode {frame}: {e}"),
+                )));
+                continue;
+            }
+        };
+        counts.peak_len.fetch_max(len, Ordering::Relaxed);
+
+        match decode_msg(&buffer[..len]) {
+            Ok(msg) => {
+                counts.decoded.fetch_add(1, Ordering::Relaxed);
+                on_frame(Ok(msg));
+            }
+            Err(e) => {
+                counts.failed.fetch_add(1, Ordering::Relaxed);
+                let cause = counts
+                    .decode_failures
+                    .record(&e)
+                    .map_or(Cause::Unknown, Cause::Decode);
+                on_frame(Err((
+                    FailedFrame::new(received_at, cause, &buffer[..len]),
+                    e.into(),
+                )));
+            }
+        }
+    }
+    true
+}
+
+/// Read a line of at most MAX_LINE_LEN bytes into `buffer`. Returns false when the line is
+/// longer, after skipping the rest of it.
+pub fn read_line_bounded(reader: &mut impl BufRead, buffer: &mut String) -> std::io::Result<bool> {
+    buffer.clear();
+    let len = Read::take(&mut *reader, MAX_LINE_LEN as u64).read_line(buffer)?;
+    if len < MAX_LINE_LEN || buffer.ends_with('\n') {
+        return Ok(true);
+    }
+    loop {
+        let available = reader.fill_buf()?;
+        match available.iter().position(|&b| b == b'\n') {
+            Some(i) => {
+                reader.consume(i + 1);
+                return Ok(false);
+            }
+            None if available.is_empty() => return Ok(false),
+            None => {
+                let len = available.len();
+                reader.consume(len);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::Cause;
+    use morty_rs::comm::encode_msg;
+    use morty_rs::comm::DecodeFailure;
+    use morty_rs::messages::PingMsg;
+    use std::io::BufReader;
+
+    fn ping(nonce: u32) -> Vec<u8> {
+        let ping = PingMsg {
+            nonce,
+            ..Default::default()
+        };
+        encode_msg(&Msg::Ping(ping)).unwrap()
+    }
+
+    fn base64(data: &[u8]) -> String {
+        general_purpose::STANDARD.encode(data)
+    }
+
+    // The nonces of the pings of `line`, and the causes of the frames that didn't decode. None
+    // when it isn't a line of frames.
+    fn decode(line: &str, counts: &FrameCounts) -> Option<Vec<Result<u32, Cause>>> {
+        let mut buffer = [0; FRAME_BUFFER_LEN];
+        let mut frames = Vec::new();
+        let decoded = decode_line(line, Duration::ZERO, &mut buffer, counts, |frame| {
+            frames.push(match frame {
+                Ok(Some(Msg::Ping(ping))) => Ok(ping.nonce),
+                Ok(msg) => panic!("Decoded {msg:?}"),
+                Err((failed, _)) => Err(failed.cause),
+            })
+        });
+        decoded.then_some(frames)
+    }
+
+    #[test]
+    fn broken_frames_dont_take_the_line_with_them() {
+        let mut corrupted = ping(3);
+        *corrupted.last_mut().unwrap() ^= 0xff;
+        let line = format!(
+            "{UART_HEADER}{},not base64!,{},{}\n",
+            base64(&ping(1)),
+            base64(&corrupted),
+            base64(&ping(2))
+        );
+        let counts = FrameCounts::new();
+        assert_eq!(
+            decode(&line, &counts),
+            Some(vec![
+                Ok(1),
+                Err(Cause::Base64),
+                Err(Cause::Decode(DecodeFailure::CrcMismatch)),
+                Ok(2)
+            ])
+        );
+        assert_eq!(counts.decoded.load(Ordering::Relaxed), 2);
+        assert_eq!(counts.failed.load(Ordering::Relaxed), 2);
+        assert_eq!(counts.decode_failures.get(DecodeFailure::CrcMismatch), 1);
+        assert_eq!(counts.peak_len.load(Ordering::Relaxed), ping(1).len());
+    }
+
+    #[test]
+    fn frames_too_long_for_the_buffer_are_skipped() {
+        let line = format!(
+            "{UART_HEADER}{},{}",
+            "A".repeat(FRAME_BUFFER_LEN * 2),
+            base64(&ping(1))
+        );
+        let counts = FrameCounts::new();
+        assert_eq!(decode(&line, &counts), Some(vec![Ok(1)]));
+        assert_eq!(counts.oversized.load(Ordering::Relaxed), 1);
+        assert_eq!(counts.failed.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn lines_without_frames_are_invalid() {
+        let counts = FrameCounts::new();
+        for line in [
+            "",
+            "\n",
+            UART_HEADER,
+            "MORTYGPS \r\n",
+            "AQID,AQID",
+            "mortygpsAQID",
+        ] {
+            assert_eq!(decode(line, &counts), None, "{line:?}");
+        }
+        assert_eq!(counts.decoded.load(Ordering::Relaxed), 0);
+        assert_eq!(counts.failed.load(Ordering::Relaxed), 0);
+    }
+
+    // Hands out at most `chunk` bytes per read, like a UART
+    struct Trickle<'a> {
+        data: &'a [u8],
+        chunk: usize,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let len = self.chunk.min(buf.len()).min(self.data.len());
+            buf[..len].copy_from_slice(&self.data[..len]);
+            self.data = &self.data[len..];
+            Ok(len)
+        }
+    }
+
+    // The lines `read_line_bounded` reads from `data` and whether they were complete
+    fn read_lines(data: &str, chunk: usize) -> Vec<(String, bool)> {
+        let trickle = Trickle {
+            data: data.as_bytes(),
+            chunk,
+        };
+        let mut reader = BufReader::with_capacity(16, trickle);
+        let mut buffer = String::new();
+        let mut lines = Vec::new();
+        loop {
+            let complete = read_line_bounded(&mut reader, &mut buffer).unwrap();
+            if complete && buffer.is_empty() {
+                return lines;
+            }
+            lines.push((buffer.clone(), complete));
+        }
+    }
+
+    #[test]
+    fn lines_come_whole_from_split_reads() {
+        let data = "MORTYGPSAQID,AQID\nMORTYGPSAQID\n\nMORTYGPSAQ";
+        for chunk in [1, 3, 7, 64] {
+            assert_eq!(
+                read_lines(data, chunk),
+                [
+                    ("MORTYGPSAQID,AQID\n".to_string(), true),
+                    ("MORTYGPSAQID\n".to_string(), true),
+                    ("\n".to_string(), true),
+                    // Cut short by the end of the data
+                    ("MORTYGPSAQ".to_string(), true),
+                ],
+                "chunks of {chunk}"
+            );
+        }
+    }
+
+    #[test]
+    fn long_lines_are_skipped() {
+        let longest = format!("{}\n", "x".repeat(MAX_LINE_LEN - 1));
+        let too_long = format!("{}\n", "x".repeat(MAX_LINE_LEN));
+        let data = format!(
+            "{longest}{too_long}MORTYGPSAQID\n{}",
+            "y".repeat(3 * MAX_LINE_LEN)
+        );
+        let lines = read_lines(&data, 5);
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], (longest, true));
+        assert!(!lines[1].1);
+        assert_eq!(lines[2], ("MORTYGPSAQID\n".to_string(), true));
+        // Too long even without a newline
+        assert!(!lines[3].1);
+    }
+
+    #[test]
+    fn lines_read_from_a_stream_decode_in_order() {
+        let data = format!(
+            "{UART_HEADER}{},{}\r\n{}\n{UART_HEADER}{}\n",
+            base64(&ping(1)),
+            base64(&ping(2)),
+            "x".repeat(MAX_LINE_LEN + 1),
+            base64(&ping(3))
+        );
+        let counts = FrameCounts::new();
+        let mut nonces = Vec::new();
+        for (line, complete) in read_lines(&data, 5) {
+            if complete {
+                nonces.extend(decode(&line, &counts).unwrap());
+            }
+        }
+        assert_eq!(nonces, [Ok(1), Ok(2), Ok(3)]);
+        assert_eq!(counts.decoded.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn where_a_line_came_from_shows() {
+        let peer: SocketAddr = "192.168.1.20:40123".parse().unwrap();
+        assert_eq!(Via::Uart.to_string(), "uart");
+        assert_eq!(Via::EspNow.to_string(), "esp-now");
+        assert_eq!(Via::Console.to_string(), "console");
+        assert_eq!(Via::Bridge(peer).to_string(), "bridge 192.168.1.20:40123");
+    }
+}
@@ -0,0 +1,407 @@
+//! Gzip for request bodies. Uploads above a threshold are compressed in the thread that sends
+//! them, and sent with `Content-Encoding: gzip`, see `ApiClient::with_gzip`.
+//!
+//! The compressor of miniz_oxide keeps about 300 KB of state, more than the heap has to spare, so
+//! this is a small deflate of our own: LZ77 matches through a hash table of HASH_SIZE entries, in
+//! a single block with the fixed Huffman codes. That gets most of the gain on JSON with its
+//! repeated keys. The output goes into a buffer no larger than the input; when it doesn't fit,
+//! compressing isn't worth it and the body is sent as it is.
+
+use morty_rs::utils::format_bytes;
+use std::fmt;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
+
+// Entries in the hash table of 3-byte sequences, 2 KB of scratch on the stack
+const HASH_BITS: u32 = 9;
+const HASH_SIZE: usize = 1 << HASH_BITS;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const MAX_DISTANCE: usize = 32 * 1024;
+// Magic, deflate, no flags, no modification time, no extra flags, unknown OS
+const HEADER: [u8; 10] = [0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// Bytes that went through compression, for the stats.
+pub struct CompressionCounts {
+    /// Bodies sent compressed, with their size before and after
+    pub compressed: AtomicU32,
+    pub bytes_in: AtomicU32,
+    pub bytes_out: AtomicU32,
+    /// Bodies over the threshold that didn't get smaller, sent as they are
+    pub incompressible: AtomicU32,
+}
+
+impl CompressionCounts {
+    pub const fn new() -> Self {
+        Self {
+            compressed: AtomicU32::new(0),
+            bytes_in: AtomicU32::new(0),
+            bytes_out: AtomicU32::new(0),
+            incompressible: AtomicU32::new(0),
+        }
+    }
+}
+
+impl fmt::Display for CompressionCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "compressed={} bytes_in={} bytes_out={} incompressible={}",
+            self.compressed.load(Ordering::Relaxed),
+            format_bytes(self.bytes_in.load(Ordering::Relaxed).into()),
+            format_bytes(self.bytes_out.load(Ordering::Relaxed).into()),
+            self.incompressible.load(Ordering::Relaxed)
+        )
+    }
+}
+
+/// Whether a body of `len` bytes should be compressed. A `threshold` of 0 turns compression off,
+/// and so does a server that refused a compressed body.
+pub fn should_compress(len: usize, threshold: usize, refused: bool) -> bool {
+    threshold > 0 && len >= threshold && !refused
+}
+
+/// `data` as gzip, None when that isn't smaller.
+pub fn compress(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = BitWriter::new(data.len());
+    out.bytes(&HEADER)?;
+    // A single final block with the fixed codes
+    out.bits(1, 1)?;
+    out.bits(1, 2)?;
+    let mut table = [0_u32; HASH_SIZE];
+    let mut i = 0;
+    while i < data.len() {
+        let length = match find_match(data, i, &mut table) {
+            Some((length, distance)) => {
+                out.length(length)?;
+                out.distance(distance)?;
+                length
+            }
+            None => {
+                out.symbol(data[i] as u16)?;
+                1
+            }
+        };
+        // The positions we skip over are in the table as well, for the matches after them
+        for j in i + 1..i + length {
+            insert(data, j, &mut table);
+        }
+        i += length;
+    }
+    out.symbol(256)?;
+    out.align()?;
+    out.bytes(&crc32(data).to_le_bytes())?;
+    out.bytes(&(data.len() as u32).to_le_bytes())?;
+    Some(out.into_inner())
+}
+
+/// CRC-32 as gzip has it.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0_u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+fn hash(data: &[u8], i: usize) -> usize {
+    let key = u32::from_le_bytes([data[i], data[i + 1], data[i + 2], 0]);
+    (key.wrapping_mul(0x9e37_79b1) >> (32 - HASH_BITS)) as usize
+}
+
+// Remember position `i`, stored plus one so 0 is empty
+fn insert(data: &[u8], i: usize, table: &mut [u32; HASH_SIZE]) -> Option<usize> {
+    if i + MIN_MATCH > data.len() {
+        return None;
+    }
+    let h = hash(data, i);
+    let previous = table[h].checked_sub(1).map(|p| p as usize);
+    table[h] = i as u32 + 1;
+    previous
+}
+
+// The longest match at `i` with the last position that had the same hash, as length and distance
+fn find_match(data: &[u8], i: usize, table: &mut [u32; HASH_SIZE]) -> Option<(usize, usize)> {
+    let candidate = insert(data, i, table)?;
+    let distance = i - candidate;
+    if distance > MAX_DISTANCE {
+        return None;
+    }
+    let max = MAX_MATCH.min(data.len() - i);
+    let length = (0..max)
+        .take_while(|&k| data[candidate + k] == data[i + k])
+        .count();
+    (length >= MIN_MATCH).then_some((length, distance))
+}
+
+/// Writes a deflate stream into a buffer of a fixed size.
+struct BitWriter {
+    out: Vec<u8>,
+    limit: usize,
+    bits: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    fn new(limit: usize) -> Self {
+        Self {
+            out: Vec::with_capacity(limit),
+            limit,
+            bits: 0,
+            count: 0,
+        }
+    }
+
+    fn push(&mut self, byte: u8) -> Option<()> {
+        if self.out.len() >= self.limit {
+            return None;
+        }
+        self.out.push(byte);
+        Some(())
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) -> Option<()> {
+        bytes.iter().try_for_each(|&b| self.push(b))
+    }
+
+    /// The `count` low bits of `value`, least significant first.
+    fn bits(&mut self, value: u32, count: u32) -> Option<()> {
+        self.bits |= value << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.push(self.bits as u8)?;
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+        Some(())
+    }
+
+    /// A Huffman code, which goes most significant bit first.
+    fn code(&mut self, code: u32, count: u32) -> Option<()> {
+        self.bits(code.reverse_bits() >> (32 - count), count)
+    }
+
+    /// A literal or length symbol in the fixed code.
+    fn symbol(&mut self, symbol: u16) -> Option<()> {
+        let symbol = symbol as u32;
+        match symbol {
+            0..=143 => self.code(0x30 + symbol, 8),
+            144..=255 => self.code(0x190 + symbol - 144, 9),
+            256..=279 => self.code(symbol - 256, 7),
+            _ => self.code(0xc0 + symbol - 280, 8),
+        }
+    }
+
+    fn length(&mut self, length: usize) -> Option<()> {
+        let code = LENGTH_BASE
+            .iter()
+            .rposition(|&base| base as usize <= length)?;
+        self.symbol(257 + code as u16)?;
+        let extra = (length - LENGTH_BASE[code] as usize) as u32;
+        self.bits(extra, LENGTH_EXTRA[code] as u32)
+    }
+
+    fn distance(&mut self, distance: usize) -> Option<()> {
+        let code = DISTANCE_BASE
+            .iter()
+            .rposition(|&base| base as usize <= distance)?;
+        self.code(code as u32, 5)?;
+        let extra = (distance - DISTANCE_BASE[code] as usize) as u32;
+        self.bits(extra, DISTANCE_EXTRA[code] as u32)
+    }
+
+    /// Pad to a whole byte.
+    fn align(&mut self) -> Option<()> {
+        if self.count > 0 {
+            self.bits(0, 8 - self.count)?;
+        }
+        Some(())
+    }
+
+    fn into_inner(self) -> Vec<u8> {
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Reads a deflate stream, least significant bit first
+    struct BitReader<'a> {
+        data: &'a [u8],
+        bit: usize,
+    }
+
+    impl BitReader<'_> {
+        fn bit(&mut self) -> u32 {
+            let bit = (self.data[self.bit / 8] >> (self.bit % 8)) & 1;
+            self.bit += 1;
+            bit as u32
+        }
+
+        fn bits(&mut self, count: u32) -> u32 {
+            (0..count).fold(0, |value, i| value | self.bit() << i)
+        }
+
+        // A Huffman code of `count` bits, most significant bit first
+        fn code(&mut self, count: u32, mut value: u32) -> u32 {
+            for _ in 0..count {
+                value = value << 1 | self.bit();
+            }
+            value
+        }
+
+        // A literal or length symbol in the fixed code
+        fn symbol(&mut self) -> u32 {
+            let code = self.code(7, 0);
+            if code <= 0x17 {
+                return code + 256;
+            }
+            let code = self.code(1, code);
+            match code {
+                0x30..=0xbf => code - 0x30,
+                0xc0..=0xc7 => code - 0xc0 + 280,
+                _ => self.code(1, code) - 0x190 + 144,
+            }
+        }
+    }
+
+    // The data in a gzip stream of a single block with the fixed codes, as `compress` writes it,
+    // checked against its CRC and length
+    fn decompress(gzip: &[u8]) -> Vec<u8> {
+        assert_eq!(gzip[..10], HEADER);
+        let mut reader = BitReader {
+            data: &gzip[10..],
+            bit: 0,
+        };
+        assert_eq!(reader.bits(1), 1, "final block");
+        assert_eq!(reader.bits(2), 1, "fixed codes");
+        let mut out: Vec<u8> = Vec::new();
+        loop {
+            let symbol = reader.symbol();
+            match symbol {
+                0..=255 => out.push(symbol as u8),
+                256 => break,
+                _ => {
+                    let code = (symbol - 257) as usize;
+                    let length = LENGTH_BASE[code] as usize
+                        + reader.bits(LENGTH_EXTRA[code] as u32) as usize;
+                    let code = reader.code(5, 0) as usize;
+                    let distance = DISTANCE_BASE[code] as usize
+                        + reader.bits(DISTANCE_EXTRA[code] as u32) as usize;
+                    for _ in 0..length {
+                        out.push(out[out.len() - distance]);
+                    }
+                }
+            }
+        }
+        let trailer = &reader.data[(reader.bit + 7) / 8..];
+        assert_eq!(trailer.len(), 8);
+        assert_eq!(trailer[..4], crc32(&out).to_le_bytes());
+        assert_eq!(trailer[4..], (out.len() as u32).to_le_bytes());
+        out
+    }
+
+    fn json(fixes: usize) -> Vec<u8> {
+        let fixes: Vec<String> = (0..fixes)
+            .map(|i| format!(r#"{{"uid":"{i:08x}","lat":52.{i:04},"lon":4.{i:04},"sats":9}}"#))
+            .collect();
+        format!("[{}]", fixes.join(",")).into_bytes()
+    }
+
+    // Bytes that don't repeat, from a xorshift
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_u32;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn compression_starts_at_the_threshold() {
+        assert!(!should_compress(1023, 1024, false));
+        assert!(should_compress(1024, 1024, false));
+        assert!(should_compress(50_000, 1024, false));
+        // Off
+        assert!(!should_compress(50_000, 0, false));
+        assert!(!should_compress(0, 0, false));
+        // Refused by the server
+        assert!(!should_compress(50_000, 1024, true));
+    }
+
+    #[test]
+    fn crc32_matches_the_check_value() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn json_round_trips_smaller() {
+        let data = json(50);
+        let compressed = compress(&data).unwrap();
+        assert!(compressed.len() < data.len() / 2, "{}", compressed.len());
+        assert_eq!(decompress(&compressed), data);
+    }
+
+    #[test]
+    fn long_repeats_round_trip() {
+        let data = b"abc".repeat(40_000);
+        let compressed = compress(&data).unwrap();
+        assert!(compressed.len() < 1000, "{}", compressed.len());
+        assert_eq!(decompress(&compressed), data);
+
+        // Matches further back than the window
+        let mut data = json(800);
+        assert!(data.len() > MAX_DISTANCE + 1000);
+        data.extend_from_within(..1000);
+        let compressed = compress(&data).unwrap();
+        assert_eq!(decompress(&compressed), data);
+    }
+
+    #[test]
+    fn every_byte_round_trips() {
+        let data: Vec<u8> = (0..=255).cycle().take(2048).collect();
+        assert_eq!(decompress(&compress(&data).unwrap()), data);
+    }
+
+    #[test]
+    fn bodies_that_dont_get_smaller_are_left_alone() {
+        assert_eq!(compress(b""), None);
+        assert_eq!(compress(b"{}"), None);
+        assert_eq!(compress(&noise(4096)), None);
+    }
+
+    #[test]
+    fn counts_show_in_the_stats() {
+        let counts = CompressionCounts::new();
+        counts.compressed.fetch_add(2, Ordering::Relaxed);
+        counts.incompressible.fetch_add(1, Ordering::Relaxed);
+        assert!(counts.to_string().starts_with("compressed=2 bytes_in="));
+        assert!(counts.to_string().ends_with(" incompressible=1"));
+    }
+}
@@ -0,0 +1,203 @@
+//! Lines handed from the threads that read them, the UART first of all, to the thread that handles
+//! them, see `uart_task`. Handling a line can take a while, e.g. when an upload is due, and the
+//! UART has no flow control, so a reader must never wait for it. The queue holds at most
+//! `capacity` lines and a full queue drops its oldest line for a new one, which is the one least
+//! likely to still matter. Those are counted in the counter passed to `channel`.
+//!
+//! Like an mpsc channel, sending fails once the receiver is gone and the receiver runs out once
+//! every sender is gone. The receiver can wait with a timeout, to get to other work between lines.
+
+use crate::frames::Via;
+use std::collections::VecDeque;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::mpsc::SendError;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+/// A line with the monotonic time it came in and where it came from.
+pub type Line = (Duration, Via, String);
+
+struct Queue {
+    lines: VecDeque<Line>,
+    senders: usize,
+    receiving: bool,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    ready: Condvar,
+    capacity: usize,
+    dropped: &'static AtomicU32,
+}
+
+/// Hands lines to the `LineReceiver`, see the module docs.
+pub struct LineSender {
+    shared: Arc<Shared>,
+}
+
+/// Takes the lines the `LineSender`s handed over, oldest first.
+pub struct LineReceiver {
+    shared: Arc<Shared>,
+}
+
+/// A queue of at most `capacity` lines, which counts the lines it dropped in `dropped`.
+pub fn channel(capacity: usize, dropped: &'static AtomicU32) -> (LineSender, LineReceiver) {
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(Queue {
+            lines: VecDeque::with_capacity(capacity),
+            senders: 1,
+            receiving: true,
+        }),
+        ready: Condvar::new(),
+        capacity,
+        dropped,
+    });
+    (
+        LineSender {
+            shared: shared.clone(),
+        },
+        LineReceiver { shared },
+    )
+}
+
+impl LineSender {
+    /// Queue `line`, dropping the oldest one when the queue is full. Fails when the receiver is
+    /// gone. This never waits for the receiver.
+    pub fn send(&self, line: Line) -> Result<(), SendError<Line>> {
+        let mut queue = self.shared.queue.lock().unwrap();
+        if !queue.receiving {
+            return Err(SendError(line));
+        }
+        if queue.lines.len() >= self.shared.capacity {
+            queue.lines.pop_front();
+            self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        queue.lines.push_back(line);
+        self.shared.ready.notify_one();
+        Ok(())
+    }
+}
+
+impl Clone for LineSender {
+    fn clone(&self) -> Self {
+        self.shared.queue.lock().unwrap().senders += 1;
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for LineSender {
+    fn drop(&mut self) {
+        // Under the lock, so the receiver can't miss it between checking and waiting
+        let mut queue = self.shared.queue.lock().unwrap();
+        queue.senders -= 1;
+        if queue.senders == 0 {
+            self.shared.ready.notify_all();
+        }
+    }
+}
+
+impl LineReceiver {
+    /// The oldest line, waiting at most `timeout` for one when there's none. Disconnected once
+    /// every sender is gone and the lines are taken.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<Line, RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+        let mut queue = self.shared.queue.lock().unwrap();
+        loop {
+            if let Some(line) = queue.lines.pop_front() {
+                return Ok(line);
+            }
+            if queue.senders == 0 {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            queue = self.shared.ready.wait_timeout(queue, left).unwrap().0;
+        }
+    }
+}
+
+impl Iterator for LineReceiver {
+    type Item = Line;
+
+    /// The oldest line, waiting for one when there's none. None once every sender is gone.
+    fn next(&mut self) -> Option<Line> {
+        let mut queue = self.shared.queue.lock().unwrap();
+        loop {
+            if let Some(line) = queue.lines.pop_front() {
+                return Some(line);
+            }
+            if queue.senders == 0 {
+                return None;
+            }
+            queue = self.shared.ready.wait(queue).unwrap();
+        }
+    }
+}
+
+impl Drop for LineReceiver {
+    fn drop(&mut self) {
+        self.shared.queue.lock().unwrap().receiving = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static DROPPED: AtomicU32 = AtomicU32::new(0);
+
+    fn line(text: &str) -> Line {
+        (Duration::ZERO, Via::Uart, text.to_string())
+    }
+
+    #[test]
+    fn waiting_for_a_line_times_out() {
+        let (lines, mut received) = channel(4, &DROPPED);
+        let started = Instant::now();
+        assert_eq!(
+            received.recv_timeout(Duration::from_millis(20)),
+            Err(RecvTimeoutError::Timeout)
+        );
+        assert!(started.elapsed() >= Duration::from_millis(20));
+        lines.send(line("a")).unwrap();
+        assert_eq!(
+            received.recv_timeout(Duration::from_millis(20)),
+            Ok(line("a"))
+        );
+    }
+
+    #[test]
+    fn a_line_sent_while_waiting_is_received() {
+        let (lines, mut received) = channel(4, &DROPPED);
+        let sender = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            lines.send(line("a")).unwrap();
+        });
+        assert_eq!(
+            received.recv_timeout(Duration::from_secs(10)),
+            Ok(line("a"))
+        );
+        sender.join().unwrap();
+    }
+
+    #[test]
+    fn lines_are_taken_before_the_senders_are_missed() {
+        let (lines, mut received) = channel(4, &DROPPED);
+        lines.send(line("a")).unwrap();
+        drop(lines);
+        assert_eq!(received.recv_timeout(Duration::ZERO), Ok(line("a")));
+        assert_eq!(
+            received.recv_timeout(Duration::ZERO),
+            Err(RecvTimeoutError::Disconnected)
+        );
+    }
+}
@@ -0,0 +1,187 @@
+//! The last validated fix of every source. These are kept in NVS, so after a reboot we still know
+//! where everything was. Fixes that were restored from NVS are marked as such, so they aren't
+//! mistaken for fresh data.
+//!
+//! The stored blob starts with a format version, followed by a protobuf encoded StoredFixesMsg.
+//! New fields can be added to the protobuf messages without touching the version, it only has to
+//! be bumped for changes that older firmware can't read.
+
+use crate::storage::read_blob;
+use crate::storage::write_blob;
+use esp_idf_sys::EspError;
+use log::*;
+use morty_rs::messages::GpsMsg;
+use morty_rs::messages::StoredFixMsg;
+use morty_rs::messages::StoredFixesMsg;
+use prost::Message;
+use std::fmt;
+use std::time::Duration;
+use std::time::Instant;
+
+const NAMESPACE: &[u8] = b"lastfix\0";
+const KEY: &[u8] = b"fixes\0";
+const FORMAT_VERSION: u8 = 1;
+
+/// The last fix we've seen from a source.
+#[derive(Clone, Debug)]
+pub struct LastFix {
+    pub src: String,
+    // Timestamp of the fix as relayed by the beacon
+    pub timestamp: i64,
+    pub gps: GpsMsg,
+    // Restored from NVS at boot, rather than received since
+    pub from_persistence: bool,
+}
+
+impl fmt::Display for LastFix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} at {}: {:.6}, {:.6} ({})",
+            self.src, self.timestamp, self.gps.latitude, self.gps.longitude, self.gps.uid
+        )?;
+        if self.from_persistence {
+            write!(f, ", from_persistence: true")?;
+        }
+        Ok(())
+    }
+}
+
+/// The last fix of at most `size` sources. When a new source shows up and there is no room, the
+/// source with the stalest fix is dropped. Changes are written to NVS at most once every
+/// `save_interval`, to limit flash wear.
+pub struct LastFixes {
+    fixes: Vec<LastFix>,
+    size: usize,
+    save_interval: Duration,
+    last_save: Option<Instant>,
+    dirty: bool,
+}
+
+impl LastFixes {
+    pub fn new(size: usize, save_interval: Duration) -> Self {
+        Self {
+            fixes: Vec::new(),
+            size,
+            save_interval,
+            last_save: None,
+            dirty: false,
+        }
+    }
+
+    /// Load the fixes we stored before the last reboot.
+    pub fn restore(&mut self) -> Result<(), EspError> {
+        let Some(blob) = read_blob(NAMESPACE, KEY)? else {
+            return Ok(());
+        };
+        match decode(&blob) {
+            Some(fixes) => {
+                for fix in fixes {
+                    self.insert(fix);
+                }
+            }
+            None => warn!("Ignoring stored fixes in an unknown format"),
+        }
+        Ok(())
+    }
+
+    /// Remember `gps` as the last fix of `src`.
+    pub fn update(&mut self, src: &str, timestamp: i64, gps: &GpsMsg) {
+        self.insert(LastFix {
+            src: src.to_string(),
+            timestamp,
+            gps: gps.clone(),
+            from_persistence: false,
+        });
+        self.dirty = true;
+        self.save_if_due();
+    }
+
+    /// Write the fixes to NVS if they changed and we didn't do that recently.
+    pub fn save_if_due(&mut self) {
+        if !self.dirty
+            || self
+                .last_save
+                .map_or(false, |t| t.elapsed() < self.save_interval)
+        {
+            return;
+        }
+        self.save();
+    }
+
+    /// Write the fixes to NVS now, e.g. because we're about to reboot.
+    pub fn save(&mut self) {
+        match write_blob(NAMESPACE, KEY, &encode(&self.fixes)) {
+            Ok(()) => self.dirty = false,
+            Err(e) => error!("Unable to store last fixes: {e}"),
+        }
+        // Also after a failure, so we don't hammer NVS
+        self.last_save = Some(Instant::now());
+    }
+
+    pub fn fixes(&self) -> &[LastFix] {
+        &self.fixes
+    }
+
+    /// Write all fixes to the console.
+    pub fn log(&self) {
+        info!("Last fix of {} sources:", self.fixes.len());
+        for fix in &self.fixes {
+            info!("{fix}");
+        }
+    }
+
+    fn insert(&mut self, fix: LastFix) {
+        match self.fixes.iter_mut().find(|f| f.src == fix.src) {
+            Some(existing) => *existing = fix,
+            None => {
+                self.fixes.push(fix);
+                if self.fixes.len() > self.size {
+                    let stalest = self
+                        .fixes
+                        .iter()
+                        .enumerate()
+                        .min_by_key(|(_, f)| f.timestamp)
+                        .map(|(i, _)| i)
+                        .unwrap();
+                    self.fixes.remove(stalest);
+                }
+            }
+        }
+    }
+}
+
+fn encode(fixes: &[LastFix]) -> Vec<u8> {
+    let msg = StoredFixesMsg {
+        fixes: fixes
+            .iter()
+            .map(|f| StoredFixMsg {
+                src: f.src.clone(),
+                timestamp: f.timestamp,
+                gps: Some(f.gps.clone()),
+            })
+            .collect(),
+    };
+    let mut blob = vec![FORMAT_VERSION];
+    blob.extend_from_slice(&msg.encode_to_vec());
+    blob
+}
+
+fn decode(blob: &[u8]) -> Option<Vec<LastFix>> {
+    let (&version, data) = blob.split_first()?;
+    if version != FORMAT_VERSION {
+        return None;
+    }
+    let msg = StoredFixesMsg::decode(data).ok()?;
+    Some(
+        msg.fixes
+            .into_iter()
+            .map(|f| LastFix {
+                src: f.src,
+                timestamp: f.timestamp,
+                gps: f.gps.unwrap_or_default(),
+                from_persistence: true,
+            })
+            .collect(),
+    )
+}
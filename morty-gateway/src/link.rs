@@ -0,0 +1,578 @@
+//! Checks that a beacon is attached to the UART. A beacon only sends when it has something to
+//! relay, so a quiet UART looks the same whether nothing happens or TX and RX are swapped. We ping
+//! the beacon at boot and every so often after that, and it answers with who it is. When it
+//! doesn't answer, or another beacon does, we raise the alarm.
+//!
+//! The pings also carry the negotiation of the baud rate, see `morty_rs::baud`. While the UART runs
+//! at another rate than the default, we ping every KEEPALIVE_INTERVAL.
+//!
+//! The time beacons for the clocks of the beacons and trackers go out from here as well, see
+//! `morty_rs::timesync`, and the clock the beacon reports in its pongs is passed on as an event.
+//!
+//! The pings tell the beacon who we are, and it stamps that on what it writes to the UART as the
+//! gateway hint of the RelayMsgs. A hint that isn't us means a beacon is wired to the wrong
+//! gateway or was moved, see `check_hint`.
+//!
+//! In maintenance mode, the pings tell the beacon we're paused and this thread moves maintenance
+//! along, see `maintenance`.
+
+use crate::downlink::send_frame;
+use crate::events::Events;
+use crate::events::GatewayEvent;
+use crate::maintenance;
+use crate::maintenance::Phase;
+use crate::maintenance::QUEUE_DEPTH;
+use crate::UART_ERRORS;
+use crate::UART_FRAMES;
+use log::*;
+use morty_rs::baud::set_uart_baud;
+use morty_rs::baud::BaudChange;
+use morty_rs::baud::BaudLink;
+use morty_rs::baud::BaudPolicy;
+use morty_rs::baud::LinkCounts;
+use morty_rs::baud::LinkQuality;
+use morty_rs::baud::DEFAULT_BAUD;
+use morty_rs::baud::KEEPALIVE_INTERVAL;
+use morty_rs::clock::Clock;
+use morty_rs::comm::own_mac;
+use morty_rs::messages::morty_message;
+use morty_rs::messages::PingMsg;
+use morty_rs::messages::PongMsg;
+use morty_rs::timesync::time_beacon;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// How often we look for a ping that wasn't answered in time
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Who the beacon on the UART says it is.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BeaconIdentity {
+    pub device_id: String,
+    pub firmware_version: String,
+    pub config_version: u32,
+}
+
+/// A change of the link with the beacon.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LinkState {
+    /// A beacon answered for the first time, or again after it went quiet.
+    Up(BeaconIdentity),
+    /// The last ping wasn't answered in time.
+    Lost,
+    /// A different beacon answered than before.
+    Changed {
+        previous: BeaconIdentity,
+        current: BeaconIdentity,
+    },
+    /// The same beacon answered, but it was restarted since the last answer.
+    Rebooted(BeaconIdentity),
+}
+
+/// Keeps track of pings and their answers. This doesn't know about the UART, the caller sends the
+/// pings and hands over the pongs.
+pub struct LinkMonitor {
+    timeout: Duration,
+    // Our MAC address, for the beacon to stamp on what it relays
+    gateway_id: String,
+    next_nonce: u32,
+    // Nonce and send time of the ping we're waiting for
+    pending: Option<(u32, Duration)>,
+    beacon: Option<BeaconIdentity>,
+    // Uptime of the beacon in its last answer
+    uptime: Duration,
+    // None until the first ping was answered or timed out
+    up: Option<bool>,
+}
+
+impl LinkMonitor {
+    pub fn new(timeout: Duration, gateway_id: &str) -> Self {
+        Self {
+            timeout,
+            gateway_id: gateway_id.to_string(),
+            next_nonce: 1,
+            pending: None,
+            beacon: None,
+            uptime: Duration::ZERO,
+            up: None,
+        }
+    }
+
+    /// The ping to send at `now`, which says whether we're `paused`. Answers to earlier pings are
+    /// ignored after this.
+    pub fn ping(&mut self, now: Duration, paused: bool) -> PingMsg {
+        let nonce = self.next_nonce;
+        self.next_nonce = self.next_nonce.wrapping_add(1);
+        self.pending = Some((nonce, now));
+        PingMsg {
+            nonce,
+            gateway_id: self.gateway_id.clone(),
+            paused,
+            ..Default::default()
+        }
+    }
+
+    /// Handle an answer to a ping. Returns the change of the link, if any.
+    pub fn pong(&mut self, pong: &PongMsg) -> Option<LinkState> {
+        match self.pending {
+            Some((nonce, _)) if nonce == pong.nonce => self.pending = None,
+            _ => return None,
+        }
+        let current = BeaconIdentity {
+            device_id: pong.device_id.clone(),
+            firmware_version: pong.firmware_version.clone(),
+            config_version: pong.config_version,
+        };
+        let uptime = Duration::from_millis(pong.uptime_ms);
+        let was_up = self.up.replace(true) == Some(true);
+        let previous = self.beacon.replace(current.clone());
+        let rebooted = uptime < self.uptime;
+        self.uptime = uptime;
+
+        match previous {
+            Some(previous) if previous.device_id != current.device_id => {
+                Some(LinkState::Changed { previous, current })
+            }
+            _ if !was_up => Some(LinkState::Up(current)),
+            _ if rebooted => Some(LinkState::Rebooted(current)),
+            _ => None,
+        }
+    }
+
+    /// Who answered the last ping, if anyone did.
+    pub fn beacon(&self) -> Option<&BeaconIdentity> {
+        self.beacon.as_ref()
+    }
+
+    /// Check at `now` whether the last ping was answered in time. Returns `LinkState::Lost` once,
+    /// when it wasn't.
+    pub fn check(&mut self, now: Duration) -> Option<LinkState> {
+        let (_, sent) = self.pending?;
+        if now.saturating_sub(sent) <= self.timeout {
+            return None;
+        }
+        self.pending = None;
+        if self.up.replace(false) == Some(false) {
+            None
+        } else {
+            Some(LinkState::Lost)
+        }
+    }
+}
+
+/// Messages that came in with the gateway hint of another gateway.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HintMismatch {
+    /// The gateway the beacon thinks it's attached to
+    pub hint: String,
+    /// Source of the last of these messages
+    pub src: String,
+    pub count: u32,
+}
+
+// The mismatches since boot, by hint
+static HINT_MISMATCHES: Mutex<Vec<HintMismatch>> = Mutex::new(Vec::new());
+
+/// Check the gateway hint of a message from `src` against our own `gateway_id`. Messages without
+/// a hint are fine, beacons only know who we are after the first ping. Returns the mismatch the
+/// first time we see a hint that isn't us, so it's reported once.
+pub fn check_hint(gateway_id: &str, hint: &str, src: &str) -> Option<HintMismatch> {
+    if hint.is_empty() || hint == gateway_id {
+        return None;
+    }
+    let mut mismatches = HINT_MISMATCHES.lock().unwrap();
+    if let Some(known) = mismatches.iter_mut().find(|m| m.hint == hint) {
+        known.src = src.to_string();
+        known.count += 1;
+        return None;
+    }
+    let mismatch = HintMismatch {
+        hint: hint.to_string(),
+        src: src.to_string(),
+        count: 1,
+    };
+    mismatches.push(mismatch.clone());
+    Some(mismatch)
+}
+
+/// The gateway hints that weren't us since boot.
+pub fn hint_mismatches() -> Vec<HintMismatch> {
+    HINT_MISMATCHES.lock().unwrap().clone()
+}
+
+/// The gateway's end of the baud rate negotiation. Every `check_interval` of the policy, we look
+/// at the frames that went over the UART in both directions and might propose another rate.
+struct BaudNegotiation {
+    link: BaudLink,
+    policy: Option<BaudPolicy>,
+    local: LinkQuality,
+    remote: LinkQuality,
+    // Counters of the beacon, from its last pong
+    remote_totals: LinkCounts,
+    next_check: Duration,
+    // A rate we fell back from, and until when we don't propose it again
+    blocked: Option<(u32, Duration)>,
+}
+
+impl BaudNegotiation {
+    fn new(policy: Option<BaudPolicy>, now: Duration) -> Self {
+        Self {
+            link: BaudLink::new(now),
+            policy,
+            local: LinkQuality::default(),
+            remote: LinkQuality::default(),
+            remote_totals: LinkCounts::default(),
+            next_check: now + policy.map_or(Duration::ZERO, |p| p.check_interval),
+            blocked: None,
+        }
+    }
+
+    /// Add our proposal or countdown to `ping`.
+    fn ping(&self, ping: PingMsg, now: Duration) -> PingMsg {
+        let (switch_baud, switch_in) = self.link.countdown(now).unwrap_or_default();
+        PingMsg {
+            propose_baud: self.link.proposal().unwrap_or(0),
+            switch_baud,
+            switch_in_ms: switch_in.as_millis() as u32,
+            ..ping
+        }
+    }
+
+    /// Handle the negotiation part of `pong`. Returns true when the beacon accepted our proposal,
+    /// the countdown should go out right away then.
+    fn pong(&mut self, pong: &PongMsg, now: Duration) -> bool {
+        self.link.heard(now);
+        self.remote_totals = LinkCounts {
+            frames: pong.uart_frames,
+            failed: pong.uart_frames_failed,
+            framing_errors: pong.uart_framing_errors,
+        };
+        if pong.switch_ack_baud != 0 {
+            self.link.countdown_acked(pong.switch_ack_baud);
+        }
+        if pong.accept_baud != 0 && self.link.proposal() == Some(pong.accept_baud) {
+            self.link.accepted(pong.accept_baud, now);
+            return true;
+        }
+        false
+    }
+
+    /// Switch the UART on `uart_port` when the rate changes, and look at the link when that's
+    /// due. Returns true when we proposed a rate, the ping should go out right away then.
+    fn poll(&mut self, uart_port: i32, now: Duration) -> bool {
+        match self.link.poll(now) {
+            Some(BaudChange::Switched(baud)) => {
+                info!("Switching the UART to {baud} baud");
+                self.apply(uart_port, baud);
+            }
+            Some(BaudChange::FellBack { from }) => {
+                warn!("Beacon went quiet at {from} baud, going back to {DEFAULT_BAUD}");
+                self.apply(uart_port, DEFAULT_BAUD);
+                if let Some(policy) = self.policy {
+                    self.blocked = Some((from, now + policy.hold_off));
+                }
+            }
+            None => {}
+        }
+
+        let Some(policy) = self.policy else {
+            return false;
+        };
+        if now < self.next_check {
+            return false;
+        }
+        self.next_check = now + policy.check_interval;
+        let local = self.local.sample(LinkCounts {
+            frames: UART_FRAMES.decoded.load(Ordering::Relaxed),
+            failed: UART_FRAMES.failed.load(Ordering::Relaxed),
+            framing_errors: UART_ERRORS.framing.load(Ordering::Relaxed),
+        });
+        let remote = self.remote.sample(self.remote_totals);
+        let baud = self.link.baud();
+        info!("UART link at {baud} baud: from beacon {local}, to beacon {remote}");
+        let blocked = self
+            .blocked
+            .filter(|&(_, until)| now < until)
+            .map(|(baud, _)| baud);
+        match policy.next(baud, local + remote, blocked) {
+            Some(next) if self.link.propose(next, now) => {
+                info!("Proposing {next} baud to the beacon");
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn apply(&self, uart_port: i32, baud: u32) {
+        if let Err(e) = set_uart_baud(uart_port, baud) {
+            error!("Unable to switch the UART to {baud} baud: {e}");
+        }
+    }
+}
+
+/// Ping the beacon on the UART on `uart_port` every `interval` and check that it answers within
+/// `timeout`. The answers come in on the UART thread and are handed over through `pongs`. With a
+/// `baud_policy`, we negotiate the baud rate with the beacon as well.
+pub fn link_task(
+    uart_port: i32,
+    pongs: Receiver<PongMsg>,
+    events: Events,
+    interval: Duration,
+    timeout: Duration,
+    time_interval: Duration,
+    baud_policy: Option<BaudPolicy>,
+) -> ! {
+    let clock = Clock::new().unwrap();
+    let mut monitor = LinkMonitor::new(timeout, &own_mac().unwrap_or_default());
+    let mut baud = BaudNegotiation::new(baud_policy, clock.monotonic());
+    let mut next_ping = Duration::ZERO;
+    let mut next_time = Duration::ZERO;
+    let mut paused = false;
+    loop {
+        let now = clock.monotonic();
+        let phase = maintenance::phase();
+        // The beacon has to know right away
+        if phase.paused() != paused {
+            paused = phase.paused();
+            next_ping = Duration::ZERO;
+        }
+        if now >= next_ping {
+            let ping = baud.ping(monitor.ping(now, paused), now);
+            send_frame(uart_port, &morty_message::Msg::Ping(ping));
+            // A lost ping shouldn't hold up the pause until it times out
+            let busy = baud.link.busy() || phase == Phase::Pausing;
+            next_ping = now
+                + if busy {
+                    KEEPALIVE_INTERVAL.min(interval)
+                } else {
+                    interval
+                };
+        }
+        if now >= next_time {
+            // Without a valid clock, we have nothing to tell
+            if let Some(wall) = clock.wall() {
+                send_frame(
+                    uart_port,
+                    &morty_message::Msg::TimeBeacon(time_beacon(wall)),
+                );
+            }
+            next_time = now + time_interval;
+        }
+
+        let wait = next_ping
+            .min(next_time)
+            .saturating_sub(clock.monotonic())
+            .min(CHECK_INTERVAL);
+        let change = match pongs.recv_timeout(wait) {
+            Ok(pong) => {
+                if let Some(status) = pong.clock.clone() {
+                    events.emit(GatewayEvent::ClockReported {
+                        src: pong.device_id.clone(),
+                        clock: status,
+                    });
+                }
+                if baud.pong(&pong, clock.monotonic()) {
+                    next_ping = Duration::ZERO;
+                }
+                if pong.paused {
+                    if let Some(phase) = maintenance::with(|m| m.beacon_paused(clock.monotonic())) {
+                        info!("Beacon {} holds on to its relays", pong.device_id);
+                        events.emit(GatewayEvent::Maintenance { phase });
+                    }
+                }
+                monitor.pong(&pong)
+            }
+            Err(RecvTimeoutError::Timeout) => monitor.check(clock.monotonic()),
+            // The UART thread is gone, nothing will answer anymore
+            Err(RecvTimeoutError::Disconnected) => {
+                std::thread::sleep(wait);
+                monitor.check(clock.monotonic())
+            }
+        };
+        if let Some(state) = change {
+            log_state(&state);
+            events.emit(GatewayEvent::BeaconLink { state });
+        }
+        if baud.poll(uart_port, clock.monotonic()) {
+            next_ping = Duration::ZERO;
+        }
+        let queued = QUEUE_DEPTH.load(Ordering::Relaxed);
+        if let Some(phase) = maintenance::with(|m| m.poll(clock.monotonic(), queued)) {
+            if phase == Phase::Safe {
+                events.emit(GatewayEvent::ShuttingDown);
+            }
+            events.emit(GatewayEvent::Maintenance { phase });
+        }
+    }
+}
+
+fn log_state(state: &LinkState) {
+    match state {
+        LinkState::Up(beacon) => info!(
+            "Beacon {} attached, firmware {}",
+            beacon.device_id, beacon.firmware_version
+        ),
+        LinkState::Lost => {
+            warn!("Beacon doesn't answer, check that it's connected and TX and RX aren't swapped")
+        }
+        LinkState::Changed { previous, current } => warn!(
+            "Beacon {} was replaced by {}, firmware {}",
+            previous.device_id, current.device_id, current.firmware_version
+        ),
+        LinkState::Rebooted(beacon) => warn!("Beacon {} restarted", beacon.device_id),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    fn pong(ping: &PingMsg, device_id: &str, uptime: u64) -> PongMsg {
+        PongMsg {
+            nonce: ping.nonce,
+            device_id: device_id.to_string(),
+            firmware_version: "1.2.3".to_string(),
+            uptime_ms: uptime * 1000,
+            ..Default::default()
+        }
+    }
+
+    fn beacon(device_id: &str) -> BeaconIdentity {
+        BeaconIdentity {
+            device_id: device_id.to_string(),
+            firmware_version: "1.2.3".to_string(),
+            config_version: 0,
+        }
+    }
+
+    #[test]
+    fn pings_tell_the_beacon_who_we_are() {
+        let mut monitor = LinkMonitor::new(TIMEOUT, "aa:aa:aa:aa:aa:aa");
+        let first = monitor.ping(secs(0), false);
+        let second = monitor.ping(secs(1), true);
+        assert_eq!(first.gateway_id, "aa:aa:aa:aa:aa:aa");
+        assert_ne!(first.nonce, second.nonce);
+        assert!(!first.paused && second.paused);
+    }
+
+    #[test]
+    fn the_first_answer_brings_the_link_up() {
+        let mut monitor = LinkMonitor::new(TIMEOUT, "");
+        let ping = monitor.ping(secs(0), false);
+        assert_eq!(
+            monitor.pong(&pong(&ping, "b1", 10)),
+            Some(LinkState::Up(beacon("b1")))
+        );
+        assert_eq!(monitor.beacon(), Some(&beacon("b1")));
+        let ping = monitor.ping(secs(60), false);
+        assert_eq!(monitor.pong(&pong(&ping, "b1", 70)), None);
+        assert_eq!(monitor.check(secs(100)), None);
+    }
+
+    #[test]
+    fn answers_to_other_pings_are_ignored() {
+        let mut monitor = LinkMonitor::new(TIMEOUT, "");
+        let old = monitor.ping(secs(0), false);
+        monitor.ping(secs(1), false);
+        assert_eq!(monitor.pong(&pong(&old, "b1", 10)), None);
+        assert_eq!(monitor.beacon(), None);
+    }
+
+    #[test]
+    fn an_unanswered_ping_loses_the_link_once() {
+        let mut monitor = LinkMonitor::new(TIMEOUT, "");
+        let ping = monitor.ping(secs(0), false);
+        monitor.pong(&pong(&ping, "b1", 10));
+        monitor.ping(secs(60), false);
+        assert_eq!(monitor.check(secs(65)), None);
+        assert_eq!(monitor.check(secs(66)), Some(LinkState::Lost));
+        monitor.ping(secs(120), false);
+        assert_eq!(monitor.check(secs(130)), None);
+
+        // And it comes back up with the next answer
+        let ping = monitor.ping(secs(180), false);
+        assert_eq!(
+            monitor.pong(&pong(&ping, "b1", 190)),
+            Some(LinkState::Up(beacon("b1")))
+        );
+    }
+
+    #[test]
+    fn a_beacon_that_never_answered_is_lost() {
+        let mut monitor = LinkMonitor::new(TIMEOUT, "");
+        monitor.ping(secs(0), false);
+        assert_eq!(monitor.check(secs(6)), Some(LinkState::Lost));
+    }
+
+    #[test]
+    fn another_beacon_is_a_change() {
+        let mut monitor = LinkMonitor::new(TIMEOUT, "");
+        let ping = monitor.ping(secs(0), false);
+        monitor.pong(&pong(&ping, "b1", 10));
+        let ping = monitor.ping(secs(60), false);
+        assert_eq!(
+            monitor.pong(&pong(&ping, "b2", 5)),
+            Some(LinkState::Changed {
+                previous: beacon("b1"),
+                current: beacon("b2"),
+            })
+        );
+    }
+
+    #[test]
+    fn a_beacon_whose_uptime_went_back_rebooted() {
+        let mut monitor = LinkMonitor::new(TIMEOUT, "");
+        let ping = monitor.ping(secs(0), false);
+        monitor.pong(&pong(&ping, "b1", 1000));
+        let ping = monitor.ping(secs(60), false);
+        assert_eq!(
+            monitor.pong(&pong(&ping, "b1", 3)),
+            Some(LinkState::Rebooted(beacon("b1")))
+        );
+    }
+
+    #[test]
+    fn our_own_hint_and_no_hint_are_fine() {
+        assert_eq!(check_hint("gw-own", "gw-own", "t1"), None);
+        assert_eq!(check_hint("gw-own", "", "t1"), None);
+        assert!(!hint_mismatches().iter().any(|m| m.hint == "gw-own"));
+    }
+
+    #[test]
+    fn a_hint_of_another_gateway_is_reported_once() {
+        assert_eq!(
+            check_hint("gw-own", "gw-moved", "t1"),
+            Some(HintMismatch {
+                hint: "gw-moved".to_string(),
+                src: "t1".to_string(),
+                count: 1,
+            })
+        );
+        assert_eq!(check_hint("gw-own", "gw-moved", "t2"), None);
+        assert_eq!(check_hint("gw-own", "gw-moved", "t3"), None);
+        let mismatch = hint_mismatches()
+            .into_iter()
+            .find(|m| m.hint == "gw-moved")
+            .unwrap();
+        assert_eq!(mismatch.src, "t3");
+        assert_eq!(mismatch.count, 3);
+    }
+
+    #[test]
+    fn every_other_hint_is_reported() {
+        assert!(check_hint("gw-own", "gw-swapped-1", "t1").is_some());
+        assert!(check_hint("gw-own", "gw-swapped-2", "t1").is_some());
+        let hints: Vec<String> = hint_mismatches().into_iter().map(|m| m.hint).collect();
+        assert!(hints.contains(&"gw-swapped-1".to_string()));
+        assert!(hints.contains(&"gw-swapped-2".to_string()));
+    }
+}
@@ -1,5 +1,49 @@
-use base64::engine::general_purpose;
-use base64::Engine;
+mod api;
+mod assist;
+mod audit;
+mod bridge;
+mod capture;
+mod cbor;
+mod combo;
+mod config;
+mod console;
+mod devices;
+#[cfg(feature = "display")]
+mod display;
+mod downlink;
+mod events;
+mod export;
+mod frames;
+mod gzip;
+mod handoff;
+mod last_fix;
+mod link;
+mod maintenance;
+mod mapping;
+mod notify;
+mod ota;
+#[cfg(feature = "display")]
+mod pages;
+mod pin;
+mod privacy;
+mod queue;
+mod replay;
+mod serializer;
+mod sources;
+mod staleness;
+mod storage;
+mod trace;
+mod usage;
+
+use anyhow::bail;
+use api::ApiClient;
+use api::Proxy;
+use audit::AuditLog;
+use capture::FailedFrames;
+use config::config;
+use config::DEFAULT_NOTIFY_RULES;
+use console::TEST_SOURCE;
+use devices::Device;
 use esp_idf_hal::cpu::Core;
 use esp_idf_hal::gpio;
 use esp_idf_hal::peripheral::Peripheral;
@@ -7,59 +51,414 @@ use esp_idf_hal::prelude::*;
 use esp_idf_hal::uart;
 use esp_idf_hal::uart::Uart;
 use esp_idf_svc::eventloop::EspSystemEventLoop;
-use esp_idf_svc::sntp::SyncStatus;
-use esp_idf_svc::systime::EspSystemTime;
 use esp_idf_sys as _;
-use json::object;
+use events::AuditSubscriber;
+use events::CaptureSubscriber;
+use events::DeviceSubscriber;
+use events::DriftSubscriber;
+use events::Events;
+use events::GatewayEvent;
+use events::HeapEvents;
+use events::LastFixSubscriber;
+use events::LedSubscriber;
+use events::NotifySubscriber;
+use events::SourceSubscriber;
+use events::Subscriber;
+use events::TestFixSubscriber;
+use events::TraceSubscriber;
+use events::UsageSubscriber;
+use frames::decode_line;
+use frames::read_line_bounded;
+use frames::FrameCounts;
+use frames::Via;
+use frames::MAX_LINE_LEN;
+use handoff::LineSender;
+use last_fix::LastFixes;
+use link::check_hint;
 use log::*;
-use morty_rs::comm::decode_msg;
-use morty_rs::comm::start_wifi;
+use morty_rs::baud::BaudPolicy;
+use morty_rs::budget::check_frame_budget;
+use morty_rs::clock::Clock;
+use morty_rs::comm::get_message_type;
+use morty_rs::comm::own_mac;
+use morty_rs::comm::parse_mac;
+use morty_rs::comm::set_frame_format;
+use morty_rs::comm::set_tx_power_dbm;
+use morty_rs::comm::start_wifi_networks;
+use morty_rs::comm::FrameFormat;
+use morty_rs::config::CONFIG_NAMESPACE;
+use morty_rs::dedup::DedupCache;
+use morty_rs::dedup::Seen;
+use morty_rs::flashlog;
+use morty_rs::geo::format_dm;
+use morty_rs::geo::geohash;
+use morty_rs::geo::Smoother;
+use morty_rs::geo::Smoothing;
+use morty_rs::geo::SmoothingConfig;
+use morty_rs::heap::start_heap_guard;
+use morty_rs::heap::HeapThresholds;
+use morty_rs::hello::HelloLimiter;
+use morty_rs::hello::HELLO_INTERVAL;
 use morty_rs::led::colors;
 use morty_rs::led::Led;
+use morty_rs::ledlog;
+use morty_rs::ledlog::LEDLOG_NAMESPACE;
 use morty_rs::messages::morty_message::Msg;
-use morty_rs::utils::set_thread_spawn_configuration;
+use morty_rs::messages::BeaconPresentMsg;
+use morty_rs::messages::GpsMsg;
+use morty_rs::messages::GpsState;
+use morty_rs::metrics::Histogram;
+use morty_rs::nvs_recovery;
+use morty_rs::nvs_recovery::Criticality;
+use morty_rs::nvs_recovery::Namespace;
+use morty_rs::phase;
+use morty_rs::source_metrics::SourceEvent;
+use morty_rs::source_metrics::SourceMetrics;
+use morty_rs::source_metrics::DEFAULT_SOURCES;
+use morty_rs::status::fatal;
+use morty_rs::status::FatalAction;
+use morty_rs::status::OrFatal;
+use morty_rs::status::Status;
+use morty_rs::uart_errors::monitor_uart_errors;
+use morty_rs::uart_errors::UartErrors;
+use morty_rs::utils::boot_complete;
+use morty_rs::utils::dump_threads;
+use morty_rs::utils::format_duration;
+use morty_rs::utils::spawn_thread;
+use morty_rs::utils::sync_sntp;
+use morty_rs::utils::Backoff;
+use morty_rs::utils::PeriodicSet;
+use morty_rs::utils::ThreadConfig;
 use morty_rs::utils::UartRead;
-use std::collections::VecDeque;
-use std::io::BufRead;
+use morty_rs::wifi;
+use morty_rs::wire::MsgType;
+use notify::Notifier;
+use notify::Rules;
+use privacy::Privacy;
+use queue::Expired;
+use queue::Pending;
+use queue::PendingEvent;
+use queue::PendingUpload;
+use queue::RetryQueue;
+use queue::Sink;
+use queue::SinkRole;
+use queue::UploadTtl;
+use serializer::idempotency_key;
+use serializer::upload_key;
+use serializer::PayloadFormat;
+use serializer::Serializer;
+use sources::Registry;
+use staleness::MaxAgePolicy;
+use staleness::StaleAction;
+use staleness::Verdict;
+use std::collections::HashMap;
+use std::collections::HashSet;
 use std::io::BufReader;
+use std::io::Read;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::RecvTimeoutError;
 use std::time::Duration; // If using the `binstart` feature of `esp-idf-sys`, always keep this module imported
+use trace::Traces;
+use usage::DailyUsage;
 
-const SSID: &str = "IoT";
-const PASS: &str = "EddieVedder7";
+// The settings that differ between installations, e.g. the wifi and the API host, are in `config`
 
 const LED_BRIGHTNESS: u8 = 10;
-const API_HOST: &str = "wouterdebie-personal.ue.r.appspot.com";
+const EVENT_LOG_LEVEL: LevelFilter = LevelFilter::Info;
+// Layout of the frames we send. Switch to `FrameFormat::Header` once the whole fleet
+// understands it.
+const FRAME_FORMAT: FrameFormat = FrameFormat::Legacy;
+const SNTP_TIMEOUT: Duration = Duration::from_secs(30);
+// With more than one network in the settings, we move on to the next one after this many failed
+// attempts in a row on the one we use
+const WIFI_FAILOVER_AFTER: u32 = 5;
+// HTTP proxy for networks that can only reach the internet through one, e.g.
+// `Some(Proxy { host: "proxy.example.com", port: 3128, auth: Some(("user", "password")) })`
+const PROXY: Option<Proxy> = None;
+
+const RETRY_QUEUE_SIZE: usize = 256;
+// Uploads that have been queued for longer than this are dropped instead of uploaded. Events get
+// the longest, but a power event from six hours ago is no use to anyone either.
+const UPLOAD_TTL: UploadTtl = UploadTtl {
+    live_fix: Duration::from_secs(60 * 60),
+    backfill: Duration::from_secs(4 * 60 * 60),
+    event: Duration::from_secs(6 * 60 * 60),
+};
+// Fixes older than this when we get to upload them are stale and dropped or flagged. Backfilled
+// fixes get longer, they are expected to be old.
+const MAX_FIX_AGE: MaxAgePolicy = MaxAgePolicy {
+    live: Duration::from_secs(60 * 60),
+    backfill: Duration::from_secs(24 * 60 * 60),
+    action: StaleAction::Flag,
+};
+// Number of upload attempts kept in the audit log
+const AUDIT_LOG_SIZE: usize = 200;
+// Format of the uploads. Use `PayloadFormat::Cbor` to save bandwidth with the default backend,
+// `PayloadFormat::Protobuf` for protobuf native backends and `PayloadFormat::Mapped` for backends
+// that want other fields, e.g. `mapping::OSMAND` for Traccar.
+const PAYLOAD_FORMAT: PayloadFormat = PayloadFormat::Json;
+const GEOHASH_PRECISION: usize = 9;
+// How precise the positions of a source are in what leaves the gateway, by MAC address, e.g.
+// `("aa:bb:cc:dd:ee:ff", Privacy::Rounded100m)`. Sources that aren't listed get DEFAULT_PRIVACY.
+const SOURCE_PRIVACY: &[(&str, Privacy)] = &[];
+const DEFAULT_PRIVACY: Privacy = Privacy::Full;
+// Names of sources at this site, by MAC address, e.g. `("aa:bb:cc:dd:ee:ff", "trailer-1")`. A
+// name the device reports itself wins, see `morty_rs::naming`, and the console's `status` shows
+// the sources where the two differ.
+const SOURCE_NAMES: &[(&str, &str)] = &[];
+// What the API server does with the fixes. Set this to `SinkRole::Display` for one that only shows
+// them on a map, e.g. Traccar, and fixes are smoothed with FIX_SMOOTHING before they're queued, so
+// a tracker that stands still doesn't jitter. The last fixes and the exports on the console
+// always have the positions as they came in.
+const API_SINK_ROLE: SinkRole = SinkRole::Archival;
+const FIX_SMOOTHING: SmoothingConfig = SmoothingConfig {
+    smoothing: Smoothing::Snap { radius_m: 15.0 },
+    max_gap_secs: 10 * 60,
+    max_speed_mps: 70.0,
+    max_sources: 32,
+};
+// Negotiate the baud rate of the UART with the beacon, going down on a noisy link and up on a
+// clean one. Beacons that don't know about this never accept, so we stay at 115200 with them.
+const UART_BAUD_NEGOTIATION: bool = false;
+const UART_BAUD_POLICY: BaudPolicy = BaudPolicy {
+    min_baud: 57_600,
+    max_baud: 230_400,
+    check_interval: Duration::from_secs(10 * 60),
+    min_frames: 100,
+    max_error_rate: 0.02,
+    hold_off: Duration::from_secs(24 * 60 * 60),
+};
+// EPO file with GPS assistance data for the MTK GPS modules of the trackers, None to disable
+const ASSIST_URL: Option<&str> = None;
+const ASSIST_REFRESH_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+const ASSIST_RESEND_INTERVAL: Duration = Duration::from_secs(30 * 60);
+// Number of sources we remember the last fix of, across reboots
+const LAST_FIX_SOURCES: usize = 16;
+// Changes to the last fixes are written to NVS at most this often
+const LAST_FIX_SAVE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+// Changes to the registry of every source we heard from are written to NVS at most this often
+const SOURCES_SAVE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+// What we keep in NVS, erased in this order when NVS is full or corrupted. Wifi doesn't use NVS on
+// the gateway, but the settings can hold its password and we remember which network last worked.
+const NVS_NAMESPACES: &[Namespace] = &[
+    Namespace {
+        name: "phy",
+        criticality: Criticality::Cache,
+    },
+    Namespace {
+        name: wifi::WIFI_NAMESPACE,
+        criticality: Criticality::Cache,
+    },
+    Namespace {
+        name: "lastfix",
+        criticality: Criticality::Cache,
+    },
+    Namespace {
+        name: "usage",
+        criticality: Criticality::Stats,
+    },
+    Namespace {
+        name: "sources",
+        criticality: Criticality::Stats,
+    },
+    Namespace {
+        name: LEDLOG_NAMESPACE,
+        criticality: Criticality::Stats,
+    },
+    Namespace {
+        name: CONFIG_NAMESPACE,
+        criticality: Criticality::Credentials,
+    },
+];
+// Fixes per source and per day are kept for this many days, for billing. Changes are written to
+// NVS at most every USAGE_SAVE_INTERVAL, so a reboot loses at most that much. Fixes from before
+// the wall clock is valid wait for it, up to USAGE_UNDATED of them.
+const USAGE_RETENTION_DAYS: u32 = 31;
+const USAGE_SAVE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const USAGE_UNDATED: usize = 256;
+// In maintenance mode, see `maintenance`, we wait this long for the beacon to confirm that it holds
+// on to its relays, and then this long for the retry queue to be uploaded
+const MAINTENANCE_PAUSE_TIMEOUT: Duration = Duration::from_secs(30);
+const MAINTENANCE_DRAIN_TIMEOUT: Duration = Duration::from_secs(2 * 60);
+// TLS handshakes start failing well before the heap runs out, so we act early
+const HEAP_THRESHOLDS: HeapThresholds = HeapThresholds {
+    warning: 32 * 1024,
+    critical: 16 * 1024,
+    hysteresis: 4 * 1024,
+    critical_timeout: Duration::from_secs(30),
+};
+// Upper bounds of the buckets of the line latency histogram
+const LATENCY_BUCKETS: [Duration; 6] = [
+    Duration::from_millis(1),
+    Duration::from_millis(10),
+    Duration::from_millis(100),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_secs(5),
+];
+// Lines waiting to be handled, see `handoff`. More drop the oldest.
+const LINE_QUEUE_SIZE: usize = 32;
+// How often the retry queue is drained when no lines come in, e.g. while the beacon is down
+const DRAIN_INTERVAL: Duration = Duration::from_secs(1);
+// Frames are decoded into a buffer of this size. ESP-NOW frames are at most 250 bytes.
+const FRAME_BUFFER_LEN: usize = 300;
+// UIDs of the last fixes, to drop the copies other beacons relay
+const DEDUP_CACHE_SIZE: usize = 10;
+// Frames that didn't decode kept for `dump failed` on the console, once capturing is started with
+// `capture failed on`. Capturing stops by itself after a while.
+const FAILED_FRAMES: usize = 50;
+const FAILED_FRAME_CAPTURE_TIME: Duration = Duration::from_secs(60 * 60);
+// Sources we keep fix, duplicate and loss counters of. When all of them were heard from in the
+// last SOURCE_IDLE_EVICT, new sources are counted under "other".
+const SOURCE_METRICS_SIZE: usize = DEFAULT_SOURCES;
+const SOURCE_IDLE_EVICT: Duration = Duration::from_secs(24 * 60 * 60);
+// Every thread we spawn, by priority. Whatever takes frames off the UART and the radio comes
+// first, so they don't pile up in the driver, then the pipeline that uploads them. The rest is
+// housekeeping, with the LED and the display last. See `threads` on the console for how much
+// stack they have left.
+const UART_READ_THREAD: ThreadConfig =
+    ThreadConfig::new("uart-read-thread\0", 4096, 15, Some(Core::Core1));
+const COMBO_THREAD: ThreadConfig = ThreadConfig::new("combo-thread\0", 8196, 14, None);
+const BRIDGE_READ_THREAD: ThreadConfig = ThreadConfig::new("bridge-read\0", 4096, 13, None);
+const RECV_THREAD: ThreadConfig = ThreadConfig::new("recv-thread\0", 8196, 12, Some(Core::Core1));
+const COMMAND_THREAD: ThreadConfig = ThreadConfig::new("command-thread\0", 8196, 8, None);
+const LINK_THREAD: ThreadConfig = ThreadConfig::new("link-thread\0", 8196, 8, None);
+const BRIDGE_THREAD: ThreadConfig = ThreadConfig::new("bridge-thread\0", 8196, 8, None);
+const EVENT_THREAD: ThreadConfig = ThreadConfig::new("event-thread\0", 8196, 6, None);
+const HEAP_GUARD_THREAD: ThreadConfig = ThreadConfig::new("heap-guard\0", 4096, 6, None);
+const UART_ERRORS_THREAD: ThreadConfig = ThreadConfig::new("uart-errors\0", 4096, 6, None);
+const CONSOLE_THREAD: ThreadConfig = ThreadConfig::new("console-thread\0", 8196, 5, None);
+const ASSIST_THREAD: ThreadConfig = ThreadConfig::new("assist-thread\0", 8196, 4, None);
+const OTA_THREAD: ThreadConfig = ThreadConfig::new("ota-thread\0", 8196, 3, None);
+const DISPLAY_THREAD: ThreadConfig = ThreadConfig::new("display-thread\0", 8196, 2, None);
+const LED_THREAD: ThreadConfig = ThreadConfig::new("led-thread\0", 4196, 2, None);
+
+// Timers
+const STATS_LOG: &str = "stats_log";
+const DRAIN: &str = "drain";
+
+static UART_ERRORS: UartErrors = UartErrors::new();
+// Time from a line coming in until we get to it, which is mostly spent on uploads
+static LINE_LATENCY: Histogram<{ LATENCY_BUCKETS.len() }> = Histogram::new(LATENCY_BUCKETS);
+// Time the UART thread takes to hand a line over, which it can't read during
+static LINE_HANDOFF: Histogram<{ LATENCY_BUCKETS.len() }> = Histogram::new(LATENCY_BUCKETS);
+// Time it takes to decode the frames of a line, drop the duplicates and queue the fixes, without
+// the uploads
+static LINE_HANDLING: Histogram<{ LATENCY_BUCKETS.len() }> = Histogram::new(LATENCY_BUCKETS);
+// Lines dropped for newer ones because they weren't handled in time
+static LINES_DROPPED: AtomicU32 = AtomicU32::new(0);
+// Longest line we've seen, to see how close we get to the size of the buffer
+static PEAK_LINE_LEN: AtomicUsize = AtomicUsize::new(0);
+// Lines read from the UART, to compare with the frames heard over ESP-NOW in combo mode
+static UART_LINES: AtomicU32 = AtomicU32::new(0);
+// Frames from the beacon that decoded and that didn't, for the baud rate negotiation, and why
+// they didn't. In combo mode this includes the frames heard over ESP-NOW.
+static UART_FRAMES: FrameCounts = FrameCounts::new();
+static SOURCE_METRICS: SourceMetrics<SOURCE_METRICS_SIZE> = SourceMetrics::new(SOURCE_IDLE_EVICT);
 
 fn main() -> anyhow::Result<()> {
     esp_idf_svc::log::EspLogger::initialize_default();
+    set_frame_format(FRAME_FORMAT);
+    if let Err(e) = check_frame_budget() {
+        error!("{e}");
+    }
 
-    let sysloop = EspSystemEventLoop::take()?;
     let peripherals = Peripherals::take().unwrap();
     let pins = peripherals.pins;
 
-    // Configure the LED
+    // Configure the LED before anything else, so it can show what goes wrong after this
     let mut led = Led::new();
-    led.start(pins.gpio18.into(), pins.gpio17.into())?;
+    led.start(pins.gpio18.into(), pins.gpio17.into(), LED_THREAD)
+        .or_fatal(Status::Startup, &led);
     led.set_color(colors::BLUE, LED_BRIGHTNESS)?;
 
+    // The event log is nice to have, so we carry on without it
+    phase!("flashlog", {
+        if let Err(e) = flashlog::start(EVENT_LOG_LEVEL) {
+            error!("Unable to start event log: {e}");
+        }
+    });
+
+    // A broken field mapping is a configuration error, so we find out right away
+    let serializer = PAYLOAD_FORMAT.serializer().or_fatal(Status::Startup, &led);
+
+    let sysloop = EspSystemEventLoop::take().or_fatal(Status::Startup, &led);
+    // Initializes NVS, where the last fixes and the settings are kept. When it's broken beyond
+    // repair, we run without it, with the default settings, and forget the last fixes at a
+    // reboot.
+    let _nvs = nvs_recovery::take_partition(NVS_NAMESPACES).or_fatal(Status::Startup, &led);
+    ledlog::restore();
+    ledlog::install_panic_hook();
+    let config = config::load().or_fatal(Status::Startup, &led);
+    // A pinned certificate that doesn't parse leaves the bundle out as well, the API host isn't
+    // trusted until it's replaced on the console
+    if !config.api_ca.is_empty() {
+        match pin::parse_pem(&config.api_ca) {
+            Ok(der) => api::pin_certificate(&config.api_host, der),
+            Err(e) => {
+                error!("Invalid pinned certificate for {}: {e}", config.api_host);
+                api::pin_certificate(&config.api_host, Vec::new());
+            }
+        }
+    }
+
     // Configure the wifi
-    let _wifi = start_wifi(peripherals.modem, sysloop, SSID, PASS)?;
+    let _wifi = phase!("wifi_connect", {
+        start_wifi_networks(
+            peripherals.modem,
+            sysloop,
+            config.networks(),
+            WIFI_FAILOVER_AFTER,
+        )
+        .or_fatal(Status::Wifi, &led)
+    });
+    set_tx_power_dbm(config.tx_power_dbm).or_fatal(Status::Wifi, &led);
     led.set_color(colors::YELLOW, LED_BRIGHTNESS)?;
 
     // Update system time
-    update_sntp()?;
+    phase!("sntp", {
+        while let Err(e) = sync_sntp(SNTP_TIMEOUT) {
+            error!("{e}");
+            fatal(Status::Sntp, &led, FatalAction::Retry);
+            led.set_color(colors::YELLOW, LED_BRIGHTNESS)?;
+        }
+    });
 
     led.set_color(colors::GREEN, LED_BRIGHTNESS)?;
 
+    // Subscribers for hardware that not every gateway has
+    #[allow(unused_mut)]
+    let mut subscribers: Vec<Box<dyn Subscriber>> = Vec::new();
+    #[cfg(feature = "display")]
+    {
+        let ip = _wifi.sta_netif().get_ip_info()?.ip.to_string();
+        // Set these to the SDA and SCL pins the display is connected to
+        let (sda, scl) = (pins.gpio8.into(), pins.gpio9.into());
+        if let Some(display) = display::start(peripherals.i2c0, sda, scl, ip) {
+            subscribers.push(Box::new(display));
+        }
+    }
+
     // Spawn the recv thread on core 1
-    set_thread_spawn_configuration("recv-thread\0", 8196, 15, Some(Core::Core1))?;
-    let recv_thread = std::thread::Builder::new()
-        .stack_size(8196)
-        .spawn(move || {
-            uart_task(peripherals.uart1, pins.gpio0.into(), pins.gpio2.into(), led).unwrap();
-        })?;
+    let led_handle = led.handle();
+    let recv_thread = spawn_thread(RECV_THREAD, move || {
+        let uart_led = led.handle();
+        uart_task(
+            peripherals.uart1,
+            pins.gpio0.into(),
+            pins.gpio2.into(),
+            led,
+            serializer,
+            subscribers,
+        )
+        .or_fatal(Status::Uart, &uart_led);
+    })
+    .or_fatal(Status::Thread, &led_handle);
 
-    recv_thread.join().unwrap();
+    recv_thread.join().or_fatal(Status::Thread, &led_handle);
     Ok(())
 }
 
@@ -68,10 +467,13 @@ fn uart_task(
     uart: impl Peripheral<P = impl Uart> + 'static,
     tx: gpio::AnyOutputPin,
     rx: gpio::AnyInputPin,
-    mut led: Led,
+    led: Led,
+    serializer: Box<dyn Serializer + Send>,
+    extra_subscribers: Vec<Box<dyn Subscriber>>,
 ) -> Result<(), anyhow::Error> {
     info!("Starting UART task");
-    let config = uart::config::Config::default().baudrate(Hertz(115200));
+    let config = config();
+    let uart_config = uart::config::Config::default().baudrate(Hertz(115200));
 
     let uart_driver = uart::UartDriver::new(
         uart,
@@ -79,169 +481,838 @@ fn uart_task(
         rx,
         Option::<gpio::Gpio0>::None,
         Option::<gpio::Gpio0>::None,
-        &config,
+        &uart_config,
     )?;
 
     // Create a cache of the last 10 IDs we've seen, since we can have multiple messages with the
     // same id, because a message might have been relayed by multiple beacons.
-    let mut cache = IdCache::new(10);
+    let mut cache = DedupCache::new(DEDUP_CACHE_SIZE);
 
+    let mut sources = Sources {
+        gps_faults: HashSet::new(),
+        gps_silent: HashSet::new(),
+        beacon_power: HashMap::new(),
+        hellos: HelloLimiter::new(HELLO_INTERVAL),
+        traces: Traces::new(),
+        smoother: Smoother::new(FIX_SMOOTHING),
+    };
+
+    // Fixes and events that still need to be uploaded. These pile up when the internet connection
+    // is down.
+    let mut queue = RetryQueue::new(RETRY_QUEUE_SIZE, config.drain_policy, UPLOAD_TTL);
+    info!("Retry queue drain policy: {}", queue.policy());
+
+    // The last fix of every source from before the reboot, until they report again
+    let mut last_fixes = LastFixes::new(LAST_FIX_SOURCES, LAST_FIX_SAVE_INTERVAL);
+    if let Err(e) = last_fixes.restore() {
+        error!("Unable to restore last fixes: {e}");
+    }
+    last_fixes.log();
+
+    // Fixes per source and per day, from before the reboot as well
+    let mut usage = DailyUsage::new(USAGE_RETENTION_DAYS, USAGE_UNDATED);
+    if let Err(e) = usage.restore() {
+        error!("Unable to restore usage: {e}");
+    }
+
+    // Every source that ever talked to us, see `sources`
+    let mut registry = Registry::new(SOURCES_SAVE_INTERVAL);
+    if let Err(e) = registry.restore() {
+        error!("Unable to restore sources: {e}");
+    }
+
+    // The pipeline only emits events, these take care of showing and recording them. Every
+    // upload attempt is recorded in the audit log, so we can find out why a fix shows up twice.
+    let mut subscribers: Vec<Box<dyn Subscriber>> = vec![
+        Box::new(LedSubscriber::new(led.handle())),
+        Box::new(AuditSubscriber::new(AuditLog::new(AUDIT_LOG_SIZE))),
+        Box::new(LastFixSubscriber::new(last_fixes)),
+        Box::new(SourceSubscriber::new(registry, Clock::new()?)),
+        Box::new(CaptureSubscriber::new(FailedFrames::new(
+            FAILED_FRAMES,
+            FAILED_FRAME_CAPTURE_TIME,
+        ))),
+        Box::new(TestFixSubscriber::default()),
+        Box::new(DeviceSubscriber::default()),
+        Box::new(DriftSubscriber::default()),
+        Box::new(UsageSubscriber::new(
+            usage,
+            Clock::new()?,
+            USAGE_SAVE_INTERVAL,
+        )),
+        Box::new(TraceSubscriber::default()),
+    ];
+    // Critical events are posted to the webhook, if there is one. The notifications come back
+    // here to go through the retry queue.
+    let (notifications, queued_notifications) = std::sync::mpsc::channel();
+    if !config.webhook_url.is_empty() {
+        let rules =
+            Rules::parse(&config.notify_rules, config.low_battery_volts).unwrap_or_else(|e| {
+                error!("Invalid notify rules, using the default: {e}");
+                Rules::parse(DEFAULT_NOTIFY_RULES, config.low_battery_volts).unwrap()
+            });
+        subscribers.push(Box::new(NotifySubscriber::new(
+            Notifier::new(rules, config.notify_grace),
+            Clock::new()?,
+            notifications,
+        )));
+    }
+    subscribers.extend(extra_subscribers);
+    let events = events::start(subscribers)?;
+    let mut depth = queue.len();
+
+    // When the heap runs low, the event subscribers shed load and save what they can
+    start_heap_guard(
+        HEAP_THRESHOLDS,
+        config.heap_check_interval,
+        led.handle(),
+        Box::new(HeapEvents::new(events.clone())),
+        HEAP_GUARD_THREAD,
+    )?;
+
+    let uart_port = uart_driver.port() as i32;
+    monitor_uart_errors(
+        uart_port,
+        &UART_ERRORS,
+        config.uart_framing_error_threshold,
+        led.handle(),
+        UART_ERRORS_THREAD,
+    )?;
     uart_driver.flush_read()?;
 
-    let mut reader = BufReader::new(UartRead::new(uart_driver));
-    let mut buffer = String::new();
+    // Commands for the devices go out over the same UART, from their own thread
+    let command_events = events.clone();
+    spawn_thread(COMMAND_THREAD, move || {
+        downlink::command_task(uart_port, config.command_poll_interval, command_events)
+    })?;
+
+    // So do the pings for the beacon, the answers are handed over from this thread
+    let (pongs, received_pongs) = std::sync::mpsc::channel();
+    let link_events = events.clone();
+    spawn_thread(LINK_THREAD, move || {
+        link::link_task(
+            uart_port,
+            received_pongs,
+            link_events,
+            config.beacon_ping_interval,
+            config.beacon_pong_timeout,
+            config.time_beacon_interval,
+            UART_BAUD_NEGOTIATION.then_some(UART_BAUD_POLICY),
+        )
+    })?;
+
+    if let Some(url) = ASSIST_URL {
+        spawn_thread(ASSIST_THREAD, move || {
+            assist::assist_task(
+                uart_port,
+                url,
+                ASSIST_REFRESH_INTERVAL,
+                ASSIST_RESEND_INTERVAL,
+            )
+        })?;
+    }
+
+    if ota::OTA_PUBLIC_KEY.is_some() {
+        spawn_thread(OTA_THREAD, move || ota::ota_task(config.ota_check_interval))?;
+    }
+
+    // Lines from the UART, test fixes injected from the console, the lines of the beacons bridged
+    // over TCP and in combo mode the frames heard over ESP-NOW are handled the same way, so they
+    // are read by their own thread and handed over here, with where they came from. The readers
+    // never wait for us, when we fall behind the oldest lines are dropped, see `handoff`. Handled
+    // lines go back to the UART thread, which reuses them, so we don't allocate for every line.
+    let (lines, mut received) = handoff::channel(LINE_QUEUE_SIZE, &LINES_DROPPED);
+    let (handled, reusable) = std::sync::mpsc::channel();
+    let uart_lines = lines.clone();
+    let uart_led = led.handle();
+    spawn_thread(UART_READ_THREAD, move || {
+        read_lines(uart_driver, uart_lines, reusable).or_fatal(Status::Uart, &uart_led)
+    })?;
+    let console_events = events.clone();
+    let console_lines = lines.clone();
+    spawn_thread(CONSOLE_THREAD, move || {
+        console::console_task(console_lines, console_events)
+    })?;
+    if config.bridge_port != 0 {
+        if let Err(e) = bridge::start(config.bridge_port, config.bridge_idle, lines.clone()) {
+            error!(
+                "Unable to accept bridges on port {}: {e}",
+                config.bridge_port
+            );
+        }
+    }
+    if config.combo_mode {
+        if let Err(e) = combo::start(lines) {
+            error!("Unable to receive over ESP-NOW, only using the UART: {e}");
+        }
+    }
+
+    // Frames are stamped with the monotonic clock when they come in, since the wall clock might
+    // not be valid yet. They get their wall clock time when they are uploaded.
+    let clock = Clock::new()?;
+    let mut schedule = PeriodicSet::new();
+    schedule.add(STATS_LOG, config.stats_log_interval);
+    schedule.add(DRAIN, DRAIN_INTERVAL);
+    let mut frame_buffer = [0u8; FRAME_BUFFER_LEN];
+    let mut upload_backoff =
+        Backoff::new(config.upload_retry_delay, 2, config.upload_retry_max_delay).with_jitter();
+    let mut next_upload = Duration::ZERO;
+    // What the beacons should stamp as the gateway hint, see `link::check_hint`
+    let gateway_id = own_mac()?;
+    boot_complete();
 
     loop {
-        buffer.clear();
-        reader.read_line(&mut buffer)?;
-        if &buffer[0..8] != "MORTYGPS" {
-            warn!("Received invalid message: {}", buffer);
-        } else {
-            // Decode Base64
-            let bytes = general_purpose::STANDARD.decode(buffer[8..].trim());
-            if bytes.is_err() {
-                error!("Unable to decode: {}", buffer);
-                continue;
+        // Between lines, the retry queue is still drained every DRAIN_INTERVAL
+        let line = match received.recv_timeout(DRAIN_INTERVAL) {
+            Ok(line) => Some(line),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        if schedule.due(STATS_LOG, clock.monotonic()) {
+            info!("Line latency: {LINE_LATENCY}");
+            info!("Line handoff: {LINE_HANDOFF}");
+            info!("Line handling: {LINE_HANDLING}");
+            info!("Decode failures: {}", UART_FRAMES.decode_failures);
+            info!("Sources: {SOURCE_METRICS}");
+            info!(
+                "Wifi: {} ssid={}",
+                wifi::link(),
+                wifi::active_ssid().unwrap_or_default()
+            );
+            info!("Threads:\n{}", dump_threads());
+            info!(
+                "Received: uart_lines={} lines_dropped={} esp_now_frames={} esp_now_dropped={} \
+                 maintenance_refused={}",
+                UART_LINES.load(Ordering::Relaxed),
+                LINES_DROPPED.load(Ordering::Relaxed),
+                combo::ESP_NOW_FRAMES.load(Ordering::Relaxed),
+                combo::ESP_NOW_DROPPED.load(Ordering::Relaxed),
+                maintenance::REFUSED.load(Ordering::Relaxed)
+            );
+            info!(
+                "Peak line length: {}/{MAX_LINE_LEN}, peak frame length: {}/{FRAME_BUFFER_LEN}, \
+                 oversized frames: {}",
+                PEAK_LINE_LEN.load(Ordering::Relaxed),
+                UART_FRAMES.peak_len.load(Ordering::Relaxed),
+                UART_FRAMES.oversized.load(Ordering::Relaxed)
+            );
+            if let Some(report) = nvs_recovery::take_report() {
+                warn!("NVS recovery: {report}");
             }
-
-            // Decode protobuf
-            let morty_msg = decode_msg(bytes.unwrap().as_slice());
-            match morty_msg {
-                Ok(Some(Msg::Relay(relay_msg))) => {
-                    handle_relay_message(relay_msg, &mut cache, &mut led).unwrap();
-                }
-                Ok(msg) => {
-                    warn!("Received unknown message: {:?}", msg);
-                }
-                Err(e) => {
-                    error!("Error decoding message: {:?}", e);
+            let expired: Vec<String> = queue
+                .expired()
+                .map(|(class, count)| format!("{class}={count}"))
+                .collect();
+            info!("Expired uploads: {}", expired.join(" "));
+            info!("Compression: {}", api::COMPRESSION);
+            for bridge in bridge::bridges() {
+                info!("Bridge: {}", bridge.status_line(clock.monotonic()));
+            }
+        }
+        let mut drain = schedule.due(DRAIN, clock.monotonic());
+        if let Some((received_at, via, buffer)) = line {
+            LINE_LATENCY.record(clock.monotonic().saturating_sub(received_at));
+            PEAK_LINE_LEN.fetch_max(buffer.len(), Ordering::Relaxed);
+            // A line can hold multiple frames, see `frames`
+            let started = clock.monotonic();
+            let mut types = Vec::new();
+            let valid = decode_line(
+                &buffer,
+                received_at,
+                &mut frame_buffer,
+                &UART_FRAMES,
+                |frame| match frame {
+                    Ok(Some(Msg::Relay(relay_msg))) => {
+                        types.push("relay");
+                        if !maintenance::accept_relay() {
+                            return;
+                        }
+                        events.emit(GatewayEvent::FrameDecoded {
+                            src: relay_msg.src.clone(),
+                        });
+                        if let Some(mismatch) =
+                            check_hint(&gateway_id, &relay_msg.gateway_hint, &relay_msg.src)
+                        {
+                            warn!(
+                                "A beacon attached to gateway {} wrote to us via {via}, check the \
+                                 wiring",
+                                mismatch.hint
+                            );
+                            events.emit(GatewayEvent::GatewayHintMismatch {
+                                hint: mismatch.hint,
+                                src: mismatch.src,
+                                via,
+                            });
+                        }
+                        handle_relay_message(
+                            relay_msg,
+                            received_at,
+                            via,
+                            &mut cache,
+                            &mut sources,
+                            &mut queue,
+                            &events,
+                        );
+                    }
+                    Ok(Some(Msg::Pong(pong))) => {
+                        types.push("pong");
+                        match via {
+                            Via::Bridge(peer) => bridge::pong(peer, &pong),
+                            // The link thread might be gone, then nobody is waiting for it
+                            _ => {
+                                let _ = pongs.send(pong);
+                            }
+                        }
+                    }
+                    Ok(msg) => {
+                        types.push(msg.as_ref().map_or("empty", msg_type_name));
+                        warn!("Received unknown message: {:?}", msg);
+                    }
+                    Err((frame, e)) => {
+                        types.push("failed");
+                        error!("Error decoding frame ({UART_ERRORS}): {:?}", e);
+                        events.emit(GatewayEvent::FrameFailed { frame });
+                    }
+                },
+            );
+            let took = clock.monotonic().saturating_sub(started);
+            LINE_HANDLING.record(took);
+            if took > config.line_budget {
+                warn!(
+                    "Handling a line of {} bytes via {via} took {}, more than {}: {}",
+                    buffer.len(),
+                    format_duration(took),
+                    format_duration(config.line_budget),
+                    types.join(" ")
+                );
+            }
+            if !valid {
+                warn!(
+                    "Received invalid message via {via} ({UART_ERRORS}): {}",
+                    buffer
+                );
+            }
+            drain |= valid;
+            // The UART thread might be gone, then the buffer goes as well
+            let _ = handled.send(buffer);
+        }
+        // Notifications are queued on every pass, not only after a line. A lost beacon is
+        // notified exactly when the lines stop.
+        for notification in queued_notifications.try_iter() {
+            push_event(notification, &mut queue);
+        }
+        // So is the maintenance event, maintenance mustn't wait for a beacon that's gone
+        if maintenance::with(|m| m.take_announcement()) {
+            let timestamp = clock.wall().map_or(0, |t| t.as_secs() as i64);
+            let event = PendingEvent::new(
+                gateway_id.clone(),
+                "maintenance",
+                timestamp,
+                clock.monotonic(),
+            );
+            queue_event(event, &mut queue, &events);
+        }
+        if drain {
+            // Fixes that come in while we back off are queued
+            if clock.monotonic() >= next_upload {
+                if drain_queue(&mut queue, &clock, &events, serializer.as_ref()) {
+                    upload_backoff.reset();
+                } else {
+                    let delay = upload_backoff
+                        .next_delay()
+                        .unwrap_or(config.upload_retry_max_delay);
+                    info!("Uploading again in {}", format_duration(delay));
+                    next_upload = clock.monotonic() + delay;
                 }
-            };
+            }
+            if queue.len() != depth {
+                depth = queue.len();
+                maintenance::QUEUE_DEPTH.store(depth, Ordering::Relaxed);
+                events.emit(GatewayEvent::QueueDepthChanged { depth });
+            }
         }
     }
+    bail!("Nothing left to read lines from")
+}
+
+/// Read lines from the UART and hand them to `lines`, with the time they came in. Buffers are
+/// taken from `reusable` when there are any.
+fn read_lines(
+    uart_driver: uart::UartDriver<'static>,
+    lines: LineSender,
+    reusable: Receiver<String>,
+) -> Result<(), anyhow::Error> {
+    let clock = Clock::new()?;
+    let mut reader = BufReader::new(UartRead::new(uart_driver));
+    loop {
+        let mut buffer = reusable
+            .try_recv()
+            .unwrap_or_else(|_| String::with_capacity(MAX_LINE_LEN));
+        while !read_line_bounded(&mut reader, &mut buffer)? {
+            warn!("Dropping line longer than {MAX_LINE_LEN} bytes ({UART_ERRORS})");
+        }
+        UART_LINES.fetch_add(1, Ordering::Relaxed);
+        let read_at = clock.monotonic();
+        lines.send((read_at, Via::Uart, buffer))?;
+        LINE_HANDOFF.record(clock.monotonic().saturating_sub(read_at));
+    }
+}
+
+/// What we remember about the sources, so we only act on changes.
+struct Sources {
+    // Sources that told us they suspect a GPS fault, so we only upload an event when that starts
+    gps_faults: HashSet<String>,
+    // Sources whose GPS module sends nothing, so we only upload an event when that starts
+    gps_silent: HashSet<String>,
+    // Whether each beacon with a backup battery runs on it, as far as we know
+    beacon_power: HashMap<String, bool>,
+    // Every beacon in range relays a hello, we act on one per source per minute
+    hellos: HelloLimiter,
+    // Traces we're putting together
+    traces: Traces,
+    // Positions for a display, see API_SINK_ROLE
+    smoother: Smoother,
+}
+
+/// Check a fix against the ones we've seen and count it for its source in `metrics`. A fix we
+/// hadn't seen, or a retransmission with news, comes back as the upload of what its source wants
+/// to share. A duplicate comes back as its UID. `replay` validates with its own cache and metrics.
+fn validate_fix<const N: usize>(
+    src: String,
+    timestamp: i64,
+    path_delay_ms: u32,
+    mut gps: GpsMsg,
+    received_at: Duration,
+    cache: &mut DedupCache,
+    metrics: &SourceMetrics<N>,
+) -> Result<PendingUpload, String> {
+    let mac = parse_mac(&src);
+    let count = |event| match mac {
+        Some(mac) => metrics.record(mac, event, received_at),
+        None => metrics.record_other(event),
+    };
+
+    // Check if we have already seen the message by its UID. A retransmission with news is
+    // uploaded again, but it's still the same fix.
+    let seen = cache.check(&gps);
+    if seen == Seen::Duplicate {
+        count(SourceEvent::Duplicate);
+        return Err(gps.uid);
+    }
+    let update = seen == Seen::Update;
+    if !update {
+        count(SourceEvent::Fix {
+            boot_id: gps.boot_id,
+            seq: gps.seq,
+        });
+    }
+    // Checks on the exact position are done, from here on it's what the source wants to share
+    let privacy = Privacy::of(SOURCE_PRIVACY, DEFAULT_PRIVACY, &src);
+    privacy.apply(&mut gps);
+    let test = src == TEST_SOURCE;
+    let mut upload = PendingUpload::new(src, timestamp, received_at, gps);
+    upload.test = test;
+    upload.privacy = privacy;
+    upload.path_delay_ms = path_delay_ms;
+    upload.update = update;
+    Ok(upload)
+}
+
+/// The name of the type of `msg`, for the log.
+fn msg_type_name(msg: &Msg) -> &'static str {
+    MsgType::of(get_message_type(msg)).map_or("unknown", |t| t.name)
 }
 
 // Handle the relay message
 fn handle_relay_message(
     relay_message: morty_rs::messages::RelayMsg,
-    cache: &mut IdCache,
-    led: &mut Led,
-) -> Result<(), anyhow::Error> {
+    received_at: Duration,
+    via: Via,
+    cache: &mut DedupCache,
+    sources: &mut Sources,
+    queue: &mut RetryQueue,
+    events: &Events,
+) {
     match relay_message.msg {
         Some(morty_rs::messages::relay_msg::Msg::Gps(gps)) => {
             info!("Received GPS: {:?}", gps);
+            info!(
+                "Fix from {} via {via} at {} ({})",
+                relay_message.src,
+                format_dm(gps.latitude, gps.longitude),
+                geohash(gps.latitude, gps.longitude, GEOHASH_PRECISION)
+            );
 
-            // Check if we have already seen the message by its UID
-            if !cache.contains(&gps.uid) {
-                let uri = format!(
-                    "https://{API_HOST}/api/v1/source/{}/location",
-                    relay_message.src
-                );
-
-                // Create a json object
-                let json = object! {
-                    "latitude": gps.latitude,
-                    "longitude": gps.longitude,
-                    "hdop": gps.hdop,
-                    "timestamp": relay_message.timestamp,
-                    "utc": gps.utc,
-                    "fix_quality": gps.fix_quality,
-                    "satellites": gps.satellites,
-                    "uid" : gps.uid.to_string(),
-                    "charging": gps.charging,
-                    "battery_voltage": gps.battery_voltage,
+            match validate_fix(
+                relay_message.src,
+                relay_message.timestamp,
+                relay_message.path_delay_ms,
+                gps,
+                received_at,
+                cache,
+                &SOURCE_METRICS,
+            ) {
+                Ok(mut upload) => {
+                    upload.gateway_hint = relay_message.gateway_hint;
+                    // Test fixes are uploaded like any other, but don't count for the source
+                    // alerts. Privacy leaves the fault flag alone.
+                    if !upload.test {
+                        check_gps_fault(
+                            &upload.src,
+                            upload.timestamp,
+                            received_at,
+                            &upload.gps,
+                            &mut sources.gps_faults,
+                            queue,
+                            events,
+                        );
+                        check_gps_silent(
+                            &upload.src,
+                            upload.timestamp,
+                            received_at,
+                            &upload.gps,
+                            &mut sources.gps_silent,
+                            queue,
+                            events,
+                        );
+                    }
+                    events.emit(GatewayEvent::FixValidated {
+                        uid: upload.gps.uid.clone(),
+                        src: upload.src.clone(),
+                        timestamp: upload.timestamp,
+                        gps: upload.gps.clone(),
+                        update: upload.update,
+                    });
+                    // Rounded or suppressed positions don't jitter
+                    if API_SINK_ROLE == SinkRole::Display && upload.privacy == Privacy::Full {
+                        (upload.gps.latitude, upload.gps.longitude) = sources.smoother.smooth(
+                            &upload.src,
+                            upload.gps.latitude,
+                            upload.gps.longitude,
+                            upload.timestamp,
+                        );
+                    }
+                    if let Some(dropped) = queue.push(upload) {
+                        warn!(
+                            "Retry queue full, dropped {} from {}",
+                            dropped.id(),
+                            dropped.src()
+                        );
+                    }
                 }
-                .dump();
-
-                let data = json.as_bytes();
-
-                // Send stuff to the API server over HTTPS
-                let mut client = embedded_svc::http::client::Client::wrap(
-                    esp_idf_svc::http::client::EspHttpConnection::new(
-                        &esp_idf_svc::http::client::Configuration {
-                            crt_bundle_attach: Some(esp_idf_sys::esp_crt_bundle_attach),
-
-                            ..Default::default()
-                        },
-                    )?,
-                );
-
-                let headers = [
-                    ("Content-Type", "application/json"),
-                    ("Content-Length", &format!("{}", data.len())),
-                ];
-
-                let mut request = client.post(&uri, &headers)?;
-                request.connection().write(data)?;
-                let mut response = request.submit()?;
-
-                let mut body = [0_u8; 128];
-                let read = embedded_svc::utils::io::try_read_full(&mut response, &mut body)
-                    .map_err(|err| err.0)?;
-                info!(
-                    "Response: {}",
-                    String::from_utf8_lossy(&body[..read]).into_owned().trim()
+                Err(uid) => events.emit(GatewayEvent::DuplicateDropped { uid }),
+            }
+        }
+        // Beacons send these a couple of times and other beacons relay them, so we only act on
+        // changes
+        Some(morty_rs::messages::relay_msg::Msg::PowerEvent(event)) => {
+            if sources
+                .beacon_power
+                .insert(relay_message.src.clone(), event.on_battery)
+                == Some(event.on_battery)
+            {
+                return;
+            }
+            let name = if event.on_battery {
+                warn!(
+                    "Beacon {} runs on battery ({:.2}V)",
+                    relay_message.src, event.battery_voltage
                 );
-                use embedded_svc::io::Read;
-                // Complete the response
-                while response.read(&mut body)? > 0 {}
-
-                cache.add(&gps.uid);
-                led.blink_color(
-                    colors::PURPLE,
-                    LED_BRIGHTNESS,
-                    Duration::from_millis(300),
-                    2,
-                )?;
+                "power_lost"
             } else {
-                // Blink the LED when it's a duplicate message
-                led.blink_color(
-                    colors::ORANGE,
-                    LED_BRIGHTNESS,
-                    Duration::from_millis(300),
-                    2,
-                )?;
+                info!("Beacon {} has external power again", relay_message.src);
+                "power_restored"
+            };
+            let event = PendingEvent::new(
+                relay_message.src,
+                name,
+                relay_message.timestamp,
+                received_at,
+            );
+            queue_event(event, queue, events);
+        }
+        Some(morty_rs::messages::relay_msg::Msg::TransferAck(ack)) => {
+            assist::log_ack(&relay_message.src, &ack);
+        }
+        Some(morty_rs::messages::relay_msg::Msg::Trace(trace)) => {
+            let Some(records) = sources.traces.add(&relay_message.src, &trace, received_at) else {
+                return;
+            };
+            info!(
+                "Trace of {} with {} wakes",
+                relay_message.src,
+                records.len()
+            );
+            if let Err(e) = trace::upload(&relay_message.src, &records) {
+                error!("Error uploading trace: {:?}", e);
             }
         }
+        Some(morty_rs::messages::relay_msg::Msg::CommandAck(ack)) => {
+            info!("Command ack from {}: {:?}", relay_message.src, ack);
+            if let Err(e) = downlink::upload_ack(&relay_message.src, &ack) {
+                error!("Error uploading command ack: {:?}", e);
+            }
+        }
+        Some(morty_rs::messages::relay_msg::Msg::Hello(hello)) => {
+            if !sources.hellos.allow(&relay_message.src, received_at) {
+                debug!("Ignoring another hello of {}", relay_message.src);
+                return;
+            }
+            let device = Device {
+                src: relay_message.src,
+                timestamp: relay_message.timestamp,
+                hello,
+            };
+            info!("Booted: {device}");
+            if let Err(e) = devices::upload(&device.src, device.timestamp, &device.hello) {
+                error!("Error uploading hello: {:?}", e);
+            }
+            events.emit(GatewayEvent::HelloReceived { device });
+        }
+        // Beacons pass on the clocks of the other beacons they hear, see `morty_rs::timesync`
+        Some(morty_rs::messages::relay_msg::Msg::BeaconPresent(BeaconPresentMsg {
+            clock: Some(clock),
+            ..
+        })) => {
+            events.emit(GatewayEvent::ClockReported {
+                src: relay_message.src,
+                clock,
+            });
+        }
         _ => {
             warn!("Received unknown message: {:?}", relay_message);
         }
     }
-    Ok(())
 }
 
-fn update_sntp() -> Result<(), anyhow::Error> {
-    let sntp = esp_idf_svc::sntp::EspSntp::new_default()?;
-    while sntp.get_sync_status() != SyncStatus::Completed {
-        info!("Waiting for SNTP to sync");
-        std::thread::sleep(Duration::from_secs(1));
+/// Queue an event when a source starts suspecting a GPS fault. The flag is set on every report
+/// until the next fix, so we remember which sources we already reported.
+fn check_gps_fault(
+    src: &str,
+    timestamp: i64,
+    received_at: Duration,
+    gps: &GpsMsg,
+    gps_faults: &mut HashSet<String>,
+    queue: &mut RetryQueue,
+    events: &Events,
+) {
+    if !gps.gps_fault_suspected {
+        if gps_faults.remove(src) {
+            info!("{src} has a fix again");
+        }
+        return;
+    }
+    if gps_faults.insert(src.to_string()) {
+        warn!("{src} suspects a GPS fault");
+        let event = PendingEvent::new(
+            src.to_string(),
+            "gps_fault_suspected",
+            timestamp,
+            received_at,
+        );
+        queue_event(event, queue, events);
     }
-    let now = EspSystemTime.now();
-    info!("Current time: {:?}", now);
-    Ok(())
 }
 
-struct IdCache {
-    data: VecDeque<String>,
-    size: usize,
+/// Queue an event when the GPS module of a source stops sending anything, which is a fault of the
+/// device rather than a report without a fix, see `morty_rs::gps_state`. Trackers that don't
+/// report the state of their GPS module never trigger it.
+fn check_gps_silent(
+    src: &str,
+    timestamp: i64,
+    received_at: Duration,
+    gps: &GpsMsg,
+    gps_silent: &mut HashSet<String>,
+    queue: &mut RetryQueue,
+    events: &Events,
+) {
+    if gps.gps_state() != GpsState::NoData {
+        if gps_silent.remove(src) {
+            info!("{src} hears its GPS module again");
+        }
+        return;
+    }
+    if gps_silent.insert(src.to_string()) {
+        warn!("{src} gets no data from its GPS module");
+        let event = PendingEvent::new(src.to_string(), "gps_no_data", timestamp, received_at);
+        queue_event(event, queue, events);
+    }
 }
 
-impl IdCache {
-    pub fn new(size: usize) -> Self {
-        Self {
-            data: VecDeque::new(),
-            size,
-        }
+/// Queue an event of a source for upload, and let the subscribers know.
+fn queue_event(event: PendingEvent, queue: &mut RetryQueue, events: &Events) {
+    events.emit(GatewayEvent::EventQueued {
+        src: event.src.clone(),
+        name: event.name,
+        timestamp: event.timestamp,
+    });
+    push_event(event, queue);
+}
+
+fn push_event(event: PendingEvent, queue: &mut RetryQueue) {
+    if let Some(dropped) = queue.push_event(event) {
+        warn!(
+            "Retry queue full, dropped {} from {}",
+            dropped.id(),
+            dropped.src()
+        );
     }
+}
 
-    fn add(&mut self, data: &str) {
-        self.data.push_back(data.to_string());
-        if self.data.len() > self.size {
-            self.data.pop_front();
+/// Upload everything in the retry queue, events first and then fixes in the order determined by
+/// its drain policy. We stop at the first failed upload and leave the rest for the next time
+/// around. Every attempt at a fix and every expired upload is emitted as an event. Returns false
+/// when an upload failed.
+fn drain_queue(
+    queue: &mut RetryQueue,
+    clock: &Clock,
+    events: &Events,
+    serializer: &dyn Serializer,
+) -> bool {
+    let on_expired = |expired: Expired| {
+        info!(
+            "Dropping {} {} from {}, it was queued {}s ago",
+            expired.class,
+            expired.id,
+            expired.src,
+            expired.age.as_secs()
+        );
+        events.emit(GatewayEvent::UploadExpired {
+            class: expired.class,
+            id: expired.id,
+            src: expired.src,
+            age: expired.age,
+        });
+    };
+    while let Some(pending) = queue.pop(clock.monotonic(), on_expired) {
+        let mut upload = match pending {
+            Pending::Fix(upload) => upload,
+            Pending::Event(mut event) => {
+                event.attempts += 1;
+                let result = match &event.sink {
+                    Sink::Backend => upload_event(&event.src, event.name, event.timestamp),
+                    Sink::Webhook(body) => notify::post(body).map(|_| ()),
+                };
+                if let Err(e) = result {
+                    error!(
+                        "Error uploading {} event of {} (attempt {}): {:?}",
+                        event.name, event.src, event.attempts, e
+                    );
+                    queue.requeue(Pending::Event(event));
+                    return false;
+                }
+                continue;
+            }
+        };
+        upload.attempts += 1;
+        if upload.received.is_none() {
+            upload.received = clock
+                .to_wall(upload.received_at)
+                .map(|t| t.as_secs() as i64);
+        }
+        if upload.attempts == 1 {
+            if let Some((earlier, later)) = upload.times().out_of_order() {
+                warn!(
+                    "Times of {} from {} are out of order, {later} before {earlier}: {:?}",
+                    upload.gps.uid,
+                    upload.src,
+                    upload.times()
+                );
+            }
+        }
+
+        let now = clock.wall().map(|t| t.as_secs() as i64);
+        match MAX_FIX_AGE.check(upload.timestamp, upload.received, now, upload.backfill) {
+            Verdict::Stale(StaleAction::Drop, age) => {
+                info!(
+                    "Dropping {} from {}, it is {}s old",
+                    upload.gps.uid,
+                    upload.src,
+                    age.as_secs()
+                );
+                events.emit(GatewayEvent::StaleDropped {
+                    uid: upload.gps.uid,
+                    src: upload.src,
+                    age,
+                });
+                continue;
+            }
+            Verdict::Stale(StaleAction::Flag, _) => upload.stale = true,
+            Verdict::Fresh | Verdict::Unknown => {}
+        }
+
+        let idempotency_key = upload_key(&upload);
+        match upload_fix(&upload, serializer, &idempotency_key) {
+            Ok((status, body_hash)) => {
+                events.emit(GatewayEvent::UploadSucceeded {
+                    uid: upload.gps.uid,
+                    src: upload.src,
+                    attempt: upload.attempts,
+                    idempotency_key,
+                    status,
+                    body_hash,
+                });
+            }
+            Err(e) => {
+                events.emit(GatewayEvent::UploadFailed {
+                    uid: upload.gps.uid.clone(),
+                    src: upload.src.clone(),
+                    attempt: upload.attempts,
+                    idempotency_key,
+                    proxy: api::is_proxy_error(&e),
+                    certificate: api::certificate_failure(&e),
+                    reason: format!("{:?}", e),
+                });
+                queue.requeue(Pending::Fix(upload));
+                return false;
+            }
         }
     }
+    true
+}
+
+/// Send a fix to the API server over HTTPS, with `idempotency_key`, see `serializer::upload_key`.
+/// Returns the HTTP status and a hash of the response body.
+fn upload_fix(
+    upload: &PendingUpload,
+    serializer: &dyn Serializer,
+    idempotency_key: &str,
+) -> Result<(u16, u64), anyhow::Error> {
+    let uri = format!(
+        "https://{}/api/v1/source/{}/location",
+        config().api_host,
+        upload.src
+    );
+
+    let data = serializer.serialize(upload);
+    let response = ApiClient::new(PROXY)
+        .with_gzip(config().gzip_threshold)
+        .with_idempotency_key(idempotency_key)
+        .post(&uri, serializer.content_type(), &data)?;
+    info!(
+        "Response: {} {}",
+        response.status,
+        String::from_utf8_lossy(&response.body).into_owned().trim()
+    );
 
-    fn contains(&self, data: &str) -> bool {
-        self.data.contains(&data.to_string())
+    Ok((response.status, audit::body_hash(&response.body)))
+}
+
+/// Post an event for a source to the API server.
+fn upload_event(src: &str, event: &str, timestamp: i64) -> Result<(), anyhow::Error> {
+    let uri = format!("https://{}/api/v1/source/{src}/event", config().api_host);
+    let key = idempotency_key(&[src.as_bytes(), event.as_bytes(), &timestamp.to_le_bytes()]);
+    let data = json::object! {
+        "event": event,
+        "timestamp": timestamp,
+        "idempotency_key": key.as_str(),
     }
+    .dump();
+
+    let response = ApiClient::new(PROXY)
+        .with_gzip(config().gzip_threshold)
+        .with_idempotency_key(&key)
+        .post(&uri, "application/json", data.as_bytes())?;
+    info!("Event response: {}", response.status);
+    Ok(())
 }
@@ -0,0 +1,352 @@
+//! Maintenance mode, for a planned power-down, e.g. to move the gateway. `maintenance` on the
+//! console starts it and `maintenance off` goes back to work without a reboot. In between:
+//!
+//! 1. Pausing: the pings tell the beacon on the UART that we're paused, see `link`. It answers
+//!    with a pong that says it holds on to its relays from then on, and writes them once a ping
+//!    says we're no longer paused. A `maintenance` event for the gateway is queued for upload.
+//! 2. Draining: relays are refused, what comes in anyway, e.g. over a bridge or ESP-NOW, is
+//!    counted. The retry queue is uploaded.
+//! 3. Safe: the queue is empty, or didn't get there in time, and everything kept in NVS was
+//!    written, see `GatewayEvent::ShuttingDown`. The LED is steady white, it's safe to unplug.
+//!
+//! A beacon that doesn't answer in time doesn't keep us from draining. The link thread moves on
+//! with the timeouts when nothing comes back, and the pipeline queues the event and drains the
+//! queue every DRAIN_INTERVAL, also when no lines come in.
+
+use log::*;
+use std::fmt;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Relays we refused since boot, because we were in maintenance.
+pub static REFUSED: AtomicU32 = AtomicU32::new(0);
+/// Uploads in the retry queue, as the pipeline last saw it.
+pub static QUEUE_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// Where the gateway is in maintenance mode, see the module docs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    Running,
+    Pausing,
+    Draining,
+    Safe,
+}
+
+impl Phase {
+    /// Whether the pings should tell the beacon that we're paused.
+    pub fn paused(&self) -> bool {
+        *self != Phase::Running
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Phase::Running => "running",
+            Phase::Pausing => "pausing",
+            Phase::Draining => "draining",
+            Phase::Safe => "safe to unplug",
+        })
+    }
+}
+
+/// The phases of maintenance mode. This doesn't know about the UART or the queue, the caller
+/// passes in what happened. All times are monotonic.
+pub struct Maintenance {
+    pause_timeout: Duration,
+    drain_timeout: Duration,
+    phase: Phase,
+    // When the current phase started
+    since: Duration,
+    // The maintenance event was queued for upload
+    announced: bool,
+}
+
+impl Maintenance {
+    pub const fn new(pause_timeout: Duration, drain_timeout: Duration) -> Self {
+        Self {
+            pause_timeout,
+            drain_timeout,
+            phase: Phase::Running,
+            since: Duration::ZERO,
+            announced: false,
+        }
+    }
+
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    /// Start maintenance at `now`. Returns false when we already were in maintenance.
+    pub fn enter(&mut self, now: Duration) -> bool {
+        if self.phase != Phase::Running {
+            return false;
+        }
+        self.set(Phase::Pausing, now);
+        self.announced = false;
+        true
+    }
+
+    /// Go back to work at `now`. Returns false when we weren't in maintenance.
+    pub fn resume(&mut self, now: Duration) -> bool {
+        if self.phase == Phase::Running {
+            return false;
+        }
+        self.set(Phase::Running, now);
+        true
+    }
+
+    /// Whether relays are handled. While pausing they still are, the beacon might have written
+    /// them before it knew.
+    pub fn accepts_relays(&self) -> bool {
+        matches!(self.phase, Phase::Running | Phase::Pausing)
+    }
+
+    /// Whether the maintenance event should be queued now. Returns true once per maintenance.
+    pub fn take_announcement(&mut self) -> bool {
+        if self.phase == Phase::Running || self.announced {
+            return false;
+        }
+        self.announced = true;
+        true
+    }
+
+    /// The beacon answered at `now` that it holds on to its relays.
+    pub fn beacon_paused(&mut self, now: Duration) -> Option<Phase> {
+        if self.phase != Phase::Pausing {
+            return None;
+        }
+        self.set(Phase::Draining, now);
+        Some(self.phase)
+    }
+
+    /// Move on at `now` when a phase is over, with `queued` uploads left in the retry queue.
+    /// Returns the new phase, if any.
+    pub fn poll(&mut self, now: Duration, queued: usize) -> Option<Phase> {
+        let elapsed = now.saturating_sub(self.since);
+        match self.phase {
+            Phase::Pausing if elapsed >= self.pause_timeout => {
+                warn!("Beacon didn't confirm the pause, draining anyway");
+                self.set(Phase::Draining, now);
+            }
+            Phase::Draining if self.announced && queued == 0 => self.set(Phase::Safe, now),
+            Phase::Draining if elapsed >= self.drain_timeout => {
+                warn!("Retry queue didn't drain in time, {queued} uploads are lost at power-down");
+                self.set(Phase::Safe, now);
+            }
+            _ => return None,
+        }
+        Some(self.phase)
+    }
+
+    fn set(&mut self, phase: Phase, now: Duration) {
+        info!("Maintenance: {phase}");
+        self.phase = phase;
+        self.since = now;
+    }
+}
+
+// The gateway's maintenance mode, shared by the console, the link thread and the pipeline
+static MAINTENANCE: Mutex<Maintenance> = Mutex::new(Maintenance::new(
+    crate::MAINTENANCE_PAUSE_TIMEOUT,
+    crate::MAINTENANCE_DRAIN_TIMEOUT,
+));
+
+/// Run `f` with the gateway's maintenance mode.
+pub fn with<T>(f: impl FnOnce(&mut Maintenance) -> T) -> T {
+    f(&mut MAINTENANCE.lock().unwrap())
+}
+
+pub fn phase() -> Phase {
+    with(|m| m.phase())
+}
+
+pub fn paused() -> bool {
+    phase().paused()
+}
+
+/// Whether the pipeline handles relays, see `Maintenance::accepts_relays`. Counts the ones that
+/// are refused.
+pub fn accept_relay() -> bool {
+    let accepts = with(|m| m.accepts_relays());
+    if !accepts {
+        REFUSED.fetch_add(1, Ordering::Relaxed);
+    }
+    accepts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAUSE: Duration = Duration::from_secs(30);
+    const DRAIN: Duration = Duration::from_secs(120);
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    // In maintenance since 100 s, announced
+    fn entered() -> Maintenance {
+        let mut maintenance = Maintenance::new(PAUSE, DRAIN);
+        assert!(maintenance.enter(secs(100)));
+        assert!(maintenance.take_announcement());
+        maintenance
+    }
+
+    // Entered, and the beacon confirmed the pause at 110 s
+    fn draining() -> Maintenance {
+        let mut maintenance = entered();
+        assert_eq!(maintenance.beacon_paused(secs(110)), Some(Phase::Draining));
+        maintenance
+    }
+
+    #[test]
+    fn only_running_isnt_paused() {
+        assert!(!Phase::Running.paused());
+        for phase in [Phase::Pausing, Phase::Draining, Phase::Safe] {
+            assert!(phase.paused(), "{phase}");
+        }
+        assert_eq!(Phase::Safe.to_string(), "safe to unplug");
+    }
+
+    #[test]
+    fn a_gateway_runs_until_maintenance_starts() {
+        let mut maintenance = Maintenance::new(PAUSE, DRAIN);
+        assert_eq!(maintenance.phase(), Phase::Running);
+        assert!(maintenance.accepts_relays());
+        assert!(!maintenance.take_announcement());
+        assert_eq!(maintenance.poll(secs(1000), 0), None);
+        assert_eq!(maintenance.beacon_paused(secs(1000)), None);
+        assert!(!maintenance.resume(secs(1000)));
+    }
+
+    #[test]
+    fn maintenance_pauses_drains_and_becomes_safe() {
+        let mut maintenance = Maintenance::new(PAUSE, DRAIN);
+        assert!(maintenance.enter(secs(100)));
+        assert_eq!(maintenance.phase(), Phase::Pausing);
+        // The beacon may have written relays before it knew
+        assert!(maintenance.accepts_relays());
+        assert!(maintenance.take_announcement());
+        assert!(!maintenance.take_announcement());
+        assert_eq!(maintenance.poll(secs(101), 3), None);
+
+        assert_eq!(maintenance.beacon_paused(secs(110)), Some(Phase::Draining));
+        assert!(!maintenance.accepts_relays());
+        assert_eq!(maintenance.poll(secs(111), 3), None);
+        assert_eq!(maintenance.poll(secs(112), 1), None);
+        assert_eq!(maintenance.poll(secs(113), 0), Some(Phase::Safe));
+        assert!(!maintenance.accepts_relays());
+        // Nothing happens after that
+        assert_eq!(maintenance.poll(secs(1000), 0), None);
+        assert_eq!(maintenance.beacon_paused(secs(1000)), None);
+        assert_eq!(maintenance.phase(), Phase::Safe);
+    }
+
+    #[test]
+    fn maintenance_starts_once() {
+        let mut maintenance = draining();
+        assert!(!maintenance.enter(secs(200)));
+        assert_eq!(maintenance.phase(), Phase::Draining);
+        assert!(!maintenance.take_announcement());
+    }
+
+    #[test]
+    fn a_beacon_that_doesnt_answer_doesnt_keep_us_from_draining() {
+        let mut maintenance = entered();
+        assert_eq!(maintenance.poll(secs(129), 0), None);
+        assert_eq!(maintenance.poll(secs(130), 0), Some(Phase::Draining));
+        // The drain has a timeout of its own from here
+        assert_eq!(maintenance.poll(secs(131), 0), Some(Phase::Safe));
+    }
+
+    #[test]
+    fn the_beacon_confirming_again_changes_nothing() {
+        let mut maintenance = draining();
+        assert_eq!(maintenance.beacon_paused(secs(115)), None);
+        assert_eq!(maintenance.phase(), Phase::Draining);
+    }
+
+    #[test]
+    fn a_queue_that_doesnt_drain_becomes_safe_after_a_while() {
+        let mut maintenance = draining();
+        assert_eq!(maintenance.poll(secs(229), 5), None);
+        assert_eq!(maintenance.poll(secs(230), 5), Some(Phase::Safe));
+    }
+
+    #[test]
+    fn the_maintenance_event_is_queued_before_it_is_safe() {
+        let mut maintenance = Maintenance::new(PAUSE, DRAIN);
+        maintenance.enter(secs(100));
+        maintenance.beacon_paused(secs(110));
+        // The queue is empty, but the event isn't in it yet
+        assert_eq!(maintenance.poll(secs(111), 0), None);
+        assert!(maintenance.take_announcement());
+        assert_eq!(maintenance.poll(secs(112), 1), None);
+        assert_eq!(maintenance.poll(secs(113), 0), Some(Phase::Safe));
+    }
+
+    #[test]
+    fn maintenance_can_be_left_in_every_phase() {
+        for phase in [Phase::Pausing, Phase::Draining, Phase::Safe] {
+            let mut maintenance = entered();
+            if phase != Phase::Pausing {
+                maintenance.beacon_paused(secs(110));
+            }
+            if phase == Phase::Safe {
+                maintenance.poll(secs(111), 0);
+            }
+            assert_eq!(maintenance.phase(), phase);
+            assert!(maintenance.resume(secs(200)), "{phase}");
+            assert_eq!(maintenance.phase(), Phase::Running);
+            assert!(maintenance.accepts_relays());
+            assert_eq!(maintenance.poll(secs(1000), 0), None);
+        }
+    }
+
+    #[test]
+    fn maintenance_can_start_again_after_resuming() {
+        let mut maintenance = draining();
+        maintenance.resume(secs(200));
+        assert!(maintenance.enter(secs(300)));
+        assert_eq!(maintenance.phase(), Phase::Pausing);
+        // Announced again, and timed from the new start
+        assert!(maintenance.take_announcement());
+        assert_eq!(maintenance.poll(secs(329), 0), None);
+        assert_eq!(maintenance.poll(secs(330), 0), Some(Phase::Draining));
+    }
+
+    #[test]
+    fn maintenance_without_a_beacon_or_lines_still_announces_and_drains() {
+        let mut maintenance = Maintenance::new(PAUSE, DRAIN);
+        let mut queued = 3;
+        let mut uploaded = 0;
+        let start = secs(100);
+        assert!(maintenance.enter(start));
+        let mut now = start;
+        let safe_at = loop {
+            now += crate::DRAIN_INTERVAL;
+            assert!(now <= start + PAUSE + DRAIN, "never safe");
+            // The pipeline on its timer, as no lines come in, and the uploads go through
+            if maintenance.take_announcement() {
+                queued += 1;
+            }
+            uploaded += queued;
+            queued = 0;
+            // The link thread, without pongs
+            if maintenance.poll(now, queued) == Some(Phase::Safe) {
+                break now;
+            }
+        };
+        // The queue and the maintenance event went out, rather than the drain timing out
+        assert_eq!(uploaded, 4);
+        assert!(
+            safe_at <= start + PAUSE + crate::DRAIN_INTERVAL,
+            "{safe_at:?}"
+        );
+    }
+}
@@ -0,0 +1,449 @@
+//! JSON payloads for third-party backends that want other field names or units than ours. The
+//! payload is described by a list of (target field, source field) pairs, so adding a backend is a
+//! matter of writing down its fields. A source field can have a transform that converts its unit,
+//! e.g. `("batt", "battery_voltage|percent")`. Mappings are checked when the gateway starts.
+
+use crate::privacy::Privacy;
+use crate::queue::PendingUpload;
+use crate::serializer::Serializer;
+use crate::GEOHASH_PRECISION;
+use anyhow::anyhow;
+use anyhow::bail;
+use json::JsonValue;
+use morty_rs::battery::estimate_soc;
+use morty_rs::geo::geohash;
+use morty_rs::messages::GpsMsg;
+
+/// A target field and the source field (and optional transform) it's filled from.
+pub type FieldMapping = (&'static str, &'static str);
+
+/// Traccar's OsmAnd protocol.
+#[allow(dead_code)]
+pub const OSMAND: &[FieldMapping] = &[
+    ("id", "src"),
+    ("lat", "latitude"),
+    ("lon", "longitude"),
+    ("timestamp", "timestamp"),
+    ("hdop", "hdop"),
+    ("batt", "battery_voltage|percent"),
+    ("charge", "charging"),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Field {
+    Src,
+    Latitude,
+    Longitude,
+    Hdop,
+    Timestamp,
+    Received,
+    Utc,
+    FixEpoch,
+    SentEpoch,
+    RelayEpoch,
+    GatewayEpoch,
+    FixQuality,
+    Satellites,
+    Uid,
+    Charging,
+    BatteryVoltage,
+    TxPower,
+    BootId,
+    Seq,
+    GpsFaultSuspected,
+    Profile,
+    ConfigVersion,
+    Backfill,
+    PathDelayMs,
+    Geohash,
+    Test,
+    Stale,
+    Privacy,
+    UpdateOf,
+}
+
+impl Field {
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "src" => Field::Src,
+            "latitude" => Field::Latitude,
+            "longitude" => Field::Longitude,
+            "hdop" => Field::Hdop,
+            "timestamp" => Field::Timestamp,
+            "received" => Field::Received,
+            "utc" => Field::Utc,
+            "fix_epoch" => Field::FixEpoch,
+            "sent_epoch" => Field::SentEpoch,
+            "relay_epoch" => Field::RelayEpoch,
+            "gateway_epoch" => Field::GatewayEpoch,
+            "fix_quality" => Field::FixQuality,
+            "satellites" => Field::Satellites,
+            "uid" => Field::Uid,
+            "charging" => Field::Charging,
+            "battery_voltage" => Field::BatteryVoltage,
+            "tx_power" => Field::TxPower,
+            "boot_id" => Field::BootId,
+            "seq" => Field::Seq,
+            "gps_fault_suspected" => Field::GpsFaultSuspected,
+            "profile" => Field::Profile,
+            "config_version" => Field::ConfigVersion,
+            "backfill" => Field::Backfill,
+            "path_delay_ms" => Field::PathDelayMs,
+            "geohash" => Field::Geohash,
+            "test" => Field::Test,
+            "stale" => Field::Stale,
+            "privacy" => Field::Privacy,
+            "update_of" => Field::UpdateOf,
+            _ => return None,
+        })
+    }
+
+    fn value(&self, upload: &PendingUpload) -> JsonValue {
+        let gps = &upload.gps;
+        let suppressed = upload.privacy == Privacy::Suppressed;
+        match self {
+            Field::Src => upload.src.as_str().into(),
+            Field::Latitude | Field::Longitude if suppressed => JsonValue::Null,
+            Field::Latitude => gps.latitude.into(),
+            Field::Longitude => gps.longitude.into(),
+            Field::Hdop => gps.hdop.into(),
+            Field::Timestamp => upload.timestamp.into(),
+            Field::Received => upload.received.into(),
+            Field::Utc => gps.utc.into(),
+            Field::FixEpoch => upload.times().fix.into(),
+            Field::SentEpoch => upload.times().sent.into(),
+            Field::RelayEpoch => upload.times().relay.into(),
+            Field::GatewayEpoch => upload.times().gateway.into(),
+            Field::FixQuality => gps.fix_quality.into(),
+            Field::Satellites => gps.satellites.into(),
+            Field::Uid => gps.uid.as_str().into(),
+            Field::Charging => gps.charging.into(),
+            Field::BatteryVoltage => gps.battery_voltage.into(),
+            Field::TxPower => gps.tx_power.into(),
+            Field::BootId => gps.boot_id.into(),
+            Field::Seq => gps.seq.into(),
+            Field::GpsFaultSuspected => gps.gps_fault_suspected.into(),
+            Field::Profile => gps.profile.as_str().into(),
+            Field::ConfigVersion => gps.config_version.into(),
+            Field::Backfill => upload.backfill.into(),
+            Field::PathDelayMs => upload.path_delay_ms.into(),
+            // Only when we have an actual fix
+            Field::Geohash if gps.fix_quality > 0 => {
+                geohash(gps.latitude, gps.longitude, GEOHASH_PRECISION).into()
+            }
+            Field::Geohash => JsonValue::Null,
+            Field::Test => upload.test.into(),
+            Field::Stale => upload.stale.into(),
+            Field::Privacy => upload.privacy.name().into(),
+            Field::UpdateOf if upload.update => gps.uid.as_str().into(),
+            Field::UpdateOf => JsonValue::Null,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Transform {
+    /// Battery voltage to a charge percentage
+    Percent,
+    /// Seconds since the epoch to an ISO 8601 UTC timestamp
+    Iso8601,
+}
+
+impl Transform {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "percent" => Some(Transform::Percent),
+            "iso8601" => Some(Transform::Iso8601),
+            _ => None,
+        }
+    }
+
+    fn applies_to(&self, field: Field) -> bool {
+        match self {
+            Transform::Percent => field == Field::BatteryVoltage,
+            Transform::Iso8601 => matches!(
+                field,
+                Field::Timestamp
+                    | Field::Received
+                    | Field::FixEpoch
+                    | Field::SentEpoch
+                    | Field::RelayEpoch
+                    | Field::GatewayEpoch
+            ),
+        }
+    }
+
+    fn apply(&self, value: JsonValue, upload: &PendingUpload) -> JsonValue {
+        match self {
+            // The percentage the tracker estimated, rather than the voltage
+            Transform::Percent => match value.as_f32() {
+                Some(_) => battery_percent(&upload.gps).into(),
+                None => JsonValue::Null,
+            },
+            Transform::Iso8601 => match value.as_i64() {
+                Some(secs) => iso8601(secs).into(),
+                None => JsonValue::Null,
+            },
+        }
+    }
+}
+
+struct Mapping {
+    target: &'static str,
+    field: Field,
+    transform: Option<Transform>,
+}
+
+/// Serializes uploads to a JSON object with the fields of a mapping.
+pub struct MappedJsonSerializer {
+    mappings: Vec<Mapping>,
+}
+
+impl MappedJsonSerializer {
+    /// Check `fields` and build a serializer for it. Fails on unknown source fields and
+    /// transforms, transforms that don't fit their field, and duplicate targets.
+    pub fn new(fields: &[FieldMapping]) -> Result<Self, anyhow::Error> {
+        let mut mappings: Vec<Mapping> = Vec::new();
+        for &(target, source) in fields {
+            if mappings.iter().any(|m| m.target == target) {
+                bail!("Field mapping: duplicate target field \"{target}\"");
+            }
+            let (name, transform) = match source.split_once('|') {
+                Some((name, transform)) => (name, Some(transform)),
+                None => (source, None),
+            };
+            let field = Field::parse(name).ok_or_else(|| {
+                anyhow!("Field mapping: unknown source field \"{name}\" for \"{target}\"")
+            })?;
+            let transform = match transform {
+                Some(t) => {
+                    let transform = Transform::parse(t).ok_or_else(|| {
+                        anyhow!("Field mapping: unknown transform \"{t}\" for \"{target}\"")
+                    })?;
+                    if !transform.applies_to(field) {
+                        bail!("Field mapping: transform \"{t}\" doesn't apply to \"{name}\"");
+                    }
+                    Some(transform)
+                }
+                None => None,
+            };
+            mappings.push(Mapping {
+                target,
+                field,
+                transform,
+            });
+        }
+        Ok(Self { mappings })
+    }
+}
+
+impl Serializer for MappedJsonSerializer {
+    fn content_type(&self) -> &'static str {
+        "application/json"
+    }
+
+    fn serialize(&self, upload: &PendingUpload) -> Vec<u8> {
+        let mut json = JsonValue::new_object();
+        for m in &self.mappings {
+            let value = m.field.value(upload);
+            json[m.target] = match m.transform {
+                Some(transform) => transform.apply(value, upload),
+                None => value,
+            };
+        }
+        json.dump().into_bytes()
+    }
+}
+
+/// Charge of the battery of a fix in whole percents, as the tracker estimated it. Trackers that
+/// don't estimate it get the estimate without their calibration, which for a flat battery is about
+/// the 0 they report.
+pub fn battery_percent(gps: &GpsMsg) -> u8 {
+    if gps.battery_percent > 0 {
+        return gps.battery_percent.min(100) as u8;
+    }
+    estimate_soc(gps.battery_voltage, gps.charging, 0.0)
+}
+
+/// `secs` since the epoch as an ISO 8601 UTC timestamp, e.g. "2023-04-01T12:00:00Z".
+pub fn iso8601(secs: i64) -> String {
+    let days = secs.div_euclid(86400);
+    let time = secs.rem_euclid(86400);
+
+    // Civil date from days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    // 2023-04-01T12:00:00Z
+    const TIMESTAMP: i64 = 1_680_350_400;
+
+    fn upload() -> PendingUpload {
+        let gps = GpsMsg {
+            latitude: 52.375,
+            longitude: 4.875,
+            fix_quality: 1,
+            hdop: 0.5,
+            battery_voltage: 4.0,
+            battery_percent: 80,
+            charging: true,
+            ..Default::default()
+        };
+        PendingUpload::new(
+            "aa:bb:cc:dd:ee:ff".to_string(),
+            TIMESTAMP,
+            Duration::ZERO,
+            gps,
+        )
+    }
+
+    fn payload(fields: &[FieldMapping], upload: &PendingUpload) -> JsonValue {
+        let serializer = MappedJsonSerializer::new(fields).unwrap();
+        json::parse(std::str::from_utf8(&serializer.serialize(upload)).unwrap()).unwrap()
+    }
+
+    fn error(fields: &[FieldMapping]) -> String {
+        match MappedJsonSerializer::new(fields) {
+            Ok(_) => panic!("{fields:?} checked out"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn osmand_gets_its_own_names_and_units() {
+        let json = payload(OSMAND, &upload());
+        assert_eq!(json.len(), OSMAND.len());
+        assert_eq!(json["id"], "aa:bb:cc:dd:ee:ff");
+        assert_eq!(json["lat"].as_f64(), Some(52.375));
+        assert_eq!(json["lon"].as_f64(), Some(4.875));
+        assert_eq!(json["timestamp"].as_i64(), Some(TIMESTAMP));
+        assert_eq!(json["hdop"].as_f32(), Some(0.5));
+        assert_eq!(json["batt"].as_u8(), Some(80));
+        assert_eq!(json["charge"].as_bool(), Some(true));
+    }
+
+    #[test]
+    fn timestamps_can_be_iso8601() {
+        let json = payload(&[("time", "timestamp|iso8601")], &upload());
+        assert_eq!(json["time"], "2023-04-01T12:00:00Z");
+        // Unknown times stay unknown
+        let json = payload(&[("received", "received|iso8601")], &upload());
+        assert!(json["received"].is_null());
+    }
+
+    #[test]
+    fn suppressed_positions_stay_suppressed() {
+        let mut upload = upload();
+        upload.privacy = Privacy::Suppressed;
+        let json = payload(&[("lat", "latitude"), ("lon", "longitude")], &upload);
+        assert!(json["lat"].is_null());
+        assert!(json["lon"].is_null());
+    }
+
+    #[test]
+    fn geohashes_need_a_fix() {
+        let mut upload = upload();
+        let json = payload(&[("hash", "geohash")], &upload);
+        assert_eq!(json["hash"].as_str().map(str::len), Some(GEOHASH_PRECISION));
+        upload.gps.fix_quality = 0;
+        let json = payload(&[("hash", "geohash")], &upload);
+        assert!(json["hash"].is_null());
+    }
+
+    #[test]
+    fn unknown_source_fields_are_refused() {
+        assert_eq!(
+            error(&[("lat", "lattitude")]),
+            "Field mapping: unknown source field \"lattitude\" for \"lat\""
+        );
+    }
+
+    #[test]
+    fn unknown_transforms_are_refused() {
+        assert_eq!(
+            error(&[("batt", "battery_voltage|percentage")]),
+            "Field mapping: unknown transform \"percentage\" for \"batt\""
+        );
+    }
+
+    #[test]
+    fn transforms_have_to_fit_their_field() {
+        assert_eq!(
+            error(&[("lat", "latitude|iso8601")]),
+            "Field mapping: transform \"iso8601\" doesn't apply to \"latitude\""
+        );
+        assert_eq!(
+            error(&[("time", "timestamp|percent")]),
+            "Field mapping: transform \"percent\" doesn't apply to \"timestamp\""
+        );
+    }
+
+    #[test]
+    fn targets_are_filled_once() {
+        assert_eq!(
+            error(&[("t", "timestamp"), ("t", "received")]),
+            "Field mapping: duplicate target field \"t\""
+        );
+    }
+
+    #[test]
+    fn the_tracker_estimate_is_the_battery_percentage() {
+        let mut gps = upload().gps;
+        assert_eq!(battery_percent(&gps), 80);
+        gps.battery_percent = 150;
+        assert_eq!(battery_percent(&gps), 100);
+    }
+
+    #[test]
+    fn iso8601_matches_reference_dates() {
+        assert_eq!(iso8601(0), "1970-01-01T00:00:00Z");
+        assert_eq!(iso8601(-1), "1969-12-31T23:59:59Z");
+        assert_eq!(iso8601(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(iso8601(1_709_251_199), "2024-02-29T23:59:59Z");
+        assert_eq!(iso8601(4_102_444_800), "2100-01-01T00:00:00Z");
+    }
+
+    #[test]
+    fn every_time_of_a_fix_can_be_mapped() {
+        let mut upload = upload();
+        upload.gps.fix_epoch = TIMESTAMP - 10;
+        upload.gps.sent_epoch = TIMESTAMP - 5;
+        upload.received = Some(TIMESTAMP + 1);
+        let json = payload(
+            &[
+                ("measured", "fix_epoch|iso8601"),
+                ("sent", "sent_epoch"),
+                ("relayed", "relay_epoch|iso8601"),
+                ("received", "gateway_epoch"),
+            ],
+            &upload,
+        );
+        assert_eq!(json["measured"], "2023-04-01T11:59:50Z");
+        assert_eq!(json["sent"].as_i64(), Some(TIMESTAMP - 5));
+        assert_eq!(json["relayed"], "2023-04-01T12:00:00Z");
+        assert_eq!(json["received"].as_i64(), Some(TIMESTAMP + 1));
+        // Unknown ones are null
+        upload.gps.fix_epoch = 0;
+        let json = payload(&[("measured", "fix_epoch|iso8601")], &upload);
+        assert!(json["measured"].is_null());
+    }
+}
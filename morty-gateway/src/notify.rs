@@ -0,0 +1,838 @@
+//! Notifications of critical events, posted to a webhook so they reach someone right away rather
+//! than just ending up in the backend. Which events are critical, and how often each of them may
+//! be sent for the same source, comes from the `notify_rules` setting, e.g.
+//! `low_battery=21600,beacon_lost=3600`: a tracker reporting a low battery all day gives one
+//! notification every 6 hours. Events that come in between are counted and the count goes out
+//! with the next notification.
+//!
+//! After a power outage everything boots at once, and every tracker and beacon has something to
+//! say about it. So for `notify_grace` after the gateway boots, or after its wall clock jumped
+//! forward by more than an hour, nothing is posted. What comes in is still uploaded. When the
+//! grace period is over, one `site_recovered` notification says how many sources reported in it
+//! and how many notifications were held back.
+//!
+//! `Notifier` picks the notifications out of the gateway events, the `NotifySubscriber` hands them
+//! to the pipeline, which posts them from the retry queue like the events of the sources.
+
+use crate::api::ApiClient;
+use crate::config::config;
+use crate::console::TEST_SOURCE;
+use crate::events::GatewayEvent;
+use crate::link::LinkState;
+use crate::PROXY;
+use anyhow::bail;
+use json::JsonValue;
+use log::*;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt;
+use std::time::Duration;
+
+// Source of the notifications about the gateway itself
+const GATEWAY_SOURCE: &str = "gateway";
+// A wall clock that jumps forward more than this, compared to the monotonic one, was stuck or off
+// for a while, like during an outage
+const TIME_JUMP: i64 = 60 * 60;
+
+/// An event worth a notification.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Critical {
+    /// A tracker that isn't charging reports a low battery
+    LowBattery,
+    /// A tracker started suspecting a GPS fault, or gets no data from its GPS module
+    GpsFault,
+    /// A beacon runs on its backup battery
+    PowerLost,
+    /// The beacon on the UART stopped answering pings
+    BeaconLost,
+    /// Uploads work again after they failed
+    UplinkRestored,
+    /// A grace period is over, see the module docs. Sent whatever the rules say.
+    SiteRecovered,
+}
+
+const CRITICAL: [Critical; 5] = [
+    Critical::LowBattery,
+    Critical::GpsFault,
+    Critical::PowerLost,
+    Critical::BeaconLost,
+    Critical::UplinkRestored,
+];
+
+impl Critical {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Critical::LowBattery => "low_battery",
+            Critical::GpsFault => "gps_fault",
+            Critical::PowerLost => "power_lost",
+            Critical::BeaconLost => "beacon_lost",
+            Critical::UplinkRestored => "uplink_restored",
+            Critical::SiteRecovered => "site_recovered",
+        }
+    }
+}
+
+impl fmt::Display for Critical {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Which events are critical, and the least time between two notifications of one of them for
+/// the same source.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rules {
+    intervals: Vec<(Critical, Duration)>,
+    low_battery_volts: f32,
+}
+
+impl Rules {
+    /// Parse a comma separated list of `name=seconds`. Events that aren't listed aren't critical.
+    pub fn parse(text: &str, low_battery_volts: f32) -> Result<Self, String> {
+        let mut intervals = Vec::new();
+        for rule in text
+            .split(',')
+            .map(str::trim)
+            .filter(|rule| !rule.is_empty())
+        {
+            let Some((name, secs)) = rule.split_once('=') else {
+                return Err(format!("{rule:?} is not name=seconds"));
+            };
+            let Some(&critical) = CRITICAL.iter().find(|c| c.name() == name.trim()) else {
+                return Err(format!("{name:?} is not an event we notify about"));
+            };
+            let secs = secs
+                .trim()
+                .parse()
+                .map_err(|_| format!("{secs:?} is not a number of seconds"))?;
+            intervals.retain(|&(c, _)| c != critical);
+            intervals.push((critical, Duration::from_secs(secs)));
+        }
+        Ok(Self {
+            intervals,
+            low_battery_volts,
+        })
+    }
+
+    fn interval(&self, critical: Critical) -> Option<Duration> {
+        self.intervals
+            .iter()
+            .find(|&&(c, _)| c == critical)
+            .map(|&(_, interval)| interval)
+    }
+}
+
+/// A critical event, ready to be sent.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Notification {
+    pub critical: Critical,
+    pub src: String,
+    /// Wall clock time of the event, 0 when we don't know the time yet
+    pub timestamp: i64,
+    pub detail: String,
+    /// Events of this kind for this source that weren't sent since the last notification
+    pub suppressed: u32,
+}
+
+impl Notification {
+    pub fn payload(&self) -> JsonValue {
+        json::object! {
+            "event": self.critical.name(),
+            "src": self.src.as_str(),
+            "timestamp": self.timestamp,
+            "detail": self.detail.as_str(),
+            "suppressed": self.suppressed,
+        }
+    }
+}
+
+impl fmt::Display for Notification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} of {}: {}", self.critical, self.src, self.detail)?;
+        if self.suppressed > 0 {
+            write!(f, " ({} more since the last one)", self.suppressed)?;
+        }
+        Ok(())
+    }
+}
+
+/// Turns gateway events into notifications, at most one per kind and source per interval of its
+/// rule. Times are monotonic and passed in by the caller.
+pub struct Notifier {
+    rules: Rules,
+    // When we last sent a notification, and how many we held back since
+    sent: HashMap<(Critical, String), (Duration, u32)>,
+    // The beacon that answered last, for when it stops answering
+    beacon: Option<String>,
+    // Whether the last upload failed
+    uplink_down: bool,
+    grace: Duration,
+    // When the grace period we're in ends, None when we're not in one
+    grace_until: Option<Duration>,
+    // The sources that reported during the grace period, and the notifications we held back
+    recovering: HashSet<String>,
+    held_back: u32,
+    // Wall clock minus monotonic time in seconds, to notice the wall clock jumping
+    wall_offset: Option<i64>,
+}
+
+impl Notifier {
+    /// A notifier for a gateway that just booted, so it starts with a grace period of `grace`.
+    pub fn new(rules: Rules, grace: Duration) -> Self {
+        Self {
+            rules,
+            sent: HashMap::new(),
+            beacon: None,
+            uplink_down: false,
+            grace,
+            grace_until: (!grace.is_zero()).then_some(grace),
+            recovering: HashSet::new(),
+            held_back: 0,
+            wall_offset: None,
+        }
+    }
+
+    /// Whether notifications are held back at `now`.
+    fn in_grace(&self, now: Duration) -> bool {
+        self.grace_until.map_or(false, |until| now < until)
+    }
+
+    /// Start a grace period when the wall clock jumped forward, and end the one we're in when it's
+    /// over. Returns the `site_recovered` notification when it ended. Call this before `handle`.
+    pub fn check_grace(&mut self, now: Duration, wall: Option<i64>) -> Option<Notification> {
+        if let Some(wall) = wall {
+            let offset = wall - now.as_secs() as i64;
+            let jumped = self
+                .wall_offset
+                .map_or(false, |last| offset - last > TIME_JUMP);
+            self.wall_offset = Some(offset);
+            if jumped && !self.grace.is_zero() && !self.in_grace(now) {
+                info!("Wall clock jumped forward, holding back notifications");
+                self.grace_until = Some(now + self.grace);
+            }
+        }
+        let until = self.grace_until?;
+        if now < until {
+            return None;
+        }
+        self.grace_until = None;
+        let sources = std::mem::take(&mut self.recovering).len();
+        let suppressed = std::mem::take(&mut self.held_back);
+        Some(Notification {
+            critical: Critical::SiteRecovered,
+            src: GATEWAY_SOURCE.to_string(),
+            timestamp: wall.unwrap_or(0),
+            detail: format!("site recovered, {sources} sources reporting"),
+            suppressed,
+        })
+    }
+
+    /// The notification to send for `event` at `now`, if it's critical and we didn't send one of
+    /// its kind for its source too recently. `wall` is the wall clock time in seconds, if valid.
+    pub fn handle(
+        &mut self,
+        event: &GatewayEvent,
+        now: Duration,
+        wall: Option<i64>,
+    ) -> Option<Notification> {
+        let in_grace = self.in_grace(now);
+        if in_grace {
+            match event {
+                GatewayEvent::FrameDecoded { src } | GatewayEvent::FixValidated { src, .. }
+                    if src != TEST_SOURCE =>
+                {
+                    self.recovering.insert(src.clone());
+                }
+                _ => {}
+            }
+        }
+        let (critical, src, timestamp, detail) = self.classify(event, wall)?;
+        let interval = self.rules.interval(critical)?;
+        if in_grace {
+            self.held_back += 1;
+            return None;
+        }
+        let suppressed = match self.sent.get_mut(&(critical, src.clone())) {
+            Some((sent, suppressed)) if now.saturating_sub(*sent) < interval => {
+                *suppressed += 1;
+                return None;
+            }
+            Some((_, suppressed)) => *suppressed,
+            None => 0,
+        };
+        self.sent.insert((critical, src.clone()), (now, 0));
+        Some(Notification {
+            critical,
+            src,
+            timestamp,
+            detail,
+            suppressed,
+        })
+    }
+
+    fn classify(
+        &mut self,
+        event: &GatewayEvent,
+        wall: Option<i64>,
+    ) -> Option<(Critical, String, i64, String)> {
+        let wall = wall.unwrap_or(0);
+        match event {
+            GatewayEvent::FixValidated { src, .. } if src == TEST_SOURCE => None,
+            // A retransmission says nothing new about the battery
+            GatewayEvent::FixValidated { update: true, .. } => None,
+            GatewayEvent::FixValidated {
+                src,
+                timestamp,
+                gps,
+                ..
+            } if !gps.charging
+                && gps.battery_voltage > 0.0
+                && gps.battery_voltage < self.rules.low_battery_volts =>
+            {
+                Some((
+                    Critical::LowBattery,
+                    src.clone(),
+                    *timestamp,
+                    format!("battery at {:.2}V", gps.battery_voltage),
+                ))
+            }
+            GatewayEvent::EventQueued {
+                src,
+                name,
+                timestamp,
+            } => {
+                let critical = match *name {
+                    "gps_fault_suspected" | "gps_no_data" => Critical::GpsFault,
+                    "power_lost" => Critical::PowerLost,
+                    _ => return None,
+                };
+                Some((critical, src.clone(), *timestamp, name.to_string()))
+            }
+            GatewayEvent::BeaconLink { state } => match state {
+                LinkState::Up(beacon)
+                | LinkState::Changed {
+                    current: beacon, ..
+                }
+                | LinkState::Rebooted(beacon) => {
+                    self.beacon = Some(beacon.device_id.clone());
+                    None
+                }
+                LinkState::Lost => Some((
+                    Critical::BeaconLost,
+                    self.beacon
+                        .clone()
+                        .unwrap_or_else(|| GATEWAY_SOURCE.to_string()),
+                    wall,
+                    "the beacon stopped answering pings".to_string(),
+                )),
+            },
+            GatewayEvent::UploadFailed { .. } => {
+                self.uplink_down = true;
+                None
+            }
+            GatewayEvent::UploadSucceeded { .. } if self.uplink_down => {
+                self.uplink_down = false;
+                Some((
+                    Critical::UplinkRestored,
+                    GATEWAY_SOURCE.to_string(),
+                    wall,
+                    "uploads work again".to_string(),
+                ))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The notification `webhook test` on the console sends.
+pub fn test_notification(wall: Option<i64>) -> Notification {
+    Notification {
+        critical: Critical::UplinkRestored,
+        src: TEST_SOURCE.to_string(),
+        timestamp: wall.unwrap_or(0),
+        detail: "test notification from the console".to_string(),
+        suppressed: 0,
+    }
+}
+
+/// Post a notification to the webhook. Returns the HTTP status.
+pub fn post(body: &str) -> Result<u16, anyhow::Error> {
+    let config = config();
+    if config.webhook_url.is_empty() {
+        bail!("No webhook configured");
+    }
+    let mut client = ApiClient::new(PROXY);
+    if !config.webhook_auth.is_empty() {
+        client = client.with_authorization(&config.webhook_auth);
+    }
+    let response = client.post(&config.webhook_url, "application/json", body.as_bytes())?;
+    if !(200..300).contains(&response.status) {
+        bail!("Webhook answered HTTP {}", response.status);
+    }
+    Ok(response.status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DEFAULT_NOTIFY_RULES;
+    use crate::link::BeaconIdentity;
+    use morty_rs::messages::GpsMsg;
+
+    const HOUR: u64 = 60 * 60;
+
+    fn secs(s: u64) -> Duration {
+        Duration::from_secs(s)
+    }
+
+    fn notifier(rules: &str) -> Notifier {
+        Notifier::new(Rules::parse(rules, 3.5).unwrap(), Duration::ZERO)
+    }
+
+    fn notifier_with_everything() -> Notifier {
+        notifier(DEFAULT_NOTIFY_RULES)
+    }
+
+    fn battery(src: &str, volts: f32, charging: bool) -> GatewayEvent {
+        GatewayEvent::FixValidated {
+            uid: "uid".to_string(),
+            src: src.to_string(),
+            timestamp: 1_700_000_000,
+            gps: GpsMsg {
+                battery_voltage: volts,
+                charging,
+                ..Default::default()
+            },
+            update: false,
+        }
+    }
+
+    fn queued(src: &str, name: &'static str) -> GatewayEvent {
+        GatewayEvent::EventQueued {
+            src: src.to_string(),
+            name,
+            timestamp: 1_700_000_000,
+        }
+    }
+
+    fn beacon(device_id: &str) -> BeaconIdentity {
+        BeaconIdentity {
+            device_id: device_id.to_string(),
+            firmware_version: "1.0.0".to_string(),
+            config_version: 0,
+        }
+    }
+
+    fn upload_failed() -> GatewayEvent {
+        GatewayEvent::UploadFailed {
+            uid: "uid".to_string(),
+            src: "a".to_string(),
+            attempt: 1,
+            idempotency_key: String::new(),
+            proxy: false,
+            certificate: None,
+            reason: "timed out".to_string(),
+        }
+    }
+
+    fn upload_succeeded() -> GatewayEvent {
+        GatewayEvent::UploadSucceeded {
+            uid: "uid".to_string(),
+            src: "a".to_string(),
+            attempt: 2,
+            idempotency_key: String::new(),
+            status: 200,
+            body_hash: 0,
+        }
+    }
+
+    // What each of `events` notifies about, one event per second from `start`
+    fn stream(notifier: &mut Notifier, start: u64, events: &[GatewayEvent]) -> Vec<Notification> {
+        events
+            .iter()
+            .enumerate()
+            .filter_map(|(i, event)| notifier.handle(event, secs(start + i as u64), None))
+            .collect()
+    }
+
+    #[test]
+    fn rules_parse() {
+        let rules = Rules::parse(" low_battery = 21600 , power_lost=0,", 3.5).unwrap();
+        assert_eq!(rules.interval(Critical::LowBattery), Some(secs(6 * HOUR)));
+        assert_eq!(rules.interval(Critical::PowerLost), Some(Duration::ZERO));
+        assert_eq!(rules.interval(Critical::GpsFault), None);
+        assert!(Rules::parse("", 3.5).unwrap().intervals.is_empty());
+
+        // The last of a kind wins
+        let rules = Rules::parse("gps_fault=10,gps_fault=20", 3.5).unwrap();
+        assert_eq!(rules.intervals, [(Critical::GpsFault, secs(20))]);
+    }
+
+    #[test]
+    fn bad_rules_dont_parse() {
+        assert!(Rules::parse("low_battery", 3.5).is_err());
+        assert!(Rules::parse("low_battery=soon", 3.5).is_err());
+        assert!(Rules::parse("low_battery=-1", 3.5).is_err());
+        assert!(Rules::parse("site_recovered=60", 3.5).is_err());
+        assert!(Rules::parse("geofence_exit=60", 3.5).is_err());
+    }
+
+    #[test]
+    fn the_default_rules_parse() {
+        let rules = Rules::parse(DEFAULT_NOTIFY_RULES, 3.5).unwrap();
+        for critical in CRITICAL {
+            assert!(rules.interval(critical).is_some(), "{critical}");
+        }
+    }
+
+    #[test]
+    fn low_batteries_notify_when_not_charging() {
+        let mut notifier = notifier("low_battery=21600");
+        let events = [
+            battery("a", 3.8, false),
+            battery("a", 3.4, true),
+            // No battery
+            battery("a", 0.0, false),
+            battery("a", 3.4, false),
+        ];
+        let notifications = stream(&mut notifier, 0, &events);
+        assert_eq!(
+            notifications,
+            [Notification {
+                critical: Critical::LowBattery,
+                src: "a".to_string(),
+                timestamp: 1_700_000_000,
+                detail: "battery at 3.40V".to_string(),
+                suppressed: 0,
+            }]
+        );
+    }
+
+    #[test]
+    fn retransmissions_and_test_fixes_dont_notify() {
+        let mut notifier = notifier("low_battery=0");
+        let mut update = battery("a", 3.0, false);
+        if let GatewayEvent::FixValidated { update, .. } = &mut update {
+            *update = true;
+        }
+        let events = [update, battery(TEST_SOURCE, 3.0, false)];
+        assert!(stream(&mut notifier, 0, &events).is_empty());
+    }
+
+    #[test]
+    fn a_burst_is_collapsed_into_one_notification_per_interval() {
+        let mut notifier = notifier("low_battery=21600");
+        let burst: Vec<_> = (0..100).map(|_| battery("a", 3.0, false)).collect();
+        let notifications = stream(&mut notifier, 0, &burst);
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0].suppressed, 0);
+
+        // Still within 6 hours of the first
+        assert!(notifier
+            .handle(&battery("a", 3.0, false), secs(6 * HOUR - 1), None)
+            .is_none());
+        let next = notifier
+            .handle(&battery("a", 3.0, false), secs(6 * HOUR), None)
+            .unwrap();
+        assert_eq!(next.suppressed, 100);
+        assert_eq!(
+            next.to_string(),
+            "low_battery of a: battery at 3.00V (100 more since the last one)"
+        );
+
+        // The count starts over
+        let later = stream(&mut notifier, 12 * HOUR, &[battery("a", 3.0, false)]);
+        assert_eq!(later[0].suppressed, 0);
+    }
+
+    #[test]
+    fn sources_and_kinds_are_limited_apart() {
+        let mut notifier = notifier("low_battery=21600,power_lost=3600");
+        let events = [
+            battery("a", 3.0, false),
+            battery("b", 3.0, false),
+            queued("a", "power_lost"),
+            battery("a", 3.0, false),
+            queued("a", "power_lost"),
+            queued("b", "power_lost"),
+        ];
+        let notified: Vec<_> = stream(&mut notifier, 0, &events)
+            .into_iter()
+            .map(|n| (n.critical, n.src))
+            .collect();
+        assert_eq!(
+            notified,
+            [
+                (Critical::LowBattery, "a".to_string()),
+                (Critical::LowBattery, "b".to_string()),
+                (Critical::PowerLost, "a".to_string()),
+                (Critical::PowerLost, "b".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn events_without_a_rule_dont_notify() {
+        let mut notifier = notifier("power_lost=3600");
+        let events = [
+            battery("a", 3.0, false),
+            queued("a", "gps_fault_suspected"),
+            queued("a", "gps_no_data"),
+            queued("a", "charging_started"),
+        ];
+        assert!(stream(&mut notifier, 0, &events).is_empty());
+
+        let mut notifier = notifier_with_everything();
+        let kinds: Vec<_> = stream(&mut notifier, 0, &events)
+            .into_iter()
+            .map(|n| n.critical)
+            .collect();
+        assert_eq!(kinds, [Critical::LowBattery, Critical::GpsFault]);
+    }
+
+    #[test]
+    fn a_lost_beacon_notifies_as_the_last_one_that_answered() {
+        let mut notifier = notifier_with_everything();
+        let lost = GatewayEvent::BeaconLink {
+            state: LinkState::Lost,
+        };
+        let first = notifier.handle(&lost, secs(0), Some(100)).unwrap();
+        assert_eq!(first.src, GATEWAY_SOURCE);
+        assert_eq!(first.timestamp, 100);
+
+        let events = [
+            GatewayEvent::BeaconLink {
+                state: LinkState::Up(beacon("b1")),
+            },
+            GatewayEvent::BeaconLink {
+                state: LinkState::Changed {
+                    previous: beacon("b1"),
+                    current: beacon("b2"),
+                },
+            },
+            lost,
+        ];
+        let notifications = stream(&mut notifier, 10, &events);
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0].critical, Critical::BeaconLost);
+        assert_eq!(notifications[0].src, "b2");
+    }
+
+    #[test]
+    fn the_uplink_is_restored_once_after_failures() {
+        let mut notifier = notifier("uplink_restored=0");
+        let events = [
+            upload_succeeded(),
+            upload_failed(),
+            upload_failed(),
+            upload_succeeded(),
+            upload_succeeded(),
+        ];
+        let notifications = stream(&mut notifier, 0, &events);
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0].critical, Critical::UplinkRestored);
+        assert_eq!(notifications[0].src, GATEWAY_SOURCE);
+    }
+
+    #[test]
+    fn payloads_are_compact() {
+        let notification = Notification {
+            critical: Critical::PowerLost,
+            src: "a".to_string(),
+            timestamp: 1_700_000_000,
+            detail: "power_lost".to_string(),
+            suppressed: 2,
+        };
+        assert_eq!(
+            notification.payload().dump(),
+            concat!(
+                r#"{"event":"power_lost","src":"a","timestamp":1700000000,"#,
+                r#""detail":"power_lost","suppressed":2}"#
+            )
+        );
+        assert_eq!(test_notification(Some(5)).src, TEST_SOURCE);
+    }
+
+    const GRACE: Duration = Duration::from_secs(10 * 60);
+    const WALL: i64 = 1_700_000_000;
+
+    fn notifier_with_grace() -> Notifier {
+        Notifier::new(Rules::parse(DEFAULT_NOTIFY_RULES, 3.5).unwrap(), GRACE)
+    }
+
+    // What `event` at `now` notifies about, the way the NotifySubscriber asks for it, with a wall
+    // clock that runs along with the monotonic one
+    fn deliver(notifier: &mut Notifier, now: u64, event: &GatewayEvent) -> Vec<Notification> {
+        deliver_at(notifier, now, Some(WALL + now as i64), event)
+    }
+
+    fn deliver_at(
+        notifier: &mut Notifier,
+        now: u64,
+        wall: Option<i64>,
+        event: &GatewayEvent,
+    ) -> Vec<Notification> {
+        let recovered = notifier.check_grace(secs(now), wall);
+        let notification = notifier.handle(event, secs(now), wall);
+        recovered.into_iter().chain(notification).collect()
+    }
+
+    fn decoded(src: &str) -> GatewayEvent {
+        GatewayEvent::FrameDecoded {
+            src: src.to_string(),
+        }
+    }
+
+    // Everything boots at once: every tracker says hello with a low battery and lost power, and
+    // the beacon comes up late
+    fn mass_reboot(sources: &[&str]) -> Vec<GatewayEvent> {
+        let mut events = vec![GatewayEvent::BeaconLink {
+            state: LinkState::Lost,
+        }];
+        for src in sources {
+            events.push(decoded(src));
+            events.push(battery(src, 3.3, false));
+            events.push(queued(src, "power_lost"));
+            events.push(decoded(src));
+        }
+        events.push(battery(TEST_SOURCE, 3.3, false));
+        events.push(GatewayEvent::BeaconLink {
+            state: LinkState::Up(beacon("b1")),
+        });
+        events
+    }
+
+    fn site_recovered(sources: usize, suppressed: u32, now: u64) -> Notification {
+        Notification {
+            critical: Critical::SiteRecovered,
+            src: GATEWAY_SOURCE.to_string(),
+            timestamp: WALL + now as i64,
+            detail: format!("site recovered, {sources} sources reporting"),
+            suppressed,
+        }
+    }
+
+    #[test]
+    fn a_mass_reboot_is_one_notification() {
+        let mut notifier = notifier_with_grace();
+        for (i, event) in mass_reboot(&["t1", "t2", "t3"]).iter().enumerate() {
+            assert_eq!(deliver(&mut notifier, i as u64, event), [], "{event:?}");
+        }
+        // 3 low batteries, 3 lost powers and the lost beacon
+        let end = GRACE.as_secs();
+        assert_eq!(
+            deliver(&mut notifier, end, &decoded("t1")),
+            [site_recovered(3, 7, end)]
+        );
+        // And then it's back to normal
+        let notified = deliver(&mut notifier, end + 1, &battery("t1", 3.3, false));
+        assert_eq!(notified.len(), 1);
+        assert_eq!(notified[0].critical, Critical::LowBattery);
+        assert_eq!(notified[0].suppressed, 0);
+        assert_eq!(deliver(&mut notifier, end + 2, &decoded("t1")), []);
+    }
+
+    #[test]
+    fn the_recovery_goes_out_whatever_the_rules_say() {
+        let mut notifier = Notifier::new(Rules::parse("", 3.5).unwrap(), GRACE);
+        for event in mass_reboot(&["t1", "t2"]) {
+            assert_eq!(deliver(&mut notifier, 1, &event), []);
+        }
+        // Nothing was held back, without rules it wouldn't have gone out anyway
+        let end = GRACE.as_secs();
+        assert_eq!(
+            deliver(&mut notifier, end + 30, &decoded("t1")),
+            [site_recovered(2, 0, end + 30)]
+        );
+    }
+
+    #[test]
+    fn a_quiet_grace_period_still_ends() {
+        let mut notifier = notifier_with_grace();
+        let end = GRACE.as_secs();
+        assert_eq!(deliver(&mut notifier, end - 1, &decoded(TEST_SOURCE)), []);
+        assert_eq!(
+            deliver_at(&mut notifier, end, None, &decoded("t1")),
+            [Notification {
+                timestamp: 0,
+                ..site_recovered(0, 0, 0)
+            }]
+        );
+    }
+
+    #[test]
+    fn without_a_grace_period_nothing_is_held_back() {
+        let mut notifier = notifier_with_everything();
+        let events = mass_reboot(&["t1", "t2"]);
+        let notified: Vec<_> = events
+            .iter()
+            .flat_map(|event| deliver(&mut notifier, 0, event))
+            .map(|n| n.critical)
+            .collect();
+        assert_eq!(
+            notified,
+            [
+                Critical::BeaconLost,
+                Critical::LowBattery,
+                Critical::PowerLost,
+                Critical::LowBattery,
+                Critical::PowerLost,
+            ]
+        );
+        // Not even when the wall clock jumps
+        let jumped = Some(WALL + 10 * HOUR as i64);
+        assert_eq!(deliver_at(&mut notifier, 1, jumped, &decoded("t1")), []);
+        let notified = deliver_at(&mut notifier, 2, jumped, &queued("t3", "power_lost"));
+        assert_eq!(notified.len(), 1);
+    }
+
+    #[test]
+    fn a_wall_clock_jump_starts_a_grace_period() {
+        let mut notifier = notifier_with_grace();
+        let end = GRACE.as_secs();
+        assert_eq!(deliver(&mut notifier, end, &decoded("t1")).len(), 1);
+        // The wall clock was stuck until the power came back
+        let now = end + 100;
+        let jumped = Some(WALL + now as i64 + 2 * HOUR as i64);
+        assert_eq!(
+            deliver_at(&mut notifier, now, jumped, &queued("t1", "power_lost")),
+            []
+        );
+        let again = now + GRACE.as_secs();
+        let wall = Some(WALL + again as i64 + 2 * HOUR as i64);
+        assert_eq!(
+            deliver_at(&mut notifier, again, wall, &decoded("t1")),
+            [Notification {
+                timestamp: wall.unwrap(),
+                ..site_recovered(0, 1, 0)
+            }]
+        );
+    }
+
+    #[test]
+    fn small_or_backward_wall_clock_steps_dont_start_a_grace_period() {
+        let mut notifier = notifier_with_grace();
+        let end = GRACE.as_secs();
+        assert_eq!(deliver(&mut notifier, end, &decoded("t1")).len(), 1);
+        let hour = HOUR as i64;
+        for (now, wall) in [
+            (end + 1, WALL + end as i64 + 1 + hour),
+            (end + 2, WALL),
+            (end + 3, WALL + hour),
+        ] {
+            let notified = deliver_at(&mut notifier, now, Some(wall), &decoded("t1"));
+            assert_eq!(notified, [], "{now}");
+            assert!(!notifier.in_grace(secs(now)), "{now}");
+        }
+    }
+
+    #[test]
+    fn a_jump_during_the_grace_period_doesnt_extend_it() {
+        let mut notifier = notifier_with_grace();
+        assert_eq!(deliver(&mut notifier, 1, &decoded("t1")), []);
+        let jumped = Some(WALL + 2 + 2 * HOUR as i64);
+        assert_eq!(deliver_at(&mut notifier, 2, jumped, &decoded("t2")), []);
+        let end = GRACE.as_secs();
+        assert_eq!(deliver(&mut notifier, end, &decoded("t1")).len(), 1);
+    }
+}
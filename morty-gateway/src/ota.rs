@@ -0,0 +1,127 @@
+//! Over the air updates. Every now and then we ask the backend which image we should run. It
+//! answers with a manifest signed by the owner and, to canary an image on one gateway, the version
+//! this gateway is pinned to. We only download images of manifests that carry a valid signature of
+//! OTA_PUBLIC_KEY and that `Manifest::should_install` agrees with, and only boot them when they
+//! match the SHA-256 in the manifest.
+//!
+//! A new image has to reach the backend once before it's marked valid. When it reboots before
+//! that, the bootloader rolls back to the previous one.
+
+use crate::api::ApiClient;
+use crate::config::config;
+use crate::PROXY;
+use anyhow::anyhow;
+use anyhow::bail;
+use esp_idf_sys::esp;
+use log::*;
+use morty_rs::comm::own_mac;
+use morty_rs::ota::ImageCheck;
+use morty_rs::ota::Manifest;
+use morty_rs::ota::Version;
+use std::ffi::c_void;
+use std::time::Duration;
+
+include!(concat!(env!("OUT_DIR"), "/ota_public_key.rs"));
+
+/// Ask the backend for a manifest every `interval` and install the image when we should.
+pub fn ota_task(interval: Duration) -> ! {
+    let running: Version = env!("CARGO_PKG_VERSION").parse().unwrap();
+    info!("Running version {running}");
+    let mut confirmed = false;
+
+    loop {
+        match check(running) {
+            // We can reach the backend with this image, even when there was nothing to install
+            Ok(()) if !confirmed => confirmed = mark_valid(),
+            Ok(()) => {}
+            Err(e) => error!("OTA update failed: {:?}", e),
+        }
+        std::thread::sleep(interval);
+    }
+}
+
+/// Ask for the manifest of this gateway and install its image when we should.
+fn check(running: Version) -> Result<(), anyhow::Error> {
+    let uri = format!(
+        "https://{}/api/v1/gateway/{}/ota",
+        config().api_host,
+        own_mac()?
+    );
+    let response = ApiClient::new(PROXY).get(&uri)?;
+    if response.status == 404 {
+        return Ok(());
+    }
+    if !(200..300).contains(&response.status) {
+        bail!("HTTP {} from {uri}", response.status);
+    }
+    let json = json::parse(&String::from_utf8_lossy(&response.body))?;
+    let Some(text) = json["manifest"].as_str() else {
+        return Ok(());
+    };
+    let pinned = match json["pinned_version"].as_str() {
+        Some(pinned) => Some(pinned.parse::<Version>()?),
+        None => None,
+    };
+
+    let manifest = Manifest::verify(text, OTA_PUBLIC_KEY.as_ref())?;
+    if !manifest.should_install(running, pinned)? {
+        return Ok(());
+    }
+    info!(
+        "Installing version {} from {}",
+        manifest.version, manifest.url
+    );
+    install(&manifest)?;
+    info!("Installed version {}, restarting", manifest.version);
+    morty_rs::ledlog::save();
+    unsafe { esp_idf_sys::esp_restart() }
+}
+
+/// Download the image of `manifest` into the OTA partition we don't run from and boot from it
+/// next time, when it matches the manifest.
+fn install(manifest: &Manifest) -> Result<(), anyhow::Error> {
+    let partition = unsafe { esp_idf_sys::esp_ota_get_next_update_partition(std::ptr::null()) };
+    if partition.is_null() {
+        bail!("No partition to install an update in");
+    }
+    let mut handle = 0;
+    esp!(unsafe {
+        esp_idf_sys::esp_ota_begin(
+            partition,
+            esp_idf_sys::OTA_SIZE_UNKNOWN as usize,
+            &mut handle,
+        )
+    })?;
+
+    let mut image = ImageCheck::default();
+    let downloaded = ApiClient::new(PROXY)
+        .download(&manifest.url, &mut |chunk| {
+            image.update(chunk);
+            esp!(unsafe {
+                esp_idf_sys::esp_ota_write(handle, chunk.as_ptr() as *const c_void, chunk.len())
+            })?;
+            Ok(())
+        })
+        .map_err(|e| anyhow!("Unable to download {}: {e}", manifest.url));
+    let len = image.len();
+    if let Err(e) = downloaded.and_then(|_| Ok(image.finish(manifest)?)) {
+        unsafe { esp_idf_sys::esp_ota_abort(handle) };
+        return Err(e);
+    }
+    info!("Downloaded {len} bytes of version {}", manifest.version);
+
+    esp!(unsafe { esp_idf_sys::esp_ota_end(handle) })?;
+    esp!(unsafe { esp_idf_sys::esp_ota_set_boot_partition(partition) })?;
+    Ok(())
+}
+
+/// Keep the image we run, so the bootloader doesn't roll back. Returns whether that worked.
+fn mark_valid() -> bool {
+    match esp!(unsafe { esp_idf_sys::esp_ota_mark_app_valid_cancel_rollback() }) {
+        Ok(()) => true,
+        Err(e) => {
+            error!("Unable to mark the image as valid: {e}");
+            false
+        }
+    }
+}
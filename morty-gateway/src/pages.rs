@@ -0,0 +1,236 @@
+//! What the status display shows, as lines of text. This doesn't know about the display itself,
+//! so the layout can be worked on without one.
+
+use std::time::Duration;
+
+/// Characters that fit on a line of a 128 pixel wide display, with a 6 pixel wide font.
+pub const LINE_LEN: usize = 21;
+/// Lines that fit on a 64 pixel high display, with a 10 pixel high font.
+pub const LINES: usize = 6;
+
+// Sources on a page, below the title
+const SOURCES_PER_PAGE: usize = LINES - 1;
+
+/// Everything the pages show, at one point in time.
+#[derive(Clone, Debug, Default)]
+pub struct Snapshot {
+    pub ssid: String,
+    pub ip: String,
+    pub rssi: Option<i8>,
+    pub received_last_hour: usize,
+    pub uploaded_last_hour: usize,
+    pub queue_depth: usize,
+    // Every source and the age of its last fix
+    pub sources: Vec<(String, Duration)>,
+    pub upload_failures: u32,
+    pub uart_framing_errors: u32,
+    pub uart_parity_errors: u32,
+    pub uart_overruns: u32,
+    pub decode_failures: u32,
+    // MAC and firmware version of the beacon on the UART, when it ever answered
+    pub beacon: Option<(String, String)>,
+    // None until the beacon answered a ping or didn't
+    pub beacon_up: Option<bool>,
+}
+
+/// The pages to rotate through. Every page has at most `LINES` lines of at most `LINE_LEN`
+/// characters.
+pub fn pages(snapshot: &Snapshot) -> Vec<Vec<String>> {
+    let mut pages = vec![
+        vec![
+            "Wifi".to_string(),
+            snapshot.ssid.clone(),
+            format!("IP {}", snapshot.ip),
+            match snapshot.rssi {
+                Some(rssi) => format!("RSSI {rssi} dBm"),
+                None => "RSSI -".to_string(),
+            },
+        ],
+        vec![
+            "Last hour".to_string(),
+            format!("Received {}", snapshot.received_last_hour),
+            format!("Uploaded {}", snapshot.uploaded_last_hour),
+            format!("Queued {}", snapshot.queue_depth),
+        ],
+    ];
+
+    let mut beacon = vec!["Beacon".to_string()];
+    match &snapshot.beacon {
+        Some((device_id, firmware_version)) => {
+            beacon.push(device_id.clone());
+            beacon.push(format!("Firmware {firmware_version}"));
+        }
+        None => beacon.push("Unknown".to_string()),
+    }
+    beacon.push(
+        match snapshot.beacon_up {
+            Some(true) => "Link up",
+            Some(false) => "Link lost",
+            None => "Link -",
+        }
+        .to_string(),
+    );
+    pages.push(beacon);
+
+    // Most recent first, over as many pages as it takes
+    let mut sources = snapshot.sources.clone();
+    sources.sort_by_key(|&(_, age)| age);
+    let count = ((sources.len() + SOURCES_PER_PAGE - 1) / SOURCES_PER_PAGE).max(1);
+    for i in 0..count {
+        let mut page = vec![if count > 1 {
+            format!("Sources {}/{count}", i + 1)
+        } else {
+            "Sources".to_string()
+        }];
+        page.extend(
+            sources
+                .iter()
+                .skip(i * SOURCES_PER_PAGE)
+                .take(SOURCES_PER_PAGE)
+                .map(|(src, age)| source_line(src, *age)),
+        );
+        if sources.is_empty() {
+            page.push("None yet".to_string());
+        }
+        pages.push(page);
+    }
+
+    pages.push(vec![
+        "Errors".to_string(),
+        format!("Upload {}", snapshot.upload_failures),
+        format!("UART framing {}", snapshot.uart_framing_errors),
+        format!("UART parity {}", snapshot.uart_parity_errors),
+        format!("UART overrun {}", snapshot.uart_overruns),
+        format!("Decode {}", snapshot.decode_failures),
+    ]);
+
+    for page in pages.iter_mut() {
+        for line in page.iter_mut() {
+            truncate(line);
+        }
+    }
+    pages
+}
+
+/// A source with the age of its last fix on the right. Long names are cut off, the age isn't.
+fn source_line(src: &str, age: Duration) -> String {
+    let age = format_age(age);
+    let width = LINE_LEN.saturating_sub(age.len() + 1);
+    let src: String = src.chars().take(width).collect();
+    format!("{src:width$} {age}")
+}
+
+/// `age` in the largest unit that keeps it short, e.g. "45s", "12m", "3h" or "2d".
+fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    match secs {
+        0..=59 => format!("{secs}s"),
+        60..=3599 => format!("{}m", secs / 60),
+        3600..=172_799 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
+
+fn truncate(line: &mut String) {
+    if let Some((i, _)) = line.char_indices().nth(LINE_LEN) {
+        line.truncate(i);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(sources: usize) -> Snapshot {
+        Snapshot {
+            ssid: "a-network-with-a-very-long-name".to_string(),
+            ip: "192.168.1.23".to_string(),
+            rssi: Some(-61),
+            received_last_hour: 12,
+            uploaded_last_hour: 11,
+            queue_depth: 1,
+            sources: (0..sources)
+                .map(|i| (format!("tracker-{i}"), Duration::from_secs(100 - i as u64)))
+                .collect(),
+            beacon: Some(("24:0a:c4:00:00:01".to_string(), "1.2.3".to_string())),
+            beacon_up: Some(true),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn pages_come_in_order() {
+        let pages = pages(&snapshot(0));
+        let titles: Vec<&str> = pages.iter().map(|p| p[0].as_str()).collect();
+        assert_eq!(titles, ["Wifi", "Last hour", "Beacon", "Sources", "Errors"]);
+        assert_eq!(
+            pages[0],
+            [
+                "Wifi",
+                "a-network-with-a-very",
+                "IP 192.168.1.23",
+                "RSSI -61 dBm"
+            ]
+        );
+        assert_eq!(
+            pages[2],
+            ["Beacon", "24:0a:c4:00:00:01", "Firmware 1.2.3", "Link up"]
+        );
+        assert_eq!(pages[3], ["Sources", "None yet"]);
+    }
+
+    #[test]
+    fn every_page_fits_the_display() {
+        for page in pages(&snapshot(12)) {
+            assert!(page.len() <= LINES, "{page:?}");
+            for line in page {
+                assert!(line.chars().count() <= LINE_LEN, "{line}");
+            }
+        }
+    }
+
+    #[test]
+    fn sources_are_spread_over_pages_most_recent_first() {
+        let pages = pages(&snapshot(7));
+        assert_eq!(pages[3][0], "Sources 1/2");
+        assert_eq!(pages[3].len(), LINES);
+        assert_eq!(pages[3][1], "tracker-6          1m");
+        assert_eq!(pages[4][0], "Sources 2/2");
+        assert_eq!(
+            pages[4][1..],
+            ["tracker-1          1m", "tracker-0          1m"]
+        );
+    }
+
+    #[test]
+    fn an_unknown_beacon_says_so() {
+        let pages = pages(&Snapshot::default());
+        assert_eq!(pages[0][3], "RSSI -");
+        assert_eq!(pages[2], ["Beacon", "Unknown", "Link -"]);
+    }
+
+    #[test]
+    fn long_sources_are_cut_off_but_their_age_isnt() {
+        let line = source_line("a-tracker-with-a-long-name", Duration::from_secs(7200));
+        assert_eq!(line, "a-tracker-with-a-l 2h");
+        assert_eq!(line.len(), LINE_LEN);
+    }
+
+    #[test]
+    fn ages_are_short() {
+        let age = |secs| format_age(Duration::from_secs(secs));
+        assert_eq!(age(59), "59s");
+        assert_eq!(age(60), "1m");
+        assert_eq!(age(3599), "59m");
+        assert_eq!(age(3600), "1h");
+        assert_eq!(age(172_799), "47h");
+        assert_eq!(age(172_800), "2d");
+    }
+
+    #[test]
+    fn lines_are_cut_off_on_characters() {
+        let mut line = "é".repeat(LINE_LEN + 3);
+        truncate(&mut line);
+        assert_eq!(line, "é".repeat(LINE_LEN));
+    }
+}
@@ -0,0 +1,125 @@
+//! How precise the positions of a source are when they leave the gateway. Some of the users that
+//! share a gateway don't want their exact positions stored, so every source can have its own
+//! level in SOURCE_PRIVACY. The level is applied to a fix once we checked it, before it's queued,
+//! kept as the last fix or exported, so nothing downstream sees the exact position.
+
+use morty_rs::geo::round_coordinate;
+use morty_rs::messages::GpsMsg;
+
+/// The privacy of a source.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Privacy {
+    /// Positions as the tracker sent them
+    Full,
+    /// Positions rounded to 3 decimal places, about 100 m
+    Rounded100m,
+    /// Positions rounded to 2 decimal places, about 1 km
+    Rounded1km,
+    /// No positions at all, only that the tracker is alive, with its battery and such
+    Suppressed,
+}
+
+impl Privacy {
+    /// The privacy of `src` in `table`, `default` for sources that aren't in it.
+    pub fn of(table: &[(&str, Privacy)], default: Privacy, src: &str) -> Privacy {
+        table
+            .iter()
+            .find(|(s, _)| s.eq_ignore_ascii_case(src))
+            .map_or(default, |(_, privacy)| *privacy)
+    }
+
+    /// The name in the payload.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Privacy::Full => "full",
+            Privacy::Rounded100m => "100m",
+            Privacy::Rounded1km => "1km",
+            Privacy::Suppressed => "suppressed",
+        }
+    }
+
+    /// Take the position out of `gps` as far as this level wants. A suppressed fix doesn't have
+    /// a fix quality either, so the backend treats it as a ping.
+    pub fn apply(&self, gps: &mut GpsMsg) {
+        let decimals = match self {
+            Privacy::Full => return,
+            Privacy::Rounded100m => 3,
+            Privacy::Rounded1km => 2,
+            Privacy::Suppressed => {
+                gps.latitude = 0.0;
+                gps.longitude = 0.0;
+                gps.hdop = 0.0;
+                gps.fix_quality = 0;
+                gps.satellites = 0;
+                return;
+            }
+        };
+        (gps.latitude, gps.longitude) = round_coordinate(gps.latitude, gps.longitude, decimals);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fix() -> GpsMsg {
+        GpsMsg {
+            latitude: 52.37403,
+            longitude: 4.88969,
+            hdop: 0.9,
+            fix_quality: 1,
+            satellites: 9,
+            battery_voltage: 4.0,
+            ..Default::default()
+        }
+    }
+
+    fn applied(privacy: Privacy) -> GpsMsg {
+        let mut gps = fix();
+        privacy.apply(&mut gps);
+        gps
+    }
+
+    #[test]
+    fn sources_that_arent_listed_get_the_default() {
+        let table = [("AA:BB:CC:DD:EE:FF", Privacy::Rounded1km)];
+        assert_eq!(
+            Privacy::of(&table, Privacy::Full, "aa:bb:cc:dd:ee:ff"),
+            Privacy::Rounded1km
+        );
+        assert_eq!(
+            Privacy::of(&table, Privacy::Full, "aa:bb:cc:dd:ee:00"),
+            Privacy::Full
+        );
+    }
+
+    #[test]
+    fn full_leaves_the_fix_alone() {
+        assert_eq!(applied(Privacy::Full), fix());
+    }
+
+    #[test]
+    fn rounding_only_touches_the_position() {
+        let gps = applied(Privacy::Rounded100m);
+        assert_eq!((gps.latitude, gps.longitude), (52.374, 4.89));
+        assert_eq!(
+            GpsMsg {
+                latitude: fix().latitude,
+                longitude: fix().longitude,
+                ..gps
+            },
+            fix()
+        );
+        let gps = applied(Privacy::Rounded1km);
+        assert_eq!((gps.latitude, gps.longitude), (52.37, 4.89));
+    }
+
+    #[test]
+    fn suppressed_fixes_are_pings() {
+        let gps = applied(Privacy::Suppressed);
+        assert_eq!((gps.latitude, gps.longitude, gps.hdop), (0.0, 0.0, 0.0));
+        assert_eq!((gps.fix_quality, gps.satellites), (0, 0));
+        assert_eq!(gps.battery_voltage, 4.0);
+    }
+}
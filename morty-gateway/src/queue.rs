@@ -0,0 +1,744 @@
+use crate::privacy::Privacy;
+use morty_rs::messages::GpsMsg;
+use morty_rs::timestamps::FixTimes;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// The order in which pending uploads are drained from the retry queue.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DrainPolicy {
+    /// Upload fixes in the order they were received.
+    OldestFirst,
+    /// Upload the most recently received fix first.
+    NewestFirst,
+    /// Upload the newest pending fix of every source first and then backfill the older ones,
+    /// marking them as such.
+    NewestFirstWithBackfill,
+}
+
+impl DrainPolicy {
+    pub const ALL: [DrainPolicy; 3] = [
+        DrainPolicy::OldestFirst,
+        DrainPolicy::NewestFirst,
+        DrainPolicy::NewestFirstWithBackfill,
+    ];
+
+    /// The name of the policy in the settings, see `config`.
+    pub const fn name(self) -> &'static str {
+        match self {
+            DrainPolicy::OldestFirst => "oldest-first",
+            DrainPolicy::NewestFirst => "newest-first",
+            DrainPolicy::NewestFirstWithBackfill => "newest-first-with-backfill",
+        }
+    }
+}
+
+/// The names of `DrainPolicy::ALL`.
+pub const DRAIN_POLICY_NAMES: [&str; 3] = [
+    DrainPolicy::ALL[0].name(),
+    DrainPolicy::ALL[1].name(),
+    DrainPolicy::ALL[2].name(),
+];
+
+impl fmt::Display for DrainPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for DrainPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        DrainPolicy::ALL
+            .into_iter()
+            .find(|policy| policy.name() == s)
+            .ok_or_else(|| format!("{s:?} is not a drain policy"))
+    }
+}
+
+/// What an upload is, which decides how long it's worth uploading.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UploadClass {
+    /// The newest fix of a source.
+    LiveFix,
+    /// An older fix, see `DrainPolicy::NewestFirstWithBackfill`.
+    Backfill,
+    /// Something that happened to a source, like a beacon losing power. Events are never dropped
+    /// to make room for fixes.
+    Event,
+}
+
+const CLASSES: [UploadClass; 3] = [
+    UploadClass::LiveFix,
+    UploadClass::Backfill,
+    UploadClass::Event,
+];
+
+impl fmt::Display for UploadClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UploadClass::LiveFix => write!(f, "live-fix"),
+            UploadClass::Backfill => write!(f, "backfill"),
+            UploadClass::Event => write!(f, "event"),
+        }
+    }
+}
+
+/// How long after it was received an upload is still worth uploading, per class. The age is
+/// measured on the monotonic clock, so a wall clock that is set late doesn't expire the whole
+/// queue at once.
+#[derive(Clone, Copy, Debug)]
+pub struct UploadTtl {
+    pub live_fix: Duration,
+    pub backfill: Duration,
+    pub event: Duration,
+}
+
+impl UploadTtl {
+    pub fn of(&self, class: UploadClass) -> Duration {
+        match class {
+            UploadClass::LiveFix => self.live_fix,
+            UploadClass::Backfill => self.backfill,
+            UploadClass::Event => self.event,
+        }
+    }
+}
+
+/// A GPS fix that still has to be uploaded to the API server.
+#[derive(Clone, Debug)]
+pub struct PendingUpload {
+    pub src: String,
+    // When the first beacon received the fix, see `morty_rs::timestamps`
+    pub timestamp: i64,
+    pub gps: GpsMsg,
+    // Monotonic time at which the gateway received the fix
+    pub received_at: Duration,
+    // Wall clock time at which the gateway received the fix, filled in once the time is valid
+    pub received: Option<i64>,
+    pub backfill: bool,
+    // Time the fix spent in the beacons on its way to us, see `morty_rs::relay`
+    pub path_delay_ms: u32,
+    // The gateway the beacon that handed it to us says it's attached to, empty when it didn't say
+    pub gateway_hint: String,
+    // A retransmission of a fix that was queued already, with news, see `morty_rs::dedup`
+    pub update: bool,
+    // Injected from the console to test the pipeline, rather than sent by a tracker
+    pub test: bool,
+    // Too old by the time we got to upload it, see `staleness`
+    pub stale: bool,
+    // What was taken out of the position before it was queued, see `privacy`
+    pub privacy: Privacy,
+    // Number of times we tried to upload this fix
+    pub attempts: u32,
+    seq: u64,
+    prev_live: Option<u64>,
+}
+
+impl PendingUpload {
+    pub fn new(src: String, timestamp: i64, received_at: Duration, gps: GpsMsg) -> Self {
+        Self {
+            src,
+            timestamp,
+            gps,
+            received_at,
+            received: None,
+            backfill: false,
+            path_delay_ms: 0,
+            gateway_hint: String::new(),
+            update: false,
+            test: false,
+            stale: false,
+            privacy: Privacy::Full,
+            attempts: 0,
+            seq: 0,
+            prev_live: None,
+        }
+    }
+
+    pub fn class(&self) -> UploadClass {
+        if self.backfill {
+            UploadClass::Backfill
+        } else {
+            UploadClass::LiveFix
+        }
+    }
+
+    /// When the fix was measured, sent, relayed and received by us, see `morty_rs::timestamps`.
+    pub fn times(&self) -> FixTimes {
+        FixTimes::new(&self.gps, self.timestamp, self.received)
+    }
+}
+
+/// Where an event goes.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Sink {
+    /// The event endpoint of the API server
+    Backend,
+    /// The webhook, with the notification as JSON, see `notify`
+    Webhook(String),
+}
+
+/// What a sink does with the positions it gets.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SinkRole {
+    /// Keeps them, so it gets them as they came in
+    Archival,
+    /// Only shows them, e.g. on a live map, so they're smoothed, see `morty_rs::geo::Smoother`
+    Display,
+}
+
+/// An event of a source that still has to be uploaded to the API server, or a notification that
+/// still has to be posted to the webhook.
+#[derive(Clone, Debug)]
+pub struct PendingEvent {
+    pub src: String,
+    pub name: &'static str,
+    pub timestamp: i64,
+    // Monotonic time at which the gateway received the event
+    pub received_at: Duration,
+    pub sink: Sink,
+    // Number of times we tried to upload this event
+    pub attempts: u32,
+}
+
+impl PendingEvent {
+    pub fn new(src: String, name: &'static str, timestamp: i64, received_at: Duration) -> Self {
+        Self {
+            src,
+            name,
+            timestamp,
+            received_at,
+            sink: Sink::Backend,
+            attempts: 0,
+        }
+    }
+
+    pub fn notification(
+        src: String,
+        name: &'static str,
+        timestamp: i64,
+        received_at: Duration,
+        body: String,
+    ) -> Self {
+        Self {
+            sink: Sink::Webhook(body),
+            ..Self::new(src, name, timestamp, received_at)
+        }
+    }
+}
+
+/// Anything in the retry queue.
+#[derive(Clone, Debug)]
+pub enum Pending {
+    Fix(PendingUpload),
+    Event(PendingEvent),
+}
+
+impl Pending {
+    pub fn class(&self) -> UploadClass {
+        match self {
+            Pending::Fix(upload) => upload.class(),
+            Pending::Event(_) => UploadClass::Event,
+        }
+    }
+
+    pub fn src(&self) -> &str {
+        match self {
+            Pending::Fix(upload) => &upload.src,
+            Pending::Event(event) => &event.src,
+        }
+    }
+
+    /// The UID of a fix or the name of an event
+    pub fn id(&self) -> &str {
+        match self {
+            Pending::Fix(upload) => &upload.gps.uid,
+            Pending::Event(event) => event.name,
+        }
+    }
+
+    fn received_at(&self) -> Duration {
+        match self {
+            Pending::Fix(upload) => upload.received_at,
+            Pending::Event(event) => event.received_at,
+        }
+    }
+}
+
+/// An upload that spent longer in the queue than its class is worth uploading.
+#[derive(Clone, Debug)]
+pub struct Expired {
+    pub class: UploadClass,
+    pub src: String,
+    pub id: String,
+    pub age: Duration,
+}
+
+/// Bounded queue of uploads that haven't made it to the API server yet. When the queue is full,
+/// the oldest fix is dropped. Events go out before fixes, oldest first, and are only dropped when
+/// there are no fixes left to make room. Uploads that are older than the TTL of their class when
+/// their turn comes are dropped.
+pub struct RetryQueue {
+    data: VecDeque<PendingUpload>,
+    events: VecDeque<PendingEvent>,
+    size: usize,
+    policy: DrainPolicy,
+    ttl: UploadTtl,
+    next_seq: u64,
+    // Sequence number of the last live (non-backfill) upload per source
+    last_live: HashMap<String, u64>,
+    // Number of expired uploads per class, in the order of `CLASSES`
+    expired: [u32; CLASSES.len()],
+}
+
+impl RetryQueue {
+    pub fn new(size: usize, policy: DrainPolicy, ttl: UploadTtl) -> Self {
+        Self {
+            data: VecDeque::new(),
+            events: VecDeque::new(),
+            size,
+            policy,
+            ttl,
+            next_seq: 0,
+            last_live: HashMap::new(),
+            expired: [0; CLASSES.len()],
+        }
+    }
+
+    pub fn policy(&self) -> DrainPolicy {
+        self.policy
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len() + self.events.len()
+    }
+
+    /// Number of uploads that expired so far, per class.
+    pub fn expired(&self) -> impl Iterator<Item = (UploadClass, u32)> + '_ {
+        CLASSES.iter().copied().zip(self.expired.iter().copied())
+    }
+
+    /// Add a fix to the queue. Returns the upload that was dropped to make room, if any.
+    pub fn push(&mut self, mut upload: PendingUpload) -> Option<Pending> {
+        upload.seq = self.next_seq;
+        self.next_seq += 1;
+        self.data.push_back(upload);
+        self.make_room()
+    }
+
+    /// Add an event to the queue. Returns the upload that was dropped to make room, if any.
+    pub fn push_event(&mut self, event: PendingEvent) -> Option<Pending> {
+        self.events.push_back(event);
+        self.make_room()
+    }
+
+    /// Take the next upload: events first, then fixes according to the drain policy. Uploads
+    /// that are older than their TTL at `now` (monotonic) are dropped and passed to
+    /// `on_expired`.
+    pub fn pop(&mut self, now: Duration, mut on_expired: impl FnMut(Expired)) -> Option<Pending> {
+        loop {
+            let pending = match self.events.pop_front() {
+                Some(event) => Pending::Event(event),
+                None => Pending::Fix(self.pop_fix()?),
+            };
+            let class = pending.class();
+            let age = now.saturating_sub(pending.received_at());
+            if age <= self.ttl.of(class) {
+                return Some(pending);
+            }
+            self.expired[class as usize] += 1;
+            on_expired(Expired {
+                class,
+                src: pending.src().to_string(),
+                id: pending.id().to_string(),
+                age,
+            });
+        }
+    }
+
+    /// Put an upload that failed back in the queue, at the position it was taken from.
+    pub fn requeue(&mut self, pending: Pending) {
+        match pending {
+            Pending::Fix(upload) => self.requeue_fix(upload),
+            Pending::Event(event) => self.events.push_front(event),
+        }
+        self.make_room();
+    }
+
+    // Drop the oldest fix, or the oldest event when there are only events, while the queue is
+    // over its size
+    fn make_room(&mut self) -> Option<Pending> {
+        if self.len() <= self.size {
+            return None;
+        }
+        match self.data.pop_front() {
+            Some(upload) => Some(Pending::Fix(upload)),
+            None => self.events.pop_front().map(Pending::Event),
+        }
+    }
+
+    fn pop_fix(&mut self) -> Option<PendingUpload> {
+        let index = self.next_index()?;
+        let mut upload = self.data.remove(index)?;
+
+        if self.policy == DrainPolicy::NewestFirstWithBackfill {
+            let prev = self.last_live.get(&upload.src).copied();
+            let live = prev.map_or(true, |s| upload.seq > s);
+            if live {
+                upload.prev_live = prev;
+                self.last_live.insert(upload.src.clone(), upload.seq);
+            }
+            upload.backfill = !live;
+        }
+        Some(upload)
+    }
+
+    fn requeue_fix(&mut self, upload: PendingUpload) {
+        if self.policy == DrainPolicy::NewestFirstWithBackfill && !upload.backfill {
+            match upload.prev_live {
+                Some(s) => self.last_live.insert(upload.src.clone(), s),
+                None => self.last_live.remove(&upload.src),
+            };
+        }
+        let index = self.data.partition_point(|u| u.seq < upload.seq);
+        self.data.insert(index, upload);
+    }
+
+    fn next_index(&self) -> Option<usize> {
+        if self.data.is_empty() {
+            return None;
+        }
+
+        match self.policy {
+            DrainPolicy::OldestFirst => Some(0),
+            DrainPolicy::NewestFirst => Some(self.data.len() - 1),
+            DrainPolicy::NewestFirstWithBackfill => {
+                // The newest fix of a source is live if it's newer than anything we've sent for
+                // that source. Live fixes go newest first, after that we backfill oldest first.
+                let mut newest: HashMap<&str, usize> = HashMap::new();
+                for (i, u) in self.data.iter().enumerate() {
+                    newest.insert(&u.src, i);
+                }
+                newest
+                    .values()
+                    .copied()
+                    .filter(|&i| {
+                        let u = &self.data[i];
+                        self.last_live.get(&u.src).map_or(true, |&s| u.seq > s)
+                    })
+                    .max()
+                    .or(Some(0))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: UploadTtl = UploadTtl {
+        live_fix: Duration::from_secs(60),
+        backfill: Duration::from_secs(120),
+        event: Duration::from_secs(180),
+    };
+
+    fn fix(src: &str, uid: &str) -> PendingUpload {
+        let gps = GpsMsg {
+            uid: uid.to_string(),
+            ..Default::default()
+        };
+        PendingUpload::new(src.to_string(), 0, Duration::ZERO, gps)
+    }
+
+    fn queue(policy: DrainPolicy, fixes: &[(&str, &str)]) -> RetryQueue {
+        let mut queue = RetryQueue::new(16, policy, TTL);
+        for (src, uid) in fixes {
+            assert!(queue.push(fix(src, uid)).is_none());
+        }
+        queue
+    }
+
+    // The source, UID and whether it's a backfill of the fixes in the order they come out
+    fn drain(queue: &mut RetryQueue) -> Vec<(String, String, bool)> {
+        let mut drained = Vec::new();
+        while let Some(pending) = queue.pop(Duration::ZERO, |e| panic!("{} expired", e.id)) {
+            match pending {
+                Pending::Fix(u) => drained.push((u.src, u.gps.uid, u.backfill)),
+                Pending::Event(e) => drained.push((e.src, e.name.to_string(), false)),
+            }
+        }
+        drained
+    }
+
+    fn fixes(expected: &[(&str, &str, bool)]) -> Vec<(String, String, bool)> {
+        expected
+            .iter()
+            .map(|(src, uid, backfill)| (src.to_string(), uid.to_string(), *backfill))
+            .collect()
+    }
+
+    const INTERLEAVED: [(&str, &str); 5] = [
+        ("a", "a1"),
+        ("a", "a2"),
+        ("b", "b1"),
+        ("b", "b2"),
+        ("a", "a3"),
+    ];
+
+    #[test]
+    fn policies_have_names() {
+        for policy in DrainPolicy::ALL {
+            assert_eq!(policy.name().parse(), Ok(policy));
+            assert_eq!(policy.to_string(), policy.name());
+        }
+        assert_eq!(DRAIN_POLICY_NAMES.len(), DrainPolicy::ALL.len());
+        assert!("newest".parse::<DrainPolicy>().is_err());
+    }
+
+    #[test]
+    fn oldest_first_drains_in_the_order_received() {
+        let mut queue = queue(DrainPolicy::OldestFirst, &INTERLEAVED);
+        let expected = [
+            ("a", "a1", false),
+            ("a", "a2", false),
+            ("b", "b1", false),
+            ("b", "b2", false),
+            ("a", "a3", false),
+        ];
+        assert_eq!(drain(&mut queue), fixes(&expected));
+    }
+
+    #[test]
+    fn newest_first_drains_in_reverse() {
+        let mut queue = queue(DrainPolicy::NewestFirst, &INTERLEAVED);
+        let expected = [
+            ("a", "a3", false),
+            ("b", "b2", false),
+            ("b", "b1", false),
+            ("a", "a2", false),
+            ("a", "a1", false),
+        ];
+        assert_eq!(drain(&mut queue), fixes(&expected));
+    }
+
+    #[test]
+    fn backfill_sends_the_newest_of_every_source_first() {
+        let mut queue = queue(DrainPolicy::NewestFirstWithBackfill, &INTERLEAVED);
+        let expected = [
+            ("a", "a3", false),
+            ("b", "b2", false),
+            ("a", "a1", true),
+            ("a", "a2", true),
+            ("b", "b1", true),
+        ];
+        assert_eq!(drain(&mut queue), fixes(&expected));
+
+        // Newer fixes are live again
+        queue.push(fix("a", "a4"));
+        queue.push(fix("c", "c1"));
+        let expected = [("c", "c1", false), ("a", "a4", false)];
+        assert_eq!(drain(&mut queue), fixes(&expected));
+    }
+
+    #[test]
+    fn requeued_fixes_go_back_where_they_were() {
+        for policy in DrainPolicy::ALL {
+            let mut queue = queue(policy, &INTERLEAVED);
+            let expected = drain(&mut self::queue(policy, &INTERLEAVED));
+            let first = queue.pop(Duration::ZERO, |_| {}).unwrap();
+            queue.requeue(first);
+            assert_eq!(drain(&mut queue), expected, "{policy}");
+
+            // Failing halfway through doesn't change the order either
+            let mut queue = self::queue(policy, &INTERLEAVED);
+            let mut drained = Vec::new();
+            for _ in 0..2 {
+                match queue.pop(Duration::ZERO, |_| {}).unwrap() {
+                    Pending::Fix(u) => drained.push((u.src, u.gps.uid, u.backfill)),
+                    Pending::Event(_) => unreachable!(),
+                }
+            }
+            let third = queue.pop(Duration::ZERO, |_| {}).unwrap();
+            queue.requeue(third);
+            drained.extend(drain(&mut queue));
+            assert_eq!(drained, expected, "{policy}");
+        }
+    }
+
+    #[test]
+    fn events_go_first_and_fixes_make_room() {
+        let mut queue = RetryQueue::new(3, DrainPolicy::NewestFirst, TTL);
+        assert!(queue.push(fix("a", "a1")).is_none());
+        assert!(queue
+            .push_event(PendingEvent::new(
+                "b".into(),
+                "power_lost",
+                0,
+                Duration::ZERO
+            ))
+            .is_none());
+        assert!(queue.push(fix("b", "b1")).is_none());
+        match queue.push(fix("a", "a2")) {
+            Some(Pending::Fix(u)) => assert_eq!(u.gps.uid, "a1"),
+            other => panic!("dropped {other:?}"),
+        }
+        match queue.push(fix("c", "c1")) {
+            Some(Pending::Fix(u)) => assert_eq!(u.gps.uid, "b1"),
+            other => panic!("dropped {other:?}"),
+        }
+        let expected = [
+            ("b", "power_lost", false),
+            ("c", "c1", false),
+            ("a", "a2", false),
+        ];
+        assert_eq!(drain(&mut queue), fixes(&expected));
+    }
+
+    #[test]
+    fn uploads_expire_by_class() {
+        let mut queue = queue(
+            DrainPolicy::NewestFirstWithBackfill,
+            &[("a", "a1"), ("a", "a2")],
+        );
+        queue.push_event(PendingEvent::new(
+            "b".into(),
+            "gps_fault",
+            0,
+            Duration::ZERO,
+        ));
+        let mut expired = Vec::new();
+        let now = Duration::from_secs(100);
+        // The live fix is too old, the backfill and the event aren't
+        let mut popped = Vec::new();
+        while let Some(pending) = queue.pop(now, |e| expired.push((e.class, e.id))) {
+            popped.push(pending.id().to_string());
+        }
+        assert_eq!(popped, ["gps_fault", "a1"]);
+        assert_eq!(expired, [(UploadClass::LiveFix, "a2".to_string())]);
+        let counts: Vec<_> = queue.expired().collect();
+        assert_eq!(
+            counts,
+            [
+                (UploadClass::LiveFix, 1),
+                (UploadClass::Backfill, 0),
+                (UploadClass::Event, 0)
+            ]
+        );
+    }
+
+    fn event(src: &str, name: &'static str, received_at: Duration) -> PendingEvent {
+        PendingEvent::new(src.to_string(), name, 0, received_at)
+    }
+
+    #[test]
+    fn every_class_expires_after_its_own_ttl() {
+        for (class, ttl) in [
+            (UploadClass::LiveFix, TTL.live_fix),
+            (UploadClass::Backfill, TTL.backfill),
+            (UploadClass::Event, TTL.event),
+        ] {
+            assert_eq!(TTL.of(class), ttl);
+        }
+        // Right at the TTL is still in time
+        let mut queue = queue(DrainPolicy::OldestFirst, &[("a", "a1")]);
+        assert!(queue
+            .pop(TTL.live_fix, |e| panic!("{} expired", e.id))
+            .is_some());
+
+        let mut queue = self::queue(
+            DrainPolicy::NewestFirstWithBackfill,
+            &[("a", "a1"), ("a", "a2")],
+        );
+        queue.push_event(event("b", "gps_fault", Duration::ZERO));
+
+        let mut expired = Vec::new();
+        let now = TTL.event + Duration::from_secs(1);
+        assert!(queue.pop(now, |e| expired.push((e.class, e.id))).is_none());
+        assert_eq!(
+            expired,
+            [
+                (UploadClass::Event, "gps_fault".to_string()),
+                (UploadClass::LiveFix, "a2".to_string()),
+                (UploadClass::Backfill, "a1".to_string()),
+            ]
+        );
+        let counts: Vec<_> = queue.expired().collect();
+        assert_eq!(
+            counts,
+            [
+                (UploadClass::LiveFix, 1),
+                (UploadClass::Backfill, 1),
+                (UploadClass::Event, 1)
+            ]
+        );
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn events_are_only_dropped_when_there_are_no_fixes() {
+        let mut queue = RetryQueue::new(2, DrainPolicy::OldestFirst, TTL);
+        assert!(queue.push(fix("a", "a1")).is_none());
+        assert!(queue
+            .push_event(event("a", "power_lost", Duration::ZERO))
+            .is_none());
+        match queue.push_event(event("b", "power_lost", Duration::ZERO)) {
+            Some(Pending::Fix(u)) => assert_eq!(u.gps.uid, "a1"),
+            other => panic!("dropped {other:?}"),
+        }
+        // A new fix doesn't push out an event, it's dropped itself
+        match queue.push(fix("c", "c1")) {
+            Some(Pending::Fix(u)) => assert_eq!(u.gps.uid, "c1"),
+            other => panic!("dropped {other:?}"),
+        }
+        // With only events left, the oldest one goes
+        match queue.push_event(event("c", "gps_fault", Duration::ZERO)) {
+            Some(Pending::Event(e)) => assert_eq!(e.src, "a"),
+            other => panic!("dropped {other:?}"),
+        }
+        let expected = [("b", "power_lost", false), ("c", "gps_fault", false)];
+        assert_eq!(drain(&mut queue), fixes(&expected));
+    }
+
+    #[test]
+    fn the_age_is_measured_on_the_monotonic_clock() {
+        let mut queue = RetryQueue::new(16, DrainPolicy::OldestFirst, TTL);
+        let mut upload = fix("a", "a1");
+        upload.received_at = Duration::from_secs(1000);
+        // A wall clock that was set long after the fix doesn't make it old
+        upload.received = Some(0);
+        upload.timestamp = 0;
+        queue.push(upload);
+        let now = Duration::from_secs(1000) + TTL.live_fix;
+        assert!(queue.pop(now, |e| panic!("{} expired", e.id)).is_some());
+
+        // Nor does a monotonic time from before the fix
+        queue.push_event(event("a", "power_lost", Duration::from_secs(1000)));
+        assert!(queue
+            .pop(Duration::ZERO, |e| panic!("{} expired", e.id))
+            .is_some());
+    }
+
+    #[test]
+    fn requeued_uploads_keep_their_age() {
+        let mut queue = queue(DrainPolicy::OldestFirst, &[("a", "a1")]);
+        queue.push_event(event("b", "power_lost", Duration::ZERO));
+        let now = Duration::from_secs(30);
+        let first = queue.pop(now, |e| panic!("{} expired", e.id)).unwrap();
+        queue.requeue(first);
+        let second = queue.pop(now, |e| panic!("{} expired", e.id)).unwrap();
+        queue.requeue(second);
+
+        let mut expired = Vec::new();
+        let later = TTL.live_fix + Duration::from_secs(1);
+        let popped = queue.pop(later, |e| expired.push(e.id)).unwrap();
+        assert_eq!(popped.id(), "power_lost");
+        assert!(queue.pop(later, |e| expired.push(e.id)).is_none());
+        assert_eq!(expired, ["a1"]);
+    }
+}
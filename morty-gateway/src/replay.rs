@@ -0,0 +1,415 @@
+//! Replay of a capture of the UART, to see what the pipeline makes of a night when fixes went
+//! missing. Captures are files on the `captures` SPIFFS partition, in the line format the beacon
+//! writes, see `partitions.csv` for how they get there. `replay <file>` on the console feeds them
+//! through the frame decoder, dedup, validation and serializer, like the lines from the UART.
+//!
+//! A replay has a dedup cache, frame counts and source metrics of its own, so it doesn't show up
+//! in the live stats and the live dedup cache doesn't drop its fixes. Nothing is uploaded: the
+//! payloads and the frames that didn't decode are kept in the `Replay`, and its `Summary` can be
+//! held against what the backend got. Lines are taken to come in `LINE_INTERVAL` apart, so the
+//! same capture gives the same summary every time.
+
+use crate::capture::Cause;
+use crate::capture::FailedFrames;
+use crate::frames::decode_line;
+use crate::frames::FrameCounts;
+use crate::serializer::Serializer;
+use crate::validate_fix;
+use crate::DEDUP_CACHE_SIZE;
+use crate::FRAME_BUFFER_LEN;
+use crate::SOURCE_IDLE_EVICT;
+use crate::SOURCE_METRICS_SIZE;
+use anyhow::bail;
+use esp_idf_sys::esp;
+use morty_rs::dedup::DedupCache;
+use morty_rs::messages::morty_message::Msg;
+use morty_rs::messages::relay_msg;
+use morty_rs::source_metrics::SourceMetrics;
+use std::fmt;
+use std::io::BufRead;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Where the captures partition is mounted.
+pub const MOUNT_POINT: &str = "/captures";
+const BASE_PATH: &[u8] = b"/captures\0";
+const PARTITION_LABEL: &[u8] = b"captures\0";
+// Files open at the same time, we only read one
+const MAX_FILES: usize = 2;
+// Time between two lines of a capture
+const LINE_INTERVAL: Duration = Duration::from_millis(1);
+// Frames that didn't decode kept from a replay
+const FAILED_FRAMES: usize = 50;
+
+static MOUNTED: Mutex<bool> = Mutex::new(false);
+
+/// What a replay made of a capture.
+pub struct Summary {
+    pub lines: u32,
+    /// Lines without the UART header
+    pub invalid_lines: u32,
+    pub frames: FrameCounts,
+    /// Frames that weren't valid base64, `frames` sorts the rest of the failures
+    pub base64_failures: u32,
+    pub sources: SourceMetrics<SOURCE_METRICS_SIZE>,
+    /// Fixes we hadn't seen
+    pub validated: u32,
+    /// Retransmissions with news, uploaded again
+    pub updates: u32,
+    pub duplicates: u32,
+    /// Frames that decoded to something other than a fix
+    pub other: u32,
+    pub payload_bytes: usize,
+}
+
+impl Summary {
+    pub fn new() -> Self {
+        Self {
+            lines: 0,
+            invalid_lines: 0,
+            frames: FrameCounts::new(),
+            base64_failures: 0,
+            sources: SourceMetrics::new(SOURCE_IDLE_EVICT),
+            validated: 0,
+            updates: 0,
+            duplicates: 0,
+            other: 0,
+            payload_bytes: 0,
+        }
+    }
+}
+
+impl Default for Summary {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "lines={} invalid={}", self.lines, self.invalid_lines)?;
+        writeln!(
+            f,
+            "frames decoded={} failed={} oversized={}",
+            self.frames.decoded.load(Ordering::Relaxed),
+            self.frames.failed.load(Ordering::Relaxed),
+            self.frames.oversized.load(Ordering::Relaxed)
+        )?;
+        writeln!(
+            f,
+            "failures base64={} {}",
+            self.base64_failures, self.frames.decode_failures
+        )?;
+        writeln!(
+            f,
+            "fixes validated={} updates={} duplicates={} other={}",
+            self.validated, self.updates, self.duplicates, self.other
+        )?;
+        writeln!(
+            f,
+            "payloads={} bytes={}",
+            self.validated + self.updates,
+            self.payload_bytes
+        )?;
+        write!(f, "sources {}", self.sources)
+    }
+}
+
+/// A payload the pipeline would have uploaded.
+pub struct Payload {
+    pub src: String,
+    pub uid: String,
+    pub data: Vec<u8>,
+}
+
+/// The pipeline, without the network.
+pub struct Replay {
+    cache: DedupCache,
+    serializer: Box<dyn Serializer + Send>,
+    buffer: [u8; FRAME_BUFFER_LEN],
+    received_at: Duration,
+    pub summary: Summary,
+    pub payloads: Vec<Payload>,
+    pub failed: FailedFrames,
+}
+
+impl Replay {
+    pub fn new(serializer: Box<dyn Serializer + Send>) -> Self {
+        let mut failed = FailedFrames::new(FAILED_FRAMES, Duration::MAX);
+        failed.start(Duration::ZERO);
+        Self {
+            cache: DedupCache::new(DEDUP_CACHE_SIZE),
+            serializer,
+            buffer: [0; FRAME_BUFFER_LEN],
+            received_at: Duration::ZERO,
+            summary: Summary::new(),
+            payloads: Vec::new(),
+            failed,
+        }
+    }
+
+    /// Feed a line of the capture through the pipeline.
+    pub fn line(&mut self, line: &str) {
+        self.received_at += LINE_INTERVAL;
+        self.summary.lines += 1;
+        let received_at = self.received_at;
+        let Self {
+            cache,
+            serializer,
+            buffer,
+            summary,
+            payloads,
+            failed,
+            ..
+        } = self;
+        let valid = decode_line(line, received_at, buffer, &summary.frames, |frame| {
+            let relay = match frame {
+                Ok(Some(Msg::Relay(relay))) => relay,
+                Ok(_) => {
+                    summary.other += 1;
+                    return;
+                }
+                Err((frame, _)) => {
+                    if frame.cause == Cause::Base64 {
+                        summary.base64_failures += 1;
+                    }
+                    failed.record(frame, received_at);
+                    return;
+                }
+            };
+            let Some(relay_msg::Msg::Gps(gps)) = relay.msg else {
+                summary.other += 1;
+                return;
+            };
+            match validate_fix(
+                relay.src,
+                relay.timestamp,
+                relay.path_delay_ms,
+                gps,
+                received_at,
+                cache,
+                &summary.sources,
+            ) {
+                Ok(upload) => {
+                    if upload.update {
+                        summary.updates += 1;
+                    } else {
+                        summary.validated += 1;
+                    }
+                    let data = serializer.serialize(&upload);
+                    summary.payload_bytes += data.len();
+                    payloads.push(Payload {
+                        src: upload.src,
+                        uid: upload.gps.uid,
+                        data,
+                    });
+                }
+                Err(_) => summary.duplicates += 1,
+            }
+        });
+        if !valid {
+            self.summary.invalid_lines += 1;
+        }
+    }
+
+    /// Feed every line of `input` through the pipeline.
+    pub fn run(&mut self, input: impl BufRead) -> Result<(), anyhow::Error> {
+        for line in input.lines() {
+            self.line(&line?);
+        }
+        Ok(())
+    }
+}
+
+/// Mount the captures partition, when it isn't yet.
+pub fn mount() -> Result<(), anyhow::Error> {
+    let mut mounted = MOUNTED.lock().unwrap();
+    if *mounted {
+        return Ok(());
+    }
+    let conf = esp_idf_sys::esp_vfs_spiffs_conf_t {
+        base_path: BASE_PATH.as_ptr() as *const _,
+        partition_label: PARTITION_LABEL.as_ptr() as *const _,
+        max_files: MAX_FILES,
+        // Formatting would throw away the captures we couldn't read
+        format_if_mount_failed: false,
+    };
+    if let Err(e) = esp!(unsafe { esp_idf_sys::esp_vfs_spiffs_register(&conf) }) {
+        bail!("Unable to mount the captures partition: {e}");
+    }
+    *mounted = true;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serializer::JsonSerializer;
+    use crate::SOURCE_METRICS;
+    use crate::UART_FRAMES;
+    use base64::engine::general_purpose;
+    use base64::Engine;
+    use morty_rs::comm::encode_msg;
+    use morty_rs::comm::DecodeFailure;
+    use morty_rs::comm::UART_HEADER;
+    use morty_rs::messages::GpsMsg;
+    use morty_rs::messages::PingMsg;
+    use morty_rs::messages::RelayMsg;
+
+    const A: &str = "24:0a:c4:00:00:01";
+    const B: &str = "24:0a:c4:00:00:02";
+
+    fn relay(src: &str, uid: &str, charging: bool) -> Vec<u8> {
+        let gps = GpsMsg {
+            uid: uid.to_string(),
+            charging,
+            battery_voltage: 3.7,
+            ..Default::default()
+        };
+        encode_msg(&Msg::Relay(RelayMsg {
+            src: src.to_string(),
+            timestamp: 1_700_000_000,
+            msg: Some(relay_msg::Msg::Gps(gps)),
+            ..Default::default()
+        }))
+        .unwrap()
+    }
+
+    fn ping() -> Vec<u8> {
+        encode_msg(&Msg::Ping(PingMsg::default())).unwrap()
+    }
+
+    fn line(frames: &[&[u8]]) -> String {
+        let frames: Vec<_> = frames
+            .iter()
+            .map(|frame| general_purpose::STANDARD.encode(frame))
+            .collect();
+        format!("{UART_HEADER}{}\n", frames.join(","))
+    }
+
+    // A night with a bit of everything
+    fn capture() -> String {
+        let mut corrupted = relay(A, "a2", false);
+        *corrupted.last_mut().unwrap() ^= 0xff;
+        [
+            line(&[&relay(A, "a1", false), &ping()]),
+            // Relayed by another beacon
+            line(&[&relay(A, "a1", false)]),
+            // Retransmitted once it started charging
+            line(&[&relay(A, "a1", true)]),
+            "boot: rst:0x1 (POWERON_RESET)\n".to_string(),
+            format!(
+                "{UART_HEADER}not base64!,{}\n",
+                general_purpose::STANDARD.encode(&corrupted)
+            ),
+            line(&[&relay(B, "b1", false)]),
+        ]
+        .concat()
+    }
+
+    fn replay(capture: &str) -> Replay {
+        let mut replay = Replay::new(Box::new(JsonSerializer));
+        replay.run(capture.as_bytes()).unwrap();
+        replay
+    }
+
+    #[test]
+    fn replays_count_what_happened() {
+        let replay = replay(&capture());
+        let summary = &replay.summary;
+        assert_eq!(summary.lines, 6);
+        assert_eq!(summary.invalid_lines, 1);
+        assert_eq!(summary.frames.decoded.load(Ordering::Relaxed), 5);
+        assert_eq!(summary.frames.failed.load(Ordering::Relaxed), 2);
+        assert_eq!(summary.base64_failures, 1);
+        assert_eq!(
+            summary
+                .frames
+                .decode_failures
+                .get(DecodeFailure::CrcMismatch),
+            1
+        );
+        assert_eq!(summary.validated, 2);
+        assert_eq!(summary.updates, 1);
+        assert_eq!(summary.duplicates, 1);
+        assert_eq!(summary.other, 1);
+
+        let payloads: Vec<_> = replay
+            .payloads
+            .iter()
+            .map(|p| (p.src.as_str(), p.uid.as_str()))
+            .collect();
+        assert_eq!(payloads, [(A, "a1"), (A, "a1"), (B, "b1")]);
+        let bytes: usize = replay.payloads.iter().map(|p| p.data.len()).sum();
+        assert_eq!(summary.payload_bytes, bytes);
+        let causes: Vec<_> = replay.failed.frames().iter().map(|f| f.cause).collect();
+        assert_eq!(
+            causes,
+            [Cause::Base64, Cause::Decode(DecodeFailure::CrcMismatch)]
+        );
+    }
+
+    #[test]
+    fn summaries_read_like_the_stats() {
+        let replay = replay(&capture());
+        let summary = replay.summary.to_string();
+        let lines: Vec<_> = summary.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines[0], "lines=6 invalid=1");
+        assert_eq!(lines[1], "frames decoded=5 failed=2 oversized=0");
+        assert_eq!(
+            lines[2],
+            "failures base64=1 decode_truncated=0 decode_crc_mismatch=1 decode_type_mismatch=0 \
+             decode_protobuf_error=0 decode_hmac_fail=0 decode_decrypt_fail=0 \
+             decode_version_unknown=0"
+        );
+        assert_eq!(lines[3], "fixes validated=2 updates=1 duplicates=1 other=1");
+        assert_eq!(
+            lines[4],
+            format!("payloads=3 bytes={}", replay.summary.payload_bytes)
+        );
+        assert!(lines[5].starts_with("sources "));
+        assert!(lines[5].contains(&format!("{A} fixes=1 duplicates=1 lost=0")));
+        assert!(lines[5].contains(&format!("{B} fixes=1 duplicates=0 lost=0")));
+    }
+
+    #[test]
+    fn empty_captures_summarize_to_nothing() {
+        let replay = replay("");
+        assert!(replay.payloads.is_empty());
+        let summary = replay.summary.to_string();
+        assert!(summary.starts_with("lines=0 invalid=0\nframes decoded=0 failed=0 oversized=0\n"));
+        assert!(summary.contains("payloads=0 bytes=0\n"));
+    }
+
+    #[test]
+    fn replays_are_deterministic() {
+        let first = replay(&capture());
+        let second = replay(&capture());
+        assert_eq!(first.summary.to_string(), second.summary.to_string());
+        let data = |replay: &Replay| -> Vec<Vec<u8>> {
+            replay.payloads.iter().map(|p| p.data.clone()).collect()
+        };
+        assert_eq!(data(&first), data(&second));
+    }
+
+    // What the live pipeline counted
+    fn live_stats() -> (u32, u32, u32, String) {
+        (
+            UART_FRAMES.decoded.load(Ordering::Relaxed),
+            UART_FRAMES.failed.load(Ordering::Relaxed),
+            UART_FRAMES.decode_failures.total(),
+            SOURCE_METRICS.to_string(),
+        )
+    }
+
+    #[test]
+    fn replays_dont_touch_the_live_stats() {
+        let before = live_stats();
+        let replay = replay(&capture());
+        assert_eq!(live_stats(), before);
+        assert!(!SOURCE_METRICS.to_string().contains(A));
+        assert!(replay.summary.sources.to_string().contains(A));
+    }
+}
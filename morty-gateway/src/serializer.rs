@@ -0,0 +1,581 @@
+use crate::cbor;
+use crate::mapping::FieldMapping;
+use crate::mapping::MappedJsonSerializer;
+use crate::privacy::Privacy;
+use crate::queue::PendingUpload;
+use crate::GEOHASH_PRECISION;
+use json::JsonValue;
+use log::*;
+use morty_rs::dedup::Fingerprint;
+use morty_rs::geo::geohash;
+use morty_rs::gps_state::state_name;
+use morty_rs::messages::relay_msg;
+use morty_rs::messages::GpsState;
+use morty_rs::messages::RelayMsg;
+use morty_rs::timesync::source_name;
+use prost::Message;
+
+/// The format of the uploads.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug)]
+pub enum PayloadFormat {
+    /// Our own JSON format, what the default backend expects
+    Json,
+    /// Our own format in CBOR with short keys, see `CBOR_KEYS`. About half the size of `Json`, for
+    /// gateways that pay for their bandwidth.
+    Cbor,
+    /// Protobuf encoded RelayMsg, for backends that speak protobuf natively
+    Protobuf,
+    /// JSON with the fields of a mapping, for third-party backends
+    Mapped(&'static [FieldMapping]),
+}
+
+impl PayloadFormat {
+    /// The serializer for this format. Fails when a mapping doesn't check out.
+    pub fn serializer(&self) -> Result<Box<dyn Serializer + Send>, anyhow::Error> {
+        Ok(match self {
+            PayloadFormat::Json => Box::new(JsonSerializer),
+            PayloadFormat::Cbor => Box::new(CborSerializer),
+            PayloadFormat::Protobuf => Box::new(ProtobufSerializer),
+            PayloadFormat::Mapped(fields) => Box::new(MappedJsonSerializer::new(fields)?),
+        })
+    }
+}
+
+/// Turns a pending upload into the body of the request to the API server.
+pub trait Serializer {
+    fn content_type(&self) -> &'static str;
+    fn serialize(&self, upload: &PendingUpload) -> Vec<u8>;
+}
+
+// FNV-1a, 64 bit
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// The Idempotency-Key of a request with a body made of `parts`: the same for every attempt,
+/// another one when any part changes. This is FNV-1a rather than the hasher of std, which can
+/// change between builds, so a firmware update doesn't change the key of an upload that's being
+/// retried. Parts are separated by a 0, so moving a byte from one to the next changes the key.
+pub fn idempotency_key(parts: &[&[u8]]) -> String {
+    let hash = parts.iter().fold(FNV_OFFSET, |hash, part| {
+        part.iter()
+            .chain(&[0])
+            .fold(hash, |hash, &b| (hash ^ b as u64).wrapping_mul(FNV_PRIME))
+    });
+    format!("{hash:016x}")
+}
+
+/// The Idempotency-Key of the upload of a fix, from its source, the boot of the tracker, its uid
+/// and its `Fingerprint`. Retries of an upload have the same key, an update of the fix another
+/// one. What we add ourselves, like the privacy or the stale flag, doesn't count.
+pub fn upload_key(upload: &PendingUpload) -> String {
+    idempotency_key(&[
+        upload.src.as_bytes(),
+        &upload.gps.boot_id.to_le_bytes(),
+        upload.gps.uid.as_bytes(),
+        &Fingerprint::of(&upload.gps).to_bytes(),
+    ])
+}
+
+/// Short keys of the fields in CBOR uploads. The backend expands them again.
+const CBOR_KEYS: &[(&str, &str)] = &[
+    ("latitude", "lat"),
+    ("longitude", "lon"),
+    ("hdop", "hd"),
+    ("timestamp", "ts"),
+    ("received", "rx"),
+    ("utc", "utc"),
+    ("fix_epoch", "fe"),
+    ("sent_epoch", "se"),
+    ("relay_epoch", "re"),
+    ("gateway_epoch", "ge"),
+    ("fix_quality", "fq"),
+    ("satellites", "sat"),
+    ("uid", "id"),
+    ("charging", "ch"),
+    ("battery_voltage", "bv"),
+    ("tx_power", "tx"),
+    ("boot_id", "bi"),
+    ("seq", "sq"),
+    ("gps_fault_suspected", "gf"),
+    ("profile", "pr"),
+    ("config_version", "cv"),
+    ("backfill", "bf"),
+    ("path_delay_ms", "pd"),
+    ("geohash", "gh"),
+    ("test", "t"),
+    ("stale", "st"),
+    ("privacy", "pv"),
+    ("update_of", "uo"),
+    ("clock_source", "cs"),
+    ("clock_offset_ms", "co"),
+    ("gps_state", "gs"),
+    ("idempotency_key", "ik"),
+    ("gateway_hint", "gw"),
+    ("name", "nm"),
+];
+
+/// A value in our own payload format, before it's written as JSON or CBOR.
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    Null,
+    Bool(bool),
+    Int(i64),
+    F32(f32),
+    F64(f64),
+    Str(String),
+}
+
+/// The fields of our own payload format, in order. JSON and CBOR are both written from these, so
+/// they can't get different fields.
+fn fields(upload: &PendingUpload) -> Vec<(&'static str, Value)> {
+    let gps = &upload.gps;
+    let times = upload.times();
+    let epoch = |time: Option<i64>| time.map_or(Value::Null, Value::Int);
+    // A suppressed position is left out rather than sent as 0, 0
+    let position = |value| {
+        if upload.privacy == Privacy::Suppressed {
+            Value::Null
+        } else {
+            Value::F64(value)
+        }
+    };
+    let mut fields = vec![
+        ("latitude", position(gps.latitude)),
+        ("longitude", position(gps.longitude)),
+        ("hdop", Value::F32(gps.hdop)),
+        // The times of the fix, see `morty_rs::timestamps`, null when they aren't known
+        ("fix_epoch", epoch(times.fix)),
+        ("sent_epoch", epoch(times.sent)),
+        ("relay_epoch", epoch(times.relay)),
+        ("gateway_epoch", epoch(times.gateway)),
+        // Deprecated, the relay epoch, gateway epoch and time of day of the fix, as they were
+        // sent before. Still here for backends that don't know the epochs.
+        ("timestamp", Value::Int(upload.timestamp)),
+        ("received", upload.received.map_or(Value::Null, Value::Int)),
+        ("utc", Value::Int(gps.utc as i64)),
+        ("fix_quality", Value::Int(gps.fix_quality as i64)),
+        ("satellites", Value::Int(gps.satellites as i64)),
+        ("uid", Value::Str(gps.uid.clone())),
+        ("charging", Value::Bool(gps.charging)),
+        ("battery_voltage", Value::F32(gps.battery_voltage)),
+        ("tx_power", Value::F32(gps.tx_power)),
+        ("boot_id", Value::Int(gps.boot_id as i64)),
+        ("seq", Value::Int(gps.seq as i64)),
+        ("gps_fault_suspected", Value::Bool(gps.gps_fault_suspected)),
+        ("profile", Value::Str(gps.profile.clone())),
+        ("config_version", Value::Int(gps.config_version as i64)),
+        ("backfill", Value::Bool(upload.backfill)),
+        ("path_delay_ms", Value::Int(upload.path_delay_ms as i64)),
+        // Also in the header, for backends that prefer it in the body
+        ("idempotency_key", Value::Str(upload_key(upload))),
+    ];
+    // Only add a geohash when we have an actual fix
+    if gps.fix_quality > 0 {
+        fields.push((
+            "geohash",
+            Value::Str(geohash(gps.latitude, gps.longitude, GEOHASH_PRECISION)),
+        ));
+    }
+    if upload.test {
+        fields.push(("test", Value::Bool(true)));
+    }
+    if upload.stale {
+        fields.push(("stale", Value::Bool(true)));
+    }
+    if upload.privacy != Privacy::Full {
+        fields.push(("privacy", Value::Str(upload.privacy.name().to_string())));
+    }
+    if upload.update {
+        fields.push(("update_of", Value::Str(gps.uid.clone())));
+    }
+    // Only in the reports that go out with the name, see `morty_rs::naming`
+    if !gps.name.is_empty() {
+        fields.push(("name", Value::Str(gps.name.clone())));
+    }
+    if !upload.gateway_hint.is_empty() {
+        fields.push(("gateway_hint", Value::Str(upload.gateway_hint.clone())));
+    }
+    if gps.gps_state() != GpsState::Unknown {
+        fields.push((
+            "gps_state",
+            Value::Str(state_name(gps.gps_state).to_string()),
+        ));
+    }
+    // Trackers that don't know about time beacons don't report their clock
+    if let Some(clock) = &gps.clock {
+        fields.push((
+            "clock_source",
+            Value::Str(source_name(clock.source).to_string()),
+        ));
+        fields.push(("clock_offset_ms", Value::Int(clock.offset_ms as i64)));
+    }
+    fields
+}
+
+/// Write `fields` as a JSON object.
+fn to_json(fields: &[(&'static str, Value)]) -> Vec<u8> {
+    let mut json = JsonValue::new_object();
+    for (name, value) in fields {
+        json[*name] = match value {
+            Value::Null => JsonValue::Null,
+            Value::Bool(b) => (*b).into(),
+            Value::Int(n) => (*n).into(),
+            Value::F32(f) => (*f).into(),
+            Value::F64(f) => (*f).into(),
+            Value::Str(s) => s.as_str().into(),
+        };
+    }
+    json.dump().into_bytes()
+}
+
+/// Write `fields` as a CBOR map, with the keys from `CBOR_KEYS`.
+fn to_cbor(fields: &[(&'static str, Value)]) -> Vec<u8> {
+    let mut cbor = cbor::Encoder::new();
+    cbor.map(fields.len());
+    for (name, value) in fields {
+        cbor.text(cbor_key(name));
+        match value {
+            Value::Null => cbor.null(),
+            Value::Bool(b) => cbor.bool(*b),
+            Value::Int(n) => cbor.int(*n),
+            Value::F32(f) => cbor.f32(*f),
+            Value::F64(f) => cbor.f64(*f),
+            Value::Str(s) => cbor.text(s),
+        };
+    }
+    cbor.finish()
+}
+
+fn cbor_key(name: &'static str) -> &'static str {
+    CBOR_KEYS
+        .iter()
+        .find(|(long, _)| *long == name)
+        .map_or(name, |(_, short)| short)
+}
+
+/// Serializes uploads to a flat JSON object. This is what the default backend expects.
+pub struct JsonSerializer;
+
+impl Serializer for JsonSerializer {
+    fn content_type(&self) -> &'static str {
+        "application/json"
+    }
+
+    fn serialize(&self, upload: &PendingUpload) -> Vec<u8> {
+        to_json(&fields(upload))
+    }
+}
+
+/// Serializes uploads to a CBOR map, with the same fields as `JsonSerializer` under shorter keys.
+pub struct CborSerializer;
+
+impl Serializer for CborSerializer {
+    fn content_type(&self) -> &'static str {
+        "application/cbor"
+    }
+
+    fn serialize(&self, upload: &PendingUpload) -> Vec<u8> {
+        let fields = fields(upload);
+        let cbor = to_cbor(&fields);
+        if log_enabled!(Level::Debug) {
+            let json = to_json(&fields).len();
+            debug!(
+                "Payload of {} is {} bytes, {} bytes ({}%) less than JSON",
+                upload.gps.uid,
+                cbor.len(),
+                json.saturating_sub(cbor.len()),
+                json.saturating_sub(cbor.len()) * 100 / json.max(1)
+            );
+        }
+        cbor
+    }
+}
+
+/// Serializes uploads to a protobuf encoded RelayMsg, for backends that speak protobuf natively.
+/// RelayMsg has no room for the privacy, a suppressed position is sent as 0, 0 with a fix quality
+/// of 0. Neither has it for updates, these are sent like the fix they update.
+pub struct ProtobufSerializer;
+
+impl Serializer for ProtobufSerializer {
+    fn content_type(&self) -> &'static str {
+        "application/x-protobuf"
+    }
+
+    fn serialize(&self, upload: &PendingUpload) -> Vec<u8> {
+        RelayMsg {
+            src: upload.src.clone(),
+            timestamp: upload.timestamp,
+            backfill: upload.backfill,
+            path_delay_ms: upload.path_delay_ms,
+            gateway_hint: upload.gateway_hint.clone(),
+            msg: Some(relay_msg::Msg::Gps(upload.gps.clone())),
+        }
+        .encode_to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use morty_rs::messages::ClockStatusMsg;
+    use morty_rs::messages::GpsMsg;
+    use morty_rs::messages::TimeSource;
+    use std::time::Duration;
+
+    /// An upload with every optional field set.
+    fn upload() -> PendingUpload {
+        let gps = GpsMsg {
+            latitude: 52.375,
+            longitude: 4.875,
+            fix_quality: 1,
+            satellites: 9,
+            hdop: 0.5,
+            uid: "1234".to_string(),
+            battery_voltage: 4.0,
+            tx_power: 8.5,
+            boot_id: 0xdead_beef,
+            seq: 42,
+            name: "Morty".to_string(),
+            clock: Some(ClockStatusMsg {
+                source: TimeSource::Beacon as i32,
+                offset_ms: -250,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let mut upload = PendingUpload::new(
+            "aa:bb:cc:dd:ee:ff".to_string(),
+            1_680_350_400,
+            Duration::ZERO,
+            gps,
+        );
+        upload.gateway_hint = "gw-1".to_string();
+        upload.update = true;
+        upload.test = true;
+        upload.stale = true;
+        upload.privacy = Privacy::Rounded100m;
+        upload
+    }
+
+    /// Read a CBOR map with text keys, as written by `to_cbor`.
+    fn decode(mut cbor: &[u8]) -> Vec<(String, Value)> {
+        let len = match item(&mut cbor) {
+            Item::Map(len) => len,
+            item => panic!("Expected a map, got {item:?}"),
+        };
+        let fields = (0..len)
+            .map(|_| match (item(&mut cbor), item(&mut cbor)) {
+                (Item::Value(Value::Str(key)), Item::Value(value)) => (key, value),
+                pair => panic!("Expected a text key and a value, got {pair:?}"),
+            })
+            .collect();
+        assert!(cbor.is_empty(), "{} bytes left", cbor.len());
+        fields
+    }
+
+    #[derive(Debug)]
+    enum Item {
+        Map(u64),
+        Value(Value),
+    }
+
+    fn take<'a>(cbor: &mut &'a [u8], n: usize) -> &'a [u8] {
+        let (taken, rest) = cbor.split_at(n);
+        *cbor = rest;
+        taken
+    }
+
+    fn item(cbor: &mut &[u8]) -> Item {
+        let initial = take(cbor, 1)[0];
+        let (major, info) = (initial >> 5, initial & 0x1f);
+        let argument = |cbor: &mut &[u8]| match info {
+            0..=23 => info as u64,
+            24 => take(cbor, 1)[0] as u64,
+            25 => u16::from_be_bytes(take(cbor, 2).try_into().unwrap()) as u64,
+            26 => u32::from_be_bytes(take(cbor, 4).try_into().unwrap()) as u64,
+            27 => u64::from_be_bytes(take(cbor, 8).try_into().unwrap()),
+            _ => panic!("Unexpected initial byte {initial:#04x}"),
+        };
+        Item::Value(match (major, info) {
+            (0, _) => Value::Int(argument(cbor) as i64),
+            (1, _) => Value::Int(-1 - argument(cbor) as i64),
+            (3, _) => {
+                let len = argument(cbor) as usize;
+                Value::Str(String::from_utf8(take(cbor, len).to_vec()).unwrap())
+            }
+            (5, _) => return Item::Map(argument(cbor)),
+            (7, 20) => Value::Bool(false),
+            (7, 21) => Value::Bool(true),
+            (7, 22) => Value::Null,
+            (7, 26) => Value::F32(f32::from_be_bytes(take(cbor, 4).try_into().unwrap())),
+            (7, 27) => Value::F64(f64::from_be_bytes(take(cbor, 8).try_into().unwrap())),
+            _ => panic!("Unexpected initial byte {initial:#04x}"),
+        })
+    }
+
+    #[test]
+    fn cbor_payloads_decode_to_the_fields_under_their_short_keys() {
+        for upload in [
+            upload(),
+            PendingUpload::new(String::new(), 0, Duration::ZERO, Default::default()),
+        ] {
+            let expected: Vec<_> = fields(&upload)
+                .into_iter()
+                .map(|(name, value)| (cbor_key(name).to_string(), value))
+                .collect();
+            assert_eq!(decode(&CborSerializer.serialize(&upload)), expected);
+        }
+    }
+
+    #[test]
+    fn json_payloads_have_the_same_fields() {
+        let upload = upload();
+        let json =
+            json::parse(std::str::from_utf8(&JsonSerializer.serialize(&upload)).unwrap()).unwrap();
+        let fields = fields(&upload);
+        assert_eq!(json.len(), fields.len());
+        for (name, value) in fields {
+            let json = &json[name];
+            match value {
+                Value::Null => assert!(json.is_null(), "{name}"),
+                Value::Bool(b) => assert_eq!(json.as_bool(), Some(b), "{name}"),
+                Value::Int(n) => assert_eq!(json.as_i64(), Some(n), "{name}"),
+                Value::F32(f) => assert_eq!(json.as_f32(), Some(f), "{name}"),
+                Value::F64(f) => assert_eq!(json.as_f64(), Some(f), "{name}"),
+                Value::Str(s) => assert_eq!(json.as_str(), Some(s.as_str()), "{name}"),
+            }
+        }
+    }
+
+    #[test]
+    fn every_field_has_a_unique_short_key() {
+        for (name, _) in fields(&upload()) {
+            assert!(CBOR_KEYS.iter().any(|(long, _)| *long == name), "{name}");
+        }
+        for (i, (long, short)) in CBOR_KEYS.iter().enumerate() {
+            assert!(short.len() <= long.len(), "{long}");
+            assert!(
+                !CBOR_KEYS[i + 1..].iter().any(|(_, other)| other == short),
+                "{short}"
+            );
+        }
+    }
+
+    #[test]
+    fn suppressed_positions_are_null() {
+        let mut upload = upload();
+        upload.privacy = Privacy::Suppressed;
+        let fields = decode(&CborSerializer.serialize(&upload));
+        let field = |key: &str| {
+            fields
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.clone())
+        };
+        assert_eq!(field("lat"), Some(Value::Null));
+        assert_eq!(field("lon"), Some(Value::Null));
+        assert_eq!(field("pv"), Some(Value::Str("suppressed".to_string())));
+    }
+
+    #[test]
+    fn content_types() {
+        assert_eq!(JsonSerializer.content_type(), "application/json");
+        assert_eq!(CborSerializer.content_type(), "application/cbor");
+        assert_eq!(ProtobufSerializer.content_type(), "application/x-protobuf");
+    }
+
+    #[test]
+    fn idempotency_keys_are_fnv1a_of_the_parts() {
+        assert_eq!(idempotency_key(&[]), "cbf29ce484222325");
+        assert_eq!(idempotency_key(&[b""]), "af63bd4c8601b7df");
+        assert_eq!(idempotency_key(&[b"ab", b"c"]), "ad22872f536e4705");
+        // Moving a byte to the next part changes the key
+        assert_eq!(idempotency_key(&[b"a", b"bc"]), "401801fc84f3ca79");
+    }
+
+    #[test]
+    fn the_key_of_an_upload_is_pinned() {
+        // Retries in flight during a firmware update depend on this
+        assert_eq!(upload_key(&upload()), "bede2da193337751");
+    }
+
+    #[test]
+    fn retries_of_an_upload_have_the_same_key() {
+        let key = upload_key(&upload());
+        let mut retry = upload();
+        retry.attempts = 3;
+        retry.gateway_hint = "gw-2".to_string();
+        retry.stale = false;
+        retry.privacy = Privacy::Full;
+        retry.path_delay_ms = 1_500;
+        assert_eq!(upload_key(&retry), key);
+        // A voltage in the same step of the fingerprint
+        retry.gps.battery_voltage = 4.05;
+        assert_eq!(upload_key(&retry), key);
+    }
+
+    #[test]
+    fn an_update_or_another_fix_has_another_key() {
+        let key = upload_key(&upload());
+        let changes: [fn(&mut PendingUpload); 5] = [
+            |u| u.src = "aa:bb:cc:dd:ee:00".to_string(),
+            |u| u.gps.boot_id += 1,
+            |u| u.gps.uid = "1235".to_string(),
+            |u| u.gps.charging = true,
+            |u| u.gps.battery_voltage = 3.9,
+        ];
+        for (i, change) in changes.iter().enumerate() {
+            let mut upload = upload();
+            change(&mut upload);
+            assert_ne!(upload_key(&upload), key, "change {i}");
+        }
+    }
+
+    #[test]
+    fn the_key_is_in_the_payload() {
+        let upload = upload();
+        let fields = fields(&upload);
+        let key = fields.iter().find(|(name, _)| *name == "idempotency_key");
+        assert_eq!(
+            key,
+            Some(&("idempotency_key", Value::Str(upload_key(&upload))))
+        );
+        assert_eq!(cbor_key("idempotency_key"), "ik");
+    }
+
+    fn field(upload: &PendingUpload, name: &str) -> Value {
+        let fields = fields(upload);
+        let found = fields.into_iter().find(|(n, _)| *n == name);
+        found.unwrap_or_else(|| panic!("No {name}")).1
+    }
+
+    #[test]
+    fn every_time_of_a_fix_has_a_field_of_its_own() {
+        let mut upload = upload();
+        upload.gps.utc = 12 * 3600;
+        upload.gps.fix_epoch = 1_680_350_390;
+        upload.gps.sent_epoch = 1_680_350_395;
+        upload.received = Some(1_680_350_401);
+        assert_eq!(field(&upload, "fix_epoch"), Value::Int(1_680_350_390));
+        assert_eq!(field(&upload, "sent_epoch"), Value::Int(1_680_350_395));
+        assert_eq!(field(&upload, "relay_epoch"), Value::Int(1_680_350_400));
+        assert_eq!(field(&upload, "gateway_epoch"), Value::Int(1_680_350_401));
+        // Still there for older backends
+        assert_eq!(field(&upload, "timestamp"), Value::Int(1_680_350_400));
+        assert_eq!(field(&upload, "received"), Value::Int(1_680_350_401));
+        assert_eq!(field(&upload, "utc"), Value::Int(12 * 3600));
+    }
+
+    #[test]
+    fn unknown_times_of_a_fix_are_null() {
+        // A beacon that wasn't synced sends the time since it booted
+        let mut upload = upload();
+        upload.timestamp = 42;
+        for name in ["fix_epoch", "sent_epoch", "relay_epoch", "gateway_epoch"] {
+            assert_eq!(field(&upload, name), Value::Null, "{name}");
+        }
+        assert_eq!(field(&upload, "timestamp"), Value::Int(42));
+        assert_eq!(field(&upload, "received"), Value::Null);
+    }
+}
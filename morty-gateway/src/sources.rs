@@ -0,0 +1,502 @@
+//! Every source that ever talked to this site, for an inventory: when we first and last heard from
+//! it, how many of its messages we got, the firmware of its last hello and the name it gave itself,
+//! see `morty_rs::naming`. The registry is kept in
+//! NVS, so it survives reboots, and `sources` on the console shows it, as text or JSON.
+//!
+//! It holds at most MAX_SOURCES sources. A full registry doesn't make room by itself, so a device
+//! that was heard once is never silently forgotten: new sources are counted but not recorded
+//! until sources are pruned on the console, which asks to confirm first.
+//!
+//! The stored blob starts with a format version, followed by a protobuf encoded
+//! StoredSourcesMsg, like `last_fix`.
+
+use crate::storage::read_blob;
+use crate::storage::write_blob;
+use esp_idf_sys::EspError;
+use json::JsonValue;
+use log::*;
+use morty_rs::messages::StoredSourceMsg;
+use morty_rs::messages::StoredSourcesMsg;
+use morty_rs::naming::resolve;
+use morty_rs::naming::with_name;
+use morty_rs::naming::Resolved;
+use morty_rs::utils::format_age;
+use prost::Message;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Most sources we keep.
+pub const MAX_SOURCES: usize = 64;
+
+const NAMESPACE: &[u8] = b"sources\0";
+const KEY: &[u8] = b"registry\0";
+const FORMAT_VERSION: u8 = 1;
+const SECS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// A source we heard from. Times are in seconds since the epoch, 0 while we didn't know the time.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Source {
+    pub src: String,
+    pub first_seen: i64,
+    pub last_seen: i64,
+    pub messages: u32,
+    /// From its last hello, empty when it never sent one
+    pub firmware_version: String,
+    /// The name it reported last, empty for none
+    pub name: String,
+}
+
+impl Source {
+    /// The source for the console, with how long before `now` we heard from it, in seconds since
+    /// the epoch. None while we don't know the time.
+    pub fn status_line(&self, now: Option<i64>) -> String {
+        let age = |t: i64| match now {
+            _ if t == 0 => "at an unknown time".to_string(),
+            Some(now) => format_age(t, now),
+            None => format!("at {t}"),
+        };
+        let firmware = if self.firmware_version.is_empty() {
+            "unknown"
+        } else {
+            &self.firmware_version
+        };
+        format!(
+            "{}: first seen {}, last seen {}, {} messages, firmware {firmware}",
+            with_name(&self.src, self.resolved_name().name),
+            age(self.first_seen),
+            age(self.last_seen),
+            self.messages
+        )
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let resolved = self.resolved_name();
+        json::object! {
+            "src": self.src.as_str(),
+            "name": resolved.name,
+            "name_conflict": resolved.conflict,
+            "first_seen": self.first_seen,
+            "last_seen": self.last_seen,
+            "messages": self.messages,
+            "firmware_version": self.firmware_version.as_str(),
+        }
+    }
+
+    pub fn resolved_name(&self) -> Resolved<'_> {
+        resolve_name(&self.src, &self.name)
+    }
+}
+
+/// The name of `src`, which reported `reported`, see `morty_rs::naming::resolve`. The gateway's own
+/// names are in SOURCE_NAMES.
+pub fn resolve_name<'a>(src: &str, reported: &'a str) -> Resolved<'a> {
+    let local = crate::SOURCE_NAMES
+        .iter()
+        .find(|(s, _)| *s == src)
+        .map(|&(_, name)| name);
+    resolve(reported, local)
+}
+
+/// The sources we heard from, see the module docs. Changes are written to NVS at most once every
+/// `save_interval`, to limit flash wear.
+pub struct Registry {
+    sources: Vec<Source>,
+    // Messages of sources that didn't fit since boot
+    unrecorded: u32,
+    save_interval: Duration,
+    last_save: Option<Instant>,
+    dirty: bool,
+}
+
+impl Registry {
+    pub fn new(save_interval: Duration) -> Self {
+        Self {
+            sources: Vec::new(),
+            unrecorded: 0,
+            save_interval,
+            last_save: None,
+            dirty: false,
+        }
+    }
+
+    /// Load the sources we stored before the last reboot.
+    pub fn restore(&mut self) -> Result<(), EspError> {
+        if let Some(blob) = read_blob(NAMESPACE, KEY)? {
+            self.restore_blob(&blob);
+        }
+        Ok(())
+    }
+
+    fn restore_blob(&mut self, blob: &[u8]) {
+        match decode(blob) {
+            Some(mut sources) => {
+                sources.truncate(MAX_SOURCES);
+                self.sources = sources;
+            }
+            None => warn!("Ignoring stored sources in an unknown format"),
+        }
+    }
+
+    /// Count a message of `src`, received at `now` in seconds since the epoch, None while we
+    /// don't know the time.
+    pub fn seen(&mut self, src: &str, now: Option<i64>) {
+        if let Some(source) = self.source_mut(src) {
+            source.messages = source.messages.saturating_add(1);
+            if let Some(now) = now {
+                if source.first_seen == 0 {
+                    source.first_seen = now;
+                }
+                source.last_seen = now;
+            }
+        }
+    }
+
+    /// Remember the firmware version and name of `src` from its hello.
+    pub fn hello(&mut self, src: &str, firmware_version: &str, name: &str) {
+        if let Some(source) = self.source_mut(src) {
+            source.firmware_version = firmware_version.to_string();
+            source.name = name.to_string();
+        }
+    }
+
+    /// Remember the name `src` reported with a fix. Fixes without one don't change it.
+    pub fn name(&mut self, src: &str, name: &str) {
+        if name.is_empty() {
+            return;
+        }
+        if let Some(source) = self.source_mut(src) {
+            if source.name != name {
+                info!("Source {src} is called {name:?}");
+                source.name = name.to_string();
+            }
+        }
+    }
+
+    pub fn sources(&self) -> &[Source] {
+        &self.sources
+    }
+
+    /// Forget the sources we last heard from before `before`, in seconds since the epoch. Returns
+    /// the ones that were forgotten. The next `save_if_due` writes that to NVS right away.
+    pub fn prune(&mut self, before: i64) -> Vec<String> {
+        let (pruned, kept) = std::mem::take(&mut self.sources)
+            .into_iter()
+            .partition(|s| s.last_seen < before);
+        self.sources = kept;
+        let pruned: Vec<String> = pruned.into_iter().map(|s: Source| s.src).collect();
+        if !pruned.is_empty() {
+            info!("Pruned {} sources: {}", pruned.len(), pruned.join(", "));
+            self.dirty = true;
+            self.last_save = None;
+        }
+        pruned
+    }
+
+    /// A line for the log, e.g. "12/64 sources, 9 seen in the last day".
+    pub fn summary(&self, now: i64) -> String {
+        let recent = self
+            .sources
+            .iter()
+            .filter(|s| now - s.last_seen < SECS_PER_DAY)
+            .count();
+        let mut summary = format!(
+            "{}/{MAX_SOURCES} sources, {recent} seen in the last day",
+            self.sources.len()
+        );
+        if self.unrecorded > 0 {
+            summary += &format!(
+                ", {} messages of sources that didn't fit, prune on the console",
+                self.unrecorded
+            );
+        }
+        summary
+    }
+
+    /// Write the sources to NVS if they changed and we didn't do that recently.
+    pub fn save_if_due(&mut self) {
+        if !self.dirty
+            || self
+                .last_save
+                .map_or(false, |t| t.elapsed() < self.save_interval)
+        {
+            return;
+        }
+        self.save();
+    }
+
+    /// Write the sources to NVS now, e.g. because we're about to reboot.
+    pub fn save(&mut self) {
+        match write_blob(NAMESPACE, KEY, &encode(&self.sources)) {
+            Ok(()) => self.dirty = false,
+            Err(e) => error!("Unable to store sources: {e}"),
+        }
+        // Also after a failure, so we don't hammer NVS
+        self.last_save = Some(Instant::now());
+    }
+
+    /// The source `src`, added when there's room. None when the registry is full.
+    fn source_mut(&mut self, src: &str) -> Option<&mut Source> {
+        let i = match self.sources.iter().position(|s| s.src == src) {
+            Some(i) => i,
+            None if self.sources.len() >= MAX_SOURCES => {
+                if self.unrecorded == 0 {
+                    warn!("No room to record source {src}, prune sources on the console");
+                }
+                self.unrecorded = self.unrecorded.saturating_add(1);
+                return None;
+            }
+            None => {
+                info!("New source {src}");
+                self.sources.push(Source {
+                    src: src.to_string(),
+                    first_seen: 0,
+                    last_seen: 0,
+                    messages: 0,
+                    firmware_version: String::new(),
+                    name: String::new(),
+                });
+                self.sources.len() - 1
+            }
+        };
+        self.dirty = true;
+        Some(&mut self.sources[i])
+    }
+}
+
+fn encode(sources: &[Source]) -> Vec<u8> {
+    let msg = StoredSourcesMsg {
+        sources: sources
+            .iter()
+            .map(|s| StoredSourceMsg {
+                src: s.src.clone(),
+                first_seen: s.first_seen,
+                last_seen: s.last_seen,
+                messages: s.messages,
+                firmware_version: s.firmware_version.clone(),
+                name: s.name.clone(),
+            })
+            .collect(),
+    };
+    let mut blob = vec![FORMAT_VERSION];
+    blob.extend_from_slice(&msg.encode_to_vec());
+    blob
+}
+
+fn decode(blob: &[u8]) -> Option<Vec<Source>> {
+    let (&version, data) = blob.split_first()?;
+    if version != FORMAT_VERSION {
+        return None;
+    }
+    let msg = StoredSourcesMsg::decode(data).ok()?;
+    Some(
+        msg.sources
+            .into_iter()
+            .map(|s| Source {
+                src: s.src,
+                first_seen: s.first_seen,
+                last_seen: s.last_seen,
+                messages: s.messages,
+                firmware_version: s.firmware_version,
+                name: s.name,
+            })
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_700_000_000;
+
+    fn registry() -> Registry {
+        Registry::new(Duration::from_secs(600))
+    }
+
+    fn mac(i: usize) -> String {
+        format!("aa:bb:cc:dd:{:02x}:{:02x}", i / 256, i % 256)
+    }
+
+    #[test]
+    fn sources_are_recorded_as_they_are_heard() {
+        let mut registry = registry();
+        registry.seen("a", None);
+        registry.seen("a", Some(NOW));
+        registry.seen("a", Some(NOW + 60));
+        registry.hello("a", "1.2.3", "bike");
+        registry.seen("b", Some(NOW + 30));
+        assert_eq!(
+            registry.sources(),
+            [
+                Source {
+                    src: "a".to_string(),
+                    first_seen: NOW,
+                    last_seen: NOW + 60,
+                    messages: 3,
+                    firmware_version: "1.2.3".to_string(),
+                    name: "bike".to_string(),
+                },
+                Source {
+                    src: "b".to_string(),
+                    first_seen: NOW + 30,
+                    last_seen: NOW + 30,
+                    messages: 1,
+                    firmware_version: String::new(),
+                    name: String::new(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn fixes_without_a_name_keep_the_one_we_have() {
+        let mut registry = registry();
+        registry.name("a", "");
+        registry.hello("a", "1.2.3", "bike");
+        registry.name("a", "");
+        assert_eq!(registry.sources()[0].name, "bike");
+        registry.name("a", "trailer");
+        assert_eq!(registry.sources()[0].name, "trailer");
+    }
+
+    #[test]
+    fn a_full_registry_forgets_nobody() {
+        let mut registry = registry();
+        for i in 0..MAX_SOURCES {
+            registry.seen(&mac(i), Some(NOW));
+        }
+        registry.seen("new", Some(NOW));
+        registry.hello("new", "1.2.3", "");
+        registry.seen(&mac(0), Some(NOW + 1));
+        assert_eq!(registry.sources().len(), MAX_SOURCES);
+        assert!(!registry.sources().iter().any(|s| s.src == "new"));
+        assert_eq!(registry.sources()[0].messages, 2);
+        assert_eq!(
+            registry.summary(NOW + 1),
+            "64/64 sources, 64 seen in the last day, 2 messages of sources that didn't fit, \
+             prune on the console"
+        );
+    }
+
+    #[test]
+    fn pruning_makes_room() {
+        let mut registry = registry();
+        for i in 0..MAX_SOURCES {
+            registry.seen(&mac(i), Some(NOW + i as i64));
+        }
+        let pruned = registry.prune(NOW + 2);
+        assert_eq!(pruned, [mac(0), mac(1)]);
+        assert_eq!(registry.sources().len(), MAX_SOURCES - 2);
+        assert_eq!(registry.prune(NOW + 2), Vec::<String>::new());
+        registry.seen("new", Some(NOW + 100));
+        assert_eq!(registry.sources().last().unwrap().src, "new");
+    }
+
+    #[test]
+    fn the_summary_counts_the_sources_of_the_last_day() {
+        let mut registry = registry();
+        assert_eq!(
+            registry.summary(NOW),
+            "0/64 sources, 0 seen in the last day"
+        );
+        registry.seen("a", Some(NOW - SECS_PER_DAY));
+        registry.seen("b", Some(NOW - SECS_PER_DAY + 1));
+        assert_eq!(
+            registry.summary(NOW),
+            "2/64 sources, 1 seen in the last day"
+        );
+    }
+
+    #[test]
+    fn sources_survive_a_reboot() {
+        let mut registry = registry();
+        registry.seen("a", Some(NOW));
+        registry.hello("a", "1.2.3", "bike");
+        registry.seen("b", None);
+        let blob = encode(registry.sources());
+        assert_eq!(blob[0], FORMAT_VERSION);
+
+        let mut restored = self::registry();
+        restored.restore_blob(&blob);
+        assert_eq!(restored.sources(), registry.sources());
+    }
+
+    #[test]
+    fn no_more_than_max_sources_are_restored() {
+        let sources: Vec<Source> = (0..MAX_SOURCES + 3)
+            .map(|i| Source {
+                src: mac(i),
+                first_seen: NOW,
+                last_seen: NOW,
+                messages: 1,
+                firmware_version: String::new(),
+                name: String::new(),
+            })
+            .collect();
+        let mut registry = registry();
+        registry.restore_blob(&encode(&sources));
+        assert_eq!(registry.sources(), &sources[..MAX_SOURCES]);
+    }
+
+    #[test]
+    fn stored_sources_in_another_format_are_ignored() {
+        let mut registry = registry();
+        registry.seen("a", Some(NOW));
+        let mut blob = encode(registry.sources());
+        blob[0] = FORMAT_VERSION + 1;
+        assert_eq!(decode(&blob), None);
+        assert_eq!(decode(&[]), None);
+        assert_eq!(decode(&[FORMAT_VERSION, 0xff]), None);
+
+        let mut restored = self::registry();
+        restored.restore_blob(&blob);
+        assert!(restored.sources().is_empty());
+        assert_eq!(decode(&[FORMAT_VERSION]), Some(Vec::new()));
+    }
+
+    #[test]
+    fn a_source_shows_its_name_and_ages() {
+        let source = Source {
+            src: "aa:bb:cc:dd:ee:ff".to_string(),
+            first_seen: NOW - 3 * 60 * 60,
+            last_seen: NOW - 30,
+            messages: 12,
+            firmware_version: "1.2.3".to_string(),
+            name: "bike".to_string(),
+        };
+        assert_eq!(
+            source.status_line(Some(NOW)),
+            "aa:bb:cc:dd:ee:ff (bike): first seen 3h ago, last seen 30s ago, 12 messages, \
+             firmware 1.2.3"
+        );
+        let unknown = Source {
+            first_seen: 0,
+            firmware_version: String::new(),
+            name: String::new(),
+            ..source
+        };
+        assert_eq!(
+            unknown.status_line(None),
+            format!(
+                "aa:bb:cc:dd:ee:ff: first seen at an unknown time, last seen at {}, 12 messages, \
+                 firmware unknown",
+                NOW - 30
+            )
+        );
+    }
+
+    #[test]
+    fn the_json_of_a_source_has_every_field() {
+        let mut registry = registry();
+        registry.seen("a", Some(NOW));
+        registry.hello("a", "1.2.3", "bike");
+        let json = registry.sources()[0].to_json();
+        assert_eq!(json["src"], "a");
+        assert_eq!(json["name"], "bike");
+        assert!(json["name_conflict"].is_null());
+        assert_eq!(json["first_seen"], NOW);
+        assert_eq!(json["last_seen"], NOW);
+        assert_eq!(json["messages"], 1);
+        assert_eq!(json["firmware_version"], "1.2.3");
+    }
+}
@@ -0,0 +1,130 @@
+//! When a fix is too old to show up on a live map. Beacons that reboot can replay frames that sat
+//! in their queues, and fixes can sit in our retry queue for hours when the API server is
+//! unreachable. This only decides, the caller drops or flags the fix.
+
+use morty_rs::timestamps::known;
+use std::time::Duration;
+
+/// What to do with a fix that is too old.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StaleAction {
+    /// Don't upload it at all.
+    Drop,
+    /// Upload it with `stale: true`, so the backend can leave it off the map.
+    Flag,
+}
+
+/// How old a fix can get before it's stale. Backfilled fixes are known to be older, so they get
+/// their own threshold.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MaxAgePolicy {
+    pub live: Duration,
+    pub backfill: Duration,
+    pub action: StaleAction,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Fresh,
+    /// Too old by the policy, with the age of the fix.
+    Stale(StaleAction, Duration),
+    /// We don't know when the fix was made or what time it is now, so we can't tell. These are
+    /// treated as fresh, we don't throw away what we can't date.
+    Unknown,
+}
+
+impl MaxAgePolicy {
+    /// Judge a fix relayed at `timestamp` by the beacon, which we received at `received`. Both
+    /// are seconds since the epoch, `received` and `now` are None while our clock isn't synced.
+    /// The beacon timestamp is used when its clock was synced, our own otherwise.
+    pub fn check(
+        &self,
+        timestamp: i64,
+        received: Option<i64>,
+        now: Option<i64>,
+        backfill: bool,
+    ) -> Verdict {
+        let made = known(timestamp).or(received);
+        let (Some(made), Some(now)) = (made, now) else {
+            return Verdict::Unknown;
+        };
+
+        // Fixes from the future are fresh, the beacon clock is just a bit ahead
+        let age = Duration::from_secs(now.saturating_sub(made).max(0) as u64);
+        let max_age = if backfill { self.backfill } else { self.live };
+        if age > max_age {
+            Verdict::Stale(self.action, age)
+        } else {
+            Verdict::Fresh
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2023-04-01T12:00:00Z
+    const NOW: i64 = 1_680_350_400;
+    const POLICY: MaxAgePolicy = MaxAgePolicy {
+        live: Duration::from_secs(600),
+        backfill: Duration::from_secs(24 * 3600),
+        action: StaleAction::Flag,
+    };
+
+    #[test]
+    fn fixes_up_to_the_max_age_are_fresh() {
+        assert_eq!(
+            POLICY.check(NOW - 600, None, Some(NOW), false),
+            Verdict::Fresh
+        );
+        assert_eq!(
+            POLICY.check(NOW - 601, None, Some(NOW), false),
+            Verdict::Stale(StaleAction::Flag, Duration::from_secs(601))
+        );
+    }
+
+    #[test]
+    fn backfilled_fixes_have_their_own_max_age() {
+        assert_eq!(
+            POLICY.check(NOW - 3600, None, Some(NOW), true),
+            Verdict::Fresh
+        );
+        let policy = MaxAgePolicy {
+            action: StaleAction::Drop,
+            ..POLICY
+        };
+        assert_eq!(
+            policy.check(NOW - 24 * 3600 - 1, None, Some(NOW), true),
+            Verdict::Stale(StaleAction::Drop, Duration::from_secs(24 * 3600 + 1))
+        );
+    }
+
+    #[test]
+    fn without_a_beacon_time_our_own_is_used() {
+        assert_eq!(
+            POLICY.check(0, Some(NOW - 900), Some(NOW), false),
+            Verdict::Stale(StaleAction::Flag, Duration::from_secs(900))
+        );
+        // The beacon time wins when there is one
+        assert_eq!(
+            POLICY.check(NOW - 60, Some(NOW - 900), Some(NOW), false),
+            Verdict::Fresh
+        );
+    }
+
+    #[test]
+    fn fixes_we_cant_date_are_unknown() {
+        assert_eq!(POLICY.check(0, None, Some(NOW), false), Verdict::Unknown);
+        assert_eq!(POLICY.check(NOW - 900, None, None, false), Verdict::Unknown);
+    }
+
+    #[test]
+    fn fixes_from_the_future_are_fresh() {
+        assert_eq!(
+            POLICY.check(NOW + 3600, None, Some(NOW), false),
+            Verdict::Fresh
+        );
+    }
+}
@@ -0,0 +1,70 @@
+//! Blobs in NVS, for what the gateway keeps across reboots. Namespaces and keys are NUL
+//! terminated byte strings. When NVS is full, writes make room first, see `nvs_recovery`, and
+//! when NVS couldn't be recovered at boot, nothing is read or written.
+
+use esp_idf_sys::esp;
+use esp_idf_sys::EspError;
+use morty_rs::nvs_recovery::persistence_available;
+use morty_rs::nvs_recovery::recover_write;
+
+/// Read the blob under `key` in `namespace`. None when it was never written.
+pub fn read_blob(namespace: &[u8], key: &[u8]) -> Result<Option<Vec<u8>>, EspError> {
+    if !persistence_available() {
+        return Ok(None);
+    }
+    // Before the first save, the namespace doesn't exist
+    let handle = match open(namespace, esp_idf_sys::nvs_open_mode_t_NVS_READONLY) {
+        Ok(handle) => handle,
+        Err(e) if e.code() == esp_idf_sys::ESP_ERR_NVS_NOT_FOUND as i32 => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let key = key.as_ptr() as *const _;
+    let mut len = 0;
+    let result =
+        esp!(unsafe { esp_idf_sys::nvs_get_blob(handle, key, std::ptr::null_mut(), &mut len) })
+            .and_then(|_| {
+                let mut blob = vec![0u8; len];
+                esp!(unsafe {
+                    esp_idf_sys::nvs_get_blob(handle, key, blob.as_mut_ptr() as *mut _, &mut len)
+                })
+                .map(|_| blob)
+            });
+    unsafe { esp_idf_sys::nvs_close(handle) };
+    match result {
+        Ok(blob) => Ok(Some(blob)),
+        Err(e) if e.code() == esp_idf_sys::ESP_ERR_NVS_NOT_FOUND as i32 => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Write `blob` under `key` in `namespace`. Does nothing when we run without persistence.
+pub fn write_blob(namespace: &[u8], key: &[u8], blob: &[u8]) -> Result<(), EspError> {
+    if !persistence_available() {
+        return Ok(());
+    }
+    set_blob(namespace, key, blob).or_else(|e| recover_write(e, || set_blob(namespace, key, blob)))
+}
+
+fn open(
+    namespace: &[u8],
+    mode: esp_idf_sys::nvs_open_mode_t,
+) -> Result<esp_idf_sys::nvs_handle_t, EspError> {
+    let mut handle: esp_idf_sys::nvs_handle_t = 0;
+    esp!(unsafe { esp_idf_sys::nvs_open(namespace.as_ptr() as *const _, mode, &mut handle) })?;
+    Ok(handle)
+}
+
+fn set_blob(namespace: &[u8], key: &[u8], blob: &[u8]) -> Result<(), EspError> {
+    let handle = open(namespace, esp_idf_sys::nvs_open_mode_t_NVS_READWRITE)?;
+    let result = esp!(unsafe {
+        esp_idf_sys::nvs_set_blob(
+            handle,
+            key.as_ptr() as *const _,
+            blob.as_ptr() as *const _,
+            blob.len(),
+        )
+    })
+    .and_then(|_| esp!(unsafe { esp_idf_sys::nvs_commit(handle) }));
+    unsafe { esp_idf_sys::nvs_close(handle) };
+    result
+}
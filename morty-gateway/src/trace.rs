@@ -0,0 +1,244 @@
+//! Traces of the last wakes of trackers, see `morty_rs::trace`. A tracker sends its trace in
+//! pieces when the backend sends it a ResendTrace command. Every beacon in range relays them, so
+//! we put the pieces together per source and upload a trace once, when we have all of it.
+
+use crate::api::ApiClient;
+use crate::config::config;
+use crate::serializer::idempotency_key;
+use crate::PROXY;
+use json::JsonValue;
+use log::*;
+use morty_rs::gps_state::state_name;
+use morty_rs::messages::TraceMsg;
+use morty_rs::trace::pack;
+use morty_rs::trace::unpack;
+use morty_rs::trace::wake_cause_name;
+use morty_rs::trace::WakeRecord;
+use morty_rs::trace::TRACE_LEN;
+use morty_rs::trace::TRACE_RECORDS_PER_FRAME;
+use std::collections::HashMap;
+use std::time::Duration;
+
+// Pieces of a trace that isn't complete after this are dropped
+const TRACE_TIMEOUT: Duration = Duration::from_secs(60);
+// Most pieces a trace can have
+const MAX_PIECES: u32 =
+    ((TRACE_LEN + TRACE_RECORDS_PER_FRAME - 1) / TRACE_RECORDS_PER_FRAME) as u32;
+
+struct Partial {
+    transfer_id: u32,
+    pieces: Vec<Option<Vec<u8>>>,
+    started: Duration,
+}
+
+/// The traces we're receiving, by source.
+pub struct Traces {
+    partial: HashMap<String, Partial>,
+    // The last trace we completed of every source, so the copies other beacons relay are ignored
+    completed: HashMap<String, u32>,
+}
+
+impl Traces {
+    pub fn new() -> Self {
+        Self {
+            partial: HashMap::new(),
+            completed: HashMap::new(),
+        }
+    }
+
+    /// Add a piece of the trace of `src`, received at monotonic `now`. Returns the records, oldest
+    /// first, once we have all pieces.
+    pub fn add(&mut self, src: &str, msg: &TraceMsg, now: Duration) -> Option<Vec<WakeRecord>> {
+        self.partial
+            .retain(|_, partial| now.saturating_sub(partial.started) < TRACE_TIMEOUT);
+        if msg.count == 0 || msg.count > MAX_PIECES || msg.index >= msg.count {
+            warn!("Ignoring trace piece {}/{} of {src}", msg.index, msg.count);
+            return None;
+        }
+        if self.completed.get(src) == Some(&msg.transfer_id) {
+            return None;
+        }
+        // A new trace replaces the one we were receiving
+        let partial = self.partial.entry(src.to_string()).or_insert(Partial {
+            transfer_id: msg.transfer_id,
+            pieces: Vec::new(),
+            started: now,
+        });
+        if partial.transfer_id != msg.transfer_id || partial.pieces.len() != msg.count as usize {
+            *partial = Partial {
+                transfer_id: msg.transfer_id,
+                pieces: vec![None; msg.count as usize],
+                started: now,
+            };
+        }
+        partial.pieces[msg.index as usize] = Some(msg.records.clone());
+        if partial.pieces.iter().any(Option::is_none) {
+            return None;
+        }
+
+        let partial = self.partial.remove(src)?;
+        self.completed.insert(src.to_string(), partial.transfer_id);
+        let records: Vec<u8> = partial.pieces.into_iter().flatten().flatten().collect();
+        Some(unpack(&records))
+    }
+}
+
+impl Default for Traces {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The body of the upload of a trace, with its `idempotency_key`.
+pub fn payload(records: &[WakeRecord], idempotency_key: &str) -> JsonValue {
+    let records: Vec<JsonValue> = records
+        .iter()
+        .map(|r| {
+            json::object! {
+                "woke_at": r.woke_at,
+                "wake_cause": wake_cause_name(r.wake_cause),
+                "ttff_ms": r.ttff_ms,
+                "gps_state": state_name(r.gps_state as i32),
+                "battery_voltage": r.battery_mv as f32 / 1000.0,
+                "sends": r.sends,
+                "send_failures": r.send_failures,
+                "beacons_heard": r.beacons_heard,
+                "sleep_secs": r.sleep_secs,
+            }
+        })
+        .collect();
+    json::object! { "records": records, "idempotency_key": idempotency_key }
+}
+
+/// Upload the trace of `src`.
+pub fn upload(src: &str, records: &[WakeRecord]) -> Result<(), anyhow::Error> {
+    let uri = format!("https://{}/api/v1/source/{src}/trace", config().api_host);
+    let key = idempotency_key(&[src.as_bytes(), &pack(records)]);
+    let data = payload(records, &key).dump();
+    let response = ApiClient::new(PROXY)
+        .with_gzip(config().gzip_threshold)
+        .with_idempotency_key(&key)
+        .post(&uri, "application/json", data.as_bytes())?;
+    info!("Trace response: {}", response.status);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use morty_rs::trace::fragments;
+
+    const SRC: &str = "aa:bb:cc:dd:ee:01";
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    fn records(count: u32) -> Vec<WakeRecord> {
+        (0..count)
+            .map(|i| WakeRecord {
+                woke_at: 1_700_000_000 + i,
+                wake_cause: 4,
+                battery_mv: 3_500,
+                sleep_secs: 300,
+                ..WakeRecord::new()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn a_trace_is_put_together_in_any_order() {
+        let records = records(TRACE_LEN as u32);
+        let msgs = fragments(&records, 7);
+        let mut traces = Traces::new();
+        let last = msgs.len() - 1;
+        for msg in msgs[1..].iter().rev() {
+            assert_eq!(traces.add(SRC, msg, secs(1)), None);
+        }
+        assert_eq!(traces.add(SRC, &msgs[0], secs(2)), Some(records));
+        assert_eq!(traces.add(SRC, &msgs[last], secs(3)), None);
+    }
+
+    #[test]
+    fn an_empty_trace_completes_right_away() {
+        let mut traces = Traces::new();
+        let msgs = fragments(&[], 7);
+        assert_eq!(traces.add(SRC, &msgs[0], secs(1)), Some(Vec::new()));
+    }
+
+    #[test]
+    fn copies_relayed_by_other_beacons_are_ignored() {
+        let records = records(TRACE_LEN as u32);
+        let msgs = fragments(&records, 7);
+        let mut traces = Traces::new();
+        let results: Vec<_> = msgs.iter().map(|m| traces.add(SRC, m, secs(1))).collect();
+        assert_eq!(results.last(), Some(&Some(records.clone())));
+        for msg in &msgs {
+            assert_eq!(traces.add(SRC, msg, secs(2)), None);
+        }
+        // A trace the tracker sends again gets a new transfer
+        let again = fragments(&records, 8);
+        let results: Vec<_> = again.iter().map(|m| traces.add(SRC, m, secs(3))).collect();
+        assert_eq!(results.last(), Some(&Some(records)));
+    }
+
+    #[test]
+    fn a_new_trace_replaces_the_one_being_received() {
+        let old = fragments(&records(TRACE_LEN as u32), 1);
+        let new = fragments(&records(2), 2);
+        let mut traces = Traces::new();
+        assert_eq!(traces.add(SRC, &old[0], secs(1)), None);
+        assert_eq!(traces.add(SRC, &new[0], secs(2)), Some(records(2)));
+        for msg in &old[1..] {
+            assert_eq!(traces.add(SRC, msg, secs(3)), None);
+        }
+    }
+
+    #[test]
+    fn sources_are_put_together_separately() {
+        let msgs = fragments(&records(TRACE_LEN as u32), 1);
+        let mut traces = Traces::new();
+        for msg in &msgs[1..] {
+            assert_eq!(traces.add(SRC, msg, secs(1)), None);
+            assert_eq!(traces.add("other", msg, secs(1)), None);
+        }
+        assert!(traces.add("other", &msgs[0], secs(1)).is_some());
+        assert!(traces.add(SRC, &msgs[0], secs(1)).is_some());
+    }
+
+    #[test]
+    fn pieces_of_a_trace_that_takes_too_long_are_dropped() {
+        let msgs = fragments(&records(TRACE_LEN as u32), 1);
+        let mut traces = Traces::new();
+        for msg in &msgs[1..] {
+            assert_eq!(traces.add(SRC, msg, secs(1)), None);
+        }
+        assert_eq!(traces.add(SRC, &msgs[0], secs(1) + TRACE_TIMEOUT), None);
+    }
+
+    #[test]
+    fn pieces_that_make_no_sense_are_ignored() {
+        let mut traces = Traces::new();
+        for (index, count) in [(0, 0), (1, 1), (0, MAX_PIECES + 1)] {
+            let msg = TraceMsg {
+                transfer_id: 1,
+                index,
+                count,
+                records: pack(&records(1)),
+            };
+            assert_eq!(traces.add(SRC, &msg, secs(1)), None, "{index}/{count}");
+        }
+    }
+
+    #[test]
+    fn the_payload_names_the_fields() {
+        let payload = payload(&records(1), "key");
+        assert_eq!(payload["idempotency_key"], "key");
+        let record = &payload["records"][0];
+        assert_eq!(record["woke_at"], 1_700_000_000);
+        assert_eq!(record["wake_cause"], "timer");
+        assert_eq!(record["battery_voltage"], 3.5);
+        assert_eq!(record["sleep_secs"], 300);
+        assert_eq!(payload["records"].len(), 1);
+    }
+}
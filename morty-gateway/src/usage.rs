@@ -0,0 +1,360 @@
+//! Fixes accepted for upload per source and per UTC day, for billing the users of a shared
+//! gateway. Fixes are counted once they passed deduplication and validation, so duplicates and
+//! garbage aren't billed. The counts are kept in NVS, so a reboot doesn't lose the tally of the
+//! day.
+//!
+//! A fix counts for the day the gateway accepted it, by the wall clock. Until the wall clock is
+//! valid we don't know which day that is, so those fixes are held with their monotonic time and
+//! get their day once it is. These don't survive a reboot.
+
+use crate::mapping::iso8601;
+use crate::storage::read_blob;
+use crate::storage::write_blob;
+use esp_idf_sys::EspError;
+use json::JsonValue;
+use log::*;
+use morty_rs::messages::StoredUsageCountMsg;
+use morty_rs::messages::StoredUsageDayMsg;
+use morty_rs::messages::StoredUsageMsg;
+use prost::Message;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+const NAMESPACE: &[u8] = b"usage\0";
+const KEY: &[u8] = b"days\0";
+const FORMAT_VERSION: u8 = 1;
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// The UTC day of `wall`, in days since the epoch.
+pub fn day_of(wall: Duration) -> u32 {
+    (wall.as_secs() / SECS_PER_DAY) as u32
+}
+
+/// `day` as an ISO 8601 date, e.g. "2023-04-01".
+pub fn date(day: u32) -> String {
+    iso8601(day as i64 * SECS_PER_DAY as i64)[..10].to_string()
+}
+
+/// Fixes per source and per day, for the last `retention` days up to the newest day we've seen.
+pub struct DailyUsage {
+    days: BTreeMap<u32, BTreeMap<String, u32>>,
+    // Fixes from before the wall clock was valid, with their monotonic time. At most
+    // `max_undated`, the ones after that are only counted in `undated_dropped`.
+    undated: Vec<(String, Duration)>,
+    max_undated: usize,
+    undated_dropped: u32,
+    retention: u32,
+    // The newest day we've seen. Days only go forward, so a clock that jumps back doesn't undo a
+    // rollover.
+    today: Option<u32>,
+}
+
+impl DailyUsage {
+    pub fn new(retention: u32, max_undated: usize) -> Self {
+        Self {
+            days: BTreeMap::new(),
+            undated: Vec::new(),
+            max_undated,
+            undated_dropped: 0,
+            retention: retention.max(1),
+            today: None,
+        }
+    }
+
+    /// Count a fix of `src`, accepted at `wall`, or at `monotonic` when the wall clock isn't
+    /// valid.
+    pub fn record(&mut self, src: &str, wall: Option<Duration>, monotonic: Duration) {
+        match wall {
+            Some(wall) => self.add(src, day_of(wall), 1),
+            None if self.undated.len() < self.max_undated => {
+                self.undated.push((src.to_string(), monotonic))
+            }
+            None => self.undated_dropped += 1,
+        }
+    }
+
+    /// Give the fixes from before the wall clock was valid their day. `to_wall` translates their
+    /// monotonic time to wall clock time.
+    pub fn date(&mut self, to_wall: impl Fn(Duration) -> Duration) {
+        for (src, monotonic) in std::mem::take(&mut self.undated) {
+            self.add(&src, day_of(to_wall(monotonic)), 1);
+        }
+    }
+
+    /// Move on to the day of `wall` and prune the days that fall out of the retention. Returns
+    /// the day that ended, when `wall` is on a later day than we've seen before.
+    pub fn advance(&mut self, wall: Duration) -> Option<u32> {
+        let day = day_of(wall);
+        let ended = match self.today {
+            Some(today) if day <= today => return None,
+            today => today,
+        };
+        self.today = Some(day);
+        let oldest = day.saturating_sub(self.retention - 1);
+        self.days.retain(|&d, _| d >= oldest);
+        ended
+    }
+
+    /// The fixes per source on `day`.
+    pub fn day(&self, day: u32) -> BTreeMap<String, u32> {
+        self.days.get(&day).cloned().unwrap_or_default()
+    }
+
+    /// The last `n` days up to today, oldest first. Days without fixes are there as well, so gaps
+    /// show. Empty before the wall clock was valid.
+    pub fn last_days(&self, n: u32) -> Vec<(u32, BTreeMap<String, u32>)> {
+        let Some(today) = self.today else {
+            return Vec::new();
+        };
+        (today.saturating_sub(n.saturating_sub(1))..=today)
+            .map(|day| (day, self.day(day)))
+            .collect()
+    }
+
+    /// Fixes that don't have a day yet, and the ones that didn't fit.
+    pub fn undated(&self) -> (usize, u32) {
+        (self.undated.len(), self.undated_dropped)
+    }
+
+    /// The last `n` days as JSON, e.g.
+    /// `{"days":[{"date":"2023-04-01","total":3,"fixes":{"aa:bb:cc:dd:ee:ff":3}}],"undated":0}`.
+    pub fn to_json(&self, n: u32) -> String {
+        let mut days = JsonValue::new_array();
+        for (day, counts) in self.last_days(n) {
+            let _ = days.push(day_json(day, &counts));
+        }
+        let (undated, dropped) = self.undated();
+        json::object! {
+            "days": days,
+            "undated": undated as u32 + dropped,
+        }
+        .dump()
+    }
+
+    /// Load the counts we stored before the last reboot.
+    pub fn restore(&mut self) -> Result<(), EspError> {
+        let Some(blob) = read_blob(NAMESPACE, KEY)? else {
+            return Ok(());
+        };
+        match decode(&blob) {
+            Some(days) => {
+                for (day, counts) in days {
+                    for (src, fixes) in counts {
+                        self.add(&src, day, fixes);
+                    }
+                }
+            }
+            None => warn!("Ignoring stored usage in an unknown format"),
+        }
+        Ok(())
+    }
+
+    /// Write the counts to NVS.
+    pub fn save(&self) -> Result<(), EspError> {
+        write_blob(NAMESPACE, KEY, &encode(&self.days))
+    }
+
+    fn add(&mut self, src: &str, day: u32, fixes: u32) {
+        // Pruned already, or about to be
+        if matches!(self.today, Some(today) if day + self.retention <= today) {
+            return;
+        }
+        let count = self
+            .days
+            .entry(day)
+            .or_default()
+            .entry(src.to_string())
+            .or_default();
+        *count = count.saturating_add(fixes);
+    }
+}
+
+/// A day of `counts` as JSON, see `DailyUsage::to_json`.
+pub fn day_json(day: u32, counts: &BTreeMap<String, u32>) -> JsonValue {
+    let mut fixes = JsonValue::new_object();
+    for (src, count) in counts {
+        fixes[src.as_str()] = (*count).into();
+    }
+    json::object! {
+        "date": date(day),
+        "total": counts.values().sum::<u32>(),
+        "fixes": fixes,
+    }
+}
+
+fn encode(days: &BTreeMap<u32, BTreeMap<String, u32>>) -> Vec<u8> {
+    let msg = StoredUsageMsg {
+        days: days
+            .iter()
+            .map(|(&day, counts)| StoredUsageDayMsg {
+                day,
+                counts: counts
+                    .iter()
+                    .map(|(src, &fixes)| StoredUsageCountMsg {
+                        src: src.clone(),
+                        fixes,
+                    })
+                    .collect(),
+            })
+            .collect(),
+    };
+    let mut blob = vec![FORMAT_VERSION];
+    blob.extend_from_slice(&msg.encode_to_vec());
+    blob
+}
+
+fn decode(blob: &[u8]) -> Option<Vec<(u32, Vec<(String, u32)>)>> {
+    let (&version, data) = blob.split_first()?;
+    if version != FORMAT_VERSION {
+        return None;
+    }
+    let msg = StoredUsageMsg::decode(data).ok()?;
+    Some(
+        msg.days
+            .into_iter()
+            .map(|d| {
+                (
+                    d.day,
+                    d.counts.into_iter().map(|c| (c.src, c.fixes)).collect(),
+                )
+            })
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2023-04-01
+    const DAY: u32 = 19_448;
+
+    /// `hours` into DAY, by the wall clock.
+    fn wall(hours: u64) -> Option<Duration> {
+        Some(Duration::from_secs(
+            DAY as u64 * SECS_PER_DAY + hours * 3600,
+        ))
+    }
+
+    fn counts(counts: &[(&str, u32)]) -> BTreeMap<String, u32> {
+        counts
+            .iter()
+            .map(|&(src, n)| (src.to_string(), n))
+            .collect()
+    }
+
+    #[test]
+    fn days_are_utc_dates() {
+        assert_eq!(day_of(wall(0).unwrap()), DAY);
+        assert_eq!(day_of(wall(23).unwrap()), DAY);
+        assert_eq!(day_of(wall(24).unwrap()), DAY + 1);
+        assert_eq!(date(DAY), "2023-04-01");
+        assert_eq!(date(0), "1970-01-01");
+    }
+
+    #[test]
+    fn fixes_are_counted_per_source_and_day() {
+        let mut usage = DailyUsage::new(7, 10);
+        usage.record("tracker-1", wall(1), Duration::ZERO);
+        usage.record("tracker-1", wall(2), Duration::ZERO);
+        usage.record("tracker-2", wall(3), Duration::ZERO);
+        usage.record("tracker-1", wall(25), Duration::ZERO);
+        assert_eq!(
+            usage.day(DAY),
+            counts(&[("tracker-1", 2), ("tracker-2", 1)])
+        );
+        assert_eq!(usage.day(DAY + 1), counts(&[("tracker-1", 1)]));
+        assert_eq!(usage.day(DAY + 2), counts(&[]));
+    }
+
+    #[test]
+    fn a_new_day_ends_the_last_one_and_prunes_old_days() {
+        let mut usage = DailyUsage::new(2, 10);
+        assert_eq!(usage.advance(wall(1).unwrap()), None);
+        usage.record("tracker", wall(1), Duration::ZERO);
+        assert_eq!(usage.advance(wall(23).unwrap()), None);
+        assert_eq!(usage.advance(wall(25).unwrap()), Some(DAY));
+        assert_eq!(usage.day(DAY), counts(&[("tracker", 1)]));
+        assert_eq!(usage.advance(wall(49).unwrap()), Some(DAY + 1));
+        assert_eq!(usage.day(DAY), counts(&[]));
+        // Fixes of pruned days aren't counted anymore
+        usage.record("tracker", wall(1), Duration::ZERO);
+        assert_eq!(usage.day(DAY), counts(&[]));
+    }
+
+    #[test]
+    fn a_clock_that_goes_back_doesnt_undo_a_rollover() {
+        let mut usage = DailyUsage::new(7, 10);
+        usage.advance(wall(25).unwrap());
+        assert_eq!(usage.advance(wall(1).unwrap()), None);
+        assert_eq!(usage.last_days(1), [(DAY + 1, counts(&[]))]);
+    }
+
+    #[test]
+    fn fixes_from_before_the_wall_clock_was_valid_get_their_day_later() {
+        let mut usage = DailyUsage::new(7, 2);
+        usage.record("tracker", None, Duration::from_secs(10));
+        usage.record("tracker", None, Duration::from_secs(20));
+        usage.record("tracker", None, Duration::from_secs(30));
+        assert_eq!(usage.undated(), (2, 1));
+        assert_eq!(usage.day(DAY), counts(&[]));
+        // The wall clock is 23:59:50 at a monotonic 10 s
+        let boot = wall(24).unwrap() - Duration::from_secs(20);
+        usage.date(|monotonic| boot + monotonic);
+        assert_eq!(usage.day(DAY), counts(&[("tracker", 1)]));
+        assert_eq!(usage.day(DAY + 1), counts(&[("tracker", 1)]));
+        assert_eq!(usage.undated(), (0, 1));
+    }
+
+    #[test]
+    fn the_last_days_show_gaps() {
+        let mut usage = DailyUsage::new(7, 10);
+        assert_eq!(usage.last_days(3), []);
+        usage.record("tracker", wall(1), Duration::ZERO);
+        usage.advance(wall(49).unwrap());
+        usage.record("tracker", wall(49), Duration::ZERO);
+        assert_eq!(
+            usage.last_days(3),
+            [
+                (DAY, counts(&[("tracker", 1)])),
+                (DAY + 1, counts(&[])),
+                (DAY + 2, counts(&[("tracker", 1)])),
+            ]
+        );
+    }
+
+    #[test]
+    fn usage_as_json() {
+        let mut usage = DailyUsage::new(7, 10);
+        usage.record("aa:bb:cc:dd:ee:ff", wall(1), Duration::ZERO);
+        usage.record("aa:bb:cc:dd:ee:ff", wall(2), Duration::ZERO);
+        usage.record("tracker", None, Duration::ZERO);
+        usage.advance(wall(3).unwrap());
+        assert_eq!(
+            usage.to_json(1),
+            r#"{"days":[{"date":"2023-04-01","total":2,"fixes":{"aa:bb:cc:dd:ee:ff":2}}],"undated":1}"#
+        );
+    }
+
+    #[test]
+    fn stored_counts_round_trip() {
+        let mut days = BTreeMap::new();
+        days.insert(DAY, counts(&[("tracker-1", 2), ("tracker-2", 1)]));
+        days.insert(DAY + 1, counts(&[("tracker-1", 5)]));
+        let decoded = decode(&encode(&days)).unwrap();
+        assert_eq!(
+            decoded,
+            [
+                (
+                    DAY,
+                    vec![("tracker-1".to_string(), 2), ("tracker-2".to_string(), 1)]
+                ),
+                (DAY + 1, vec![("tracker-1".to_string(), 5)]),
+            ]
+        );
+        let mut blob = encode(&days);
+        blob[0] = FORMAT_VERSION + 1;
+        assert_eq!(decode(&blob), None);
+        assert_eq!(decode(&[]), None);
+    }
+}
@@ -0,0 +1,182 @@
+//! Settings of the tracker that can differ between trackers, see `morty_rs::config`. They are
+//! loaded from NVS every time we wake up, anything that isn't stored there gets the default below.
+//! The ones that change what we report go into the configuration version.
+
+use morty_rs::config::check_schema;
+use morty_rs::config::load_nvs;
+use morty_rs::config::Kind;
+use morty_rs::config::Setting;
+use morty_rs::config::Settings;
+use morty_rs::config::Value;
+use morty_rs::naming::valid_name;
+use morty_rs::naming::MAX_NAME_LEN;
+use morty_rs::naming::NAME_KEY;
+use std::borrow::Cow;
+use std::sync::Mutex;
+use std::time::Duration;
+
+const GPS_BAUDRATE: &str = "gps_baudrate";
+const MAX_AWAKE_TIME: &str = "max_awake";
+const GPS_POWER_OFF_EXTRA_AWAKE_TIME: &str = "gps_off_extra";
+const GPS_STANDBY: &str = "gps_standby";
+const REPORT_ON_CHARGING_CHANGE: &str = "report_charging";
+const CONFIG_REPORT_INTERVAL: &str = "config_report";
+const BATTERY_CALIBRATION: &str = "battery_cal";
+
+const fn secs(secs: u64) -> Duration {
+    Duration::from_secs(secs)
+}
+
+pub static SCHEMA: &[Setting] = &[
+    Setting::new(
+        GPS_BAUDRATE,
+        Kind::U32 {
+            min: 4800,
+            max: 115_200,
+        },
+        Value::U32(9600),
+    ),
+    // Hard limit on how long the tracker can be awake when running on battery, and how much
+    // longer when the GPS module was powered off while we slept
+    Setting::new(
+        MAX_AWAKE_TIME,
+        Kind::Duration {
+            min: secs(30),
+            max: secs(60 * 60),
+        },
+        Value::Duration(secs(180)),
+    ),
+    Setting::new(
+        GPS_POWER_OFF_EXTRA_AWAKE_TIME,
+        Kind::Duration {
+            min: secs(0),
+            max: secs(10 * 60),
+        },
+        Value::Duration(secs(120)),
+    ),
+    // Put the GPS module in standby with a PMTK command while we sleep
+    Setting::new(GPS_STANDBY, Kind::Bool, Value::Bool(false)),
+    // Send a report right away when the tracker is plugged in or unplugged
+    Setting::new(REPORT_ON_CHARGING_CHANGE, Kind::Bool, Value::Bool(true)),
+    // How often the version of our configuration goes out with a report, and right away when it
+    // changed
+    Setting::new(
+        CONFIG_REPORT_INTERVAL,
+        Kind::Duration {
+            min: secs(5 * 60),
+            max: secs(24 * 60 * 60),
+        },
+        Value::Duration(secs(60 * 60)),
+    ),
+    // Added to the battery voltage before estimating the charge, in volts, for the divider and
+    // ADC of this tracker, see `morty_rs::battery`
+    Setting::new(
+        BATTERY_CALIBRATION,
+        Kind::F32 {
+            min: -0.5,
+            max: 0.5,
+        },
+        Value::F32(0.0),
+    ),
+    // What the tracker calls itself, see `morty_rs::naming`, empty for no name
+    Setting::new(
+        NAME_KEY,
+        Kind::Str {
+            max_len: MAX_NAME_LEN,
+        },
+        Value::Str(Cow::Borrowed("")),
+    ),
+];
+
+/// What the tracker runs with.
+pub struct Config {
+    pub gps_baudrate: u32,
+    pub max_awake_time: Duration,
+    pub gps_power_off_extra_awake_time: Duration,
+    pub gps_standby: bool,
+    pub report_on_charging_change: bool,
+    pub config_report_interval: Duration,
+    pub battery_calibration: f32,
+    /// Empty when the tracker has no name
+    pub name: String,
+    /// Every setting with where it came from, and the stored values we couldn't use
+    pub settings: Settings,
+}
+
+impl Config {
+    fn new(settings: Settings) -> Self {
+        Self {
+            gps_baudrate: settings.u32(GPS_BAUDRATE),
+            max_awake_time: settings.duration(MAX_AWAKE_TIME),
+            gps_power_off_extra_awake_time: settings.duration(GPS_POWER_OFF_EXTRA_AWAKE_TIME),
+            gps_standby: settings.bool(GPS_STANDBY),
+            report_on_charging_change: settings.bool(REPORT_ON_CHARGING_CHANGE),
+            config_report_interval: settings.duration(CONFIG_REPORT_INTERVAL),
+            battery_calibration: settings.f32(BATTERY_CALIBRATION),
+            name: valid_name(settings.str(NAME_KEY)),
+            settings,
+        }
+    }
+}
+
+// The configuration, once it's loaded
+static CONFIG: Mutex<Option<&'static Config>> = Mutex::new(None);
+
+/// Load the configuration from NVS, which has to be initialized. Invalid values are logged and
+/// replaced by their default.
+pub fn load() -> Result<&'static Config, anyhow::Error> {
+    check_schema(SCHEMA)?;
+    let settings = load_nvs(SCHEMA);
+    settings.log_invalid();
+    let config: &'static Config = Box::leak(Box::new(Config::new(settings)));
+    *CONFIG.lock().unwrap() = Some(config);
+    Ok(config)
+}
+
+/// The configuration. Panics when it wasn't loaded yet.
+pub fn config() -> &'static Config {
+    CONFIG.lock().unwrap().expect("Configuration not loaded")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_schema_checks_out() {
+        check_schema(SCHEMA).unwrap();
+    }
+
+    #[test]
+    fn the_defaults_make_a_config() {
+        let config = Config::new(Settings::load(SCHEMA, |_| Ok(None)));
+        assert!(config.settings.invalid().is_empty());
+        assert_eq!(config.name, "");
+    }
+
+    fn config_with_name(name: &str) -> Config {
+        Config::new(Settings::load(SCHEMA, |key| match key {
+            NAME_KEY => Ok(Some(name.to_string())),
+            _ => Ok(None),
+        }))
+    }
+
+    #[test]
+    fn a_stored_name_is_used() {
+        let config = config_with_name("trailer-1");
+        assert!(config.settings.invalid().is_empty());
+        assert_eq!(config.name, "trailer-1");
+    }
+
+    #[test]
+    fn invalid_names_are_ignored() {
+        // Too long for the setting
+        let config = config_with_name("0123456789abcdefg");
+        assert_eq!(config.settings.invalid()[0].key, NAME_KEY);
+        assert_eq!(config.name, "");
+        // Short enough, but not a name
+        let config = config_with_name("trailer 1");
+        assert!(config.settings.invalid().is_empty());
+        assert_eq!(config.name, "");
+    }
+}
@@ -0,0 +1,296 @@
+use crate::gps;
+use crate::set_stay_awake;
+use crate::PENDING_SENDS;
+use esp_idf_svc::espnow::EspNow;
+use esp_idf_svc::systime::EspSystemTime;
+use log::*;
+use morty_rs::comm::broadcast_msg;
+use morty_rs::comm::decode_msg;
+use morty_rs::comm::DecodeFailures;
+use morty_rs::comm::Priority;
+use morty_rs::comm::RecvFrame;
+use morty_rs::comm::BEACON_PRESENT_TYPE;
+use morty_rs::comm::CHUNK_TYPE;
+use morty_rs::comm::COMMAND_TYPE;
+use morty_rs::comm::TIME_BEACON_TYPE;
+use morty_rs::command;
+use morty_rs::command::CommandHandler;
+use morty_rs::duty::ListenSchedule;
+use morty_rs::led::LedHandle;
+use morty_rs::messages::morty_message;
+use morty_rs::messages::BeaconPresentMsg;
+use morty_rs::messages::ChunkMsg;
+use morty_rs::messages::ClockStatusMsg;
+use morty_rs::messages::TimeBeaconMsg;
+use morty_rs::messages::TimeSource;
+use morty_rs::presence::PresenceTable;
+use morty_rs::presence::Sighting;
+use morty_rs::timesync;
+use morty_rs::timesync::Correction;
+use morty_rs::timesync::Discipline;
+use morty_rs::timesync::DEFAULT_POLICY;
+use morty_rs::trace;
+use morty_rs::transfer::Reassembly;
+use morty_rs::utils::uptime;
+use std::cell::Cell;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
+use std::time::Instant;
+
+// Report the progress of a transfer every this many chunks
+const TRANSFER_PROGRESS_INTERVAL: usize = 16;
+// Time between the pieces of a trace, so sending one doesn't take the channel from the others
+const TRACE_PIECE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The message types the downlink handles.
+pub const DOWNLINK_TYPES: &[u8] = &[
+    BEACON_PRESENT_TYPE,
+    COMMAND_TYPE,
+    CHUNK_TYPE,
+    TIME_BEACON_TYPE,
+];
+
+// Frames from beacons and other trackers that didn't decode, since we woke up
+static DECODE_FAILURES: DecodeFailures = DecodeFailures::new();
+
+// Number of beacons we remember, and for how long
+pub const BEACON_TABLE_SIZE: usize = 4;
+const BEACON_MAX_AGE: Duration = Duration::from_secs(30 * 60);
+
+// Beacons we heard, in RTC memory so we still know them after a deep sleep. Only the UART thread
+// touches this, through `Downlink`.
+#[link_section = ".rtc.data"]
+static mut BEACONS: PresenceTable<BEACON_TABLE_SIZE> = PresenceTable::new(BEACON_MAX_AGE);
+
+// Our clock, disciplined with time beacons, see `morty_rs::timesync`. The wall clock is the sleep
+// clock plus the offset, once the discipline has a source. Like the beacons, it's in RTC memory
+// and only touched through `Downlink`.
+#[link_section = ".rtc.data"]
+static mut CLOCK: Discipline = Discipline::new(DEFAULT_POLICY, TimeSource::None);
+#[link_section = ".rtc.data"]
+static mut CLOCK_OFFSET_MS: i64 = 0;
+
+/// Handles what the beacons send us: commands and GPS assistance data. Frames are handed over by
+/// the ESP-NOW callback and handled on the UART thread, in between GPS sentences. We only hear
+/// them while we're awake, which on battery isn't long.
+pub struct Downlink {
+    // Frames from the ESP-NOW callback, see `DOWNLINK_TYPES`
+    recv_rx: Receiver<RecvFrame>,
+    mac: String,
+    commands: CommandHandler,
+    assist: Option<Reassembly>,
+    // Wall clock time of the last beacon present message, and when we received it. We don't
+    // have a synced clock, but the beacons do.
+    beacon_time: Option<(i64, Instant)>,
+    clock: Discipline,
+    clock_offset_ms: i64,
+    beacons: PresenceTable<BEACON_TABLE_SIZE>,
+    // Beacon present messages since we woke up
+    beacons_heard: u32,
+}
+
+impl Downlink {
+    pub fn new(recv_rx: Receiver<RecvFrame>, mac: String, led: LedHandle) -> Self {
+        let mut beacons = unsafe { BEACONS };
+        beacons.forget_old(sleep_clock());
+        Self {
+            recv_rx,
+            commands: CommandHandler::new(mac.clone(), led),
+            mac,
+            assist: None,
+            beacon_time: None,
+            clock: unsafe { CLOCK },
+            clock_offset_ms: unsafe { CLOCK_OFFSET_MS },
+            beacons,
+            beacons_heard: 0,
+        }
+    }
+
+    /// The beacons we heard recently, also before the last deep sleep.
+    pub fn beacons(&self) -> &PresenceTable<BEACON_TABLE_SIZE> {
+        &self.beacons
+    }
+
+    /// What to report about our clock.
+    pub fn clock_status(&self) -> ClockStatusMsg {
+        self.clock.status()
+    }
+
+    /// Number of beacon present messages since we woke up.
+    pub fn beacons_heard(&self) -> u32 {
+        self.beacons_heard
+    }
+
+    /// Handle everything that came in since the last time.
+    pub fn handle(&mut self, esp_now: &EspNow) -> Result<(), anyhow::Error> {
+        while let Ok(RecvFrame {
+            src,
+            data,
+            received_at,
+            ..
+        }) = self.recv_rx.try_recv()
+        {
+            match decode_msg(&data) {
+                Ok(Some(morty_message::Msg::BeaconPresent(beacon))) => {
+                    self.beacon_time = Some((beacon.timestamp, Instant::now()));
+                    self.record_beacon(&src, &beacon);
+                }
+                Ok(Some(morty_message::Msg::TimeBeacon(beacon))) => {
+                    self.take_time(&beacon, received_at);
+                }
+                Ok(Some(morty_message::Msg::Command(cmd))) if self.commands.is_for_us(&cmd) => {
+                    let resend_trace = Cell::new(false);
+                    let handled = self.commands.handle(
+                        &cmd,
+                        self.now(),
+                        None,
+                        Some(&set_stay_awake),
+                        Some(&|| resend_trace.set(true)),
+                    );
+                    if let Some(handled) = handled {
+                        send(morty_message::Msg::CommandAck(handled.ack), esp_now)?;
+                        if handled.reboot {
+                            command::reboot();
+                        }
+                    }
+                    if resend_trace.get() {
+                        send_trace(esp_now)?;
+                    }
+                }
+                Ok(Some(morty_message::Msg::Chunk(chunk)))
+                    if chunk.target.is_empty() || chunk.target.eq_ignore_ascii_case(&self.mac) =>
+                {
+                    self.handle_chunk(&chunk, esp_now)?;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    DECODE_FAILURES.record(&e);
+                    warn!("Error decoding message: {e} ({DECODE_FAILURES})");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn record_beacon(&mut self, src: &[u8], beacon: &BeaconPresentMsg) {
+        let Ok(mac) = src.try_into() else {
+            return;
+        };
+        self.beacons_heard += 1;
+        self.beacons.record(Sighting {
+            mac,
+            channel: beacon.channel as u8,
+            // The receive callback doesn't tell us
+            rssi: None,
+            seen_at: sleep_clock(),
+            schedule: ListenSchedule::from_msg(beacon),
+        });
+        unsafe { BEACONS = self.beacons };
+    }
+
+    /// Discipline our clock with a time beacon we received at `received_at`, since boot.
+    fn take_time(&mut self, beacon: &TimeBeaconMsg, received_at: Duration) {
+        let held = uptime().saturating_sub(received_at);
+        let local_ms = sleep_clock().as_millis() as i64 + self.clock_offset_ms;
+        let offset_ms = timesync::offset_ms(beacon, held, local_ms);
+        let valid = self.clock.source() != TimeSource::None;
+        match self
+            .clock
+            .sample(offset_ms, beacon.hops, valid, sleep_clock())
+        {
+            Correction::Set(ms) | Correction::Slew(ms) | Correction::Step(ms) => {
+                self.clock_offset_ms += ms;
+            }
+            Correction::Ignore => {}
+        }
+        unsafe {
+            CLOCK = self.clock;
+            CLOCK_OFFSET_MS = self.clock_offset_ms;
+        }
+    }
+
+    fn handle_chunk(&mut self, chunk: &ChunkMsg, esp_now: &EspNow) -> Result<(), anyhow::Error> {
+        let now = self.now();
+        // A new transfer replaces the one we were working on
+        if self.assist.as_ref().map(|a| a.transfer_id()) != Some(chunk.transfer_id) {
+            self.assist = Some(Reassembly::new(chunk));
+        }
+        let assist = self.assist.as_mut().unwrap();
+        if assist.is_complete() {
+            return Ok(());
+        }
+        let before = assist.received();
+        if !assist.add(chunk) || assist.received() == before {
+            return Ok(());
+        }
+
+        if let Some(data) = assist.data() {
+            let valid_until = assist.valid_until();
+            let stale =
+                matches!(now, Some(now) if valid_until != 0 && now.as_secs() as i64 >= valid_until);
+            let ok = if stale {
+                warn!("GPS assistance data expired, not using it");
+                false
+            } else {
+                info!("Loading {} bytes of GPS assistance data", data.len());
+                match gps::inject_epo(&data) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        error!("Unable to load GPS assistance data: {e}");
+                        false
+                    }
+                }
+            };
+            let ack = assist.ack(true, ok);
+            send(morty_message::Msg::TransferAck(ack), esp_now)?;
+        } else if assist.received() % TRANSFER_PROGRESS_INTERVAL == 0 {
+            let ack = assist.ack(false, true);
+            send(morty_message::Msg::TransferAck(ack), esp_now)?;
+        }
+        Ok(())
+    }
+
+    /// Wall clock time, from our clock once time beacons set it, and otherwise based on the last
+    /// beacon present message.
+    pub fn now(&self) -> Option<std::time::Duration> {
+        if self.clock.source() != TimeSource::None {
+            let now_ms = sleep_clock().as_millis() as i64 + self.clock_offset_ms;
+            return Some(Duration::from_millis(now_ms.max(0) as u64));
+        }
+        self.beacon_time.map(|(timestamp, at)| {
+            std::time::Duration::from_secs(timestamp.max(0) as u64) + at.elapsed()
+        })
+    }
+}
+
+/// Time on a clock that keeps running during deep sleep. It's not the wall clock, we don't have
+/// a synced one.
+pub fn sleep_clock() -> Duration {
+    EspSystemTime.now()
+}
+
+/// Send the trace of our last wakes, see `morty_rs::trace`. The pieces go out
+/// `TRACE_PIECE_INTERVAL` apart and we don't go to sleep before they're all out.
+fn send_trace(esp_now: &EspNow) -> Result<(), anyhow::Error> {
+    let records = trace::load();
+    info!("Sending the trace of our last {} wakes", records.len());
+    let transfer_id = unsafe { esp_idf_sys::esp_random() };
+    let pieces = trace::fragments(&records, transfer_id);
+    let priority = Priority::High;
+    let sends = pieces.len() as u32 * priority.broadcast_count() as u32;
+    PENDING_SENDS.fetch_add(sends, Ordering::SeqCst);
+    for (i, piece) in pieces.into_iter().enumerate() {
+        if i > 0 {
+            std::thread::sleep(TRACE_PIECE_INTERVAL);
+        }
+        broadcast_msg(&morty_message::Msg::Trace(piece), priority, esp_now)?;
+    }
+    Ok(())
+}
+
+fn send(msg: morty_message::Msg, esp_now: &EspNow) -> Result<(), anyhow::Error> {
+    let priority = Priority::High;
+    PENDING_SENDS.store(priority.broadcast_count() as u32, Ordering::SeqCst);
+    Ok(broadcast_msg(&msg, priority, esp_now)?)
+}
@@ -0,0 +1,78 @@
+/// Counts the reports we sent in a row without a fix. A tracker with a broken GPS antenna keeps
+/// waking up and reporting that it has no fix, which looks just like a tracker that's indoors.
+/// After `threshold` of these reports we suspect the GPS is broken, until the next fix.
+///
+/// The count is kept by the caller, in memory that survives deep sleep, and passed in with
+/// `resume`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FixWatch {
+    threshold: u32,
+    without_fix: u32,
+}
+
+impl FixWatch {
+    /// Continue counting from `without_fix` reports without a fix.
+    pub fn resume(threshold: u32, without_fix: u32) -> Self {
+        Self {
+            threshold,
+            without_fix,
+        }
+    }
+
+    /// Number of reports in a row without a fix.
+    pub fn without_fix(&self) -> u32 {
+        self.without_fix
+    }
+
+    /// Record a report, with or without a fix. Returns whether we suspect the GPS is broken.
+    pub fn record(&mut self, fix: bool) -> bool {
+        self.without_fix = if fix {
+            0
+        } else {
+            self.without_fix.saturating_add(1)
+        };
+        self.fault_suspected()
+    }
+
+    pub fn fault_suspected(&self) -> bool {
+        self.without_fix >= self.threshold
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_gps_is_suspected_after_threshold_reports_without_a_fix() {
+        let mut watch = FixWatch::resume(3, 0);
+        assert!(!watch.record(false));
+        assert!(!watch.record(false));
+        assert!(watch.record(false));
+        assert!(watch.record(false));
+        assert_eq!(watch.without_fix(), 4);
+    }
+
+    #[test]
+    fn a_fix_clears_the_suspicion() {
+        let mut watch = FixWatch::resume(3, 10);
+        assert!(watch.fault_suspected());
+        assert!(!watch.record(true));
+        assert_eq!(watch.without_fix(), 0);
+        assert!(!watch.record(false));
+    }
+
+    #[test]
+    fn counting_resumes_after_deep_sleep() {
+        let mut watch = FixWatch::resume(3, 2);
+        assert!(!watch.fault_suspected());
+        assert!(watch.record(false));
+    }
+
+    #[test]
+    fn the_count_saturates() {
+        let mut watch = FixWatch::resume(3, u32::MAX);
+        assert!(watch.record(false));
+        assert_eq!(watch.without_fix(), u32::MAX);
+    }
+}
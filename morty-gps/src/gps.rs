@@ -0,0 +1,154 @@
+use esp_idf_sys::esp;
+use esp_idf_sys::EspError;
+use log::*;
+use morty_rs::epo;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicI32;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+// PMTK command that puts the GPS module in standby. Any byte sent to the module wakes it up.
+const PMTK_STANDBY: &[u8] = b"$PMTK161,0*28\r\n";
+const PMTK_WAKE: &[u8] = b"\r\n";
+
+// Cutting power means the module has to start from scratch, which can take a lot longer to get a
+// fix. That's only worth it when we're going to sleep for a long time.
+const POWER_OFF_MIN_SLEEP: Duration = Duration::from_secs(10 * 60);
+// Time the module needs to switch to the binary protocol, and between EPO packets
+const EPO_MODE_SWITCH_DELAY: Duration = Duration::from_millis(100);
+const EPO_PACKET_INTERVAL: Duration = Duration::from_millis(20);
+
+// The GPS module is controlled from the send callback and timers as well, so we keep the port
+// and pin around in statics. -1 means not configured.
+static UART_PORT: AtomicI32 = AtomicI32::new(-1);
+static POWER_GPIO: AtomicI32 = AtomicI32::new(-1);
+static STANDBY: AtomicBool = AtomicBool::new(false);
+
+/// What to do with the GPS module while we're in deep sleep.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SleepMode {
+    /// Leave the module running
+    On,
+    /// Put the module in standby with a PMTK command
+    Standby,
+    /// Cut the power to the module, after putting it in standby if that's configured
+    PowerOff,
+}
+
+/// Decide what to do with the GPS module when going to sleep for `sleep`.
+pub fn sleep_mode(sleep: Duration, standby: bool, power_pin: bool) -> SleepMode {
+    if power_pin && sleep >= POWER_OFF_MIN_SLEEP {
+        SleepMode::PowerOff
+    } else if standby {
+        SleepMode::Standby
+    } else {
+        SleepMode::On
+    }
+}
+
+/// Power up and wake up the GPS module. `standby` enables the use of PMTK standby when going to
+/// sleep and `power_gpio` is the pin that switches the power of the module, if there is one.
+pub fn init(uart_port: i32, standby: bool, power_gpio: Option<i32>) -> Result<(), EspError> {
+    UART_PORT.store(uart_port, Ordering::SeqCst);
+    STANDBY.store(standby, Ordering::SeqCst);
+
+    if let Some(gpio) = power_gpio {
+        info!("Powering GPS module through GPIO {gpio}");
+        POWER_GPIO.store(gpio, Ordering::SeqCst);
+        // The pin was held low during deep sleep
+        esp!(unsafe { esp_idf_sys::gpio_hold_dis(gpio) })?;
+        esp!(unsafe {
+            esp_idf_sys::gpio_set_direction(gpio, esp_idf_sys::gpio_mode_t_GPIO_MODE_OUTPUT)
+        })?;
+        esp!(unsafe { esp_idf_sys::gpio_set_level(gpio, 1) })?;
+    }
+
+    if standby {
+        uart_write(uart_port, PMTK_WAKE);
+    }
+    Ok(())
+}
+
+/// Put the GPS module in the state it should be in while we sleep for `sleep`.
+pub fn prepare_for_sleep(sleep: Duration) {
+    let uart_port = UART_PORT.load(Ordering::SeqCst);
+    let power_gpio = POWER_GPIO.load(Ordering::SeqCst);
+    let standby = STANDBY.load(Ordering::SeqCst) && uart_port >= 0;
+
+    let mode = sleep_mode(sleep, standby, power_gpio >= 0);
+    info!("GPS sleep mode: {:?}", mode);
+
+    if mode != SleepMode::On && standby {
+        uart_write(uart_port, PMTK_STANDBY);
+    }
+
+    if mode == SleepMode::PowerOff {
+        // Keep the pin low while we're in deep sleep
+        unsafe {
+            esp_idf_sys::gpio_set_level(power_gpio, 0);
+            esp_idf_sys::gpio_hold_en(power_gpio);
+            esp_idf_sys::gpio_deep_sleep_hold_en();
+        }
+    }
+}
+
+/// Load EPO assistance data into the GPS module, so it gets a fix quicker. The module is switched
+/// to the binary protocol for this and back to NMEA when we're done.
+pub fn inject_epo(data: &[u8]) -> Result<(), EspError> {
+    let uart_port = UART_PORT.load(Ordering::SeqCst);
+    if uart_port < 0 {
+        esp!(esp_idf_sys::ESP_ERR_INVALID_STATE)?;
+    }
+
+    uart_write(uart_port, epo::PMTK_BINARY_MODE);
+    std::thread::sleep(EPO_MODE_SWITCH_DELAY);
+    for packet in epo::epo_packets(data) {
+        uart_write(uart_port, &packet);
+        std::thread::sleep(EPO_PACKET_INTERVAL);
+    }
+    uart_write(uart_port, &epo::nmea_mode_packet());
+    Ok(())
+}
+
+fn uart_write(uart_port: i32, data: &[u8]) {
+    unsafe {
+        esp_idf_sys::uart_write_bytes(uart_port, data.as_ptr() as *const _, data.len());
+        // Give it 100ms to go out
+        esp_idf_sys::uart_wait_tx_done(uart_port, esp_idf_sys::configTICK_RATE_HZ / 10);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHORT: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn short_sleeps_keep_the_power_on() {
+        assert_eq!(sleep_mode(SHORT, false, false), SleepMode::On);
+        assert_eq!(sleep_mode(SHORT, true, false), SleepMode::Standby);
+        assert_eq!(sleep_mode(SHORT, false, true), SleepMode::On);
+        assert_eq!(sleep_mode(SHORT, true, true), SleepMode::Standby);
+        let just_short = POWER_OFF_MIN_SLEEP - Duration::from_millis(1);
+        assert_eq!(sleep_mode(just_short, true, true), SleepMode::Standby);
+    }
+
+    #[test]
+    fn long_sleeps_cut_the_power_when_they_can() {
+        for sleep in [POWER_OFF_MIN_SLEEP, Duration::from_secs(24 * 60 * 60)] {
+            assert_eq!(sleep_mode(sleep, false, true), SleepMode::PowerOff);
+            assert_eq!(sleep_mode(sleep, true, true), SleepMode::PowerOff);
+            assert_eq!(sleep_mode(sleep, true, false), SleepMode::Standby);
+            assert_eq!(sleep_mode(sleep, false, false), SleepMode::On);
+        }
+    }
+
+    #[test]
+    fn the_standby_command_has_its_checksum() {
+        let sentence = std::str::from_utf8(PMTK_STANDBY).unwrap();
+        let (body, checksum) = sentence[1..].trim_end().split_once('*').unwrap();
+        let xor = body.bytes().fold(0, |acc, b| acc ^ b);
+        assert_eq!(format!("{xor:02X}"), checksum);
+    }
+}
@@ -1,8 +1,19 @@
+mod config;
+mod downlink;
+mod fault;
+mod gps;
+mod switch;
+
+use config::config;
+use downlink::sleep_clock;
+use downlink::Downlink;
+use downlink::BEACON_TABLE_SIZE;
+use downlink::DOWNLINK_TYPES;
 use esp_idf_hal::adc;
 use esp_idf_hal::adc::ADC1;
-use esp_idf_hal::delay::BLOCK;
 use esp_idf_hal::gpio;
 use esp_idf_hal::gpio::ADCPin;
+use esp_idf_hal::gpio::Pin;
 use esp_idf_hal::peripheral::Peripheral;
 use esp_idf_hal::prelude::*;
 use esp_idf_hal::uart;
@@ -11,48 +22,291 @@ use esp_idf_svc::espnow::EspNow;
 use esp_idf_svc::espnow::SendStatus;
 use esp_idf_svc::eventloop::EspSystemEventLoop;
 use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_svc::timer::EspTimerService;
 use esp_idf_svc::wifi::*;
 use esp_idf_sys as _;
 use esp_idf_sys::esp;
 use esp_idf_sys::esp_deep_sleep_start;
 use esp_idf_sys::esp_sleep_enable_timer_wakeup;
+use fault::FixWatch;
 use lazy_static::lazy_static;
 use log::*;
-use morty_rs::comm::{broadcast_msg, esp_now_init};
+use morty_rs::battery::estimate_soc;
+use morty_rs::budget::check_frame_budget;
+use morty_rs::comm::RecvDispatcher;
+use morty_rs::comm::{broadcast_msg, esp_now_init, mac_to_string, own_mac, ESP_NOW_CHANNEL};
+use morty_rs::comm::{set_frame_format, set_tx_power_dbm, tx_power_dbm, FrameFormat, Priority};
+use morty_rs::config::ConfigVersion;
+use morty_rs::config::CONFIG_NAMESPACE;
+use morty_rs::flashlog;
+use morty_rs::flashlog::EventKind;
+use morty_rs::geo::{format_dm, geohash};
+use morty_rs::gps_state::gps_state;
+use morty_rs::gps_state::gsa_fix_mode;
+use morty_rs::gps_state::state_name;
+use morty_rs::gps_state::SentenceCounts;
+use morty_rs::hello;
 use morty_rs::led::colors;
 use morty_rs::led::Led;
+use morty_rs::ledlog;
+use morty_rs::ledlog::LEDLOG_NAMESPACE;
 use morty_rs::messages::*;
-use morty_rs::utils::set_thread_spawn_configuration;
-use morty_rs::utils::LastUpdate;
+use morty_rs::naming::name_due;
+use morty_rs::nvs_recovery;
+use morty_rs::nvs_recovery::Criticality;
+use morty_rs::nvs_recovery::Namespace;
+use morty_rs::phase;
+use morty_rs::power::read_battery_voltage;
+use morty_rs::power::read_level_debounced;
+use morty_rs::presence::PresenceTable;
+use morty_rs::profile::check_profiles;
+use morty_rs::profile::Profile;
+use morty_rs::sequence::Sequence;
+use morty_rs::status;
+use morty_rs::status::OrFatal;
+use morty_rs::status::Status;
+use morty_rs::timestamps::fix_epoch;
+use morty_rs::timestamps::known;
+use morty_rs::trace;
+use morty_rs::trace::WakeRecord;
+use morty_rs::trace::TRACE_NAMESPACE;
+use morty_rs::utils::boot_complete;
+use morty_rs::utils::spawn_thread;
+use morty_rs::utils::uptime;
+use morty_rs::utils::PeriodicSet;
+use morty_rs::utils::ThreadConfig;
 use morty_rs::GPS_UPDATE_INTERVAL_SECONDS;
 use nmea0183::ParseResult;
 use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU32;
 use std::sync::atomic::Ordering;
+use std::sync::Mutex;
 use std::time::Duration;
+use std::time::Instant;
+use switch::ProfileSwitch;
 use uuid::Uuid; // If using the `binstart` feature of `esp-idf-sys`, always keep this module imported
 
 const LED_BRIGHTNESS: u8 = 10;
-const GPS_BAUDRATE: u32 = 9600;
+// Debug includes the fixes we report
+const EVENT_LOG_LEVEL: LevelFilter = LevelFilter::Debug;
+const TX_POWER_DBM: f32 = 20.0;
+// Layout of the frames we send. Switch to `FrameFormat::Header` once the whole fleet
+// understands it.
+const FRAME_FORMAT: FrameFormat = FrameFormat::Legacy;
+// Step through SURVEY_TX_POWER_LEVELS on every report instead of using TX_POWER_DBM, for range
+// testing.
+const SURVEY_MODE: bool = false;
+const SURVEY_TX_POWER_LEVELS: [f32; 7] = [2.0, 5.0, 8.0, 11.0, 14.0, 17.0, 20.0];
+const GEOHASH_PRECISION: usize = 9;
+const SEARCHING_PULSE_PERIOD: Duration = Duration::from_secs(2);
+// The GPS module sends a GGA sentence every second. Without one for GPS_SILENCE, we report what we
+// got anyway, see `morty_rs::gps_state`. Reads of the UART wait at most a second (in ticks), so a
+// silent module doesn't stop the reports.
+const GPS_SILENCE: Duration = Duration::from_secs(3);
+const GPS_READ_TIMEOUT: u32 = esp_idf_sys::configTICK_RATE_HZ;
+// NMEA sentences are at most 82 characters, longer ones are cut off
+const MAX_SENTENCE_LEN: usize = 82;
+// Number of reports in a row without a fix after which we suspect the GPS is broken. In the
+// precise profile we report about every GPS_UPDATE_INTERVAL_SECONDS, so this is roughly 12 hours.
+// It's longer in the economy profile.
+const GPS_FAULT_REPORTS: u32 = (12 * 3600 / GPS_UPDATE_INTERVAL_SECONDS) as u32;
+// Stay awake instead of sleeping between reports, for trackers with constant power. The GPS
+// module stays on and we report every ALWAYS_AWAKE_REPORT_INTERVAL. Can be changed at runtime
+// with the StayAwake and AllowSleep commands. When the power is lost, the maximum awake time in
+// `config` still applies.
+const ALWAYS_AWAKE: bool = false;
+const ALWAYS_AWAKE_REPORT_INTERVAL: Duration = Duration::from_secs(2);
+// Listen for beacons for this long when we wake up, before we read the GPS. What we hear is kept
+// across deep sleep and used when sending, see `delivery_priority`. Zero to skip it.
+const LISTEN_SLICE: Duration = Duration::from_millis(200);
+// Wait at most this long for a beacon that turns its radio off to listen again before we send,
+// see `delivery_priority`. We're awake while we wait, so this costs battery.
+const MAX_WINDOW_WAIT: Duration = Duration::from_secs(5);
+// Frames from the beacons that can wait for the UART thread, more are dropped
+const DOWNLINK_QUEUE_SIZE: usize = 32;
+// What we keep in NVS, erased in this order when NVS is full or corrupted. Wifi keeps its
+// settings in nvs.net80211, which is never erased, and neither are our own settings.
+const NVS_NAMESPACES: &[Namespace] = &[
+    Namespace {
+        name: "phy",
+        criticality: Criticality::Cache,
+    },
+    Namespace {
+        name: "nvs.net80211",
+        criticality: Criticality::Credentials,
+    },
+    Namespace {
+        name: LEDLOG_NAMESPACE,
+        criticality: Criticality::Stats,
+    },
+    Namespace {
+        name: TRACE_NAMESPACE,
+        criticality: Criticality::Stats,
+    },
+    Namespace {
+        name: CONFIG_NAMESPACE,
+        criticality: Criticality::Credentials,
+    },
+];
+// Reporting profiles, picked with the profile switch. Without a switch we use the first one.
+const PRECISE: Profile = Profile {
+    name: "precise",
+    interval: Duration::from_secs(GPS_UPDATE_INTERVAL_SECONDS),
+    led: true,
+    tx_power_dbm: TX_POWER_DBM,
+};
+const ECONOMY: Profile = Profile {
+    name: "economy",
+    interval: Duration::from_secs(5 * 60),
+    led: false,
+    tx_power_dbm: TX_POWER_DBM,
+};
+// The profile for a low and a high level of the profile switch. The pin has a pull-up, so an
+// open switch picks the second one.
+static SWITCH_PROFILES: [Profile; 2] = [PRECISE, ECONOMY];
+
+// Every thread we spawn, by priority. The UART thread reads the GPS module and does the radio,
+// the LED only shows what it does.
+const UART_THREAD: ThreadConfig = ThreadConfig::new("uart-thread\0", 8196, 15, None);
+const LED_THREAD: ThreadConfig = ThreadConfig::new("led-thread\0", 4196, 2, None);
+
+// Timers
+const REPORT: &str = "report";
+const AWAKE_REPORT: &str = "awake_report";
 
 lazy_static! {
     static ref CHARGING: AtomicBool = AtomicBool::new(false);
 }
 
+// Number of sends of the current report that haven't been confirmed by the send callback yet
+static PENDING_SENDS: AtomicU32 = AtomicU32::new(0);
+
+// Position in SURVEY_TX_POWER_LEVELS. This lives in RTC memory, so it survives deep sleep.
+#[link_section = ".rtc.data"]
+static SURVEY_STEP: AtomicU32 = AtomicU32::new(0);
+
+// Whether we stay awake, in RTC memory so a command survives deep sleep
+#[link_section = ".rtc.data"]
+static STAY_AWAKE: AtomicBool = AtomicBool::new(ALWAYS_AWAKE);
+
+// Position of the active profile in SWITCH_PROFILES. This isn't kept over deep sleep, we read the
+// switch again at every wake.
+static PROFILE: AtomicU32 = AtomicU32::new(0);
+
+// Number of reports in a row without a fix, in RTC memory so it survives deep sleep
+#[link_section = ".rtc.data"]
+static REPORTS_WITHOUT_FIX: AtomicU32 = AtomicU32::new(0);
+
+// Time spent in listen slices, the number of slices and how many of them heard a beacon. In RTC
+// memory, so they add up over deep sleeps and show whether listening pays for itself.
+#[link_section = ".rtc.data"]
+static LISTEN_TIME_MS: AtomicU32 = AtomicU32::new(0);
+#[link_section = ".rtc.data"]
+static LISTEN_SLICES: AtomicU32 = AtomicU32::new(0);
+#[link_section = ".rtc.data"]
+static LISTEN_HITS: AtomicU32 = AtomicU32::new(0);
+// Frames from the beacons dropped because the UART thread didn't get to them in time, since we
+// woke up
+static DOWNLINK_DROPPED: AtomicU32 = AtomicU32::new(0);
+
+// Configuration version we reported last, and the number of reports until we send it again. In
+// RTC memory, so we don't send it with every report after a deep sleep.
+#[link_section = ".rtc.data"]
+static REPORTED_CONFIG_VERSION: AtomicU32 = AtomicU32::new(0);
+#[link_section = ".rtc.data"]
+static REPORTS_UNTIL_CONFIG: AtomicU32 = AtomicU32::new(0);
+
+// Message sequence, kept in RTC memory that isn't touched at boot, so it survives deep sleep.
+// After anything but a deep sleep wake, or when the magic doesn't match because the layout
+// changed, we start a new sequence.
+const SEQUENCE_MAGIC: u32 = 0x5345_5101;
+#[link_section = ".rtc.noinit"]
+static SEQUENCE_MAGIC_RTC: AtomicU32 = AtomicU32::new(0);
+#[link_section = ".rtc.noinit"]
+static SEQUENCE_BOOT_ID: AtomicU32 = AtomicU32::new(0);
+#[link_section = ".rtc.noinit"]
+static SEQUENCE_NEXT: AtomicU32 = AtomicU32::new(0);
+// Set once the sequence in RTC memory belongs to this boot
+static SEQUENCE_CURRENT: AtomicBool = AtomicBool::new(false);
+
+// What happened since we woke up, added to the trace when we go to sleep, see `morty_rs::trace`
+static WAKE: Mutex<WakeRecord> = Mutex::new(WakeRecord::new());
+
 fn main() -> anyhow::Result<()> {
     esp_idf_svc::log::EspLogger::initialize_default();
-    let sysloop = EspSystemEventLoop::take()?;
+    set_frame_format(FRAME_FORMAT);
+    if let Err(e) = check_frame_budget() {
+        error!("{e}");
+    }
 
     let peripherals = Peripherals::take().unwrap();
     let pins = peripherals.pins;
 
-    // Configure the LED
+    // Configure the LED before anything else, so it can show what goes wrong after this
     let mut led = Led::new();
-    led.start(pins.gpio18.into(), pins.gpio17.into())?;
+    led.start(pins.gpio18.into(), pins.gpio17.into(), LED_THREAD)
+        .or_fatal(Status::Startup, &led);
     led.set_color(colors::BLUE, LED_BRIGHTNESS)?;
+    check_profiles(&SWITCH_PROFILES).or_fatal(Status::Startup, &led);
+
+    // The event log is nice to have, so we carry on without it
+    phase!("flashlog", {
+        if let Err(e) = flashlog::start(EVENT_LOG_LEVEL) {
+            error!("Unable to start event log: {e}");
+        }
+    });
+
+    let sysloop = EspSystemEventLoop::take().or_fatal(Status::Startup, &led);
+    // Without NVS, the radio still works, it just can't keep its settings, and we run with the
+    // default settings
+    let nvs = nvs_recovery::take_partition(NVS_NAMESPACES).or_fatal(Status::Startup, &led);
+    ledlog::restore();
+    ledlog::install_panic_hook();
+    config::load().or_fatal(Status::Startup, &led);
 
     // Configure Wifi for use with ESP-NOW
-    let nvs = EspDefaultNvsPartition::take()?;
-    let mut wifi = Box::new(EspWifi::new(peripherals.modem, sysloop, Some(nvs))?);
+    let _wifi = phase!("radio_start", {
+        start_radio(peripherals.modem, sysloop, nvs).or_fatal(Status::Radio, &led)
+    });
+
+    // Create a thread that reads the UART and transforms this into a protobuf to broadcast
+    let led_handle = led.handle();
+
+    // Set this to the pin that switches the power of the GPS module, if the board has one, e.g.
+    // `Some(pins.gpio38.into())`
+    let gps_power_pin: Option<gpio::AnyOutputPin> = None;
+    // Set this to the pin of the profile switch, if the board has one, e.g.
+    // `Some(pins.gpio34.into())`
+    let profile_switch_pin: Option<gpio::AnyInputPin> = None;
+
+    let uart_thread = spawn_thread(UART_THREAD, move || {
+        let uart_led = led.handle();
+        uart_task(
+            peripherals.uart1,
+            pins.gpio0.into(),
+            pins.gpio1.into(),
+            gps_power_pin,
+            profile_switch_pin,
+            pins.gpio33.into(),
+            pins.gpio10,
+            peripherals.adc1,
+            led,
+        )
+        .or_fatal(Status::Uart, &uart_led);
+    })
+    .or_fatal(Status::Thread, &led_handle);
+
+    uart_thread.join().or_fatal(Status::Thread, &led_handle);
+    Ok(())
+}
+
+/// Start wifi in long range mode, for use with ESP-NOW
+fn start_radio(
+    modem: esp_idf_hal::modem::Modem,
+    sysloop: EspSystemEventLoop,
+    nvs: Option<EspDefaultNvsPartition>,
+) -> Result<Box<EspWifi<'static>>, anyhow::Error> {
+    let mut wifi = Box::new(EspWifi::new(modem, sysloop, nvs)?);
 
     esp!(unsafe {
         esp_idf_sys::esp_wifi_set_protocol(
@@ -62,39 +316,23 @@ fn main() -> anyhow::Result<()> {
     })?;
 
     wifi.start()?;
-
-    // Create a thread that reads the UART and transforms this into a protobuf to broadcast
-    set_thread_spawn_configuration("uart-thread", 8196, 15, None)?;
-
-    let uart_thread = std::thread::Builder::new()
-        .stack_size(8196)
-        .spawn(move || {
-            uart_task(
-                peripherals.uart1,
-                pins.gpio0.into(),
-                pins.gpio1.into(),
-                pins.gpio33.into(),
-                pins.gpio10,
-                peripherals.adc1,
-                led,
-            )
-            .unwrap();
-        })?;
-
-    uart_thread.join().unwrap();
-    Ok(())
+    set_tx_power_dbm(TX_POWER_DBM)?;
+    Ok(wifi)
 }
 
 fn uart_task(
     uart: impl Peripheral<P = impl Uart> + 'static,
     tx: gpio::AnyOutputPin,
     rx: gpio::AnyInputPin,
+    gps_power_pin: Option<gpio::AnyOutputPin>,
+    profile_switch_pin: Option<gpio::AnyInputPin>,
     vbus_sense_pin: gpio::AnyInputPin,
     vbat_sense_pin: impl gpio::ADCPin<Adc = ADC1>,
     adc_peripheral: impl Peripheral<P = impl adc::Adc> + 'static,
     mut led: Led,
 ) -> Result<(), anyhow::Error> {
-    let config = uart::config::Config::default().baudrate(Hertz(GPS_BAUDRATE));
+    let config = config();
+    let uart_config = uart::config::Config::default().baudrate(Hertz(config.gps_baudrate));
 
     let uart_driver = uart::UartDriver::new(
         uart,
@@ -102,9 +340,18 @@ fn uart_task(
         rx,
         Option::<gpio::Gpio0>::None,
         Option::<gpio::Gpio0>::None,
-        &config,
+        &uart_config,
     )?;
 
+    // Power up the GPS module, in case it was powered down or in standby while we slept
+    phase!("gps_init", {
+        gps::init(
+            uart_driver.port() as i32,
+            config.gps_standby,
+            gps_power_pin.as_ref().map(|p| p.pin()),
+        )?
+    });
+
     uart_driver.flush_read()?;
 
     let vbus_sense = gpio::PinDriver::input(vbus_sense_pin)?;
@@ -118,32 +365,139 @@ fn uart_task(
 
     let mut nmea_parser = nmea0183::Parser::new();
 
-    let esp_now = esp_now_init();
+    let esp_now = phase!("esp_now_init", { esp_now_init() });
     esp_now.register_send_cb(esp_now_send_cb)?;
 
-    let mut buf = [0u8; 1];
+    // Commands and assistance data are handled by this thread, the callback only hands over the
+    // frames.
+    let mut dispatcher = RecvDispatcher::new();
+    let frames = dispatcher.register(
+        "downlink",
+        Some(DOWNLINK_TYPES),
+        DOWNLINK_QUEUE_SIZE,
+        &DOWNLINK_DROPPED,
+    );
+    dispatcher.freeze().attach(&esp_now)?;
+    let mut downlink = Downlink::new(frames, own_mac()?, led.handle());
+    if hello::booted() {
+        send_hello(&esp_now)?;
+    }
+    phase!("listen", { listen_for_beacons(&mut downlink, &esp_now)? });
+    trace_wake(|wake| {
+        wake.wake_cause = unsafe { esp_idf_sys::esp_sleep_get_wakeup_cause() } as u8;
+        wake.woke_at = downlink.now().map_or(0, |now| now.as_secs() as u32);
+    });
+
+    // Last resort to protect the battery: whatever keeps us awake, we go to sleep after the
+    // maximum awake time, unless we're charging. This also applies when we're told to stay
+    // awake. When the GPS module was powered off, it can take a lot longer to get a fix, so we
+    // give it some extra time.
+    let max_awake_time = match gps_power_pin {
+        Some(_) => config.max_awake_time + config.gps_power_off_extra_awake_time,
+        None => config.max_awake_time,
+    };
+    let timer_service = EspTimerService::new()?;
+    let awake_watchdog = timer_service.timer(move || {
+        if !CHARGING.load(Ordering::SeqCst) {
+            warn!("Awake for longer than {:?}", max_awake_time);
+            deep_sleep(profile().interval);
+        }
+    })?;
+    awake_watchdog.every(max_awake_time)?;
 
-    // Keep track of last updated time
-    let mut last_update = LastUpdate::new();
+    let mut buf = [0u8; 1];
+    // What the GPS module sent since we woke up. The parser doesn't do GSA sentences, so we keep
+    // the sentence to look at those ourselves.
+    let mut counts = SentenceCounts::default();
+    let mut sentence = String::with_capacity(MAX_SENTENCE_LEN);
+    let mut gsa_mode = None;
+    let mut last_gga = Instant::now();
+
+    let mut schedule = PeriodicSet::new();
+    schedule
+        .add(REPORT, profile().interval)
+        .add(AWAKE_REPORT, ALWAYS_AWAKE_REPORT_INTERVAL);
+
+    let mut profile_switch = profile_switch_pin.map(ProfileSwitch::new).transpose()?;
+    check_profile_switch(&mut profile_switch, &mut schedule)?;
+
+    // Show we're searching until the GPS gives us something, after that the LED shows whether we
+    // have a fix or not.
+    led.pulse_color(colors::BLUE, led_brightness(), SEARCHING_PULSE_PERIOD)?;
+    boot_complete();
 
     loop {
-        uart_driver.read(&mut buf, BLOCK)?;
-        match nmea_parser.parse_from_byte(buf[0]) {
+        check_profile_switch(&mut profile_switch, &mut schedule)?;
+        // Nothing parsed as GGA for a while: the module is silent or sends garbage
+        if last_gga.elapsed() >= GPS_SILENCE {
+            last_gga = Instant::now();
+            downlink.handle(&esp_now)?;
+            let state = gps_state(counts, 0, 0, gsa_mode);
+            warn!(
+                "No GGA sentence for {GPS_SILENCE:?}: {} ({} bytes, {} sentences)",
+                state_name(state as i32),
+                counts.bytes,
+                counts.sentences
+            );
+            handle_message(
+                None,
+                &esp_now,
+                &vbus_sense,
+                &mut vbat_driver,
+                &mut adc1,
+                &mut led,
+                &mut schedule,
+                &downlink,
+                state,
+            )?;
+        }
+        if uart_driver.read(&mut buf, GPS_READ_TIMEOUT)? == 0 {
+            continue;
+        }
+        counts.bytes = counts.bytes.saturating_add(1);
+        match buf[0] {
+            b'$' => sentence.clear(),
+            b'\n' => gsa_mode = gsa_fix_mode(&sentence).or(gsa_mode),
+            _ => {}
+        }
+        if sentence.len() < MAX_SENTENCE_LEN {
+            sentence.push(buf[0] as char);
+        }
+        let parsed = nmea_parser.parse_from_byte(buf[0]);
+        if let Some(Ok(_)) = parsed {
+            counts.sentences = counts.sentences.saturating_add(1);
+        }
+        match parsed {
             Some(Ok(ParseResult::GGA(Some(gga)))) => {
-                led.set_color(colors::GREEN, LED_BRIGHTNESS)?;
-
+                last_gga = Instant::now();
+                downlink.handle(&esp_now)?;
+                led.set_color(colors::GREEN, led_brightness())?;
+                let state = gps_state(
+                    counts,
+                    gga.gps_quality as i32,
+                    gga.sat_in_use as i32,
+                    gsa_mode,
+                );
+
+                let utc = gga.time.hours as i32 * 3600
+                    + gga.time.minutes as i32 * 60
+                    + gga.time.seconds as i32;
                 let msg = GpsMsg {
                     latitude: gga.latitude.as_f64(),
                     longitude: gga.longitude.as_f64(),
                     satellites: gga.sat_in_use as i32,
                     fix_quality: gga.gps_quality as i32,
                     hdop: gga.hdop,
-                    utc: gga.time.hours as i32 * 3600
-                        + gga.time.minutes as i32 * 60
-                        + gga.time.seconds as i32,
+                    utc,
+                    fix_epoch: fix_epoch(utc, downlink.now().map(|t| t.as_secs() as i64)),
                     uid: Uuid::new_v4().to_string()[0..6].to_string(),
                     ..Default::default()
                 };
+                info!(
+                    "Fix at {} ({})",
+                    format_dm(msg.latitude, msg.longitude),
+                    geohash(msg.latitude, msg.longitude, GEOHASH_PRECISION)
+                );
 
                 handle_message(
                     Some(msg),
@@ -152,11 +506,16 @@ fn uart_task(
                     &mut vbat_driver,
                     &mut adc1,
                     &mut led,
-                    &mut last_update,
+                    &mut schedule,
+                    &downlink,
+                    state,
                 )?;
             }
             Some(Ok(ParseResult::GGA(None))) => {
-                led.set_color(colors::RED, LED_BRIGHTNESS)?;
+                last_gga = Instant::now();
+                downlink.handle(&esp_now)?;
+                led.set_color(colors::RED, led_brightness())?;
+                let state = gps_state(counts, 0, 0, gsa_mode);
 
                 handle_message(
                     None,
@@ -165,7 +524,9 @@ fn uart_task(
                     &mut vbat_driver,
                     &mut adc1,
                     &mut led,
-                    &mut last_update,
+                    &mut schedule,
+                    &downlink,
+                    state,
                 )?;
             }
             _ => {}
@@ -180,14 +541,58 @@ fn handle_message<T: gpio::ADCPin>(
     vbat_driver: &mut adc::AdcChannelDriver<T, adc::Atten11dB<adc::ADC1>>,
     adc: &mut adc::AdcDriver<impl adc::Adc>,
     led: &mut Led,
-    last_update: &mut LastUpdate,
+    schedule: &mut PeriodicSet,
+    downlink: &Downlink,
+    state: GpsState,
 ) -> Result<(), anyhow::Error>
 where
     adc::Atten11dB<ADC1>: adc::Attenuation<<T as ADCPin>::Adc>,
 {
-    if last_update.should_update(Duration::from_secs(10)) {
+    // State changes are more important than regular reports
+    let mut priority = Priority::Routine;
+    let report = if STAY_AWAKE.load(Ordering::SeqCst) {
+        AWAKE_REPORT
+    } else {
+        REPORT
+    };
+
+    // Report out of cycle when the charging state changes, so plugging in shows up immediately
+    if config().report_on_charging_change {
+        if let Some(charging) = read_level_debounced(vbus_sense) {
+            if charging != CHARGING.load(Ordering::SeqCst) {
+                info!("Charging state changed to {charging}");
+                schedule.reset(report);
+                priority = Priority::High;
+            }
+        }
+    }
+
+    if schedule.due(report, uptime()) {
         let (charging, battery_voltage) = check_power(vbus_sense, vbat_driver, adc)?;
+        let battery_percent =
+            estimate_soc(battery_voltage, charging, config().battery_calibration) as u32;
+        let tx_power = next_tx_power()?;
+        let (boot_id, seq) = next_sequence();
+        let gps_fault_suspected = record_fix(gps_message.is_some());
+        let config_version = next_config_version(report);
+        let name = if name_due(seq) {
+            config().name.clone()
+        } else {
+            String::new()
+        };
+        let sent_epoch = downlink
+            .now()
+            .and_then(|t| known(t.as_secs() as i64))
+            .unwrap_or(0);
         CHARGING.store(charging, Ordering::SeqCst);
+        trace_wake(|wake| {
+            if gps_message.is_some() && wake.ttff_ms == 0 {
+                wake.ttff_ms = uptime().as_millis() as u32;
+            }
+            wake.gps_state = state as u8;
+            wake.battery_mv = (battery_voltage * 1000.0) as u16;
+            wake.beacons_heard = downlink.beacons_heard().min(u8::MAX as u32) as u8;
+        });
 
         let blink_color = match &gps_message {
             Some(_) => colors::PURPLE,
@@ -198,6 +603,21 @@ where
             Some(mut m) => {
                 m.charging = charging;
                 m.battery_voltage = battery_voltage;
+                m.battery_percent = battery_percent;
+                m.tx_power = tx_power;
+                m.boot_id = boot_id;
+                m.seq = seq;
+                m.gps_fault_suspected = gps_fault_suspected;
+                m.profile = profile().name.to_string();
+                m.config_version = config_version;
+                m.clock = Some(downlink.clock_status());
+                m.gps_state = state as i32;
+                m.name = name;
+                m.sent_epoch = sent_epoch;
+                flashlog::log_event(EventKind::Fix {
+                    latitude: m.latitude,
+                    longitude: m.longitude,
+                });
                 morty_message::Msg::Gps(m)
             }
             None => {
@@ -205,19 +625,210 @@ where
                     uid: Uuid::new_v4().to_string()[0..6].to_string(),
                     charging,
                     battery_voltage,
+                    battery_percent,
+                    tx_power,
+                    boot_id,
+                    seq,
+                    gps_fault_suspected,
+                    profile: profile().name.to_string(),
+                    config_version,
+                    clock: Some(downlink.clock_status()),
+                    gps_state: state as i32,
+                    name,
+                    sent_epoch,
                     ..Default::default()
                 };
                 morty_message::Msg::Gps(m)
             }
         };
 
-        led.blink_color(blink_color, LED_BRIGHTNESS, Duration::from_millis(300), 2)?;
+        // A module that sends nothing is a different fault than one without a fix
+        if state == GpsState::NoData {
+            status::show(Status::GpsNoData, &led.handle())?;
+        } else if gps_fault_suspected {
+            status::show(Status::GpsFault, &led.handle())?;
+        } else {
+            led.blink_color(blink_color, led_brightness(), Duration::from_millis(300), 2)?;
+        }
 
-        broadcast_msg(&msg, esp_now)?;
+        let priority = delivery_priority(priority, downlink.beacons(), downlink.now());
+        PENDING_SENDS.store(priority.broadcast_count() as u32, Ordering::SeqCst);
+        broadcast_msg(&msg, priority, esp_now)?;
     }
     Ok(())
 }
 
+/// Tell the beacons and the gateway that we booted, see `morty_rs::hello`.
+fn send_hello(esp_now: &EspNow) -> Result<(), anyhow::Error> {
+    let mut capabilities = hello::CAP_GPS | hello::CAP_COMMANDS | hello::CAP_ASSIST;
+    if FRAME_FORMAT == FrameFormat::Header {
+        capabilities |= hello::CAP_FRAME_HEADER;
+    }
+    let msg = morty_message::Msg::Hello(hello::hello(
+        Role::Tracker,
+        env!("CARGO_PKG_VERSION"),
+        capabilities,
+        config_version(),
+        &config().name,
+    )?);
+    // The hello isn't the report we go to sleep after, so the send callback must not get to zero
+    let priority = Priority::High;
+    PENDING_SENDS.store(priority.broadcast_count() as u32 + 1, Ordering::SeqCst);
+    Ok(broadcast_msg(&msg, priority, esp_now)?)
+}
+
+/// Listen for beacons for LISTEN_SLICE, and add the time it took to the listen stats.
+fn listen_for_beacons(downlink: &mut Downlink, esp_now: &EspNow) -> Result<(), anyhow::Error> {
+    if LISTEN_SLICE.is_zero() {
+        return Ok(());
+    }
+    let start = Instant::now();
+    let before = downlink.beacons_heard();
+    std::thread::sleep(LISTEN_SLICE);
+    downlink.handle(esp_now)?;
+    let heard = downlink.beacons_heard() - before;
+
+    let elapsed = start.elapsed().as_millis() as u32;
+    let total = LISTEN_TIME_MS.fetch_add(elapsed, Ordering::SeqCst) + elapsed;
+    let slices = LISTEN_SLICES.fetch_add(1, Ordering::SeqCst) + 1;
+    let hits = LISTEN_HITS.fetch_add((heard > 0) as u32, Ordering::SeqCst) + (heard > 0) as u32;
+    let dropped = DOWNLINK_DROPPED.load(Ordering::SeqCst);
+    info!(
+        "Listened for beacons for {elapsed} ms and heard {heard}, dropped {dropped} frames. In \
+         total {total} ms in {slices} slices, {hits} of them heard a beacon."
+    );
+    Ok(())
+}
+
+/// How hard to try to get a report across, given the beacons we heard. When we didn't hear one
+/// recently, we repeat the report, to reach a beacon at the edge of our range. When all the
+/// beacons we heard turn their radio off now and then, see `morty_rs::duty`, we wait for the
+/// first one to listen, or repeat the report when that takes longer than `MAX_WINDOW_WAIT`.
+fn delivery_priority(
+    priority: Priority,
+    beacons: &PresenceTable<BEACON_TABLE_SIZE>,
+    now: Option<Duration>,
+) -> Priority {
+    let Some(beacon) = beacons.best(sleep_clock()) else {
+        return Priority::High;
+    };
+    if beacon.channel != 0 && beacon.channel != ESP_NOW_CHANNEL {
+        warn!(
+            "Beacon {} listens on channel {}, we send on {ESP_NOW_CHANNEL}",
+            mac_to_string(&beacon.mac),
+            beacon.channel
+        );
+    }
+    // Without a wall clock we can't tell when they listen
+    let Some(now) = now else {
+        return priority;
+    };
+    let now_ms = now.as_millis() as i64;
+    let wait = beacons
+        .sightings(sleep_clock())
+        .map(|b| b.schedule.until_window(now_ms))
+        .min()
+        .unwrap_or_default();
+    if wait.is_zero() {
+        priority
+    } else if wait <= MAX_WINDOW_WAIT {
+        info!("Waiting {} ms for a beacon to listen", wait.as_millis());
+        std::thread::sleep(wait);
+        priority
+    } else {
+        Priority::High
+    }
+}
+
+/// Boot id and sequence number for the next report. The sequence continues across deep sleep,
+/// so the gateway only sees a new boot after a power loss or reset.
+fn next_sequence() -> (u32, u32) {
+    let woke_up = unsafe { esp_idf_sys::esp_reset_reason() }
+        == esp_idf_sys::esp_reset_reason_t_ESP_RST_DEEPSLEEP;
+    let current = woke_up || SEQUENCE_CURRENT.swap(true, Ordering::SeqCst);
+    let valid = current && SEQUENCE_MAGIC_RTC.load(Ordering::SeqCst) == SEQUENCE_MAGIC;
+    let saved = valid.then(|| {
+        (
+            SEQUENCE_BOOT_ID.load(Ordering::SeqCst),
+            SEQUENCE_NEXT.load(Ordering::SeqCst),
+        )
+    });
+    let mut sequence = Sequence::restore(saved);
+    let next = sequence.next();
+
+    SEQUENCE_BOOT_ID.store(sequence.boot_id(), Ordering::SeqCst);
+    SEQUENCE_NEXT.store(sequence.peek(), Ordering::SeqCst);
+    SEQUENCE_MAGIC_RTC.store(SEQUENCE_MAGIC, Ordering::SeqCst);
+    next
+}
+
+/// Record whether the next report has a fix. Returns whether we suspect the GPS is broken.
+fn record_fix(fix: bool) -> bool {
+    let mut watch = FixWatch::resume(
+        GPS_FAULT_REPORTS,
+        REPORTS_WITHOUT_FIX.load(Ordering::SeqCst),
+    );
+    let suspected = watch.record(fix);
+    REPORTS_WITHOUT_FIX.store(watch.without_fix(), Ordering::SeqCst);
+    if watch.without_fix() == GPS_FAULT_REPORTS {
+        warn!("No fix in {GPS_FAULT_REPORTS} reports, suspecting a GPS fault");
+    }
+    suspected
+}
+
+/// The configuration version for the next report, sent with the report `report`. It goes out
+/// about every `config_report_interval` and when it changed, it's 0 in the other reports.
+fn next_config_version(report: &str) -> u32 {
+    let version = config_version();
+    let left = REPORTS_UNTIL_CONFIG.load(Ordering::SeqCst);
+    if left > 0 && version == REPORTED_CONFIG_VERSION.load(Ordering::SeqCst) {
+        REPORTS_UNTIL_CONFIG.store(left - 1, Ordering::SeqCst);
+        return 0;
+    }
+    let interval = if report == AWAKE_REPORT {
+        ALWAYS_AWAKE_REPORT_INTERVAL
+    } else {
+        profile().interval
+    };
+    let reports = (config().config_report_interval.as_secs() / interval.as_secs().max(1)).max(1);
+    REPORTS_UNTIL_CONFIG.store(reports as u32 - 1, Ordering::SeqCst);
+    REPORTED_CONFIG_VERSION.store(version, Ordering::SeqCst);
+    version
+}
+
+/// Version of the configuration we run with. Add settings here when they can differ between
+/// trackers.
+fn config_version() -> u32 {
+    let profile = profile();
+    ConfigVersion::new()
+        .str("profile", profile.name)
+        .duration("interval", profile.interval)
+        .bool("led", profile.led)
+        .f32("tx_power_dbm", profile.tx_power_dbm)
+        .bool("frame_header", FRAME_FORMAT == FrameFormat::Header)
+        .bool("gps_standby", config().gps_standby)
+        .bool("survey_mode", SURVEY_MODE)
+        .bool("stay_awake", STAY_AWAKE.load(Ordering::SeqCst))
+        .bool(
+            "report_on_charging_change",
+            config().report_on_charging_change,
+        )
+        .finish()
+}
+
+/// The TX power to use for the next report. In survey mode we step through a couple of power
+/// levels, so the reach of every level can be tested.
+fn next_tx_power() -> Result<f32, anyhow::Error> {
+    if SURVEY_MODE {
+        let step = SURVEY_STEP.fetch_add(1, Ordering::SeqCst) as usize;
+        Ok(set_tx_power_dbm(
+            SURVEY_TX_POWER_LEVELS[step % SURVEY_TX_POWER_LEVELS.len()],
+        )?)
+    } else {
+        Ok(tx_power_dbm()?)
+    }
+}
+
 fn check_power<T: gpio::ADCPin>(
     vbus_sense: &gpio::PinDriver<<&mut gpio::AnyInputPin as Peripheral>::P, gpio::Input>,
     vbat_driver: &mut adc::AdcChannelDriver<T, adc::Atten11dB<adc::ADC1>>,
@@ -228,26 +839,104 @@ where
 {
     // check if the device is powered by USB or battery
 
-    let charging = vbus_sense.is_high();
-    let voltage = adc.read(vbat_driver)?;
-    Ok((charging, voltage as f32 / 262.0))
+    let charging = read_level_debounced(vbus_sense).unwrap_or_else(|| vbus_sense.is_high());
+    let voltage = read_battery_voltage(vbat_driver, adc)?;
+    Ok((charging, voltage))
+}
+
+/// The active reporting profile.
+fn profile() -> &'static Profile {
+    &SWITCH_PROFILES[PROFILE.load(Ordering::SeqCst) as usize]
+}
+
+/// Brightness of the fix and report colors, off when the profile doesn't use the LED.
+fn led_brightness() -> u8 {
+    if profile().led {
+        LED_BRIGHTNESS
+    } else {
+        0
+    }
+}
+
+/// Switch to the profile the switch is set to, when it moved.
+fn check_profile_switch(
+    switch: &mut Option<ProfileSwitch>,
+    schedule: &mut PeriodicSet,
+) -> Result<(), anyhow::Error> {
+    if let Some(level) = switch.as_mut().map(|s| s.changed()).transpose()?.flatten() {
+        apply_profile(level as usize, schedule)?;
+    }
+    Ok(())
+}
+
+/// Make the profile at `index` in SWITCH_PROFILES the active one.
+fn apply_profile(index: usize, schedule: &mut PeriodicSet) -> Result<(), anyhow::Error> {
+    PROFILE.store(index as u32, Ordering::SeqCst);
+    let profile = profile();
+    schedule.add(REPORT, profile.interval);
+    if !SURVEY_MODE {
+        set_tx_power_dbm(profile.tx_power_dbm)?;
+    }
+    info!(
+        "Using the {} profile: every {:?}, LED {}, {} dBm",
+        profile.name,
+        profile.interval,
+        if profile.led { "on" } else { "off" },
+        profile.tx_power_dbm
+    );
+    Ok(())
+}
+
+/// Stay awake, or go back to sleeping between reports.
+fn set_stay_awake(stay: bool) {
+    info!("Staying awake: {stay}");
+    STAY_AWAKE.store(stay, Ordering::SeqCst);
+}
+
+/// Update the trace record of this wake.
+fn trace_wake(update: impl FnOnce(&mut WakeRecord)) {
+    update(&mut WAKE.lock().unwrap());
 }
 
 fn esp_now_send_cb(_dst: &[u8], status: SendStatus) {
+    trace_wake(|wake| {
+        wake.sends = wake.sends.saturating_add(1);
+        if matches!(status, SendStatus::FAIL) {
+            wake.send_failures = wake.send_failures.saturating_add(1);
+        }
+    });
+    if STAY_AWAKE.load(Ordering::SeqCst) {
+        return;
+    }
     let charging = CHARGING.load(Ordering::SeqCst);
     if charging {
         return;
     }
 
+    // Wait until all repeats of the report went out
+    let pending = PENDING_SENDS
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+            Some(n.saturating_sub(1))
+        })
+        .unwrap_or(0);
+    if pending > 1 {
+        return;
+    }
+
     match status {
-        SendStatus::SUCCESS => {
-            info!("Going to sleep..");
-            let us = Duration::from_secs(GPS_UPDATE_INTERVAL_SECONDS);
-            unsafe {
-                esp_sleep_enable_timer_wakeup(us.as_micros() as u64);
-                esp_deep_sleep_start();
-            }
-        }
+        SendStatus::SUCCESS => deep_sleep(profile().interval),
         SendStatus::FAIL => {}
     }
 }
+
+fn deep_sleep(duration: Duration) -> ! {
+    gps::prepare_for_sleep(duration);
+    trace_wake(|wake| wake.sleep_secs = duration.as_secs() as u32);
+    trace::push(*WAKE.lock().unwrap());
+    flashlog::flush();
+    info!("Going to sleep for {:?}..", duration);
+    unsafe {
+        esp_sleep_enable_timer_wakeup(duration.as_micros() as u64);
+        esp_deep_sleep_start()
+    }
+}
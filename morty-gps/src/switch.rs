@@ -0,0 +1,40 @@
+use esp_idf_hal::gpio;
+use morty_rs::power::read_level_debounced;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+
+// Set by the interrupt when the switch moved, and at startup so we read it once
+static CHANGED: AtomicBool = AtomicBool::new(true);
+
+/// The switch that picks the reporting profile. The pin has a pull-up, so an open switch reads
+/// high. We get an interrupt when it moves and read it once it settled.
+pub struct ProfileSwitch {
+    pin: gpio::PinDriver<'static, gpio::AnyInputPin, gpio::Input>,
+}
+
+impl ProfileSwitch {
+    pub fn new(pin: gpio::AnyInputPin) -> Result<Self, anyhow::Error> {
+        let mut pin = gpio::PinDriver::input(pin)?;
+        pin.set_pull(gpio::Pull::Up)?;
+        pin.set_interrupt_type(gpio::InterruptType::AnyEdge)?;
+        unsafe { pin.subscribe(|| CHANGED.store(true, Ordering::SeqCst))? };
+        pin.enable_interrupt()?;
+        CHANGED.store(true, Ordering::SeqCst);
+        Ok(Self { pin })
+    }
+
+    /// The level of the switch when it moved since the last call, and has settled.
+    pub fn changed(&mut self) -> Result<Option<bool>, anyhow::Error> {
+        if !CHANGED.swap(false, Ordering::SeqCst) {
+            return Ok(None);
+        }
+        // The interrupt is disabled once it fired
+        self.pin.enable_interrupt()?;
+        let level = read_level_debounced(&self.pin);
+        if level.is_none() {
+            // Still bouncing, try again next time
+            CHANGED.store(true, Ordering::SeqCst);
+        }
+        Ok(level)
+    }
+}
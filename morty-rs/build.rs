@@ -1,5 +1,9 @@
 // Necessary because of this issue: https://github.com/rust-lang/cargo/issues/9641
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // The messages are only built with std, `wire` does without them
+    if std::env::var_os("CARGO_FEATURE_STD").is_none() {
+        return Ok(());
+    }
     let project_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
     prost_build::compile_protos(
         &[format!("{project_dir}/src/morty.proto")],
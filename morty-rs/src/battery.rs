@@ -0,0 +1,121 @@
+//! State of charge of a single LiPo cell, estimated from its voltage. The discharge curve is a
+//! const table, so it stays in flash and is read through the cache like the rest of rodata,
+//! without taking any RAM. Between the points of the curve we interpolate linearly.
+//!
+//! The curve is for a cell at rest. While charging, the voltage reads about CHARGING_OFFSET high.
+//! The divider and ADC of every device are off by a bit as well, which a calibration offset per
+//! device corrects. The tracker and the gateway both estimate with `estimate_soc`, so they agree.
+
+/// Voltage of a cell at rest in millivolts, at 0, 5, .., 100 percent charge.
+pub const DISCHARGE_CURVE: [u16; 21] = [
+    3270, 3610, 3690, 3710, 3730, 3750, 3770, 3790, 3800, 3820, 3840, 3850, 3870, 3910, 3950, 3980,
+    4020, 4080, 4110, 4150, 4200,
+];
+
+// Percent between two points of the curve
+const CURVE_STEP: f32 = 5.0;
+
+/// How much higher the voltage of a cell reads while it's charging, in volts.
+pub const CHARGING_OFFSET: f32 = 0.15;
+
+/// Charge of a cell at `voltage` in whole percents. `calibration` is added to the voltage, both
+/// in volts.
+pub fn estimate_soc(voltage: f32, charging: bool, calibration: f32) -> u8 {
+    let mut volts = voltage + calibration;
+    if charging {
+        volts -= CHARGING_OFFSET;
+    }
+    let millivolts = volts * 1000.0;
+    let empty = DISCHARGE_CURVE[0] as f32;
+    let full = DISCHARGE_CURVE[DISCHARGE_CURVE.len() - 1] as f32;
+    // Also for readings that aren't a number
+    if !(millivolts > empty) {
+        return 0;
+    }
+    if millivolts >= full {
+        return 100;
+    }
+    // The first point above us, there is one below as well
+    let high = DISCHARGE_CURVE
+        .iter()
+        .position(|&point| point as f32 > millivolts)
+        .unwrap_or(DISCHARGE_CURVE.len() - 1);
+    let (v_low, v_high) = (
+        DISCHARGE_CURVE[high - 1] as f32,
+        DISCHARGE_CURVE[high] as f32,
+    );
+    let percent =
+        (high - 1) as f32 * CURVE_STEP + (millivolts - v_low) / (v_high - v_low) * CURVE_STEP;
+    percent.round() as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn volts(millivolts: u16) -> f32 {
+        millivolts as f32 / 1000.0
+    }
+
+    #[test]
+    fn table_points_give_their_percentage() {
+        for (i, &point) in DISCHARGE_CURVE.iter().enumerate() {
+            assert_eq!(
+                estimate_soc(volts(point), false, 0.0),
+                i as u8 * 5,
+                "{point}mV"
+            );
+        }
+    }
+
+    #[test]
+    fn midpoints_are_interpolated() {
+        for (i, pair) in DISCHARGE_CURVE.windows(2).enumerate() {
+            let mid = (pair[0] + pair[1]) / 2;
+            let soc = estimate_soc(volts(mid), false, 0.0);
+            let low = i as u8 * 5;
+            assert!((low + 2..=low + 3).contains(&soc), "{mid}mV gave {soc}%");
+        }
+        // A quarter of the way from 15% to 20%
+        assert_eq!(estimate_soc(3.715, false, 0.0), 16);
+        assert_eq!(estimate_soc(3.44, false, 0.0), 3);
+    }
+
+    #[test]
+    fn readings_out_of_range_are_clamped() {
+        assert_eq!(estimate_soc(0.0, false, 0.0), 0);
+        assert_eq!(estimate_soc(3.0, false, 0.0), 0);
+        assert_eq!(estimate_soc(-1.0, false, 0.0), 0);
+        assert_eq!(estimate_soc(4.25, false, 0.0), 100);
+        assert_eq!(estimate_soc(12.0, false, 0.0), 100);
+        assert_eq!(estimate_soc(f32::INFINITY, false, 0.0), 100);
+        assert_eq!(estimate_soc(f32::NAN, false, 0.0), 0);
+    }
+
+    #[test]
+    fn charging_reads_high() {
+        assert_eq!(estimate_soc(3.76, true, 0.0), 5);
+        assert_eq!(estimate_soc(3.76, false, 0.0), 28);
+        assert_eq!(estimate_soc(4.35, true, 0.0), 100);
+        assert_eq!(estimate_soc(4.26, true, 0.0), 90);
+        assert_eq!(estimate_soc(3.4, true, 0.0), 0);
+    }
+
+    #[test]
+    fn calibration_is_added() {
+        assert_eq!(estimate_soc(3.61, false, 0.1), 15);
+        assert_eq!(estimate_soc(3.81, false, -0.1), 15);
+        assert_eq!(estimate_soc(4.1, false, 0.1), 100);
+        // Together with charging
+        assert_eq!(estimate_soc(3.76, true, 0.1), 15);
+    }
+
+    #[test]
+    fn the_curve_rises() {
+        assert!(DISCHARGE_CURVE.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(
+            estimate_soc(volts(DISCHARGE_CURVE[0]), true, CHARGING_OFFSET),
+            0
+        );
+    }
+}
@@ -0,0 +1,577 @@
+//! Negotiation of the baud rate of the UART between a beacon and the gateway. A long cable can be
+//! too much for 115200 baud, a short one can do with more. The gateway proposes a rate in a ping,
+//! and when the beacon accepts it in its pong, the gateway sends a countdown with the next pings
+//! until the beacon acknowledges it. Both ends switch when the countdown runs out.
+//!
+//! Frames get lost, so one end might switch while the other doesn't. Both ends run the same
+//! `BaudLink`: at any other rate than DEFAULT_BAUD, it has to hear from the other end at least
+//! every SILENCE_TIMEOUT, and falls back to DEFAULT_BAUD when it doesn't. The gateway pings every
+//! KEEPALIVE_INTERVAL at those rates, so when one end falls back, the other follows.
+
+use esp_idf_sys::esp;
+use esp_idf_sys::EspError;
+use std::fmt;
+use std::time::Duration;
+
+pub const DEFAULT_BAUD: u32 = 115_200;
+/// The rates both ends support, from slow to fast.
+pub const BAUDS: [u32; 3] = [57_600, 115_200, 230_400];
+pub const SILENCE_TIMEOUT: Duration = Duration::from_secs(10);
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(2);
+// Time between accepting a rate and switching to it. The countdown is resent with every
+// keepalive until it's acknowledged, so this leaves room for a lost frame.
+const COUNTDOWN: Duration = Duration::from_secs(5);
+// How long we wait for a proposal to be accepted
+const PROPOSAL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A change of the rate, to apply to the UART.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BaudChange {
+    /// The countdown ran out.
+    Switched(u32),
+    /// We didn't hear from the other end at `from` for SILENCE_TIMEOUT, and went back to
+    /// DEFAULT_BAUD.
+    FellBack { from: u32 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Stable,
+    /// We proposed `baud` at `at`, and wait for the other end to accept it
+    Proposed {
+        baud: u32,
+        at: Duration,
+    },
+    /// We switch to `baud` at `switch_at`. `acked` once the other end knows about it.
+    Countdown {
+        baud: u32,
+        switch_at: Duration,
+        acked: bool,
+    },
+}
+
+/// One end of the link. This doesn't know about the UART, the caller sends and receives the
+/// frames and applies the changes `poll` returns. Times are passed in by the caller.
+#[derive(Clone, Debug)]
+pub struct BaudLink {
+    baud: u32,
+    state: State,
+    last_heard: Duration,
+}
+
+impl BaudLink {
+    pub fn new(now: Duration) -> Self {
+        Self {
+            baud: DEFAULT_BAUD,
+            state: State::Stable,
+            last_heard: now,
+        }
+    }
+
+    pub fn baud(&self) -> u32 {
+        self.baud
+    }
+
+    /// Whether we're proposing or switching, or not at the default rate. The gateway pings every
+    /// KEEPALIVE_INTERVAL while this is true.
+    pub fn busy(&self) -> bool {
+        self.state != State::Stable || self.baud != DEFAULT_BAUD
+    }
+
+    /// Whether `baud` is a rate we can switch to.
+    pub fn supports(baud: u32) -> bool {
+        BAUDS.contains(&baud)
+    }
+
+    /// A frame from the other end decoded at `now`.
+    pub fn heard(&mut self, now: Duration) {
+        self.last_heard = now;
+    }
+
+    /// Propose `baud` at `now`. Returns false when we don't support it, already run at it, or are
+    /// in the middle of a negotiation.
+    pub fn propose(&mut self, baud: u32, now: Duration) -> bool {
+        if !Self::supports(baud) || baud == self.baud || self.state != State::Stable {
+            return false;
+        }
+        self.state = State::Proposed { baud, at: now };
+        true
+    }
+
+    /// The rate we're proposing, to send along with the next ping.
+    pub fn proposal(&self) -> Option<u32> {
+        match self.state {
+            State::Proposed { baud, .. } => Some(baud),
+            _ => None,
+        }
+    }
+
+    /// The other end accepted `baud` at `now`, which starts the countdown.
+    pub fn accepted(&mut self, baud: u32, now: Duration) {
+        if matches!(self.state, State::Proposed { baud: proposed, .. } if proposed == baud) {
+            self.state = State::Countdown {
+                baud,
+                switch_at: now + COUNTDOWN,
+                acked: false,
+            };
+        }
+    }
+
+    /// The countdown to send at `now`: the rate and the time until we switch. None once the
+    /// other end acknowledged it.
+    pub fn countdown(&self, now: Duration) -> Option<(u32, Duration)> {
+        match self.state {
+            State::Countdown {
+                baud,
+                switch_at,
+                acked: false,
+            } if switch_at > now => Some((baud, switch_at - now)),
+            _ => None,
+        }
+    }
+
+    /// The other end acknowledged the countdown to `baud`.
+    pub fn countdown_acked(&mut self, baud: u32) {
+        if let State::Countdown {
+            baud: switching,
+            acked,
+            ..
+        } = &mut self.state
+        {
+            if *switching == baud {
+                *acked = true;
+            }
+        }
+    }
+
+    /// The other end counts down to `baud`, switching `left` after `now`. Returns whether we go
+    /// along, which is what we acknowledge. A repeated countdown keeps the time of the first one.
+    pub fn count_down(&mut self, baud: u32, left: Duration, now: Duration) -> bool {
+        if !Self::supports(baud) {
+            return false;
+        }
+        match self.state {
+            State::Countdown {
+                baud: switching, ..
+            } if switching == baud => {}
+            _ if baud == self.baud => self.state = State::Stable,
+            _ => {
+                self.state = State::Countdown {
+                    baud,
+                    switch_at: now + left,
+                    acked: true,
+                }
+            }
+        }
+        true
+    }
+
+    /// Check at `now` whether the rate changes. The countdown might have run out, or the other
+    /// end went quiet.
+    pub fn poll(&mut self, now: Duration) -> Option<BaudChange> {
+        match self.state {
+            State::Countdown {
+                baud, switch_at, ..
+            } if now >= switch_at => {
+                self.baud = baud;
+                self.state = State::Stable;
+                // The other end gets SILENCE_TIMEOUT to show up at the new rate
+                self.last_heard = now;
+                return Some(BaudChange::Switched(baud));
+            }
+            State::Proposed { at, .. } if now.saturating_sub(at) > PROPOSAL_TIMEOUT => {
+                self.state = State::Stable;
+            }
+            _ => {}
+        }
+        if self.baud != DEFAULT_BAUD && now.saturating_sub(self.last_heard) > SILENCE_TIMEOUT {
+            let from = self.baud;
+            self.baud = DEFAULT_BAUD;
+            self.state = State::Stable;
+            self.last_heard = now;
+            return Some(BaudChange::FellBack { from });
+        }
+        None
+    }
+}
+
+/// What went over one direction of a link.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LinkCounts {
+    pub frames: u32,
+    // Frames that didn't decode, mostly CRC failures and broken base64
+    pub failed: u32,
+    // Hardware framing errors of the UART
+    pub framing_errors: u32,
+}
+
+impl LinkCounts {
+    /// Errors per frame, failed frames and framing errors alike. 0 without any frames.
+    pub fn error_rate(&self) -> f32 {
+        let errors = self.failed + self.framing_errors;
+        if errors == 0 {
+            0.0
+        } else {
+            errors as f32 / (self.frames + self.failed).max(1) as f32
+        }
+    }
+}
+
+impl std::ops::Add for LinkCounts {
+    type Output = LinkCounts;
+
+    fn add(self, other: LinkCounts) -> LinkCounts {
+        LinkCounts {
+            frames: self.frames.saturating_add(other.frames),
+            failed: self.failed.saturating_add(other.failed),
+            framing_errors: self.framing_errors.saturating_add(other.framing_errors),
+        }
+    }
+}
+
+impl fmt::Display for LinkCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "frames={} failed={} framing_errors={}",
+            self.frames, self.failed, self.framing_errors
+        )
+    }
+}
+
+/// Turns counters since boot into what changed since the last look.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LinkQuality {
+    last: LinkCounts,
+}
+
+impl LinkQuality {
+    /// What changed since the last call, from the counters since boot. When the counters went
+    /// down, the other end rebooted, and all of them are new.
+    pub fn sample(&mut self, totals: LinkCounts) -> LinkCounts {
+        let last = std::mem::replace(&mut self.last, totals);
+        let delta = |now: u32, last: u32| now.checked_sub(last).unwrap_or(now);
+        LinkCounts {
+            frames: delta(totals.frames, last.frames),
+            failed: delta(totals.failed, last.failed),
+            framing_errors: delta(totals.framing_errors, last.framing_errors),
+        }
+    }
+}
+
+/// When the gateway proposes another rate.
+#[derive(Clone, Copy, Debug)]
+pub struct BaudPolicy {
+    /// The slowest and fastest rate we go to, from BAUDS
+    pub min_baud: u32,
+    pub max_baud: u32,
+    /// How often we look at the link
+    pub check_interval: Duration,
+    /// Frames in both directions we need to have seen within `check_interval` to judge the link
+    pub min_frames: u32,
+    /// Go a rate down when the error rate is higher than this, and a rate up when there were no
+    /// errors at all
+    pub max_error_rate: f32,
+    /// Don't go back to a rate we fell back from for this long
+    pub hold_off: Duration,
+}
+
+impl BaudPolicy {
+    /// The rate to propose when the link at `baud` had `quality` in both directions, if any.
+    /// `blocked` is a rate we shouldn't go to.
+    pub fn next(&self, baud: u32, quality: LinkCounts, blocked: Option<u32>) -> Option<u32> {
+        if quality.frames + quality.failed < self.min_frames {
+            return None;
+        }
+        let index = BAUDS.iter().position(|&b| b == baud)?;
+        let next = if quality.error_rate() > self.max_error_rate {
+            BAUDS[..index].last()
+        } else if quality.failed + quality.framing_errors == 0 {
+            BAUDS.get(index + 1)
+        } else {
+            None
+        };
+        next.copied()
+            .filter(|&b| b >= self.min_baud && b <= self.max_baud && Some(b) != blocked)
+    }
+}
+
+/// Switch UART `port` to `baud`, after what was written to it went out.
+pub fn set_uart_baud(port: i32, baud: u32) -> Result<(), EspError> {
+    esp!(unsafe { esp_idf_sys::uart_wait_tx_done(port, 100) })?;
+    esp!(unsafe { esp_idf_sys::uart_set_baudrate(port, baud) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    enum Frame {
+        Ping,
+        Pong,
+    }
+
+    /// Both ends of a UART, exchanging a ping and a pong every 500 ms like the gateway and the
+    /// beacon do. Frames only get across when both ends run at the same rate, and when `lose`
+    /// doesn't lose them.
+    struct Pair {
+        gateway: BaudLink,
+        beacon: BaudLink,
+        lose: Box<dyn Fn(Duration, Frame) -> bool>,
+        changes: Vec<(&'static str, BaudChange, Duration)>,
+    }
+
+    impl Pair {
+        fn new(lose: impl Fn(Duration, Frame) -> bool + 'static) -> Self {
+            Self {
+                gateway: BaudLink::new(Duration::ZERO),
+                beacon: BaudLink::new(Duration::ZERO),
+                lose: Box::new(lose),
+                changes: Vec::new(),
+            }
+        }
+
+        fn deliver(&self, now: Duration, frame: Frame) -> bool {
+            self.gateway.baud() == self.beacon.baud() && !(self.lose)(now, frame)
+        }
+
+        fn exchange(&mut self, now: Duration) {
+            let proposal = self.gateway.proposal();
+            let countdown = self.gateway.countdown(now);
+            if !self.deliver(now, Frame::Ping) {
+                return;
+            }
+            self.beacon.heard(now);
+            let accept = proposal.filter(|&baud| BaudLink::supports(baud));
+            let ack = countdown
+                .filter(|&(baud, left)| self.beacon.count_down(baud, left, now))
+                .map(|(baud, _)| baud);
+            if !self.deliver(now, Frame::Pong) {
+                return;
+            }
+            self.gateway.heard(now);
+            if let Some(baud) = ack {
+                self.gateway.countdown_acked(baud);
+            }
+            if let Some(baud) = accept {
+                self.gateway.accepted(baud, now);
+            }
+        }
+
+        fn run(&mut self, from: Duration, until: Duration) {
+            let mut now = from;
+            while now <= until {
+                self.exchange(now);
+                if let Some(change) = self.gateway.poll(now) {
+                    self.changes.push(("gateway", change, now));
+                }
+                if let Some(change) = self.beacon.poll(now) {
+                    self.changes.push(("beacon", change, now));
+                }
+                now += ms(500);
+            }
+        }
+    }
+
+    #[test]
+    fn both_ends_switch_when_the_countdown_runs_out() {
+        let mut pair = Pair::new(|_, _| false);
+        assert!(pair.gateway.propose(230_400, Duration::ZERO));
+        pair.run(Duration::ZERO, ms(60_000));
+        assert_eq!(
+            pair.changes,
+            [
+                ("gateway", BaudChange::Switched(230_400), ms(5000)),
+                ("beacon", BaudChange::Switched(230_400), ms(5000)),
+            ]
+        );
+        assert!(pair.gateway.busy());
+        assert_eq!(pair.gateway.countdown(ms(60_000)), None);
+    }
+
+    #[test]
+    fn a_lost_accept_is_repeated() {
+        let mut pair = Pair::new(|now, frame| now == Duration::ZERO && frame == Frame::Pong);
+        pair.gateway.propose(57_600, Duration::ZERO);
+        pair.run(Duration::ZERO, ms(20_000));
+        assert_eq!(
+            pair.changes,
+            [
+                ("gateway", BaudChange::Switched(57_600), ms(5500)),
+                ("beacon", BaudChange::Switched(57_600), ms(5500)),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_lost_switch_ack_keeps_the_time_of_the_first_countdown() {
+        let mut pair = Pair::new(|now, frame| now == ms(500) && frame == Frame::Pong);
+        pair.gateway.propose(230_400, Duration::ZERO);
+        pair.run(Duration::ZERO, ms(500));
+        // The countdown goes out again, with the time that's left
+        assert_eq!(pair.gateway.countdown(ms(1000)), Some((230_400, ms(4000))));
+        pair.run(ms(1000), ms(20_000));
+        assert_eq!(
+            pair.changes,
+            [
+                ("gateway", BaudChange::Switched(230_400), ms(5000)),
+                ("beacon", BaudChange::Switched(230_400), ms(5000)),
+            ]
+        );
+    }
+
+    #[test]
+    fn an_end_that_switched_alone_falls_back() {
+        // The beacon never hears the countdown
+        let mut pair = Pair::new(|now, frame| now > Duration::ZERO && frame == Frame::Ping);
+        pair.gateway.propose(230_400, Duration::ZERO);
+        pair.run(Duration::ZERO, ms(30_000));
+        assert_eq!(
+            pair.changes,
+            [
+                ("gateway", BaudChange::Switched(230_400), ms(5000)),
+                (
+                    "gateway",
+                    BaudChange::FellBack { from: 230_400 },
+                    ms(15_500)
+                ),
+            ]
+        );
+        assert_eq!(pair.beacon.baud(), DEFAULT_BAUD);
+        assert!(!pair.gateway.busy());
+    }
+
+    #[test]
+    fn both_ends_fall_back_when_the_link_goes_quiet() {
+        let mut pair = Pair::new(|now, _| now >= ms(6000) && now < ms(40_000));
+        pair.gateway.propose(230_400, Duration::ZERO);
+        pair.run(Duration::ZERO, ms(60_000));
+        assert_eq!(
+            pair.changes,
+            [
+                ("gateway", BaudChange::Switched(230_400), ms(5000)),
+                ("beacon", BaudChange::Switched(230_400), ms(5000)),
+                (
+                    "gateway",
+                    BaudChange::FellBack { from: 230_400 },
+                    ms(16_000)
+                ),
+                ("beacon", BaudChange::FellBack { from: 230_400 }, ms(16_000)),
+            ]
+        );
+    }
+
+    #[test]
+    fn proposals_are_checked() {
+        let mut link = BaudLink::new(Duration::ZERO);
+        assert!(!link.propose(9600, Duration::ZERO));
+        assert!(!link.propose(DEFAULT_BAUD, Duration::ZERO));
+        assert!(link.propose(57_600, Duration::ZERO));
+        assert!(!link.propose(230_400, Duration::ZERO));
+        assert_eq!(link.proposal(), Some(57_600));
+        // Accepting another rate than we proposed does nothing
+        link.accepted(230_400, Duration::ZERO);
+        assert_eq!(link.proposal(), Some(57_600));
+    }
+
+    #[test]
+    fn an_unanswered_proposal_is_dropped() {
+        let mut link = BaudLink::new(Duration::ZERO);
+        link.propose(57_600, Duration::ZERO);
+        assert_eq!(link.poll(ms(5000)), None);
+        assert_eq!(link.proposal(), Some(57_600));
+        assert_eq!(link.poll(ms(5001)), None);
+        assert_eq!(link.proposal(), None);
+        assert!(!link.busy());
+    }
+
+    #[test]
+    fn a_countdown_to_the_current_rate_cancels_one() {
+        let mut link = BaudLink::new(Duration::ZERO);
+        assert!(!link.count_down(9600, ms(1000), Duration::ZERO));
+        assert!(link.count_down(57_600, ms(1000), Duration::ZERO));
+        assert!(link.busy());
+        assert!(link.count_down(DEFAULT_BAUD, ms(1000), Duration::ZERO));
+        assert!(!link.busy());
+        assert_eq!(link.poll(ms(2000)), None);
+    }
+
+    #[test]
+    fn error_rates() {
+        let counts = |frames, failed, framing_errors| LinkCounts {
+            frames,
+            failed,
+            framing_errors,
+        };
+        assert_eq!(counts(0, 0, 0).error_rate(), 0.0);
+        assert_eq!(counts(100, 0, 0).error_rate(), 0.0);
+        assert_eq!(counts(90, 10, 0).error_rate(), 0.1);
+        assert_eq!(counts(95, 5, 5).error_rate(), 0.1);
+        assert_eq!(counts(0, 0, 3).error_rate(), 3.0);
+        assert_eq!(
+            counts(u32::MAX, 1, 2) + counts(1, 2, 3),
+            counts(u32::MAX, 3, 5)
+        );
+        assert_eq!(
+            counts(1, 2, 3).to_string(),
+            "frames=1 failed=2 framing_errors=3"
+        );
+    }
+
+    #[test]
+    fn link_quality_is_what_changed_since_the_last_look() {
+        let counts = |frames, failed, framing_errors| LinkCounts {
+            frames,
+            failed,
+            framing_errors,
+        };
+        let mut quality = LinkQuality::default();
+        assert_eq!(quality.sample(counts(10, 1, 0)), counts(10, 1, 0));
+        assert_eq!(quality.sample(counts(15, 1, 2)), counts(5, 0, 2));
+        // The other end rebooted
+        assert_eq!(quality.sample(counts(3, 0, 0)), counts(3, 0, 0));
+    }
+
+    #[test]
+    fn the_policy_goes_down_on_errors_and_up_on_a_clean_link() {
+        let policy = BaudPolicy {
+            min_baud: 57_600,
+            max_baud: 230_400,
+            check_interval: Duration::from_secs(60),
+            min_frames: 10,
+            max_error_rate: 0.05,
+            hold_off: Duration::from_secs(600),
+        };
+        let counts = |frames, failed| LinkCounts {
+            frames,
+            failed,
+            framing_errors: 0,
+        };
+        assert_eq!(policy.next(DEFAULT_BAUD, counts(5, 0), None), None);
+        assert_eq!(
+            policy.next(DEFAULT_BAUD, counts(90, 10), None),
+            Some(57_600)
+        );
+        assert_eq!(policy.next(DEFAULT_BAUD, counts(99, 1), None), None);
+        assert_eq!(
+            policy.next(DEFAULT_BAUD, counts(100, 0), None),
+            Some(230_400)
+        );
+        assert_eq!(
+            policy.next(DEFAULT_BAUD, counts(100, 0), Some(230_400)),
+            None
+        );
+        assert_eq!(policy.next(230_400, counts(100, 0), None), None);
+        assert_eq!(policy.next(57_600, counts(90, 10), None), None);
+        let policy = BaudPolicy {
+            min_baud: DEFAULT_BAUD,
+            ..policy
+        };
+        assert_eq!(policy.next(DEFAULT_BAUD, counts(90, 10), None), None);
+    }
+}
@@ -0,0 +1,300 @@
+//! How many bytes our messages take on the air. ESP-NOW frames can't be larger than
+//! `ESP_NOW_MAX_FRAME_LEN`, so we build the largest version of every message we send, with the
+//! longest strings we actually use and every field set, and measure it. The tests fail when a
+//! proto change breaks the budget, and every device checks it again at startup, so it shows up
+//! right away instead of as frames that silently fail to send.
+
+use crate::comm::FrameFormat;
+use crate::messages::*;
+use crate::naming::MAX_NAME_LEN;
+use crate::profile::MAX_PROFILE_NAME_LEN;
+use crate::trace::RECORD_LEN;
+use crate::trace::TRACE_RECORDS_PER_FRAME;
+use anyhow::bail;
+use lazy_static::lazy_static;
+use prost::Message;
+
+pub const ESP_NOW_MAX_FRAME_LEN: usize = esp_idf_sys::ESP_NOW_MAX_DATA_LEN as usize;
+// Room we keep free in every frame, for fields we add later
+pub const FRAME_HEADROOM: usize = 16;
+/// Largest frame we allow ourselves to send.
+pub const FRAME_BUDGET: usize = ESP_NOW_MAX_FRAME_LEN - FRAME_HEADROOM;
+
+// Longest strings we send: MACs as formatted by `mac_to_string`, fix uids, NVS namespaces and
+// firmware versions
+const MAC_LEN: usize = 17;
+const UID_LEN: usize = 6;
+const NVS_NAMESPACE_LEN: usize = 15;
+const FIRMWARE_VERSION_LEN: usize = 16;
+
+lazy_static! {
+    /// Worst case frame length of every message we send, by name.
+    pub static ref WORST_CASE_FRAME_LENS: Vec<(&'static str, usize)> = worst_cases()
+        .into_iter()
+        .map(|(name, msg)| (name, frame_len(msg)))
+        .collect();
+
+    /// Longest frame any of our messages can take.
+    pub static ref MAX_FRAME_LEN: usize = WORST_CASE_FRAME_LENS
+        .iter()
+        .map(|&(_, len)| len)
+        .max()
+        .unwrap_or(0);
+
+    /// Largest chunk of a transfer that still fits in the frame budget.
+    pub static ref MAX_CHUNK_DATA_LEN: usize = (0..FRAME_BUDGET)
+        .rev()
+        .find(|&len| frame_len(morty_message::Msg::Chunk(worst_case_chunk(len))) <= FRAME_BUDGET)
+        .unwrap_or(0);
+}
+
+/// Check that all our messages fit in the frame budget.
+pub fn check_frame_budget() -> Result<(), anyhow::Error> {
+    let over: Vec<String> = WORST_CASE_FRAME_LENS
+        .iter()
+        .filter(|&&(_, len)| len > FRAME_BUDGET)
+        .map(|(name, len)| format!("{name} ({len} bytes)"))
+        .collect();
+    if !over.is_empty() {
+        bail!(
+            "Messages over the frame budget of {FRAME_BUDGET} bytes: {}",
+            over.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Length of `msg` as a frame with a header, which is longer than a legacy frame.
+fn frame_len(msg: morty_message::Msg) -> usize {
+    let encoded = MortyMessage { msg: Some(msg) }.encoded_len();
+    FrameFormat::Header.overhead() + encoded
+}
+
+fn worst_cases() -> Vec<(&'static str, morty_message::Msg)> {
+    let gps = worst_case_gps();
+    let beacon_present = BeaconPresentMsg {
+        timestamp: i64::MAX,
+        tx_power: -1.0,
+        channel: u32::MAX,
+        clock: Some(worst_case_clock()),
+        listen_period_ms: u32::MAX,
+        listen_window_ms: u32::MAX,
+        listen_offset_ms: u32::MAX,
+    };
+    let command_ack = CommandAckMsg {
+        nonce: u32::MAX,
+        ok: true,
+    };
+    let transfer_ack = TransferAckMsg {
+        transfer_id: u32::MAX,
+        received: u32::MAX,
+        count: u32::MAX,
+        done: true,
+        ok: true,
+    };
+    let power_event = PowerEventMsg {
+        on_battery: true,
+        battery_voltage: -1.0,
+    };
+    let hello = HelloMsg {
+        device_id: "x".repeat(MAC_LEN),
+        role: Role::GatewayCombo as i32,
+        firmware_version: "x".repeat(FIRMWARE_VERSION_LEN),
+        reset_reason: u32::MAX,
+        capabilities: u32::MAX,
+        config_version: u32::MAX,
+        name: "x".repeat(MAX_NAME_LEN),
+    };
+    let trace = TraceMsg {
+        transfer_id: u32::MAX,
+        index: u32::MAX,
+        count: u32::MAX,
+        records: vec![0xff; RECORD_LEN * TRACE_RECORDS_PER_FRAME],
+    };
+    let relay = |msg| RelayMsg {
+        src: "x".repeat(MAC_LEN),
+        timestamp: i64::MAX,
+        msg: Some(msg),
+        backfill: true,
+        path_delay_ms: u32::MAX,
+        // Only on the UART, see `comm::encode_relay`
+        gateway_hint: String::new(),
+    };
+
+    vec![
+        (
+            "beacon present",
+            morty_message::Msg::BeaconPresent(beacon_present.clone()),
+        ),
+        ("gps", morty_message::Msg::Gps(gps.clone())),
+        (
+            "relayed gps",
+            morty_message::Msg::Relay(relay(relay_msg::Msg::Gps(gps))),
+        ),
+        (
+            "relayed command ack",
+            morty_message::Msg::Relay(relay(relay_msg::Msg::CommandAck(command_ack.clone()))),
+        ),
+        (
+            "relayed transfer ack",
+            morty_message::Msg::Relay(relay(relay_msg::Msg::TransferAck(transfer_ack.clone()))),
+        ),
+        (
+            "relayed power event",
+            morty_message::Msg::Relay(relay(relay_msg::Msg::PowerEvent(power_event.clone()))),
+        ),
+        (
+            "relayed hello",
+            morty_message::Msg::Relay(relay(relay_msg::Msg::Hello(hello.clone()))),
+        ),
+        (
+            "relayed beacon present",
+            morty_message::Msg::Relay(relay(relay_msg::Msg::BeaconPresent(beacon_present))),
+        ),
+        (
+            "relayed trace",
+            morty_message::Msg::Relay(relay(relay_msg::Msg::Trace(trace.clone()))),
+        ),
+        (
+            "command",
+            morty_message::Msg::Command(CommandMsg {
+                target: "x".repeat(MAC_LEN),
+                command: Command::ClearNvsSection as i32,
+                nonce: u32::MAX,
+                timestamp: i64::MAX,
+                section: "x".repeat(NVS_NAMESPACE_LEN),
+                name: "x".repeat(MAX_NAME_LEN),
+            }),
+        ),
+        ("command ack", morty_message::Msg::CommandAck(command_ack)),
+        (
+            "chunk",
+            morty_message::Msg::Chunk(worst_case_chunk(*MAX_CHUNK_DATA_LEN)),
+        ),
+        (
+            "transfer ack",
+            morty_message::Msg::TransferAck(transfer_ack),
+        ),
+        ("power event", morty_message::Msg::PowerEvent(power_event)),
+        ("trace", morty_message::Msg::Trace(trace)),
+        (
+            "ping",
+            morty_message::Msg::Ping(PingMsg {
+                nonce: u32::MAX,
+                propose_baud: u32::MAX,
+                switch_baud: u32::MAX,
+                switch_in_ms: u32::MAX,
+                gateway_id: "x".repeat(MAC_LEN),
+                paused: true,
+            }),
+        ),
+        (
+            "pong",
+            morty_message::Msg::Pong(PongMsg {
+                nonce: u32::MAX,
+                device_id: "x".repeat(MAC_LEN),
+                firmware_version: "x".repeat(FIRMWARE_VERSION_LEN),
+                uptime_ms: u64::MAX,
+                config_version: u32::MAX,
+                accept_baud: u32::MAX,
+                switch_ack_baud: u32::MAX,
+                uart_frames: u32::MAX,
+                uart_frames_failed: u32::MAX,
+                uart_framing_errors: u32::MAX,
+                clock: Some(worst_case_clock()),
+                paused: true,
+            }),
+        ),
+        ("hello", morty_message::Msg::Hello(hello)),
+        (
+            "time beacon",
+            morty_message::Msg::TimeBeacon(TimeBeaconMsg {
+                time_ms: i64::MAX,
+                hops: u32::MAX,
+                holdoff_ms: u32::MAX,
+            }),
+        ),
+    ]
+}
+
+fn worst_case_gps() -> GpsMsg {
+    GpsMsg {
+        utc: i32::MAX,
+        fix_epoch: i64::MAX,
+        sent_epoch: i64::MAX,
+        latitude: -1.0,
+        longitude: -1.0,
+        fix_quality: i32::MAX,
+        satellites: i32::MAX,
+        hdop: -1.0,
+        uid: "x".repeat(UID_LEN),
+        charging: true,
+        battery_voltage: -1.0,
+        tx_power: -1.0,
+        boot_id: u32::MAX,
+        seq: u32::MAX,
+        gps_fault_suspected: true,
+        profile: "x".repeat(MAX_PROFILE_NAME_LEN),
+        config_version: u32::MAX,
+        clock: Some(worst_case_clock()),
+        battery_percent: u32::MAX,
+        gps_state: GpsState::Fix3d as i32,
+        name: "x".repeat(MAX_NAME_LEN),
+    }
+}
+
+fn worst_case_clock() -> ClockStatusMsg {
+    ClockStatusMsg {
+        source: TimeSource::Beacon as i32,
+        offset_ms: i32::MIN,
+        hops: u32::MAX,
+        steps: u32::MAX,
+    }
+}
+
+fn worst_case_chunk(data_len: usize) -> ChunkMsg {
+    ChunkMsg {
+        target: "x".repeat(MAC_LEN),
+        transfer_id: u32::MAX,
+        index: u32::MAX,
+        count: u32::MAX,
+        data: vec![0xff; data_len],
+        valid_until: i64::MAX,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::comm::encode_msg;
+
+    #[test]
+    fn every_message_fits_in_the_frame_budget() {
+        for &(name, len) in WORST_CASE_FRAME_LENS.iter() {
+            assert!(
+                len <= FRAME_BUDGET,
+                "{name} takes {len} bytes, over the budget of {FRAME_BUDGET}"
+            );
+        }
+        assert!(*MAX_FRAME_LEN <= FRAME_BUDGET);
+        assert!(check_frame_budget().is_ok());
+    }
+
+    #[test]
+    fn encoded_frames_are_as_long_as_measured() {
+        for ((name, msg), &(_, len)) in worst_cases().iter().zip(WORST_CASE_FRAME_LENS.iter()) {
+            let frame = encode_msg(msg).unwrap();
+            // We send legacy frames, which are shorter than the ones with a header we measure
+            assert!(frame.len() <= len, "{name} takes {} bytes", frame.len());
+        }
+    }
+
+    #[test]
+    fn chunks_fill_the_budget() {
+        assert!(*MAX_CHUNK_DATA_LEN > 0);
+        let fits = worst_case_chunk(*MAX_CHUNK_DATA_LEN);
+        let over = worst_case_chunk(*MAX_CHUNK_DATA_LEN + 1);
+        assert!(frame_len(morty_message::Msg::Chunk(fits)) <= FRAME_BUDGET);
+        assert!(frame_len(morty_message::Msg::Chunk(over)) > FRAME_BUDGET);
+    }
+}
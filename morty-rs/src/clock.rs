@@ -0,0 +1,175 @@
+use crate::messages::ClockStatusMsg;
+use crate::messages::TimeSource;
+use crate::timesync::Correction;
+use anyhow::bail;
+use esp_idf_svc::systime::EspSystemTime;
+use esp_idf_svc::timer::EspTimerService;
+use esp_idf_sys::EspError;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Set once the system time has been synced, e.g. through SNTP
+static WALL_CLOCK_VALID: AtomicBool = AtomicBool::new(false);
+// What's reported about the system time once it's disciplined with time beacons
+static CLOCK_STATUS: Mutex<Option<ClockStatusMsg>> = Mutex::new(None);
+
+/// Mark the wall clock as valid. Before this, the system time starts at the epoch.
+pub fn mark_wall_clock_valid() {
+    WALL_CLOCK_VALID.store(true, Ordering::SeqCst);
+}
+
+/// Where the system time came from, and how far off it was when it was last disciplined.
+pub fn clock_status() -> ClockStatusMsg {
+    CLOCK_STATUS.lock().unwrap().clone().unwrap_or_else(|| {
+        // Only SNTP marks the wall clock valid without a status
+        let source = if WALL_CLOCK_VALID.load(Ordering::SeqCst) {
+            TimeSource::Sntp
+        } else {
+            TimeSource::None
+        };
+        ClockStatusMsg {
+            source: source as i32,
+            ..Default::default()
+        }
+    })
+}
+
+/// Set what's reported about the system time, see `timesync::Discipline::status`.
+pub fn set_clock_status(status: ClockStatusMsg) {
+    *CLOCK_STATUS.lock().unwrap() = Some(status);
+}
+
+/// Apply a correction to the system time, and mark the wall clock valid.
+pub fn correct_system_time(correction: Correction) -> Result<(), anyhow::Error> {
+    let result = match correction {
+        Correction::Set(offset_ms) | Correction::Step(offset_ms) => {
+            let now_us = EspSystemTime.now().as_micros() as i64 + offset_ms * 1000;
+            let tv = esp_idf_sys::timeval {
+                tv_sec: now_us.div_euclid(1_000_000) as _,
+                tv_usec: now_us.rem_euclid(1_000_000) as _,
+            };
+            unsafe { esp_idf_sys::settimeofday(&tv, std::ptr::null()) }
+        }
+        Correction::Slew(offset_ms) => {
+            let delta = esp_idf_sys::timeval {
+                tv_sec: (offset_ms / 1000) as _,
+                tv_usec: (offset_ms % 1000 * 1000) as _,
+            };
+            unsafe { esp_idf_sys::adjtime(&delta, std::ptr::null_mut()) }
+        }
+        Correction::Ignore => return Ok(()),
+    };
+    if result != 0 {
+        bail!("Unable to apply {correction:?} to the system time");
+    }
+    mark_wall_clock_valid();
+    Ok(())
+}
+
+/// Gives access to both the monotonic clock, which counts from boot and can always be trusted,
+/// and the wall clock, which is only valid once it has been synced.
+pub struct Clock {
+    timer_service: EspTimerService<esp_idf_svc::timer::Task>,
+}
+
+impl Clock {
+    pub fn new() -> Result<Self, EspError> {
+        Ok(Self {
+            timer_service: EspTimerService::new()?,
+        })
+    }
+
+    /// Time since boot.
+    pub fn monotonic(&self) -> Duration {
+        self.timer_service.now()
+    }
+
+    /// Whether the wall clock has been synced.
+    pub fn is_valid(&self) -> bool {
+        WALL_CLOCK_VALID.load(Ordering::SeqCst)
+    }
+
+    /// Time since the epoch, if the wall clock is valid.
+    pub fn wall(&self) -> Option<Duration> {
+        self.is_valid().then(|| EspSystemTime.now())
+    }
+
+    /// Translate a monotonic timestamp to wall clock time, if the wall clock is valid. This also
+    /// works for timestamps taken before the wall clock was synced.
+    pub fn to_wall(&self, monotonic: Duration) -> Option<Duration> {
+        let now_monotonic = self.monotonic();
+        self.wall()
+            .map(|now_wall| monotonic_to_wall(monotonic, now_monotonic, now_wall))
+    }
+}
+
+/// Translate `monotonic` to wall clock time, given the current monotonic and wall clock time.
+/// Timestamps from the future are treated as now and timestamps from before the epoch saturate
+/// at the epoch.
+pub fn monotonic_to_wall(
+    monotonic: Duration,
+    now_monotonic: Duration,
+    now_wall: Duration,
+) -> Duration {
+    now_wall.saturating_sub(now_monotonic.saturating_sub(monotonic))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn monotonic_times_translate_to_the_wall_clock() {
+        let now_wall = secs(1_700_000_000);
+        assert_eq!(monotonic_to_wall(secs(100), secs(100), now_wall), now_wall);
+        assert_eq!(
+            monotonic_to_wall(secs(40), secs(100), now_wall),
+            secs(1_699_999_940)
+        );
+        // Taken before the wall clock was synced
+        assert_eq!(
+            monotonic_to_wall(Duration::ZERO, secs(100), now_wall),
+            secs(1_699_999_900)
+        );
+        assert_eq!(
+            monotonic_to_wall(Duration::from_millis(99_500), secs(100), now_wall),
+            Duration::from_millis(1_699_999_999_500)
+        );
+    }
+
+    #[test]
+    fn monotonic_times_saturate() {
+        // From the future is now
+        assert_eq!(
+            monotonic_to_wall(secs(200), secs(100), secs(1000)),
+            secs(1000)
+        );
+        // From before the epoch is the epoch, e.g. with a wall clock that was never synced
+        assert_eq!(
+            monotonic_to_wall(secs(10), secs(100), secs(30)),
+            Duration::ZERO
+        );
+        assert_eq!(
+            monotonic_to_wall(secs(0), secs(100), secs(0)),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn the_clock_status_follows_what_set_the_time() {
+        assert_eq!(clock_status().source, TimeSource::None as i32);
+        mark_wall_clock_valid();
+        assert_eq!(clock_status().source, TimeSource::Sntp as i32);
+        set_clock_status(ClockStatusMsg {
+            source: TimeSource::Beacon as i32,
+            ..Default::default()
+        });
+        assert_eq!(clock_status().source, TimeSource::Beacon as i32);
+    }
+}
@@ -1,87 +1,762 @@
-use std::{net::Ipv4Addr, time::Duration};
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+    sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
+    sync::Arc,
+    time::Duration,
+};
 
+use crate::budget::ESP_NOW_MAX_FRAME_LEN;
 use crate::messages::{morty_message, MortyMessage};
-use anyhow::{anyhow, bail};
-use crc8::Crc8;
+use crate::utils::uptime;
+use crate::wifi;
+use crate::wire;
+use crate::wire::encode_relay_into;
+use crate::wire::reframe_into;
+use crate::wire::reframe_relay_into;
+use crate::wire::WireError;
 use embedded_svc::wifi::ClientConfiguration;
 use embedded_svc::wifi::Configuration;
 use esp_idf_svc::{
     espnow::{EspNow, PeerInfo, BROADCAST},
     eventloop::EspSystemEventLoop,
-    netif::{EspNetif, EspNetifWait},
-    wifi::{EspWifi, WifiWait},
+    wifi::EspWifi,
 };
+use esp_idf_sys::{esp, EspError};
 use log::*;
 use prost::Message;
 
+// The frame layout lives in `wire`, which doesn't need std
+pub use crate::wire::{
+    peek_type, CrcVariant, DecodeFailure, FrameFlags, FrameFormat, FrameHeader,
+    BEACON_PRESENT_TYPE, CHECKSUM, CHUNK_TYPE, COMMAND_ACK_TYPE, COMMAND_TYPE, FRAME_HEADER_LEN,
+    FRAME_MAGIC, FRAME_VERSION, GPS_TYPE, HELLO_TYPE, PING_TYPE, PONG_TYPE, POWER_EVENT_TYPE,
+    RELAY_TYPE, TIME_BEACON_TYPE, TRACE_TYPE, TRANSFER_ACK_TYPE,
+};
+
 pub const ESP_NOW_CHANNEL: u8 = 1;
 
+// Lines sent from the beacon to the gateway over UART start with this header, followed by one or
+// more base64 encoded frames separated by the delimiter.
+pub const UART_HEADER: &str = "MORTYGPS";
+pub const UART_FRAME_DELIMITER: char = ',';
+
+// Range supported by esp_wifi_set_max_tx_power, which works in steps of 0.25 dBm
+pub const MIN_TX_POWER_DBM: f32 = 2.0;
+pub const MAX_TX_POWER_DBM: f32 = 20.0;
+
+// How long the wifi gets to start, connect and get a DHCP lease
+const WIFI_CONNECT_TIMEOUT: Duration = Duration::from_secs(40);
+
+// Room for the header, the CRC and the fields of a RelayMsg besides the source and the hint
+const RELAY_OVERHEAD: usize = 48;
+
+/// An ESP-NOW peer that we talk to with encryption, together with its local master key.
+#[derive(Clone, Copy, Debug)]
+pub struct EncryptedPeer {
+    pub mac: [u8; 6],
+    pub lmk: [u8; 16],
+}
+
+/// How ESP-NOW traffic is protected.
+///
+/// ESP-NOW only encrypts unicast frames to registered peers, and the receive callback doesn't say
+/// whether a frame was encrypted. That's why received frames are accepted or rejected based on
+/// who sent them. The broadcast peer is always registered, so beacon present messages still go
+/// out in plaintext.
+#[derive(Clone, Copy, Debug)]
+pub enum Encryption {
+    /// Nothing is encrypted and every frame is accepted.
+    Off,
+    /// Encrypted peers are registered next to the plaintext broadcast peer, and frames from
+    /// other nodes still fall back to plaintext. This keeps nodes with older firmware working
+    /// while encryption is rolled out, but anybody in range can still inject frames, so the
+    /// fleet isn't any safer than with `Off` yet. Switch to `Required` once every node has its
+    /// keys.
+    Transitional {
+        pmk: [u8; 16],
+        peers: &'static [EncryptedPeer],
+    },
+    /// Only frames from encrypted peers are accepted.
+    Required {
+        pmk: [u8; 16],
+        peers: &'static [EncryptedPeer],
+    },
+}
+
+impl Encryption {
+    fn keys(&self) -> Option<(&[u8; 16], &'static [EncryptedPeer])> {
+        match self {
+            Encryption::Off => None,
+            Encryption::Transitional { pmk, peers } | Encryption::Required { pmk, peers } => {
+                Some((pmk, peers))
+            }
+        }
+    }
+
+    /// Whether `mac` is one of our encrypted peers.
+    pub fn is_encrypted_peer(&self, mac: &[u8]) -> bool {
+        self.keys()
+            .map_or(false, |(_, peers)| peers.iter().any(|p| p.mac == mac))
+    }
+
+    /// Whether a frame from `mac` should be handled at all.
+    pub fn accepts(&self, mac: &[u8]) -> bool {
+        match self {
+            Encryption::Off | Encryption::Transitional { .. } => true,
+            Encryption::Required { .. } => self.is_encrypted_peer(mac),
+        }
+    }
+}
+
 pub fn esp_now_init() -> EspNow {
-    let esp_now = EspNow::take().unwrap();
+    esp_now_init_with_encryption(&Encryption::Off).unwrap()
+}
 
+/// Take ESP-NOW and register the broadcast peer, plus the encrypted peers when encryption is on.
+pub fn esp_now_init_with_encryption(encryption: &Encryption) -> Result<EspNow, EspError> {
+    let esp_now = EspNow::take()?;
+    add_peers(&esp_now, encryption)?;
+    Ok(esp_now)
+}
+
+/// Take ESP-NOW next to a wifi connection. ESP-NOW then has to use the channel of the access
+/// point, so devices that want to talk to us have to be on that channel as well. The access
+/// point doesn't speak long range mode, so it can't be enabled on the station interface, and
+/// devices in long range mode won't hear us. Returns the channel.
+pub fn esp_now_init_on_sta_channel() -> Result<(EspNow, u8), CommError> {
+    let mut protocol = 0u8;
+    esp!(unsafe {
+        esp_idf_sys::esp_wifi_get_protocol(esp_idf_sys::wifi_interface_t_WIFI_IF_STA, &mut protocol)
+    })
+    .map_err(CommError::Wifi)?;
+    if protocol as u32 & esp_idf_sys::WIFI_PROTOCOL_LR != 0 {
+        return Err(CommError::LongRange);
+    }
+
+    let mut channel = 0u8;
+    let mut second = esp_idf_sys::wifi_second_chan_t_WIFI_SECOND_CHAN_NONE;
+    esp!(unsafe { esp_idf_sys::esp_wifi_get_channel(&mut channel, &mut second) })
+        .map_err(CommError::Wifi)?;
+
+    let esp_now = EspNow::take().map_err(CommError::EspNow)?;
     esp_now
         .add_peer(PeerInfo {
             peer_addr: BROADCAST,
-            channel: ESP_NOW_CHANNEL,
+            channel,
             ifidx: 0,
             encrypt: false,
             ..Default::default()
         })
-        .unwrap();
-    esp_now
+        .map_err(CommError::EspNow)?;
+    Ok((esp_now, channel))
 }
 
-pub fn get_message_type(msg: &Option<morty_message::Msg>) -> u8 {
+fn add_peers(esp_now: &EspNow, encryption: &Encryption) -> Result<(), EspError> {
+    add_broadcast_peer(esp_now)?;
+    if let Some((pmk, peers)) = encryption.keys() {
+        esp!(unsafe { esp_idf_sys::esp_now_set_pmk(pmk.as_ptr()) })?;
+        for peer in peers {
+            esp_now.add_peer(PeerInfo {
+                peer_addr: peer.mac,
+                lmk: peer.lmk,
+                channel: ESP_NOW_CHANNEL,
+                ifidx: 0,
+                encrypt: true,
+                ..Default::default()
+            })?;
+        }
+        info!("Registered {} encrypted ESP-NOW peers", peers.len());
+    }
+    Ok(())
+}
+
+fn add_broadcast_peer(esp_now: &EspNow) -> Result<(), EspError> {
+    esp_now.add_peer(PeerInfo {
+        peer_addr: BROADCAST,
+        channel: ESP_NOW_CHANNEL,
+        ifidx: 0,
+        encrypt: false,
+        ..Default::default()
+    })
+}
+
+/// Tear down ESP-NOW and the wifi driver underneath it and bring them back up again. This is a
+/// last resort for when the radio got wedged. Callbacks are lost in the process, so they need to
+/// be registered again on `esp_now` afterwards.
+pub fn esp_now_reinit(esp_now: &EspNow, encryption: &Encryption) -> Result<(), EspError> {
+    warn!("Reinitializing ESP-NOW");
+    esp!(unsafe { esp_idf_sys::esp_now_deinit() })?;
+    esp!(unsafe { esp_idf_sys::esp_wifi_stop() })?;
+    esp!(unsafe { esp_idf_sys::esp_wifi_start() })?;
+    esp!(unsafe { esp_idf_sys::esp_now_init() })?;
+    add_peers(esp_now, encryption)
+}
+
+/// Set the maximum TX power of the radio, clamped to what the chip supports. Wifi has to be
+/// started for this to work. The chip rounds the value to a level it supports, so we return the
+/// power that is actually in use.
+pub fn set_tx_power_dbm(dbm: f32) -> Result<f32, EspError> {
+    let quarter_dbm = (dbm.clamp(MIN_TX_POWER_DBM, MAX_TX_POWER_DBM) * 4.0).round() as i8;
+    esp!(unsafe { esp_idf_sys::esp_wifi_set_max_tx_power(quarter_dbm) })?;
+    let power = tx_power_dbm()?;
+    info!("TX power set to {power} dBm (requested {dbm} dBm)");
+    Ok(power)
+}
+
+/// The maximum TX power of the radio in dBm.
+pub fn tx_power_dbm() -> Result<f32, EspError> {
+    let mut quarter_dbm: i8 = 0;
+    esp!(unsafe { esp_idf_sys::esp_wifi_get_max_tx_power(&mut quarter_dbm) })?;
+    Ok(quarter_dbm as f32 / 4.0)
+}
+
+static SEND_FRAME_HEADER: AtomicBool = AtomicBool::new(false);
+
+/// Set the layout of the frames we send, `FrameFormat::Legacy` until this is called.
+pub fn set_frame_format(format: FrameFormat) {
+    SEND_FRAME_HEADER.store(format == FrameFormat::Header, Ordering::SeqCst);
+}
+
+fn frame_format() -> FrameFormat {
+    if SEND_FRAME_HEADER.load(Ordering::SeqCst) {
+        FrameFormat::Header
+    } else {
+        FrameFormat::Legacy
+    }
+}
+
+/// The type of `msg` in the frame. This is the field number of the message in MortyMessage.
+pub fn get_message_type(msg: &morty_message::Msg) -> u8 {
     match msg {
-        Some(morty_message::Msg::BeaconPresent(_)) => 1,
-        Some(morty_message::Msg::Gps(_)) => 2,
-        Some(morty_message::Msg::Relay(_)) => 3,
-        None => 0,
+        morty_message::Msg::BeaconPresent(_) => BEACON_PRESENT_TYPE,
+        morty_message::Msg::Gps(_) => GPS_TYPE,
+        morty_message::Msg::Relay(_) => RELAY_TYPE,
+        morty_message::Msg::Command(_) => COMMAND_TYPE,
+        morty_message::Msg::CommandAck(_) => COMMAND_ACK_TYPE,
+        morty_message::Msg::Chunk(_) => CHUNK_TYPE,
+        morty_message::Msg::TransferAck(_) => TRANSFER_ACK_TYPE,
+        morty_message::Msg::PowerEvent(_) => POWER_EVENT_TYPE,
+        morty_message::Msg::Ping(_) => PING_TYPE,
+        morty_message::Msg::Pong(_) => PONG_TYPE,
+        morty_message::Msg::Hello(_) => HELLO_TYPE,
+        morty_message::Msg::TimeBeacon(_) => TIME_BEACON_TYPE,
+        morty_message::Msg::Trace(_) => TRACE_TYPE,
     }
 }
 
-pub fn broadcast_msg(msg: &morty_message::Msg, esp_now: &EspNow) -> Result<(), anyhow::Error> {
-    info!("Broadcasting message: {:?}", msg);
-    let data = encode_msg(msg);
-    broadcast_data(&data, esp_now)
+/// How important an outgoing message is. ESP-NOW broadcasts aren't acknowledged, so more
+/// important messages are simply broadcast more often to make it more likely they arrive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Routine,
+    High,
+}
+
+impl Priority {
+    pub fn broadcast_count(&self) -> u8 {
+        match self {
+            Priority::Routine => 1,
+            Priority::High => 3,
+        }
+    }
+}
+
+// Time between repeated broadcasts of the same message
+const REPEAT_INTERVAL: Duration = Duration::from_millis(20);
+
+pub fn broadcast_msg(
+    msg: &morty_message::Msg,
+    priority: Priority,
+    esp_now: &EspNow,
+) -> Result<(), CommError> {
+    info!("Broadcasting {:?} message: {:?}", priority, msg);
+    let mut frame = [0; ESP_NOW_MAX_FRAME_LEN];
+    let len = encode_msg_into(msg, &mut frame)?;
+    broadcast_data(&frame[..len], priority, esp_now)
 }
 
-pub fn broadcast_data(data: &Vec<u8>, esp_now: &EspNow) -> Result<(), anyhow::Error> {
-    esp_now.send(BROADCAST, data.as_slice())?;
+pub fn broadcast_data(data: &[u8], priority: Priority, esp_now: &EspNow) -> Result<(), CommError> {
+    for i in 0..priority.broadcast_count() {
+        if i > 0 {
+            std::thread::sleep(REPEAT_INTERVAL);
+        }
+        esp_now.send(BROADCAST, data).map_err(CommError::EspNow)?;
+    }
     Ok(())
 }
 
-pub fn encode_msg(msg: &morty_message::Msg) -> Vec<u8> {
-    let morty_message = MortyMessage {
-        msg: Some(msg.clone()),
-    };
+/// Broadcast a `frame` we received, e.g. a command the gateway sent for a tracker. The message
+/// isn't decoded and encoded again, only the header is rewritten to the layout we send.
+pub fn broadcast_encoded(
+    frame: &[u8],
+    priority: Priority,
+    esp_now: &EspNow,
+) -> Result<(), CommError> {
+    broadcast_data(&reframe(frame)?, priority, esp_now)
+}
+
+/// `encode_msg_into` into a Vec.
+pub fn encode_msg(msg: &morty_message::Msg) -> Result<Vec<u8>, CommError> {
+    let format = frame_format();
+    let mut frame = vec![0; format.overhead() + msg.encoded_len()];
+    let len = encode_msg_with(msg, format, &mut frame)?;
+    frame.truncate(len);
+    Ok(frame)
+}
+
+/// Write the frame of `msg` to `buf`, in the layout we send, without allocating. Returns the
+/// length of the frame, or an error when it doesn't fit.
+pub fn encode_msg_into(msg: &morty_message::Msg, buf: &mut [u8]) -> Result<usize, CommError> {
+    encode_msg_with(msg, frame_format(), buf)
+}
+
+fn encode_msg_with(
+    msg: &morty_message::Msg,
+    format: FrameFormat,
+    buf: &mut [u8],
+) -> Result<usize, CommError> {
+    // A MortyMessage only holds the oneof, so encoding the oneof by itself gives the same bytes
+    // without having to move `msg` into one
+    Ok(wire::encode_frame_with(
+        format,
+        get_message_type(msg),
+        msg.encoded_len(),
+        buf,
+        |mut out| msg.encode(&mut out),
+    )?)
+}
 
-    let msg_type = &[get_message_type(&morty_message.msg)];
-    let vec = morty_message.encode_to_vec();
-    let bytes = vec.as_slice();
+/// A received `frame` in the layout we send, with the message bytes as they were. Returns an
+/// error when the frame doesn't check out.
+pub fn reframe(frame: &[u8]) -> Result<Vec<u8>, CommError> {
+    let format = frame_format();
+    encode_vec(frame.len() + format.overhead(), |out| {
+        reframe_into(frame, format, out)
+    })
+}
 
-    let mut crc8 = Crc8::create_msb(0x07);
-    let crc = &[crc8.calc(bytes, bytes.len() as i32, 0)];
+/// Wrap the message of a received `frame` in a RelayMsg from `src`, without decoding it, see
+/// `wire::encode_relay_into`. Only fixes, acks, power events, hellos, beacon present messages and
+/// traces can be relayed. `gateway_hint` is only for what goes to the UART, empty leaves it out.
+pub fn encode_relay(
+    src: &str,
+    timestamp: i64,
+    path_delay_ms: u32,
+    gateway_hint: &str,
+    frame: &[u8],
+) -> Result<Vec<u8>, CommError> {
+    let format = frame_format();
+    let max_len = frame.len() + src.len() + gateway_hint.len() + RELAY_OVERHEAD;
+    encode_vec(max_len, |out| {
+        encode_relay_into(
+            src,
+            timestamp,
+            path_delay_ms,
+            gateway_hint,
+            frame,
+            format,
+            out,
+        )
+    })
+}
 
-    [msg_type, crc, bytes].concat()
+/// A received relay `frame` in the layout we send, with `path_delay_ms` as its path delay and
+/// `gateway_hint` as the gateway, empty for none. The other fields, including the relayed message,
+/// keep their bytes.
+pub fn reframe_relay(
+    frame: &[u8],
+    path_delay_ms: u32,
+    gateway_hint: &str,
+) -> Result<Vec<u8>, CommError> {
+    let format = frame_format();
+    let max_len = frame.len() + gateway_hint.len() + RELAY_OVERHEAD;
+    encode_vec(max_len, |out| {
+        reframe_relay_into(frame, path_delay_ms, gateway_hint, format, out)
+    })
 }
 
-pub fn decode_msg(data: &[u8]) -> Result<Option<morty_message::Msg>, anyhow::Error> {
-    let crc = data[1];
-    let msg_data = &data[2..];
+/// A frame written by `encode` to a buffer of `max_len` bytes.
+fn encode_vec(
+    max_len: usize,
+    encode: impl FnOnce(&mut [u8]) -> Result<usize, WireError>,
+) -> Result<Vec<u8>, CommError> {
+    let mut frame = vec![0; max_len];
+    let len = encode(&mut frame)?;
+    frame.truncate(len);
+    Ok(frame)
+}
 
-    let mut crc8 = Crc8::create_msb(0x07);
-    let calc_crc = crc8.calc(msg_data, msg_data.len() as i32, 0);
+/// What went wrong sending or receiving. Frames that didn't decode have a `cause`, so callers can
+/// tell e.g. a frame garbled on the air from one of firmware that sends something we don't know.
+/// Converts to an anyhow::Error like any other error.
+#[derive(Debug)]
+pub enum CommError {
+    /// The frame is too short for a type and a CRC, with its length
+    FrameTooShort(usize),
+    /// The CRC in the frame isn't the one of its message
+    CrcMismatch { expected: u16, actual: u16 },
+    /// The frame holds a message of another type than it says
+    TypeMismatch { frame: u8, message: u8 },
+    /// The message isn't valid protobuf
+    Decode(prost::DecodeError),
+    /// Any other frame that doesn't check out, or one that can't be built, see `WireError`
+    Wire(WireError),
+    /// ESP-NOW failed, e.g. it didn't take the frame
+    EspNow(EspError),
+    /// ESP-NOW next to wifi can't use long range mode, see `esp_now_init_on_sta_channel`
+    LongRange,
+    /// The wifi driver failed
+    Wifi(EspError),
+    /// There are no wifi networks to connect to
+    NoNetworks,
+    /// The wifi didn't come up in time, with the reason
+    WifiTimeout(String),
+}
 
-    if crc != calc_crc {
-        error!("Invalid CRC: {} != {}", crc, calc_crc);
-        return Err(anyhow!("Invalid CRC: {} != {}", crc, calc_crc));
+impl CommError {
+    /// Why the frame didn't decode, None for errors sending or connecting.
+    pub fn cause(&self) -> Option<DecodeFailure> {
+        match self {
+            CommError::FrameTooShort(_) => Some(DecodeFailure::Truncated),
+            CommError::CrcMismatch { .. } => Some(DecodeFailure::CrcMismatch),
+            CommError::TypeMismatch { .. } => Some(DecodeFailure::TypeMismatch),
+            CommError::Decode(_) => Some(DecodeFailure::ProtobufError),
+            CommError::Wire(e) => e.cause(),
+            CommError::EspNow(_)
+            | CommError::LongRange
+            | CommError::Wifi(_)
+            | CommError::NoNetworks
+            | CommError::WifiTimeout(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for CommError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommError::FrameTooShort(len) => write!(f, "Frame too short: {len} bytes")?,
+            CommError::CrcMismatch { expected, actual } => {
+                write!(f, "Invalid CRC: {expected} != {actual}")?
+            }
+            CommError::TypeMismatch { frame, message } => {
+                write!(f, "Frame of type {frame} holds a message of type {message}")?
+            }
+            CommError::Decode(e) => write!(f, "Invalid protobuf: {e}")?,
+            CommError::Wire(e) => write!(f, "{e}")?,
+            CommError::EspNow(e) => write!(f, "ESP-NOW failed: {e}")?,
+            CommError::LongRange => {
+                f.write_str("ESP-NOW next to wifi can't use long range mode")?
+            }
+            CommError::Wifi(e) => write!(f, "Wifi failed: {e}")?,
+            CommError::NoNetworks => f.write_str("No wifi networks to connect to")?,
+            CommError::WifiTimeout(reason) => write!(f, "Wifi did not come up: {reason}")?,
+        }
+        match self.cause() {
+            Some(cause) => write!(f, " ({cause})"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for CommError {}
+
+impl From<WireError> for CommError {
+    fn from(e: WireError) -> Self {
+        match e {
+            WireError::Crc { frame, message } => CommError::CrcMismatch {
+                expected: frame,
+                actual: message,
+            },
+            e => CommError::Wire(e),
+        }
+    }
+}
+
+/// The cause of a CommError that `e` holds. None for any other error.
+pub fn classify_decode_error(e: &anyhow::Error) -> Option<DecodeFailure> {
+    e.downcast_ref::<CommError>().and_then(CommError::cause)
+}
+
+// Needed to initialize the counter array in a const fn
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU32 = AtomicU32::new(0);
+
+/// Frames that didn't decode since boot, by cause.
+pub struct DecodeFailures {
+    counts: [AtomicU32; DecodeFailure::ALL.len()],
+}
+
+impl DecodeFailures {
+    pub const fn new() -> Self {
+        Self {
+            counts: [ZERO; DecodeFailure::ALL.len()],
+        }
+    }
+
+    /// Count `e` under its cause. Returns the cause, None when `e` isn't a decode error.
+    pub fn record(&self, e: &CommError) -> Option<DecodeFailure> {
+        let cause = e.cause()?;
+        self.counts[cause as usize].fetch_add(1, Ordering::Relaxed);
+        Some(cause)
+    }
+
+    pub fn get(&self, cause: DecodeFailure) -> u32 {
+        self.counts[cause as usize].load(Ordering::Relaxed)
+    }
+
+    /// Failures of all causes.
+    pub fn total(&self) -> u32 {
+        DecodeFailure::ALL
+            .iter()
+            .map(|&cause| self.get(cause))
+            .sum()
+    }
+}
+
+impl Default for DecodeFailures {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for DecodeFailures {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, cause) in DecodeFailure::ALL.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "decode_{cause}={}", self.get(*cause))?;
+        }
+        Ok(())
+    }
+}
+
+/// The message in a frame. A frame too short for a type and a CRC is an error, one without a
+/// message after its CRC decodes to None. The message must have the type the frame says it has,
+/// the CRC only covers the message, so a mix-up of frames can otherwise go unnoticed.
+pub fn decode_msg(data: &[u8]) -> Result<Option<morty_message::Msg>, CommError> {
+    if data.len() < 2 {
+        return Err(CommError::FrameTooShort(data.len()));
+    }
+    let (msg_type, msg_data) = split_frame(data)?;
+    if msg_data.is_empty() {
+        return Ok(None);
+    }
+    let msg = MortyMessage::decode(msg_data)
+        .map_err(CommError::Decode)?
+        .msg;
+    if let Some(msg) = &msg {
+        let actual = get_message_type(msg);
+        if actual != msg_type {
+            return Err(CommError::TypeMismatch {
+                frame: msg_type,
+                message: actual,
+            });
+        }
     }
-    let msg = MortyMessage::decode(msg_data)?.msg;
 
     Ok(msg)
 }
 
+/// The message type and the encoded MortyMessage of a frame, once its header and CRC check out.
+fn split_frame(data: &[u8]) -> Result<(u8, &[u8]), CommError> {
+    wire::split_frame(data).map_err(|e| {
+        if let WireError::Crc { .. } = e {
+            error!("{e}");
+        }
+        e.into()
+    })
+}
+
+/// A frame from the ESP-NOW receive callback, with the monotonic time it came in. It's copied out
+/// of the callback without allocating.
+#[derive(Clone, Debug)]
+pub struct RecvFrame {
+    pub src: [u8; 6],
+    pub data: heapless::Vec<u8, ESP_NOW_MAX_FRAME_LEN>,
+    pub received_at: Duration,
+}
+
+// A handler registered with the dispatcher
+#[derive(Clone)]
+struct Route {
+    name: &'static str,
+    // None for every frame, also the ones we can't tell the type of
+    types: Option<&'static [u8]>,
+    tx: SyncSender<RecvFrame>,
+    dropped: &'static AtomicU32,
+    // Set once the handler dropped its receiver, shared by the copies of the route
+    gone: Arc<AtomicBool>,
+}
+
+type RecvFilter = dyn Fn(&[u8], &[u8]) -> bool + Send + Sync;
+
+/// Owns the ESP-NOW receive callback, of which there can only be one, and hands the frames to the
+/// handlers that registered for their message type. Every handler gets a bounded channel of its
+/// own, so a handler that doesn't keep up loses frames, rather than stalling the wifi stack or
+/// the other handlers. A handler that drops its receiver gets no more frames.
+///
+/// The handlers are registered first and then frozen, see `freeze`, so the callback doesn't take
+/// a lock or allocate.
+#[derive(Default)]
+pub struct RecvDispatcher {
+    routes: Vec<Route>,
+    filter: Option<Arc<RecvFilter>>,
+}
+
+impl RecvDispatcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Run `filter` with the source and data of every frame before it's dispatched. Frames it
+    /// returns false for are dropped. This runs in the callback, so it has to be quick.
+    pub fn with_filter(
+        mut self,
+        filter: impl Fn(&[u8], &[u8]) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// Register a handler for frames of the message types in `types`, or for every frame when
+    /// None. Up to `capacity` frames wait for the handler, frames that don't fit are counted in
+    /// `dropped`.
+    pub fn register(
+        &mut self,
+        name: &'static str,
+        types: Option<&'static [u8]>,
+        capacity: usize,
+        dropped: &'static AtomicU32,
+    ) -> Receiver<RecvFrame> {
+        let (tx, rx) = sync_channel(capacity);
+        self.routes.push(Route {
+            name,
+            types,
+            tx,
+            dropped,
+            gone: Arc::new(AtomicBool::new(false)),
+        });
+        rx
+    }
+
+    /// The handlers as they are now, to attach to ESP-NOW.
+    pub fn freeze(self) -> FrozenDispatcher {
+        FrozenDispatcher {
+            routes: self.routes.into(),
+            filter: self.filter,
+        }
+    }
+}
+
+/// A `RecvDispatcher` of which the handlers can't change anymore. Every callback that's attached
+/// gets a copy of the routes rather than sharing them, a `SyncSender` can't be shared between
+/// threads.
+#[derive(Clone)]
+pub struct FrozenDispatcher {
+    routes: Box<[Route]>,
+    filter: Option<Arc<RecvFilter>>,
+}
+
+impl FrozenDispatcher {
+    /// Register the callback with ESP-NOW. This has to be done again when ESP-NOW was
+    /// reinitialized.
+    pub fn attach(&self, esp_now: &EspNow) -> Result<(), EspError> {
+        let dispatcher = self.clone();
+        esp_now.register_recv_cb(move |src: &[u8], data: &[u8]| {
+            dispatcher.dispatch(src, data, uptime())
+        })
+    }
+
+    /// The names of the handlers that dropped their receiver, for the log.
+    pub fn gone(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.routes
+            .iter()
+            .filter(|route| route.gone.load(Ordering::Relaxed))
+            .map(|route| route.name)
+    }
+
+    fn dispatch(&self, src: &[u8], data: &[u8], received_at: Duration) {
+        if let Some(filter) = &self.filter {
+            if !filter(src, data) {
+                return;
+            }
+        }
+        // ESP-NOW hands us a MAC and at most a full frame
+        let (Ok(src), Ok(data)) = (src.try_into(), heapless::Vec::from_slice(data)) else {
+            return;
+        };
+        let frame = RecvFrame {
+            src,
+            data,
+            received_at,
+        };
+        let msg_type = peek_type(&frame.data);
+        for route in self.routes.iter() {
+            let wanted = match (route.types, msg_type) {
+                (None, _) => true,
+                (Some(types), Some(msg_type)) => types.contains(&msg_type),
+                (Some(_), None) => false,
+            };
+            if !wanted || route.gone.load(Ordering::Relaxed) {
+                continue;
+            }
+            // Never block the wifi stack, nor log, which goes over the UART. The stats log has the
+            // counts, and `gone` the handlers that are gone.
+            match route.tx.try_send(frame.clone()) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    route.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(TrySendError::Disconnected(_)) => {
+                    route.gone.store(true, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
+
+/// The messages a beacon passed on last, by source and sequence number, so it can drop another
+/// copy of them. A tracker sends a report more than once, see `Priority`, and the same fix reaches
+/// a beacon directly as well as relayed by another beacon. Sequence numbers are the boot id and
+/// number `sequence::Sequence::next` hands out, so a reboot doesn't look like a copy. Messages
+/// without one, boot id 0, are never copies.
+///
+/// `seen` takes the `(boot_id, seq)` pair rather than the sequence number alone. A tracker counts
+/// from 0 again on every boot, so the number alone would make the first reports after a reboot
+/// look like copies of the ones before it.
+pub struct SeqDedup {
+    entries: VecDeque<(String, (u32, u32))>,
+    capacity: usize,
+}
+
+impl SeqDedup {
+    /// Remembers the last `capacity` messages.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Whether we've seen the message of `src` with sequence number `seq` before, and remember it.
+    pub fn seen(&mut self, src: &str, seq: (u32, u32)) -> bool {
+        if seq.0 == 0 {
+            return false;
+        }
+        if self.entries.iter().any(|(s, q)| *q == seq && s == src) {
+            return true;
+        }
+        if self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((src.to_string(), seq));
+        false
+    }
+}
+
 pub fn mac_to_string(mac: &[u8]) -> String {
     let mut mac_str = String::new();
     for i in 0..mac.len() {
@@ -93,34 +768,370 @@ pub fn mac_to_string(mac: &[u8]) -> String {
     mac_str
 }
 
+/// Parse a MAC address in the format of `mac_to_string`.
+pub fn parse_mac(s: &str) -> Option<[u8; 6]> {
+    let mut mac = [0u8; 6];
+    let mut parts = s.split(':');
+    for b in mac.iter_mut() {
+        *b = u8::from_str_radix(parts.next()?, 16).ok()?;
+    }
+    parts.next().is_none().then_some(mac)
+}
+
+/// MAC address of this device, in the same format as the sources of received frames.
+pub fn own_mac() -> Result<String, EspError> {
+    let mut mac = [0u8; 6];
+    esp!(unsafe {
+        esp_idf_sys::esp_read_mac(
+            mac.as_mut_ptr(),
+            esp_idf_sys::esp_mac_type_t_ESP_MAC_WIFI_STA,
+        )
+    })?;
+    Ok(mac_to_string(&mac))
+}
+
 pub fn start_wifi(
     modem: esp_idf_hal::modem::Modem,
     sysloop: EspSystemEventLoop,
     ssid: &str,
     password: &str,
-) -> Result<Box<EspWifi<'static>>, anyhow::Error> {
-    let mut wifi = Box::new(EspWifi::new(modem, sysloop.clone(), None)?);
+) -> Result<Box<EspWifi<'static>>, CommError> {
+    start_wifi_networks(
+        modem,
+        sysloop,
+        vec![wifi::Network::new(ssid, password)],
+        u32::MAX,
+    )
+}
+
+/// Start the wifi with `networks` in order of preference, which can't be empty. We move on to the
+/// next one after `max_failures` failed attempts in a row, see `wifi::Failover`, so the first
+/// connection gets time for every network.
+pub fn start_wifi_networks(
+    modem: esp_idf_hal::modem::Modem,
+    sysloop: EspSystemEventLoop,
+    networks: Vec<wifi::Network>,
+    max_failures: u32,
+) -> Result<Box<EspWifi<'static>>, CommError> {
+    if networks.is_empty() {
+        return Err(CommError::NoNetworks);
+    }
+    let timeout = WIFI_CONNECT_TIMEOUT * networks.len() as u32;
+    let first = wifi::set_networks(networks, max_failures);
+    let mut wifi = Box::new(EspWifi::new(modem, sysloop, None).map_err(CommError::Wifi)?);
     wifi.set_configuration(&Configuration::Client(ClientConfiguration {
-        ssid: ssid.into(),
-        password: password.into(),
+        ssid: first.ssid.as_str().into(),
+        password: first.password.as_str().into(),
         ..Default::default()
-    }))?;
-    wifi.start()?;
-    if !WifiWait::new(&sysloop)?
-        .wait_with_timeout(Duration::from_secs(20), || wifi.is_started().unwrap())
-    {
-        bail!("Wifi did not start");
-    }
-    wifi.connect()?;
-    if !EspNetifWait::new::<EspNetif>(wifi.sta_netif(), &sysloop)?.wait_with_timeout(
-        Duration::from_secs(20),
-        || {
-            wifi.is_up().unwrap()
-                && wifi.sta_netif().get_ip_info().unwrap().ip != Ipv4Addr::new(0, 0, 0, 0)
-        },
-    ) {
-        bail!("Wifi did not connect or did not receive a DHCP lease");
-    }
+    }))
+    .map_err(CommError::Wifi)?;
+    // The connection is driven by the events from here on, including reconnecting after it
+    // drops, see `wifi`
+    wifi::watch().map_err(CommError::Wifi)?;
+    wifi.start().map_err(CommError::Wifi)?;
+    wifi::wait_up(timeout).map_err(CommError::WifiTimeout)?;
 
     Ok(wifi)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hello::CAP_GPS;
+    use crate::hello::CAP_OTA;
+    use crate::messages::relay_msg;
+    use crate::messages::HelloMsg;
+    use crate::messages::RelayMsg;
+    use crate::messages::Role;
+
+    #[test]
+    fn seq_dedup_remembers_messages() {
+        let mut dedup = SeqDedup::new(4);
+        assert!(!dedup.seen("a", (7, 1)));
+        assert!(dedup.seen("a", (7, 1)));
+        assert!(!dedup.seen("a", (7, 2)));
+        // Same number after a reboot
+        assert!(!dedup.seen("a", (8, 1)));
+        assert!(dedup.seen("a", (8, 1)));
+    }
+
+    #[test]
+    fn seq_dedup_keeps_sources_apart() {
+        let mut dedup = SeqDedup::new(4);
+        assert!(!dedup.seen("a", (7, 1)));
+        assert!(!dedup.seen("b", (7, 1)));
+        assert!(dedup.seen("a", (7, 1)));
+        assert!(dedup.seen("b", (7, 1)));
+        assert!(!dedup.seen("c", (7, 1)));
+    }
+
+    #[test]
+    fn seq_dedup_without_a_boot_id_never_sees_copies() {
+        let mut dedup = SeqDedup::new(4);
+        for _ in 0..3 {
+            assert!(!dedup.seen("a", (0, 1)));
+        }
+        // Nor are they remembered
+        assert!(!dedup.seen("a", (7, 1)));
+        for seq in 2..5 {
+            assert!(!dedup.seen("b", (7, seq)));
+        }
+        assert!(dedup.seen("a", (7, 1)));
+    }
+
+    #[test]
+    fn seq_dedup_forgets_the_oldest_message() {
+        let mut dedup = SeqDedup::new(3);
+        for seq in 1..=3 {
+            assert!(!dedup.seen("a", (7, seq)));
+        }
+        assert!(!dedup.seen("a", (7, 4)));
+        // 1 was evicted by 4, and remembering it again evicts 2
+        assert!(!dedup.seen("a", (7, 1)));
+        assert!(dedup.seen("a", (7, 3)));
+        assert!(dedup.seen("a", (7, 4)));
+        assert!(dedup.seen("a", (7, 1)));
+        assert!(!dedup.seen("a", (7, 2)));
+    }
+
+    const MAC: [u8; 6] = [0x24, 0x0a, 0xc4, 0x00, 0x00, 0x01];
+
+    fn received(rx: &Receiver<RecvFrame>) -> Vec<Vec<u8>> {
+        rx.try_iter().map(|frame| frame.data.to_vec()).collect()
+    }
+
+    #[test]
+    fn frames_go_to_the_handlers_of_their_type() {
+        static DROPPED: AtomicU32 = AtomicU32::new(0);
+        let mut dispatcher = RecvDispatcher::new();
+        let gps = dispatcher.register("gps", Some(&[GPS_TYPE]), 4, &DROPPED);
+        let all = dispatcher.register("all", None, 4, &DROPPED);
+        let pings = dispatcher.register("pings", Some(&[PING_TYPE, PONG_TYPE]), 4, &DROPPED);
+        let dispatcher = dispatcher.freeze();
+
+        let at = Duration::from_millis(1234);
+        dispatcher.dispatch(&MAC, &[GPS_TYPE, 1], at);
+        dispatcher.dispatch(&MAC, &[PONG_TYPE, 2], at);
+        // Of which we can't tell the type
+        dispatcher.dispatch(&MAC, &[], at);
+
+        let frame = gps.try_recv().unwrap();
+        assert_eq!(frame.src, MAC);
+        assert_eq!(frame.data, [GPS_TYPE, 1]);
+        assert_eq!(frame.received_at, at);
+        assert!(gps.try_recv().is_err());
+        assert_eq!(
+            received(&all),
+            [vec![GPS_TYPE, 1], vec![PONG_TYPE, 2], vec![]]
+        );
+        assert_eq!(received(&pings), [vec![PONG_TYPE, 2]]);
+        assert_eq!(DROPPED.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn full_handlers_lose_frames() {
+        static SLOW_DROPPED: AtomicU32 = AtomicU32::new(0);
+        static FAST_DROPPED: AtomicU32 = AtomicU32::new(0);
+        let mut dispatcher = RecvDispatcher::new();
+        let slow = dispatcher.register("slow", None, 1, &SLOW_DROPPED);
+        let fast = dispatcher.register("fast", None, 4, &FAST_DROPPED);
+        let dispatcher = dispatcher.freeze();
+
+        for i in 0..3 {
+            dispatcher.dispatch(&MAC, &[GPS_TYPE, i], Duration::ZERO);
+        }
+        assert_eq!(received(&slow), [vec![GPS_TYPE, 0]]);
+        assert_eq!(SLOW_DROPPED.load(Ordering::Relaxed), 2);
+        assert_eq!(received(&fast).len(), 3);
+        assert_eq!(FAST_DROPPED.load(Ordering::Relaxed), 0);
+
+        // There's room again
+        dispatcher.dispatch(&MAC, &[GPS_TYPE, 3], Duration::ZERO);
+        assert_eq!(received(&slow), [vec![GPS_TYPE, 3]]);
+    }
+
+    #[test]
+    fn filtered_and_malformed_frames_are_dropped() {
+        static DROPPED: AtomicU32 = AtomicU32::new(0);
+        let mut dispatcher =
+            RecvDispatcher::new().with_filter(|src: &[u8], _: &[u8]| src.last() != Some(&0xff));
+        let all = dispatcher.register("all", None, 4, &DROPPED);
+        let dispatcher = dispatcher.freeze();
+
+        dispatcher.dispatch(
+            &[0x24, 0x0a, 0xc4, 0x00, 0x00, 0xff],
+            &[GPS_TYPE],
+            Duration::ZERO,
+        );
+        dispatcher.dispatch(&MAC[..5], &[GPS_TYPE], Duration::ZERO);
+        dispatcher.dispatch(&MAC, &[GPS_TYPE; ESP_NOW_MAX_FRAME_LEN + 1], Duration::ZERO);
+        assert!(all.try_recv().is_err());
+
+        dispatcher.dispatch(&MAC, &[GPS_TYPE; ESP_NOW_MAX_FRAME_LEN], Duration::ZERO);
+        assert_eq!(all.try_recv().unwrap().data.len(), ESP_NOW_MAX_FRAME_LEN);
+        assert_eq!(DROPPED.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn copies_of_a_dispatcher_share_the_handlers() {
+        static DROPPED: AtomicU32 = AtomicU32::new(0);
+        let mut dispatcher = RecvDispatcher::new();
+        let gone = dispatcher.register("gone", None, 1, &DROPPED);
+        let kept = dispatcher.register("kept", None, 4, &DROPPED);
+        let dispatcher = dispatcher.freeze();
+        drop(gone);
+        // Not until a frame for it comes in
+        assert_eq!(dispatcher.gone().count(), 0);
+
+        // Like the callback, which runs in the wifi task
+        let copy = dispatcher.clone();
+        std::thread::spawn(move || copy.dispatch(&MAC, &[GPS_TYPE], Duration::ZERO))
+            .join()
+            .unwrap();
+        assert!(dispatcher.routes[0].gone.load(Ordering::Relaxed));
+        assert_eq!(dispatcher.gone().collect::<Vec<_>>(), ["gone"]);
+        dispatcher.dispatch(&MAC, &[GPS_TYPE], Duration::ZERO);
+        assert_eq!(received(&kept).len(), 2);
+    }
+
+    #[test]
+    fn hellos_are_relayed_as_they_were_sent() {
+        let hello = HelloMsg {
+            device_id: "24:0a:c4:00:00:01".to_string(),
+            role: Role::Tracker as i32,
+            firmware_version: "1.2.3".to_string(),
+            reset_reason: 1,
+            capabilities: CAP_GPS | CAP_OTA,
+            config_version: 0xdeadbeef,
+            name: "bike".to_string(),
+        };
+        let frame = encode_msg(&morty_message::Msg::Hello(hello.clone())).unwrap();
+        assert_eq!(peek_type(&frame), Some(HELLO_TYPE));
+        assert_eq!(
+            decode_msg(&frame).unwrap(),
+            Some(morty_message::Msg::Hello(hello.clone()))
+        );
+
+        let relayed = encode_relay("24:0a:c4:00:00:01", 1_700_000_000, 20, "", &frame).unwrap();
+        assert_eq!(peek_type(&relayed), Some(RELAY_TYPE));
+        let expected = RelayMsg {
+            src: "24:0a:c4:00:00:01".to_string(),
+            timestamp: 1_700_000_000,
+            msg: Some(relay_msg::Msg::Hello(hello)),
+            backfill: false,
+            path_delay_ms: 20,
+            gateway_hint: String::new(),
+        };
+        assert_eq!(
+            decode_msg(&relayed).unwrap(),
+            Some(morty_message::Msg::Relay(expected))
+        );
+    }
+
+    // A hello from a tracker relayed by a beacon, with `gateway_hint`
+    fn relayed_hello(gateway_hint: &str) -> RelayMsg {
+        RelayMsg {
+            src: "24:0a:c4:00:00:01".to_string(),
+            timestamp: 1_700_000_000,
+            msg: Some(relay_msg::Msg::Hello(HelloMsg {
+                device_id: "24:0a:c4:00:00:01".to_string(),
+                role: Role::Tracker as i32,
+                ..Default::default()
+            })),
+            backfill: false,
+            path_delay_ms: 20,
+            gateway_hint: gateway_hint.to_string(),
+        }
+    }
+
+    fn hello_frame(relay: &RelayMsg) -> Vec<u8> {
+        let Some(relay_msg::Msg::Hello(hello)) = &relay.msg else {
+            panic!("Not a hello: {relay:?}");
+        };
+        encode_msg(&morty_message::Msg::Hello(hello.clone())).unwrap()
+    }
+
+    #[test]
+    fn the_gateway_hint_is_stamped_like_prost_would() {
+        for hint in ["", "aa:bb:cc:dd:ee:ff"] {
+            let expected = relayed_hello(hint);
+            let relayed = encode_relay(
+                &expected.src,
+                expected.timestamp,
+                expected.path_delay_ms,
+                hint,
+                &hello_frame(&expected),
+            )
+            .unwrap();
+            assert_eq!(
+                relayed,
+                encode_msg(&morty_message::Msg::Relay(expected.clone())).unwrap(),
+                "{hint:?}"
+            );
+            assert_eq!(
+                decode_msg(&relayed).unwrap(),
+                Some(morty_message::Msg::Relay(expected))
+            );
+        }
+    }
+
+    #[test]
+    fn reframing_replaces_the_gateway_hint() {
+        let frame = encode_msg(&morty_message::Msg::Relay(relayed_hello("old"))).unwrap();
+        for hint in ["", "new"] {
+            let reframed = reframe_relay(&frame, 35, hint).unwrap();
+            let expected = RelayMsg {
+                path_delay_ms: 35,
+                ..relayed_hello(hint)
+            };
+            assert_eq!(
+                decode_msg(&reframed).unwrap(),
+                Some(morty_message::Msg::Relay(expected.clone())),
+                "{hint:?}"
+            );
+            assert_eq!(
+                reframed,
+                encode_msg(&morty_message::Msg::Relay(expected)).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn frames_without_a_crc_are_too_short() {
+        for frame in [&[][..], &[HELLO_TYPE]] {
+            let e = decode_msg(frame).unwrap_err();
+            assert!(matches!(e, CommError::FrameTooShort(len) if len == frame.len()));
+            assert_eq!(e.cause(), Some(DecodeFailure::Truncated));
+        }
+        assert_eq!(
+            decode_msg(&[]).unwrap_err().to_string(),
+            format!("Frame too short: 0 bytes ({})", DecodeFailure::Truncated)
+        );
+    }
+
+    #[test]
+    fn a_frame_with_only_a_crc_has_no_message() {
+        let frame = [HELLO_TYPE, wire::crc8(&[])];
+        assert_eq!(decode_msg(&frame).unwrap(), None);
+        // The CRC is still checked
+        let frame = [HELLO_TYPE, wire::crc8(&[]) ^ 1];
+        assert!(matches!(
+            decode_msg(&frame),
+            Err(CommError::CrcMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn a_frame_with_one_byte_of_message_goes_to_protobuf() {
+        // Field number 0 isn't valid protobuf
+        let frame = [HELLO_TYPE, wire::crc8(&[0]), 0];
+        let e = decode_msg(&frame).unwrap_err();
+        assert!(matches!(e, CommError::Decode(_)));
+        assert_eq!(e.cause(), Some(DecodeFailure::ProtobufError));
+        let frame = [HELLO_TYPE, wire::crc8(&[0]) ^ 1, 0];
+        assert!(matches!(
+            decode_msg(&frame),
+            Err(CommError::CrcMismatch { .. })
+        ));
+    }
+}
@@ -0,0 +1,191 @@
+//! Commands for a single device, sent by the backend through the gateway and a beacon. Trackers
+//! and beacons both execute them through a `CommandHandler`, so the common commands behave the
+//! same on every device.
+
+use crate::config::erase_nvs;
+use crate::config::store_nvs;
+use crate::flashlog;
+use crate::flashlog::EventKind;
+use crate::led::colors;
+use crate::led::LedHandle;
+use crate::ledlog;
+use crate::messages::Command;
+use crate::messages::CommandAckMsg;
+use crate::messages::CommandMsg;
+use crate::naming::check_name;
+use crate::naming::NAME_KEY;
+use crate::nvs_recovery::erase_namespace;
+use log::*;
+use std::collections::VecDeque;
+use std::ffi::CString;
+use std::time::Duration;
+
+// Commands are ignored when they were issued longer ago than this, or this far in the future
+pub const COMMAND_VALIDITY: Duration = Duration::from_secs(5 * 60);
+// Reason recorded in the event log when rebooting because we were told to
+pub const REBOOT_REASON_COMMAND: u32 = 100;
+
+// Number of nonces we remember to recognize replays
+const SEEN_NONCES: usize = 32;
+
+const IDENTIFY_DURATION: Duration = Duration::from_secs(30);
+const IDENTIFY_PERIOD: Duration = Duration::from_millis(200);
+const IDENTIFY_BRIGHTNESS: u8 = 50;
+
+/// The acknowledgement to send for a command, and whether to reboot once it's sent.
+pub struct Handled {
+    pub ack: CommandAckMsg,
+    pub reboot: bool,
+}
+
+pub struct CommandHandler {
+    mac: String,
+    led: LedHandle,
+    seen: VecDeque<u32>,
+}
+
+impl CommandHandler {
+    /// `mac` is the address of this device, as returned by `comm::own_mac`.
+    pub fn new(mac: String, led: LedHandle) -> Self {
+        Self {
+            mac,
+            led,
+            seen: VecDeque::new(),
+        }
+    }
+
+    pub fn is_for_us(&self, cmd: &CommandMsg) -> bool {
+        cmd.target.eq_ignore_ascii_case(&self.mac)
+    }
+
+    /// Execute a command addressed to us. `now` is the wall clock time, if it's valid. Without
+    /// it the validity window can't be checked and only the nonce protects against replays.
+    /// `resend_stats` handles ResendStats on devices that keep stats, `stay_awake` handles
+    /// StayAwake and AllowSleep on devices that sleep and `resend_trace` handles ResendTrace on
+    /// devices that keep a trace. Returns None for commands we have seen before, since they are
+    /// broadcast multiple times.
+    pub fn handle(
+        &mut self,
+        cmd: &CommandMsg,
+        now: Option<Duration>,
+        resend_stats: Option<&dyn Fn()>,
+        stay_awake: Option<&dyn Fn(bool)>,
+        resend_trace: Option<&dyn Fn()>,
+    ) -> Option<Handled> {
+        if self.seen.contains(&cmd.nonce) {
+            debug!("Ignoring command {} we have seen before", cmd.nonce);
+            return None;
+        }
+        self.seen.push_back(cmd.nonce);
+        if self.seen.len() > SEEN_NONCES {
+            self.seen.pop_front();
+        }
+
+        let ack = |ok| CommandAckMsg {
+            nonce: cmd.nonce,
+            ok,
+        };
+
+        if let Some(now) = now {
+            let age = now.as_secs() as i64 - cmd.timestamp;
+            if age.unsigned_abs() > COMMAND_VALIDITY.as_secs() {
+                warn!("Ignoring command {} issued {}s ago", cmd.nonce, age);
+                return Some(Handled {
+                    ack: ack(false),
+                    reboot: false,
+                });
+            }
+        }
+
+        let command = Command::from_i32(cmd.command).unwrap_or(Command::Unspecified);
+        info!("Executing command {}: {:?}", cmd.nonce, command);
+        let (ok, reboot) = match command {
+            Command::Reboot => (true, true),
+            Command::Identify => {
+                let times = (IDENTIFY_DURATION.as_millis() / IDENTIFY_PERIOD.as_millis()) as u8;
+                let result = self.led.blink_color(
+                    colors::WHITE,
+                    IDENTIFY_BRIGHTNESS,
+                    IDENTIFY_PERIOD,
+                    times,
+                );
+                (result.is_ok(), false)
+            }
+            Command::ClearNvsSection => match clear_nvs_section(&cmd.section) {
+                Ok(()) => (true, false),
+                Err(e) => {
+                    error!("Unable to clear NVS section {}: {e}", cmd.section);
+                    (false, false)
+                }
+            },
+            Command::ResendStats => match resend_stats {
+                Some(resend_stats) => {
+                    resend_stats();
+                    (true, false)
+                }
+                None => (false, false),
+            },
+            Command::StayAwake | Command::AllowSleep => match stay_awake {
+                Some(stay_awake) => {
+                    stay_awake(command == Command::StayAwake);
+                    (true, false)
+                }
+                None => (false, false),
+            },
+            Command::ResendTrace => match resend_trace {
+                Some(resend_trace) => {
+                    resend_trace();
+                    (true, false)
+                }
+                None => (false, false),
+            },
+            Command::SetName => match set_name(&cmd.name) {
+                Ok(()) => (true, false),
+                Err(e) => {
+                    error!("Unable to set the name to {:?}: {e}", cmd.name);
+                    (false, false)
+                }
+            },
+            Command::Unspecified => {
+                warn!("Unknown command {}", cmd.command);
+                (false, false)
+            }
+        };
+
+        Some(Handled {
+            ack: ack(ok),
+            reboot,
+        })
+    }
+}
+
+/// Reboot after a Reboot command. The acknowledgement should have been sent already, we give it a
+/// moment to go out.
+pub fn reboot() -> ! {
+    warn!("Rebooting on command");
+    flashlog::log_event(EventKind::Reboot {
+        reason: REBOOT_REASON_COMMAND,
+    });
+    flashlog::flush();
+    ledlog::save();
+    std::thread::sleep(Duration::from_millis(200));
+    unsafe { esp_idf_sys::esp_restart() }
+}
+
+fn clear_nvs_section(section: &str) -> Result<(), anyhow::Error> {
+    let namespace = CString::new(section)?;
+    Ok(erase_namespace(&namespace)?)
+}
+
+/// Store `name` as the name of this device, or remove it when it's empty. Devices load their
+/// settings when they boot or wake up, so it goes out from then on.
+fn set_name(name: &str) -> Result<(), anyhow::Error> {
+    check_name(name).map_err(|e| anyhow::anyhow!(e))?;
+    if name.is_empty() {
+        erase_nvs(NAME_KEY)?;
+    } else {
+        store_nvs(NAME_KEY, name)?;
+    }
+    info!("Name set to {name:?}");
+    Ok(())
+}
@@ -0,0 +1,817 @@
+//! Configuration versions, so we can tell which devices in the field run with other settings than
+//! they should. A device hashes the settings it applied into a version and reports it every now
+//! and then. The gateway compares that to the version the device should have.
+//!
+//! The hash has to be the same on every chip and with every build, so it doesn't use `Hash`.
+//! Settings are encoded in a fixed layout, sorted by name, and hashed with 32 bit FNV-1a.
+//!
+//! Settings that can differ between devices of the same build are described by a schema: every
+//! `Setting` has a key, a type with the values it may take and a default. They are stored as
+//! strings in the `config` namespace of NVS, e.g. written when provisioning with a CSV for
+//! `nvs_partition_gen.py`:
+//!
+//! ```text
+//! key,type,encoding,value
+//! config,namespace,,
+//! ssid,data,string,IoT
+//! stats_interval,data,string,60
+//! ```
+//!
+//! `Settings::load` takes the stored value of every setting, or the default when it isn't stored.
+//! A value that doesn't parse or is out of range doesn't stop the device from booting, it's
+//! reported and the default is used instead. Durations are in seconds.
+
+use esp_idf_sys::esp;
+use esp_idf_sys::EspError;
+use log::*;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+use std::ffi::CString;
+use std::fmt;
+use std::time::Duration;
+
+const FNV_OFFSET: u32 = 0x811c_9dc5;
+const FNV_PRIME: u32 = 0x0100_0193;
+
+// NVS namespace of the settings. Keys in NVS are at most 15 bytes.
+pub const CONFIG_NAMESPACE: &str = "config";
+const MAX_KEY_LEN: usize = 15;
+
+// Type tags, so a setting doesn't hash the same when its type changes
+const TAG_BOOL: u8 = 1;
+const TAG_U32: u8 = 2;
+const TAG_F32: u8 = 3;
+const TAG_STR: u8 = 4;
+const TAG_DURATION: u8 = 5;
+
+/// Collects the settings of a device and hashes them into its configuration version. The order
+/// in which settings are added doesn't matter. Adding a setting twice keeps the last value.
+#[derive(Default)]
+pub struct ConfigVersion {
+    settings: BTreeMap<&'static str, Vec<u8>>,
+}
+
+impl ConfigVersion {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn bool(&mut self, name: &'static str, value: bool) -> &mut Self {
+        self.add(name, TAG_BOOL, &[value as u8])
+    }
+
+    pub fn u32(&mut self, name: &'static str, value: u32) -> &mut Self {
+        self.add(name, TAG_U32, &value.to_le_bytes())
+    }
+
+    pub fn f32(&mut self, name: &'static str, value: f32) -> &mut Self {
+        // -0.0 and 0.0, and all NaNs, are the same setting
+        let value = if value == 0.0 {
+            0.0
+        } else if value.is_nan() {
+            f32::NAN
+        } else {
+            value
+        };
+        self.add(name, TAG_F32, &value.to_bits().to_le_bytes())
+    }
+
+    pub fn str(&mut self, name: &'static str, value: &str) -> &mut Self {
+        self.add(name, TAG_STR, value.as_bytes())
+    }
+
+    pub fn duration(&mut self, name: &'static str, value: Duration) -> &mut Self {
+        self.add(
+            name,
+            TAG_DURATION,
+            &(value.as_millis() as u64).to_le_bytes(),
+        )
+    }
+
+    /// The version of the settings. This is never 0, which means a device didn't report one.
+    pub fn finish(&self) -> u32 {
+        let mut hash = FNV_OFFSET;
+        for (name, value) in &self.settings {
+            for part in [
+                &(name.len() as u16).to_le_bytes()[..],
+                name.as_bytes(),
+                &(value.len() as u16).to_le_bytes(),
+                value,
+            ] {
+                for byte in part {
+                    hash = (hash ^ *byte as u32).wrapping_mul(FNV_PRIME);
+                }
+            }
+        }
+        hash.max(1)
+    }
+
+    fn add(&mut self, name: &'static str, tag: u8, value: &[u8]) -> &mut Self {
+        let mut encoded = Vec::with_capacity(value.len() + 1);
+        encoded.push(tag);
+        encoded.extend_from_slice(value);
+        self.settings.insert(name, encoded);
+        self
+    }
+}
+
+/// Whether a device runs with the configuration it should.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Drift {
+    InSync,
+    Drifted {
+        reported: u32,
+        desired: u32,
+    },
+    /// The device didn't report a version, or we don't know which one it should have.
+    Unknown,
+}
+
+/// Compare the version a device `reported`, 0 when it didn't, with the one it should have.
+pub fn check_drift(reported: u32, desired: Option<u32>) -> Drift {
+    match desired {
+        Some(desired) if reported != 0 && desired != 0 => {
+            if reported == desired {
+                Drift::InSync
+            } else {
+                Drift::Drifted { reported, desired }
+            }
+        }
+        _ => Drift::Unknown,
+    }
+}
+
+/// The type of a setting, with the values it may take.
+#[derive(Clone, Copy, Debug)]
+pub enum Kind {
+    Bool,
+    U32 {
+        min: u32,
+        max: u32,
+    },
+    F32 {
+        min: f32,
+        max: f32,
+    },
+    /// At most `max_len` bytes, an empty string is valid
+    Str {
+        max_len: usize,
+    },
+    /// In whole seconds
+    Duration {
+        min: Duration,
+        max: Duration,
+    },
+    /// One of `choices`, as a string
+    Choice {
+        choices: &'static [&'static str],
+    },
+}
+
+/// The value of a setting.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Bool(bool),
+    U32(u32),
+    F32(f32),
+    Str(Cow<'static, str>),
+    Duration(Duration),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Bool(b) => write!(f, "{b}"),
+            Value::U32(n) => write!(f, "{n}"),
+            Value::F32(x) => write!(f, "{x}"),
+            Value::Str(s) => write!(f, "{s:?}"),
+            Value::Duration(d) => write!(f, "{}s", d.as_secs()),
+        }
+    }
+}
+
+/// A setting of the schema of a device.
+#[derive(Clone, Debug)]
+pub struct Setting {
+    /// Key in NVS, at most 15 bytes
+    pub key: &'static str,
+    pub kind: Kind,
+    pub default: Value,
+    /// Not shown, e.g. a password
+    pub secret: bool,
+}
+
+impl Setting {
+    pub const fn new(key: &'static str, kind: Kind, default: Value) -> Self {
+        Self {
+            key,
+            kind,
+            default,
+            secret: false,
+        }
+    }
+
+    pub const fn secret(mut self) -> Self {
+        // Not `..self`, a const fn can't drop what's left of it
+        self.secret = true;
+        self
+    }
+
+    /// Parse `text` as a value of this setting and check that it's in range.
+    pub fn parse(&self, text: &str) -> Result<Value, String> {
+        let text = text.trim();
+        let value = match self.kind {
+            Kind::Bool => match text {
+                "true" | "1" => Value::Bool(true),
+                "false" | "0" => Value::Bool(false),
+                _ => return Err(format!("{text:?} is not true or false")),
+            },
+            Kind::U32 { .. } => Value::U32(
+                text.parse()
+                    .map_err(|_| format!("{text:?} is not a whole number"))?,
+            ),
+            Kind::F32 { .. } => match text.parse::<f32>() {
+                Ok(x) if x.is_finite() => Value::F32(x),
+                _ => return Err(format!("{text:?} is not a number")),
+            },
+            Kind::Str { .. } | Kind::Choice { .. } => Value::Str(Cow::Owned(text.to_string())),
+            Kind::Duration { .. } => Value::Duration(Duration::from_secs(
+                text.strip_suffix('s')
+                    .unwrap_or(text)
+                    .parse()
+                    .map_err(|_| format!("{text:?} is not a number of seconds"))?,
+            )),
+        };
+        self.check(&value)?;
+        Ok(value)
+    }
+
+    /// Whether `value` has the type of this setting and is in range.
+    pub fn check(&self, value: &Value) -> Result<(), String> {
+        match (self.kind, value) {
+            (Kind::Bool, Value::Bool(_)) => Ok(()),
+            (Kind::U32 { min, max }, Value::U32(n)) if (min..=max).contains(n) => Ok(()),
+            (Kind::U32 { min, max }, Value::U32(n)) => {
+                Err(format!("{n} is not between {min} and {max}"))
+            }
+            (Kind::F32 { min, max }, Value::F32(x)) if (min..=max).contains(x) => Ok(()),
+            (Kind::F32 { min, max }, Value::F32(x)) => {
+                Err(format!("{x} is not between {min} and {max}"))
+            }
+            (Kind::Str { max_len }, Value::Str(s)) if s.len() <= max_len => Ok(()),
+            (Kind::Str { max_len }, Value::Str(s)) => {
+                Err(format!("{} bytes is longer than {max_len}", s.len()))
+            }
+            (Kind::Duration { min, max }, Value::Duration(d)) if (min..=max).contains(d) => Ok(()),
+            (Kind::Duration { min, max }, Value::Duration(d)) => Err(format!(
+                "{}s is not between {}s and {}s",
+                d.as_secs(),
+                min.as_secs(),
+                max.as_secs()
+            )),
+            (Kind::Choice { choices }, Value::Str(s)) if choices.iter().any(|&c| c == *s) => Ok(()),
+            (Kind::Choice { choices }, Value::Str(s)) => {
+                Err(format!("{s:?} is not one of {}", choices.join(", ")))
+            }
+            (kind, value) => Err(format!("{value} is not a {kind:?}")),
+        }
+    }
+}
+
+/// Check that the keys of `schema` fit in NVS and are unique, and that the defaults are valid.
+pub fn check_schema(schema: &[Setting]) -> Result<(), anyhow::Error> {
+    let mut keys = HashSet::new();
+    for setting in schema {
+        if setting.key.is_empty() || setting.key.len() > MAX_KEY_LEN {
+            anyhow::bail!("Setting {:?} needs a key of 1 to 15 bytes", setting.key);
+        }
+        if !keys.insert(setting.key) {
+            anyhow::bail!("Setting {} is in the schema twice", setting.key);
+        }
+        if let Err(e) = setting.check(&setting.default) {
+            anyhow::bail!("Default of setting {} is invalid: {e}", setting.key);
+        }
+    }
+    Ok(())
+}
+
+/// Where the value of a setting came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    Default,
+    Nvs,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Default => write!(f, "default"),
+            Source::Nvs => write!(f, "nvs"),
+        }
+    }
+}
+
+/// A stored value that we couldn't use, so the setting got its default.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Invalid {
+    pub key: &'static str,
+    pub error: String,
+}
+
+impl fmt::Display for Invalid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.key, self.error)
+    }
+}
+
+/// The value of every setting of a schema, and where it came from.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    schema: &'static [Setting],
+    values: Vec<(Value, Source)>,
+    invalid: Vec<Invalid>,
+}
+
+impl Settings {
+    /// Take the value of every setting in `schema` from `stored`, which returns the text stored
+    /// under a key, None when nothing is, or why it couldn't be read. Values that can't be read,
+    /// don't parse or are out of range are left out, the setting gets its default.
+    pub fn load(
+        schema: &'static [Setting],
+        mut stored: impl FnMut(&str) -> Result<Option<String>, String>,
+    ) -> Self {
+        let mut invalid = Vec::new();
+        let values = schema
+            .iter()
+            .map(|setting| {
+                match stored(setting.key)
+                    .and_then(|text| text.map(|t| setting.parse(&t)).transpose())
+                {
+                    Ok(Some(value)) => (value, Source::Nvs),
+                    Ok(None) => (setting.default.clone(), Source::Default),
+                    Err(error) => {
+                        invalid.push(Invalid {
+                            key: setting.key,
+                            error,
+                        });
+                        (setting.default.clone(), Source::Default)
+                    }
+                }
+            })
+            .collect();
+        Self {
+            schema,
+            values,
+            invalid,
+        }
+    }
+
+    /// All settings, with their value and where it came from.
+    pub fn iter(&self) -> impl Iterator<Item = (&Setting, &Value, Source)> {
+        self.schema
+            .iter()
+            .zip(&self.values)
+            .map(|(setting, (value, source))| (setting, value, *source))
+    }
+
+    /// The stored values we couldn't use.
+    pub fn invalid(&self) -> &[Invalid] {
+        &self.invalid
+    }
+
+    /// Log the stored values we couldn't use.
+    pub fn log_invalid(&self) {
+        for invalid in &self.invalid {
+            error!("Invalid setting {invalid}, using the default");
+        }
+    }
+
+    // The getters panic on keys that aren't in the schema, or that have another type. That's a
+    // bug in the device, not in what's stored.
+
+    pub fn bool(&self, key: &str) -> bool {
+        match self.get(key) {
+            Value::Bool(b) => *b,
+            value => panic!("Setting {key} is {value:?}, not a bool"),
+        }
+    }
+
+    pub fn u32(&self, key: &str) -> u32 {
+        match self.get(key) {
+            Value::U32(n) => *n,
+            value => panic!("Setting {key} is {value:?}, not a u32"),
+        }
+    }
+
+    pub fn f32(&self, key: &str) -> f32 {
+        match self.get(key) {
+            Value::F32(x) => *x,
+            value => panic!("Setting {key} is {value:?}, not a f32"),
+        }
+    }
+
+    pub fn str(&self, key: &str) -> &str {
+        match self.get(key) {
+            Value::Str(s) => s,
+            value => panic!("Setting {key} is {value:?}, not a string"),
+        }
+    }
+
+    pub fn duration(&self, key: &str) -> Duration {
+        match self.get(key) {
+            Value::Duration(d) => *d,
+            value => panic!("Setting {key} is {value:?}, not a duration"),
+        }
+    }
+
+    fn get(&self, key: &str) -> &Value {
+        let i = self
+            .schema
+            .iter()
+            .position(|s| s.key == key)
+            .unwrap_or_else(|| panic!("Setting {key} is not in the schema"));
+        &self.values[i].0
+    }
+}
+
+/// Load the settings of `schema` from the `config` namespace in NVS. Without persistence, or when
+/// nothing was ever stored, every setting gets its default.
+pub fn load_nvs(schema: &'static [Setting]) -> Settings {
+    let namespace = CString::new(CONFIG_NAMESPACE).unwrap();
+    let mut handle: esp_idf_sys::nvs_handle_t = 0;
+    let opened = crate::nvs_recovery::persistence_available()
+        && esp!(unsafe {
+            esp_idf_sys::nvs_open(
+                namespace.as_ptr(),
+                esp_idf_sys::nvs_open_mode_t_NVS_READONLY,
+                &mut handle,
+            )
+        })
+        .map_err(|e| {
+            if e.code() != esp_idf_sys::ESP_ERR_NVS_NOT_FOUND as i32 {
+                error!("Unable to open the settings in NVS: {e}");
+            }
+        })
+        .is_ok();
+    let settings = Settings::load(schema, |key| {
+        if !opened {
+            return Ok(None);
+        }
+        read_str(handle, key).map_err(|e| format!("unable to read: {e}"))
+    });
+    if opened {
+        unsafe { esp_idf_sys::nvs_close(handle) };
+    }
+    settings
+}
+
+fn read_str(handle: esp_idf_sys::nvs_handle_t, key: &str) -> Result<Option<String>, EspError> {
+    let key = CString::new(key).unwrap();
+    let mut len = 0;
+    let result = esp!(unsafe {
+        esp_idf_sys::nvs_get_str(handle, key.as_ptr(), std::ptr::null_mut(), &mut len)
+    })
+    .and_then(|_| {
+        let mut text = vec![0u8; len];
+        esp!(unsafe {
+            esp_idf_sys::nvs_get_str(handle, key.as_ptr(), text.as_mut_ptr() as *mut _, &mut len)
+        })
+        .map(|_| text)
+    });
+    match result {
+        Ok(mut text) => {
+            // Without the NUL
+            text.truncate(len.saturating_sub(1));
+            Ok(Some(String::from_utf8_lossy(&text).into_owned()))
+        }
+        Err(e) if e.code() == esp_idf_sys::ESP_ERR_NVS_NOT_FOUND as i32 => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Store `text` as the value of `key` in the `config` namespace in NVS, for the next boot. Check
+/// it with `Setting::parse` first. Does nothing without persistence.
+pub fn store_nvs(key: &str, text: &str) -> Result<(), EspError> {
+    let text = CString::new(text)
+        .map_err(|_| EspError::from(esp_idf_sys::ESP_ERR_INVALID_ARG as i32).unwrap())?;
+    write_nvs(key, |handle, key| unsafe {
+        esp_idf_sys::nvs_set_str(handle, key, text.as_ptr())
+    })
+}
+
+/// Remove the stored value of `key` from NVS, so it gets its default at the next boot.
+pub fn erase_nvs(key: &str) -> Result<(), EspError> {
+    write_nvs(key, |handle, key| {
+        match unsafe { esp_idf_sys::nvs_erase_key(handle, key) } {
+            code if code == esp_idf_sys::ESP_ERR_NVS_NOT_FOUND as i32 => esp_idf_sys::ESP_OK as i32,
+            code => code,
+        }
+    })
+}
+
+fn write_nvs(
+    key: &str,
+    write: impl Fn(esp_idf_sys::nvs_handle_t, *const std::ffi::c_char) -> esp_idf_sys::esp_err_t,
+) -> Result<(), EspError> {
+    if !crate::nvs_recovery::persistence_available() {
+        return Ok(());
+    }
+    let namespace = CString::new(CONFIG_NAMESPACE).unwrap();
+    let key = CString::new(key).unwrap();
+    let attempt = || {
+        let mut handle: esp_idf_sys::nvs_handle_t = 0;
+        esp!(unsafe {
+            esp_idf_sys::nvs_open(
+                namespace.as_ptr(),
+                esp_idf_sys::nvs_open_mode_t_NVS_READWRITE,
+                &mut handle,
+            )
+        })?;
+        let result = esp!(write(handle, key.as_ptr()))
+            .and_then(|_| esp!(unsafe { esp_idf_sys::nvs_commit(handle) }));
+        unsafe { esp_idf_sys::nvs_close(handle) };
+        result
+    };
+    attempt().or_else(|e| crate::nvs_recovery::recover_write(e, attempt))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions_are_pinned() {
+        // Devices in the field report these, a change of the hash makes them all drift
+        assert_eq!(ConfigVersion::new().finish(), FNV_OFFSET);
+        let version = ConfigVersion::new()
+            .u32("interval", 60)
+            .bool("led", true)
+            .finish();
+        assert_eq!(version, 0xf96f_629b);
+    }
+
+    #[test]
+    fn the_order_of_settings_doesnt_matter() {
+        let a = ConfigVersion::new()
+            .u32("interval", 60)
+            .str("ssid", "IoT")
+            .finish();
+        let b = ConfigVersion::new()
+            .str("ssid", "IoT")
+            .u32("interval", 60)
+            .finish();
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn the_last_value_of_a_setting_counts() {
+        let twice = ConfigVersion::new()
+            .u32("interval", 30)
+            .u32("interval", 60)
+            .finish();
+        assert_eq!(twice, ConfigVersion::new().u32("interval", 60).finish());
+    }
+
+    #[test]
+    fn values_names_and_types_change_the_version() {
+        let version = ConfigVersion::new().u32("interval", 60).finish();
+        assert_ne!(version, ConfigVersion::new().u32("interval", 61).finish());
+        assert_ne!(version, ConfigVersion::new().u32("period", 60).finish());
+        assert_ne!(
+            version,
+            ConfigVersion::new()
+                .duration("interval", Duration::from_millis(60))
+                .finish()
+        );
+        // Names and values can't run into each other
+        assert_ne!(
+            ConfigVersion::new().str("a", "bc").finish(),
+            ConfigVersion::new().str("ab", "c").finish()
+        );
+    }
+
+    #[test]
+    fn zeroes_and_nans_are_the_same_setting() {
+        let version = |x: f32| ConfigVersion::new().f32("x", x).finish();
+        assert_eq!(version(0.0), version(-0.0));
+        assert_eq!(version(f32::NAN), version(-f32::NAN));
+        assert_ne!(version(0.0), version(f32::NAN));
+    }
+
+    #[test]
+    fn devices_that_dont_say_have_an_unknown_drift() {
+        assert_eq!(check_drift(0, Some(5)), Drift::Unknown);
+        assert_eq!(check_drift(5, None), Drift::Unknown);
+        assert_eq!(check_drift(5, Some(0)), Drift::Unknown);
+    }
+
+    #[test]
+    fn drift_compares_the_versions() {
+        assert_eq!(check_drift(5, Some(5)), Drift::InSync);
+        assert_eq!(
+            check_drift(5, Some(6)),
+            Drift::Drifted {
+                reported: 5,
+                desired: 6
+            }
+        );
+    }
+
+    static SCHEMA: &[Setting] = &[
+        Setting::new("led", Kind::Bool, Value::Bool(true)),
+        Setting::new("interval", Kind::U32 { min: 1, max: 3600 }, Value::U32(60)),
+        Setting::new(
+            "tx_power",
+            Kind::F32 {
+                min: 2.0,
+                max: 20.0,
+            },
+            Value::F32(8.5),
+        ),
+        Setting::new(
+            "password",
+            Kind::Str { max_len: 8 },
+            Value::Str(Cow::Borrowed("")),
+        )
+        .secret(),
+        Setting::new(
+            "timeout",
+            Kind::Duration {
+                min: Duration::from_secs(1),
+                max: Duration::from_secs(600),
+            },
+            Value::Duration(Duration::from_secs(20)),
+        ),
+        Setting::new(
+            "policy",
+            Kind::Choice {
+                choices: &["fifo", "newest"],
+            },
+            Value::Str(Cow::Borrowed("fifo")),
+        ),
+    ];
+
+    fn setting(key: &str) -> &'static Setting {
+        SCHEMA.iter().find(|s| s.key == key).unwrap()
+    }
+
+    fn str(s: &'static str) -> Value {
+        Value::Str(Cow::Borrowed(s))
+    }
+
+    #[test]
+    fn values_are_parsed_by_their_kind() {
+        assert_eq!(setting("led").parse("true"), Ok(Value::Bool(true)));
+        assert_eq!(setting("led").parse(" 0 "), Ok(Value::Bool(false)));
+        assert_eq!(setting("interval").parse("3600"), Ok(Value::U32(3600)));
+        assert_eq!(setting("tx_power").parse("2"), Ok(Value::F32(2.0)));
+        assert_eq!(setting("password").parse("secret"), Ok(str("secret")));
+        assert_eq!(setting("password").parse(""), Ok(str("")));
+        assert_eq!(
+            setting("timeout").parse("30s"),
+            Ok(Value::Duration(Duration::from_secs(30)))
+        );
+        assert_eq!(
+            setting("timeout").parse("30"),
+            Ok(Value::Duration(Duration::from_secs(30)))
+        );
+        assert_eq!(setting("policy").parse("newest"), Ok(str("newest")));
+    }
+
+    #[test]
+    fn values_that_dont_parse_say_why() {
+        let error = |key, text| setting(key).parse(text).unwrap_err();
+        assert_eq!(error("led", "yes"), "\"yes\" is not true or false");
+        assert_eq!(error("interval", "-1"), "\"-1\" is not a whole number");
+        assert_eq!(error("tx_power", "NaN"), "\"NaN\" is not a number");
+        assert_eq!(error("tx_power", "inf"), "\"inf\" is not a number");
+        assert_eq!(error("timeout", "1m"), "\"1m\" is not a number of seconds");
+    }
+
+    #[test]
+    fn values_out_of_range_are_rejected() {
+        let error = |key, text| setting(key).parse(text).unwrap_err();
+        assert_eq!(error("interval", "0"), "0 is not between 1 and 3600");
+        assert_eq!(error("tx_power", "20.5"), "20.5 is not between 2 and 20");
+        assert_eq!(error("password", "123456789"), "9 bytes is longer than 8");
+        assert_eq!(error("timeout", "601"), "601s is not between 1s and 600s");
+        assert_eq!(
+            error("policy", "oldest"),
+            "\"oldest\" is not one of fifo, newest"
+        );
+        assert_eq!(
+            setting("led").check(&Value::U32(1)),
+            Err("1 is not a Bool".to_string())
+        );
+    }
+
+    #[test]
+    fn schemas_are_checked() {
+        check_schema(SCHEMA).unwrap();
+        let bad = |setting: Setting| check_schema(&[setting]).unwrap_err().to_string();
+        assert_eq!(
+            bad(Setting::new("", Kind::Bool, Value::Bool(false))),
+            "Setting \"\" needs a key of 1 to 15 bytes"
+        );
+        assert_eq!(
+            bad(Setting::new(
+                "sixteen_bytes_xx",
+                Kind::Bool,
+                Value::Bool(false)
+            )),
+            "Setting \"sixteen_bytes_xx\" needs a key of 1 to 15 bytes"
+        );
+        assert_eq!(
+            bad(Setting::new(
+                "interval",
+                Kind::U32 { min: 1, max: 10 },
+                Value::U32(0)
+            )),
+            "Default of setting interval is invalid: 0 is not between 1 and 10"
+        );
+        assert_eq!(
+            bad(Setting::new(
+                "policy",
+                Kind::Choice { choices: &["fifo"] },
+                str("lifo")
+            )),
+            "Default of setting policy is invalid: \"lifo\" is not one of fifo"
+        );
+        let twice = [setting("led").clone(), setting("led").clone()];
+        assert_eq!(
+            check_schema(&twice).unwrap_err().to_string(),
+            "Setting led is in the schema twice"
+        );
+    }
+
+    #[test]
+    fn settings_come_from_nvs_or_their_default() {
+        let settings = Settings::load(SCHEMA, |key| {
+            Ok(match key {
+                "interval" => Some("120".to_string()),
+                "policy" => Some("newest".to_string()),
+                _ => None,
+            })
+        });
+        assert!(settings.invalid().is_empty());
+        assert!(settings.bool("led"));
+        assert_eq!(settings.u32("interval"), 120);
+        assert_eq!(settings.f32("tx_power"), 8.5);
+        assert_eq!(settings.str("password"), "");
+        assert_eq!(settings.duration("timeout"), Duration::from_secs(20));
+        assert_eq!(settings.str("policy"), "newest");
+        let sources: Vec<(&str, Source)> =
+            settings.iter().map(|(s, _, src)| (s.key, src)).collect();
+        assert_eq!(
+            sources,
+            [
+                ("led", Source::Default),
+                ("interval", Source::Nvs),
+                ("tx_power", Source::Default),
+                ("password", Source::Default),
+                ("timeout", Source::Default),
+                ("policy", Source::Nvs),
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_values_fall_back_to_their_default() {
+        let settings = Settings::load(SCHEMA, |key| match key {
+            "interval" => Ok(Some("0".to_string())),
+            "policy" => Ok(Some("oldest".to_string())),
+            "led" => Err("unreadable".to_string()),
+            _ => Ok(None),
+        });
+        assert!(settings.bool("led"));
+        assert_eq!(settings.u32("interval"), 60);
+        assert_eq!(settings.str("policy"), "fifo");
+        assert!(settings
+            .iter()
+            .all(|(_, _, source)| source == Source::Default));
+        let invalid: Vec<String> = settings.invalid().iter().map(|i| i.to_string()).collect();
+        assert_eq!(
+            invalid,
+            [
+                "led: unreadable",
+                "interval: 0 is not between 1 and 3600",
+                "policy: \"oldest\" is not one of fifo, newest",
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Setting interval is U32(60), not a bool")]
+    fn getters_of_another_type_panic() {
+        Settings::load(SCHEMA, |_| Ok(None)).bool("interval");
+    }
+
+    #[test]
+    fn values_are_displayed() {
+        assert_eq!(Value::Bool(true).to_string(), "true");
+        assert_eq!(Value::F32(8.5).to_string(), "8.5");
+        assert_eq!(str("IoT").to_string(), "\"IoT\"");
+        assert_eq!(Value::Duration(Duration::from_secs(20)).to_string(), "20s");
+    }
+}
@@ -0,0 +1,94 @@
+//! Deduplication of fixes by their uid. The same fix reaches us more than once when multiple
+//! beacons relay it, or when the tracker retransmits it. A retransmission can carry news though,
+//! e.g. the tracker was plugged in between two attempts, so next to the uid we keep a fingerprint
+//! of the fields that can change between attempts. A known uid with another fingerprint is an
+//! update rather than a duplicate.
+//!
+//! | uid     | fingerprint | verdict     |
+//! |---------|-------------|-------------|
+//! | new     | any         | `New`       |
+//! | known   | same        | `Duplicate` |
+//! | known   | changed     | `Update`    |
+//!
+//! `wire::DedupRing` does the same without allocating, for receivers without std.
+
+use crate::messages::GpsMsg;
+use std::collections::VecDeque;
+
+pub use crate::wire::Fingerprint;
+pub use crate::wire::Seen;
+
+impl Fingerprint {
+    pub fn of(gps: &GpsMsg) -> Self {
+        Self::new(gps.charging, gps.battery_voltage, gps.gps_fault_suspected)
+    }
+}
+
+/// The uids and fingerprints of the last `size` fixes.
+pub struct DedupCache {
+    entries: VecDeque<(String, Fingerprint)>,
+    size: usize,
+}
+
+impl DedupCache {
+    pub fn new(size: usize) -> Self {
+        Self {
+            entries: VecDeque::with_capacity(size),
+            size,
+        }
+    }
+
+    /// Whether we've seen `gps` before, and remember it. An update replaces the fingerprint we
+    /// had, so another copy of it is a duplicate.
+    pub fn check(&mut self, gps: &GpsMsg) -> Seen {
+        let fingerprint = Fingerprint::of(gps);
+        match self.entries.iter_mut().find(|(uid, _)| *uid == gps.uid) {
+            Some((_, known)) if *known == fingerprint => Seen::Duplicate,
+            Some((_, known)) => {
+                *known = fingerprint;
+                Seen::Update
+            }
+            None => {
+                self.entries.push_back((gps.uid.clone(), fingerprint));
+                if self.entries.len() > self.size {
+                    self.entries.pop_front();
+                }
+                Seen::New
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fix(uid: &str, charging: bool) -> GpsMsg {
+        GpsMsg {
+            uid: uid.to_string(),
+            charging,
+            battery_voltage: 3.7,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn cache_decides_like_the_ring() {
+        let mut cache = DedupCache::new(2);
+        assert_eq!(cache.check(&fix("a", false)), Seen::New);
+        assert_eq!(cache.check(&fix("a", false)), Seen::Duplicate);
+        assert_eq!(cache.check(&fix("a", true)), Seen::Update);
+        assert_eq!(cache.check(&fix("a", true)), Seen::Duplicate);
+        assert_eq!(cache.check(&fix("b", true)), Seen::New);
+        assert_eq!(cache.check(&fix("c", true)), Seen::New);
+        // The oldest one was forgotten
+        assert_eq!(cache.check(&fix("a", true)), Seen::New);
+    }
+
+    #[test]
+    fn fingerprints_of_fixes_ignore_the_position() {
+        let mut moved = fix("a", false);
+        moved.latitude = 52.0;
+        assert_eq!(Fingerprint::of(&moved), Fingerprint::of(&fix("a", false)));
+    }
+}
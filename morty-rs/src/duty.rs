@@ -0,0 +1,346 @@
+//! Duty-cycled listening for beacons that run on a battery, e.g. a repeater on a solar panel. Such
+//! a beacon only listens for a window of every period and keeps its radio off for the rest. The
+//! windows are aligned to the wall clock, which the beacons and the trackers share through the
+//! time beacons, see `timesync`, so a tracker can tell when a beacon listens and send then.
+//!
+//! The period is cut into slots of the length of a window, and every beacon takes the slot its
+//! MAC hashes to. Two neighbours either listen in the same slot or their windows don't overlap at
+//! all, so with a couple of repeaters in range most of them listen at different times. A beacon
+//! advertises its schedule in its BeaconPresentMsg, which it sends at the start of every window.
+//!
+//! All times are in ms since the epoch, the wall clock of the device.
+
+use crate::messages::BeaconPresentMsg;
+use std::time::Duration;
+
+const FNV_OFFSET: u32 = 0x811c_9dc5;
+const FNV_PRIME: u32 = 0x0100_0193;
+
+/// How long a beacon listens, out of every period. A window as long as the period, or longer,
+/// listens all the time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DutyCycle {
+    pub period: Duration,
+    pub window: Duration,
+}
+
+impl DutyCycle {
+    /// Whether this listens all the time.
+    pub fn always(&self) -> bool {
+        self.window.is_zero() || self.window >= self.period
+    }
+}
+
+/// Which duty cycle to use with how much battery is left. A beacon on external power, or one that
+/// can't read its battery, listens for `window` of every `period`, on a low battery for
+/// `low_battery_window`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DutyPolicy {
+    pub period: Duration,
+    pub window: Duration,
+    pub low_battery_window: Duration,
+    pub low_battery_volts: f32,
+}
+
+impl DutyPolicy {
+    /// The duty cycle for a battery at `battery_volts`, None when we don't know.
+    pub fn cycle(&self, battery_volts: Option<f32>) -> DutyCycle {
+        let window = match battery_volts {
+            Some(volts) if volts > 0.0 && volts < self.low_battery_volts => self.low_battery_window,
+            _ => self.window,
+        };
+        DutyCycle {
+            period: self.period,
+            window,
+        }
+    }
+}
+
+/// When a beacon listens: from `offset_ms` for `window_ms` of every `period_ms`, counted from the
+/// epoch. A period of 0 means it listens all the time, which is what beacons that don't
+/// advertise a schedule do.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ListenSchedule {
+    pub period_ms: u32,
+    pub window_ms: u32,
+    pub offset_ms: u32,
+}
+
+impl ListenSchedule {
+    /// Listening all the time.
+    pub const ALWAYS: ListenSchedule = ListenSchedule {
+        period_ms: 0,
+        window_ms: 0,
+        offset_ms: 0,
+    };
+
+    /// The schedule of the beacon with `mac`, listening with `cycle`. The window starts in the
+    /// slot of the period the MAC hashes to.
+    pub fn for_device(mac: &[u8; 6], cycle: DutyCycle) -> Self {
+        if cycle.always() {
+            return Self::ALWAYS;
+        }
+        let period_ms = cycle.period.as_millis().min(u32::MAX as u128) as u32;
+        let window_ms = (cycle.window.as_millis() as u32).max(1);
+        let slots = period_ms / window_ms;
+        let slot = fnv1a(mac) % slots;
+        Self {
+            period_ms,
+            window_ms,
+            offset_ms: slot * window_ms,
+        }
+    }
+
+    /// The schedule a beacon advertises, always listening for beacons that don't. A schedule that
+    /// makes no sense is taken as always listening as well.
+    pub fn from_msg(msg: &BeaconPresentMsg) -> Self {
+        let schedule = Self {
+            period_ms: msg.listen_period_ms,
+            window_ms: msg.listen_window_ms,
+            offset_ms: msg.listen_offset_ms,
+        };
+        if schedule.period_ms == 0
+            || schedule.window_ms == 0
+            || schedule.window_ms >= schedule.period_ms
+            || schedule.offset_ms >= schedule.period_ms
+        {
+            return Self::ALWAYS;
+        }
+        schedule
+    }
+
+    /// Put the schedule in a BeaconPresentMsg.
+    pub fn advertise(&self, msg: &mut BeaconPresentMsg) {
+        msg.listen_period_ms = self.period_ms;
+        msg.listen_window_ms = self.window_ms;
+        msg.listen_offset_ms = self.offset_ms;
+    }
+
+    pub fn always(&self) -> bool {
+        self.period_ms == 0
+    }
+
+    /// Whether the beacon listens at `now_ms`.
+    pub fn is_listening(&self, now_ms: i64) -> bool {
+        self.until_window(now_ms).is_zero()
+    }
+
+    /// Time until the next window starts, zero when we're in one.
+    pub fn until_window(&self, now_ms: i64) -> Duration {
+        if self.always() {
+            return Duration::ZERO;
+        }
+        let into_window = self.into_window(now_ms);
+        if into_window < self.window_ms as u64 {
+            return Duration::ZERO;
+        }
+        Duration::from_millis(self.period_ms as u64 - into_window)
+    }
+
+    /// Time left of the window we're in, zero when we're not in one. Listening all the time
+    /// never ends.
+    pub fn window_left(&self, now_ms: i64) -> Duration {
+        if self.always() {
+            return Duration::MAX;
+        }
+        let into_window = self.into_window(now_ms);
+        Duration::from_millis((self.window_ms as u64).saturating_sub(into_window))
+    }
+
+    /// Time since the start of the last window, wrapping at the period.
+    fn into_window(&self, now_ms: i64) -> u64 {
+        let period = self.period_ms as i64;
+        (now_ms - self.offset_ms as i64).rem_euclid(period) as u64
+    }
+}
+
+fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(FNV_OFFSET, |hash, &b| {
+        (hash ^ b as u32).wrapping_mul(FNV_PRIME)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    fn mac(last: u8) -> [u8; 6] {
+        [0xaa, 0xbb, 0xcc, 0xdd, 0xee, last]
+    }
+
+    // A minute, listening for 10s of it
+    fn cycle() -> DutyCycle {
+        DutyCycle {
+            period: secs(60),
+            window: secs(10),
+        }
+    }
+
+    fn policy() -> DutyPolicy {
+        DutyPolicy {
+            period: secs(60),
+            window: secs(10),
+            low_battery_window: secs(5),
+            low_battery_volts: 3.5,
+        }
+    }
+
+    fn schedule(period_ms: u32, window_ms: u32, offset_ms: u32) -> ListenSchedule {
+        ListenSchedule {
+            period_ms,
+            window_ms,
+            offset_ms,
+        }
+    }
+
+    #[test]
+    fn a_window_as_long_as_the_period_listens_all_the_time() {
+        for window in [secs(0), secs(60), secs(61)] {
+            let cycle = DutyCycle {
+                period: secs(60),
+                window,
+            };
+            assert!(cycle.always(), "{window:?}");
+            assert_eq!(
+                ListenSchedule::for_device(&mac(1), cycle),
+                ListenSchedule::ALWAYS
+            );
+        }
+        assert!(!cycle().always());
+    }
+
+    #[test]
+    fn a_low_battery_listens_less() {
+        let policy = policy();
+        assert_eq!(policy.cycle(Some(3.4)).window, secs(5));
+        assert_eq!(policy.cycle(Some(3.5)).window, secs(10));
+        assert_eq!(policy.cycle(Some(4.1)).window, secs(10));
+        // A battery we can't read
+        assert_eq!(policy.cycle(None).window, secs(10));
+        assert_eq!(policy.cycle(Some(0.0)).window, secs(10));
+        assert_eq!(policy.cycle(Some(3.4)).period, secs(60));
+    }
+
+    #[test]
+    fn the_hash_is_fnv1a() {
+        assert_eq!(fnv1a(b""), FNV_OFFSET);
+        assert_eq!(fnv1a(b"a"), 0xe40c_292c);
+        assert_eq!(fnv1a(b"foobar"), 0xbf9c_f968);
+    }
+
+    #[test]
+    fn a_device_listens_in_a_slot_of_the_period() {
+        for last in 0..=255 {
+            let schedule = ListenSchedule::for_device(&mac(last), cycle());
+            assert_eq!(schedule.period_ms, 60_000);
+            assert_eq!(schedule.window_ms, 10_000);
+            assert_eq!(schedule.offset_ms % 10_000, 0);
+            assert!(schedule.offset_ms + schedule.window_ms <= schedule.period_ms);
+            assert_eq!(ListenSchedule::for_device(&mac(last), cycle()), schedule);
+        }
+    }
+
+    #[test]
+    fn a_window_that_doesnt_divide_the_period_still_fits() {
+        let cycle = DutyCycle {
+            period: secs(60),
+            window: secs(25),
+        };
+        for last in 0..=255 {
+            let schedule = ListenSchedule::for_device(&mac(last), cycle);
+            assert!([0, 25_000].contains(&schedule.offset_ms));
+            assert!(schedule.offset_ms + schedule.window_ms <= schedule.period_ms);
+        }
+    }
+
+    #[test]
+    fn devices_are_spread_over_the_slots() {
+        let mut used = [false; 6];
+        for last in 0..64 {
+            let schedule = ListenSchedule::for_device(&mac(last), cycle());
+            used[(schedule.offset_ms / 10_000) as usize] = true;
+        }
+        assert_eq!(used, [true; 6]);
+    }
+
+    #[test]
+    fn neighbours_share_a_whole_window_or_nothing() {
+        let schedules: Vec<_> = (0..16)
+            .map(|last| ListenSchedule::for_device(&mac(last), cycle()))
+            .collect();
+        for a in &schedules {
+            for b in &schedules {
+                let overlap = (0..120)
+                    .map(|i| i * 500)
+                    .filter(|&now| a.is_listening(now) && b.is_listening(now))
+                    .count();
+                let expected = if a.offset_ms == b.offset_ms { 20 } else { 0 };
+                assert_eq!(overlap, expected, "{a:?} {b:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn the_window_repeats_every_period() {
+        let schedule = schedule(60_000, 10_000, 20_000);
+        for period in [-2, -1, 0, 1, 1000] {
+            let start = period * 60_000 + 20_000;
+            assert!(!schedule.is_listening(start - 1));
+            assert_eq!(schedule.until_window(start - 1), Duration::from_millis(1));
+            assert!(schedule.is_listening(start));
+            assert_eq!(schedule.window_left(start), secs(10));
+            assert!(schedule.is_listening(start + 9_999));
+            assert_eq!(
+                schedule.window_left(start + 9_999),
+                Duration::from_millis(1)
+            );
+            assert!(!schedule.is_listening(start + 10_000));
+            assert_eq!(schedule.window_left(start + 10_000), Duration::ZERO);
+            assert_eq!(schedule.until_window(start + 10_000), secs(50));
+        }
+    }
+
+    #[test]
+    fn always_listening_never_waits() {
+        let always = ListenSchedule::ALWAYS;
+        for now in [i64::MIN, -1, 0, 12_345, i64::MAX] {
+            assert!(always.is_listening(now));
+            assert_eq!(always.until_window(now), Duration::ZERO);
+            assert_eq!(always.window_left(now), Duration::MAX);
+        }
+    }
+
+    #[test]
+    fn a_schedule_is_advertised_and_read_back() {
+        let schedule = schedule(60_000, 10_000, 30_000);
+        let mut msg = BeaconPresentMsg::default();
+        schedule.advertise(&mut msg);
+        assert_eq!(ListenSchedule::from_msg(&msg), schedule);
+        assert_eq!(
+            ListenSchedule::from_msg(&BeaconPresentMsg::default()),
+            ListenSchedule::ALWAYS
+        );
+    }
+
+    #[test]
+    fn an_advertised_schedule_that_makes_no_sense_listens_all_the_time() {
+        for bad in [
+            schedule(60_000, 0, 0),
+            schedule(60_000, 60_000, 0),
+            schedule(60_000, 10_000, 60_000),
+            schedule(0, 10_000, 0),
+        ] {
+            let mut msg = BeaconPresentMsg::default();
+            bad.advertise(&mut msg);
+            assert_eq!(
+                ListenSchedule::from_msg(&msg),
+                ListenSchedule::ALWAYS,
+                "{bad:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,184 @@
+//! MediaTek EPO (Extended Prediction Orbit) assistance data, which gives MTK GPS modules a warm
+//! start. An EPO file is a series of 6 hour segments, each with a 60 byte record for all 32 GPS
+//! satellites. The data is sent to the module with the binary packets of the MTK protocol.
+
+// Size of the record for a single satellite and of a segment with all satellites
+pub const SV_RECORD_SIZE: usize = 60;
+pub const SEGMENT_SIZE: usize = 32 * SV_RECORD_SIZE;
+const SEGMENT_HOURS: i64 = 6;
+
+// Start of GPS time (1980-01-06) in seconds since the Unix epoch, ignoring leap seconds
+const GPS_EPOCH: i64 = 315_964_800;
+
+/// Switches the module from NMEA to the binary protocol.
+pub const PMTK_BINARY_MODE: &[u8] = b"$PMTK253,1,0*37\r\n";
+
+const PREAMBLE: [u8; 2] = [0x04, 0x24];
+const END: [u8; 2] = [0x0d, 0x0a];
+const CMD_SET_EPO_DATA: u16 = 722;
+const CMD_SET_OUTPUT_FORMAT: u16 = 253;
+// A data packet carries three satellite records
+const RECORDS_PER_PACKET: usize = 3;
+// Sequence number that marks the end of the data
+const SEQ_END: u16 = 0xffff;
+
+/// Time (seconds since the epoch) until which an EPO file is useful, None if it's not a valid
+/// EPO file.
+pub fn valid_until(data: &[u8]) -> Option<i64> {
+    if data.is_empty() || data.len() % SEGMENT_SIZE != 0 {
+        return None;
+    }
+    let segments = (data.len() / SEGMENT_SIZE) as i64;
+    Some(GPS_EPOCH + (gps_hour(data) + segments * SEGMENT_HOURS) * 3600)
+}
+
+/// The part of an EPO file that covers `now` and at most `segments - 1` segments after that, so
+/// we don't have to send data the module doesn't need yet. None if `now` isn't covered.
+pub fn current(data: &[u8], now: i64, segments: usize) -> Option<&[u8]> {
+    valid_until(data)?;
+    let start = GPS_EPOCH + gps_hour(data) * 3600;
+    if now < start {
+        return None;
+    }
+    let first = ((now - start) / (SEGMENT_HOURS * 3600)) as usize * SEGMENT_SIZE;
+    if first >= data.len() {
+        return None;
+    }
+    let last = (first + segments * SEGMENT_SIZE).min(data.len());
+    Some(&data[first..last])
+}
+
+/// Hours since the start of GPS time, at which the first segment starts.
+fn gps_hour(data: &[u8]) -> i64 {
+    data[0] as i64 | (data[1] as i64) << 8 | (data[2] as i64) << 16
+}
+
+/// The binary packets that load `data` into the module, including the one marking the end of
+/// the data. The module has to be in binary mode, see `PMTK_BINARY_MODE`.
+pub fn epo_packets(data: &[u8]) -> Vec<Vec<u8>> {
+    let mut packets: Vec<Vec<u8>> = data
+        .chunks(SV_RECORD_SIZE * RECORDS_PER_PACKET)
+        .enumerate()
+        .map(|(seq, records)| epo_packet(seq as u16, records))
+        .collect();
+    packets.push(epo_packet(SEQ_END, &[]));
+    packets
+}
+
+fn epo_packet(seq: u16, records: &[u8]) -> Vec<u8> {
+    let mut payload = vec![0u8; 2 + SV_RECORD_SIZE * RECORDS_PER_PACKET];
+    payload[0..2].copy_from_slice(&seq.to_le_bytes());
+    payload[2..2 + records.len()].copy_from_slice(records);
+    binary_packet(CMD_SET_EPO_DATA, &payload)
+}
+
+/// Switches the module back from the binary protocol to NMEA, keeping the baud rate.
+pub fn nmea_mode_packet() -> Vec<u8> {
+    binary_packet(CMD_SET_OUTPUT_FORMAT, &[0, 0, 0, 0, 0])
+}
+
+/// Preamble, length of the whole packet, command, payload, checksum and end. The checksum is the
+/// XOR of everything between the preamble and the checksum.
+fn binary_packet(command: u16, payload: &[u8]) -> Vec<u8> {
+    let len = (PREAMBLE.len() + 2 + 2 + payload.len() + 1 + END.len()) as u16;
+    let mut packet = PREAMBLE.to_vec();
+    packet.extend_from_slice(&len.to_le_bytes());
+    packet.extend_from_slice(&command.to_le_bytes());
+    packet.extend_from_slice(payload);
+    let checksum = packet[PREAMBLE.len()..].iter().fold(0, |acc, b| acc ^ b);
+    packet.push(checksum);
+    packet.extend_from_slice(&END);
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // GPS hour of the first segment, 2024-01-01 00:00 UTC
+    const HOUR: i64 = 385_848;
+    const START: i64 = GPS_EPOCH + HOUR * 3600;
+
+    // An EPO file of `segments` segments, each filled with its number, starting at one
+    fn epo_file(segments: usize) -> Vec<u8> {
+        let mut data: Vec<u8> = (0..segments * SEGMENT_SIZE)
+            .map(|i| (i / SEGMENT_SIZE) as u8 + 1)
+            .collect();
+        data[0..3].copy_from_slice(&HOUR.to_le_bytes()[0..3]);
+        data
+    }
+
+    #[test]
+    fn files_are_valid_until_their_last_segment_ends() {
+        assert_eq!(valid_until(&epo_file(1)), Some(START + 6 * 3600));
+        assert_eq!(valid_until(&epo_file(4)), Some(START + 24 * 3600));
+    }
+
+    #[test]
+    fn files_of_partial_segments_are_not_epo_files() {
+        assert_eq!(valid_until(&[]), None);
+        assert_eq!(valid_until(&epo_file(2)[1..]), None);
+        assert_eq!(current(&epo_file(2)[1..], START, 1), None);
+    }
+
+    #[test]
+    fn current_starts_at_the_segment_covering_now() {
+        let data = epo_file(4);
+        let part = current(&data, START + 7 * 3600, 2).unwrap();
+        assert_eq!(part.len(), 2 * SEGMENT_SIZE);
+        assert_eq!(part[3], 2);
+        assert_eq!(part[SEGMENT_SIZE], 3);
+
+        let part = current(&data, START, 1).unwrap();
+        assert_eq!(part.len(), SEGMENT_SIZE);
+        assert_eq!(&part[0..3], &data[0..3]);
+    }
+
+    #[test]
+    fn current_stops_at_the_end_of_the_file() {
+        let data = epo_file(4);
+        let part = current(&data, START + 19 * 3600, 4).unwrap();
+        assert_eq!(part.len(), SEGMENT_SIZE);
+        assert_eq!(part[0], 4);
+    }
+
+    #[test]
+    fn current_is_none_outside_of_the_file() {
+        let data = epo_file(4);
+        assert_eq!(current(&data, START - 1, 1), None);
+        assert_eq!(current(&data, START + 24 * 3600, 1), None);
+    }
+
+    #[test]
+    fn every_three_records_make_a_packet() {
+        let data = epo_file(1);
+        let packets = epo_packets(&data);
+        // 32 records in packets of 3, and the one marking the end
+        assert_eq!(packets.len(), 11 + 1);
+        for (seq, packet) in packets.iter().enumerate() {
+            assert_eq!(packet.len(), 191);
+            assert_eq!(&packet[0..2], &PREAMBLE);
+            assert_eq!(&packet[2..4], &191u16.to_le_bytes());
+            assert_eq!(&packet[4..6], &CMD_SET_EPO_DATA.to_le_bytes());
+            assert_eq!(&packet[189..], &END);
+            let checksum = packet[2..188].iter().fold(0, |acc, b| acc ^ b);
+            assert_eq!(packet[188], checksum);
+            if seq < 11 {
+                assert_eq!(&packet[6..8], &(seq as u16).to_le_bytes());
+            }
+        }
+        assert_eq!(&packets[0][8..188], &data[0..180]);
+        // The last packet has two records and is padded with zeroes
+        assert_eq!(&packets[10][8..128], &data[1800..1920]);
+        assert!(packets[10][128..188].iter().all(|&b| b == 0));
+        assert_eq!(&packets[11][6..8], &[0xff, 0xff]);
+    }
+
+    #[test]
+    fn the_nmea_mode_packet_matches_the_reference() {
+        assert_eq!(
+            nmea_mode_packet(),
+            [0x04, 0x24, 0x0e, 0x00, 0xfd, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xf3, 0x0d, 0x0a]
+        );
+    }
+}
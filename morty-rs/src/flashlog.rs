@@ -0,0 +1,328 @@
+//! A small event log that is kept in a flash partition, so we can find out what happened to a
+//! device after the fact. Events are written as fixed size records to a ring buffer of flash
+//! sectors. To limit flash wear, events are buffered in memory and written in batches, and
+//! events below the configured level are dropped.
+//!
+//! The log lives in a data partition with subtype 0x40 and label "eventlog". When the partition
+//! isn't there, logging is a no-op.
+
+use esp_idf_sys::esp;
+use esp_idf_sys::EspError;
+use log::*;
+use std::sync::Mutex;
+
+const PARTITION_LABEL: &[u8] = b"eventlog\0";
+const PARTITION_SUBTYPE: u32 = 0x40;
+const SECTOR_SIZE: usize = 4096;
+const RECORD_SIZE: usize = 32;
+const RECORDS_PER_SECTOR: usize = SECTOR_SIZE / RECORD_SIZE;
+const RECORD_MAGIC: u8 = 0x4d;
+// Events are written to flash once this many have been buffered
+const BATCH_SIZE: usize = 8;
+
+/// Something that happened on the device.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EventKind {
+    /// The device booted, with the reset reason reported by ESP-IDF
+    Boot { reset_reason: u32 },
+    /// The device is going to reboot, with a device specific reason
+    Reboot { reason: u32 },
+    /// A device specific state transition
+    State { state: u32 },
+    /// A device specific error
+    Error { code: u32 },
+    /// A GPS fix
+    Fix { latitude: f64, longitude: f64 },
+}
+
+impl EventKind {
+    pub fn level(&self) -> Level {
+        match self {
+            EventKind::Boot { .. } | EventKind::Reboot { .. } => Level::Warn,
+            EventKind::Error { .. } => Level::Error,
+            EventKind::State { .. } => Level::Info,
+            EventKind::Fix { .. } => Level::Debug,
+        }
+    }
+
+    fn encode(&self) -> (u8, [u8; 16]) {
+        let mut payload = [0u8; 16];
+        let kind = match *self {
+            EventKind::Boot { reset_reason } => {
+                payload[0..4].copy_from_slice(&reset_reason.to_le_bytes());
+                1
+            }
+            EventKind::Reboot { reason } => {
+                payload[0..4].copy_from_slice(&reason.to_le_bytes());
+                2
+            }
+            EventKind::State { state } => {
+                payload[0..4].copy_from_slice(&state.to_le_bytes());
+                3
+            }
+            EventKind::Error { code } => {
+                payload[0..4].copy_from_slice(&code.to_le_bytes());
+                4
+            }
+            EventKind::Fix {
+                latitude,
+                longitude,
+            } => {
+                payload[0..8].copy_from_slice(&latitude.to_le_bytes());
+                payload[8..16].copy_from_slice(&longitude.to_le_bytes());
+                5
+            }
+        };
+        (kind, payload)
+    }
+
+    fn decode(kind: u8, payload: &[u8]) -> Option<Self> {
+        let u32_at = |i: usize| u32::from_le_bytes(payload[i..i + 4].try_into().unwrap());
+        let f64_at = |i: usize| f64::from_le_bytes(payload[i..i + 8].try_into().unwrap());
+        match kind {
+            1 => Some(EventKind::Boot {
+                reset_reason: u32_at(0),
+            }),
+            2 => Some(EventKind::Reboot { reason: u32_at(0) }),
+            3 => Some(EventKind::State { state: u32_at(0) }),
+            4 => Some(EventKind::Error { code: u32_at(0) }),
+            5 => Some(EventKind::Fix {
+                latitude: f64_at(0),
+                longitude: f64_at(8),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// An event as it was read back from flash.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Event {
+    pub seq: u32,
+    /// Seconds since boot
+    pub uptime: u32,
+    pub level: Level,
+    pub kind: EventKind,
+}
+
+impl Event {
+    // Record layout: magic, level, kind, reserved, seq, uptime, 16 bytes payload, 4 reserved
+    fn encode(&self) -> [u8; RECORD_SIZE] {
+        let mut record = [0xffu8; RECORD_SIZE];
+        let (kind, payload) = self.kind.encode();
+        record[0] = RECORD_MAGIC;
+        record[1] = self.level as u8;
+        record[2] = kind;
+        record[4..8].copy_from_slice(&self.seq.to_le_bytes());
+        record[8..12].copy_from_slice(&self.uptime.to_le_bytes());
+        record[12..28].copy_from_slice(&payload);
+        record
+    }
+
+    fn decode(record: &[u8]) -> Option<Self> {
+        if record[0] != RECORD_MAGIC {
+            return None;
+        }
+        let level = match record[1] {
+            1 => Level::Error,
+            2 => Level::Warn,
+            3 => Level::Info,
+            4 => Level::Debug,
+            5 => Level::Trace,
+            _ => return None,
+        };
+        Some(Self {
+            seq: u32::from_le_bytes(record[4..8].try_into().unwrap()),
+            uptime: u32::from_le_bytes(record[8..12].try_into().unwrap()),
+            level,
+            kind: EventKind::decode(record[2], &record[12..28])?,
+        })
+    }
+}
+
+struct FlashLog {
+    partition: *const esp_idf_sys::esp_partition_t,
+    sectors: usize,
+    // Index of the next record to write, over the whole partition
+    next: usize,
+    next_seq: u32,
+    level: LevelFilter,
+    pending: Vec<Event>,
+}
+
+// The partition pointer points to a static partition table entry
+unsafe impl Send for FlashLog {}
+
+static FLASHLOG: Mutex<Option<FlashLog>> = Mutex::new(None);
+
+/// Find the event log partition and figure out where we left off. Events below `level` are
+/// dropped.
+pub fn init(level: LevelFilter) -> Result<(), EspError> {
+    let partition = unsafe {
+        esp_idf_sys::esp_partition_find_first(
+            esp_idf_sys::esp_partition_type_t_ESP_PARTITION_TYPE_DATA,
+            PARTITION_SUBTYPE as esp_idf_sys::esp_partition_subtype_t,
+            PARTITION_LABEL.as_ptr() as *const _,
+        )
+    };
+    if partition.is_null() {
+        warn!("No event log partition, not logging events");
+        return Ok(());
+    }
+
+    let sectors = unsafe { (*partition).size } as usize / SECTOR_SIZE;
+    let mut log = FlashLog {
+        partition,
+        sectors,
+        next: 0,
+        next_seq: 0,
+        level,
+        pending: Vec::new(),
+    };
+
+    // The newest record is followed by either an empty slot or an older record
+    let mut newest: Option<(usize, u32)> = None;
+    let mut record = [0u8; RECORD_SIZE];
+    for i in 0..sectors * RECORDS_PER_SECTOR {
+        log.read(i, &mut record)?;
+        if let Some(event) = Event::decode(&record) {
+            if newest.map_or(true, |(_, seq)| event.seq > seq) {
+                newest = Some((i, event.seq));
+            }
+        }
+    }
+    if let Some((i, seq)) = newest {
+        log.next = (i + 1) % (sectors * RECORDS_PER_SECTOR);
+        log.next_seq = seq + 1;
+    }
+    info!(
+        "Event log with {} sectors, next record {}",
+        sectors, log.next
+    );
+
+    *FLASHLOG.lock().unwrap() = Some(log);
+    Ok(())
+}
+
+/// Initialize the event log and record that we booted. Unless we woke up from deep sleep, the
+/// whole log is written to the console, so it can be retrieved by connecting to the device.
+pub fn start(level: LevelFilter) -> Result<(), EspError> {
+    init(level)?;
+    let reset_reason = unsafe { esp_idf_sys::esp_reset_reason() };
+    log_event(EventKind::Boot {
+        reset_reason: reset_reason as u32,
+    });
+    if reset_reason != esp_idf_sys::esp_reset_reason_t_ESP_RST_DEEPSLEEP {
+        log_dump()?;
+    }
+    Ok(())
+}
+
+/// Log an event. Events are buffered and written to flash in batches, call `flush` to write
+/// them out right away, e.g. before going to sleep.
+pub fn log_event(kind: EventKind) {
+    let mut flashlog = FLASHLOG.lock().unwrap();
+    let Some(log) = flashlog.as_mut() else {
+        return;
+    };
+    if kind.level() > log.level {
+        return;
+    }
+
+    let uptime = (unsafe { esp_idf_sys::esp_timer_get_time() } / 1_000_000) as u32;
+    let event = Event {
+        seq: log.next_seq,
+        uptime,
+        level: kind.level(),
+        kind,
+    };
+    log.next_seq += 1;
+    log.pending.push(event);
+
+    // Errors and reboots are written right away, since we might not get another chance
+    if log.pending.len() >= BATCH_SIZE || event.level <= Level::Warn {
+        if let Err(e) = log.write_pending() {
+            error!("Unable to write event log: {e}");
+        }
+    }
+}
+
+/// Write buffered events to flash.
+pub fn flush() {
+    if let Some(log) = FLASHLOG.lock().unwrap().as_mut() {
+        if let Err(e) = log.write_pending() {
+            error!("Unable to write event log: {e}");
+        }
+    }
+}
+
+/// Read all events from flash, oldest first.
+pub fn dump() -> Result<Vec<Event>, EspError> {
+    let mut flashlog = FLASHLOG.lock().unwrap();
+    let Some(log) = flashlog.as_mut() else {
+        return Ok(Vec::new());
+    };
+    log.write_pending()?;
+
+    let mut events = Vec::new();
+    let mut record = [0u8; RECORD_SIZE];
+    for i in 0..log.sectors * RECORDS_PER_SECTOR {
+        log.read(i, &mut record)?;
+        if let Some(event) = Event::decode(&record) {
+            events.push(event);
+        }
+    }
+    events.sort_by_key(|e| e.seq);
+    Ok(events)
+}
+
+/// Write all events in flash to the console.
+pub fn log_dump() -> Result<(), EspError> {
+    let events = dump()?;
+    info!("Event log, {} events:", events.len());
+    for e in events {
+        info!("#{} +{}s {} {:?}", e.seq, e.uptime, e.level, e.kind);
+    }
+    Ok(())
+}
+
+impl FlashLog {
+    fn read(&self, index: usize, record: &mut [u8; RECORD_SIZE]) -> Result<(), EspError> {
+        esp!(unsafe {
+            esp_idf_sys::esp_partition_read(
+                self.partition,
+                index * RECORD_SIZE,
+                record.as_mut_ptr() as *mut _,
+                RECORD_SIZE,
+            )
+        })
+    }
+
+    fn write_pending(&mut self) -> Result<(), EspError> {
+        let total = self.sectors * RECORDS_PER_SECTOR;
+        let pending = std::mem::take(&mut self.pending);
+        for event in pending {
+            // Starting a new sector means erasing it, which drops the oldest events
+            if self.next % RECORDS_PER_SECTOR == 0 {
+                esp!(unsafe {
+                    esp_idf_sys::esp_partition_erase_range(
+                        self.partition,
+                        self.next * RECORD_SIZE,
+                        SECTOR_SIZE,
+                    )
+                })?;
+            }
+            let record = event.encode();
+            esp!(unsafe {
+                esp_idf_sys::esp_partition_write(
+                    self.partition,
+                    self.next * RECORD_SIZE,
+                    record.as_ptr() as *const _,
+                    RECORD_SIZE,
+                )
+            })?;
+            self.next = (self.next + 1) % total;
+        }
+        Ok(())
+    }
+}
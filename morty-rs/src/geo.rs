@@ -0,0 +1,482 @@
+/// Format a coordinate in degrees and decimal minutes, e.g. "40°26.767′N 79°58.933′W".
+pub fn format_dm(lat: f64, lon: f64) -> String {
+    format!(
+        "{} {}",
+        format_dm_part(lat, 'N', 'S'),
+        format_dm_part(lon, 'E', 'W')
+    )
+}
+
+fn format_dm_part(value: f64, pos: char, neg: char) -> String {
+    let hemisphere = if value < 0.0 { neg } else { pos };
+    // Round to thousandths of a minute first, so we never end up with 60.000 minutes
+    let total = (value.abs() * 60_000.0).round() as u64;
+    let degrees = total / 60_000;
+    let minutes = (total % 60_000) as f64 / 1000.0;
+    format!("{degrees}°{minutes:06.3}′{hemisphere}")
+}
+
+const GEOHASH_BASE32: &[u8; 32] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+/// Encode a coordinate as a geohash of `precision` characters.
+pub fn geohash(lat: f64, lon: f64, precision: usize) -> String {
+    let mut lat_range = (-90.0, 90.0);
+    let mut lon_range = (-180.0, 180.0);
+    let mut hash = String::with_capacity(precision);
+    let mut even = true;
+    let mut bits = 0;
+    let mut ch = 0;
+
+    while hash.len() < precision {
+        // Even bits bisect the longitude, odd bits the latitude
+        let (range, value) = if even {
+            (&mut lon_range, lon)
+        } else {
+            (&mut lat_range, lat)
+        };
+        let mid = (range.0 + range.1) / 2.0;
+        ch <<= 1;
+        if value >= mid {
+            ch |= 1;
+            range.0 = mid;
+        } else {
+            range.1 = mid;
+        }
+        even = !even;
+
+        bits += 1;
+        if bits == 5 {
+            hash.push(GEOHASH_BASE32[ch] as char);
+            bits = 0;
+            ch = 0;
+        }
+    }
+    hash
+}
+
+/// Round a coordinate to `decimals` decimal places of a degree, e.g. 3 for about 100 m. The
+/// latitude stays within ±90°. The longitude stays within [-180°, 180°), so one that rounds up
+/// to 180° ends up at -180°, the same meridian. At the poles every longitude is the same point,
+/// so the longitude is 0 there.
+pub fn round_coordinate(lat: f64, lon: f64, decimals: u32) -> (f64, f64) {
+    let scale = 10f64.powi(decimals as i32);
+    // Adding 0 turns -0 into 0, so it isn't written as "-0"
+    let round = |value: f64| (value * scale).round() / scale + 0.0;
+    let lat = round(lat.clamp(-90.0, 90.0));
+    if lat.abs() == 90.0 {
+        return (lat, 0.0);
+    }
+    (lat, normalize_longitude(round(normalize_longitude(lon))))
+}
+
+/// `lon` within [-180°, 180°). Longitudes that already are come back as they were.
+pub fn normalize_longitude(lon: f64) -> f64 {
+    if (-180.0..180.0).contains(&lon) {
+        return lon;
+    }
+    let lon = (lon + 180.0).rem_euclid(360.0) - 180.0;
+    // rem_euclid can round up to the divisor for tiny negative values
+    if lon >= 180.0 {
+        lon - 360.0
+    } else {
+        lon
+    }
+}
+
+// Mean radius of the earth, in meters
+const EARTH_RADIUS_M: f64 = 6_371_000.0;
+
+/// Great circle distance between two coordinates, in meters.
+pub fn distance_m(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let d_lat = lat2 - lat1;
+    let d_lon = (lon2 - lon1).to_radians();
+    let a = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_M * a.sqrt().min(1.0).asin()
+}
+
+/// How `Smoother` follows the positions of a source.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Smoothing {
+    /// Exponential moving average, `alpha` is the weight of a new position, between 0 and 1. A
+    /// source moving at a steady speed lags `(1 - alpha) / alpha` of its distance between fixes.
+    Average { alpha: f64 },
+    /// Stay put while new positions are within `radius_m` of the smoothed one and move to them
+    /// when they're not, so the lag is never more than `radius_m`.
+    Snap { radius_m: f64 },
+}
+
+/// What `Smoother` does and how much it keeps.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SmoothingConfig {
+    pub smoothing: Smoothing,
+    /// A position that comes more than this many seconds after the previous one, or before it,
+    /// starts over
+    pub max_gap_secs: i64,
+    /// A position that means moving faster than this since the previous one starts over, in m/s
+    pub max_speed_mps: f64,
+    /// Most sources we smooth, the one we didn't hear from for the longest is forgotten for a new
+    /// one
+    pub max_sources: usize,
+}
+
+struct Track {
+    src: String,
+    // The smoothed position
+    lat: f64,
+    lon: f64,
+    // The last position as it came in, and its time in seconds since the epoch
+    raw_lat: f64,
+    raw_lon: f64,
+    timestamp: i64,
+}
+
+/// Smooths the positions of sources for people looking at a map, so a tracker that stands still
+/// doesn't jitter a few meters with every fix. A source starts over at its new position after a
+/// gap or a jump, see `SmoothingConfig`. Positions that are kept or analyzed should be left as
+/// they came in.
+pub struct Smoother {
+    config: SmoothingConfig,
+    tracks: Vec<Track>,
+}
+
+impl Smoother {
+    pub fn new(config: SmoothingConfig) -> Self {
+        Self {
+            config,
+            tracks: Vec::new(),
+        }
+    }
+
+    /// The smoothed position of `src` after its position `lat`, `lon` at `timestamp`, in seconds
+    /// since the epoch.
+    pub fn smooth(&mut self, src: &str, lat: f64, lon: f64, timestamp: i64) -> (f64, f64) {
+        let Some(track) = self.tracks.iter_mut().find(|t| t.src == src) else {
+            if self.tracks.len() >= self.config.max_sources {
+                let oldest = (0..self.tracks.len()).min_by_key(|&i| self.tracks[i].timestamp);
+                if let Some(oldest) = oldest {
+                    self.tracks.swap_remove(oldest);
+                }
+            }
+            if self.config.max_sources > 0 {
+                self.tracks.push(Track {
+                    src: src.to_string(),
+                    lat,
+                    lon,
+                    raw_lat: lat,
+                    raw_lon: lon,
+                    timestamp,
+                });
+            }
+            return (lat, lon);
+        };
+
+        let elapsed = timestamp - track.timestamp;
+        let moved = distance_m(track.raw_lat, track.raw_lon, lat, lon);
+        // The same fix again, e.g. a retransmission, doesn't pull any harder
+        if elapsed == 0 && moved == 0.0 {
+            return (track.lat, track.lon);
+        }
+        let jumped = elapsed > 0 && moved / elapsed as f64 > self.config.max_speed_mps;
+        (track.raw_lat, track.raw_lon, track.timestamp) = (lat, lon, timestamp);
+        if elapsed < 0 || elapsed > self.config.max_gap_secs || jumped {
+            (track.lat, track.lon) = (lat, lon);
+            return (lat, lon);
+        }
+
+        match self.config.smoothing {
+            Smoothing::Average { alpha } => {
+                let alpha = alpha.clamp(0.0, 1.0);
+                track.lat += alpha * (lat - track.lat);
+                // The short way around, also across the antimeridian
+                let d_lon = normalize_longitude(lon - track.lon);
+                track.lon = normalize_longitude(track.lon + alpha * d_lon);
+            }
+            Smoothing::Snap { radius_m } => {
+                if distance_m(track.lat, track.lon, lat, lon) > radius_m {
+                    (track.lat, track.lon) = (lat, lon);
+                }
+            }
+        }
+        (track.lat, track.lon)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn degrees_minutes_have_hemispheres() {
+        assert_eq!(format_dm(40.446195, -79.948862), "40°26.772′N 79°56.932′W");
+        assert_eq!(
+            format_dm(-33.856784, 151.215297),
+            "33°51.407′S 151°12.918′E"
+        );
+        assert_eq!(format_dm(0.0, 0.0), "0°00.000′N 0°00.000′E");
+        assert_eq!(format_dm(-90.0, -180.0), "90°00.000′S 180°00.000′W");
+    }
+
+    #[test]
+    fn degrees_minutes_never_have_sixty_minutes() {
+        // 59.9999 minutes rounds up to the next degree
+        assert_eq!(format_dm(51.999999, 4.0000001), "52°00.000′N 4°00.000′E");
+        assert_eq!(format_dm(0.0083333, -0.0000001), "0°00.500′N 0°00.000′W");
+    }
+
+    #[test]
+    fn geohashes_match_the_reference() {
+        assert_eq!(geohash(57.64911, 10.40744, 11), "u4pruydqqvj");
+        assert_eq!(geohash(42.6, -5.6, 5), "ezs42");
+        assert_eq!(geohash(-25.382708, -49.265506, 12), "6gkzwgjzn820");
+        assert_eq!(geohash(0.0, 0.0, 4), "s000");
+        assert_eq!(geohash(-90.0, -180.0, 3), "000");
+        assert_eq!(geohash(90.0, 180.0, 3), "zzz");
+        assert_eq!(geohash(51.5, 0.0, 0), "");
+    }
+
+    #[test]
+    fn shorter_geohashes_are_prefixes() {
+        let hash = geohash(52.37403, 4.88969, 12);
+        for precision in 1..12 {
+            assert_eq!(geohash(52.37403, 4.88969, precision), hash[..precision]);
+        }
+    }
+
+    #[test]
+    fn coordinates_are_rounded_to_decimals() {
+        assert_eq!(round_coordinate(52.37403, 4.88969, 3), (52.374, 4.89));
+        assert_eq!(round_coordinate(52.37403, 4.88969, 2), (52.37, 4.89));
+        assert_eq!(
+            round_coordinate(-33.86785, 151.20732, 3),
+            (-33.868, 151.207)
+        );
+        assert_eq!(round_coordinate(-33.86785, 151.20732, 2), (-33.87, 151.21));
+    }
+
+    #[test]
+    fn rounding_wraps_at_the_antimeridian() {
+        assert_eq!(round_coordinate(0.0, 179.9996, 3), (0.0, -180.0));
+        assert_eq!(round_coordinate(0.0, -179.9996, 3), (0.0, -180.0));
+        assert_eq!(round_coordinate(0.0, 179.9994, 3), (0.0, 179.999));
+        assert_eq!(round_coordinate(0.0, 190.0, 2), (0.0, -170.0));
+        assert_eq!(round_coordinate(0.0, -190.0, 2), (0.0, 170.0));
+    }
+
+    #[test]
+    fn rounding_keeps_the_poles() {
+        assert_eq!(round_coordinate(89.9996, 10.0, 3), (90.0, 0.0));
+        assert_eq!(round_coordinate(-89.9996, -10.0, 3), (-90.0, 0.0));
+        assert_eq!(round_coordinate(95.0, 10.0, 3), (90.0, 0.0));
+        assert_eq!(round_coordinate(89.9994, 10.0, 3), (89.999, 10.0));
+    }
+
+    #[test]
+    fn rounding_doesnt_give_negative_zero() {
+        let (lat, lon) = round_coordinate(-0.0001, -0.0001, 3);
+        assert!(lat == 0.0 && lat.is_sign_positive());
+        assert!(lon == 0.0 && lon.is_sign_positive());
+    }
+
+    #[test]
+    fn longitudes_are_normalized() {
+        assert_eq!(normalize_longitude(179.99), 179.99);
+        assert_eq!(normalize_longitude(-180.0), -180.0);
+        assert_eq!(normalize_longitude(180.0), -180.0);
+        assert_eq!(normalize_longitude(360.0), 0.0);
+        assert_eq!(normalize_longitude(540.0), -180.0);
+        assert_eq!(normalize_longitude(-181.0), 179.0);
+        // Just below -180°, where rem_euclid rounds up to 360
+        let lon = normalize_longitude(f64::from_bits((-180f64).to_bits() + 1));
+        assert!((-180.0..180.0).contains(&lon), "{lon}");
+    }
+
+    const LAT: f64 = 52.37;
+    const LON: f64 = 4.89;
+    // Meters in a degree of latitude
+    const M_PER_DEGREE: f64 = EARTH_RADIUS_M * std::f64::consts::PI / 180.0;
+
+    fn smoother(smoothing: Smoothing) -> Smoother {
+        Smoother::new(SmoothingConfig {
+            smoothing,
+            max_gap_secs: 600,
+            max_speed_mps: 70.0,
+            max_sources: 4,
+        })
+    }
+
+    // `north` and `east` meters from LAT, LON
+    fn offset(north: f64, east: f64) -> (f64, f64) {
+        let lon_scale = LAT.to_radians().cos();
+        (
+            LAT + north / M_PER_DEGREE,
+            LON + east / (M_PER_DEGREE * lon_scale),
+        )
+    }
+
+    // Noise of up to `meters` in both directions, the same in every run
+    fn noise(n: usize, meters: f64) -> Vec<(f64, f64)> {
+        let mut state = 0x2545_f491_u32;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            (state as f64 / u32::MAX as f64 * 2.0 - 1.0) * meters
+        };
+        (0..n).map(|_| offset(next(), next())).collect()
+    }
+
+    fn distance_from_start(&(lat, lon): &(f64, f64)) -> f64 {
+        distance_m(LAT, LON, lat, lon)
+    }
+
+    #[test]
+    fn distances_are_great_circles() {
+        assert_eq!(distance_m(LAT, LON, LAT, LON), 0.0);
+        assert!((distance_m(0.0, 0.0, 1.0, 0.0) - M_PER_DEGREE).abs() < 1e-6);
+        assert!((distance_m(0.0, 0.0, 0.0, 1.0) - M_PER_DEGREE).abs() < 1e-6);
+        // Across the antimeridian, and halfway around the earth
+        assert!((distance_m(0.0, 179.5, 0.0, -179.5) - M_PER_DEGREE).abs() < 1e-6);
+        let half = distance_m(0.0, 0.0, 0.0, 180.0);
+        assert!((half - EARTH_RADIUS_M * std::f64::consts::PI).abs() < 1e-6);
+        let (lat, lon) = offset(300.0, 400.0);
+        assert!((distance_m(LAT, LON, lat, lon) - 500.0).abs() < 0.5);
+    }
+
+    #[test]
+    fn a_tracker_that_stands_still_doesnt_jitter_with_snap() {
+        let mut smoother = smoother(Smoothing::Snap { radius_m: 15.0 });
+        let fixes = noise(100, 5.0);
+        let smoothed: Vec<_> = (fixes.iter().enumerate())
+            .map(|(i, &(lat, lon))| smoother.smooth("a", lat, lon, i as i64 * 30))
+            .collect();
+        assert!(smoothed.iter().all(|&p| p == smoothed[0]));
+    }
+
+    #[test]
+    fn an_average_damps_the_jitter_of_a_tracker_that_stands_still() {
+        let mut smoother = smoother(Smoothing::Average { alpha: 0.2 });
+        let fixes = noise(200, 5.0);
+        let smoothed: Vec<_> = (fixes.iter().enumerate())
+            .map(|(i, &(lat, lon))| smoother.smooth("a", lat, lon, i as i64 * 30))
+            .collect();
+        let worst = |positions: &[(f64, f64)]| {
+            positions[20..]
+                .iter()
+                .map(distance_from_start)
+                .fold(0.0, f64::max)
+        };
+        assert!(worst(&fixes) > 5.0);
+        assert!(worst(&smoothed) < 3.0, "{}", worst(&smoothed));
+    }
+
+    // Lag of the smoothed positions of a tracker heading north at 10 m/s, with a fix every 10 s
+    fn lags(smoothing: Smoothing) -> Vec<f64> {
+        let mut smoother = smoother(smoothing);
+        (0..60)
+            .map(|i| {
+                let (lat, lon) = offset(i as f64 * 100.0, 0.0);
+                let smoothed = smoother.smooth("a", lat, lon, i * 10);
+                distance_m(lat, lon, smoothed.0, smoothed.1)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn an_average_lags_a_moving_tracker_by_a_bounded_distance() {
+        // (1 - alpha) / alpha of the 100 m between fixes
+        for (alpha, bound) in [(0.5, 100.0), (0.25, 300.0), (1.0, 0.0)] {
+            let lags = lags(Smoothing::Average { alpha });
+            assert!(lags.iter().all(|&lag| lag <= bound + 0.5), "{alpha}");
+            assert!((lags[59] - bound).abs() < 0.5, "{alpha} {}", lags[59]);
+        }
+    }
+
+    #[test]
+    fn snap_lags_a_moving_tracker_by_at_most_its_radius() {
+        // Fixes further apart than the radius are followed right away
+        assert!(lags(Smoothing::Snap { radius_m: 50.0 })
+            .iter()
+            .all(|&lag| lag == 0.0));
+
+        // A tracker that creeps along is left behind by at most the radius
+        let mut smoother = smoother(Smoothing::Snap { radius_m: 25.0 });
+        for i in 0..100 {
+            let (lat, lon) = offset(i as f64 * 4.0, 0.0);
+            let smoothed = smoother.smooth("a", lat, lon, i * 10);
+            assert!(distance_m(lat, lon, smoothed.0, smoothed.1) <= 25.0, "{i}");
+        }
+    }
+
+    #[test]
+    fn gaps_jumps_and_going_back_in_time_start_over() {
+        let mut smoother = smoother(Smoothing::Average { alpha: 0.1 });
+        let (lat, lon) = offset(0.0, 0.0);
+        smoother.smooth("a", lat, lon, 1000);
+        // 1 km in 601 s, slow enough, but after too long a gap
+        let far = offset(1000.0, 0.0);
+        assert_eq!(smoother.smooth("a", far.0, far.1, 1601), far);
+        // 1 km in 10 s is faster than we believe
+        let back = offset(0.0, 0.0);
+        assert_eq!(smoother.smooth("a", back.0, back.1, 1611), back);
+        // Before the last one
+        assert_eq!(smoother.smooth("a", far.0, far.1, 1600), far);
+        // And then it's smoothed again
+        let next = offset(1010.0, 0.0);
+        assert_ne!(smoother.smooth("a", next.0, next.1, 1610), next);
+    }
+
+    #[test]
+    fn the_same_fix_again_doesnt_pull_harder() {
+        let mut smoother = smoother(Smoothing::Average { alpha: 0.5 });
+        smoother.smooth("a", LAT, LON, 0);
+        let (lat, lon) = offset(10.0, 0.0);
+        let once = smoother.smooth("a", lat, lon, 10);
+        assert_eq!(smoother.smooth("a", lat, lon, 10), once);
+        assert_eq!(smoother.smooth("a", lat, lon, 10), once);
+    }
+
+    #[test]
+    fn sources_are_smoothed_apart() {
+        let mut smoother = smoother(Smoothing::Snap { radius_m: 15.0 });
+        let (lat, lon) = offset(10.0, 0.0);
+        smoother.smooth("a", LAT, LON, 0);
+        smoother.smooth("b", lat, lon, 0);
+        assert_eq!(smoother.smooth("a", lat, lon, 10), (LAT, LON));
+        assert_eq!(smoother.smooth("b", LAT, LON, 10), (lat, lon));
+    }
+
+    #[test]
+    fn the_source_heard_from_longest_ago_is_forgotten() {
+        let mut smoother = smoother(Smoothing::Snap { radius_m: 15.0 });
+        for (i, src) in ["a", "b", "c", "d"].iter().enumerate() {
+            smoother.smooth(src, LAT, LON, 100 - i as i64);
+        }
+        // "d" is the oldest, and makes room for "e"
+        smoother.smooth("e", LAT, LON, 200);
+        assert_eq!(smoother.tracks.len(), 4);
+        let (lat, lon) = offset(10.0, 0.0);
+        assert_eq!(smoother.smooth("a", lat, lon, 210), (LAT, LON));
+        assert_eq!(smoother.smooth("d", lat, lon, 210), (lat, lon));
+    }
+
+    #[test]
+    fn without_room_for_sources_nothing_is_smoothed() {
+        let mut smoother = Smoother::new(SmoothingConfig {
+            max_sources: 0,
+            ..smoother(Smoothing::Snap { radius_m: 15.0 }).config
+        });
+        let (lat, lon) = offset(10.0, 0.0);
+        assert_eq!(smoother.smooth("a", LAT, LON, 0), (LAT, LON));
+        assert_eq!(smoother.smooth("a", lat, lon, 10), (lat, lon));
+        assert!(smoother.tracks.is_empty());
+    }
+
+    #[test]
+    fn an_average_takes_the_short_way_across_the_antimeridian() {
+        let mut smoother = smoother(Smoothing::Average { alpha: 0.5 });
+        smoother.smooth("a", 0.0, 179.9999, 0);
+        let (_, lon) = smoother.smooth("a", 0.0, -179.9999, 10);
+        assert!(lon.abs() > 179.9999 - 1e-9, "{lon}");
+    }
+}
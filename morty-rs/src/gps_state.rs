@@ -0,0 +1,155 @@
+//! What the GPS module gave us since the tracker woke up, see GpsState in `morty.proto`. A module
+//! that lost its power or its UART sends nothing, or nothing we can parse, while a module without
+//! a view of the sky sends sentences without a fix. Both used to look like the same report
+//! without a fix. The tracker counts the bytes and the valid sentences it got, and the state is
+//! derived from those, the quality of the last GGA sentence and the fix mode of the last GSA
+//! sentence.
+
+use crate::messages::GpsState;
+
+/// What came in over the UART of the GPS module, since we woke up.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SentenceCounts {
+    pub bytes: u32,
+    /// Sentences that parsed, of any type
+    pub sentences: u32,
+}
+
+/// The state of the GPS module. Bytes without a valid sentence, e.g. at the wrong baud rate, are
+/// no data as well. `fix_quality` and `satellites` are from the last GGA sentence,
+/// `gsa_mode` is the fix mode of the last GSA sentence, if there was one. Without a GSA sentence,
+/// a fix with fewer than 4 satellites is taken to be 2D.
+pub fn gps_state(
+    counts: SentenceCounts,
+    fix_quality: i32,
+    satellites: i32,
+    gsa_mode: Option<u8>,
+) -> GpsState {
+    if counts.sentences == 0 {
+        return GpsState::NoData;
+    }
+    if fix_quality <= 0 {
+        return GpsState::DataNoFix;
+    }
+    match gsa_mode {
+        Some(3) => GpsState::Fix3d,
+        Some(2) => GpsState::Fix2d,
+        // No GSA sentence, or one that says there's no fix while GGA says there is
+        _ if satellites >= 4 => GpsState::Fix3d,
+        _ => GpsState::Fix2d,
+    }
+}
+
+/// The fix mode of a GSA sentence like "$GPGSA,A,3,04,05,...*6E": 1 for no fix, 2 for 2D and 3
+/// for 3D. None for other sentences and GSA sentences without a mode.
+pub fn gsa_fix_mode(sentence: &str) -> Option<u8> {
+    let mut fields = sentence.trim_end().split(',');
+    let talker = fields.next()?;
+    if talker.len() != 6 || !talker.starts_with('$') || !talker.ends_with("GSA") {
+        return None;
+    }
+    fields
+        .nth(1)?
+        .parse()
+        .ok()
+        .filter(|mode| (1..=3).contains(mode))
+}
+
+/// Name of a GPS state, for logs and uploads.
+pub fn state_name(state: i32) -> &'static str {
+    match GpsState::from_i32(state) {
+        Some(GpsState::NoData) => "no_data",
+        Some(GpsState::DataNoFix) => "no_fix",
+        Some(GpsState::Fix2d) => "fix_2d",
+        Some(GpsState::Fix3d) => "fix_3d",
+        _ => "unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counts(bytes: u32, sentences: u32) -> SentenceCounts {
+        SentenceCounts { bytes, sentences }
+    }
+
+    #[test]
+    fn a_silent_module_has_no_data() {
+        assert_eq!(gps_state(counts(0, 0), 0, 0, None), GpsState::NoData);
+        // At the wrong baud rate
+        assert_eq!(gps_state(counts(4096, 0), 0, 0, None), GpsState::NoData);
+        // Whatever the GGA fields say
+        assert_eq!(gps_state(counts(0, 0), 1, 8, Some(3)), GpsState::NoData);
+    }
+
+    #[test]
+    fn sentences_without_a_fix_are_data_without_a_fix() {
+        assert_eq!(gps_state(counts(512, 6), 0, 0, None), GpsState::DataNoFix);
+        assert_eq!(
+            gps_state(counts(512, 6), 0, 3, Some(1)),
+            GpsState::DataNoFix
+        );
+        // GSA alone doesn't make a fix
+        assert_eq!(
+            gps_state(counts(512, 6), 0, 8, Some(3)),
+            GpsState::DataNoFix
+        );
+        assert_eq!(gps_state(counts(512, 6), -1, 0, None), GpsState::DataNoFix);
+    }
+
+    #[test]
+    fn gsa_decides_the_dimension() {
+        assert_eq!(gps_state(counts(512, 6), 1, 3, Some(3)), GpsState::Fix3d);
+        assert_eq!(gps_state(counts(512, 6), 1, 8, Some(2)), GpsState::Fix2d);
+        assert_eq!(gps_state(counts(512, 6), 2, 8, Some(3)), GpsState::Fix3d);
+    }
+
+    #[test]
+    fn without_gsa_satellites_decide_the_dimension() {
+        assert_eq!(gps_state(counts(512, 6), 1, 3, None), GpsState::Fix2d);
+        assert_eq!(gps_state(counts(512, 6), 1, 4, None), GpsState::Fix3d);
+        // A GSA sentence without a fix while GGA has one
+        assert_eq!(gps_state(counts(512, 6), 1, 4, Some(1)), GpsState::Fix3d);
+        assert_eq!(gps_state(counts(512, 6), 1, 3, Some(1)), GpsState::Fix2d);
+    }
+
+    #[test]
+    fn gsa_sentences_have_a_fix_mode() {
+        let sentence = "$GPGSA,A,3,04,05,,09,12,,,24,,,,,2.5,1.3,2.1*39";
+        assert_eq!(gsa_fix_mode(sentence), Some(3));
+        assert_eq!(
+            gsa_fix_mode("$GNGSA,M,2,04,05,,,,,,,,,,,2.5,1.3,2.1*39\r\n"),
+            Some(2)
+        );
+        assert_eq!(gsa_fix_mode("$BDGSA,A,1,,,,,,,,,,,,,,,*0F"), Some(1));
+    }
+
+    #[test]
+    fn other_sentences_have_no_fix_mode() {
+        for sentence in [
+            "",
+            "$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47",
+            "$GPGSV,3,1,11,03,03,111,00*74",
+            "GPGSA,A,3,04",
+            "$GPGSAX,A,3,04",
+            // Without a mode, or with one that doesn't exist
+            "$GPGSA,A",
+            "$GPGSA,A,,04",
+            "$GPGSA,A,0,04",
+            "$GPGSA,A,4,04",
+        ] {
+            assert_eq!(gsa_fix_mode(sentence), None, "{sentence:?}");
+        }
+    }
+
+    #[test]
+    fn states_have_names() {
+        assert_eq!(state_name(GpsState::Unknown as i32), "unknown");
+        assert_eq!(state_name(GpsState::NoData as i32), "no_data");
+        assert_eq!(state_name(GpsState::DataNoFix as i32), "no_fix");
+        assert_eq!(state_name(GpsState::Fix2d as i32), "fix_2d");
+        assert_eq!(state_name(GpsState::Fix3d as i32), "fix_3d");
+        assert_eq!(state_name(42), "unknown");
+    }
+}
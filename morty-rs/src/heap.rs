@@ -0,0 +1,252 @@
+//! Keeps an eye on the free heap. When it runs low, the device sheds load, and when it stays
+//! critically low for a while, we reboot cleanly instead of limping along with failing
+//! allocations. What shedding load means is up to the device, see `HeapActions`.
+
+use crate::flashlog;
+use crate::flashlog::EventKind;
+use crate::led::colors;
+use crate::led::LedHandle;
+use crate::ledlog;
+use crate::utils::format_bytes;
+use crate::utils::format_duration;
+use crate::utils::spawn_thread;
+use crate::utils::ThreadConfig;
+use log::*;
+use std::time::Duration;
+use std::time::Instant;
+
+pub const REBOOT_REASON_LOW_HEAP: u32 = 101;
+
+const WARNING_BRIGHTNESS: u8 = 10;
+const WARNING_BLINK_PERIOD: Duration = Duration::from_millis(300);
+
+/// Free heap levels, in bytes, at which we act.
+#[derive(Clone, Copy, Debug)]
+pub struct HeapThresholds {
+    /// Below this we warn and shed load
+    pub warning: usize,
+    /// Below this for `critical_timeout` we reboot
+    pub critical: usize,
+    /// The heap has to get this far above a threshold before we consider it recovered, so a
+    /// heap that hovers around a threshold doesn't flip back and forth
+    pub hysteresis: usize,
+    pub critical_timeout: Duration,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeapState {
+    Normal,
+    Low,
+    Critical,
+}
+
+/// What should happen after a heap reading.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeapAction {
+    /// The heap dropped below the warning threshold, start shedding load
+    Shed,
+    /// The heap recovered, stop shedding load
+    Recover,
+    /// The heap has been critically low for too long
+    Reboot,
+}
+
+/// The state machine behind the heap guard. It's fed heap readings and the monotonic time at
+/// which they were taken, and tells what to do.
+pub struct HeapMonitor {
+    thresholds: HeapThresholds,
+    state: HeapState,
+    critical_since: Option<Duration>,
+}
+
+impl HeapMonitor {
+    pub fn new(thresholds: HeapThresholds) -> Self {
+        Self {
+            thresholds,
+            state: HeapState::Normal,
+            critical_since: None,
+        }
+    }
+
+    pub fn state(&self) -> HeapState {
+        self.state
+    }
+
+    /// Handle a reading of `free` bytes at `now`.
+    pub fn update(&mut self, free: usize, now: Duration) -> Option<HeapAction> {
+        let t = &self.thresholds;
+        let previous = self.state;
+
+        self.state = match self.state {
+            HeapState::Normal if free < t.critical => HeapState::Critical,
+            HeapState::Normal if free < t.warning => HeapState::Low,
+            HeapState::Normal => HeapState::Normal,
+            HeapState::Low | HeapState::Critical if free >= t.warning + t.hysteresis => {
+                HeapState::Normal
+            }
+            HeapState::Low if free < t.critical => HeapState::Critical,
+            HeapState::Critical if free >= t.critical + t.hysteresis => HeapState::Low,
+            state => state,
+        };
+
+        if self.state == HeapState::Critical {
+            let since = *self.critical_since.get_or_insert(now);
+            if now.saturating_sub(since) >= t.critical_timeout {
+                return Some(HeapAction::Reboot);
+            }
+        } else {
+            self.critical_since = None;
+        }
+
+        match (previous, self.state) {
+            (HeapState::Normal, HeapState::Low | HeapState::Critical) => Some(HeapAction::Shed),
+            (HeapState::Low | HeapState::Critical, HeapState::Normal) => Some(HeapAction::Recover),
+            _ => None,
+        }
+    }
+}
+
+/// What a device does when the heap runs low.
+pub trait HeapActions: Send {
+    /// Stop (`true`) or resume (`false`) whatever can be done without.
+    fn shed_load(&mut self, shed: bool);
+
+    /// Write out whatever should survive the reboot that follows.
+    fn flush(&mut self) {}
+}
+
+/// Free heap in bytes.
+pub fn free_heap() -> usize {
+    unsafe { esp_idf_sys::esp_get_free_heap_size() as usize }
+}
+
+/// Check the free heap every `interval` from a thread of its own. While the heap is low, the LED
+/// blinks magenta.
+pub fn start_heap_guard(
+    thresholds: HeapThresholds,
+    interval: Duration,
+    led: LedHandle,
+    mut actions: Box<dyn HeapActions>,
+    thread: ThreadConfig,
+) -> Result<(), anyhow::Error> {
+    spawn_thread(thread, move || {
+        let start = Instant::now();
+        let mut monitor = HeapMonitor::new(thresholds);
+        loop {
+            let free = free_heap();
+            match monitor.update(free, start.elapsed()) {
+                Some(HeapAction::Shed) => {
+                    warn!(
+                        "Free heap is low ({}), shedding load",
+                        format_bytes(free as u64)
+                    );
+                    actions.shed_load(true);
+                }
+                Some(HeapAction::Recover) => {
+                    info!("Free heap recovered ({})", format_bytes(free as u64));
+                    actions.shed_load(false);
+                }
+                Some(HeapAction::Reboot) => {
+                    error!(
+                        "Free heap critically low ({}) for {}, rebooting",
+                        format_bytes(free as u64),
+                        format_duration(thresholds.critical_timeout)
+                    );
+                    actions.flush();
+                    reboot();
+                }
+                None => {}
+            }
+
+            if monitor.state() != HeapState::Normal {
+                if let Err(e) =
+                    led.blink_color(colors::MAGENTA, WARNING_BRIGHTNESS, WARNING_BLINK_PERIOD, 2)
+                {
+                    error!("Unable to blink LED: {e}");
+                }
+            }
+            std::thread::sleep(interval);
+        }
+    })?;
+    Ok(())
+}
+
+fn reboot() -> ! {
+    flashlog::log_event(EventKind::Reboot {
+        reason: REBOOT_REASON_LOW_HEAP,
+    });
+    flashlog::flush();
+    ledlog::save();
+    // Give the device specific flush and the log a moment to go out
+    std::thread::sleep(Duration::from_millis(200));
+    unsafe { esp_idf_sys::esp_restart() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THRESHOLDS: HeapThresholds = HeapThresholds {
+        warning: 40_000,
+        critical: 20_000,
+        hysteresis: 5_000,
+        critical_timeout: Duration::from_secs(30),
+    };
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn load_is_shed_below_the_warning_threshold() {
+        let mut monitor = HeapMonitor::new(THRESHOLDS);
+        assert_eq!(monitor.update(50_000, secs(0)), None);
+        assert_eq!(monitor.update(40_000, secs(1)), None);
+        assert_eq!(monitor.update(39_999, secs(2)), Some(HeapAction::Shed));
+        assert_eq!(monitor.state(), HeapState::Low);
+        assert_eq!(monitor.update(30_000, secs(3)), None);
+    }
+
+    #[test]
+    fn recovering_takes_the_hysteresis() {
+        let mut monitor = HeapMonitor::new(THRESHOLDS);
+        monitor.update(39_000, secs(0));
+        assert_eq!(monitor.update(41_000, secs(1)), None);
+        assert_eq!(monitor.update(44_999, secs(2)), None);
+        assert_eq!(monitor.state(), HeapState::Low);
+        assert_eq!(monitor.update(45_000, secs(3)), Some(HeapAction::Recover));
+        assert_eq!(monitor.state(), HeapState::Normal);
+    }
+
+    #[test]
+    fn a_heap_that_stays_critical_reboots() {
+        let mut monitor = HeapMonitor::new(THRESHOLDS);
+        assert_eq!(monitor.update(10_000, secs(100)), Some(HeapAction::Shed));
+        assert_eq!(monitor.state(), HeapState::Critical);
+        assert_eq!(monitor.update(10_000, secs(129)), None);
+        assert_eq!(monitor.update(10_000, secs(130)), Some(HeapAction::Reboot));
+    }
+
+    #[test]
+    fn leaving_critical_restarts_the_timeout() {
+        let mut monitor = HeapMonitor::new(THRESHOLDS);
+        monitor.update(30_000, secs(0));
+        assert_eq!(monitor.update(10_000, secs(10)), None);
+        // Within the hysteresis it stays critical
+        assert_eq!(monitor.update(24_999, secs(20)), None);
+        assert_eq!(monitor.state(), HeapState::Critical);
+        assert_eq!(monitor.update(25_000, secs(30)), None);
+        assert_eq!(monitor.state(), HeapState::Low);
+        assert_eq!(monitor.update(10_000, secs(50)), None);
+        assert_eq!(monitor.update(10_000, secs(79)), None);
+        assert_eq!(monitor.update(10_000, secs(80)), Some(HeapAction::Reboot));
+    }
+
+    #[test]
+    fn a_critical_heap_can_recover_at_once() {
+        let mut monitor = HeapMonitor::new(THRESHOLDS);
+        monitor.update(10_000, secs(0));
+        assert_eq!(monitor.update(45_000, secs(1)), Some(HeapAction::Recover));
+        assert_eq!(monitor.state(), HeapState::Normal);
+    }
+}
@@ -0,0 +1,233 @@
+//! The HelloMsg every device broadcasts when it boots, so the beacons and the gateway log a power
+//! cycle, a reset or an OTA update right away. Trackers don't send one when they wake up from deep
+//! sleep. Beacons relay hellos like fixes, the gateway uploads them and keeps the last one of every
+//! device as what it knows about its firmware and capabilities.
+//!
+//! After a power cut every device of a site boots at the same time, so beacons and the gateway let
+//! through at most one hello per source per `HELLO_INTERVAL`, see `HelloLimiter`. Times are
+//! monotonic and passed in by the caller.
+
+use crate::comm::own_mac;
+use crate::messages::HelloMsg;
+use crate::messages::Role;
+use esp_idf_sys::EspError;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Hellos of a source that come in within this long of the last one are dropped.
+pub const HELLO_INTERVAL: Duration = Duration::from_secs(60);
+
+// Bits of HelloMsg.capabilities
+/// Reports fixes.
+pub const CAP_GPS: u32 = 1 << 0;
+/// Relays what it hears to a gateway.
+pub const CAP_RELAY: u32 = 1 << 1;
+/// Executes commands from the backend.
+pub const CAP_COMMANDS: u32 = 1 << 2;
+/// Takes GPS assistance data, see `transfer`.
+pub const CAP_ASSIST: u32 = 1 << 3;
+/// Sends frames with a `FrameHeader`.
+pub const CAP_FRAME_HEADER: u32 = 1 << 4;
+/// Has a backup battery it can tell us about, see `power`.
+pub const CAP_BACKUP_BATTERY: u32 = 1 << 5;
+/// Updates itself over the air.
+pub const CAP_OTA: u32 = 1 << 6;
+
+const CAPABILITY_NAMES: [(u32, &str); 7] = [
+    (CAP_GPS, "gps"),
+    (CAP_RELAY, "relay"),
+    (CAP_COMMANDS, "commands"),
+    (CAP_ASSIST, "assist"),
+    (CAP_FRAME_HEADER, "frame_header"),
+    (CAP_BACKUP_BATTERY, "backup_battery"),
+    (CAP_OTA, "ota"),
+];
+
+/// The hello of this device, which calls itself `name`, see `naming`.
+pub fn hello(
+    role: Role,
+    firmware_version: &str,
+    capabilities: u32,
+    config_version: u32,
+    name: &str,
+) -> Result<HelloMsg, EspError> {
+    Ok(HelloMsg {
+        device_id: own_mac()?,
+        role: role as i32,
+        firmware_version: firmware_version.to_string(),
+        reset_reason: reset_reason(),
+        capabilities,
+        config_version,
+        name: name.to_string(),
+    })
+}
+
+/// Why we booted, as an esp_reset_reason_t.
+pub fn reset_reason() -> u32 {
+    unsafe { esp_idf_sys::esp_reset_reason() as u32 }
+}
+
+/// Whether we booted, rather than woke up from deep sleep.
+pub fn booted() -> bool {
+    reset_reason() != esp_idf_sys::esp_reset_reason_t_ESP_RST_DEEPSLEEP
+}
+
+/// Name of an esp_reset_reason_t.
+pub fn reset_reason_name(reason: u32) -> &'static str {
+    match reason {
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_POWERON => "power_on",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_EXT => "external",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_SW => "software",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_PANIC => "panic",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_INT_WDT => "interrupt_watchdog",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_TASK_WDT => "task_watchdog",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_WDT => "watchdog",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_DEEPSLEEP => "deep_sleep",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_BROWNOUT => "brownout",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_SDIO => "sdio",
+        _ => "unknown",
+    }
+}
+
+/// Name of a Role, as in the proto.
+pub fn role_name(role: i32) -> &'static str {
+    match Role::from_i32(role) {
+        Some(Role::Tracker) => "tracker",
+        Some(Role::Beacon) => "beacon",
+        Some(Role::GatewayCombo) => "gateway_combo",
+        Some(Role::Unspecified) | None => "unknown",
+    }
+}
+
+/// Names of the capabilities in `capabilities`. Bits we don't know are left out.
+pub fn capability_names(capabilities: u32) -> Vec<&'static str> {
+    CAPABILITY_NAMES
+        .iter()
+        .filter(|&&(bit, _)| capabilities & bit != 0)
+        .map(|&(_, name)| name)
+        .collect()
+}
+
+/// Lets through one hello per source per interval, and forgets sources after that.
+pub struct HelloLimiter {
+    interval: Duration,
+    // When we last let a hello of every source through
+    passed: HashMap<String, Duration>,
+}
+
+impl HelloLimiter {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            passed: HashMap::new(),
+        }
+    }
+
+    /// Whether to pass on a hello of `src` that came in at `now`.
+    pub fn allow(&mut self, src: &str, now: Duration) -> bool {
+        let interval = self.interval;
+        self.passed
+            .retain(|_, &mut passed| now.saturating_sub(passed) < interval);
+        if self.passed.contains_key(src) {
+            return false;
+        }
+        self.passed.insert(src.to_string(), now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(s: u64) -> Duration {
+        Duration::from_secs(s)
+    }
+
+    #[test]
+    fn one_hello_per_source_per_interval() {
+        let mut limiter = HelloLimiter::new(HELLO_INTERVAL);
+        assert!(limiter.allow("a", secs(10)));
+        assert!(!limiter.allow("a", secs(11)));
+        assert!(!limiter.allow("a", secs(69)));
+        // Other sources have their own interval
+        assert!(limiter.allow("b", secs(11)));
+        assert!(limiter.allow("a", secs(70)));
+        assert!(!limiter.allow("b", secs(70)));
+        assert!(!limiter.allow("a", secs(71)));
+    }
+
+    #[test]
+    fn dropped_hellos_dont_extend_the_interval() {
+        let mut limiter = HelloLimiter::new(HELLO_INTERVAL);
+        assert!(limiter.allow("a", secs(0)));
+        for t in 1..60 {
+            assert!(!limiter.allow("a", secs(t)));
+        }
+        assert!(limiter.allow("a", secs(60)));
+    }
+
+    #[test]
+    fn a_storm_of_hellos_passes_one_per_source() {
+        let mut limiter = HelloLimiter::new(HELLO_INTERVAL);
+        let sources: Vec<String> = (0..50).map(|i| format!("src{i}")).collect();
+        let mut passed = 0;
+        for round in 0..5 {
+            for src in &sources {
+                passed += limiter.allow(src, secs(round)) as u32;
+            }
+        }
+        assert_eq!(passed, 50);
+        // Sources are forgotten after the interval
+        limiter.allow("other", secs(100));
+        assert_eq!(limiter.passed.len(), 1);
+    }
+
+    #[test]
+    fn capabilities_have_names() {
+        assert!(capability_names(0).is_empty());
+        assert_eq!(
+            capability_names(CAP_RELAY | CAP_COMMANDS | CAP_OTA),
+            ["relay", "commands", "ota"]
+        );
+        // Bits we don't know are left out
+        assert_eq!(capability_names(CAP_GPS | 1 << 31), ["gps"]);
+        assert_eq!(capability_names(u32::MAX).len(), CAPABILITY_NAMES.len());
+    }
+
+    #[test]
+    fn capability_bits_are_distinct() {
+        let all = CAPABILITY_NAMES.iter().fold(0, |all, &(bit, _)| {
+            assert_eq!(bit.count_ones(), 1);
+            assert_eq!(all & bit, 0);
+            all | bit
+        });
+        assert_eq!(all, (1 << CAPABILITY_NAMES.len()) - 1);
+    }
+
+    #[test]
+    fn roles_have_names() {
+        assert_eq!(role_name(Role::Tracker as i32), "tracker");
+        assert_eq!(role_name(Role::Beacon as i32), "beacon");
+        assert_eq!(role_name(Role::GatewayCombo as i32), "gateway_combo");
+        assert_eq!(role_name(Role::Unspecified as i32), "unknown");
+        assert_eq!(role_name(42), "unknown");
+    }
+
+    #[test]
+    fn reset_reasons_have_names() {
+        assert_eq!(
+            reset_reason_name(esp_idf_sys::esp_reset_reason_t_ESP_RST_POWERON),
+            "power_on"
+        );
+        assert_eq!(
+            reset_reason_name(esp_idf_sys::esp_reset_reason_t_ESP_RST_DEEPSLEEP),
+            "deep_sleep"
+        );
+        assert_eq!(
+            reset_reason_name(esp_idf_sys::esp_reset_reason_t_ESP_RST_BROWNOUT),
+            "brownout"
+        );
+        assert_eq!(reset_reason_name(u32::MAX), "unknown");
+    }
+}
@@ -1,16 +1,21 @@
-use crate::utils::set_thread_spawn_configuration;
-use esp_idf_hal::cpu::Core;
+use crate::ledlog;
+use crate::ledlog::Shown;
+use crate::utils::spawn_thread;
+use crate::utils::ThreadConfig;
 use esp_idf_hal::gpio;
 use esp_idf_hal::gpio::Pin;
 use esp_idf_hal::gpio::PinDriver;
 pub use smart_leds::colors;
 use smart_leds::SmartLedsWrite;
 use smart_leds::RGB8;
+use std::ops::Deref;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
+use std::sync::mpsc::RecvTimeoutError;
 use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
+use std::time::Instant;
 
 enum LedCommand {
     SetColor {
@@ -24,11 +29,28 @@ enum LedCommand {
         duty_cycle: u8,
         times: u8,
     },
+    Pulse {
+        color: RGB8,
+        brightness: u8,
+        period: Duration,
+    },
 }
+
+// How often the brightness is updated while pulsing
+const PULSE_STEP: Duration = Duration::from_millis(50);
+
 pub struct Led {
     driver_handle: Option<thread::JoinHandle<()>>,
     alive: Arc<AtomicBool>,
-    cmd_tx: Option<std::sync::mpsc::Sender<LedCommand>>,
+    handle: LedHandle,
+}
+
+impl Deref for Led {
+    type Target = LedHandle;
+
+    fn deref(&self) -> &LedHandle {
+        &self.handle
+    }
 }
 
 impl Default for Led {
@@ -42,76 +64,103 @@ impl Led {
         Self {
             driver_handle: None,
             alive: Arc::new(AtomicBool::new(false)),
-            cmd_tx: None,
+            handle: LedHandle::default(),
         }
     }
 
+    /// Get a handle that can be used to control the LED from elsewhere, e.g. from another thread.
+    pub fn handle(&self) -> LedHandle {
+        self.handle.clone()
+    }
+
     pub fn start(
         &mut self,
         led_pin: gpio::AnyOutputPin,
         power_pin: gpio::AnyOutputPin,
+        thread: ThreadConfig,
     ) -> anyhow::Result<()> {
         self.alive.store(true, Ordering::SeqCst);
         let alive = self.alive.clone();
 
         let (cmd_tx, cmd_rx) = std::sync::mpsc::channel::<LedCommand>();
-        self.cmd_tx = Some(cmd_tx);
-
-        set_thread_spawn_configuration("led-htread", 4196, 15, Some(Core::Core1))?;
-        self.driver_handle = Some(
-            std::thread::Builder::new()
-                .stack_size(4196)
-                .spawn(move || {
-                    // Set the power to high
-                    let mut led = PinDriver::output(power_pin).unwrap();
-                    led.set_high().unwrap();
-
-                    let mut ws2812 = ws2812_esp32_rmt_driver::Ws2812Esp32Rmt::new(
-                        0,
-                        led_pin.pin().try_into().unwrap(),
-                    )
+        self.handle.cmd_tx = Some(cmd_tx);
+
+        self.driver_handle = Some(spawn_thread(thread, move || {
+            // Set the power to high
+            let mut led = PinDriver::output(power_pin).unwrap();
+            led.set_high().unwrap();
+
+            let mut ws2812 =
+                ws2812_esp32_rmt_driver::Ws2812Esp32Rmt::new(0, led_pin.pin().try_into().unwrap())
                     .unwrap();
 
-                    let mut current_color = colors::BLACK;
-
-                    while alive.load(Ordering::SeqCst) {
-                        match cmd_rx.recv().unwrap() {
-                            LedCommand::SetColor { color, brightness } => {
-                                current_color = apply_brightness(color, brightness);
-                                ws2812
-                                    .write(std::iter::repeat(current_color).take(1))
-                                    .unwrap();
-                            }
-                            LedCommand::Blink {
-                                color,
-                                brightness,
-                                period,
-                                duty_cycle,
-                                times,
-                            } => {
-                                let color = apply_brightness(color, brightness);
-
-                                let pos_half = period * duty_cycle as u32 / 100;
-                                let neg_half = period * (100 - duty_cycle) as u32 / 100;
-
-                                for _ in 0..times {
-                                    ws2812.write(std::iter::repeat(color).take(1)).unwrap();
-
-                                    std::thread::sleep(pos_half);
-                                    ws2812
-                                        .write(std::iter::repeat(colors::BLACK).take(1))
-                                        .unwrap();
-                                    std::thread::sleep(neg_half);
-                                }
-                                ws2812
-                                    .write(std::iter::repeat(current_color).take(1))
-                                    .unwrap()
-                            }
-                        };
+            let mut current_color = colors::BLACK;
+            let mut pulse = None;
+            let mut pulse_start = Instant::now();
+
+            while alive.load(Ordering::SeqCst) {
+                // While pulsing we can't block on the channel, since we need to keep
+                // updating the brightness.
+                let cmd = match pulse {
+                    Some((color, brightness, period)) => match cmd_rx.recv_timeout(PULSE_STEP) {
+                        Ok(cmd) => cmd,
+                        Err(RecvTimeoutError::Timeout) => {
+                            let level = pulse_level(pulse_start.elapsed(), period, brightness);
+                            current_color = apply_brightness(color, level);
+                            ws2812
+                                .write(std::iter::repeat(current_color).take(1))
+                                .unwrap();
+                            continue;
+                        }
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    },
+                    None => cmd_rx.recv().unwrap(),
+                };
+
+                match cmd {
+                    LedCommand::SetColor { color, brightness } => {
+                        pulse = None;
+                        current_color = apply_brightness(color, brightness);
+                        ws2812
+                            .write(std::iter::repeat(current_color).take(1))
+                            .unwrap();
                     }
-                })
-                .unwrap(),
-        );
+                    LedCommand::Blink {
+                        color,
+                        brightness,
+                        period,
+                        duty_cycle,
+                        times,
+                    } => {
+                        let color = apply_brightness(color, brightness);
+
+                        let pos_half = period * duty_cycle as u32 / 100;
+                        let neg_half = period * (100 - duty_cycle) as u32 / 100;
+
+                        for _ in 0..times {
+                            ws2812.write(std::iter::repeat(color).take(1)).unwrap();
+
+                            std::thread::sleep(pos_half);
+                            ws2812
+                                .write(std::iter::repeat(colors::BLACK).take(1))
+                                .unwrap();
+                            std::thread::sleep(neg_half);
+                        }
+                        ws2812
+                            .write(std::iter::repeat(current_color).take(1))
+                            .unwrap()
+                    }
+                    LedCommand::Pulse {
+                        color,
+                        brightness,
+                        period,
+                    } => {
+                        pulse = Some((color, brightness, period));
+                        pulse_start = Instant::now();
+                    }
+                };
+            }
+        })?);
 
         Ok(())
     }
@@ -124,8 +173,18 @@ impl Led {
             .join()
             .expect("Could not join spawned thread");
     }
+}
+
+/// Cheap handle to a started Led, that can be cloned and handed to other threads. Every command
+/// is recorded in the `ledlog`.
+#[derive(Clone, Default)]
+pub struct LedHandle {
+    cmd_tx: Option<std::sync::mpsc::Sender<LedCommand>>,
+}
 
-    pub fn set_color(&mut self, color: RGB8, brightness: u8) -> anyhow::Result<()> {
+impl LedHandle {
+    pub fn set_color(&self, color: RGB8, brightness: u8) -> anyhow::Result<()> {
+        ledlog::record(Shown::Color, [color.r, color.g, color.b], brightness);
         match self.cmd_tx {
             Some(ref tx) => tx
                 .send(LedCommand::SetColor { color, brightness })
@@ -135,12 +194,17 @@ impl Led {
     }
 
     pub fn blink_color(
-        &mut self,
+        &self,
         color: RGB8,
         brightness: u8,
         period: Duration,
         times: u8,
     ) -> anyhow::Result<()> {
+        ledlog::record(
+            Shown::Blink { times },
+            [color.r, color.g, color.b],
+            brightness,
+        );
         match self.cmd_tx {
             Some(ref tx) => tx
                 .send(LedCommand::Blink {
@@ -154,6 +218,34 @@ impl Led {
             None => Err(anyhow::anyhow!("Led not started")),
         }
     }
+
+    /// Slowly pulse the LED until another color is set. Blinks are shown on top of the pulse.
+    pub fn pulse_color(&self, color: RGB8, brightness: u8, period: Duration) -> anyhow::Result<()> {
+        ledlog::record(Shown::Pulse, [color.r, color.g, color.b], brightness);
+        match self.cmd_tx {
+            Some(ref tx) => tx
+                .send(LedCommand::Pulse {
+                    color,
+                    brightness,
+                    period,
+                })
+                .map_err(anyhow::Error::msg),
+            None => Err(anyhow::anyhow!("Led not started")),
+        }
+    }
+}
+
+/// Brightness at `elapsed` into a pulse, ramping up from 0 to `brightness` and back down once
+/// every `period`.
+fn pulse_level(elapsed: Duration, period: Duration, brightness: u8) -> u8 {
+    let period = period.as_millis().max(1);
+    let phase = (elapsed.as_millis() % period) * 2 * brightness as u128 / period;
+    let level = if phase > brightness as u128 {
+        2 * brightness as u128 - phase
+    } else {
+        phase
+    };
+    level as u8
 }
 
 fn apply_brightness(color: RGB8, brightness: u8) -> RGB8 {
@@ -0,0 +1,494 @@
+//! The last LED commands and status patterns, so we can tell what the firmware thought it was
+//! showing when a device was seen blinking red and then went dark. Every command sent through a
+//! `LedHandle` and every `status` pattern is recorded with the time since boot and the name of the
+//! thread that sent it, in a ring of `RING_SIZE` fixed size records.
+//!
+//! Recording doesn't allocate and never waits: when the ring is busy, the record is dropped and
+//! counted. The ring is written to NVS before we reboot on purpose and from the panic hook, see
+//! `install_panic_hook`, and read back, logged and erased by `restore` at the next boot. Deep
+//! sleep doesn't save the ring, that would wear the flash.
+
+use crate::nvs_recovery::persistence_available;
+use esp_idf_sys::esp;
+use esp_idf_sys::EspError;
+use log::*;
+use std::fmt;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::Duration;
+
+pub const RING_SIZE: usize = 64;
+const RECORD_SIZE: usize = 20;
+const THREAD_NAME_SIZE: usize = 10;
+// Blobs start with this and the number of records that follow
+const BLOB_MAGIC: u8 = 0x4c;
+/// Namespace of the ring in NVS, for `nvs_recovery`.
+pub const LEDLOG_NAMESPACE: &str = "ledlog";
+const NAMESPACE: &[u8] = b"ledlog\0";
+const KEY: &[u8] = b"ring\0";
+
+/// What was shown.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Shown {
+    Color,
+    Blink {
+        times: u8,
+    },
+    Pulse,
+    /// The error pattern of a `status::Status`, by its number of blinks
+    Status {
+        blinks: u8,
+    },
+}
+
+/// One transition of the LED.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Record {
+    /// Time since boot, in ms
+    pub uptime_ms: u32,
+    pub shown: Shown,
+    pub rgb: [u8; 3],
+    pub brightness: u8,
+    // Name of the thread that sent the command, truncated and padded with 0s
+    thread: [u8; THREAD_NAME_SIZE],
+}
+
+impl Record {
+    pub fn new(uptime: Duration, shown: Shown, rgb: [u8; 3], brightness: u8, thread: &str) -> Self {
+        // Thread names of `spawn_thread` end in a NUL
+        let name = thread.trim_end_matches('\0').as_bytes();
+        let mut padded = [0u8; THREAD_NAME_SIZE];
+        let len = name.len().min(THREAD_NAME_SIZE);
+        padded[..len].copy_from_slice(&name[..len]);
+        Self {
+            uptime_ms: uptime.as_millis().min(u32::MAX as u128) as u32,
+            shown,
+            rgb,
+            brightness,
+            thread: padded,
+        }
+    }
+
+    pub fn thread(&self) -> &str {
+        let len = self
+            .thread
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(THREAD_NAME_SIZE);
+        std::str::from_utf8(&self.thread[..len]).unwrap_or("?")
+    }
+
+    pub fn encode(&self) -> [u8; RECORD_SIZE] {
+        let mut bytes = [0u8; RECORD_SIZE];
+        bytes[0..4].copy_from_slice(&self.uptime_ms.to_le_bytes());
+        let (kind, arg) = match self.shown {
+            Shown::Color => (1, 0),
+            Shown::Blink { times } => (2, times),
+            Shown::Pulse => (3, 0),
+            Shown::Status { blinks } => (4, blinks),
+        };
+        bytes[4] = kind;
+        bytes[5] = arg;
+        bytes[6..9].copy_from_slice(&self.rgb);
+        bytes[9] = self.brightness;
+        bytes[10..].copy_from_slice(&self.thread);
+        bytes
+    }
+
+    /// None when `bytes` isn't a record.
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != RECORD_SIZE {
+            return None;
+        }
+        let shown = match (bytes[4], bytes[5]) {
+            (1, _) => Shown::Color,
+            (2, times) => Shown::Blink { times },
+            (3, _) => Shown::Pulse,
+            (4, blinks) => Shown::Status { blinks },
+            _ => return None,
+        };
+        let mut thread = [0u8; THREAD_NAME_SIZE];
+        thread.copy_from_slice(&bytes[10..]);
+        Some(Self {
+            uptime_ms: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+            shown,
+            rgb: [bytes[6], bytes[7], bytes[8]],
+            brightness: bytes[9],
+            thread,
+        })
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [r, g, b] = self.rgb;
+        write!(f, "{:>10}ms {:<10} ", self.uptime_ms, self.thread())?;
+        match self.shown {
+            Shown::Color => write!(f, "color #{r:02x}{g:02x}{b:02x}")?,
+            Shown::Blink { times } => write!(f, "blink #{r:02x}{g:02x}{b:02x} {times}x")?,
+            Shown::Pulse => write!(f, "pulse #{r:02x}{g:02x}{b:02x}")?,
+            Shown::Status { blinks } => return write!(f, "status {blinks} blinks"),
+        }
+        write!(f, " at {}", self.brightness)
+    }
+}
+
+/// The last `RING_SIZE` records.
+pub struct Ring {
+    records: [Option<Record>; RING_SIZE],
+    // Where the next record goes
+    next: usize,
+}
+
+impl Ring {
+    pub const fn new() -> Self {
+        Self {
+            records: [None; RING_SIZE],
+            next: 0,
+        }
+    }
+
+    pub fn push(&mut self, record: Record) {
+        self.records[self.next] = Some(record);
+        self.next = (self.next + 1) % RING_SIZE;
+    }
+
+    /// The records, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = &Record> {
+        self.records[self.next..]
+            .iter()
+            .chain(&self.records[..self.next])
+            .flatten()
+    }
+
+    /// The records, oldest first, as a blob for NVS.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut blob = vec![BLOB_MAGIC, 0];
+        for record in self.iter() {
+            blob.extend_from_slice(&record.encode());
+            blob[1] += 1;
+        }
+        blob
+    }
+
+    /// The records in a blob of `encode`. Records that don't decode are left out, a blob we can't
+    /// read at all gives none.
+    pub fn decode(blob: &[u8]) -> Vec<Record> {
+        match blob {
+            [BLOB_MAGIC, count, records @ ..] => records
+                .chunks_exact(RECORD_SIZE)
+                .take(*count as usize)
+                .filter_map(Record::decode)
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl Default for Ring {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static RING: Mutex<Ring> = Mutex::new(Ring::new());
+// Records dropped because the ring was busy
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+// What `restore` found, for the console
+static RESTORED: Mutex<Vec<Record>> = Mutex::new(Vec::new());
+
+/// Record what the LED is told to show by the current thread.
+pub fn record(shown: Shown, rgb: [u8; 3], brightness: u8) {
+    let thread = std::thread::current();
+    let record = Record::new(
+        crate::utils::uptime(),
+        shown,
+        rgb,
+        brightness,
+        thread.name().unwrap_or(""),
+    );
+    match RING.try_lock() {
+        Ok(mut ring) => ring.push(record),
+        Err(_) => {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Number of records dropped because the ring was busy.
+pub fn dropped() -> u32 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Write the ring to NVS, for the next boot. Called right before we reboot.
+pub fn save() {
+    // We might be panicking while the ring is locked, saving what's there is better than nothing
+    let blob = match RING.try_lock() {
+        Ok(ring) => ring.encode(),
+        Err(std::sync::TryLockError::Poisoned(ring)) => ring.into_inner().encode(),
+        Err(std::sync::TryLockError::WouldBlock) => return,
+    };
+    if let Err(e) = write_blob(&blob) {
+        error!("Unable to save the LED log: {e}");
+    }
+}
+
+/// Save the ring when we panic, before the previous hook aborts.
+pub fn install_panic_hook() {
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        save();
+        previous(info);
+    }));
+}
+
+/// Log the ring that was saved before the last reboot and erase it, so it's reported once. NVS
+/// has to be initialized.
+pub fn restore() {
+    let records = match read_blob() {
+        Ok(Some(blob)) => Ring::decode(&blob),
+        Ok(None) => return,
+        Err(e) => {
+            error!("Unable to read the LED log: {e}");
+            return;
+        }
+    };
+    warn!("LED before the last reboot, oldest first:");
+    for record in &records {
+        warn!("  {record}");
+    }
+    if let Err(e) = erase_blob() {
+        error!("Unable to erase the LED log: {e}");
+    }
+    *RESTORED.lock().unwrap() = records;
+}
+
+/// The records `restore` found, oldest first.
+pub fn restored() -> Vec<Record> {
+    RESTORED.lock().unwrap().clone()
+}
+
+fn open(mode: esp_idf_sys::nvs_open_mode_t) -> Result<esp_idf_sys::nvs_handle_t, EspError> {
+    let mut handle: esp_idf_sys::nvs_handle_t = 0;
+    esp!(unsafe { esp_idf_sys::nvs_open(NAMESPACE.as_ptr() as *const _, mode, &mut handle) })?;
+    Ok(handle)
+}
+
+fn read_blob() -> Result<Option<Vec<u8>>, EspError> {
+    if !persistence_available() {
+        return Ok(None);
+    }
+    let handle = match open(esp_idf_sys::nvs_open_mode_t_NVS_READONLY) {
+        Ok(handle) => handle,
+        Err(e) if e.code() == esp_idf_sys::ESP_ERR_NVS_NOT_FOUND as i32 => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let key = KEY.as_ptr() as *const _;
+    let mut len = 0;
+    let result =
+        esp!(unsafe { esp_idf_sys::nvs_get_blob(handle, key, std::ptr::null_mut(), &mut len) })
+            .and_then(|_| {
+                let mut blob = vec![0u8; len];
+                esp!(unsafe {
+                    esp_idf_sys::nvs_get_blob(handle, key, blob.as_mut_ptr() as *mut _, &mut len)
+                })
+                .map(|_| blob)
+            });
+    unsafe { esp_idf_sys::nvs_close(handle) };
+    match result {
+        Ok(blob) => Ok(Some(blob)),
+        Err(e) if e.code() == esp_idf_sys::ESP_ERR_NVS_NOT_FOUND as i32 => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn write_blob(blob: &[u8]) -> Result<(), EspError> {
+    if !persistence_available() {
+        return Ok(());
+    }
+    let handle = open(esp_idf_sys::nvs_open_mode_t_NVS_READWRITE)?;
+    let result = esp!(unsafe {
+        esp_idf_sys::nvs_set_blob(
+            handle,
+            KEY.as_ptr() as *const _,
+            blob.as_ptr() as *const _,
+            blob.len(),
+        )
+    })
+    .and_then(|_| esp!(unsafe { esp_idf_sys::nvs_commit(handle) }));
+    unsafe { esp_idf_sys::nvs_close(handle) };
+    result
+}
+
+fn erase_blob() -> Result<(), EspError> {
+    let handle = open(esp_idf_sys::nvs_open_mode_t_NVS_READWRITE)?;
+    let result = esp!(unsafe { esp_idf_sys::nvs_erase_key(handle, KEY.as_ptr() as *const _) })
+        .and_then(|_| esp!(unsafe { esp_idf_sys::nvs_commit(handle) }));
+    unsafe { esp_idf_sys::nvs_close(handle) };
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHOWN: [Shown; 4] = [
+        Shown::Color,
+        Shown::Blink { times: 3 },
+        Shown::Pulse,
+        Shown::Status { blinks: 5 },
+    ];
+
+    fn record(uptime_ms: u64) -> Record {
+        Record::new(
+            Duration::from_millis(uptime_ms),
+            Shown::Blink { times: 2 },
+            [0xff, 0, 0x80],
+            64,
+            "gps",
+        )
+    }
+
+    #[test]
+    fn records_round_trip() {
+        for shown in SHOWN {
+            let record = Record::new(Duration::from_millis(1234), shown, [1, 2, 3], 200, "main");
+            let bytes = record.encode();
+            assert_eq!(bytes.len(), RECORD_SIZE);
+            assert_eq!(Record::decode(&bytes), Some(record));
+        }
+    }
+
+    #[test]
+    fn records_have_a_fixed_layout() {
+        assert_eq!(
+            record(0x01020304).encode(),
+            [
+                0x04, 0x03, 0x02, 0x01, 2, 2, 0xff, 0, 0x80, 64, b'g', b'p', b's', 0, 0, 0, 0, 0,
+                0, 0
+            ]
+        );
+    }
+
+    #[test]
+    fn thread_names_are_truncated() {
+        let record = Record::new(Duration::ZERO, Shown::Pulse, [0; 3], 0, "uart_reader\0");
+        assert_eq!(record.thread(), "uart_reade");
+        let record = Record::new(Duration::ZERO, Shown::Pulse, [0; 3], 0, "led\0");
+        assert_eq!(record.thread(), "led");
+        let record = Record::new(Duration::ZERO, Shown::Pulse, [0; 3], 0, "");
+        assert_eq!(record.thread(), "");
+    }
+
+    #[test]
+    fn uptime_saturates() {
+        let record = Record::new(
+            Duration::from_secs(u64::MAX),
+            Shown::Color,
+            [0; 3],
+            0,
+            "main",
+        );
+        assert_eq!(record.uptime_ms, u32::MAX);
+    }
+
+    #[test]
+    fn bad_records_dont_decode() {
+        let bytes = record(1).encode();
+        assert_eq!(Record::decode(&bytes[..RECORD_SIZE - 1]), None);
+        let mut bad = bytes;
+        bad[4] = 0;
+        assert_eq!(Record::decode(&bad), None);
+        bad[4] = 5;
+        assert_eq!(Record::decode(&bad), None);
+    }
+
+    #[test]
+    fn records_show_what_was_shown() {
+        assert_eq!(
+            record(1500).to_string(),
+            "      1500ms gps        blink #ff0080 2x at 64"
+        );
+        let status = Record::new(
+            Duration::ZERO,
+            Shown::Status { blinks: 3 },
+            [0; 3],
+            0,
+            "main",
+        );
+        assert_eq!(
+            status.to_string(),
+            "         0ms main       status 3 blinks"
+        );
+    }
+
+    #[test]
+    fn the_ring_keeps_the_last_records_oldest_first() {
+        let mut ring = Ring::new();
+        assert_eq!(ring.iter().count(), 0);
+        for ms in 0..3 {
+            ring.push(record(ms));
+        }
+        let uptimes: Vec<_> = ring.iter().map(|r| r.uptime_ms).collect();
+        assert_eq!(uptimes, [0, 1, 2]);
+
+        for ms in 3..RING_SIZE as u64 + 10 {
+            ring.push(record(ms));
+        }
+        let uptimes: Vec<_> = ring.iter().map(|r| r.uptime_ms).collect();
+        let expected: Vec<_> = (10..RING_SIZE as u32 + 10).collect();
+        assert_eq!(uptimes, expected);
+    }
+
+    #[test]
+    fn rings_round_trip() {
+        let mut ring = Ring::new();
+        assert_eq!(ring.encode(), [BLOB_MAGIC, 0]);
+        assert!(Ring::decode(&ring.encode()).is_empty());
+
+        for ms in 0..RING_SIZE as u64 + 5 {
+            ring.push(record(ms));
+        }
+        let blob = ring.encode();
+        assert_eq!(blob.len(), 2 + RING_SIZE * RECORD_SIZE);
+        assert_eq!(blob[1] as usize, RING_SIZE);
+        let records: Vec<_> = ring.iter().copied().collect();
+        assert_eq!(Ring::decode(&blob), records);
+    }
+
+    #[test]
+    fn truncated_blobs_give_what_they_have() {
+        let mut ring = Ring::new();
+        for ms in 0..3 {
+            ring.push(record(ms));
+        }
+        let blob = ring.encode();
+        let records = Ring::decode(&blob[..blob.len() - 1]);
+        assert_eq!(records, [record(0), record(1)]);
+        assert!(Ring::decode(&blob[..1]).is_empty());
+        assert!(Ring::decode(&[]).is_empty());
+
+        // A count that's off doesn't read past the records
+        let mut blob = blob;
+        blob[1] = 2;
+        assert_eq!(Ring::decode(&blob), [record(0), record(1)]);
+        blob[1] = 200;
+        assert_eq!(Ring::decode(&blob).len(), 3);
+    }
+
+    #[test]
+    fn blobs_of_something_else_give_nothing() {
+        let mut ring = Ring::new();
+        ring.push(record(0));
+        let mut blob = ring.encode();
+        blob[0] = !BLOB_MAGIC;
+        assert!(Ring::decode(&blob).is_empty());
+
+        // Records that don't decode are left out
+        let mut blob = ring.encode();
+        blob.extend_from_slice(&[0; RECORD_SIZE]);
+        blob[1] = 2;
+        assert_eq!(Ring::decode(&blob), [record(0)]);
+    }
+}
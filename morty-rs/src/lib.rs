@@ -1,6 +1,81 @@
+// Everything but `wire` needs std and ESP-IDF, see the features in Cargo.toml
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+#[cfg(feature = "std")]
+pub mod battery;
+#[cfg(feature = "std")]
+pub mod baud;
+#[cfg(feature = "std")]
+pub mod budget;
+#[cfg(feature = "std")]
+pub mod clock;
+#[cfg(feature = "std")]
 pub mod comm;
+#[cfg(feature = "std")]
+pub mod command;
+#[cfg(feature = "std")]
+pub mod config;
+#[cfg(feature = "std")]
+pub mod dedup;
+#[cfg(feature = "std")]
+pub mod duty;
+#[cfg(feature = "std")]
+pub mod epo;
+#[cfg(feature = "std")]
+pub mod flashlog;
+#[cfg(feature = "std")]
+pub mod geo;
+#[cfg(feature = "std")]
+pub mod gps_state;
+#[cfg(feature = "std")]
+pub mod heap;
+#[cfg(feature = "std")]
+pub mod hello;
+#[cfg(feature = "std")]
 pub mod led;
+#[cfg(feature = "std")]
+pub mod ledlog;
+#[cfg(feature = "std")]
+pub mod metrics;
+#[cfg(feature = "std")]
+pub mod naming;
+#[cfg(feature = "std")]
+pub mod nvs_recovery;
+#[cfg(feature = "std")]
+pub mod ota;
+#[cfg(feature = "std")]
+pub mod power;
+#[cfg(feature = "std")]
+pub mod presence;
+#[cfg(feature = "std")]
+pub mod profile;
+#[cfg(feature = "std")]
+pub mod relay;
+#[cfg(feature = "std")]
+pub mod sequence;
+#[cfg(feature = "std")]
+pub mod source_metrics;
+#[cfg(feature = "std")]
+pub mod status;
+#[cfg(feature = "std")]
+pub mod timestamps;
+#[cfg(feature = "std")]
+pub mod timesync;
+#[cfg(feature = "std")]
+pub mod trace;
+#[cfg(feature = "std")]
+pub mod transfer;
+#[cfg(feature = "std")]
+pub mod uart_errors;
+#[cfg(feature = "std")]
 pub mod utils;
+#[cfg(feature = "std")]
+pub mod wifi;
+pub mod wire;
+#[cfg(feature = "std")]
 pub mod messages {
     include!(concat!(env!("OUT_DIR"), "/morty.messages.rs"));
 }
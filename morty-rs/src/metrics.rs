@@ -0,0 +1,140 @@
+//! Fixed bucket histograms for durations, e.g. how long frames wait before they are handled.
+//! Buckets are atomics, so a histogram can be a static that is recorded to from a callback and
+//! logged from a thread.
+
+use crate::utils::format_duration;
+use std::fmt;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+// Needed to initialize the bucket arrays in a const fn
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU32 = AtomicU32::new(0);
+
+/// Counts durations in `N` buckets with the given upper bounds, plus one for everything above the
+/// last bound.
+pub struct Histogram<const N: usize> {
+    bounds: [Duration; N],
+    counts: [AtomicU32; N],
+    over: AtomicU32,
+    // Longest duration we recorded, in microseconds
+    max_us: AtomicU32,
+}
+
+impl<const N: usize> Histogram<N> {
+    /// `bounds` has to be in ascending order.
+    pub const fn new(bounds: [Duration; N]) -> Self {
+        Self {
+            bounds,
+            counts: [ZERO; N],
+            over: AtomicU32::new(0),
+            max_us: AtomicU32::new(0),
+        }
+    }
+
+    /// Count `value` in the first bucket it fits in.
+    pub fn record(&self, value: Duration) {
+        let counter = self
+            .bounds
+            .iter()
+            .position(|&bound| value <= bound)
+            .map_or(&self.over, |i| &self.counts[i]);
+        counter.fetch_add(1, Ordering::Relaxed);
+        let us = u32::try_from(value.as_micros()).unwrap_or(u32::MAX);
+        self.max_us.fetch_max(us, Ordering::Relaxed);
+    }
+
+    /// Number of durations recorded.
+    pub fn count(&self) -> u32 {
+        self.counts
+            .iter()
+            .chain([&self.over])
+            .map(|c| c.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// The count of every bucket, with its upper bound. The last bucket has no bound.
+    pub fn buckets(&self) -> Vec<(Option<Duration>, u32)> {
+        self.bounds
+            .iter()
+            .zip(self.counts.iter())
+            .map(|(&bound, count)| (Some(bound), count.load(Ordering::Relaxed)))
+            .chain([(None, self.over.load(Ordering::Relaxed))])
+            .collect()
+    }
+
+    /// Longest duration recorded.
+    pub fn max(&self) -> Duration {
+        Duration::from_micros(self.max_us.load(Ordering::Relaxed) as u64)
+    }
+}
+
+impl<const N: usize> fmt::Display for Histogram<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (bound, count) in self.buckets() {
+            match bound {
+                Some(bound) => write!(f, "le{}:{count} ", format_duration(bound))?,
+                None => write!(f, "inf:{count} ")?,
+            }
+        }
+        write!(f, "max:{}", format_duration(self.max()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn histogram() -> Histogram<3> {
+        Histogram::new([ms(1), ms(10), ms(100)])
+    }
+
+    #[test]
+    fn durations_go_in_the_first_bucket_they_fit_in() {
+        let histogram = histogram();
+        for value in [ms(0), ms(1), ms(2), ms(10), ms(100), ms(101), ms(5000)] {
+            histogram.record(value);
+        }
+        assert_eq!(
+            histogram.buckets(),
+            [
+                (Some(ms(1)), 2),
+                (Some(ms(10)), 2),
+                (Some(ms(100)), 1),
+                (None, 2)
+            ]
+        );
+        assert_eq!(histogram.count(), 7);
+        assert_eq!(histogram.max(), ms(5000));
+    }
+
+    #[test]
+    fn an_empty_histogram_has_nothing() {
+        let histogram = histogram();
+        assert_eq!(histogram.count(), 0);
+        assert_eq!(histogram.max(), Duration::ZERO);
+    }
+
+    #[test]
+    fn the_max_saturates() {
+        let histogram = histogram();
+        histogram.record(Duration::from_secs(24 * 3600));
+        assert_eq!(histogram.max(), Duration::from_micros(u32::MAX as u64));
+    }
+
+    #[test]
+    fn histograms_show_every_bucket() {
+        let histogram = histogram();
+        histogram.record(ms(5));
+        histogram.record(ms(250));
+        assert_eq!(
+            histogram.to_string(),
+            "le1ms:0 le10ms:1 le100ms:0 inf:1 max:250ms"
+        );
+    }
+}
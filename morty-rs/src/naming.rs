@@ -0,0 +1,190 @@
+//! Names devices give themselves, like "trailer-1", so they don't have to be mapped to MAC
+//! addresses at the gateway. The name is the `name` setting, see `config`, and can also be set
+//! with a SetName command. It goes out with every hello and with every NAME_EVERY'th fix, to save
+//! bytes in the others.
+//!
+//! The gateway can still have names of its own for sources. A name the device reports wins, a
+//! different one at the gateway is a conflict worth showing, see `resolve`.
+
+use log::*;
+
+/// Key of the name in the settings of a device.
+pub const NAME_KEY: &str = "name";
+/// Longest name, in bytes.
+pub const MAX_NAME_LEN: usize = 16;
+/// Fixes that go out with the name: the first one of a boot and every NAME_EVERY'th after that.
+pub const NAME_EVERY: u32 = 10;
+
+/// Check that `name` is a valid name: at most MAX_NAME_LEN ASCII letters, digits, '-', '_' and
+/// '.', not starting with a '-' or a '.'. The empty name means the device has none.
+pub fn check_name(name: &str) -> Result<(), String> {
+    if name.len() > MAX_NAME_LEN {
+        return Err(format!("{name:?} is longer than {MAX_NAME_LEN} bytes"));
+    }
+    if let Some(c) = name
+        .chars()
+        .find(|&c| !(c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')))
+    {
+        return Err(format!("{name:?} has a {c:?}"));
+    }
+    if name.starts_with(['-', '.']) {
+        return Err(format!("{name:?} starts with a {:?}", &name[..1]));
+    }
+    Ok(())
+}
+
+/// `name` when it's a valid name, otherwise no name. A stored name is checked before it goes out,
+/// the settings only limit its length.
+pub fn valid_name(name: &str) -> String {
+    match check_name(name) {
+        Ok(()) => name.to_string(),
+        Err(e) => {
+            error!("Ignoring the name: {e}");
+            String::new()
+        }
+    }
+}
+
+/// Whether the fix with sequence number `seq`, see `sequence`, goes out with the name.
+pub fn name_due(seq: u32) -> bool {
+    seq % NAME_EVERY == 0
+}
+
+/// The name of a source, from what it reported and the one the gateway has for it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Resolved<'a> {
+    /// The name to show, None when neither has one
+    pub name: Option<&'a str>,
+    /// The name the gateway has when the source reported another one
+    pub conflict: Option<&'a str>,
+}
+
+/// The name of a source that reported `reported`, empty for none, and has `local` at the gateway.
+/// The reported name wins.
+pub fn resolve<'a>(reported: &'a str, local: Option<&'a str>) -> Resolved<'a> {
+    let local = local.filter(|name| !name.is_empty());
+    if reported.is_empty() {
+        return Resolved {
+            name: local,
+            conflict: None,
+        };
+    }
+    Resolved {
+        name: Some(reported),
+        conflict: local.filter(|&name| name != reported),
+    }
+}
+
+/// `src` with its name, if it has one, e.g. "aa:bb:cc:dd:ee:ff (trailer-1)".
+pub fn with_name(src: &str, name: Option<&str>) -> String {
+    match name {
+        Some(name) => format!("{src} ({name})"),
+        None => src.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sequence::Sequence;
+
+    #[test]
+    fn names_can_have_letters_digits_and_some_punctuation() {
+        for name in ["", "trailer-1", "Bike_2", "v1.2", "a", "0123456789abcdef"] {
+            assert_eq!(check_name(name), Ok(()), "{name}");
+        }
+    }
+
+    #[test]
+    fn names_are_short_and_plain() {
+        for name in [
+            "0123456789abcdefg",
+            "trailer 1",
+            "trailer/1",
+            "trailer:1",
+            "tráiler",
+            "-trailer",
+            ".trailer",
+            "\u{0}",
+        ] {
+            assert!(check_name(name).is_err(), "{name}");
+        }
+        // The length is in bytes, so fewer non-ASCII characters are too long as well
+        assert!(check_name(&"é".repeat(9)).unwrap_err().contains("longer"));
+        assert_eq!(check_name("-x").unwrap_err(), "\"-x\" starts with a \"-\"");
+        assert_eq!(check_name("a b").unwrap_err(), "\"a b\" has a ' '");
+    }
+
+    #[test]
+    fn invalid_names_go_out_as_no_name() {
+        assert_eq!(valid_name("trailer-1"), "trailer-1");
+        assert_eq!(valid_name("trailer 1"), "");
+        assert_eq!(valid_name(""), "");
+    }
+
+    #[test]
+    fn every_tenth_fix_has_the_name_starting_with_the_first() {
+        let mut sequence = Sequence::resume(7, 0);
+        let due: Vec<_> = (0..35)
+            .map(|_| sequence.next().1)
+            .filter(|&seq| name_due(seq))
+            .collect();
+        assert_eq!(due, [0, 10, 20, 30]);
+        // Resuming after deep sleep keeps counting
+        assert!(!name_due(Sequence::resume(7, 31).next().1));
+        assert!(name_due(Sequence::resume(7, 40).next().1));
+    }
+
+    #[test]
+    fn the_reported_name_wins() {
+        assert_eq!(
+            resolve("trailer", Some("bike")),
+            Resolved {
+                name: Some("trailer"),
+                conflict: Some("bike"),
+            }
+        );
+        assert_eq!(
+            resolve("trailer", Some("trailer")),
+            Resolved {
+                name: Some("trailer"),
+                conflict: None,
+            }
+        );
+        assert_eq!(
+            resolve("trailer", None),
+            Resolved {
+                name: Some("trailer"),
+                conflict: None,
+            }
+        );
+    }
+
+    #[test]
+    fn without_a_reported_name_the_gateway_names_it() {
+        assert_eq!(
+            resolve("", Some("bike")),
+            Resolved {
+                name: Some("bike"),
+                conflict: None,
+            }
+        );
+        let nameless = Resolved {
+            name: None,
+            conflict: None,
+        };
+        assert_eq!(resolve("", None), nameless);
+        // An empty name at the gateway is no name either
+        assert_eq!(resolve("", Some("")), nameless);
+        assert_eq!(resolve("trailer", Some("")).conflict, None);
+    }
+
+    #[test]
+    fn names_go_after_the_source() {
+        assert_eq!(
+            with_name("aa:bb:cc:dd:ee:ff", Some("trailer-1")),
+            "aa:bb:cc:dd:ee:ff (trailer-1)"
+        );
+        assert_eq!(with_name("aa:bb:cc:dd:ee:ff", None), "aa:bb:cc:dd:ee:ff");
+    }
+}
@@ -0,0 +1,418 @@
+//! Recovery from a full or corrupted NVS partition. Instead of crashing, we make room by erasing
+//! the namespaces we can do without, least critical first, and carry on without persistence when
+//! that doesn't help. What was sacrificed goes into a report, which is logged right away and again
+//! with the next stats.
+//!
+//! When the partition doesn't even initialize, namespaces can't be erased one by one. The whole
+//! partition is only erased when none of its namespaces hold credentials.
+
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_sys::esp;
+use esp_idf_sys::EspError;
+use log::*;
+use std::ffi::CStr;
+use std::ffi::CString;
+use std::fmt;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+
+/// How bad it is to lose a namespace. Recovery erases caches before stats, and never erases
+/// credentials.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Criticality {
+    /// Data that is rebuilt by itself, e.g. the last fixes or PHY calibration
+    Cache,
+    /// Counters and logs. These are lost for good, but the device works fine without them.
+    Stats,
+    /// Keys and passwords, without these the device is cut off
+    Credentials,
+}
+
+/// A namespace the device keeps in NVS. Namespaces that aren't listed are never erased.
+#[derive(Clone, Copy, Debug)]
+pub struct Namespace {
+    pub name: &'static str,
+    pub criticality: Criticality,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Failure {
+    Full,
+    Corrupted,
+}
+
+/// Whether an NVS error `code` means the partition is full or corrupted, None for other errors.
+pub fn classify(code: i32) -> Option<Failure> {
+    match code as u32 {
+        esp_idf_sys::ESP_ERR_NVS_NOT_ENOUGH_SPACE | esp_idf_sys::ESP_ERR_NVS_NO_FREE_PAGES => {
+            Some(Failure::Full)
+        }
+        esp_idf_sys::ESP_ERR_NVS_NEW_VERSION_FOUND
+        | esp_idf_sys::ESP_ERR_NVS_INVALID_STATE
+        | esp_idf_sys::ESP_ERR_NVS_REMOVE_FAILED
+        | esp_idf_sys::ESP_ERR_NVS_CORRUPT_KEY_PART => Some(Failure::Corrupted),
+        _ => None,
+    }
+}
+
+/// What recovery erases. This is NVS itself on the device.
+pub trait NvsStore {
+    fn erase_namespace(&mut self, name: &str) -> Result<(), i32>;
+    fn erase_partition(&mut self) -> Result<(), i32>;
+}
+
+/// What happened during a recovery.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Report {
+    pub failure: Failure,
+    pub code: i32,
+    // Namespaces that were erased, in the order they were, "*" for the whole partition
+    pub erased: Vec<&'static str>,
+    // Namespaces we tried to erase but couldn't
+    pub failed: Vec<&'static str>,
+    // Whether NVS works again. If not, the device runs without persistence.
+    pub recovered: bool,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let list = |names: &[&str]| {
+            if names.is_empty() {
+                "none".to_string()
+            } else {
+                names.join(",")
+            }
+        };
+        write!(
+            f,
+            "cause={} code=0x{:x} erased={} failed={} persistence={}",
+            match self.failure {
+                Failure::Full => "full",
+                Failure::Corrupted => "corrupted",
+            },
+            self.code,
+            list(&self.erased),
+            list(&self.failed),
+            if self.recovered {
+                "restored"
+            } else {
+                "reduced"
+            }
+        )
+    }
+}
+
+/// Recover from NVS error `code`. The `namespaces` below credentials are erased one at a time,
+/// caches before stats and in the order they are listed otherwise. After every erase, `retry`
+/// repeats what failed, and recovery stops once it works. When we run out of namespaces, the whole
+/// partition is erased, but only if none of the `namespaces` are credentials. Returns None for
+/// codes that don't mean the partition is full or corrupted, erasing won't help with those.
+pub fn recover<S: NvsStore>(
+    store: &mut S,
+    namespaces: &[Namespace],
+    code: i32,
+    mut retry: impl FnMut() -> Result<(), i32>,
+) -> Option<Report> {
+    let failure = classify(code)?;
+    let mut report = Report {
+        failure,
+        code,
+        erased: Vec::new(),
+        failed: Vec::new(),
+        recovered: false,
+    };
+    let mut candidates: Vec<&Namespace> = namespaces
+        .iter()
+        .filter(|n| n.criticality < Criticality::Credentials)
+        .collect();
+    // A stable sort, so namespaces of the same criticality keep their order
+    candidates.sort_by_key(|n| n.criticality);
+    // None is the whole partition
+    let mut steps: Vec<Option<&'static str>> = candidates.iter().map(|n| Some(n.name)).collect();
+    if candidates.len() == namespaces.len() {
+        steps.push(None);
+    }
+
+    for step in steps {
+        let (name, result) = match step {
+            Some(name) => (name, store.erase_namespace(name)),
+            None => ("*", store.erase_partition()),
+        };
+        if result.is_err() {
+            report.failed.push(name);
+            continue;
+        }
+        report.erased.push(name);
+        match retry() {
+            Ok(()) => {
+                report.recovered = true;
+                break;
+            }
+            // Something else is wrong, erasing more won't help
+            Err(code) if classify(code).is_none() => break,
+            Err(_) => {}
+        }
+    }
+    Some(report)
+}
+
+// Set when NVS couldn't be recovered, nothing is written to it after that
+static REDUCED: AtomicBool = AtomicBool::new(false);
+// The namespaces of the device, as passed to `take_partition`
+static NAMESPACES: Mutex<&'static [Namespace]> = Mutex::new(&[]);
+// The last recovery, until it went out with the stats
+static REPORT: Mutex<Option<Report>> = Mutex::new(None);
+
+/// False when NVS couldn't be recovered and the device runs without persistence.
+pub fn persistence_available() -> bool {
+    !REDUCED.load(Ordering::Relaxed)
+}
+
+/// The report of the last recovery, once. Devices add this to their stats.
+pub fn take_report() -> Option<Report> {
+    REPORT.lock().unwrap().take()
+}
+
+fn record(report: Report) {
+    if report.recovered {
+        warn!("Recovered NVS: {report}");
+    } else {
+        error!("Unable to recover NVS, running without persistence: {report}");
+        REDUCED.store(true, Ordering::Relaxed);
+    }
+    *REPORT.lock().unwrap() = Some(report);
+}
+
+/// Initialize and take the default NVS partition, with the `namespaces` the device keeps in it.
+/// When the partition is full or corrupted, we try to recover. None when that didn't work, the
+/// device has to run without persistence then.
+pub fn take_partition(
+    namespaces: &'static [Namespace],
+) -> Result<Option<EspDefaultNvsPartition>, EspError> {
+    *NAMESPACES.lock().unwrap() = namespaces;
+    // `take` erases the whole partition by itself when it doesn't initialize, credentials and
+    // all, so we initialize it first. Once it is, `take` leaves it alone.
+    let code = unsafe { esp_idf_sys::nvs_flash_init() };
+    if code == esp_idf_sys::ESP_OK as i32 {
+        return EspDefaultNvsPartition::take().map(Some);
+    }
+    let Some(report) = recover(&mut EspNvs, namespaces, code, || unsafe {
+        esp!(esp_idf_sys::nvs_flash_init()).map_err(|e| e.code())
+    }) else {
+        return Err(EspError::from(code).unwrap());
+    };
+    let recovered = report.recovered;
+    record(report);
+    if recovered {
+        EspDefaultNvsPartition::take().map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+/// Recover from NVS error `e` while writing, with `retry` repeating the write. Returns the error
+/// when NVS isn't full or corrupted, or when recovery didn't help.
+pub fn recover_write(
+    e: EspError,
+    mut retry: impl FnMut() -> Result<(), EspError>,
+) -> Result<(), EspError> {
+    let namespaces = *NAMESPACES.lock().unwrap();
+    let Some(report) = recover(&mut EspNvs, namespaces, e.code(), || {
+        retry().map_err(|e| e.code())
+    }) else {
+        return Err(e);
+    };
+    let recovered = report.recovered;
+    record(report);
+    if recovered {
+        Ok(())
+    } else {
+        Err(e)
+    }
+}
+
+/// Erase all keys in `namespace`.
+pub fn erase_namespace(namespace: &CStr) -> Result<(), EspError> {
+    let mut handle: esp_idf_sys::nvs_handle_t = 0;
+    esp!(unsafe {
+        esp_idf_sys::nvs_open(
+            namespace.as_ptr(),
+            esp_idf_sys::nvs_open_mode_t_NVS_READWRITE,
+            &mut handle,
+        )
+    })?;
+    let result = esp!(unsafe { esp_idf_sys::nvs_erase_all(handle) })
+        .and_then(|_| esp!(unsafe { esp_idf_sys::nvs_commit(handle) }));
+    unsafe { esp_idf_sys::nvs_close(handle) };
+    result
+}
+
+struct EspNvs;
+
+impl NvsStore for EspNvs {
+    fn erase_namespace(&mut self, name: &str) -> Result<(), i32> {
+        let namespace = CString::new(name).map_err(|_| esp_idf_sys::ESP_ERR_INVALID_ARG as i32)?;
+        erase_namespace(&namespace).map_err(|e| e.code())
+    }
+
+    fn erase_partition(&mut self) -> Result<(), i32> {
+        esp!(unsafe { esp_idf_sys::nvs_flash_erase() }).map_err(|e| e.code())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FULL: i32 = esp_idf_sys::ESP_ERR_NVS_NOT_ENOUGH_SPACE as i32;
+    const CORRUPTED: i32 = esp_idf_sys::ESP_ERR_NVS_CORRUPT_KEY_PART as i32;
+    const OTHER: i32 = esp_idf_sys::ESP_ERR_NVS_NOT_FOUND as i32;
+
+    const NAMESPACES: &[Namespace] = &[
+        Namespace {
+            name: "stats",
+            criticality: Criticality::Stats,
+        },
+        Namespace {
+            name: "wifi",
+            criticality: Criticality::Credentials,
+        },
+        Namespace {
+            name: "last_fix",
+            criticality: Criticality::Cache,
+        },
+        Namespace {
+            name: "phy",
+            criticality: Criticality::Cache,
+        },
+    ];
+
+    /// NVS that erases what it's asked to, except the namespaces in `broken`.
+    #[derive(Default)]
+    struct FakeNvs {
+        erased: Vec<String>,
+        broken: Vec<&'static str>,
+    }
+
+    impl NvsStore for FakeNvs {
+        fn erase_namespace(&mut self, name: &str) -> Result<(), i32> {
+            if self.broken.contains(&name) {
+                return Err(esp_idf_sys::ESP_FAIL);
+            }
+            self.erased.push(name.to_string());
+            Ok(())
+        }
+
+        fn erase_partition(&mut self) -> Result<(), i32> {
+            self.erased.push("*".to_string());
+            Ok(())
+        }
+    }
+
+    /// A retry that fails with `codes`, in order, and works after that.
+    fn retry(codes: &[i32]) -> impl FnMut() -> Result<(), i32> + '_ {
+        let mut codes = codes.iter();
+        move || codes.next().map_or(Ok(()), |&code| Err(code))
+    }
+
+    #[test]
+    fn full_and_corrupted_partitions_are_told_apart() {
+        assert_eq!(classify(FULL), Some(Failure::Full));
+        assert_eq!(
+            classify(esp_idf_sys::ESP_ERR_NVS_NO_FREE_PAGES as i32),
+            Some(Failure::Full)
+        );
+        assert_eq!(classify(CORRUPTED), Some(Failure::Corrupted));
+        assert_eq!(
+            classify(esp_idf_sys::ESP_ERR_NVS_NEW_VERSION_FOUND as i32),
+            Some(Failure::Corrupted)
+        );
+        assert_eq!(classify(OTHER), None);
+        assert_eq!(classify(esp_idf_sys::ESP_FAIL), None);
+    }
+
+    #[test]
+    fn caches_go_before_stats_and_credentials_never() {
+        let mut nvs = FakeNvs::default();
+        let report = recover(&mut nvs, NAMESPACES, FULL, retry(&[FULL; 10])).unwrap();
+        assert_eq!(nvs.erased, ["last_fix", "phy", "stats"]);
+        assert_eq!(
+            report,
+            Report {
+                failure: Failure::Full,
+                code: FULL,
+                erased: vec!["last_fix", "phy", "stats"],
+                failed: vec![],
+                recovered: false,
+            }
+        );
+    }
+
+    #[test]
+    fn recovery_stops_once_the_retry_works() {
+        let mut nvs = FakeNvs::default();
+        let report = recover(&mut nvs, NAMESPACES, FULL, retry(&[FULL])).unwrap();
+        assert_eq!(nvs.erased, ["last_fix", "phy"]);
+        assert!(report.recovered);
+    }
+
+    #[test]
+    fn the_partition_is_erased_when_it_holds_no_credentials() {
+        let mut nvs = FakeNvs::default();
+        let namespaces: Vec<Namespace> = NAMESPACES
+            .iter()
+            .filter(|n| n.criticality != Criticality::Credentials)
+            .copied()
+            .collect();
+        let report = recover(&mut nvs, &namespaces, CORRUPTED, retry(&[CORRUPTED; 3])).unwrap();
+        assert_eq!(report.erased, ["last_fix", "phy", "stats", "*"]);
+        assert!(report.recovered);
+    }
+
+    #[test]
+    fn namespaces_that_cant_be_erased_are_skipped() {
+        let mut nvs = FakeNvs {
+            broken: vec!["last_fix"],
+            ..Default::default()
+        };
+        let report = recover(&mut nvs, NAMESPACES, FULL, retry(&[])).unwrap();
+        assert_eq!(report.erased, ["phy"]);
+        assert_eq!(report.failed, ["last_fix"]);
+        assert!(report.recovered);
+    }
+
+    #[test]
+    fn other_errors_stop_the_recovery() {
+        let mut nvs = FakeNvs::default();
+        assert_eq!(recover(&mut nvs, NAMESPACES, OTHER, retry(&[])), None);
+        assert!(nvs.erased.is_empty());
+        // Erasing won't help when the retry fails for another reason
+        let report = recover(&mut nvs, NAMESPACES, FULL, retry(&[OTHER])).unwrap();
+        assert_eq!(nvs.erased, ["last_fix"]);
+        assert!(!report.recovered);
+    }
+
+    #[test]
+    fn reports_are_formatted_for_the_stats() {
+        let mut report = Report {
+            failure: Failure::Corrupted,
+            code: 0x1117,
+            erased: vec![],
+            failed: vec![],
+            recovered: false,
+        };
+        assert_eq!(
+            report.to_string(),
+            "cause=corrupted code=0x1117 erased=none failed=none persistence=reduced"
+        );
+        report.failure = Failure::Full;
+        report.erased = vec!["last_fix", "phy"];
+        report.failed = vec!["stats"];
+        report.recovered = true;
+        assert_eq!(
+            report.to_string(),
+            "cause=full code=0x1117 erased=last_fix,phy failed=stats persistence=restored"
+        );
+    }
+}
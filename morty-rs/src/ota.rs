@@ -0,0 +1,461 @@
+//! Signed manifests for over the air updates. A manifest says which image to install: its
+//! version, where to download it, its SHA-256 and the oldest version it can be installed over.
+//! It's signed with an Ed25519 key of the owner, and devices only install images from manifests
+//! that carry a valid signature of the public key they were built with.
+//!
+//! A manifest is text, one `key=value` per line, with the signature on the last line. The
+//! signature is over all the bytes before that line, so the manifest doesn't have to be
+//! canonicalized before checking it:
+//!
+//! ```text
+//! version=0.2.0
+//! url=https://example.com/morty-gateway-0.2.0.bin
+//! sha256=<64 hex digits>
+//! min_compatible_version=0.1.0
+//! allow_downgrade=false
+//! signature=<128 hex digits>
+//! ```
+//!
+//! This only decides, downloading and writing the image is up to the device.
+
+use ed25519_compact::PublicKey;
+use ed25519_compact::Signature;
+use sha2::Digest;
+use sha2::Sha256;
+use std::fmt;
+use std::str::FromStr;
+
+const SIGNATURE_KEY: &str = "signature=";
+
+/// A version of the firmware, e.g. "0.2.0".
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Version {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl FromStr for Version {
+    type Err = Rejection;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let malformed = || Rejection::Malformed(format!("invalid version \"{s}\""));
+        let mut parts = s.trim().split('.').map(|p| p.parse::<u32>());
+        let mut next = || parts.next().and_then(|p| p.ok()).ok_or_else(malformed);
+        let version = Version {
+            major: next()?,
+            minor: next()?,
+            patch: next()?,
+        };
+        if parts.next().is_some() {
+            return Err(malformed());
+        }
+        Ok(version)
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Why a manifest or image isn't installed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Rejection {
+    /// The device was built without a public key, so it can't check anything
+    NoKey,
+    Malformed(String),
+    /// The signature doesn't match the manifest and our key
+    BadSignature,
+    /// The manifest is for an older version and doesn't allow downgrades
+    Downgrade {
+        running: Version,
+        offered: Version,
+    },
+    /// The image can't be installed over the version we run
+    Incompatible {
+        running: Version,
+        min: Version,
+    },
+    /// The device is pinned to another version
+    Pinned {
+        pinned: Version,
+        offered: Version,
+    },
+    /// The downloaded image isn't the one in the manifest
+    HashMismatch,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::NoKey => write!(f, "no public key to check manifests with"),
+            Rejection::Malformed(e) => write!(f, "malformed manifest: {e}"),
+            Rejection::BadSignature => write!(f, "invalid signature"),
+            Rejection::Downgrade { running, offered } => {
+                write!(f, "downgrade from {running} to {offered} isn't allowed")
+            }
+            Rejection::Incompatible { running, min } => {
+                write!(f, "image needs at least {min}, we run {running}")
+            }
+            Rejection::Pinned { pinned, offered } => {
+                write!(f, "pinned to {pinned}, not installing {offered}")
+            }
+            Rejection::HashMismatch => write!(f, "image doesn't match its SHA-256"),
+        }
+    }
+}
+
+impl std::error::Error for Rejection {}
+
+/// A manifest with a valid signature.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Manifest {
+    pub version: Version,
+    pub url: String,
+    pub sha256: [u8; 32],
+    pub min_compatible_version: Version,
+    pub allow_downgrade: bool,
+}
+
+impl Manifest {
+    /// Check the signature of `text` with `public_key` and parse it. The signature is checked
+    /// before anything else is looked at.
+    pub fn verify(text: &str, public_key: Option<&[u8; 32]>) -> Result<Self, Rejection> {
+        let public_key = public_key.ok_or(Rejection::NoKey)?;
+        let start = text
+            .rfind(SIGNATURE_KEY)
+            .filter(|&i| i == 0 || text.as_bytes()[i - 1] == b'\n')
+            .ok_or_else(|| Rejection::Malformed("no signature".to_string()))?;
+        let (payload, signature) = text.split_at(start);
+        let signature = hex::<64>(signature[SIGNATURE_KEY.len()..].trim())
+            .ok_or_else(|| Rejection::Malformed("invalid signature".to_string()))?;
+        PublicKey::new(*public_key)
+            .verify(payload.as_bytes(), &Signature::new(signature))
+            .map_err(|_| Rejection::BadSignature)?;
+        Self::parse(payload)
+    }
+
+    fn parse(payload: &str) -> Result<Self, Rejection> {
+        let mut version = None;
+        let mut url = None;
+        let mut sha256 = None;
+        let mut min_compatible_version = None;
+        let mut allow_downgrade = false;
+        for line in payload.lines().filter(|l| !l.trim().is_empty()) {
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| Rejection::Malformed(format!("invalid line \"{line}\"")))?;
+            let value = value.trim();
+            match key.trim() {
+                "version" => version = Some(value.parse()?),
+                "url" => url = Some(value.to_string()),
+                "sha256" => {
+                    sha256 = Some(
+                        hex::<32>(value)
+                            .ok_or_else(|| Rejection::Malformed("invalid sha256".to_string()))?,
+                    )
+                }
+                "min_compatible_version" => min_compatible_version = Some(value.parse()?),
+                "allow_downgrade" => {
+                    allow_downgrade = value.parse().map_err(|_| {
+                        Rejection::Malformed(format!("invalid allow_downgrade \"{value}\""))
+                    })?
+                }
+                // Newer manifests can have more, the signature covers them anyway
+                _ => {}
+            }
+        }
+        let missing = |name: &str| Rejection::Malformed(format!("no {name}"));
+        Ok(Self {
+            version: version.ok_or_else(|| missing("version"))?,
+            url: url.ok_or_else(|| missing("url"))?,
+            sha256: sha256.ok_or_else(|| missing("sha256"))?,
+            min_compatible_version: min_compatible_version
+                .ok_or_else(|| missing("min_compatible_version"))?,
+            allow_downgrade,
+        })
+    }
+
+    /// Whether to install this manifest on a device that runs `running` and is pinned to
+    /// `pinned`, if anything. Ok(false) when we already run it.
+    pub fn should_install(
+        &self,
+        running: Version,
+        pinned: Option<Version>,
+    ) -> Result<bool, Rejection> {
+        if self.version == running {
+            return Ok(false);
+        }
+        if let Some(pinned) = pinned.filter(|&p| p != self.version) {
+            return Err(Rejection::Pinned {
+                pinned,
+                offered: self.version,
+            });
+        }
+        if self.version < running && !self.allow_downgrade {
+            return Err(Rejection::Downgrade {
+                running,
+                offered: self.version,
+            });
+        }
+        if running < self.min_compatible_version {
+            return Err(Rejection::Incompatible {
+                running,
+                min: self.min_compatible_version,
+            });
+        }
+        Ok(true)
+    }
+}
+
+/// Hashes an image while it's downloaded, to check it against its manifest at the end.
+#[derive(Default)]
+pub struct ImageCheck {
+    sha256: Sha256,
+    len: usize,
+}
+
+impl ImageCheck {
+    pub fn update(&mut self, chunk: &[u8]) {
+        self.sha256.update(chunk);
+        self.len += chunk.len();
+    }
+
+    /// Number of bytes seen so far.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether the image is the one in `manifest`.
+    pub fn finish(self, manifest: &Manifest) -> Result<(), Rejection> {
+        if self.sha256.finalize()[..] == manifest.sha256[..] {
+            Ok(())
+        } else {
+            Err(Rejection::HashMismatch)
+        }
+    }
+}
+
+/// `s` as `N` bytes of hex, None when it isn't.
+pub fn hex<const N: usize>(s: &str) -> Option<[u8; N]> {
+    if s.len() != N * 2 || !s.is_ascii() {
+        return None;
+    }
+    let mut bytes = [0u8; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_compact::KeyPair;
+    use ed25519_compact::Seed;
+
+    // SHA-256 of "abc"
+    const ABC_SHA256: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    fn key_pair(seed: u8) -> KeyPair {
+        KeyPair::from_seed(Seed::new([seed; 32]))
+    }
+
+    fn to_hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    fn version(s: &str) -> Version {
+        s.parse().unwrap()
+    }
+
+    /// The payload of a manifest, like sign_manifest.py writes it.
+    fn payload(version: &str, min_compatible_version: &str, allow_downgrade: bool) -> String {
+        format!(
+            "version={version}\n\
+             url=https://example.com/morty-gateway-{version}.bin\n\
+             sha256={ABC_SHA256}\n\
+             min_compatible_version={min_compatible_version}\n\
+             allow_downgrade={allow_downgrade}\n"
+        )
+    }
+
+    fn sign(payload: &str, key_pair: &KeyPair) -> String {
+        let signature = key_pair.sk.sign(payload.as_bytes(), None);
+        format!("{payload}signature={}\n", to_hex(&signature[..]))
+    }
+
+    fn verify(text: &str) -> Result<Manifest, Rejection> {
+        Manifest::verify(text, Some(&*key_pair(1).pk))
+    }
+
+    fn malformed(e: &str) -> Result<Manifest, Rejection> {
+        Err(Rejection::Malformed(e.to_string()))
+    }
+
+    #[test]
+    fn versions_parse_and_order() {
+        assert_eq!(
+            version(" 1.10.3 "),
+            Version {
+                major: 1,
+                minor: 10,
+                patch: 3
+            }
+        );
+        assert_eq!(version("1.10.3").to_string(), "1.10.3");
+        assert!(version("0.10.0") > version("0.9.9"));
+        assert!(version("1.0.0") > version("0.99.99"));
+        for s in ["1.2", "1.2.3.4", "a.b.c", "1.2.-3", ""] {
+            assert!(s.parse::<Version>().is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn hex_is_exactly_n_bytes() {
+        assert_eq!(hex::<2>("00fF"), Some([0x00, 0xff]));
+        assert_eq!(hex::<2>("00f"), None);
+        assert_eq!(hex::<2>("00fff0"), None);
+        assert_eq!(hex::<2>("00fg"), None);
+        assert_eq!(hex::<2>("0é0"), None);
+    }
+
+    #[test]
+    fn a_valid_manifest_is_parsed() {
+        let manifest = verify(&sign(&payload("0.2.0", "0.1.0", false), &key_pair(1))).unwrap();
+        assert_eq!(
+            manifest,
+            Manifest {
+                version: version("0.2.0"),
+                url: "https://example.com/morty-gateway-0.2.0.bin".to_string(),
+                sha256: hex(ABC_SHA256).unwrap(),
+                min_compatible_version: version("0.1.0"),
+                allow_downgrade: false,
+            }
+        );
+    }
+
+    #[test]
+    fn a_tampered_manifest_is_rejected() {
+        let text = sign(&payload("0.2.0", "0.1.0", false), &key_pair(1));
+        let tampered = text.replace("example.com", "example.net");
+        assert_eq!(verify(&tampered), Err(Rejection::BadSignature));
+        let tampered = text.replace("allow_downgrade=false", "allow_downgrade=true");
+        assert_eq!(verify(&tampered), Err(Rejection::BadSignature));
+        // Lines after the signature aren't signed
+        assert_eq!(
+            verify(&format!("{text}version=0.3.0\n")),
+            malformed("invalid signature")
+        );
+    }
+
+    #[test]
+    fn a_manifest_signed_with_another_key_is_rejected() {
+        let text = sign(&payload("0.2.0", "0.1.0", false), &key_pair(2));
+        assert_eq!(verify(&text), Err(Rejection::BadSignature));
+    }
+
+    #[test]
+    fn a_manifest_without_a_key_or_a_signature_is_rejected() {
+        let text = sign(&payload("0.2.0", "0.1.0", false), &key_pair(1));
+        assert_eq!(Manifest::verify(&text, None), Err(Rejection::NoKey));
+        assert_eq!(
+            verify(&payload("0.2.0", "0.1.0", false)),
+            malformed("no signature")
+        );
+        // A key that ends in "signature=" isn't the signature
+        let text = format!("x_signature=00\n{}", payload("0.2.0", "0.1.0", false));
+        assert_eq!(verify(&text), malformed("no signature"));
+    }
+
+    #[test]
+    fn signed_manifests_are_still_checked() {
+        let incomplete = payload("0.2.0", "0.1.0", false).replace("url=", "x_url=");
+        assert_eq!(
+            verify(&sign(&incomplete, &key_pair(1))),
+            malformed("no url")
+        );
+        let invalid = payload("0.2.0", "0.1", false);
+        assert_eq!(
+            verify(&sign(&invalid, &key_pair(1))),
+            malformed("invalid version \"0.1\"")
+        );
+    }
+
+    #[test]
+    fn upgrades_are_installed_and_downgrades_refused() {
+        let manifest = |v: &str, min: &str, allow_downgrade| {
+            verify(&sign(&payload(v, min, allow_downgrade), &key_pair(1))).unwrap()
+        };
+        let running = version("0.2.0");
+        assert_eq!(
+            manifest("0.2.0", "0.1.0", false).should_install(running, None),
+            Ok(false)
+        );
+        assert_eq!(
+            manifest("0.3.0", "0.1.0", false).should_install(running, None),
+            Ok(true)
+        );
+        assert_eq!(
+            manifest("0.1.0", "0.1.0", false).should_install(running, None),
+            Err(Rejection::Downgrade {
+                running,
+                offered: version("0.1.0")
+            })
+        );
+        assert_eq!(
+            manifest("0.1.0", "0.1.0", true).should_install(running, None),
+            Ok(true)
+        );
+        assert_eq!(
+            manifest("0.3.0", "0.2.1", false).should_install(running, None),
+            Err(Rejection::Incompatible {
+                running,
+                min: version("0.2.1")
+            })
+        );
+    }
+
+    #[test]
+    fn a_pinned_device_only_installs_its_version() {
+        let manifest = verify(&sign(&payload("0.3.0", "0.1.0", false), &key_pair(1))).unwrap();
+        let running = version("0.2.0");
+        assert_eq!(
+            manifest.should_install(running, Some(version("0.2.5"))),
+            Err(Rejection::Pinned {
+                pinned: version("0.2.5"),
+                offered: version("0.3.0")
+            })
+        );
+        assert_eq!(
+            manifest.should_install(running, Some(version("0.3.0"))),
+            Ok(true)
+        );
+        // Pinning doesn't allow a downgrade
+        let manifest = verify(&sign(&payload("0.1.0", "0.1.0", false), &key_pair(1))).unwrap();
+        assert!(matches!(
+            manifest.should_install(running, Some(version("0.1.0"))),
+            Err(Rejection::Downgrade { .. })
+        ));
+    }
+
+    #[test]
+    fn images_are_checked_against_their_manifest() {
+        let manifest = verify(&sign(&payload("0.2.0", "0.1.0", false), &key_pair(1))).unwrap();
+        let mut image = ImageCheck::default();
+        assert!(image.is_empty());
+        image.update(b"a");
+        image.update(b"bc");
+        assert_eq!(image.len(), 3);
+        assert_eq!(image.finish(&manifest), Ok(()));
+        let mut image = ImageCheck::default();
+        image.update(b"abd");
+        assert_eq!(image.finish(&manifest), Err(Rejection::HashMismatch));
+    }
+}
@@ -0,0 +1,134 @@
+//! Where a device gets its power from. Devices sense external (USB) power on a vbus pin and read
+//! the battery voltage through a divider on an ADC pin.
+
+use esp_idf_hal::adc;
+use esp_idf_hal::gpio;
+use std::time::Duration;
+
+// Number of consecutive identical reads of a pin needed before we trust the value
+const DEBOUNCE_READS: u32 = 5;
+const DEBOUNCE_INTERVAL: Duration = Duration::from_millis(10);
+// ADC reading per volt of battery, for the divider on the battery sense pin
+const VBAT_READING_PER_VOLT: f32 = 262.0;
+
+/// Read a pin, e.g. vbus sense, a couple of times. Returns None when the reads don't agree, e.g.
+/// because of a flaky cable.
+pub fn read_level_debounced<P: gpio::Pin>(
+    pin: &gpio::PinDriver<'_, P, gpio::Input>,
+) -> Option<bool> {
+    let first = pin.is_high();
+    for _ in 1..DEBOUNCE_READS {
+        std::thread::sleep(DEBOUNCE_INTERVAL);
+        if pin.is_high() != first {
+            return None;
+        }
+    }
+    Some(first)
+}
+
+/// Battery voltage in volts.
+pub fn read_battery_voltage<T: gpio::ADCPin>(
+    vbat_driver: &mut adc::AdcChannelDriver<T, adc::Atten11dB<adc::ADC1>>,
+    adc: &mut adc::AdcDriver<impl adc::Adc>,
+) -> Result<f32, anyhow::Error>
+where
+    adc::Atten11dB<adc::ADC1>: adc::Attenuation<<T as gpio::ADCPin>::Adc>,
+{
+    Ok(adc.read(vbat_driver)? as f32 / VBAT_READING_PER_VOLT)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PowerSource {
+    External,
+    Battery,
+}
+
+/// Decides from vbus readings whether we run on external power or on battery. The source only
+/// changes after `readings` readings in a row that say so, so a short dip or a flaky cable doesn't
+/// flip it back and forth. We assume external power until told otherwise.
+pub struct PowerMonitor {
+    readings: u32,
+    source: PowerSource,
+    // Readings in a row that disagree with `source`
+    disagreeing: u32,
+}
+
+impl PowerMonitor {
+    pub fn new(readings: u32) -> Self {
+        Self {
+            readings,
+            source: PowerSource::External,
+            disagreeing: 0,
+        }
+    }
+
+    pub fn source(&self) -> PowerSource {
+        self.source
+    }
+
+    /// Handle a vbus reading, None when the pin couldn't be read reliably. Returns the new source
+    /// when it changed.
+    pub fn update(&mut self, vbus: Option<bool>) -> Option<PowerSource> {
+        let reading = if vbus? {
+            PowerSource::External
+        } else {
+            PowerSource::Battery
+        };
+        if reading == self.source {
+            self.disagreeing = 0;
+            return None;
+        }
+        self.disagreeing += 1;
+        if self.disagreeing < self.readings {
+            return None;
+        }
+        self.disagreeing = 0;
+        self.source = reading;
+        Some(reading)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn external_power_is_assumed_at_first() {
+        let mut monitor = PowerMonitor::new(3);
+        assert_eq!(monitor.source(), PowerSource::External);
+        assert_eq!(monitor.update(Some(true)), None);
+    }
+
+    #[test]
+    fn the_source_changes_after_enough_readings_in_a_row() {
+        let mut monitor = PowerMonitor::new(3);
+        assert_eq!(monitor.update(Some(false)), None);
+        assert_eq!(monitor.update(Some(false)), None);
+        assert_eq!(monitor.update(Some(false)), Some(PowerSource::Battery));
+        assert_eq!(monitor.source(), PowerSource::Battery);
+        assert_eq!(monitor.update(Some(false)), None);
+
+        assert_eq!(monitor.update(Some(true)), None);
+        assert_eq!(monitor.update(Some(true)), None);
+        assert_eq!(monitor.update(Some(true)), Some(PowerSource::External));
+    }
+
+    #[test]
+    fn a_short_dip_doesnt_change_the_source() {
+        let mut monitor = PowerMonitor::new(3);
+        monitor.update(Some(false));
+        monitor.update(Some(false));
+        assert_eq!(monitor.update(Some(true)), None);
+        assert_eq!(monitor.update(Some(false)), None);
+        assert_eq!(monitor.update(Some(false)), None);
+        assert_eq!(monitor.source(), PowerSource::External);
+    }
+
+    #[test]
+    fn unreliable_readings_are_skipped() {
+        let mut monitor = PowerMonitor::new(2);
+        monitor.update(Some(false));
+        assert_eq!(monitor.update(None), None);
+        assert_eq!(monitor.update(Some(false)), Some(PowerSource::Battery));
+    }
+}
@@ -0,0 +1,192 @@
+//! Beacons a tracker heard recently, from their BeaconPresentMsg. A tracker keeps these across
+//! deep sleep, so it knows what's around before it sends. Times are passed in by the caller and
+//! have to come from a clock that keeps running while asleep.
+
+use crate::duty::ListenSchedule;
+use std::time::Duration;
+
+/// A beacon we heard.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sighting {
+    pub mac: [u8; 6],
+    // Channel the beacon listens on, 0 when it didn't say
+    pub channel: u8,
+    // Signal strength, None when the radio didn't tell us
+    pub rssi: Option<i8>,
+    pub seen_at: Duration,
+    // When the beacon listens, see `crate::duty`
+    pub schedule: ListenSchedule,
+}
+
+/// The last `N` beacons we heard, forgetting them after `max_age`. When the table is full, a new
+/// beacon takes the place of the one we heard longest ago.
+#[derive(Clone, Copy, Debug)]
+pub struct PresenceTable<const N: usize> {
+    entries: [Option<Sighting>; N],
+    max_age: Duration,
+}
+
+impl<const N: usize> PresenceTable<N> {
+    /// An empty table. This is const, so the table can live in a static in RTC memory.
+    pub const fn new(max_age: Duration) -> Self {
+        Self {
+            entries: [None; N],
+            max_age,
+        }
+    }
+
+    /// Record that we heard a beacon, replacing what we knew about it.
+    pub fn record(&mut self, sighting: Sighting) {
+        let slot = self
+            .entries
+            .iter()
+            .position(|e| matches!(e, Some(e) if e.mac == sighting.mac))
+            .or_else(|| self.entries.iter().position(|e| e.is_none()))
+            .or_else(|| {
+                self.entries
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(_, e)| e.map(|e| e.seen_at))
+                    .map(|(i, _)| i)
+            });
+        if let Some(slot) = slot {
+            self.entries[slot] = Some(sighting);
+        }
+    }
+
+    /// Forget the beacons we heard more than `max_age` before `now`. Beacons heard after `now`
+    /// are forgotten as well, the clock must have gone back.
+    pub fn forget_old(&mut self, now: Duration) {
+        let max_age = self.max_age;
+        for entry in self.entries.iter_mut() {
+            if matches!(entry, Some(e) if !fresh(e, now, max_age)) {
+                *entry = None;
+            }
+        }
+    }
+
+    /// The beacons heard within `max_age` before `now`.
+    pub fn sightings(&self, now: Duration) -> impl Iterator<Item = &Sighting> {
+        let max_age = self.max_age;
+        self.entries
+            .iter()
+            .flatten()
+            .filter(move |e| fresh(e, now, max_age))
+    }
+
+    /// The beacon with the strongest signal heard within `max_age` before `now`. Beacons without
+    /// a signal strength come after the ones with one, and the most recent one wins a tie.
+    pub fn best(&self, now: Duration) -> Option<Sighting> {
+        self.sightings(now)
+            .max_by_key(|e| (e.rssi.is_some(), e.rssi, e.seen_at))
+            .copied()
+    }
+}
+
+fn fresh(sighting: &Sighting, now: Duration, max_age: Duration) -> bool {
+    sighting.seen_at <= now && now - sighting.seen_at <= max_age
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_AGE: Duration = Duration::from_secs(60);
+
+    fn sighting(last: u8, rssi: Option<i8>, seen_at: u64) -> Sighting {
+        Sighting {
+            mac: [0x24, 0x0a, 0xc4, 0, 0, last],
+            channel: 1,
+            rssi,
+            seen_at: Duration::from_secs(seen_at),
+            schedule: ListenSchedule::ALWAYS,
+        }
+    }
+
+    fn macs<const N: usize>(table: &PresenceTable<N>, now: u64) -> Vec<u8> {
+        let mut macs: Vec<u8> = table
+            .sightings(Duration::from_secs(now))
+            .map(|s| s.mac[5])
+            .collect();
+        macs.sort();
+        macs
+    }
+
+    #[test]
+    fn a_beacon_takes_a_single_entry() {
+        let mut table = PresenceTable::<4>::new(MAX_AGE);
+        table.record(sighting(1, Some(-70), 10));
+        table.record(sighting(1, Some(-50), 20));
+        let sightings: Vec<&Sighting> = table.sightings(Duration::from_secs(20)).collect();
+        assert_eq!(sightings, [&sighting(1, Some(-50), 20)]);
+    }
+
+    #[test]
+    fn a_full_table_replaces_the_oldest_beacon() {
+        let mut table = PresenceTable::<2>::new(MAX_AGE);
+        table.record(sighting(1, None, 20));
+        table.record(sighting(2, None, 10));
+        table.record(sighting(3, None, 30));
+        assert_eq!(macs(&table, 30), [1, 3]);
+    }
+
+    #[test]
+    fn old_beacons_are_forgotten() {
+        let mut table = PresenceTable::<4>::new(MAX_AGE);
+        table.record(sighting(1, None, 10));
+        table.record(sighting(2, None, 40));
+        assert_eq!(macs(&table, 70), [1, 2]);
+        assert_eq!(macs(&table, 71), [2]);
+        table.forget_old(Duration::from_secs(71));
+        assert_eq!(macs(&table, 10), []);
+        assert_eq!(macs(&table, 40), [2]);
+    }
+
+    #[test]
+    fn beacons_from_the_future_are_forgotten() {
+        let mut table = PresenceTable::<4>::new(MAX_AGE);
+        table.record(sighting(1, None, 100));
+        assert_eq!(macs(&table, 99), []);
+        table.forget_old(Duration::from_secs(99));
+        assert_eq!(macs(&table, 100), []);
+    }
+
+    #[test]
+    fn the_strongest_beacon_is_best() {
+        let mut table = PresenceTable::<4>::new(MAX_AGE);
+        let now = Duration::from_secs(30);
+        assert_eq!(table.best(now), None);
+        table.record(sighting(1, None, 30));
+        table.record(sighting(2, Some(-80), 10));
+        table.record(sighting(3, Some(-60), 10));
+        assert_eq!(table.best(now).unwrap().mac[5], 3);
+        // The most recent one wins a tie
+        table.record(sighting(4, Some(-60), 20));
+        assert_eq!(table.best(now).unwrap().mac[5], 4);
+    }
+
+    #[test]
+    fn beacons_without_a_signal_strength_are_a_last_resort() {
+        let mut table = PresenceTable::<4>::new(MAX_AGE);
+        table.record(sighting(1, None, 30));
+        assert_eq!(table.best(Duration::from_secs(30)).unwrap().mac[5], 1);
+        table.record(sighting(2, Some(-95), 10));
+        assert_eq!(table.best(Duration::from_secs(30)).unwrap().mac[5], 2);
+    }
+
+    #[test]
+    fn a_table_without_room_records_nothing() {
+        let mut table = PresenceTable::<0>::new(MAX_AGE);
+        table.record(sighting(1, Some(-50), 10));
+        assert_eq!(table.best(Duration::from_secs(10)), None);
+    }
+
+    #[test]
+    fn a_table_can_live_in_a_static() {
+        static TABLE: std::sync::Mutex<PresenceTable<2>> =
+            std::sync::Mutex::new(PresenceTable::new(MAX_AGE));
+        let mut table = TABLE.lock().unwrap();
+        table.record(sighting(1, Some(-50), 10));
+        assert_eq!(macs(&table, 10), [1]);
+    }
+}
@@ -0,0 +1,161 @@
+//! Reporting profiles of a tracker: how often it reports, whether it uses the LED and the TX power
+//! it sends with. The name of the active profile goes out with every report, so the backend knows
+//! how often to expect one.
+
+use crate::comm::MAX_TX_POWER_DBM;
+use crate::comm::MIN_TX_POWER_DBM;
+use anyhow::bail;
+use std::time::Duration;
+
+/// Longest profile name, since it's part of every report.
+pub const MAX_PROFILE_NAME_LEN: usize = 12;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Profile {
+    pub name: &'static str,
+    pub interval: Duration,
+    // Show the fix state and reports on the LED
+    pub led: bool,
+    pub tx_power_dbm: f32,
+}
+
+impl Profile {
+    /// Check that the profile can be used.
+    pub fn check(&self) -> Result<(), anyhow::Error> {
+        let name = self.name;
+        if name.is_empty() || name.len() > MAX_PROFILE_NAME_LEN {
+            bail!("Profile \"{name}\": name has to be 1 to {MAX_PROFILE_NAME_LEN} characters");
+        }
+        if !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            bail!("Profile \"{name}\": name can only have letters, digits, '-' and '_'");
+        }
+        if self.interval.is_zero() {
+            bail!("Profile \"{name}\": interval can't be zero");
+        }
+        if !(MIN_TX_POWER_DBM..=MAX_TX_POWER_DBM).contains(&self.tx_power_dbm) {
+            bail!(
+                "Profile \"{name}\": TX power {} dBm is outside {MIN_TX_POWER_DBM} to {MAX_TX_POWER_DBM} dBm",
+                self.tx_power_dbm
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Check every profile, and that no two have the same name.
+pub fn check_profiles(profiles: &[Profile]) -> Result<(), anyhow::Error> {
+    for (i, profile) in profiles.iter().enumerate() {
+        profile.check()?;
+        if profiles[..i].iter().any(|p| p.name == profile.name) {
+            bail!("Profile \"{}\" is defined twice", profile.name);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NORMAL: Profile = Profile {
+        name: "normal",
+        interval: Duration::from_secs(60),
+        led: true,
+        tx_power_dbm: 8.5,
+    };
+
+    fn error(profile: Profile) -> String {
+        profile.check().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn a_sensible_profile_checks_out() {
+        assert!(NORMAL.check().is_ok());
+        let edge = Profile {
+            name: "low-power_12",
+            tx_power_dbm: MAX_TX_POWER_DBM,
+            ..NORMAL
+        };
+        assert!(edge.check().is_ok());
+    }
+
+    #[test]
+    fn names_have_to_be_short() {
+        let profile = Profile {
+            name: "much-too-long",
+            ..NORMAL
+        };
+        assert_eq!(
+            error(profile),
+            "Profile \"much-too-long\": name has to be 1 to 12 characters"
+        );
+        assert!(Profile { name: "", ..NORMAL }.check().is_err());
+    }
+
+    #[test]
+    fn names_have_to_be_plain() {
+        let profile = Profile {
+            name: "a b",
+            ..NORMAL
+        };
+        assert_eq!(
+            error(profile),
+            "Profile \"a b\": name can only have letters, digits, '-' and '_'"
+        );
+    }
+
+    #[test]
+    fn intervals_cant_be_zero() {
+        let profile = Profile {
+            interval: Duration::ZERO,
+            ..NORMAL
+        };
+        assert_eq!(error(profile), "Profile \"normal\": interval can't be zero");
+    }
+
+    #[test]
+    fn tx_power_has_to_be_in_range() {
+        let profile = Profile {
+            tx_power_dbm: 21.0,
+            ..NORMAL
+        };
+        assert_eq!(
+            error(profile),
+            "Profile \"normal\": TX power 21 dBm is outside 2 to 20 dBm"
+        );
+        let profile = Profile {
+            tx_power_dbm: 1.5,
+            ..NORMAL
+        };
+        assert!(profile.check().is_err());
+    }
+
+    #[test]
+    fn profiles_have_their_own_names() {
+        let fast = Profile {
+            name: "fast",
+            interval: Duration::from_secs(10),
+            ..NORMAL
+        };
+        assert!(check_profiles(&[NORMAL, fast]).is_ok());
+        assert_eq!(
+            check_profiles(&[NORMAL, fast, NORMAL])
+                .unwrap_err()
+                .to_string(),
+            "Profile \"normal\" is defined twice"
+        );
+    }
+
+    #[test]
+    fn a_broken_profile_fails_the_lot() {
+        let broken = Profile {
+            name: "broken",
+            interval: Duration::ZERO,
+            ..NORMAL
+        };
+        assert!(check_profiles(&[NORMAL, broken]).is_err());
+    }
+}
@@ -0,0 +1,199 @@
+//! Latency budget of relayed fixes. Every RelayMsg carries the time it spent in the beacons that
+//! handled it so far, in `path_delay_ms`, and every beacon adds its own before passing it on. A
+//! fix that went the long way round, e.g. through another beacon and some retries, is of little
+//! use once a newer fix of the same tracker made it to us, so a relay that is over the budget is
+//! dropped when we've seen a newer fix of its source. A relay over the budget is still passed on
+//! when it's the newest we know of, late is better than nothing.
+//!
+//! Fixes are only compared within a boot of the tracker, see `sequence`. Fixes of trackers that
+//! don't send sequence numbers are never dropped.
+//!
+//! All times are monotonic and passed in by the caller.
+
+use crate::wire::relay_verdict;
+use std::collections::HashMap;
+use std::time::Duration;
+
+pub use crate::wire::add_delay;
+pub use crate::wire::Verdict;
+
+#[derive(Clone, Copy, Debug)]
+struct Newest {
+    boot_id: u32,
+    seq: u32,
+    heard: Duration,
+}
+
+/// Decides which relays to pass on, by the newest fix we've seen of every source.
+pub struct HopBudget {
+    budget: Duration,
+    forget_after: Duration,
+    newest: HashMap<String, Newest>,
+}
+
+impl HopBudget {
+    /// Relays that took longer than `budget` can be dropped. Sources we haven't heard from for
+    /// `forget_after` are forgotten.
+    pub fn new(budget: Duration, forget_after: Duration) -> Self {
+        Self {
+            budget,
+            forget_after,
+            newest: HashMap::new(),
+        }
+    }
+
+    /// Record a fix of `src` with `boot_id` and `seq` that reached us directly from the tracker.
+    /// These are always passed on.
+    pub fn heard(&mut self, src: &str, boot_id: u32, seq: u32, now: Duration) {
+        self.forget(now);
+        self.record(src, boot_id, seq, now);
+    }
+
+    /// Decide on a relay of a fix of `src` with `boot_id` and `seq`, which spent `path_delay_ms`
+    /// in the beacons before us and `delay` in this one.
+    pub fn check(
+        &mut self,
+        src: &str,
+        boot_id: u32,
+        seq: u32,
+        path_delay_ms: u32,
+        delay: Duration,
+        now: Duration,
+    ) -> Verdict {
+        self.forget(now);
+        let newest = self.newest.get(src).map(|n| (n.boot_id, n.seq));
+        let verdict = relay_verdict(newest, boot_id, seq, path_delay_ms, delay, self.budget);
+        if verdict != Verdict::Drop {
+            self.record(src, boot_id, seq, now);
+        }
+        verdict
+    }
+
+    fn record(&mut self, src: &str, boot_id: u32, seq: u32, now: Duration) {
+        let fix = Newest {
+            boot_id,
+            seq,
+            heard: now,
+        };
+        match self.newest.get_mut(src) {
+            // An older fix of the same boot doesn't replace the newest, but keeps the source
+            // around
+            Some(n) if n.boot_id == boot_id && n.seq > seq => n.heard = now,
+            Some(n) => *n = fix,
+            None => {
+                self.newest.insert(src.to_string(), fix);
+            }
+        }
+    }
+
+    fn forget(&mut self, now: Duration) {
+        let forget_after = self.forget_after;
+        self.newest
+            .retain(|_, n| now.saturating_sub(n.heard) < forget_after);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BUDGET: Duration = Duration::from_secs(2);
+    const FORGET_AFTER: Duration = Duration::from_secs(300);
+    const DELAY: Duration = Duration::from_millis(100);
+    const SRC: &str = "aa:bb:cc:dd:ee:ff";
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn delays_add_up_in_milliseconds() {
+        assert_eq!(add_delay(250, DELAY), 350);
+        assert_eq!(add_delay(0, Duration::from_micros(1999)), 1);
+        assert_eq!(add_delay(u32::MAX - 1, DELAY), u32::MAX);
+        assert_eq!(add_delay(0, Duration::MAX), u32::MAX);
+    }
+
+    #[test]
+    fn a_late_relay_is_passed_on_when_its_the_newest() {
+        let mut budget = HopBudget::new(BUDGET, FORGET_AFTER);
+        assert_eq!(
+            budget.check(SRC, 1, 5, 3000, DELAY, secs(0)),
+            Verdict::Forward(3100)
+        );
+        budget.heard(SRC, 1, 4, secs(1));
+        assert_eq!(
+            budget.check(SRC, 1, 6, 3000, DELAY, secs(2)),
+            Verdict::Forward(3100)
+        );
+    }
+
+    #[test]
+    fn a_late_relay_is_dropped_when_a_newer_fix_was_seen() {
+        let mut budget = HopBudget::new(BUDGET, FORGET_AFTER);
+        budget.heard(SRC, 1, 6, secs(0));
+        assert_eq!(budget.check(SRC, 1, 5, 3000, DELAY, secs(1)), Verdict::Drop);
+        // Within the budget it still goes
+        assert_eq!(
+            budget.check(SRC, 1, 5, 1800, DELAY, secs(1)),
+            Verdict::Forward(1900)
+        );
+        // The delay of this beacon counts as well
+        assert_eq!(budget.check(SRC, 1, 5, 1950, DELAY, secs(1)), Verdict::Drop);
+        // Another source isn't affected
+        assert_eq!(
+            budget.check("other", 1, 5, 3000, DELAY, secs(1)),
+            Verdict::Forward(3100)
+        );
+    }
+
+    #[test]
+    fn forwarded_relays_are_newer_fixes_as_well() {
+        let mut budget = HopBudget::new(BUDGET, FORGET_AFTER);
+        assert_eq!(
+            budget.check(SRC, 1, 7, 0, DELAY, secs(0)),
+            Verdict::Forward(100)
+        );
+        assert_eq!(budget.check(SRC, 1, 6, 3000, DELAY, secs(1)), Verdict::Drop);
+        // A dropped relay doesn't change what's newest
+        assert_eq!(
+            budget.check(SRC, 1, 8, 3000, DELAY, secs(2)),
+            Verdict::Forward(3100)
+        );
+    }
+
+    #[test]
+    fn fixes_are_only_compared_within_a_boot() {
+        let mut budget = HopBudget::new(BUDGET, FORGET_AFTER);
+        budget.heard(SRC, 1, 100, secs(0));
+        assert_eq!(
+            budget.check(SRC, 2, 1, 3000, DELAY, secs(1)),
+            Verdict::Forward(3100)
+        );
+        // Without sequence numbers nothing is dropped
+        budget.heard(SRC, 0, 100, secs(2));
+        assert_eq!(
+            budget.check(SRC, 0, 1, 3000, DELAY, secs(3)),
+            Verdict::Forward(3100)
+        );
+    }
+
+    #[test]
+    fn sources_are_forgotten_when_we_dont_hear_them() {
+        let mut budget = HopBudget::new(BUDGET, FORGET_AFTER);
+        budget.heard(SRC, 1, 6, secs(0));
+        // An older fix keeps the source around, without replacing the newest
+        assert_eq!(
+            budget.check(SRC, 1, 5, 0, DELAY, secs(200)),
+            Verdict::Forward(100)
+        );
+        assert_eq!(
+            budget.check(SRC, 1, 5, 3000, DELAY, secs(499)),
+            Verdict::Drop
+        );
+        assert_eq!(
+            budget.check(SRC, 1, 5, 3000, DELAY, secs(500)),
+            Verdict::Forward(3100)
+        );
+    }
+}
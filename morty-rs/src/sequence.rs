@@ -0,0 +1,111 @@
+//! Sequence numbers for the messages of a device, so the receiving end can tell lost messages
+//! apart from reboots. Every boot gets a random id and numbers messages from 0. A device that
+//! keeps its counter somewhere safe, e.g. in RTC memory across deep sleep, can resume it.
+
+/// A boot id and the number of the next message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sequence {
+    boot_id: u32,
+    next: u32,
+}
+
+impl Default for Sequence {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sequence {
+    /// Start a new boot.
+    pub fn new() -> Self {
+        Self::resume(new_boot_id(), 0)
+    }
+
+    /// Continue a boot, with `next` as the number of the next message.
+    pub fn resume(boot_id: u32, next: u32) -> Self {
+        Self { boot_id, next }
+    }
+
+    /// Continue the boot id and next number that were `saved`, or start a new boot when nothing
+    /// was, e.g. after a power loss.
+    pub fn restore(saved: Option<(u32, u32)>) -> Self {
+        match saved {
+            Some((boot_id, next)) => Self::resume(boot_id, next),
+            None => Self::new(),
+        }
+    }
+
+    pub fn boot_id(&self) -> u32 {
+        self.boot_id
+    }
+
+    /// Number of the next message.
+    pub fn peek(&self) -> u32 {
+        self.next
+    }
+
+    /// Take the boot id and number for the next message. When the numbers run out, a new boot is
+    /// started, rather than wrapping around to numbers the receiver has seen before.
+    pub fn next(&mut self) -> (u32, u32) {
+        if self.next == u32::MAX {
+            *self = Self::new();
+        }
+        let seq = self.next;
+        self.next += 1;
+        (self.boot_id, seq)
+    }
+}
+
+fn new_boot_id() -> u32 {
+    // 0 is what a receiver sees from devices that don't send sequence numbers
+    loop {
+        let id = unsafe { esp_idf_sys::esp_random() };
+        if id != 0 {
+            return id;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_new_boot_numbers_from_zero() {
+        let mut sequence = Sequence::new();
+        let boot_id = sequence.boot_id();
+        assert_ne!(boot_id, 0);
+        assert_eq!(sequence.next(), (boot_id, 0));
+        assert_eq!(sequence.next(), (boot_id, 1));
+        assert_eq!(sequence.peek(), 2);
+    }
+
+    #[test]
+    fn a_resumed_boot_continues() {
+        let mut sequence = Sequence::resume(7, 41);
+        assert_eq!(sequence.next(), (7, 41));
+        assert_eq!(sequence, Sequence::resume(7, 42));
+    }
+
+    #[test]
+    fn running_out_of_numbers_starts_a_new_boot() {
+        let mut sequence = Sequence::resume(7, u32::MAX - 1);
+        assert_eq!(sequence.next(), (7, u32::MAX - 1));
+        let (boot_id, seq) = sequence.next();
+        assert_eq!(seq, 0);
+        assert_ne!(boot_id, 0);
+        assert_eq!(sequence.peek(), 1);
+    }
+
+    #[test]
+    fn a_saved_sequence_is_restored() {
+        assert_eq!(Sequence::restore(Some((7, 42))), Sequence::resume(7, 42));
+    }
+
+    #[test]
+    fn a_cold_boot_starts_a_new_sequence() {
+        let sequence = Sequence::restore(None);
+        assert_ne!(sequence.boot_id(), 0);
+        assert_eq!(sequence.peek(), 0);
+    }
+}
@@ -0,0 +1,377 @@
+//! Counters per source: fixes, duplicates and fixes lost on the way. A device that spoofs MAC
+//! addresses could make a map of these grow without bound, so the sources live in a table with
+//! room for `N` of them that is allocated up front. When the table is full, a new source takes the
+//! place of the one we haven't heard from the longest, but only when that one has been idle for
+//! `idle_evict`. Otherwise it's counted under "other". The table is behind a mutex that is only
+//! held to look up a slot, so updates can be done from callbacks.
+
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Number of sources the table has room for when there's no reason to pick another number.
+pub const DEFAULT_SOURCES: usize = 32;
+
+/// What happened to a source.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SourceEvent {
+    /// A fix we hadn't seen yet, with the boot id and sequence number of the tracker
+    Fix { boot_id: u32, seq: u32 },
+    /// A fix we had seen already
+    Duplicate,
+}
+
+/// The counters of a source, or of all the sources under "other".
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SourceCounts {
+    pub fixes: u32,
+    pub duplicates: u32,
+    // Gaps in the sequence numbers. Fixes that show up after a later one, e.g. from a backfill,
+    // were counted as lost already.
+    pub lost: u32,
+}
+
+impl SourceCounts {
+    /// Count `event` without following the sequence numbers, so nothing is lost.
+    fn count(&mut self, event: SourceEvent) {
+        match event {
+            SourceEvent::Fix { .. } => self.fixes = self.fixes.saturating_add(1),
+            SourceEvent::Duplicate => self.duplicates = self.duplicates.saturating_add(1),
+        }
+    }
+
+    fn add(&mut self, other: &SourceCounts) {
+        self.fixes = self.fixes.saturating_add(other.fixes);
+        self.duplicates = self.duplicates.saturating_add(other.duplicates);
+        self.lost = self.lost.saturating_add(other.lost);
+    }
+}
+
+impl fmt::Display for SourceCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "fixes={} duplicates={} lost={}",
+            self.fixes, self.duplicates, self.lost
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Slot {
+    mac: [u8; 6],
+    last_seen: Duration,
+    // Boot id and sequence number of the newest fix
+    last_fix: Option<(u32, u32)>,
+    counts: SourceCounts,
+}
+
+impl Slot {
+    fn record(&mut self, event: SourceEvent) {
+        self.counts.count(event);
+        let SourceEvent::Fix { boot_id, seq } = event else {
+            return;
+        };
+        match self.last_fix {
+            // A new boot starts counting again
+            Some((last_boot, last_seq)) if last_boot == boot_id => {
+                if seq > last_seq {
+                    let lost = seq - last_seq - 1;
+                    self.counts.lost = self.counts.lost.saturating_add(lost);
+                    self.last_fix = Some((boot_id, seq));
+                }
+            }
+            _ => self.last_fix = Some((boot_id, seq)),
+        }
+    }
+}
+
+struct Table<const N: usize> {
+    slots: [Option<Slot>; N],
+    other: SourceCounts,
+    // Events counted under "other" because the table was full
+    overflowed: u32,
+    // Sources that made room for another one. Their counts went to "other".
+    evicted: u32,
+}
+
+/// Counters of at most `N` sources, keyed by MAC address. Displayed as e.g.
+/// `aa:bb:cc:dd:ee:ff fixes=3 duplicates=1 lost=0, other fixes=0 duplicates=0 lost=0,
+/// occupancy=1/32 overflowed=0 evicted=0`.
+pub struct SourceMetrics<const N: usize> {
+    table: Mutex<Table<N>>,
+    idle_evict: Duration,
+}
+
+impl<const N: usize> SourceMetrics<N> {
+    /// A source can be evicted once we haven't heard from it for `idle_evict`.
+    pub const fn new(idle_evict: Duration) -> Self {
+        Self {
+            table: Mutex::new(Table {
+                slots: [None; N],
+                other: SourceCounts {
+                    fixes: 0,
+                    duplicates: 0,
+                    lost: 0,
+                },
+                overflowed: 0,
+                evicted: 0,
+            }),
+            idle_evict,
+        }
+    }
+
+    /// Count `event` of `mac` at monotonic time `now`.
+    pub fn record(&self, mac: [u8; 6], event: SourceEvent, now: Duration) {
+        let mut table = self.table.lock().unwrap();
+        let table = &mut *table;
+        let index = match table
+            .slots
+            .iter()
+            .position(|s| matches!(s, Some(s) if s.mac == mac))
+        {
+            Some(index) => Some(index),
+            None => self.make_room(table, now),
+        };
+        let Some(index) = index else {
+            table.overflowed = table.overflowed.saturating_add(1);
+            table.other.count(event);
+            return;
+        };
+        let slot = table.slots[index].get_or_insert(Slot {
+            mac,
+            last_seen: now,
+            last_fix: None,
+            counts: SourceCounts::default(),
+        });
+        slot.last_seen = now;
+        slot.record(event);
+    }
+
+    /// Count an event of a source that isn't a MAC address, e.g. the test source, under "other".
+    pub fn record_other(&self, event: SourceEvent) {
+        self.table.lock().unwrap().other.count(event);
+    }
+
+    /// A free slot, or the one of the source we haven't heard from the longest when that was more
+    /// than `idle_evict` ago. None when the table is full of active sources.
+    fn make_room(&self, table: &mut Table<N>, now: Duration) -> Option<usize> {
+        if let Some(free) = table.slots.iter().position(|s| s.is_none()) {
+            return Some(free);
+        }
+        let (index, idle) = table
+            .slots
+            .iter()
+            .enumerate()
+            .filter_map(|(i, s)| s.map(|s| (i, s)))
+            .min_by_key(|(_, s)| s.last_seen)
+            .map(|(i, s)| (i, now.saturating_sub(s.last_seen)))?;
+        if idle <= self.idle_evict {
+            return None;
+        }
+        let evicted = table.slots[index].take()?;
+        table.other.add(&evicted.counts);
+        table.evicted = table.evicted.saturating_add(1);
+        Some(index)
+    }
+
+    /// The counters of `mac`, None when it isn't in the table.
+    pub fn get(&self, mac: &[u8; 6]) -> Option<SourceCounts> {
+        let table = self.table.lock().unwrap();
+        table
+            .slots
+            .iter()
+            .flatten()
+            .find(|s| &s.mac == mac)
+            .map(|s| s.counts)
+    }
+
+    /// The counters of the sources that didn't fit, or were evicted.
+    pub fn other(&self) -> SourceCounts {
+        self.table.lock().unwrap().other
+    }
+
+    /// Number of sources in the table.
+    pub fn occupancy(&self) -> usize {
+        self.table.lock().unwrap().slots.iter().flatten().count()
+    }
+
+    /// Events counted under "other" because the table was full.
+    pub fn overflowed(&self) -> u32 {
+        self.table.lock().unwrap().overflowed
+    }
+
+    /// Sources that were evicted to make room for another.
+    pub fn evicted(&self) -> u32 {
+        self.table.lock().unwrap().evicted
+    }
+}
+
+impl<const N: usize> fmt::Display for SourceMetrics<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Copied first, so we don't hold the lock while writing
+        let (slots, other, overflowed, evicted) = {
+            let table = self.table.lock().unwrap();
+            (table.slots, table.other, table.overflowed, table.evicted)
+        };
+        let mut occupancy = 0;
+        for slot in slots.iter().flatten() {
+            occupancy += 1;
+            let m = slot.mac;
+            write!(
+                f,
+                "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x} {}, ",
+                m[0], m[1], m[2], m[3], m[4], m[5], slot.counts
+            )?;
+        }
+        write!(
+            f,
+            "other {other}, occupancy={occupancy}/{N} overflowed={overflowed} evicted={evicted}"
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    fn mac(last: u8) -> [u8; 6] {
+        [0xaa, 0xbb, 0xcc, 0xdd, 0xee, last]
+    }
+
+    fn fix(seq: u32) -> SourceEvent {
+        SourceEvent::Fix { boot_id: 1, seq }
+    }
+
+    fn counts(fixes: u32, duplicates: u32, lost: u32) -> SourceCounts {
+        SourceCounts {
+            fixes,
+            duplicates,
+            lost,
+        }
+    }
+
+    #[test]
+    fn events_are_counted_per_source() {
+        let metrics = SourceMetrics::<4>::new(DAY);
+        metrics.record(mac(1), fix(1), Duration::ZERO);
+        metrics.record(mac(1), SourceEvent::Duplicate, Duration::ZERO);
+        metrics.record(mac(2), fix(7), Duration::ZERO);
+        assert_eq!(metrics.get(&mac(1)), Some(counts(1, 1, 0)));
+        assert_eq!(metrics.get(&mac(2)), Some(counts(1, 0, 0)));
+        assert_eq!(metrics.get(&mac(3)), None);
+        assert_eq!(metrics.occupancy(), 2);
+    }
+
+    #[test]
+    fn gaps_in_the_sequence_are_lost() {
+        let metrics = SourceMetrics::<4>::new(DAY);
+        for seq in [1, 2, 5, 6] {
+            metrics.record(mac(1), fix(seq), Duration::ZERO);
+        }
+        assert_eq!(metrics.get(&mac(1)), Some(counts(4, 0, 2)));
+        // A backfilled fix was counted as lost already
+        metrics.record(mac(1), fix(3), Duration::ZERO);
+        assert_eq!(metrics.get(&mac(1)), Some(counts(5, 0, 2)));
+        // A new boot starts again
+        let reboot = SourceEvent::Fix { boot_id: 2, seq: 0 };
+        metrics.record(mac(1), reboot, Duration::ZERO);
+        metrics.record(
+            mac(1),
+            SourceEvent::Fix { boot_id: 2, seq: 1 },
+            Duration::ZERO,
+        );
+        assert_eq!(metrics.get(&mac(1)), Some(counts(7, 0, 2)));
+    }
+
+    #[test]
+    fn a_full_table_of_active_sources_overflows_into_other() {
+        let metrics = SourceMetrics::<2>::new(DAY);
+        metrics.record(mac(1), fix(1), Duration::ZERO);
+        metrics.record(mac(2), fix(1), Duration::ZERO);
+        metrics.record(mac(3), fix(1), DAY);
+        metrics.record(mac(3), SourceEvent::Duplicate, DAY);
+        assert_eq!(metrics.get(&mac(3)), None);
+        assert_eq!(metrics.other(), counts(1, 1, 0));
+        assert_eq!(metrics.overflowed(), 2);
+        assert_eq!(metrics.evicted(), 0);
+    }
+
+    #[test]
+    fn the_source_idle_the_longest_makes_room() {
+        let metrics = SourceMetrics::<2>::new(DAY);
+        metrics.record(mac(1), fix(1), Duration::ZERO);
+        metrics.record(mac(2), fix(1), Duration::from_secs(10));
+        metrics.record(mac(1), fix(3), Duration::from_secs(20));
+        // Source 2 is idle for more than a day, source 1 isn't yet
+        let now = DAY + Duration::from_secs(11);
+        metrics.record(mac(3), fix(1), now);
+        assert_eq!(metrics.get(&mac(2)), None);
+        assert_eq!(metrics.get(&mac(3)), Some(counts(1, 0, 0)));
+        assert_eq!(metrics.other(), counts(1, 0, 0));
+        assert_eq!(metrics.evicted(), 1);
+        // Its counts are in "other", and it starts over when it comes back
+        metrics.record(mac(2), fix(2), now + DAY);
+        assert_eq!(metrics.get(&mac(1)), None);
+        assert_eq!(metrics.get(&mac(2)), Some(counts(1, 0, 0)));
+        assert_eq!(metrics.other(), counts(3, 0, 1));
+        assert_eq!(metrics.evicted(), 2);
+        assert_eq!(metrics.overflowed(), 0);
+    }
+
+    #[test]
+    fn other_sources_are_counted_under_other() {
+        let metrics = SourceMetrics::<2>::new(DAY);
+        metrics.record_other(fix(1));
+        assert_eq!(metrics.other(), counts(1, 0, 0));
+        assert_eq!(metrics.occupancy(), 0);
+        assert_eq!(metrics.overflowed(), 0);
+    }
+
+    #[test]
+    fn metrics_are_displayed_with_their_occupancy() {
+        let metrics = SourceMetrics::<2>::new(DAY);
+        assert_eq!(
+            metrics.to_string(),
+            "other fixes=0 duplicates=0 lost=0, occupancy=0/2 overflowed=0 evicted=0"
+        );
+        metrics.record(mac(1), fix(1), Duration::ZERO);
+        assert_eq!(
+            metrics.to_string(),
+            "aa:bb:cc:dd:ee:01 fixes=1 duplicates=0 lost=0, \
+             other fixes=0 duplicates=0 lost=0, occupancy=1/2 overflowed=0 evicted=0"
+        );
+    }
+
+    #[test]
+    fn concurrent_updates_are_all_counted() {
+        static METRICS: SourceMetrics<4> = SourceMetrics::new(DAY);
+        // Fill the table first, so sources 4 to 7 always overflow
+        for m in 0..4 {
+            METRICS.record(mac(m), fix(0), Duration::ZERO);
+        }
+        let threads: Vec<_> = (0..8u8)
+            .map(|t| {
+                thread::spawn(move || {
+                    for seq in 1..=1000 {
+                        METRICS.record(mac(t % 4), fix(seq), Duration::ZERO);
+                        METRICS.record(mac(t), SourceEvent::Duplicate, Duration::ZERO);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let fixes: u32 = (0..4).map(|m| METRICS.get(&mac(m)).unwrap().fixes).sum();
+        let duplicates: u32 = (0..4)
+            .map(|m| METRICS.get(&mac(m)).unwrap().duplicates)
+            .sum();
+        assert_eq!(fixes, 4 + 8000);
+        assert_eq!(duplicates + METRICS.other().duplicates, 8000);
+        assert_eq!(METRICS.overflowed(), 4000);
+    }
+}
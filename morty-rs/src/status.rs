@@ -0,0 +1,144 @@
+use crate::flashlog;
+use crate::flashlog::EventKind;
+use crate::led::colors;
+use crate::led::LedHandle;
+use crate::ledlog;
+use crate::ledlog::Shown;
+use crate::utils::format_duration;
+use log::*;
+use std::fmt;
+use std::fmt::Debug;
+use std::time::Duration;
+
+/// Things that can go wrong on any of the devices. Each of them is shown as a red LED blinking a
+/// number of times, followed by a pause, so they can be told apart without a serial console.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Startup,
+    Wifi,
+    Sntp,
+    Radio,
+    Uart,
+    Thread,
+    GpsFault,
+    GpsNoData,
+}
+
+impl Status {
+    /// Number of blinks in the error pattern.
+    pub fn blinks(&self) -> u8 {
+        match self {
+            Status::Startup => 1,
+            Status::Wifi => 2,
+            Status::Sntp => 3,
+            Status::Radio => 4,
+            Status::Uart => 5,
+            Status::Thread => 6,
+            Status::GpsFault => 7,
+            Status::GpsNoData => 8,
+        }
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Status::Startup => write!(f, "startup failed"),
+            Status::Wifi => write!(f, "wifi failed"),
+            Status::Sntp => write!(f, "SNTP sync failed"),
+            Status::Radio => write!(f, "radio failed"),
+            Status::Uart => write!(f, "UART failed"),
+            Status::Thread => write!(f, "thread failed"),
+            Status::GpsFault => write!(f, "GPS fault suspected"),
+            Status::GpsNoData => write!(f, "no data from the GPS module"),
+        }
+    }
+}
+
+/// What to do once the error pattern has been shown.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FatalAction {
+    Reboot,
+    Retry,
+}
+
+// How long the error pattern is shown before rebooting or retrying
+const FATAL_DELAY: Duration = Duration::from_secs(10);
+const FATAL_BRIGHTNESS: u8 = 10;
+const FATAL_BLINK_PERIOD: Duration = Duration::from_millis(300);
+
+/// Show the error pattern for `status` on the LED for a while, so that a failed device can be
+/// told apart from one that isn't powered. After that we either reboot or return, so the caller
+/// can retry.
+pub fn fatal(status: Status, led: &LedHandle, action: FatalAction) {
+    error!(
+        "Fatal: {status}, {action:?} in {}",
+        format_duration(FATAL_DELAY)
+    );
+    flashlog::log_event(EventKind::Error {
+        code: status.blinks() as u32,
+    });
+    record(status);
+    let pattern = FATAL_BLINK_PERIOD * (status.blinks() as u32 + 2);
+    let mut shown = Duration::from_secs(0);
+    while shown < FATAL_DELAY {
+        // The LED might be what failed, so we don't care if this doesn't work
+        let _ = led.set_color(colors::BLACK, 0);
+        let _ = led.blink_color(
+            colors::RED,
+            FATAL_BRIGHTNESS,
+            FATAL_BLINK_PERIOD,
+            status.blinks(),
+        );
+        std::thread::sleep(pattern);
+        shown += pattern;
+    }
+
+    if action == FatalAction::Reboot {
+        flashlog::log_event(EventKind::Reboot {
+            reason: status.blinks() as u32,
+        });
+        flashlog::flush();
+        ledlog::save();
+        unsafe { esp_idf_sys::esp_restart() };
+    }
+}
+
+/// Show the error pattern for `status` once, for problems we carry on with.
+pub fn show(status: Status, led: &LedHandle) -> anyhow::Result<()> {
+    record(status);
+    led.blink_color(
+        colors::RED,
+        FATAL_BRIGHTNESS,
+        FATAL_BLINK_PERIOD,
+        status.blinks(),
+    )
+}
+
+fn record(status: Status) {
+    ledlog::record(
+        Shown::Status {
+            blinks: status.blinks(),
+        },
+        [colors::RED.r, colors::RED.g, colors::RED.b],
+        FATAL_BRIGHTNESS,
+    );
+}
+
+/// Reboot through `fatal` when a Result is an error.
+pub trait OrFatal<T> {
+    fn or_fatal(self, status: Status, led: &LedHandle) -> T;
+}
+
+impl<T, E: Debug> OrFatal<T> for Result<T, E> {
+    fn or_fatal(self, status: Status, led: &LedHandle) -> T {
+        match self {
+            Ok(v) => v,
+            Err(e) => {
+                error!("{status}: {e:?}");
+                fatal(status, led, FatalAction::Reboot);
+                unreachable!("esp_restart returned");
+            }
+        }
+    }
+}
@@ -0,0 +1,265 @@
+//! The times of a fix, from when it was measured to when the gateway got it. Each is in seconds
+//! since the epoch and comes from the clock of the device that took it, so it's only known when
+//! that clock was valid, see `known`. In proto messages an unknown time is 0.
+//!
+//! 1. fix: when the GPS measured the fix, `GpsMsg.fix_epoch`. The GGA only has the time of day,
+//!    the tracker takes the date from its clock, see `fix_epoch`.
+//! 2. sent: when the tracker sent the report, `GpsMsg.sent_epoch`.
+//! 3. relay: when the first beacon received it, `RelayMsg.timestamp`.
+//! 4. gateway: when the gateway received it.
+//!
+//! The known ones are in this order, give or take how far apart the clocks are, see
+//! `FixTimes::out_of_order`. `GpsMsg.utc`, the time of day of the fix, is still filled in for
+//! consumers that don't know about these.
+
+use crate::messages::GpsMsg;
+
+/// Times before this (2023-01-01) come from a clock that wasn't synced yet.
+pub const MIN_VALID_TIMESTAMP: i64 = 1_672_531_200;
+/// How far apart the clocks of our devices can be, they're disciplined with time beacons.
+pub const CLOCK_TOLERANCE_SECS: i64 = 2;
+
+const SECS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// `epoch` when it's from a clock that was synced, None otherwise, e.g. for 0.
+pub fn known(epoch: i64) -> Option<i64> {
+    (epoch >= MIN_VALID_TIMESTAMP).then_some(epoch)
+}
+
+/// When a fix with time of day `secs_of_day` was measured, with the date of `now`, both in UTC.
+/// A fix from just before midnight can be sent just after it, so this is the one of the days
+/// around `now` that's closest to it. 0 while `now` isn't known.
+pub fn fix_epoch(secs_of_day: i32, now: Option<i64>) -> i64 {
+    let Some(now) = now.and_then(known) else {
+        return 0;
+    };
+    let midnight = now - now.rem_euclid(SECS_PER_DAY);
+    [-SECS_PER_DAY, 0, SECS_PER_DAY]
+        .iter()
+        .map(|day| midnight + day + secs_of_day as i64)
+        .min_by_key(|epoch| (epoch - now).abs())
+        .unwrap_or(0)
+}
+
+/// The times of a fix, see the module docs. None for the ones that aren't known.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FixTimes {
+    pub fix: Option<i64>,
+    pub sent: Option<i64>,
+    pub relay: Option<i64>,
+    pub gateway: Option<i64>,
+}
+
+impl FixTimes {
+    /// The times of `gps`, which a beacon received at `relay_epoch` and the gateway at
+    /// `gateway_epoch`.
+    pub fn new(gps: &GpsMsg, relay_epoch: i64, gateway_epoch: Option<i64>) -> Self {
+        Self {
+            fix: known(gps.fix_epoch),
+            sent: known(gps.sent_epoch),
+            relay: known(relay_epoch),
+            gateway: gateway_epoch.and_then(known),
+        }
+    }
+
+    /// The first two known times, by name, of which the later one is more than
+    /// CLOCK_TOLERANCE_SECS before the earlier one. None when they're in order.
+    pub fn out_of_order(&self) -> Option<(&'static str, &'static str)> {
+        let times = [
+            ("fix", self.fix),
+            ("sent", self.sent),
+            ("relay", self.relay),
+            ("gateway", self.gateway),
+        ];
+        let known: Vec<(&'static str, i64)> = times
+            .iter()
+            .filter_map(|&(name, time)| time.map(|t| (name, t)))
+            .collect();
+        known
+            .windows(2)
+            .find(|pair| pair[1].1 + CLOCK_TOLERANCE_SECS < pair[0].1)
+            .map(|pair| (pair[0].0, pair[1].0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::comm::decode_msg;
+    use crate::comm::encode_msg;
+    use crate::comm::encode_relay;
+    use crate::messages::morty_message;
+    use crate::messages::relay_msg;
+
+    // 2023-11-15 00:00:00 UTC
+    const MIDNIGHT: i64 = 1_700_006_400;
+    const NOON: i64 = MIDNIGHT + SECS_PER_DAY / 2;
+
+    fn times(fix: i64, sent: i64, relay: i64, gateway: i64) -> FixTimes {
+        FixTimes {
+            fix: known(fix),
+            sent: known(sent),
+            relay: known(relay),
+            gateway: known(gateway),
+        }
+    }
+
+    #[test]
+    fn only_synced_clocks_are_known() {
+        assert_eq!(known(0), None);
+        // Time since boot, from a beacon that wasn't synced yet
+        assert_eq!(known(42), None);
+        assert_eq!(known(MIN_VALID_TIMESTAMP - 1), None);
+        assert_eq!(known(MIN_VALID_TIMESTAMP), Some(MIN_VALID_TIMESTAMP));
+        assert_eq!(known(NOON), Some(NOON));
+    }
+
+    #[test]
+    fn fixes_get_the_date_of_the_clock() {
+        let secs_of_day = (NOON - MIDNIGHT) as i32;
+        assert_eq!(fix_epoch(secs_of_day, Some(NOON)), NOON);
+        assert_eq!(fix_epoch(secs_of_day, Some(NOON + 5)), NOON);
+        assert_eq!(fix_epoch(secs_of_day - 30, Some(NOON)), NOON - 30);
+    }
+
+    #[test]
+    fn fixes_around_midnight_get_the_closest_day() {
+        let before = SECS_PER_DAY as i32 - 2;
+        // Measured just before midnight, sent just after
+        assert_eq!(fix_epoch(before, Some(MIDNIGHT + 3)), MIDNIGHT - 2);
+        // Measured just after midnight by a tracker with a clock that's a bit behind
+        assert_eq!(fix_epoch(1, Some(MIDNIGHT - 1)), MIDNIGHT + 1);
+    }
+
+    #[test]
+    fn fixes_without_a_clock_have_no_epoch() {
+        assert_eq!(fix_epoch(3600, None), 0);
+        assert_eq!(fix_epoch(3600, Some(42)), 0);
+    }
+
+    #[test]
+    fn times_in_order_are_fine() {
+        assert_eq!(times(NOON, NOON, NOON, NOON).out_of_order(), None);
+        assert_eq!(
+            times(NOON, NOON + 1, NOON + 2, NOON + 9).out_of_order(),
+            None
+        );
+        // Clocks a bit apart
+        let apart = CLOCK_TOLERANCE_SECS;
+        assert_eq!(times(NOON, NOON - apart, NOON, NOON).out_of_order(), None);
+        // And unknown ones
+        assert_eq!(times(0, 0, 0, 0).out_of_order(), None);
+        assert_eq!(times(NOON, 0, 42, NOON + 1).out_of_order(), None);
+    }
+
+    #[test]
+    fn times_out_of_order_are_named() {
+        let apart = CLOCK_TOLERANCE_SECS + 1;
+        assert_eq!(
+            times(NOON, NOON - apart, NOON, NOON).out_of_order(),
+            Some(("fix", "sent"))
+        );
+        assert_eq!(
+            times(NOON, NOON, NOON + 60, NOON).out_of_order(),
+            Some(("relay", "gateway"))
+        );
+        // Unknown times in between are skipped
+        assert_eq!(
+            times(NOON, 0, 0, NOON - 60).out_of_order(),
+            Some(("fix", "gateway"))
+        );
+    }
+
+    // How far the clocks of the tracker, the beacon and the gateway are off from the GPS, None
+    // for one that isn't synced
+    type Offsets = [Option<i64>; 3];
+
+    // A fix measured at `measured` by GPS time as it makes it to the gateway: the tracker sends
+    // it `delays[0]` later, the beacon receives it `delays[1]` after that and the gateway
+    // `delays[2]` after that. The messages go through the frames the devices exchange.
+    fn deliver(measured: i64, offsets: Offsets, delays: [i64; 3]) -> FixTimes {
+        let clock = |device: usize, at: i64| offsets[device].map(|offset| at + offset);
+        let secs_of_day = measured.rem_euclid(SECS_PER_DAY) as i32;
+
+        // The tracker
+        let sent_at = measured + delays[0];
+        let gps = GpsMsg {
+            utc: secs_of_day,
+            fix_epoch: fix_epoch(secs_of_day, clock(0, measured)),
+            sent_epoch: clock(0, sent_at).and_then(known).unwrap_or(0),
+            ..Default::default()
+        };
+        let frame = encode_msg(&morty_message::Msg::Gps(gps)).unwrap();
+
+        // The beacon, which has the time since it booted before its clock is synced
+        let relay_at = sent_at + delays[1];
+        let timestamp = clock(1, relay_at).unwrap_or(42);
+        let relayed = encode_relay("aa:bb:cc:dd:ee:ff", timestamp, 0, "", &frame).unwrap();
+
+        // The gateway
+        let gateway_at = relay_at + delays[2];
+        let Some(morty_message::Msg::Relay(relay)) = decode_msg(&relayed).unwrap() else {
+            panic!("Not a relay");
+        };
+        let Some(relay_msg::Msg::Gps(gps)) = &relay.msg else {
+            panic!("Not a fix");
+        };
+        FixTimes::new(gps, relay.timestamp, clock(2, gateway_at))
+    }
+
+    #[test]
+    fn the_times_of_a_fix_are_in_order_across_devices() {
+        for measured in [NOON, MIDNIGHT - 1, MIDNIGHT] {
+            for delays in [[0, 0, 0], [1, 0, 0], [0, 0, 5], [3, 1, 2], [30, 2, 120]] {
+                let t = deliver(measured, [Some(0); 3], delays);
+                let (fix, sent, relay, gateway) = (
+                    t.fix.unwrap(),
+                    t.sent.unwrap(),
+                    t.relay.unwrap(),
+                    t.gateway.unwrap(),
+                );
+                assert_eq!(fix, measured, "{delays:?}");
+                assert!(fix <= sent && sent <= relay && relay <= gateway, "{t:?}");
+                assert_eq!(t.out_of_order(), None);
+            }
+        }
+    }
+
+    #[test]
+    fn the_times_of_a_fix_are_in_order_within_the_clock_tolerance() {
+        let tolerance = CLOCK_TOLERANCE_SECS / 2;
+        let offsets: Vec<_> = (-tolerance..=tolerance).collect();
+        for &tracker in &offsets {
+            for &beacon in &offsets {
+                for &gateway in &offsets {
+                    let offsets = [Some(tracker), Some(beacon), Some(gateway)];
+                    for delays in [[0, 0, 0], [3, 1, 2]] {
+                        for measured in [NOON, MIDNIGHT - 1] {
+                            let t = deliver(measured, offsets, delays);
+                            assert_eq!(t.out_of_order(), None, "{offsets:?} {delays:?} {t:?}");
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn times_from_clocks_that_arent_synced_are_unknown() {
+        let t = deliver(NOON, [None, Some(0), Some(0)], [1, 1, 1]);
+        assert_eq!((t.fix, t.sent), (None, None));
+        assert_eq!((t.relay, t.gateway), (Some(NOON + 2), Some(NOON + 3)));
+        let t = deliver(NOON, [Some(0), None, None], [1, 1, 1]);
+        assert_eq!((t.fix, t.sent), (Some(NOON), Some(NOON + 1)));
+        assert_eq!((t.relay, t.gateway), (None, None));
+        assert_eq!(t.out_of_order(), None);
+    }
+
+    #[test]
+    fn a_clock_that_is_off_puts_the_times_out_of_order() {
+        let t = deliver(NOON, [Some(0), Some(-60), Some(0)], [1, 1, 1]);
+        assert_eq!(t.out_of_order(), Some(("sent", "relay")));
+        let t = deliver(NOON, [Some(0), Some(0), Some(-60)], [1, 1, 1]);
+        assert_eq!(t.out_of_order(), Some(("relay", "gateway")));
+    }
+}
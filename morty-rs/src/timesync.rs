@@ -0,0 +1,365 @@
+//! Clock discipline with time beacons. The gateway hands its time to the beacon on its UART every
+//! few minutes, which broadcasts it as a TimeBeaconMsg. Other beacons broadcast the first copy of
+//! every time beacon again, with `hops` one up and the time they held it added to `holdoff_ms`,
+//! so the time in a beacon is always `time_ms` + `holdoff_ms` at the moment it's received.
+//!
+//! A clock that isn't valid is set from the first time beacon. A valid clock is slewed when it's
+//! off by up to `Policy::slew_limit`, and stepped when it's off by more, which is counted so it
+//! shows up in the clock status. Every hop adds the latency of another radio and another task, so
+//! a time beacon that went through other beacons only slews by part of the offset, and once we
+//! hear time beacons with fewer hops, the ones with more are ignored for `Policy::prefer_for`.
+//!
+//! All times are passed in by the caller, this module doesn't read or set any clock.
+
+use crate::messages::ClockStatusMsg;
+use crate::messages::TimeBeaconMsg;
+use crate::messages::TimeSource;
+use std::time::Duration;
+
+/// How to discipline a clock with time beacons.
+#[derive(Clone, Copy, Debug)]
+pub struct Policy {
+    /// Offsets up to this are slewed, larger ones are stepped
+    pub slew_limit: Duration,
+    /// Time beacons that went through more beacons are ignored, and not broadcast again
+    pub max_hops: u32,
+    /// How long time beacons with fewer hops are preferred over ones with more
+    pub prefer_for: Duration,
+}
+
+pub const DEFAULT_POLICY: Policy = Policy {
+    slew_limit: Duration::from_secs(3),
+    max_hops: 4,
+    prefer_for: Duration::from_secs(15 * 60),
+};
+
+/// What to do with the clock. Offsets are in milliseconds, and are added to the clock.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Correction {
+    /// The clock wasn't valid, set it
+    Set(i64),
+    /// Slew the clock
+    Slew(i64),
+    /// The clock was off by more than the slew limit, step it
+    Step(i64),
+    /// Leave the clock alone
+    Ignore,
+}
+
+/// Disciplines a clock with the time beacons it's given, and keeps what's reported about it.
+#[derive(Clone, Copy, Debug)]
+pub struct Discipline {
+    policy: Policy,
+    source: TimeSource,
+    offset_ms: i64,
+    hops: u32,
+    steps: u32,
+    // Hops of the time beacon we took last, and when we took it
+    last: Option<(u32, Duration)>,
+    // `time_ms` of the last time beacon we broadcast again
+    relayed_ms: i64,
+}
+
+impl Discipline {
+    /// `source` is where the time came from so far, `TimeSource::None` if the clock isn't valid.
+    pub const fn new(policy: Policy, source: TimeSource) -> Self {
+        Self {
+            policy,
+            source,
+            offset_ms: 0,
+            hops: 0,
+            steps: 0,
+            last: None,
+            relayed_ms: i64::MIN,
+        }
+    }
+
+    /// Where the time came from.
+    pub fn source(&self) -> TimeSource {
+        self.source
+    }
+
+    /// Take a time beacon, that went through `hops` beacons and says the clock is `offset_ms`
+    /// behind, at monotonic time `now`. `valid` is whether the clock was valid before.
+    pub fn sample(&mut self, offset_ms: i64, hops: u32, valid: bool, now: Duration) -> Correction {
+        if hops > self.policy.max_hops {
+            return Correction::Ignore;
+        }
+        if let Some((last_hops, at)) = self.last {
+            if valid && hops > last_hops && now.saturating_sub(at) < self.policy.prefer_for {
+                return Correction::Ignore;
+            }
+        }
+        self.last = Some((hops, now));
+        self.source = TimeSource::Beacon;
+        self.offset_ms = offset_ms;
+        self.hops = hops;
+        if !valid {
+            return Correction::Set(offset_ms);
+        }
+        if offset_ms.unsigned_abs() > self.policy.slew_limit.as_millis() as u64 {
+            self.steps = self.steps.saturating_add(1);
+            return Correction::Step(offset_ms);
+        }
+        Correction::Slew(offset_ms / (i64::from(hops) + 1))
+    }
+
+    /// The time beacon to broadcast again after holding `beacon` for `held`, if any. Only the
+    /// first copy of every time beacon is broadcast again, up to `Policy::max_hops`.
+    pub fn relay(&mut self, beacon: &TimeBeaconMsg, held: Duration) -> Option<TimeBeaconMsg> {
+        if beacon.hops >= self.policy.max_hops || beacon.time_ms <= self.relayed_ms {
+            return None;
+        }
+        self.relayed_ms = beacon.time_ms;
+        let held = u32::try_from(held.as_millis()).unwrap_or(u32::MAX);
+        Some(TimeBeaconMsg {
+            time_ms: beacon.time_ms,
+            hops: beacon.hops + 1,
+            holdoff_ms: beacon.holdoff_ms.saturating_add(held),
+        })
+    }
+
+    /// What to report about the clock.
+    pub fn status(&self) -> ClockStatusMsg {
+        ClockStatusMsg {
+            source: self.source as i32,
+            offset_ms: self.offset_ms.clamp(i32::MIN.into(), i32::MAX.into()) as i32,
+            hops: self.hops,
+            steps: self.steps,
+        }
+    }
+}
+
+/// The time beacon for wall clock time `now`, as the gateway sends it.
+pub fn time_beacon(now: Duration) -> TimeBeaconMsg {
+    TimeBeaconMsg {
+        time_ms: now.as_millis() as i64,
+        hops: 0,
+        holdoff_ms: 0,
+    }
+}
+
+/// How far the clock at `local_ms` is behind `beacon`, which we held for `held` since it was
+/// received.
+pub fn offset_ms(beacon: &TimeBeaconMsg, held: Duration, local_ms: i64) -> i64 {
+    beacon.time_ms + i64::from(beacon.holdoff_ms) + held.as_millis() as i64 - local_ms
+}
+
+/// Name of a time source, for logs and the console.
+pub fn source_name(source: i32) -> &'static str {
+    match TimeSource::from_i32(source) {
+        Some(TimeSource::Sntp) => "sntp",
+        Some(TimeSource::Beacon) => "beacon",
+        _ => "none",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(s: u64) -> Duration {
+        Duration::from_secs(s)
+    }
+
+    fn discipline() -> Discipline {
+        Discipline::new(DEFAULT_POLICY, TimeSource::None)
+    }
+
+    fn beacon(time_ms: i64, hops: u32, holdoff_ms: u32) -> TimeBeaconMsg {
+        TimeBeaconMsg {
+            time_ms,
+            hops,
+            holdoff_ms,
+        }
+    }
+
+    #[test]
+    fn an_invalid_clock_is_set() {
+        let mut discipline = discipline();
+        assert_eq!(discipline.source(), TimeSource::None);
+        assert_eq!(
+            discipline.sample(1_700_000_000_000, 2, false, secs(0)),
+            Correction::Set(1_700_000_000_000)
+        );
+        assert_eq!(discipline.source(), TimeSource::Beacon);
+        // Setting the clock isn't a step
+        assert_eq!(discipline.status().steps, 0);
+    }
+
+    #[test]
+    fn small_offsets_are_slewed() {
+        let mut discipline = discipline();
+        assert_eq!(
+            discipline.sample(-3000, 0, true, secs(0)),
+            Correction::Slew(-3000)
+        );
+        assert_eq!(
+            discipline.sample(20, 0, true, secs(1)),
+            Correction::Slew(20)
+        );
+        assert_eq!(discipline.status().steps, 0);
+    }
+
+    #[test]
+    fn large_offsets_are_stepped_and_counted() {
+        let mut discipline = discipline();
+        assert_eq!(
+            discipline.sample(3001, 0, true, secs(0)),
+            Correction::Step(3001)
+        );
+        assert_eq!(
+            discipline.sample(-60_000, 0, true, secs(1)),
+            Correction::Step(-60_000)
+        );
+        assert_eq!(discipline.status().steps, 2);
+    }
+
+    #[test]
+    fn more_hops_slew_by_less() {
+        for (hops, slew) in [(0, 1200), (1, 600), (2, 400), (3, 300)] {
+            let mut discipline = discipline();
+            assert_eq!(
+                discipline.sample(1200, hops, true, secs(0)),
+                Correction::Slew(slew),
+                "{hops} hops"
+            );
+        }
+        // Steps are taken whole
+        let mut discipline = discipline();
+        assert_eq!(
+            discipline.sample(5000, 3, true, secs(0)),
+            Correction::Step(5000)
+        );
+    }
+
+    #[test]
+    fn too_many_hops_are_ignored() {
+        let mut discipline = discipline();
+        assert_eq!(
+            discipline.sample(100, DEFAULT_POLICY.max_hops + 1, false, secs(0)),
+            Correction::Ignore
+        );
+        assert_eq!(discipline.source(), TimeSource::None);
+        assert_eq!(
+            discipline.sample(100, DEFAULT_POLICY.max_hops, true, secs(0)),
+            Correction::Slew(100 / (i64::from(DEFAULT_POLICY.max_hops) + 1))
+        );
+    }
+
+    #[test]
+    fn fewer_hops_are_preferred_for_a_while() {
+        let mut discipline = discipline();
+        assert_eq!(
+            discipline.sample(100, 1, true, secs(0)),
+            Correction::Slew(50)
+        );
+        assert_eq!(
+            discipline.sample(100, 2, true, secs(60)),
+            Correction::Ignore
+        );
+        // As many or fewer hops are fine
+        assert_eq!(
+            discipline.sample(100, 1, true, secs(120)),
+            Correction::Slew(50)
+        );
+        assert_eq!(
+            discipline.sample(100, 0, true, secs(180)),
+            Correction::Slew(100)
+        );
+        assert_eq!(
+            discipline.sample(100, 1, true, secs(240)),
+            Correction::Ignore
+        );
+        // Until the one with fewer hops wasn't heard for long enough
+        let later = secs(180) + DEFAULT_POLICY.prefer_for;
+        assert_eq!(discipline.sample(100, 1, true, later), Correction::Slew(50));
+    }
+
+    #[test]
+    fn an_invalid_clock_takes_any_hops() {
+        let mut discipline = discipline();
+        discipline.sample(100, 0, true, secs(0));
+        assert_eq!(
+            discipline.sample(1_000_000, 3, false, secs(1)),
+            Correction::Set(1_000_000)
+        );
+    }
+
+    #[test]
+    fn the_status_reports_the_last_sample() {
+        let mut discipline = Discipline::new(DEFAULT_POLICY, TimeSource::Sntp);
+        let status = discipline.status();
+        assert_eq!(status.source, TimeSource::Sntp as i32);
+        assert_eq!((status.offset_ms, status.hops, status.steps), (0, 0, 0));
+
+        discipline.sample(-250, 2, true, secs(0));
+        let status = discipline.status();
+        assert_eq!(status.source, TimeSource::Beacon as i32);
+        assert_eq!((status.offset_ms, status.hops, status.steps), (-250, 2, 0));
+
+        // Offsets that don't fit are clamped
+        discipline.sample(i64::MAX, 0, false, secs(1));
+        assert_eq!(discipline.status().offset_ms, i32::MAX);
+        discipline.sample(i64::MIN, 0, false, secs(2));
+        assert_eq!(discipline.status().offset_ms, i32::MIN);
+    }
+
+    #[test]
+    fn only_the_first_copy_is_relayed() {
+        let mut discipline = discipline();
+        let first = beacon(1000, 0, 0);
+        assert_eq!(
+            discipline.relay(&first, Duration::from_millis(15)),
+            Some(beacon(1000, 1, 15))
+        );
+        assert_eq!(discipline.relay(&first, Duration::ZERO), None);
+        // A copy relayed by another beacon
+        assert_eq!(discipline.relay(&beacon(1000, 1, 15), Duration::ZERO), None);
+        // An older one
+        assert_eq!(discipline.relay(&beacon(900, 0, 0), Duration::ZERO), None);
+        assert_eq!(
+            discipline.relay(&beacon(2000, 1, 15), Duration::from_millis(5)),
+            Some(beacon(2000, 2, 20))
+        );
+    }
+
+    #[test]
+    fn relays_stop_at_the_max_hops() {
+        let mut discipline = discipline();
+        let max = DEFAULT_POLICY.max_hops;
+        assert_eq!(
+            discipline.relay(&beacon(1000, max, 0), Duration::ZERO),
+            None
+        );
+        assert_eq!(
+            discipline.relay(&beacon(1000, max - 1, 0), Duration::ZERO),
+            Some(beacon(1000, max, 0))
+        );
+        // The hold off saturates
+        assert_eq!(
+            discipline.relay(&beacon(2000, 0, u32::MAX - 1), secs(1)),
+            Some(beacon(2000, 1, u32::MAX))
+        );
+    }
+
+    #[test]
+    fn offsets_include_the_time_held() {
+        let gateway = time_beacon(Duration::from_millis(1_700_000_000_123));
+        assert_eq!(gateway, beacon(1_700_000_000_123, 0, 0));
+        assert_eq!(offset_ms(&gateway, Duration::ZERO, 1_700_000_000_000), 123);
+
+        // Through a beacon that held it 40ms, and we held it 10ms
+        let relayed = beacon(1000, 1, 40);
+        assert_eq!(offset_ms(&relayed, Duration::from_millis(10), 1050), 0);
+        assert_eq!(offset_ms(&relayed, Duration::from_millis(10), 1100), -50);
+    }
+
+    #[test]
+    fn sources_have_names() {
+        assert_eq!(source_name(TimeSource::Sntp as i32), "sntp");
+        assert_eq!(source_name(TimeSource::Beacon as i32), "beacon");
+        assert_eq!(source_name(TimeSource::None as i32), "none");
+        assert_eq!(source_name(42), "none");
+    }
+}
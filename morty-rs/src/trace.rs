@@ -0,0 +1,364 @@
+//! A trace of the last wakes of a tracker, for when one misbehaves and its reports don't say why.
+//! Every wake leaves a `WakeRecord`: why it woke, how long the fix took, what it sent and how long
+//! it went back to sleep for. The last `TRACE_LEN` records are kept in NVS, so they survive deep
+//! sleep and power loss, and a ResendTrace command makes the tracker send them in `TraceMsg`s, see
+//! `fragments`. The gateway uploads them to the backend.
+//!
+//! Records are packed in `RECORD_LEN` bytes, the first of which is the version of the layout.
+//! Fields are only ever added in the reserved bytes at the end, with a new version. Records of a
+//! version we don't know are dropped rather than misread.
+
+use crate::messages::TraceMsg;
+use crate::nvs_recovery::persistence_available;
+use crate::nvs_recovery::recover_write;
+use esp_idf_sys::esp;
+use esp_idf_sys::EspError;
+use log::*;
+
+/// Number of wakes we keep.
+pub const TRACE_LEN: usize = 20;
+/// Records in a TraceMsg. `budget` checks that a relayed one fits in a frame.
+pub const TRACE_RECORDS_PER_FRAME: usize = 6;
+pub const RECORD_LEN: usize = 24;
+pub const TRACE_VERSION: u8 = 1;
+/// Namespace of the trace in NVS, for `nvs_recovery`.
+pub const TRACE_NAMESPACE: &str = "trace";
+const NAMESPACE: &[u8] = b"trace\0";
+const KEY: &[u8] = b"ring\0";
+
+/// What happened during one wake of a tracker.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WakeRecord {
+    /// Wall clock time we woke up, in seconds since the epoch, 0 when we didn't know
+    pub woke_at: u32,
+    /// What woke us, an `esp_sleep_wakeup_cause_t`. 0 is a boot rather than a wake from deep
+    /// sleep.
+    pub wake_cause: u8,
+    /// Time to the first fix since we woke up, 0 without a fix
+    pub ttff_ms: u32,
+    /// The `GpsState` of the last report
+    pub gps_state: u8,
+    pub battery_mv: u16,
+    /// Frames we sent, and how many of those the radio reported as failed
+    pub sends: u8,
+    pub send_failures: u8,
+    /// Beacon present messages we heard
+    pub beacons_heard: u8,
+    /// How long we went to sleep for
+    pub sleep_secs: u32,
+}
+
+impl WakeRecord {
+    pub const fn new() -> Self {
+        Self {
+            woke_at: 0,
+            wake_cause: 0,
+            ttff_ms: 0,
+            gps_state: 0,
+            battery_mv: 0,
+            sends: 0,
+            send_failures: 0,
+            beacons_heard: 0,
+            sleep_secs: 0,
+        }
+    }
+
+    pub fn pack(&self) -> [u8; RECORD_LEN] {
+        let mut bytes = [0u8; RECORD_LEN];
+        bytes[0] = TRACE_VERSION;
+        bytes[1] = self.wake_cause;
+        bytes[2] = self.gps_state;
+        bytes[3] = self.sends;
+        bytes[4] = self.send_failures;
+        bytes[5] = self.beacons_heard;
+        bytes[6..8].copy_from_slice(&self.battery_mv.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.woke_at.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.ttff_ms.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.sleep_secs.to_le_bytes());
+        // 20..24 are reserved
+        bytes
+    }
+
+    /// None when `bytes` isn't a record of our version.
+    pub fn unpack(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != RECORD_LEN || bytes[0] != TRACE_VERSION {
+            return None;
+        }
+        let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        Some(Self {
+            wake_cause: bytes[1],
+            gps_state: bytes[2],
+            sends: bytes[3],
+            send_failures: bytes[4],
+            beacons_heard: bytes[5],
+            battery_mv: u16::from_le_bytes([bytes[6], bytes[7]]),
+            woke_at: u32_at(8),
+            ttff_ms: u32_at(12),
+            sleep_secs: u32_at(16),
+        })
+    }
+}
+
+/// Name of a wake cause, for the upload.
+pub fn wake_cause_name(cause: u8) -> &'static str {
+    match cause as u32 {
+        esp_idf_sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_UNDEFINED => "boot",
+        esp_idf_sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_EXT0 => "ext0",
+        esp_idf_sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_EXT1 => "ext1",
+        esp_idf_sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_TIMER => "timer",
+        esp_idf_sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_GPIO => "gpio",
+        esp_idf_sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_UART => "uart",
+        _ => "other",
+    }
+}
+
+/// Records packed back to back.
+pub fn pack(records: &[WakeRecord]) -> Vec<u8> {
+    records.iter().flat_map(|r| r.pack()).collect()
+}
+
+/// The records of `pack`. Records of another version are left out.
+pub fn unpack(bytes: &[u8]) -> Vec<WakeRecord> {
+    bytes
+        .chunks_exact(RECORD_LEN)
+        .filter_map(WakeRecord::unpack)
+        .collect()
+}
+
+/// `records` in as many TraceMsgs as it takes. A trace without records is one empty message, so
+/// the gateway can tell the tracker has none.
+pub fn fragments(records: &[WakeRecord], transfer_id: u32) -> Vec<TraceMsg> {
+    let chunks: Vec<&[WakeRecord]> = match records {
+        [] => vec![&[]],
+        records => records.chunks(TRACE_RECORDS_PER_FRAME).collect(),
+    };
+    let count = chunks.len() as u32;
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(index, chunk)| TraceMsg {
+            transfer_id,
+            index: index as u32,
+            count,
+            records: pack(chunk),
+        })
+        .collect()
+}
+
+/// The trace in NVS, oldest first. Empty when there's none or it can't be read.
+pub fn load() -> Vec<WakeRecord> {
+    match read_blob() {
+        Ok(Some(blob)) => unpack(&blob),
+        Ok(None) => Vec::new(),
+        Err(e) => {
+            error!("Unable to read the trace: {e}");
+            Vec::new()
+        }
+    }
+}
+
+/// Add `record` to the trace in NVS, forgetting the oldest one when there are `TRACE_LEN`.
+pub fn push(record: WakeRecord) {
+    let mut records = load();
+    records.push(record);
+    let skip = records.len().saturating_sub(TRACE_LEN);
+    let blob = pack(&records[skip..]);
+    let write = || write_blob(&blob);
+    if let Err(e) = write().or_else(|e| recover_write(e, write)) {
+        error!("Unable to save the trace: {e}");
+    }
+}
+
+fn open(mode: esp_idf_sys::nvs_open_mode_t) -> Result<esp_idf_sys::nvs_handle_t, EspError> {
+    let mut handle: esp_idf_sys::nvs_handle_t = 0;
+    esp!(unsafe { esp_idf_sys::nvs_open(NAMESPACE.as_ptr() as *const _, mode, &mut handle) })?;
+    Ok(handle)
+}
+
+fn read_blob() -> Result<Option<Vec<u8>>, EspError> {
+    if !persistence_available() {
+        return Ok(None);
+    }
+    let handle = match open(esp_idf_sys::nvs_open_mode_t_NVS_READONLY) {
+        Ok(handle) => handle,
+        Err(e) if e.code() == esp_idf_sys::ESP_ERR_NVS_NOT_FOUND as i32 => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let key = KEY.as_ptr() as *const _;
+    let mut len = 0;
+    let result =
+        esp!(unsafe { esp_idf_sys::nvs_get_blob(handle, key, std::ptr::null_mut(), &mut len) })
+            .and_then(|_| {
+                let mut blob = vec![0u8; len];
+                esp!(unsafe {
+                    esp_idf_sys::nvs_get_blob(handle, key, blob.as_mut_ptr() as *mut _, &mut len)
+                })
+                .map(|_| blob)
+            });
+    unsafe { esp_idf_sys::nvs_close(handle) };
+    match result {
+        Ok(blob) => Ok(Some(blob)),
+        Err(e) if e.code() == esp_idf_sys::ESP_ERR_NVS_NOT_FOUND as i32 => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn write_blob(blob: &[u8]) -> Result<(), EspError> {
+    if !persistence_available() {
+        return Ok(());
+    }
+    let handle = open(esp_idf_sys::nvs_open_mode_t_NVS_READWRITE)?;
+    let result = esp!(unsafe {
+        esp_idf_sys::nvs_set_blob(
+            handle,
+            KEY.as_ptr() as *const _,
+            blob.as_ptr() as *const _,
+            blob.len(),
+        )
+    })
+    .and_then(|_| esp!(unsafe { esp_idf_sys::nvs_commit(handle) }));
+    unsafe { esp_idf_sys::nvs_close(handle) };
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A wake with every field set
+    fn record(woke_at: u32) -> WakeRecord {
+        WakeRecord {
+            woke_at,
+            wake_cause: 4,
+            ttff_ms: 31_250,
+            gps_state: 2,
+            battery_mv: 3_912,
+            sends: 3,
+            send_failures: 1,
+            beacons_heard: 2,
+            sleep_secs: 300,
+        }
+    }
+
+    fn records(count: u32) -> Vec<WakeRecord> {
+        (0..count).map(|i| record(1_700_000_000 + i)).collect()
+    }
+
+    #[test]
+    fn a_record_survives_packing() {
+        let most = WakeRecord {
+            woke_at: u32::MAX,
+            wake_cause: u8::MAX,
+            ttff_ms: u32::MAX,
+            gps_state: u8::MAX,
+            battery_mv: u16::MAX,
+            sends: u8::MAX,
+            send_failures: u8::MAX,
+            beacons_heard: u8::MAX,
+            sleep_secs: u32::MAX,
+        };
+        for record in [WakeRecord::new(), record(1_700_000_000), most] {
+            assert_eq!(WakeRecord::unpack(&record.pack()), Some(record));
+        }
+    }
+
+    #[test]
+    fn the_layout_of_a_record_is_fixed() {
+        let bytes = record(0x0403_0201).pack();
+        assert_eq!(bytes[..6], [TRACE_VERSION, 4, 2, 3, 1, 2]);
+        assert_eq!(bytes[6..8], 3_912u16.to_le_bytes());
+        assert_eq!(bytes[8..12], [0x01, 0x02, 0x03, 0x04]);
+        assert_eq!(bytes[12..16], 31_250u32.to_le_bytes());
+        assert_eq!(bytes[16..20], 300u32.to_le_bytes());
+        assert_eq!(bytes[20..], [0; 4]);
+    }
+
+    #[test]
+    fn records_of_another_version_or_length_are_dropped() {
+        let mut bytes = record(1).pack();
+        bytes[0] = TRACE_VERSION + 1;
+        assert_eq!(WakeRecord::unpack(&bytes), None);
+        bytes[0] = 0;
+        assert_eq!(WakeRecord::unpack(&bytes), None);
+        let bytes = record(1).pack();
+        assert_eq!(WakeRecord::unpack(&bytes[..RECORD_LEN - 1]), None);
+        assert_eq!(WakeRecord::unpack(&[bytes.as_slice(), &[0]].concat()), None);
+        assert_eq!(WakeRecord::unpack(&[]), None);
+    }
+
+    #[test]
+    fn reserved_bytes_are_ignored() {
+        let mut bytes = record(1).pack();
+        bytes[RECORD_LEN - 4..].copy_from_slice(&[0xff; 4]);
+        assert_eq!(WakeRecord::unpack(&bytes), Some(record(1)));
+    }
+
+    #[test]
+    fn a_list_of_records_survives_packing() {
+        let records = records(TRACE_LEN as u32);
+        let bytes = pack(&records);
+        assert_eq!(bytes.len(), TRACE_LEN * RECORD_LEN);
+        assert_eq!(unpack(&bytes), records);
+        assert_eq!(pack(&[]), Vec::<u8>::new());
+        assert_eq!(unpack(&[]), []);
+    }
+
+    #[test]
+    fn unpacking_skips_records_it_cant_read() {
+        let mut bytes = pack(&records(3));
+        bytes[RECORD_LEN] = TRACE_VERSION + 1;
+        // A partial record at the end
+        bytes.extend_from_slice(&record(9).pack()[..10]);
+        assert_eq!(
+            unpack(&bytes),
+            [record(1_700_000_000), record(1_700_000_002)]
+        );
+    }
+
+    #[test]
+    fn a_trace_is_cut_into_frames() {
+        let records = records(TRACE_LEN as u32);
+        let msgs = fragments(&records, 77);
+        assert_eq!(
+            msgs.len(),
+            (TRACE_LEN + TRACE_RECORDS_PER_FRAME - 1) / TRACE_RECORDS_PER_FRAME
+        );
+        for (i, msg) in msgs.iter().enumerate() {
+            assert_eq!(msg.transfer_id, 77);
+            assert_eq!(msg.index, i as u32);
+            assert_eq!(msg.count, msgs.len() as u32);
+            assert!(msg.records.len() <= TRACE_RECORDS_PER_FRAME * RECORD_LEN);
+        }
+        let bytes: Vec<u8> = msgs.iter().flat_map(|m| m.records.clone()).collect();
+        assert_eq!(unpack(&bytes), records);
+    }
+
+    #[test]
+    fn a_trace_that_fits_one_frame_is_one_message() {
+        let records = records(TRACE_RECORDS_PER_FRAME as u32);
+        let msgs = fragments(&records, 1);
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(unpack(&msgs[0].records), records);
+    }
+
+    #[test]
+    fn an_empty_trace_is_one_empty_message() {
+        let msgs = fragments(&[], 5);
+        assert_eq!(
+            msgs,
+            [TraceMsg {
+                transfer_id: 5,
+                index: 0,
+                count: 1,
+                records: Vec::new(),
+            }]
+        );
+    }
+
+    #[test]
+    fn wake_causes_have_names() {
+        assert_eq!(wake_cause_name(0), "boot");
+        assert_eq!(wake_cause_name(4), "timer");
+        assert_eq!(wake_cause_name(u8::MAX), "other");
+    }
+}
@@ -0,0 +1,161 @@
+//! Blobs that are too big for a single ESP-NOW frame are sent as a numbered series of chunks, and
+//! put back together on the receiving device.
+
+use crate::messages::ChunkMsg;
+use crate::messages::TransferAckMsg;
+
+/// Split `data` into chunks for transfer `transfer_id`.
+pub fn split(
+    data: &[u8],
+    transfer_id: u32,
+    target: &str,
+    valid_until: i64,
+    chunk_size: usize,
+) -> Vec<ChunkMsg> {
+    let count = data.chunks(chunk_size).count() as u32;
+    data.chunks(chunk_size)
+        .enumerate()
+        .map(|(index, chunk)| ChunkMsg {
+            target: target.to_string(),
+            transfer_id,
+            index: index as u32,
+            count,
+            data: chunk.to_vec(),
+            valid_until,
+        })
+        .collect()
+}
+
+/// Collects the chunks of a single transfer. Chunks can come in any order and more than once.
+pub struct Reassembly {
+    transfer_id: u32,
+    valid_until: i64,
+    chunks: Vec<Option<Vec<u8>>>,
+}
+
+impl Reassembly {
+    pub fn new(first: &ChunkMsg) -> Self {
+        Self {
+            transfer_id: first.transfer_id,
+            valid_until: first.valid_until,
+            chunks: vec![None; first.count as usize],
+        }
+    }
+
+    pub fn transfer_id(&self) -> u32 {
+        self.transfer_id
+    }
+
+    /// Seconds since the epoch after which the data is useless, 0 if it doesn't expire.
+    pub fn valid_until(&self) -> i64 {
+        self.valid_until
+    }
+
+    /// Add a chunk. Returns false if it doesn't belong to this transfer.
+    pub fn add(&mut self, chunk: &ChunkMsg) -> bool {
+        if chunk.transfer_id != self.transfer_id || chunk.count as usize != self.chunks.len() {
+            return false;
+        }
+        match self.chunks.get_mut(chunk.index as usize) {
+            Some(slot) => {
+                *slot = Some(chunk.data.clone());
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn received(&self) -> usize {
+        self.chunks.iter().filter(|c| c.is_some()).count()
+    }
+
+    pub fn count(&self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.received() == self.count()
+    }
+
+    /// The whole blob, once all chunks are in.
+    pub fn data(&self) -> Option<Vec<u8>> {
+        self.is_complete()
+            .then(|| self.chunks.iter().flatten().flatten().copied().collect())
+    }
+
+    /// Progress report for the sender.
+    pub fn ack(&self, done: bool, ok: bool) -> TransferAckMsg {
+        TransferAckMsg {
+            transfer_id: self.transfer_id,
+            received: self.received() as u32,
+            count: self.count() as u32,
+            done,
+            ok,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_carry_the_transfer() {
+        let chunks = split(&[1, 2, 3, 4, 5], 7, "aa:bb", 1000, 2);
+        let data: Vec<&[u8]> = chunks.iter().map(|c| c.data.as_slice()).collect();
+        assert_eq!(data, [&[1, 2][..], &[3, 4], &[5]]);
+        for (index, chunk) in chunks.iter().enumerate() {
+            assert_eq!(chunk.index, index as u32);
+            assert_eq!(chunk.count, 3);
+            assert_eq!(chunk.transfer_id, 7);
+            assert_eq!(chunk.target, "aa:bb");
+            assert_eq!(chunk.valid_until, 1000);
+        }
+    }
+
+    #[test]
+    fn chunks_go_back_together_in_any_order() {
+        let data: Vec<u8> = (0..=255).collect();
+        let chunks = split(&data, 1, "", 0, 100);
+        let mut reassembly = Reassembly::new(&chunks[2]);
+        for i in [2, 0, 0, 1] {
+            assert!(!reassembly.is_complete());
+            assert_eq!(reassembly.data(), None);
+            assert!(reassembly.add(&chunks[i]));
+        }
+        assert!(reassembly.is_complete());
+        assert_eq!(reassembly.data(), Some(data));
+    }
+
+    #[test]
+    fn chunks_of_other_transfers_are_refused() {
+        let chunks = split(&[1, 2, 3, 4], 1, "", 0, 2);
+        let mut reassembly = Reassembly::new(&chunks[0]);
+        let other = split(&[1, 2, 3, 4], 2, "", 0, 2);
+        assert!(!reassembly.add(&other[0]));
+        // Same transfer, different number of chunks
+        let resized = split(&[1, 2, 3, 4], 1, "", 0, 1);
+        assert!(!reassembly.add(&resized[0]));
+        let mut beyond = chunks[1].clone();
+        beyond.index = 2;
+        assert!(!reassembly.add(&beyond));
+        assert_eq!(reassembly.received(), 0);
+    }
+
+    #[test]
+    fn acks_report_the_progress() {
+        let chunks = split(&[1, 2, 3, 4, 5, 6], 9, "", 0, 2);
+        let mut reassembly = Reassembly::new(&chunks[0]);
+        reassembly.add(&chunks[1]);
+        assert_eq!(
+            reassembly.ack(false, true),
+            TransferAckMsg {
+                transfer_id: 9,
+                received: 1,
+                count: 3,
+                done: false,
+                ok: true,
+            }
+        );
+    }
+}
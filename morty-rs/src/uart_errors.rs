@@ -0,0 +1,231 @@
+//! Hardware errors on a UART. Frames that don't decode can be caused by a bad ground or a wrong
+//! baud rate rather than a bug, and the UART driver knows about that. We listen to its event
+//! queue, count the errors and warn when there are a lot of framing errors.
+
+use crate::led::colors;
+use crate::led::LedHandle;
+use crate::utils::spawn_thread;
+use crate::utils::ThreadConfig;
+use esp_idf_hal::delay::BLOCK;
+use esp_idf_sys::esp;
+use esp_idf_sys::EspError;
+use log::*;
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::Instant;
+
+const RX_BUFFER_SIZE: i32 = 1024;
+const TX_BUFFER_SIZE: i32 = 1024;
+const EVENT_QUEUE_SIZE: i32 = 20;
+
+const WARN_BRIGHTNESS: u8 = 10;
+const WARN_BLINK_PERIOD: Duration = Duration::from_millis(100);
+const WARN_BLINKS: u8 = 10;
+
+/// Error counters of a UART, since boot.
+pub struct UartErrors {
+    pub framing: AtomicU32,
+    pub parity: AtomicU32,
+    pub overrun: AtomicU32,
+}
+
+impl UartErrors {
+    pub const fn new() -> Self {
+        Self {
+            framing: AtomicU32::new(0),
+            parity: AtomicU32::new(0),
+            overrun: AtomicU32::new(0),
+        }
+    }
+}
+
+impl Default for UartErrors {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for UartErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "uart_framing_errors={} uart_parity_errors={} uart_overruns={}",
+            self.framing.load(Ordering::Relaxed),
+            self.parity.load(Ordering::Relaxed),
+            self.overrun.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Keeps track of how many errors happened within the last `window`.
+pub struct ErrorRate {
+    window: Duration,
+    threshold: usize,
+    errors: VecDeque<Instant>,
+}
+
+impl ErrorRate {
+    pub fn new(window: Duration, threshold: usize) -> Self {
+        Self {
+            window,
+            threshold,
+            errors: VecDeque::new(),
+        }
+    }
+
+    /// Record an error at `now`. Returns true when this error pushes the number of errors within
+    /// the window over the threshold. After that the window starts over, so we don't keep on
+    /// complaining about the same burst.
+    pub fn record(&mut self, now: Instant) -> bool {
+        while let Some(oldest) = self.errors.front() {
+            if now.duration_since(*oldest) > self.window {
+                self.errors.pop_front();
+            } else {
+                break;
+            }
+        }
+        self.errors.push_back(now);
+
+        if self.errors.len() > self.threshold {
+            self.errors.clear();
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Reinstall the driver of `port` with an event queue and count its errors in `errors`. This
+/// has to be called right after creating the UartDriver for `port`. When there are more than
+/// `threshold` framing errors in a minute, we warn about the wiring and blink the LED.
+pub fn monitor_uart_errors(
+    port: i32,
+    errors: &'static UartErrors,
+    threshold: usize,
+    led: LedHandle,
+    thread: ThreadConfig,
+) -> Result<(), anyhow::Error> {
+    let mut queue: esp_idf_sys::QueueHandle_t = std::ptr::null_mut();
+    esp!(unsafe { esp_idf_sys::uart_driver_delete(port) })?;
+    esp!(unsafe {
+        esp_idf_sys::uart_driver_install(
+            port,
+            RX_BUFFER_SIZE,
+            TX_BUFFER_SIZE,
+            EVENT_QUEUE_SIZE,
+            &mut queue,
+            0,
+        )
+    })?;
+
+    // The queue handle is only used by the thread
+    let queue = queue as usize;
+    spawn_thread(thread, move || {
+        let mut rate = ErrorRate::new(Duration::from_secs(60), threshold);
+        loop {
+            if let Err(e) = wait_for_error(port, queue as _, errors, &mut rate, &led) {
+                error!("Unable to handle UART {port} event: {e}");
+            }
+        }
+    })?;
+    Ok(())
+}
+
+fn wait_for_error(
+    port: i32,
+    queue: esp_idf_sys::QueueHandle_t,
+    errors: &UartErrors,
+    rate: &mut ErrorRate,
+    led: &LedHandle,
+) -> Result<(), EspError> {
+    let mut event: esp_idf_sys::uart_event_t = unsafe { std::mem::zeroed() };
+    let received =
+        unsafe { esp_idf_sys::xQueueReceive(queue, &mut event as *mut _ as *mut _, BLOCK) };
+    if received == 0 {
+        return Ok(());
+    }
+
+    match event.type_ {
+        esp_idf_sys::uart_event_type_t_UART_FRAME_ERR => {
+            errors.framing.fetch_add(1, Ordering::Relaxed);
+            if rate.record(Instant::now()) {
+                warn!("Lots of framing errors on UART {port}, check the wiring and baud rate ({errors})");
+                let _ = led.blink_color(
+                    colors::CYAN,
+                    WARN_BRIGHTNESS,
+                    WARN_BLINK_PERIOD,
+                    WARN_BLINKS,
+                );
+            }
+        }
+        esp_idf_sys::uart_event_type_t_UART_PARITY_ERR => {
+            errors.parity.fetch_add(1, Ordering::Relaxed);
+        }
+        esp_idf_sys::uart_event_type_t_UART_FIFO_OVF
+        | esp_idf_sys::uart_event_type_t_UART_BUFFER_FULL => {
+            errors.overrun.fetch_add(1, Ordering::Relaxed);
+            // The driver stops receiving until there's room again, so we throw away what we have
+            warn!("UART {port} overrun ({errors})");
+            esp!(unsafe { esp_idf_sys::uart_flush_input(port) })?;
+            unsafe { esp_idf_sys::xQueueGenericReset(queue, 0) };
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn errors_over_the_threshold_warn() {
+        let mut rate = ErrorRate::new(WINDOW, 3);
+        let start = Instant::now();
+        for i in 0..3 {
+            assert!(!rate.record(start + Duration::from_secs(i)));
+        }
+        assert!(rate.record(start + Duration::from_secs(3)));
+        // The window starts over after a warning
+        for i in 4..7 {
+            assert!(!rate.record(start + Duration::from_secs(i)));
+        }
+        assert!(rate.record(start + Duration::from_secs(7)));
+    }
+
+    #[test]
+    fn errors_out_of_the_window_dont_count() {
+        let mut rate = ErrorRate::new(WINDOW, 2);
+        let start = Instant::now();
+        assert!(!rate.record(start));
+        assert!(!rate.record(start + Duration::from_secs(30)));
+        // The first one is just out of the window
+        assert!(!rate.record(start + Duration::from_millis(60_001)));
+        // Exactly a window after the second one is still in it
+        assert!(rate.record(start + Duration::from_secs(90)));
+    }
+
+    #[test]
+    fn a_zero_threshold_warns_about_every_error() {
+        let mut rate = ErrorRate::new(WINDOW, 0);
+        let start = Instant::now();
+        assert!(rate.record(start));
+        assert!(rate.record(start));
+    }
+
+    #[test]
+    fn counters_are_logged_by_name() {
+        let errors = UartErrors::new();
+        errors.framing.fetch_add(3, Ordering::Relaxed);
+        errors.overrun.fetch_add(1, Ordering::Relaxed);
+        assert_eq!(
+            errors.to_string(),
+            "uart_framing_errors=3 uart_parity_errors=0 uart_overruns=1"
+        );
+    }
+}
@@ -1,38 +1,412 @@
+use crate::clock::mark_wall_clock_valid;
 use esp_idf_hal::uart::UartDriver;
 use esp_idf_hal::{delay::BLOCK, task::thread::ThreadSpawnConfiguration};
-use esp_idf_svc::timer::EspTimerService;
+use esp_idf_svc::sntp::SyncStatus;
+use esp_idf_svc::systime::EspSystemTime;
 use esp_idf_sys::EspError;
 use hexdump::hexdump_iter;
 use log::*;
-use std::{io::Read, time::Duration};
+use std::{
+    fmt::Write as _,
+    io::Read,
+    sync::Mutex,
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
 
-pub struct LastUpdate {
-    last_update: Duration,
-    timer_service: EspTimerService<esp_idf_svc::timer::Task>,
+/// Time `$body` as a boot phase called `$name`, see `boot_complete`. Without the `diagnostics`
+/// feature this is just `$body`.
+#[cfg(feature = "diagnostics")]
+#[macro_export]
+macro_rules! phase {
+    ($name:expr, $body:block) => {{
+        let start = $crate::utils::phase_start();
+        let result = $body;
+        $crate::utils::phase_end($name, start);
+        result
+    }};
 }
-impl Default for LastUpdate {
+
+#[cfg(not(feature = "diagnostics"))]
+#[macro_export]
+macro_rules! phase {
+    ($name:expr, $body:block) => {
+        $body
+    };
+}
+
+// Number of boot phases we keep track of, later ones are dropped
+#[cfg(feature = "diagnostics")]
+const MAX_PHASES: usize = 16;
+
+#[cfg(feature = "diagnostics")]
+#[derive(Clone, Copy)]
+struct Phase {
+    name: &'static str,
+    // Microseconds since boot
+    start: i64,
+    end: i64,
+}
+
+#[cfg(feature = "diagnostics")]
+static PHASES: std::sync::Mutex<[Option<Phase>; MAX_PHASES]> =
+    std::sync::Mutex::new([None; MAX_PHASES]);
+
+#[cfg(feature = "diagnostics")]
+#[doc(hidden)]
+pub fn phase_start() -> i64 {
+    unsafe { esp_idf_sys::esp_timer_get_time() }
+}
+
+#[cfg(feature = "diagnostics")]
+#[doc(hidden)]
+pub fn phase_end(name: &'static str, start: i64) {
+    let end = unsafe { esp_idf_sys::esp_timer_get_time() };
+    let mut phases = PHASES.lock().unwrap();
+    if let Some(slot) = phases.iter_mut().find(|p| p.is_none()) {
+        *slot = Some(Phase { name, start, end });
+    }
+}
+
+/// The boot phases as a single line: time since boot, followed by the start and duration of every
+/// phase, all in ms. None without the `diagnostics` feature.
+#[cfg(feature = "diagnostics")]
+pub fn boot_phases() -> Option<String> {
+    let now = unsafe { esp_idf_sys::esp_timer_get_time() };
+    let mut line = format!("boot_ms={}", now / 1000);
+    for p in PHASES.lock().unwrap().iter().flatten() {
+        line.push_str(&format!(
+            " {}={}+{}",
+            p.name,
+            p.start / 1000,
+            (p.end - p.start) / 1000
+        ));
+    }
+    Some(line)
+}
+
+#[cfg(not(feature = "diagnostics"))]
+pub fn boot_phases() -> Option<String> {
+    None
+}
+
+/// Log the boot phases and the threads, once the device is up and doing what it should. Only
+/// with the `diagnostics` feature.
+pub fn boot_complete() {
+    if let Some(phases) = boot_phases() {
+        info!("Boot phases: {phases}");
+        info!("Threads:\n{}", dump_threads());
+    }
+}
+
+/// A set of named timers that each come due every interval, optionally with some random jitter
+/// so devices that booted together don't all act at the same moment. A timer that was never due
+/// before is due right away.
+///
+/// Times are passed in by the caller, usually `uptime`. Timers that should survive deep sleep
+/// can be saved with `next_due` and put back with `restore`, as long as the caller uses a clock
+/// that keeps running while asleep. When the clock jumps back, e.g. because it was set, or a
+/// restored time is from another timeline, timers that are further away than their interval come
+/// due right away.
+pub struct PeriodicSet {
+    timers: Vec<Timer>,
+    random: fn() -> u32,
+}
+
+struct Timer {
+    name: &'static str,
+    interval: Duration,
+    jitter: Duration,
+    // None until the timer was due for the first time
+    next: Option<Duration>,
+}
+
+impl Default for PeriodicSet {
     fn default() -> Self {
         Self::new()
     }
 }
 
-impl LastUpdate {
+impl PeriodicSet {
     pub fn new() -> Self {
+        Self::with_random(|| unsafe { esp_idf_sys::esp_random() })
+    }
+
+    /// A set that takes its jitter from `random`.
+    pub fn with_random(random: fn() -> u32) -> Self {
+        Self {
+            timers: Vec::new(),
+            random,
+        }
+    }
+
+    /// Add a timer that's due every `interval`.
+    pub fn add(&mut self, name: &'static str, interval: Duration) -> &mut Self {
+        self.add_with_jitter(name, interval, Duration::ZERO)
+    }
+
+    /// Add a timer that's due every `interval`, plus a random part of `jitter`.
+    pub fn add_with_jitter(
+        &mut self,
+        name: &'static str,
+        interval: Duration,
+        jitter: Duration,
+    ) -> &mut Self {
+        self.timers.retain(|t| t.name != name);
+        self.timers.push(Timer {
+            name,
+            interval,
+            jitter,
+            next: None,
+        });
+        self
+    }
+
+    /// Whether timer `name` is due at `now`. If it is, the next time it's due is scheduled.
+    pub fn due(&mut self, name: &str, now: Duration) -> bool {
+        let random = self.random;
+        match self.timers.iter_mut().find(|t| t.name == name) {
+            Some(timer) => timer.check(now, random),
+            None => {
+                warn!("Unknown timer {name}");
+                false
+            }
+        }
+    }
+
+    /// The names of all timers that are due at `now`, which are rescheduled.
+    pub fn due_now(&mut self, now: Duration) -> Vec<&'static str> {
+        let random = self.random;
+        self.timers
+            .iter_mut()
+            .filter_map(|t| t.check(now, random).then_some(t.name))
+            .collect()
+    }
+
+    /// Make timer `name` due right away.
+    pub fn reset(&mut self, name: &str) {
+        if let Some(timer) = self.timers.iter_mut().find(|t| t.name == name) {
+            timer.next = None;
+        }
+    }
+
+    /// When timer `name` is due next, None if it's due right away. Save this to keep the timer
+    /// across deep sleep.
+    pub fn next_due(&self, name: &str) -> Option<Duration> {
+        self.timers.iter().find(|t| t.name == name)?.next
+    }
+
+    /// Put back the time timer `name` is due next, as saved from `next_due`.
+    pub fn restore(&mut self, name: &str, next: Duration) {
+        if let Some(timer) = self.timers.iter_mut().find(|t| t.name == name) {
+            timer.next = Some(next);
+        }
+    }
+}
+
+impl Timer {
+    fn check(&mut self, now: Duration, random: fn() -> u32) -> bool {
+        let due = match self.next {
+            None => true,
+            // Further away than it can be, the clock went back
+            Some(next) if next > now + self.interval + self.jitter => true,
+            Some(next) => now >= next,
+        };
+        if due {
+            let jitter = match self.jitter.as_millis() as u64 {
+                0 => Duration::ZERO,
+                ms => Duration::from_millis(random() as u64 % (ms + 1)),
+            };
+            self.next = Some(now + self.interval + jitter);
+        }
+        due
+    }
+}
+
+/// Delays between retries of something that keeps failing. The first delay is `base`, every next
+/// one is `multiplier` times longer, up to `cap`. With full jitter, every delay is a random part
+/// of that instead, so devices that failed together don't all retry at the same moment. Call
+/// `reset` once it works again.
+pub struct Backoff {
+    base: Duration,
+    multiplier: u32,
+    cap: Duration,
+    jitter: bool,
+    max_attempts: Option<u32>,
+    random: fn() -> u32,
+    attempts: u32,
+    // Delay before jitter of the next attempt
+    next: Duration,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, multiplier: u32, cap: Duration) -> Self {
         Self {
-            last_update: Duration::from_secs(0),
-            timer_service: EspTimerService::new().unwrap(),
+            base,
+            multiplier,
+            cap,
+            jitter: false,
+            max_attempts: None,
+            random: || unsafe { esp_idf_sys::esp_random() },
+            attempts: 0,
+            next: base.min(cap),
+        }
+    }
+
+    /// Use full jitter: every delay is random between zero and the delay without jitter.
+    pub fn with_jitter(mut self) -> Self {
+        self.jitter = true;
+        self
+    }
+
+    /// Give up after `max_attempts` delays.
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = Some(max_attempts);
+        self
+    }
+
+    /// Take the jitter from `random`, so the delays are predictable.
+    pub fn with_random(mut self, random: fn() -> u32) -> Self {
+        self.random = random;
+        self
+    }
+
+    /// The delay before the next attempt, None when we should give up.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        if self.max_attempts.map_or(false, |max| self.attempts >= max) {
+            return None;
+        }
+        self.attempts += 1;
+        let delay = self.next;
+        // Multiplying a delay that's capped already could overflow
+        if self.next < self.cap {
+            self.next = self
+                .next
+                .checked_mul(self.multiplier)
+                .map_or(self.cap, |next| next.min(self.cap));
+        }
+        if !self.jitter {
+            return Some(delay);
         }
+        match delay.as_millis() as u64 {
+            0 => Some(Duration::ZERO),
+            ms => Some(Duration::from_millis((self.random)() as u64 % (ms + 1))),
+        }
+    }
+
+    /// Number of delays handed out since the last reset.
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Start over at `base`, after a success.
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+        self.next = self.base.min(self.cap);
     }
+}
+
+// Units of `format_duration` from days down to seconds, in seconds
+const DURATION_UNITS: [(&str, u128); 4] = [("d", 86400), ("h", 3600), ("m", 60), ("s", 1)];
+// Most units `format_duration` shows, the smallest is rounded
+const DURATION_PARTS: usize = 3;
+const BYTE_UNITS: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
 
-    pub fn should_update(&mut self, since: Duration) -> bool {
-        let now = self.timer_service.now();
-        if Duration::is_zero(&self.last_update) || now - self.last_update >= since {
-            self.last_update = now;
-            true
-        } else {
-            false
+/// `duration` for people, e.g. "2d 3h 14m", "1h 5s", "4.5s", "120ms" or "80us". From a minute up
+/// this shows at most three units, rounded at the smallest, and leaves out the ones that are 0.
+/// Under a minute this shows tenths of seconds, under a second milliseconds.
+pub fn format_duration(duration: Duration) -> String {
+    let us = duration.as_micros();
+    if us == 0 {
+        return "0s".to_string();
+    }
+    if us < 1000 {
+        return format!("{us}us");
+    }
+    let ms = (us + 500) / 1000;
+    if ms < 1000 {
+        return format!("{ms}ms");
+    }
+    let tenths = (ms + 50) / 100;
+    if tenths < 600 {
+        return match tenths % 10 {
+            0 => format!("{}s", tenths / 10),
+            tenth => format!("{}.{tenth}s", tenths / 10),
+        };
+    }
+    let secs = (ms + 500) / 1000;
+    let largest = DURATION_UNITS
+        .iter()
+        .position(|&(_, size)| secs >= size)
+        .unwrap_or(DURATION_UNITS.len() - 1);
+    let smallest = (largest + DURATION_PARTS - 1).min(DURATION_UNITS.len() - 1);
+    let step = DURATION_UNITS[smallest].1;
+    // Rounding can carry into a larger unit, which is then shown as well
+    let mut left = (secs + step / 2) / step * step;
+    let mut text = String::new();
+    for &(unit, size) in &DURATION_UNITS[..=smallest] {
+        let count = left / size;
+        left %= size;
+        if count > 0 {
+            if !text.is_empty() {
+                text.push(' ');
+            }
+            let _ = write!(text, "{count}{unit}");
         }
     }
+    text
+}
+
+/// `bytes` for people, in binary units with one decimal, e.g. "512 B", "1.0 KiB" or "1.4 MiB".
+pub fn format_bytes(bytes: u64) -> String {
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let bytes = bytes as u128;
+    let mut unit = 0;
+    let tenths = loop {
+        let size = 1_u128 << (10 * (unit + 1));
+        let tenths = (bytes * 10 + size / 2) / size;
+        // Rounding up to 1024 of a unit is 1.0 of the next one
+        if tenths < 10240 || unit == BYTE_UNITS.len() - 1 {
+            break tenths;
+        }
+        unit += 1;
+    };
+    format!("{}.{} {}", tenths / 10, tenths % 10, BYTE_UNITS[unit])
+}
+
+/// How long ago `epoch` was at `now`, both in seconds since the Unix epoch, e.g. "3h 12m ago". A
+/// time after `now` is "in 5m".
+pub fn format_age(epoch: i64, now: i64) -> String {
+    let age = Duration::from_secs(now.abs_diff(epoch));
+    if epoch > now {
+        format!("in {}", format_duration(age))
+    } else {
+        format!("{} ago", format_duration(age))
+    }
+}
+
+/// Time since boot.
+pub fn uptime() -> Duration {
+    Duration::from_micros(unsafe { esp_idf_sys::esp_timer_get_time() } as u64)
+}
+
+/// Wait for SNTP to sync the system time, giving up after `timeout`.
+pub fn sync_sntp(timeout: Duration) -> Result<(), anyhow::Error> {
+    let sntp = esp_idf_svc::sntp::EspSntp::new_default()?;
+    let start = Instant::now();
+    while sntp.get_sync_status() != SyncStatus::Completed {
+        if start.elapsed() > timeout {
+            anyhow::bail!("SNTP did not sync within {}", format_duration(timeout));
+        }
+        info!("Waiting for SNTP to sync");
+        std::thread::sleep(Duration::from_secs(1));
+    }
+    mark_wall_clock_valid();
+    let now = EspSystemTime.now();
+    info!("Current time: {:?}", now);
+    Ok(())
 }
 
 pub fn set_thread_spawn_configuration(
@@ -51,6 +425,104 @@ pub fn set_thread_spawn_configuration(
     .set()
 }
 
+/// How a thread is spawned. Every binary keeps these in one table, so the priorities can be
+/// compared. `name` ends with a NUL, FreeRTOS takes it as a C string.
+#[derive(Clone, Copy, Debug)]
+pub struct ThreadConfig {
+    pub name: &'static str,
+    pub stack_size: usize,
+    pub priority: u8,
+    pub core: Option<esp_idf_hal::cpu::Core>,
+}
+
+impl ThreadConfig {
+    pub const fn new(
+        name: &'static str,
+        stack_size: usize,
+        priority: u8,
+        core: Option<esp_idf_hal::cpu::Core>,
+    ) -> Self {
+        Self {
+            name,
+            stack_size,
+            priority,
+            core,
+        }
+    }
+}
+
+// The threads spawned with `spawn_thread` that are still running, with their FreeRTOS task
+static THREADS: Mutex<Vec<(ThreadConfig, usize)>> = Mutex::new(Vec::new());
+
+// Takes a thread out of THREADS when it ends
+struct Registration(usize);
+
+impl Registration {
+    fn new(config: ThreadConfig) -> Self {
+        let task = unsafe { esp_idf_sys::xTaskGetCurrentTaskHandle() } as usize;
+        THREADS.lock().unwrap().push((config, task));
+        Self(task)
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        THREADS.lock().unwrap().retain(|(_, task)| *task != self.0);
+    }
+}
+
+/// Spawn `f` on a thread as `config` says, and keep it in the table of `dump_threads` while it
+/// runs.
+pub fn spawn_thread<F, T>(config: ThreadConfig, f: F) -> Result<JoinHandle<T>, anyhow::Error>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    set_thread_spawn_configuration(config.name, config.stack_size, config.priority, config.core)?;
+    Ok(std::thread::Builder::new()
+        .stack_size(config.stack_size)
+        .spawn(move || {
+            let _registration = Registration::new(config);
+            f()
+        })?)
+}
+
+/// The threads spawned with `spawn_thread` as a table, with the priority they run at and the
+/// least stack they had left so far, e.g.
+///
+/// ```text
+/// thread           core prio stack  free
+/// recv-thread      1    15   8196   2412
+/// ```
+pub fn dump_threads() -> String {
+    let mut threads = THREADS.lock().unwrap().clone();
+    threads.sort_by_key(|(config, _)| std::cmp::Reverse(config.priority));
+    let mut table = format!(
+        "{:<16} {:<4} {:<4} {:<6} {}",
+        "thread", "core", "prio", "stack", "free"
+    );
+    for (config, task) in threads {
+        let task = task as esp_idf_sys::TaskHandle_t;
+        // In bytes on ESP-IDF, not in words like on other FreeRTOS ports
+        let free = unsafe { esp_idf_sys::uxTaskGetStackHighWaterMark(task) };
+        let priority = unsafe { esp_idf_sys::uxTaskPriorityGet(task) };
+        let core = match config.core {
+            Some(esp_idf_hal::cpu::Core::Core0) => "0",
+            Some(esp_idf_hal::cpu::Core::Core1) => "1",
+            None => "any",
+        };
+        table += &format!(
+            "\n{:<16} {:<4} {:<4} {:<6} {}",
+            config.name.trim_end_matches('\0'),
+            core,
+            priority,
+            config.stack_size,
+            free
+        );
+    }
+    table
+}
+
 pub fn log_hexdump(data: &[u8]) {
     let iter = hexdump_iter(data);
     for line in iter {
@@ -91,3 +563,218 @@ impl<'a> Read for UartRead<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn timers_are_due_right_away_and_then_every_interval() {
+        let mut timers = PeriodicSet::with_random(|| 0);
+        timers.add("a", secs(10));
+        assert!(timers.due("a", secs(100)));
+        assert!(!timers.due("a", secs(109)));
+        assert!(timers.due("a", secs(110)));
+        assert_eq!(timers.next_due("a"), Some(secs(120)));
+    }
+
+    #[test]
+    fn unknown_timers_are_never_due() {
+        let mut timers = PeriodicSet::with_random(|| 0);
+        assert!(!timers.due("a", secs(0)));
+        assert_eq!(timers.next_due("a"), None);
+    }
+
+    #[test]
+    fn due_now_lists_and_reschedules_the_due_timers() {
+        let mut timers = PeriodicSet::with_random(|| 0);
+        timers.add("a", secs(10)).add("b", secs(30));
+        assert_eq!(timers.due_now(secs(0)), ["a", "b"]);
+        assert_eq!(timers.due_now(secs(10)), ["a"]);
+        assert_eq!(timers.due_now(secs(15)), Vec::<&str>::new());
+        assert_eq!(timers.due_now(secs(30)), ["a", "b"]);
+    }
+
+    #[test]
+    fn adding_a_timer_again_replaces_it() {
+        let mut timers = PeriodicSet::with_random(|| 0);
+        timers.add("a", secs(10));
+        timers.due("a", secs(0));
+        timers.add("a", secs(60));
+        assert_eq!(timers.due_now(secs(1)), ["a"]);
+        assert_eq!(timers.next_due("a"), Some(secs(61)));
+    }
+
+    #[test]
+    fn jitter_is_a_random_part_of_the_jitter() {
+        let mut timers = PeriodicSet::with_random(|| 12_345);
+        timers.add_with_jitter("a", secs(10), Duration::from_millis(1000));
+        timers.due("a", secs(0));
+        // 12345 % 1001
+        assert_eq!(timers.next_due("a"), Some(Duration::from_millis(10_333)));
+    }
+
+    #[test]
+    fn reset_makes_a_timer_due() {
+        let mut timers = PeriodicSet::with_random(|| 0);
+        timers.add("a", secs(10));
+        timers.due("a", secs(0));
+        timers.reset("a");
+        assert_eq!(timers.next_due("a"), None);
+        assert!(timers.due("a", secs(1)));
+    }
+
+    #[test]
+    fn restored_timers_keep_their_time() {
+        let mut timers = PeriodicSet::with_random(|| 0);
+        timers.add("a", secs(10));
+        timers.restore("a", secs(105));
+        assert!(!timers.due("a", secs(100)));
+        assert!(timers.due("a", secs(105)));
+    }
+
+    #[test]
+    fn timers_from_before_the_clock_went_back_are_due() {
+        let mut timers = PeriodicSet::with_random(|| 0);
+        timers.add_with_jitter("a", secs(10), secs(5));
+        timers.restore("a", secs(1000));
+        // As far away as it can be with the jitter
+        assert!(!timers.due("a", secs(985)));
+        assert!(timers.due("a", secs(984)));
+        assert_eq!(timers.next_due("a"), Some(secs(994)));
+    }
+
+    #[test]
+    fn delays_grow_up_to_the_cap() {
+        let mut backoff = Backoff::new(secs(1), 2, secs(5));
+        let delays: Vec<Duration> = (0..5).map(|_| backoff.next_delay().unwrap()).collect();
+        assert_eq!(delays, [secs(1), secs(2), secs(4), secs(5), secs(5)]);
+        assert_eq!(backoff.attempts(), 5);
+    }
+
+    #[test]
+    fn a_reset_starts_over() {
+        let mut backoff = Backoff::new(secs(1), 3, secs(60));
+        backoff.next_delay();
+        backoff.next_delay();
+        backoff.reset();
+        assert_eq!(backoff.attempts(), 0);
+        assert_eq!(backoff.next_delay(), Some(secs(1)));
+    }
+
+    #[test]
+    fn a_base_over_the_cap_is_capped() {
+        let mut backoff = Backoff::new(secs(10), 2, secs(5));
+        assert_eq!(backoff.next_delay(), Some(secs(5)));
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Some(secs(5)));
+    }
+
+    #[test]
+    fn huge_delays_dont_overflow() {
+        let mut backoff = Backoff::new(Duration::MAX / 2, 4, Duration::MAX);
+        assert_eq!(backoff.next_delay(), Some(Duration::MAX / 2));
+        assert_eq!(backoff.next_delay(), Some(Duration::MAX));
+        assert_eq!(backoff.next_delay(), Some(Duration::MAX));
+    }
+
+    #[test]
+    fn attempts_run_out() {
+        let mut backoff = Backoff::new(secs(1), 2, secs(60)).with_max_attempts(2);
+        assert!(backoff.next_delay().is_some());
+        assert!(backoff.next_delay().is_some());
+        assert_eq!(backoff.next_delay(), None);
+        backoff.reset();
+        assert!(backoff.next_delay().is_some());
+    }
+
+    #[test]
+    fn jitter_is_a_random_part_of_the_delay() {
+        let mut backoff = Backoff::new(secs(1), 2, secs(60))
+            .with_jitter()
+            .with_random(|| 12_345);
+        // 12345 % 1001 and 12345 % 2001
+        assert_eq!(backoff.next_delay(), Some(Duration::from_millis(333)));
+        assert_eq!(backoff.next_delay(), Some(Duration::from_millis(339)));
+        let mut backoff = Backoff::new(Duration::ZERO, 2, secs(60)).with_jitter();
+        assert_eq!(backoff.next_delay(), Some(Duration::ZERO));
+    }
+
+    fn micros(us: u64) -> Duration {
+        Duration::from_micros(us)
+    }
+
+    #[test]
+    fn short_durations_have_small_units() {
+        assert_eq!(format_duration(Duration::ZERO), "0s");
+        assert_eq!(format_duration(micros(1)), "1us");
+        assert_eq!(format_duration(micros(999)), "999us");
+        assert_eq!(format_duration(micros(1000)), "1ms");
+        assert_eq!(format_duration(micros(120_400)), "120ms");
+        assert_eq!(format_duration(micros(999_499)), "999ms");
+        // Rounding carries into the next unit
+        assert_eq!(format_duration(micros(999_500)), "1s");
+        assert_eq!(format_duration(Duration::from_nanos(1)), "0s");
+    }
+
+    #[test]
+    fn seconds_have_tenths() {
+        assert_eq!(format_duration(secs(1)), "1s");
+        assert_eq!(format_duration(Duration::from_millis(4500)), "4.5s");
+        assert_eq!(format_duration(Duration::from_millis(4449)), "4.4s");
+        assert_eq!(format_duration(Duration::from_millis(59_940)), "59.9s");
+        assert_eq!(format_duration(Duration::from_millis(59_950)), "1m");
+    }
+
+    #[test]
+    fn long_durations_have_three_units_at_most() {
+        assert_eq!(format_duration(secs(60)), "1m");
+        assert_eq!(format_duration(secs(61)), "1m 1s");
+        assert_eq!(format_duration(secs(3599)), "59m 59s");
+        assert_eq!(format_duration(Duration::from_millis(3_599_500)), "1h");
+        assert_eq!(format_duration(secs(3600)), "1h");
+        assert_eq!(format_duration(secs(3605)), "1h 5s");
+        assert_eq!(format_duration(secs(86400)), "1d");
+        let two_days = 2 * 86400 + 3 * 3600 + 14 * 60;
+        assert_eq!(format_duration(secs(two_days)), "2d 3h 14m");
+        assert_eq!(format_duration(secs(two_days + 29)), "2d 3h 14m");
+        assert_eq!(format_duration(secs(two_days + 30)), "2d 3h 15m");
+        assert_eq!(format_duration(secs(86400 + 30)), "1d 1m");
+        assert_eq!(format_duration(secs(86400 - 30)), "23h 59m 30s");
+    }
+
+    #[test]
+    fn huge_durations_dont_overflow() {
+        assert_eq!(format_duration(secs(u64::MAX)), "213503982334601d 7h");
+        assert_eq!(format_duration(Duration::MAX), "213503982334601d 7h");
+    }
+
+    #[test]
+    fn bytes_have_binary_units() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1), "1 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1024), "1.0 KiB");
+        assert_eq!(format_bytes(1434), "1.4 KiB");
+        assert_eq!(format_bytes(1024 * 1024), "1.0 MiB");
+        assert_eq!(format_bytes(5 << 30), "5.0 GiB");
+        // Rounding up to 1024 of a unit moves to the next one
+        assert_eq!(format_bytes(1024 * 1024 - 1), "1.0 MiB");
+        assert_eq!(format_bytes(1024 * 1024 - 52), "1023.9 KiB");
+        assert_eq!(format_bytes(u64::MAX), "16.0 EiB");
+    }
+
+    #[test]
+    fn ages_are_ago_or_in() {
+        assert_eq!(format_age(100, 100), "0s ago");
+        assert_eq!(format_age(0, 3600), "1h ago");
+        assert_eq!(format_age(1000, 1000 + 3 * 3600 + 12 * 60), "3h 12m ago");
+        assert_eq!(format_age(3900, 3600), "in 5m");
+        assert!(format_age(i64::MIN, i64::MAX).ends_with("d 7h ago"));
+        assert!(format_age(i64::MAX, i64::MIN).starts_with("in "));
+    }
+}
@@ -0,0 +1,769 @@
+//! The connection to the access point, driven by the wifi and IP events of ESP-IDF. `Link` is a
+//! small state machine that is fed the events and says when to connect, so a dropped connection
+//! is picked up again right away and we know why it dropped. `watch` feeds it the events of the
+//! default event loop, `wait_up` waits for it to come up.
+//!
+//! A device can know more than one network, in order of preference, see `parse_networks`. We
+//! start with the one that last worked, which is kept in NVS, and `Failover` moves on to the next
+//! one after a number of failed attempts in a row, round robin. `switch_to` moves to another
+//! network by hand.
+
+use crate::nvs_recovery::persistence_available;
+use crate::nvs_recovery::recover_write;
+use esp_idf_sys::esp;
+use esp_idf_sys::EspError;
+use log::*;
+use std::ffi::c_void;
+use std::ffi::CString;
+use std::fmt;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// NVS namespace where we remember the network that last worked.
+pub const WIFI_NAMESPACE: &str = "wifinet";
+const LAST_NETWORK_KEY: &str = "last";
+/// Most networks a device knows.
+pub const MAX_NETWORKS: usize = 4;
+const MAX_SSID_LEN: usize = 32;
+const MAX_PASSWORD_LEN: usize = 64;
+
+/// What the wifi driver and the DHCP client tell us.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinkEvent {
+    Started,
+    Stopped,
+    /// Associated with the access point
+    Connected,
+    /// Lost the access point, or didn't get to it, with the reason code of the driver
+    Disconnected(u16),
+    GotIp,
+    LostIp,
+}
+
+/// Where the connection is at.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinkState {
+    Stopped,
+    Connecting,
+    WaitingForIp,
+    Up,
+}
+
+impl fmt::Display for LinkState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkState::Stopped => write!(f, "stopped"),
+            LinkState::Connecting => write!(f, "connecting"),
+            LinkState::WaitingForIp => write!(f, "waiting for ip"),
+            LinkState::Up => write!(f, "up"),
+        }
+    }
+}
+
+/// What to do after an event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinkAction {
+    Connect,
+}
+
+/// Why the connection dropped, from the reason code of the driver (`wifi_err_reason_t`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DisconnectReason {
+    NoApFound,
+    /// Wrong password, or the access point doesn't take us
+    AuthFailed,
+    /// The access point went quiet
+    ApLost,
+    Other,
+}
+
+impl DisconnectReason {
+    pub fn of(code: u16) -> Self {
+        match code {
+            201 => DisconnectReason::NoApFound,
+            2 | 14 | 15 | 202..=204 => DisconnectReason::AuthFailed,
+            200 => DisconnectReason::ApLost,
+            _ => DisconnectReason::Other,
+        }
+    }
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DisconnectReason::NoApFound => write!(f, "no access point found"),
+            DisconnectReason::AuthFailed => write!(f, "authentication failed"),
+            DisconnectReason::ApLost => write!(f, "access point lost"),
+            DisconnectReason::Other => write!(f, "disconnected"),
+        }
+    }
+}
+
+/// The connection to the access point. Displayed as e.g. `up disconnects=2 last_disconnect=201
+/// (no access point found)`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Link {
+    state: LinkState,
+    disconnects: u32,
+    last_disconnect: Option<u16>,
+}
+
+impl Default for Link {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Link {
+    pub const fn new() -> Self {
+        Self {
+            state: LinkState::Stopped,
+            disconnects: 0,
+            last_disconnect: None,
+        }
+    }
+
+    /// Take in `event`, and say what to do about it.
+    pub fn handle(&mut self, event: LinkEvent) -> Option<LinkAction> {
+        match event {
+            LinkEvent::Started => {
+                self.state = LinkState::Connecting;
+                Some(LinkAction::Connect)
+            }
+            LinkEvent::Stopped => {
+                self.state = LinkState::Stopped;
+                None
+            }
+            // Late events of a driver that was stopped
+            _ if self.state == LinkState::Stopped => None,
+            LinkEvent::Connected => {
+                self.state = LinkState::WaitingForIp;
+                None
+            }
+            LinkEvent::Disconnected(reason) => {
+                self.state = LinkState::Connecting;
+                self.disconnects = self.disconnects.saturating_add(1);
+                self.last_disconnect = Some(reason);
+                Some(LinkAction::Connect)
+            }
+            LinkEvent::GotIp => {
+                self.state = LinkState::Up;
+                None
+            }
+            // We're still associated, the DHCP client keeps trying
+            LinkEvent::LostIp if self.state == LinkState::Up => {
+                self.state = LinkState::WaitingForIp;
+                None
+            }
+            LinkEvent::LostIp => None,
+        }
+    }
+
+    pub fn state(&self) -> LinkState {
+        self.state
+    }
+
+    /// Number of times the connection dropped, or failed to come up.
+    pub fn disconnects(&self) -> u32 {
+        self.disconnects
+    }
+
+    /// The reason code of the driver of the last disconnect.
+    pub fn last_disconnect(&self) -> Option<u16> {
+        self.last_disconnect
+    }
+
+    /// Why the connection isn't up, None when it is.
+    pub fn failure(&self) -> Option<String> {
+        match (self.state, self.last_disconnect) {
+            (LinkState::Up, _) => None,
+            (LinkState::Stopped, _) => Some("wifi did not start".to_string()),
+            (LinkState::WaitingForIp, _) => Some("no DHCP lease".to_string()),
+            (LinkState::Connecting, Some(code)) => {
+                Some(format!("{} (reason {code})", DisconnectReason::of(code)))
+            }
+            (LinkState::Connecting, None) => Some("no answer from the access point".to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Link {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} disconnects={}", self.state, self.disconnects)?;
+        if let Some(code) = self.last_disconnect {
+            write!(
+                f,
+                " last_disconnect={code} ({})",
+                DisconnectReason::of(code)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A network we can connect to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Network {
+    pub ssid: String,
+    pub password: String,
+}
+
+impl Network {
+    pub fn new(ssid: &str, password: &str) -> Self {
+        Self {
+            ssid: ssid.to_string(),
+            password: password.to_string(),
+        }
+    }
+}
+
+/// Parse a list of networks in order of preference, e.g. `main=secret;backup=secret2`. The SSID
+/// ends at the first `=`, so it can't have one, nor a `;`. Spaces around entries are ignored,
+/// an empty list is fine.
+pub fn parse_networks(text: &str) -> Result<Vec<Network>, String> {
+    let mut networks: Vec<Network> = Vec::new();
+    for entry in text.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+        let (ssid, password) = entry
+            .split_once('=')
+            .ok_or_else(|| format!("{entry:?} is not ssid=password"))?;
+        if ssid.is_empty() || ssid.len() > MAX_SSID_LEN {
+            return Err(format!("SSID {ssid:?} is not 1 to {MAX_SSID_LEN} bytes"));
+        }
+        if password.len() > MAX_PASSWORD_LEN {
+            return Err(format!(
+                "Password of {ssid} is longer than {MAX_PASSWORD_LEN} bytes"
+            ));
+        }
+        if networks.iter().any(|n| n.ssid == ssid) {
+            return Err(format!("{ssid} is in the list twice"));
+        }
+        networks.push(Network::new(ssid, password));
+    }
+    if networks.len() > MAX_NETWORKS {
+        return Err(format!("More than {MAX_NETWORKS} networks"));
+    }
+    Ok(networks)
+}
+
+/// Which of a list of networks we use. We move on to the next one after `max_failures` failed
+/// attempts in a row, and back to the first after the last.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Failover {
+    len: usize,
+    current: usize,
+    failures: u32,
+    max_failures: u32,
+}
+
+impl Failover {
+    /// Start with network `first` of `len`, the first one when that's out of range.
+    pub fn new(len: usize, first: usize, max_failures: u32) -> Self {
+        Self {
+            len: len.max(1),
+            current: if first < len { first } else { 0 },
+            failures: 0,
+            max_failures: max_failures.max(1),
+        }
+    }
+
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    /// An attempt on the current network failed, or the connection dropped. Returns the network
+    /// to move on to, if it's time to. With a single network, that's never.
+    pub fn failed(&mut self) -> Option<usize> {
+        self.failures = self.failures.saturating_add(1);
+        if self.len == 1 || self.failures < self.max_failures {
+            return None;
+        }
+        self.failures = 0;
+        self.current = (self.current + 1) % self.len;
+        Some(self.current)
+    }
+
+    /// The connection to the current network is up.
+    pub fn connected(&mut self) {
+        self.failures = 0;
+    }
+
+    /// Use network `index` from now on. False when there's no such network.
+    pub fn force(&mut self, index: usize) -> bool {
+        if index >= self.len {
+            return false;
+        }
+        self.current = index;
+        self.failures = 0;
+        true
+    }
+}
+
+// The networks of this device and which one we use
+struct Networks {
+    networks: Vec<Network>,
+    failover: Failover,
+    // The network that last worked, as stored in NVS
+    saved: Option<usize>,
+}
+
+// The connection of this device, fed by `handle_event`
+static LINK: Mutex<Link> = Mutex::new(Link::new());
+static NETWORKS: Mutex<Option<Networks>> = Mutex::new(None);
+static LINK_CHANGED: Condvar = Condvar::new();
+
+/// Use `networks`, in order of preference, moving on to the next one after `max_failures` failed
+/// attempts in a row. We start with the one that last worked. Returns that one, so it's
+/// configured in the driver before it starts.
+pub fn set_networks(networks: Vec<Network>, max_failures: u32) -> Network {
+    let saved = read_last_network()
+        .unwrap_or_else(|e| {
+            error!("Unable to read the last wifi network: {e}");
+            None
+        })
+        .and_then(|ssid| networks.iter().position(|n| n.ssid == ssid));
+    let failover = Failover::new(networks.len(), saved.unwrap_or(0), max_failures);
+    let first = networks[failover.current()].clone();
+    *NETWORKS.lock().unwrap() = Some(Networks {
+        networks,
+        failover,
+        saved,
+    });
+    first
+}
+
+/// The networks we know, and which of them we use.
+pub fn networks() -> (Vec<Network>, usize) {
+    match &*NETWORKS.lock().unwrap() {
+        Some(n) => (n.networks.clone(), n.failover.current()),
+        None => (Vec::new(), 0),
+    }
+}
+
+/// The SSID of the network we use.
+pub fn active_ssid() -> Option<String> {
+    let networks = NETWORKS.lock().unwrap();
+    let n = networks.as_ref()?;
+    Some(n.networks[n.failover.current()].ssid.clone())
+}
+
+/// Move to network `index` right away, dropping the connection to the one we use.
+pub fn switch_to(index: usize) -> Result<(), anyhow::Error> {
+    let network = {
+        let mut networks = NETWORKS.lock().unwrap();
+        let Some(n) = networks.as_mut() else {
+            anyhow::bail!("No wifi networks");
+        };
+        if !n.failover.force(index) {
+            anyhow::bail!("There is no wifi network {index}");
+        }
+        n.networks[index].clone()
+    };
+    info!("Switching the wifi to {}", network.ssid);
+    configure(&network)?;
+    // The disconnect event connects again, to the new network
+    esp!(unsafe { esp_idf_sys::esp_wifi_disconnect() })?;
+    Ok(())
+}
+
+/// Feed the wifi and IP events of the default event loop to the connection, which connects when
+/// the driver starts and when the connection drops. Call this once, before starting the driver.
+pub fn watch() -> Result<(), EspError> {
+    unsafe {
+        esp!(esp_idf_sys::esp_event_handler_register(
+            esp_idf_sys::WIFI_EVENT,
+            esp_idf_sys::ESP_EVENT_ANY_ID,
+            Some(handle_event),
+            std::ptr::null_mut(),
+        ))?;
+        esp!(esp_idf_sys::esp_event_handler_register(
+            esp_idf_sys::IP_EVENT,
+            esp_idf_sys::ESP_EVENT_ANY_ID,
+            Some(handle_event),
+            std::ptr::null_mut(),
+        ))
+    }
+}
+
+/// The connection as it is now.
+pub fn link() -> Link {
+    *LINK.lock().unwrap()
+}
+
+/// Wait for the connection to come up, for at most `timeout`. Fails with the reason it didn't.
+pub fn wait_up(timeout: Duration) -> Result<(), String> {
+    let link = LINK.lock().unwrap();
+    let (link, _) = LINK_CHANGED
+        .wait_timeout_while(link, timeout, |l| l.state != LinkState::Up)
+        .unwrap();
+    match link.failure() {
+        Some(failure) => Err(failure),
+        None => Ok(()),
+    }
+}
+
+unsafe extern "C" fn handle_event(
+    _arg: *mut c_void,
+    base: esp_idf_sys::esp_event_base_t,
+    id: i32,
+    data: *mut c_void,
+) {
+    let id = id as u32;
+    let event = if base == esp_idf_sys::WIFI_EVENT {
+        match id {
+            esp_idf_sys::wifi_event_t_WIFI_EVENT_STA_START => LinkEvent::Started,
+            esp_idf_sys::wifi_event_t_WIFI_EVENT_STA_STOP => LinkEvent::Stopped,
+            esp_idf_sys::wifi_event_t_WIFI_EVENT_STA_CONNECTED => LinkEvent::Connected,
+            esp_idf_sys::wifi_event_t_WIFI_EVENT_STA_DISCONNECTED => {
+                let event = &*(data as *const esp_idf_sys::wifi_event_sta_disconnected_t);
+                LinkEvent::Disconnected(event.reason as u16)
+            }
+            _ => return,
+        }
+    } else if base == esp_idf_sys::IP_EVENT {
+        match id {
+            esp_idf_sys::ip_event_t_IP_EVENT_STA_GOT_IP => LinkEvent::GotIp,
+            esp_idf_sys::ip_event_t_IP_EVENT_STA_LOST_IP => LinkEvent::LostIp,
+            _ => return,
+        }
+    } else {
+        return;
+    };
+
+    let (action, link) = {
+        let mut link = LINK.lock().unwrap();
+        (link.handle(event), *link)
+    };
+    LINK_CHANGED.notify_all();
+    match event {
+        LinkEvent::Disconnected(_) => warn!("Wifi {link}"),
+        _ => info!("Wifi {link}"),
+    }
+    match event {
+        LinkEvent::Disconnected(_) => fail_over(),
+        LinkEvent::GotIp => network_up(),
+        _ => {}
+    }
+    if action == Some(LinkAction::Connect) {
+        if let Err(e) = esp!(esp_idf_sys::esp_wifi_connect()) {
+            error!("Unable to connect the wifi: {e}");
+        }
+    }
+}
+
+/// Count a failed attempt on the network we use, and move on to the next one when it's time to.
+fn fail_over() {
+    let network = {
+        let mut networks = NETWORKS.lock().unwrap();
+        let Some(n) = networks.as_mut() else {
+            return;
+        };
+        let Some(next) = n.failover.failed() else {
+            return;
+        };
+        n.networks[next].clone()
+    };
+    warn!("Wifi failing over to {}", network.ssid);
+    if let Err(e) = configure(&network) {
+        error!("Unable to configure the wifi for {}: {e}", network.ssid);
+    }
+}
+
+/// The network we use works, remember it for the next boot.
+fn network_up() {
+    let ssid = {
+        let mut networks = NETWORKS.lock().unwrap();
+        let Some(n) = networks.as_mut() else {
+            return;
+        };
+        n.failover.connected();
+        let current = n.failover.current();
+        // With a single network there's nothing to choose from
+        if n.networks.len() == 1 || n.saved == Some(current) {
+            return;
+        }
+        n.saved = Some(current);
+        n.networks[current].ssid.clone()
+    };
+    info!("Wifi up on {ssid}");
+    if let Err(e) = write_last_network(&ssid) {
+        error!("Unable to save the last wifi network: {e}");
+    }
+}
+
+/// Set the SSID and password of the station in the driver, for the next connect.
+fn configure(network: &Network) -> Result<(), EspError> {
+    unsafe {
+        let mut config: esp_idf_sys::wifi_config_t = std::mem::zeroed();
+        esp!(esp_idf_sys::esp_wifi_get_config(
+            esp_idf_sys::wifi_interface_t_WIFI_IF_STA,
+            &mut config
+        ))?;
+        config.sta.ssid = [0; MAX_SSID_LEN];
+        config.sta.ssid[..network.ssid.len()].copy_from_slice(network.ssid.as_bytes());
+        config.sta.password = [0; MAX_PASSWORD_LEN];
+        config.sta.password[..network.password.len()].copy_from_slice(network.password.as_bytes());
+        esp!(esp_idf_sys::esp_wifi_set_config(
+            esp_idf_sys::wifi_interface_t_WIFI_IF_STA,
+            &mut config
+        ))
+    }
+}
+
+fn open(mode: esp_idf_sys::nvs_open_mode_t) -> Result<esp_idf_sys::nvs_handle_t, EspError> {
+    let namespace = CString::new(WIFI_NAMESPACE).unwrap();
+    let mut handle: esp_idf_sys::nvs_handle_t = 0;
+    esp!(unsafe { esp_idf_sys::nvs_open(namespace.as_ptr(), mode, &mut handle) })?;
+    Ok(handle)
+}
+
+fn read_last_network() -> Result<Option<String>, EspError> {
+    if !persistence_available() {
+        return Ok(None);
+    }
+    let handle = match open(esp_idf_sys::nvs_open_mode_t_NVS_READONLY) {
+        Ok(handle) => handle,
+        Err(e) if e.code() == esp_idf_sys::ESP_ERR_NVS_NOT_FOUND as i32 => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let key = CString::new(LAST_NETWORK_KEY).unwrap();
+    let mut ssid = [0u8; MAX_SSID_LEN + 1];
+    let mut len = ssid.len();
+    let result = esp!(unsafe {
+        esp_idf_sys::nvs_get_str(handle, key.as_ptr(), ssid.as_mut_ptr() as *mut _, &mut len)
+    });
+    unsafe { esp_idf_sys::nvs_close(handle) };
+    match result {
+        // Without the NUL
+        Ok(()) => Ok(Some(
+            String::from_utf8_lossy(&ssid[..len.saturating_sub(1)]).into_owned(),
+        )),
+        Err(e) if e.code() == esp_idf_sys::ESP_ERR_NVS_NOT_FOUND as i32 => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn write_last_network(ssid: &str) -> Result<(), EspError> {
+    if !persistence_available() {
+        return Ok(());
+    }
+    let key = CString::new(LAST_NETWORK_KEY).unwrap();
+    // SSIDs come from our settings, which are NUL terminated in NVS as well
+    let ssid = CString::new(ssid).unwrap();
+    let write = || {
+        let handle = open(esp_idf_sys::nvs_open_mode_t_NVS_READWRITE)?;
+        let result = esp!(unsafe { esp_idf_sys::nvs_set_str(handle, key.as_ptr(), ssid.as_ptr()) })
+            .and_then(|_| esp!(unsafe { esp_idf_sys::nvs_commit(handle) }));
+        unsafe { esp_idf_sys::nvs_close(handle) };
+        result
+    };
+    write().or_else(|e| recover_write(e, write))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Reason codes of `wifi_err_reason_t`
+    const AUTH_FAIL: u16 = 202;
+    const NO_AP_FOUND: u16 = 201;
+    const BEACON_TIMEOUT: u16 = 200;
+
+    /// Feed `events` to `link`, and return the actions they asked for.
+    fn feed(link: &mut Link, events: &[LinkEvent]) -> Vec<Option<LinkAction>> {
+        events.iter().map(|&event| link.handle(event)).collect()
+    }
+
+    #[test]
+    fn a_connection_comes_up() {
+        let mut link = Link::new();
+        assert_eq!(link.failure(), Some("wifi did not start".to_string()));
+        let actions = feed(
+            &mut link,
+            &[LinkEvent::Started, LinkEvent::Connected, LinkEvent::GotIp],
+        );
+        assert_eq!(actions, [Some(LinkAction::Connect), None, None]);
+        assert_eq!(link.state(), LinkState::Up);
+        assert_eq!(link.failure(), None);
+        assert_eq!(link.to_string(), "up disconnects=0");
+    }
+
+    #[test]
+    fn a_dropped_connection_connects_again_right_away() {
+        let mut link = Link::new();
+        feed(
+            &mut link,
+            &[LinkEvent::Started, LinkEvent::Connected, LinkEvent::GotIp],
+        );
+        assert_eq!(
+            link.handle(LinkEvent::Disconnected(BEACON_TIMEOUT)),
+            Some(LinkAction::Connect)
+        );
+        assert_eq!(link.state(), LinkState::Connecting);
+        assert_eq!(
+            link.failure(),
+            Some("access point lost (reason 200)".to_string())
+        );
+        feed(&mut link, &[LinkEvent::Connected, LinkEvent::GotIp]);
+        assert_eq!(
+            link.to_string(),
+            "up disconnects=1 last_disconnect=200 (access point lost)"
+        );
+        assert_eq!(link.last_disconnect(), Some(BEACON_TIMEOUT));
+    }
+
+    #[test]
+    fn failures_to_connect_say_why() {
+        let mut link = Link::new();
+        link.handle(LinkEvent::Started);
+        assert_eq!(
+            link.failure(),
+            Some("no answer from the access point".to_string())
+        );
+        link.handle(LinkEvent::Disconnected(NO_AP_FOUND));
+        assert_eq!(
+            link.failure(),
+            Some("no access point found (reason 201)".to_string())
+        );
+        link.handle(LinkEvent::Disconnected(AUTH_FAIL));
+        assert_eq!(
+            link.failure(),
+            Some("authentication failed (reason 202)".to_string())
+        );
+        assert_eq!(link.disconnects(), 2);
+        link.handle(LinkEvent::Connected);
+        assert_eq!(link.failure(), Some("no DHCP lease".to_string()));
+    }
+
+    #[test]
+    fn a_lost_lease_waits_for_a_new_one() {
+        let mut link = Link::new();
+        feed(
+            &mut link,
+            &[LinkEvent::Started, LinkEvent::Connected, LinkEvent::GotIp],
+        );
+        assert_eq!(link.handle(LinkEvent::LostIp), None);
+        assert_eq!(link.state(), LinkState::WaitingForIp);
+        assert_eq!(link.handle(LinkEvent::LostIp), None);
+        assert_eq!(link.state(), LinkState::WaitingForIp);
+        link.handle(LinkEvent::GotIp);
+        assert_eq!(link.state(), LinkState::Up);
+        assert_eq!(link.disconnects(), 0);
+    }
+
+    #[test]
+    fn events_after_a_stop_are_ignored() {
+        let mut link = Link::new();
+        feed(&mut link, &[LinkEvent::Started, LinkEvent::Stopped]);
+        let actions = feed(
+            &mut link,
+            &[
+                LinkEvent::Disconnected(AUTH_FAIL),
+                LinkEvent::Connected,
+                LinkEvent::GotIp,
+            ],
+        );
+        assert_eq!(actions, [None, None, None]);
+        assert_eq!(link.state(), LinkState::Stopped);
+        assert_eq!(link.disconnects(), 0);
+        assert_eq!(link.handle(LinkEvent::Started), Some(LinkAction::Connect));
+    }
+
+    #[test]
+    fn reason_codes_are_grouped() {
+        assert_eq!(DisconnectReason::of(201), DisconnectReason::NoApFound);
+        for code in [2, 14, 15, 202, 203, 204] {
+            assert_eq!(DisconnectReason::of(code), DisconnectReason::AuthFailed);
+        }
+        assert_eq!(DisconnectReason::of(200), DisconnectReason::ApLost);
+        assert_eq!(DisconnectReason::of(8), DisconnectReason::Other);
+    }
+
+    #[test]
+    fn networks_are_parsed_in_order() {
+        let networks = parse_networks(" main=secret ; backup=a=b;;open=; ").unwrap();
+        assert_eq!(
+            networks,
+            [
+                Network::new("main", "secret"),
+                Network::new("backup", "a=b"),
+                Network::new("open", ""),
+            ]
+        );
+        assert_eq!(parse_networks(""), Ok(vec![]));
+        assert_eq!(parse_networks(" ; "), Ok(vec![]));
+    }
+
+    #[test]
+    fn bad_network_lists_are_refused() {
+        let long_ssid = "s".repeat(MAX_SSID_LEN + 1);
+        let long_password = "p".repeat(MAX_PASSWORD_LEN + 1);
+        for text in [
+            "main".to_string(),
+            "=secret".to_string(),
+            format!("{long_ssid}=secret"),
+            format!("main={long_password}"),
+            "main=secret;main=other".to_string(),
+            "a=1;b=2;c=3;d=4;e=5".to_string(),
+        ] {
+            assert!(parse_networks(&text).is_err(), "{text}");
+        }
+    }
+
+    #[test]
+    fn the_longest_names_and_passwords_fit() {
+        let ssid = "s".repeat(MAX_SSID_LEN);
+        let password = "p".repeat(MAX_PASSWORD_LEN);
+        let networks = parse_networks(&format!("{ssid}={password};a=1;b=2;c=3")).unwrap();
+        assert_eq!(networks.len(), MAX_NETWORKS);
+        assert_eq!(networks[0], Network::new(&ssid, &password));
+    }
+
+    #[test]
+    fn a_single_network_is_never_left() {
+        let mut failover = Failover::new(1, 0, 2);
+        for _ in 0..5 {
+            assert_eq!(failover.failed(), None);
+        }
+        assert_eq!(failover.current(), 0);
+    }
+
+    #[test]
+    fn networks_are_tried_round_robin() {
+        let mut failover = Failover::new(3, 1, 2);
+        assert_eq!(failover.current(), 1);
+        let moves: Vec<_> = (0..6).map(|_| failover.failed()).collect();
+        assert_eq!(moves, [None, Some(2), None, Some(0), None, Some(1)]);
+    }
+
+    #[test]
+    fn a_connection_resets_the_failures() {
+        let mut failover = Failover::new(2, 0, 2);
+        assert_eq!(failover.failed(), None);
+        failover.connected();
+        assert_eq!(failover.failed(), None);
+        assert_eq!(failover.failed(), Some(1));
+    }
+
+    #[test]
+    fn a_network_can_be_forced() {
+        let mut failover = Failover::new(3, 0, 2);
+        assert_eq!(failover.failed(), None);
+        assert!(failover.force(2));
+        assert_eq!(failover.current(), 2);
+        // The failure on the old network doesn't count against the new one
+        assert_eq!(failover.failed(), None);
+        assert!(!failover.force(3));
+        assert_eq!(failover.current(), 2);
+    }
+
+    #[test]
+    fn odd_failover_settings_are_made_sane() {
+        assert_eq!(Failover::new(2, 5, 3).current(), 0);
+        let mut failover = Failover::new(2, 0, 0);
+        assert_eq!(failover.failed(), Some(1));
+        let mut empty = Failover::new(0, 0, 1);
+        assert_eq!(empty.failed(), None);
+        assert_eq!(empty.current(), 0);
+    }
+}
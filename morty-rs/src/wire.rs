@@ -0,0 +1,1352 @@
+//! The core of the protocol: how frames are laid out and checked, how messages are wrapped for
+//! relaying, which relays are passed on and which fixes we've seen. This doesn't need std or
+//! ESP-IDF, so a receiver on another chip, e.g. an RP2040 with a LoRa radio, can share it.
+//! `comm`, `relay` and `dedup` are the wrappers the ESP firmware uses.
+//!
+//! Nothing here allocates: frames are encoded into a slice of the caller and the collections are
+//! `heapless`. The tests count the allocations to make sure. The rest of the crate needs the `std`
+//! feature, which is on by default. Without it only this module is built, so it should stay
+//! no_std, which `cargo +stable check-no-std` and `cargo +stable check-no-std-alloc` check, see
+//! the aliases in `.cargo/config.toml`. The `alloc` feature adds helpers that return a Vec.
+//! `cargo +stable test-wire` runs the tests on the host.
+//!
+//! Messages are protobuf, but only the few fields we need to find are read or written here, the
+//! rest is passed through as it is.
+//!
+//! The checksum of the frames we send is picked at build time with CHECKSUM. It's a `CrcVariant`
+//! rather than a trait the frame functions are generic over: a receiver has to check frames of
+//! either variant, whichever the header says, so the variants are a closed set that's chosen per
+//! frame at runtime. An enum does that without dyn or a type parameter on every function.
+
+use core::fmt;
+use core::time::Duration;
+use heapless::Deque;
+
+// Message types, the field numbers of the messages in MortyMessage
+pub const BEACON_PRESENT_TYPE: u8 = 1;
+pub const GPS_TYPE: u8 = 2;
+pub const RELAY_TYPE: u8 = 3;
+pub const COMMAND_TYPE: u8 = 4;
+pub const COMMAND_ACK_TYPE: u8 = 5;
+pub const CHUNK_TYPE: u8 = 6;
+pub const TRANSFER_ACK_TYPE: u8 = 7;
+pub const POWER_EVENT_TYPE: u8 = 8;
+pub const PING_TYPE: u8 = 9;
+pub const PONG_TYPE: u8 = 10;
+pub const HELLO_TYPE: u8 = 11;
+pub const TIME_BEACON_TYPE: u8 = 12;
+pub const TRACE_TYPE: u8 = 13;
+
+// Field numbers of RelayMsg
+const RELAY_SRC_TAG: u32 = 1;
+const RELAY_TIMESTAMP_TAG: u32 = 2;
+const RELAY_PATH_DELAY_TAG: u32 = 8;
+const RELAY_GATEWAY_HINT_TAG: u32 = 12;
+
+// Protobuf wire types
+const VARINT: u8 = 0;
+const FIXED64: u8 = 1;
+const LENGTH_DELIMITED: u8 = 2;
+const FIXED32: u8 = 5;
+
+/// A message type, with its name for logs and stats.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MsgType {
+    pub id: u8,
+    pub name: &'static str,
+    /// The field number of the message in the oneof of RelayMsg, None when it can't be relayed
+    pub relay_tag: Option<u32>,
+}
+
+const fn msg_type_entry(id: u8, name: &'static str, relay_tag: Option<u32>) -> MsgType {
+    MsgType {
+        id,
+        name,
+        relay_tag,
+    }
+}
+
+/// Every message type, in the order of their ids.
+pub const MSG_TYPES: [MsgType; 13] = [
+    msg_type_entry(BEACON_PRESENT_TYPE, "beacon_present", Some(10)),
+    msg_type_entry(GPS_TYPE, "gps", Some(3)),
+    msg_type_entry(RELAY_TYPE, "relay", None),
+    msg_type_entry(COMMAND_TYPE, "command", None),
+    msg_type_entry(COMMAND_ACK_TYPE, "command_ack", Some(5)),
+    msg_type_entry(CHUNK_TYPE, "chunk", None),
+    msg_type_entry(TRANSFER_ACK_TYPE, "transfer_ack", Some(6)),
+    msg_type_entry(POWER_EVENT_TYPE, "power_event", Some(7)),
+    msg_type_entry(PING_TYPE, "ping", None),
+    msg_type_entry(PONG_TYPE, "pong", None),
+    msg_type_entry(HELLO_TYPE, "hello", Some(9)),
+    msg_type_entry(TIME_BEACON_TYPE, "time_beacon", None),
+    msg_type_entry(TRACE_TYPE, "trace", Some(11)),
+];
+
+impl MsgType {
+    /// The message type with `id`, None for one we don't know.
+    pub fn of(id: u8) -> Option<&'static MsgType> {
+        MSG_TYPES.iter().find(|t| t.id == id)
+    }
+}
+
+// Frames with a header start with this magic in the high nibble of the first byte and the header
+// version in the low one. Legacy frames start with the message type, which never has the magic.
+pub const FRAME_MAGIC: u8 = 0xa;
+pub const FRAME_VERSION: u8 = 1;
+pub const FRAME_HEADER_LEN: usize = 4;
+// Highest message type a legacy frame can start with, so every one of MSG_TYPES
+const LEGACY_MAX_TYPE: u8 = max_msg_type();
+// A legacy frame must never look like it has a header
+const _: () = assert!(LEGACY_MAX_TYPE >> 4 != FRAME_MAGIC);
+
+const fn max_msg_type() -> u8 {
+    let mut max = 0;
+    let mut i = 0;
+    while i < MSG_TYPES.len() {
+        if MSG_TYPES[i].id > max {
+            max = MSG_TYPES[i].id;
+        }
+        i += 1;
+    }
+    max
+}
+
+const FLAG_ENCRYPTED: u8 = 0x01;
+const FLAG_FRAGMENTED: u8 = 0x02;
+const FLAG_HMAC: u8 = 0x04;
+
+// The checksum of the frames we send with a header, for the whole fleet. The header says which one
+// a frame has, so receivers take both. Firmware from before CRC16 only knows CRC8, switch once
+// every device understands CRC16. Legacy frames can't say, they always have a CRC8.
+pub const CHECKSUM: CrcVariant = CrcVariant::Crc8;
+
+/// The layout of the frames we send. Receivers understand both, so the fleet can be switched
+/// over to `Header` once every device runs firmware that knows about it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameFormat {
+    /// Message type, CRC8 and the message
+    Legacy,
+    /// `FrameHeader`, the CHECKSUM and the message
+    Header,
+}
+
+impl FrameFormat {
+    /// The checksum of the frames we send in this format.
+    pub fn crc(&self) -> CrcVariant {
+        match self {
+            FrameFormat::Legacy => CrcVariant::Crc8,
+            FrameFormat::Header => CHECKSUM,
+        }
+    }
+
+    /// Number of bytes before the message.
+    pub fn overhead(&self) -> usize {
+        match self {
+            FrameFormat::Legacy => 1 + self.crc().size(),
+            FrameFormat::Header => FRAME_HEADER_LEN + self.crc().size(),
+        }
+    }
+}
+
+/// What a frame carries besides the message.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FrameFlags {
+    pub encrypted: bool,
+    pub fragmented: bool,
+    pub hmac: bool,
+}
+
+impl FrameFlags {
+    fn to_byte(self) -> u8 {
+        let mut byte = 0;
+        if self.encrypted {
+            byte |= FLAG_ENCRYPTED;
+        }
+        if self.fragmented {
+            byte |= FLAG_FRAGMENTED;
+        }
+        if self.hmac {
+            byte |= FLAG_HMAC;
+        }
+        byte
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        if byte & !(FLAG_ENCRYPTED | FLAG_FRAGMENTED | FLAG_HMAC) != 0 {
+            return None;
+        }
+        Some(Self {
+            encrypted: byte & FLAG_ENCRYPTED != 0,
+            fragmented: byte & FLAG_FRAGMENTED != 0,
+            hmac: byte & FLAG_HMAC != 0,
+        })
+    }
+}
+
+/// The checksum that follows the header. Frames with a header are sent with CHECKSUM, the header
+/// says which one a frame has.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CrcVariant {
+    Crc8,
+    Crc16,
+}
+
+impl CrcVariant {
+    fn to_byte(self) -> u8 {
+        match self {
+            CrcVariant::Crc8 => 0,
+            CrcVariant::Crc16 => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(CrcVariant::Crc8),
+            1 => Some(CrcVariant::Crc16),
+            _ => None,
+        }
+    }
+
+    /// Number of bytes the checksum takes.
+    pub fn size(&self) -> usize {
+        match self {
+            CrcVariant::Crc8 => 1,
+            CrcVariant::Crc16 => 2,
+        }
+    }
+
+    /// The checksum of `bytes`.
+    pub fn checksum(&self, bytes: &[u8]) -> u16 {
+        match self {
+            CrcVariant::Crc8 => crc8(bytes) as u16,
+            CrcVariant::Crc16 => crc16(bytes),
+        }
+    }
+
+    /// Write `crc` to `out`, which is `size` bytes, most significant byte first.
+    fn write(&self, crc: u16, out: &mut [u8]) {
+        match self {
+            CrcVariant::Crc8 => out[0] = crc as u8,
+            CrcVariant::Crc16 => out.copy_from_slice(&crc.to_be_bytes()),
+        }
+    }
+
+    /// The checksum in `bytes`, which are `size` bytes.
+    fn read(&self, bytes: &[u8]) -> u16 {
+        match self {
+            CrcVariant::Crc8 => bytes[0] as u16,
+            CrcVariant::Crc16 => u16::from_be_bytes([bytes[0], bytes[1]]),
+        }
+    }
+}
+
+/// CRC-8 with polynomial 0x07, most significant bit first, as frames carry it.
+pub fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |crc, &b| {
+        (0..8).fold(crc ^ b, |crc, _| {
+            if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// CRC-16/CCITT-FALSE: polynomial 0x1021, starting at 0xffff, most significant bit first. A
+/// CRC8 misses one in 256 corrupted frames of the length of a relayed fix, this one in 65536.
+pub fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0xffff, |crc, &b| {
+        (0..8).fold(crc ^ ((b as u16) << 8), |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// Header of a frame: magic and version, flags, message type and CRC variant, a byte each.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub version: u8,
+    pub flags: FrameFlags,
+    pub msg_type: u8,
+    pub crc: CrcVariant,
+}
+
+impl FrameHeader {
+    pub fn new(msg_type: u8) -> Self {
+        Self {
+            version: FRAME_VERSION,
+            flags: FrameFlags::default(),
+            msg_type,
+            crc: FrameFormat::Header.crc(),
+        }
+    }
+
+    pub fn encode(&self) -> [u8; FRAME_HEADER_LEN] {
+        [
+            (FRAME_MAGIC << 4) | (self.version & 0x0f),
+            self.flags.to_byte(),
+            self.msg_type,
+            self.crc.to_byte(),
+        ]
+    }
+
+    /// Parse the header at the start of `data`. Returns None for a legacy frame, which starts
+    /// with a known message type instead of a header.
+    pub fn parse(data: &[u8]) -> Result<Option<Self>, WireError> {
+        use DecodeFailure::*;
+        let Some(&first) = data.first() else {
+            return Err(WireError::Decode(Truncated, "Empty frame"));
+        };
+        if first >> 4 != FRAME_MAGIC {
+            if first <= LEGACY_MAX_TYPE {
+                return Ok(None);
+            }
+            return Err(WireError::Decode(VersionUnknown, "Unknown frame"));
+        }
+        let version = first & 0x0f;
+        if version == 0 || version > FRAME_VERSION {
+            return Err(WireError::UnsupportedVersion(version));
+        }
+        if data.len() < FRAME_HEADER_LEN {
+            return Err(WireError::Decode(Truncated, "Frame too short for a header"));
+        }
+        let flags = FrameFlags::from_byte(data[1])
+            .ok_or(WireError::Decode(VersionUnknown, "Unknown frame flags"))?;
+        let crc = CrcVariant::from_byte(data[3])
+            .ok_or(WireError::Decode(VersionUnknown, "Unknown CRC variant"))?;
+        Ok(Some(Self {
+            version,
+            flags,
+            msg_type: data[2],
+            crc,
+        }))
+    }
+}
+
+/// Why a frame didn't decode. Every receiver sorts its failures by this, so the counts of
+/// different devices can be compared.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeFailure {
+    /// The frame ends before its header or checksum does
+    Truncated,
+    CrcMismatch,
+    /// The message doesn't have the type the frame says it has
+    TypeMismatch,
+    ProtobufError,
+    /// Frames with a HMAC, which we can't check yet
+    HmacFail,
+    /// Encrypted frames, which we can't decrypt yet
+    DecryptFail,
+    /// A frame version, flag or CRC variant we don't know
+    VersionUnknown,
+}
+
+impl DecodeFailure {
+    pub const ALL: [DecodeFailure; 7] = [
+        DecodeFailure::Truncated,
+        DecodeFailure::CrcMismatch,
+        DecodeFailure::TypeMismatch,
+        DecodeFailure::ProtobufError,
+        DecodeFailure::HmacFail,
+        DecodeFailure::DecryptFail,
+        DecodeFailure::VersionUnknown,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DecodeFailure::Truncated => "truncated",
+            DecodeFailure::CrcMismatch => "crc_mismatch",
+            DecodeFailure::TypeMismatch => "type_mismatch",
+            DecodeFailure::ProtobufError => "protobuf_error",
+            DecodeFailure::HmacFail => "hmac_fail",
+            DecodeFailure::DecryptFail => "decrypt_fail",
+            DecodeFailure::VersionUnknown => "version_unknown",
+        }
+    }
+}
+
+impl fmt::Display for DecodeFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Why a frame couldn't be decoded or encoded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WireError {
+    /// A frame that doesn't check out, with what's wrong with it
+    Decode(DecodeFailure, &'static str),
+    /// The frame has a header of a version we don't know, e.g. from newer firmware
+    UnsupportedVersion(u8),
+    /// The checksum in the frame isn't the one of its message
+    Crc { frame: u16, message: u16 },
+    /// Messages of this type can't be relayed
+    NotRelayable(u8),
+    /// The frame doesn't fit in the buffer
+    BufferTooSmall,
+}
+
+impl WireError {
+    /// Why the frame didn't decode, None for errors encoding one.
+    pub fn cause(&self) -> Option<DecodeFailure> {
+        match self {
+            WireError::Decode(cause, _) => Some(*cause),
+            WireError::UnsupportedVersion(_) => Some(DecodeFailure::VersionUnknown),
+            WireError::Crc { .. } => Some(DecodeFailure::CrcMismatch),
+            WireError::NotRelayable(_) | WireError::BufferTooSmall => None,
+        }
+    }
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WireError::Decode(_, detail) => f.write_str(detail),
+            WireError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported frame version {version}")
+            }
+            WireError::Crc { frame, message } => write!(f, "Invalid CRC: {frame} != {message}"),
+            WireError::NotRelayable(msg_type) => {
+                write!(f, "Messages of type {msg_type} can't be relayed")
+            }
+            WireError::BufferTooSmall => f.write_str("Frame doesn't fit in the buffer"),
+        }
+    }
+}
+
+/// The message type and the encoded MortyMessage of a frame, once its header and CRC check out.
+pub fn split_frame(data: &[u8]) -> Result<(u8, &[u8]), WireError> {
+    use DecodeFailure::*;
+    let (msg_type, crc, rest) = match FrameHeader::parse(data)? {
+        Some(header) => {
+            // Nothing sends these yet
+            if header.flags.encrypted {
+                return Err(WireError::Decode(DecryptFail, "Encrypted frame"));
+            }
+            if header.flags.hmac {
+                return Err(WireError::Decode(HmacFail, "Frame with a HMAC"));
+            }
+            if header.flags != FrameFlags::default() {
+                return Err(WireError::Decode(VersionUnknown, "Unsupported frame flags"));
+            }
+            (header.msg_type, header.crc, &data[FRAME_HEADER_LEN..])
+        }
+        None => match data {
+            [msg_type, rest @ ..] => (*msg_type, CrcVariant::Crc8, rest),
+            _ => return Err(WireError::Decode(Truncated, "Frame without CRC")),
+        },
+    };
+    if rest.len() < crc.size() {
+        return Err(WireError::Decode(Truncated, "Frame without CRC"));
+    }
+    let (frame_crc, msg_data) = rest.split_at(crc.size());
+
+    let frame_crc = crc.read(frame_crc);
+    let message = crc.checksum(msg_data);
+    if frame_crc != message {
+        return Err(WireError::Crc {
+            frame: frame_crc,
+            message,
+        });
+    }
+    Ok((msg_type, msg_data))
+}
+
+/// The message type of a frame, from its header or the first byte of a legacy frame. The CRC
+/// isn't checked and the message isn't decoded, so this is cheap enough for a receive callback.
+/// Returns None for frames that aren't ours.
+pub fn peek_type(data: &[u8]) -> Option<u8> {
+    match FrameHeader::parse(data) {
+        Ok(Some(header)) => Some(header.msg_type),
+        Ok(None) => data.first().copied(),
+        Err(_) => None,
+    }
+}
+
+/// Write a frame of `msg_type` in `format` to `out`, with `message`, an encoded MortyMessage.
+/// Returns the length of the frame.
+pub fn encode_frame_into(
+    format: FrameFormat,
+    msg_type: u8,
+    message: &[u8],
+    out: &mut [u8],
+) -> Result<usize, WireError> {
+    write_frame(format, msg_type, message.len(), out, |w| w.bytes(message))
+}
+
+/// Like `encode_frame_into`, for a message of `message_len` bytes that `encode` writes to the
+/// slice it's given, e.g. with prost. The message doesn't have to be encoded somewhere else first.
+pub fn encode_frame_with(
+    format: FrameFormat,
+    msg_type: u8,
+    message_len: usize,
+    out: &mut [u8],
+    encode: impl FnOnce(&mut [u8]),
+) -> Result<usize, WireError> {
+    write_frame(format, msg_type, message_len, out, |w| {
+        w.slice(message_len, encode)
+    })
+}
+
+/// `encode_frame_into` into a Vec.
+#[cfg(feature = "alloc")]
+pub fn encode_frame(format: FrameFormat, msg_type: u8, message: &[u8]) -> alloc::vec::Vec<u8> {
+    let mut frame = alloc::vec![0; format.overhead() + message.len()];
+    // The Vec is as long as the frame
+    let len = encode_frame_into(format, msg_type, message, &mut frame).unwrap_or(0);
+    frame.truncate(len);
+    frame
+}
+
+/// A received `frame` in `format`, with the message bytes as they were. Returns the length of the
+/// frame written to `out`.
+pub fn reframe_into(frame: &[u8], format: FrameFormat, out: &mut [u8]) -> Result<usize, WireError> {
+    let (msg_type, message) = split_frame(frame)?;
+    encode_frame_into(format, msg_type, message, out)
+}
+
+/// Wrap the message of a received `frame` in a RelayMsg from `src`, without decoding it, and
+/// write the frame of the relay to `out`. This gives the same bytes as decoding the message and
+/// encoding the RelayMsg with prost would. Only the types with a `relay_tag` can be relayed.
+/// `gateway_hint` is only for what goes to the UART, empty leaves it out. Returns the length of
+/// the frame.
+pub fn encode_relay_into(
+    src: &str,
+    timestamp: i64,
+    path_delay_ms: u32,
+    gateway_hint: &str,
+    frame: &[u8],
+    format: FrameFormat,
+    out: &mut [u8],
+) -> Result<usize, WireError> {
+    let (msg_type, mut bytes) = split_frame(frame)?;
+    let (tag, wire_type) = read_key(&mut bytes)?;
+    let len = read_varint(&mut bytes)? as usize;
+    if tag != msg_type as u32 || wire_type != LENGTH_DELIMITED || len != bytes.len() {
+        return Err(WireError::Decode(
+            DecodeFailure::TypeMismatch,
+            "Frame doesn't hold just the message of its type",
+        ));
+    }
+    let relay_tag = MsgType::of(msg_type)
+        .and_then(|t| t.relay_tag)
+        .ok_or(WireError::NotRelayable(msg_type))?;
+
+    // The fields in the order prost encodes them, by field number with the oneof at its lowest
+    // one, leaving out the defaults like it does
+    let src = src.as_bytes();
+    let timestamp = timestamp as u64;
+    let path_delay = path_delay_ms as u64;
+    let hint = gateway_hint.as_bytes();
+    let relay_len = optional_field_len(RELAY_SRC_TAG, src)
+        + optional_varint_len(RELAY_TIMESTAMP_TAG, timestamp)
+        + field_len(relay_tag, bytes.len())
+        + optional_varint_len(RELAY_PATH_DELAY_TAG, path_delay)
+        + optional_field_len(RELAY_GATEWAY_HINT_TAG, hint);
+    write_relay_frame(format, relay_len, out, |w| {
+        w.optional_field(RELAY_SRC_TAG, src)?;
+        w.optional_varint(RELAY_TIMESTAMP_TAG, timestamp)?;
+        w.field(relay_tag, bytes)?;
+        w.optional_varint(RELAY_PATH_DELAY_TAG, path_delay)?;
+        w.optional_field(RELAY_GATEWAY_HINT_TAG, hint)
+    })
+}
+
+/// A received relay `frame` with `path_delay_ms` as its path delay and `gateway_hint` as the
+/// gateway, empty for none, written to `out` in `format`. The other fields, including the relayed
+/// message, keep their bytes. Returns the length of the frame.
+pub fn reframe_relay_into(
+    frame: &[u8],
+    path_delay_ms: u32,
+    gateway_hint: &str,
+    format: FrameFormat,
+    out: &mut [u8],
+) -> Result<usize, WireError> {
+    let (msg_type, mut bytes) = split_frame(frame)?;
+    let (tag, wire_type) = read_key(&mut bytes)?;
+    let len = read_varint(&mut bytes)? as usize;
+    if msg_type != RELAY_TYPE
+        || tag != RELAY_TYPE as u32
+        || wire_type != LENGTH_DELIMITED
+        || len != bytes.len()
+    {
+        return Err(WireError::Decode(
+            DecodeFailure::TypeMismatch,
+            "Frame doesn't hold just a relay",
+        ));
+    }
+
+    // Every field but the path delay and the hint as it was, then those two, which have the highest
+    // field numbers. The oneof goes where its lowest field number is, so this is how prost has it.
+    let relay = bytes;
+    let path_delay = path_delay_ms as u64;
+    let hint = gateway_hint.as_bytes();
+    let mut relay_len = optional_varint_len(RELAY_PATH_DELAY_TAG, path_delay)
+        + optional_field_len(RELAY_GATEWAY_HINT_TAG, hint);
+    for_each_field(relay, |tag, field| {
+        if tag != RELAY_PATH_DELAY_TAG && tag != RELAY_GATEWAY_HINT_TAG {
+            relay_len += field.len();
+        }
+        Ok(())
+    })?;
+    write_relay_frame(format, relay_len, out, |w| {
+        for_each_field(relay, |tag, field| {
+            if tag == RELAY_PATH_DELAY_TAG || tag == RELAY_GATEWAY_HINT_TAG {
+                return Ok(());
+            }
+            w.bytes(field)
+        })?;
+        w.optional_varint(RELAY_PATH_DELAY_TAG, path_delay)?;
+        w.optional_field(RELAY_GATEWAY_HINT_TAG, hint)
+    })
+}
+
+/// A RelayMsg of `relay_len` bytes, written by `write`, as MortyMessage.relay in a frame.
+fn write_relay_frame(
+    format: FrameFormat,
+    relay_len: usize,
+    out: &mut [u8],
+    write: impl FnOnce(&mut Writer) -> Result<(), WireError>,
+) -> Result<usize, WireError> {
+    let message_len = field_len(RELAY_TYPE as u32, relay_len);
+    write_frame(format, RELAY_TYPE, message_len, out, |w| {
+        w.key(RELAY_TYPE as u32, LENGTH_DELIMITED)?;
+        w.varint(relay_len as u64)?;
+        write(w)
+    })
+}
+
+/// The header, or the message type for legacy frames, the CRC and a message of `message_len`
+/// bytes, written by `write`.
+fn write_frame(
+    format: FrameFormat,
+    msg_type: u8,
+    message_len: usize,
+    out: &mut [u8],
+    write: impl FnOnce(&mut Writer) -> Result<(), WireError>,
+) -> Result<usize, WireError> {
+    let start = format.overhead();
+    let len = start + message_len;
+    let out = out.get_mut(..len).ok_or(WireError::BufferTooSmall)?;
+    match format {
+        FrameFormat::Legacy => out[0] = msg_type,
+        FrameFormat::Header => {
+            out[..FRAME_HEADER_LEN].copy_from_slice(&FrameHeader::new(msg_type).encode())
+        }
+    }
+    let crc = format.crc();
+    let (frame_crc, message) = out[start - crc.size()..].split_at_mut(crc.size());
+    let mut writer = Writer {
+        out: message,
+        len: 0,
+    };
+    write(&mut writer)?;
+    if writer.len != message_len {
+        return Err(WireError::BufferTooSmall);
+    }
+    crc.write(crc.checksum(message), frame_crc);
+    Ok(len)
+}
+
+// Writes protobuf to a slice
+struct Writer<'a> {
+    out: &'a mut [u8],
+    len: usize,
+}
+
+impl Writer<'_> {
+    fn bytes(&mut self, bytes: &[u8]) -> Result<(), WireError> {
+        self.slice(bytes.len(), |out| out.copy_from_slice(bytes))
+    }
+
+    // The next `len` bytes, written by `write`
+    fn slice(&mut self, len: usize, write: impl FnOnce(&mut [u8])) -> Result<(), WireError> {
+        let end = self.len + len;
+        write(
+            self.out
+                .get_mut(self.len..end)
+                .ok_or(WireError::BufferTooSmall)?,
+        );
+        self.len = end;
+        Ok(())
+    }
+
+    fn varint(&mut self, mut value: u64) -> Result<(), WireError> {
+        loop {
+            if value < 0x80 {
+                return self.bytes(&[value as u8]);
+            }
+            self.bytes(&[(value as u8 & 0x7f) | 0x80])?;
+            value >>= 7;
+        }
+    }
+
+    fn key(&mut self, tag: u32, wire_type: u8) -> Result<(), WireError> {
+        self.varint(((tag as u64) << 3) | wire_type as u64)
+    }
+
+    fn field(&mut self, tag: u32, bytes: &[u8]) -> Result<(), WireError> {
+        self.key(tag, LENGTH_DELIMITED)?;
+        self.varint(bytes.len() as u64)?;
+        self.bytes(bytes)
+    }
+
+    // Empty fields are left out, like prost does
+    fn optional_field(&mut self, tag: u32, bytes: &[u8]) -> Result<(), WireError> {
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        self.field(tag, bytes)
+    }
+
+    fn optional_varint(&mut self, tag: u32, value: u64) -> Result<(), WireError> {
+        if value == 0 {
+            return Ok(());
+        }
+        self.key(tag, VARINT)?;
+        self.varint(value)
+    }
+}
+
+fn varint_len(value: u64) -> usize {
+    (64 - (value | 1).leading_zeros() as usize + 6) / 7
+}
+
+fn key_len(tag: u32) -> usize {
+    varint_len((tag as u64) << 3)
+}
+
+fn field_len(tag: u32, len: usize) -> usize {
+    key_len(tag) + varint_len(len as u64) + len
+}
+
+fn optional_field_len(tag: u32, bytes: &[u8]) -> usize {
+    if bytes.is_empty() {
+        0
+    } else {
+        field_len(tag, bytes.len())
+    }
+}
+
+fn optional_varint_len(tag: u32, value: u64) -> usize {
+    if value == 0 {
+        0
+    } else {
+        key_len(tag) + varint_len(value)
+    }
+}
+
+const PROTOBUF_ERROR: WireError =
+    WireError::Decode(DecodeFailure::ProtobufError, "Invalid protobuf");
+
+fn read_varint(bytes: &mut &[u8]) -> Result<u64, WireError> {
+    let mut value = 0u64;
+    for i in 0..10 {
+        let (&b, rest) = bytes.split_first().ok_or(PROTOBUF_ERROR)?;
+        *bytes = rest;
+        value |= ((b & 0x7f) as u64) << (7 * i);
+        if b < 0x80 {
+            return Ok(value);
+        }
+    }
+    Err(PROTOBUF_ERROR)
+}
+
+fn read_key(bytes: &mut &[u8]) -> Result<(u32, u8), WireError> {
+    let key = read_varint(bytes)?;
+    let tag = u32::try_from(key >> 3).map_err(|_| PROTOBUF_ERROR)?;
+    if tag == 0 {
+        return Err(PROTOBUF_ERROR);
+    }
+    Ok((tag, (key & 0x07) as u8))
+}
+
+/// Call `f` with the field number and the bytes, key included, of every field in `message`.
+fn for_each_field(
+    mut message: &[u8],
+    mut f: impl FnMut(u32, &[u8]) -> Result<(), WireError>,
+) -> Result<(), WireError> {
+    while !message.is_empty() {
+        let field = message;
+        let (tag, wire_type) = read_key(&mut message)?;
+        let len = match wire_type {
+            VARINT => {
+                read_varint(&mut message)?;
+                0
+            }
+            FIXED64 => 8,
+            LENGTH_DELIMITED => read_varint(&mut message)? as usize,
+            FIXED32 => 4,
+            _ => return Err(PROTOBUF_ERROR),
+        };
+        message = message.get(len..).ok_or(PROTOBUF_ERROR)?;
+        f(tag, &field[..field.len() - message.len()])?;
+    }
+    Ok(())
+}
+
+/// `path_delay_ms` with `delay` added, in milliseconds.
+pub fn add_delay(path_delay_ms: u32, delay: Duration) -> u32 {
+    let delay = u32::try_from(delay.as_millis()).unwrap_or(u32::MAX);
+    path_delay_ms.saturating_add(delay)
+}
+
+/// What to do with a relay.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// Pass it on with this `path_delay_ms`
+    Forward(u32),
+    /// Over the budget while we've seen a newer fix of its source
+    Drop,
+}
+
+/// Whether to pass on a relay of fix `seq` of boot `boot_id`, which spent `path_delay_ms` in the
+/// beacons before us and `delay` in this one. `newest` is the boot id and sequence number of the
+/// newest fix we've seen of its source. See `relay` for why.
+pub fn relay_verdict(
+    newest: Option<(u32, u32)>,
+    boot_id: u32,
+    seq: u32,
+    path_delay_ms: u32,
+    delay: Duration,
+    budget: Duration,
+) -> Verdict {
+    let path_delay_ms = add_delay(path_delay_ms, delay);
+    let superseded = boot_id != 0 && matches!(newest, Some((b, s)) if b == boot_id && s > seq);
+    if superseded && Duration::from_millis(path_delay_ms as u64) > budget {
+        return Verdict::Drop;
+    }
+    Verdict::Forward(path_delay_ms)
+}
+
+// Battery voltages within the same step of this many millivolts don't count as a change, so the
+// noise of the ADC doesn't turn duplicates into updates
+const VOLTAGE_STEP_MV: i32 = 100;
+
+/// The fields of a fix that can change between two attempts to send it, see `dedup`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Fingerprint {
+    charging: bool,
+    // The battery voltage in steps of VOLTAGE_STEP_MV
+    voltage_step: i32,
+    gps_fault_suspected: bool,
+}
+
+impl Fingerprint {
+    pub fn new(charging: bool, battery_voltage: f32, gps_fault_suspected: bool) -> Self {
+        // Rounded to whole millivolts first, without the round of std, so a step starts exactly
+        // at its voltage: 4.2 V divided by 0.1 V is 41.99999 in an f32
+        let mv = battery_voltage * 1000.0;
+        let mv = (mv + if mv < 0.0 { -0.5 } else { 0.5 }) as i32;
+        Self {
+            charging,
+            voltage_step: mv.div_euclid(VOLTAGE_STEP_MV),
+            gps_fault_suspected,
+        }
+    }
+
+    /// The fingerprint as bytes, the same in every build, e.g. for a hash that's stored or sent.
+    pub fn to_bytes(&self) -> [u8; 6] {
+        let mut bytes = [0u8; 6];
+        bytes[0] = self.charging as u8;
+        bytes[1] = self.gps_fault_suspected as u8;
+        bytes[2..6].copy_from_slice(&self.voltage_step.to_le_bytes());
+        bytes
+    }
+}
+
+/// Whether we've seen a fix before.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Seen {
+    New,
+    Duplicate,
+    /// We've seen the uid, but the fix has news
+    Update,
+}
+
+/// The uids and fingerprints of the last `N` fixes, for uids of up to `UID_LEN` bytes. A fix with
+/// a longer uid is always new, it can't be remembered.
+pub struct DedupRing<const N: usize, const UID_LEN: usize> {
+    entries: Deque<(heapless::String<UID_LEN>, Fingerprint), N>,
+}
+
+impl<const N: usize, const UID_LEN: usize> DedupRing<N, UID_LEN> {
+    pub const fn new() -> Self {
+        Self {
+            entries: Deque::new(),
+        }
+    }
+
+    /// Whether we've seen the fix with `uid` and `fingerprint` before, and remember it. An update
+    /// replaces the fingerprint we had, so another copy of it is a duplicate.
+    pub fn check(&mut self, uid: &str, fingerprint: Fingerprint) -> Seen {
+        match self
+            .entries
+            .iter_mut()
+            .find(|(known, _)| known.as_str() == uid)
+        {
+            Some((_, known)) if *known == fingerprint => Seen::Duplicate,
+            Some((_, known)) => {
+                *known = fingerprint;
+                Seen::Update
+            }
+            None => {
+                let mut key = heapless::String::new();
+                if key.push_str(uid).is_err() {
+                    return Seen::New;
+                }
+                if self.entries.is_full() {
+                    self.entries.pop_front();
+                }
+                let _ = self.entries.push_back((key, fingerprint));
+                Seen::New
+            }
+        }
+    }
+}
+
+impl<const N: usize, const UID_LEN: usize> Default for DedupRing<N, UID_LEN> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::alloc::GlobalAlloc;
+    use std::alloc::Layout;
+    use std::alloc::System;
+    use std::cell::Cell;
+
+    // Counts the allocations per thread, so a test can tell whether what it runs allocates while
+    // the other tests run alongside it
+    struct CountingAllocator;
+
+    std::thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    // The number of allocations `f` makes
+    fn allocations(f: impl FnOnce()) -> usize {
+        let before = ALLOCATIONS.with(Cell::get);
+        f();
+        ALLOCATIONS.with(Cell::get) - before
+    }
+
+    // A MortyMessage with an empty message of `msg_type`
+    fn message(msg_type: u8) -> [u8; 2] {
+        [(msg_type << 3) | LENGTH_DELIMITED, 0]
+    }
+
+    // A GpsMsg with uid "abc" as MortyMessage.gps
+    const GPS_MESSAGE: [u8; 7] = [0x12, 0x05, 0x0a, 0x03, b'a', b'b', b'c'];
+
+    fn legacy_frame(msg_type: u8, message: &[u8]) -> std::vec::Vec<u8> {
+        let mut frame = std::vec![msg_type, crc8(message)];
+        frame.extend_from_slice(message);
+        frame
+    }
+
+    // A frame of `message` with `header`, with its checksum computed by hand
+    fn header_frame(header: FrameHeader, message: &[u8]) -> std::vec::Vec<u8> {
+        let mut frame = header.encode().to_vec();
+        let crc = header.crc.checksum(message);
+        match header.crc {
+            CrcVariant::Crc8 => frame.push(crc as u8),
+            CrcVariant::Crc16 => frame.extend_from_slice(&crc.to_be_bytes()),
+        }
+        frame.extend_from_slice(message);
+        frame
+    }
+
+    fn with_crc(crc: CrcVariant) -> FrameHeader {
+        FrameHeader {
+            crc,
+            ..FrameHeader::new(GPS_TYPE)
+        }
+    }
+
+    #[test]
+    fn every_type_round_trips_in_a_legacy_frame() {
+        for t in MSG_TYPES {
+            let message = message(t.id);
+            let mut frame = [0; 16];
+            let len = encode_frame_into(FrameFormat::Legacy, t.id, &message, &mut frame).unwrap();
+            let frame = &frame[..len];
+            assert_eq!(FrameHeader::parse(frame), Ok(None), "{}", t.name);
+            assert_eq!(peek_type(frame), Some(t.id), "{}", t.name);
+            assert_eq!(split_frame(frame), Ok((t.id, &message[..])), "{}", t.name);
+        }
+    }
+
+    #[test]
+    fn reframing_keeps_the_bytes() {
+        let frame = legacy_frame(GPS_TYPE, &GPS_MESSAGE);
+        let mut legacy = [0; 32];
+        let len = reframe_into(&frame, FrameFormat::Legacy, &mut legacy).unwrap();
+        assert_eq!(&legacy[..len], &frame[..]);
+
+        let mut header = [0; 32];
+        let len = reframe_into(&frame, FrameFormat::Header, &mut header).unwrap();
+        let len = reframe_into(&header[..len], FrameFormat::Legacy, &mut legacy).unwrap();
+        assert_eq!(&legacy[..len], &frame[..]);
+    }
+
+    #[test]
+    fn encode_with_gives_the_bytes_of_encode_into() {
+        for format in [FrameFormat::Legacy, FrameFormat::Header] {
+            let mut into = [0; 32];
+            let mut with = [0; 32];
+            let len = encode_frame_into(format, GPS_TYPE, &GPS_MESSAGE, &mut into).unwrap();
+            let encode = |out: &mut [u8]| out.copy_from_slice(&GPS_MESSAGE);
+            let with_len =
+                encode_frame_with(format, GPS_TYPE, GPS_MESSAGE.len(), &mut with, encode).unwrap();
+            assert_eq!(into[..len], with[..with_len]);
+        }
+    }
+
+    // RelayMsg{src: "aa", timestamp: 5, gps: GPS_MESSAGE, path_delay_ms: 7, gateway_hint: "gw"} as
+    // prost encodes it, in a legacy frame
+    fn relay_frame() -> std::vec::Vec<u8> {
+        let mut relay = std::vec![0x0a, 0x02, b'a', b'a', 0x10, 0x05, 0x1a, 0x05];
+        relay.extend_from_slice(&GPS_MESSAGE[2..]);
+        relay.extend_from_slice(&[0x40, 0x07, 0x62, 0x02, b'g', b'w']);
+        let mut message = std::vec![0x1a, relay.len() as u8];
+        message.extend_from_slice(&relay);
+        legacy_frame(RELAY_TYPE, &message)
+    }
+
+    #[test]
+    fn relays_have_the_bytes_of_prost() {
+        let frame = legacy_frame(GPS_TYPE, &GPS_MESSAGE);
+        let mut out = [0; 64];
+        let len = encode_relay_into("aa", 5, 7, "gw", &frame, FrameFormat::Legacy, &mut out);
+        assert_eq!(&out[..len.unwrap()], &relay_frame()[..]);
+    }
+
+    #[test]
+    fn unchanged_relays_keep_their_bytes() {
+        let frame = relay_frame();
+        let mut out = [0; 64];
+        let len = reframe_relay_into(&frame, 7, "gw", FrameFormat::Legacy, &mut out).unwrap();
+        assert_eq!(&out[..len], &frame[..]);
+
+        // Only the path delay changes
+        let len = reframe_relay_into(&frame, 9, "gw", FrameFormat::Legacy, &mut out).unwrap();
+        let (_, message) = split_frame(&out[..len]).unwrap();
+        let (_, expected) = split_frame(&frame).unwrap();
+        assert_eq!(message.len(), expected.len());
+        let differ: std::vec::Vec<usize> = (0..message.len())
+            .filter(|&i| message[i] != expected[i])
+            .collect();
+        assert_eq!(differ, [16]);
+        assert_eq!(message[16], 9);
+    }
+
+    #[test]
+    fn both_formats_round_trip() {
+        for format in [FrameFormat::Legacy, FrameFormat::Header] {
+            let mut frame = [0; 32];
+            let len = encode_frame_into(format, GPS_TYPE, &GPS_MESSAGE, &mut frame).unwrap();
+            assert_eq!(len, format.overhead() + GPS_MESSAGE.len());
+            assert_eq!(split_frame(&frame[..len]), Ok((GPS_TYPE, &GPS_MESSAGE[..])));
+            assert_eq!(peek_type(&frame[..len]), Some(GPS_TYPE));
+        }
+        let mut frame = [0; 32];
+        let len = encode_frame_into(FrameFormat::Legacy, GPS_TYPE, &GPS_MESSAGE, &mut frame);
+        assert_eq!(
+            &frame[..len.unwrap()],
+            &legacy_frame(GPS_TYPE, &GPS_MESSAGE)[..]
+        );
+        let len = encode_frame_into(FrameFormat::Header, GPS_TYPE, &GPS_MESSAGE, &mut frame);
+        let expected = header_frame(FrameHeader::new(GPS_TYPE), &GPS_MESSAGE);
+        assert_eq!(&frame[..len.unwrap()], &expected[..]);
+    }
+
+    #[test]
+    fn both_checksums_round_trip() {
+        for crc in [CrcVariant::Crc8, CrcVariant::Crc16] {
+            let frame = header_frame(with_crc(crc), &GPS_MESSAGE);
+            assert_eq!(
+                frame.len(),
+                FRAME_HEADER_LEN + crc.size() + GPS_MESSAGE.len()
+            );
+            assert_eq!(split_frame(&frame), Ok((GPS_TYPE, &GPS_MESSAGE[..])));
+        }
+    }
+
+    #[test]
+    fn corrupted_frames_are_rejected() {
+        let frames = [
+            legacy_frame(GPS_TYPE, &GPS_MESSAGE),
+            header_frame(with_crc(CrcVariant::Crc8), &GPS_MESSAGE),
+            header_frame(with_crc(CrcVariant::Crc16), &GPS_MESSAGE),
+        ];
+        for frame in frames {
+            let message_start = frame.len() - GPS_MESSAGE.len();
+            // Every single bit flip in the checksum or the message is caught
+            for i in message_start - 1..frame.len() {
+                for bit in 0..8 {
+                    let mut corrupted = frame.clone();
+                    corrupted[i] ^= 1 << bit;
+                    let result = split_frame(&corrupted);
+                    assert!(
+                        matches!(result, Err(WireError::Crc { .. })),
+                        "{corrupted:?}"
+                    );
+                    let cause = result.unwrap_err().cause();
+                    assert_eq!(cause, Some(DecodeFailure::CrcMismatch));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn frames_of_another_version_are_rejected() {
+        let mut frame = header_frame(FrameHeader::new(GPS_TYPE), &GPS_MESSAGE);
+        frame[0] = (FRAME_MAGIC << 4) | (FRAME_VERSION + 1);
+        assert_eq!(
+            split_frame(&frame),
+            Err(WireError::UnsupportedVersion(FRAME_VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn headers_round_trip() {
+        for flags in 0..8 {
+            for crc in [CrcVariant::Crc8, CrcVariant::Crc16] {
+                let header = FrameHeader {
+                    version: FRAME_VERSION,
+                    flags: FrameFlags::from_byte(flags).unwrap(),
+                    msg_type: GPS_TYPE,
+                    crc,
+                };
+                let bytes = header.encode();
+                assert_eq!(bytes[0], 0xa1);
+                assert_eq!(bytes[1], flags);
+                assert_eq!(bytes[2], GPS_TYPE);
+                assert_eq!(FrameHeader::parse(&bytes), Ok(Some(header)));
+            }
+        }
+    }
+
+    #[test]
+    fn header_fields_have_their_bits() {
+        let flags = |byte| FrameFlags::from_byte(byte).unwrap();
+        assert_eq!(flags(0), FrameFlags::default());
+        assert!(flags(0x01).encrypted && !flags(0x01).fragmented && !flags(0x01).hmac);
+        assert!(flags(0x02).fragmented && !flags(0x02).encrypted && !flags(0x02).hmac);
+        assert!(flags(0x04).hmac && !flags(0x04).encrypted && !flags(0x04).fragmented);
+        assert_eq!(CrcVariant::Crc8.to_byte(), 0);
+        assert_eq!(CrcVariant::Crc16.to_byte(), 1);
+        assert_eq!(CrcVariant::from_byte(0), Some(CrcVariant::Crc8));
+        assert_eq!(CrcVariant::from_byte(1), Some(CrcVariant::Crc16));
+        let header = FrameHeader::new(TRACE_TYPE);
+        assert_eq!(
+            header.encode(),
+            [0xa1, 0x00, TRACE_TYPE, CHECKSUM.to_byte()]
+        );
+    }
+
+    #[test]
+    fn unknown_header_fields_are_rejected() {
+        let unknown = |data: &[u8]| {
+            let cause = FrameHeader::parse(data).unwrap_err().cause();
+            cause == Some(DecodeFailure::VersionUnknown)
+        };
+        for flags in [0x08, 0x10, 0x80, 0xff] {
+            assert!(unknown(&[0xa1, flags, GPS_TYPE, 0]), "{flags}");
+        }
+        for crc in [2, 0x10, 0xff] {
+            assert!(unknown(&[0xa1, 0, GPS_TYPE, crc]), "{crc}");
+        }
+    }
+
+    #[test]
+    fn every_first_byte_is_a_legacy_type_a_header_or_unknown() {
+        for first in 0..=u8::MAX {
+            let result = FrameHeader::parse(&[first, 0, GPS_TYPE, 0]);
+            let version = first & 0x0f;
+            if first >> 4 == FRAME_MAGIC {
+                if version == 0 || version > FRAME_VERSION {
+                    assert_eq!(result, Err(WireError::UnsupportedVersion(version)));
+                } else {
+                    assert_eq!(result.unwrap().unwrap().version, version);
+                }
+            } else if first <= LEGACY_MAX_TYPE {
+                assert_eq!(result, Ok(None), "{first}");
+            } else {
+                let cause = result.unwrap_err().cause();
+                assert_eq!(cause, Some(DecodeFailure::VersionUnknown), "{first}");
+            }
+        }
+    }
+
+    #[test]
+    fn short_frames_are_rejected() {
+        let truncated = |data: &[u8]| {
+            let cause = FrameHeader::parse(data).unwrap_err().cause();
+            cause == Some(DecodeFailure::Truncated)
+        };
+        assert!(truncated(&[]));
+        assert!(truncated(&[0xa1]));
+        assert!(truncated(&[0xa1, 0]));
+        assert!(truncated(&[0xa1, 0, GPS_TYPE]));
+        // A legacy frame is just its type, the CRC is checked later
+        assert_eq!(FrameHeader::parse(&[GPS_TYPE]), Ok(None));
+    }
+
+    #[test]
+    fn voltages_in_a_step_have_the_same_fingerprint() {
+        let fp = |volts| Fingerprint::new(false, volts, false);
+        for (low, high) in [(0.0, 0.099), (3.7, 3.799), (4.1, 4.199), (4.2, 4.299)] {
+            assert_eq!(fp(low), fp(high), "{low} {high}");
+        }
+        for (below, edge) in [(3.699, 3.7), (4.199, 4.2), (-0.001, 0.0)] {
+            assert_ne!(fp(below), fp(edge), "{below} {edge}");
+        }
+        // Readings within half a millivolt of an edge are on it
+        assert_eq!(fp(4.2), fp(4.1996));
+        assert_eq!(fp(-1.0), fp(-0.901));
+    }
+
+    #[test]
+    fn fingerprints_have_the_fields_that_change() {
+        let fp = Fingerprint::new(false, 3.7, false);
+        assert_ne!(fp, Fingerprint::new(true, 3.7, false));
+        assert_ne!(fp, Fingerprint::new(false, 3.7, true));
+        assert_ne!(fp, Fingerprint::new(false, 3.8, false));
+        // The bytes go into idempotency keys, so they must never change
+        assert_eq!(fp.to_bytes(), [0, 0, 37, 0, 0, 0]);
+        assert_eq!(
+            Fingerprint::new(true, -1.0, true).to_bytes(),
+            [1, 1, 0xf6, 0xff, 0xff, 0xff]
+        );
+    }
+
+    #[test]
+    fn dedup_decides_by_uid_and_fingerprint() {
+        let mut ring = DedupRing::<4, 6>::new();
+        let fp = Fingerprint::new(false, 3.7, false);
+        let plugged_in = Fingerprint::new(true, 3.7, false);
+        assert_eq!(ring.check("abc123", fp), Seen::New);
+        assert_eq!(ring.check("abc123", fp), Seen::Duplicate);
+        assert_eq!(ring.check("abc123", plugged_in), Seen::Update);
+        // The update replaced the fingerprint
+        assert_eq!(ring.check("abc123", plugged_in), Seen::Duplicate);
+        assert_eq!(ring.check("abc123", fp), Seen::Update);
+        // Another uid with the same fingerprint is another fix
+        assert_eq!(ring.check("def456", fp), Seen::New);
+        assert_eq!(ring.check("abc123", fp), Seen::Duplicate);
+    }
+
+    #[test]
+    fn dedup_forgets_the_oldest_fix() {
+        let mut ring = DedupRing::<2, 6>::new();
+        let fp = Fingerprint::new(false, 3.7, false);
+        assert_eq!(ring.check("a", fp), Seen::New);
+        assert_eq!(ring.check("b", fp), Seen::New);
+        assert_eq!(ring.check("c", fp), Seen::New);
+        assert_eq!(ring.check("a", fp), Seen::New);
+        assert_eq!(ring.check("c", fp), Seen::Duplicate);
+    }
+
+    #[test]
+    fn uids_too_long_to_remember_are_always_new() {
+        let mut ring = DedupRing::<2, 6>::new();
+        let fp = Fingerprint::new(false, 3.7, false);
+        assert_eq!(ring.check("abcdefg", fp), Seen::New);
+        assert_eq!(ring.check("abcdefg", fp), Seen::New);
+    }
+
+    #[test]
+    fn checksums_match_their_check_values() {
+        // The check values of the catalogue of CRC parameters, for "123456789"
+        assert_eq!(crc8(b"123456789"), 0xf4);
+        assert_eq!(crc16(b"123456789"), 0x29b1);
+        assert_eq!(crc8(b""), 0x00);
+        assert_eq!(crc16(b""), 0xffff);
+        assert_eq!(CrcVariant::Crc8.checksum(b"123456789"), 0xf4);
+        assert_eq!(CrcVariant::Crc16.checksum(b"123456789"), 0x29b1);
+    }
+
+    #[test]
+    fn checksums_are_written_most_significant_byte_first() {
+        for (crc, value, bytes) in [
+            (CrcVariant::Crc8, 0xf4, &[0xf4][..]),
+            (CrcVariant::Crc16, 0x29b1, &[0x29, 0xb1][..]),
+        ] {
+            let mut out = [0; 2];
+            crc.write(value, &mut out[..crc.size()]);
+            assert_eq!(&out[..crc.size()], bytes);
+            assert_eq!(crc.read(bytes), value);
+        }
+    }
+
+    #[test]
+    fn header_frames_have_the_build_time_checksum() {
+        assert_eq!(FrameFormat::Header.crc(), CHECKSUM);
+        assert_eq!(FrameFormat::Legacy.crc(), CrcVariant::Crc8);
+        let mut frame = [0; 32];
+        let len = encode_frame_into(FrameFormat::Header, GPS_TYPE, &GPS_MESSAGE, &mut frame);
+        assert_eq!(
+            len,
+            Ok(FRAME_HEADER_LEN + CHECKSUM.size() + GPS_MESSAGE.len())
+        );
+        let header = FrameHeader::parse(&frame).unwrap().unwrap();
+        assert_eq!(header.crc, CHECKSUM);
+    }
+
+    #[test]
+    fn nothing_here_allocates() {
+        assert_eq!(
+            allocations(|| drop(std::hint::black_box(std::vec![0u8; 16]))),
+            1
+        );
+
+        let frame = legacy_frame(GPS_TYPE, &GPS_MESSAGE);
+        let relay = relay_frame();
+        let mut out = [0; 64];
+        let mut ring = DedupRing::<4, 16>::new();
+        let n = allocations(|| {
+            for format in [FrameFormat::Legacy, FrameFormat::Header] {
+                encode_frame_into(format, GPS_TYPE, &GPS_MESSAGE, &mut out).unwrap();
+                encode_frame_with(format, GPS_TYPE, GPS_MESSAGE.len(), &mut out, |message| {
+                    message.copy_from_slice(&GPS_MESSAGE)
+                })
+                .unwrap();
+                reframe_into(&frame, format, &mut out).unwrap();
+                encode_relay_into("aa", 5, 7, "gw", &frame, format, &mut out).unwrap();
+                reframe_relay_into(&relay, 9, "gw", format, &mut out).unwrap();
+            }
+            split_frame(&frame).unwrap();
+            FrameHeader::parse(&out).unwrap();
+            peek_type(&relay).unwrap();
+            let fingerprint = Fingerprint::new(false, 3.7, false);
+            assert_eq!(ring.check("abc", fingerprint), Seen::New);
+            assert_eq!(ring.check("abc", fingerprint), Seen::Duplicate);
+        });
+        assert_eq!(n, 0);
+    }
+}